@@ -1,2 +1,3 @@
+pub mod network;
 pub mod parser;
 pub mod proto;
@@ -0,0 +1,530 @@
+//! The messages of the P2P protocol needed to download blocks. Each one is framed by the
+//! network magic, the command, the size of the payload and the first 4 bytes of its double
+//! SHA256 as checksum.
+
+use std::io::{self, Cursor, Read, Write};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+
+use byteorder::{BigEndian, LittleEndian, ReadBytesExt};
+
+use crate::blockchain::parser::reader::BlockchainRead;
+use crate::blockchain::proto::block::BlockFormat;
+use crate::blockchain::proto::header::BlockHeader;
+use crate::blockchain::proto::varuint::VarUint;
+use crate::blockchain::proto::{Hashed, ToRaw};
+use crate::common::utils;
+use crate::errors::{OpError, OpResult};
+
+/// Version sent in the handshake, the one of Bitcoin Core 0.13.2 (BIP152)
+pub const PROTOCOL_VERSION: u32 = 70015;
+/// Peers below this version don't answer `getheaders`
+pub const MIN_PEER_VERSION: u32 = 31800;
+/// Serves the full chain
+pub const NODE_NETWORK: u64 = 1;
+/// Serves blocks with their witnesses (BIP144)
+pub const NODE_WITNESS: u64 = 1 << 3;
+/// Pruned node, serves the last 288 blocks only (BIP159)
+pub const NODE_NETWORK_LIMITED: u64 = 1 << 10;
+/// Inventory type of blocks, with `MSG_WITNESS_FLAG` the witnesses are included
+pub const MSG_BLOCK: u32 = 2;
+pub const MSG_WITNESS_FLAG: u32 = 1 << 30;
+/// Headers answered to a single `getheaders`, fewer mean the tip of the peer was reached
+pub const MAX_HEADERS: usize = 2000;
+/// Entries of `getdata` and `notfound`
+const MAX_INVENTORY: u64 = 50_000;
+/// Blocks of Bitcoin Cash are up to 32 MB
+const MAX_PAYLOAD: u32 = 32 * 1024 * 1024;
+const MAX_USER_AGENT: u64 = 256;
+const COMMAND_SIZE: usize = 12;
+
+/// Address of a node as sent in `version`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NetAddr {
+    pub services: u64,
+    pub ip: Ipv6Addr,
+    pub port: u16,
+}
+
+impl NetAddr {
+    /// The address of the sender, unknown to itself
+    pub fn unspecified(services: u64) -> Self {
+        NetAddr {
+            services,
+            ip: Ipv6Addr::UNSPECIFIED,
+            port: 0,
+        }
+    }
+
+    /// IPv4 addresses are mapped into IPv6
+    pub fn from_socket_addr(addr: SocketAddr, services: u64) -> Self {
+        let ip = match addr.ip() {
+            IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+            IpAddr::V6(ip) => ip,
+        };
+        NetAddr {
+            services,
+            ip,
+            port: addr.port(),
+        }
+    }
+
+    fn write_to(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.services.to_le_bytes());
+        out.extend_from_slice(&self.ip.octets());
+        // The port is the only big endian field of the protocol
+        out.extend_from_slice(&self.port.to_be_bytes());
+    }
+
+    fn read_from(reader: &mut Cursor<Vec<u8>>) -> OpResult<Self> {
+        let services = reader.read_u64::<LittleEndian>()?;
+        let mut ip = [0u8; 16];
+        reader.read_exact(&mut ip)?;
+        let port = reader.read_u16::<BigEndian>()?;
+        Ok(NetAddr {
+            services,
+            ip: Ipv6Addr::from(ip),
+            port,
+        })
+    }
+}
+
+/// Payload of `version`, the first message each side sends
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Version {
+    pub version: u32,
+    pub services: u64,
+    pub timestamp: i64,
+    pub receiver: NetAddr,
+    pub sender: NetAddr,
+    pub nonce: u64,
+    pub user_agent: String,
+    pub start_height: i32,
+    /// Missing before version 70001, which means true
+    pub relay: bool,
+}
+
+/// Object requested by `getdata`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Inventory {
+    pub kind: u32,
+    pub hash: [u8; 32],
+}
+
+impl Inventory {
+    pub fn block(hash: [u8; 32], witness: bool) -> Self {
+        let kind = if witness {
+            MSG_BLOCK | MSG_WITNESS_FLAG
+        } else {
+            MSG_BLOCK
+        };
+        Inventory { kind, hash }
+    }
+}
+
+#[derive(Debug)]
+pub enum Message {
+    Version(Version),
+    Verack,
+    Ping(u64),
+    Pong(u64),
+    /// Asks for the headers after the first locator hash known to the peer, up to `stop` or
+    /// `MAX_HEADERS` if it is zero
+    GetHeaders {
+        locator: Vec<[u8; 32]>,
+        stop: [u8; 32],
+    },
+    /// The AuxPoW of merge mined headers is read but not kept, so it isn't encoded either
+    Headers(Vec<Hashed<BlockHeader>>),
+    GetData(Vec<Inventory>),
+    /// Answer to `getdata` for objects the peer doesn't have
+    NotFound(Vec<Inventory>),
+    /// Serialized block as in the blk files
+    Block(Vec<u8>),
+    /// Any other command, its payload isn't needed
+    Other(String),
+}
+
+impl Message {
+    pub fn command(&self) -> &str {
+        match self {
+            Message::Version(_) => "version",
+            Message::Verack => "verack",
+            Message::Ping(_) => "ping",
+            Message::Pong(_) => "pong",
+            Message::GetHeaders { .. } => "getheaders",
+            Message::Headers(_) => "headers",
+            Message::GetData(_) => "getdata",
+            Message::NotFound(_) => "notfound",
+            Message::Block(_) => "block",
+            Message::Other(command) => command,
+        }
+    }
+
+    /// Serializes the message with its frame, unknown commands have an empty payload
+    pub fn to_frame(&self, magic: u32) -> Vec<u8> {
+        let payload = self.payload();
+        let mut command = [0u8; COMMAND_SIZE];
+        let name = self.command().as_bytes();
+        command[..name.len().min(COMMAND_SIZE)]
+            .copy_from_slice(&name[..name.len().min(COMMAND_SIZE)]);
+
+        let mut frame = Vec::with_capacity(24 + payload.len());
+        frame.extend_from_slice(&magic.to_le_bytes());
+        frame.extend_from_slice(&command);
+        frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&utils::sha256d(&payload)[..4]);
+        frame.extend_from_slice(&payload);
+        frame
+    }
+
+    fn payload(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        match self {
+            Message::Version(version) => {
+                out.extend_from_slice(&version.version.to_le_bytes());
+                out.extend_from_slice(&version.services.to_le_bytes());
+                out.extend_from_slice(&version.timestamp.to_le_bytes());
+                version.receiver.write_to(&mut out);
+                version.sender.write_to(&mut out);
+                out.extend_from_slice(&version.nonce.to_le_bytes());
+                out.extend_from_slice(
+                    &VarUint::compact(version.user_agent.len() as u64).to_bytes(),
+                );
+                out.extend_from_slice(version.user_agent.as_bytes());
+                out.extend_from_slice(&version.start_height.to_le_bytes());
+                out.push(version.relay as u8);
+            }
+            Message::Verack | Message::Other(_) => (),
+            Message::Ping(nonce) | Message::Pong(nonce) => {
+                out.extend_from_slice(&nonce.to_le_bytes())
+            }
+            Message::GetHeaders { locator, stop } => {
+                out.extend_from_slice(&PROTOCOL_VERSION.to_le_bytes());
+                out.extend_from_slice(&VarUint::compact(locator.len() as u64).to_bytes());
+                for hash in locator {
+                    out.extend_from_slice(hash);
+                }
+                out.extend_from_slice(stop);
+            }
+            Message::Headers(headers) => {
+                out.extend_from_slice(&VarUint::compact(headers.len() as u64).to_bytes());
+                for header in headers {
+                    out.extend_from_slice(&header.value.to_bytes());
+                    // Transaction count, always zero
+                    out.push(0);
+                }
+            }
+            Message::GetData(inventory) | Message::NotFound(inventory) => {
+                out.extend_from_slice(&VarUint::compact(inventory.len() as u64).to_bytes());
+                for inv in inventory {
+                    out.extend_from_slice(&inv.kind.to_le_bytes());
+                    out.extend_from_slice(&inv.hash);
+                }
+            }
+            Message::Block(raw) => out.extend_from_slice(raw),
+        }
+        out
+    }
+
+    /// Deserializes the payload of the given command, headers are read in the given format
+    pub fn from_payload(command: &str, payload: Vec<u8>, format: BlockFormat) -> OpResult<Self> {
+        let mut reader = Cursor::new(payload);
+        let message = match command {
+            "version" => {
+                let version = reader.read_u32::<LittleEndian>()?;
+                let services = reader.read_u64::<LittleEndian>()?;
+                let timestamp = reader.read_i64::<LittleEndian>()?;
+                let receiver = NetAddr::read_from(&mut reader)?;
+                let sender = NetAddr::read_from(&mut reader)?;
+                let nonce = reader.read_u64::<LittleEndian>()?;
+                let len = VarUint::read_from(&mut reader)?.value;
+                if len > MAX_USER_AGENT {
+                    return Err(OpError::from(format!("User agent of {} bytes", len)));
+                }
+                let user_agent =
+                    String::from_utf8_lossy(&reader.read_u8_vec(len as u32)?).into_owned();
+                let start_height = reader.read_i32::<LittleEndian>()?;
+                let relay = match reader.read_u8() {
+                    Ok(relay) => relay != 0,
+                    Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => true,
+                    Err(e) => return Err(OpError::from(e)),
+                };
+                Message::Version(Version {
+                    version,
+                    services,
+                    timestamp,
+                    receiver,
+                    sender,
+                    nonce,
+                    user_agent,
+                    start_height,
+                    relay,
+                })
+            }
+            "verack" => Message::Verack,
+            "ping" => Message::Ping(reader.read_u64::<LittleEndian>()?),
+            "pong" => Message::Pong(reader.read_u64::<LittleEndian>()?),
+            "getheaders" => {
+                // The version isn't needed
+                reader.read_u32::<LittleEndian>()?;
+                let count = VarUint::read_from(&mut reader)?.value;
+                if count > MAX_HEADERS as u64 {
+                    return Err(OpError::from(format!("getheaders with {} hashes", count)));
+                }
+                let locator = (0..count)
+                    .map(|_| reader.read_256hash())
+                    .collect::<OpResult<Vec<_>>>()?;
+                Message::GetHeaders {
+                    locator,
+                    stop: reader.read_256hash()?,
+                }
+            }
+            "headers" => {
+                let count = VarUint::read_from(&mut reader)?.value;
+                if count > MAX_HEADERS as u64 {
+                    return Err(OpError::from(format!("headers with {} entries", count)));
+                }
+                let mut headers = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    // Each header is followed by the transaction count, which is zero
+                    let block = reader.read_block_without_txs(0, format)?;
+                    if block.tx_count.value != 0 {
+                        return Err(OpError::from(format!(
+                            "Header with {} transactions",
+                            block.tx_count.value
+                        )));
+                    }
+                    headers.push(block.header);
+                }
+                Message::Headers(headers)
+            }
+            "getdata" | "notfound" => {
+                let count = VarUint::read_from(&mut reader)?.value;
+                if count > MAX_INVENTORY {
+                    return Err(OpError::from(format!("{} with {} entries", command, count)));
+                }
+                let mut inventory = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    inventory.push(Inventory {
+                        kind: reader.read_u32::<LittleEndian>()?,
+                        hash: reader.read_256hash()?,
+                    });
+                }
+                if command == "getdata" {
+                    Message::GetData(inventory)
+                } else {
+                    Message::NotFound(inventory)
+                }
+            }
+            "block" => return Ok(Message::Block(reader.into_inner())),
+            command => return Ok(Message::Other(String::from(command))),
+        };
+        Ok(message)
+    }
+}
+
+/// Writes the framed message
+pub fn write_message<W: Write>(writer: &mut W, magic: u32, message: &Message) -> io::Result<()> {
+    writer.write_all(&message.to_frame(magic))?;
+    writer.flush()
+}
+
+/// Reads the next message and checks its frame
+pub fn read_message<R: Read>(reader: &mut R, magic: u32, format: BlockFormat) -> OpResult<Message> {
+    let actual_magic = reader.read_u32::<LittleEndian>()?;
+    if actual_magic != magic {
+        return Err(OpError::from(format!(
+            "Message with magic {:08x}, expected {:08x}",
+            actual_magic, magic
+        )));
+    }
+    let mut command = [0u8; COMMAND_SIZE];
+    reader.read_exact(&mut command)?;
+    let len = command.iter().position(|b| *b == 0).unwrap_or(COMMAND_SIZE);
+    // Commands are padded with zeros
+    if !command[..len].iter().all(u8::is_ascii_graphic) || command[len..].iter().any(|b| *b != 0) {
+        return Err(OpError::from(format!(
+            "Invalid command {}",
+            utils::arr_to_hex(&command)
+        )));
+    }
+    let command = String::from_utf8_lossy(&command[..len]).into_owned();
+    let size = reader.read_u32::<LittleEndian>()?;
+    if size > MAX_PAYLOAD {
+        return Err(OpError::from(format!(
+            "`{}` message of {} bytes",
+            command, size
+        )));
+    }
+    let mut checksum = [0u8; 4];
+    reader.read_exact(&mut checksum)?;
+    let payload = reader.read_u8_vec(size)?;
+    if utils::sha256d(&payload)[..4] != checksum {
+        return Err(OpError::from(format!(
+            "Checksum of `{}` message doesn't match",
+            command
+        )));
+    }
+    Message::from_payload(&command, payload, format)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAGIC: u32 = 0xd9b4bef9;
+    // Frames of the Bitcoin mainnet, checksums computed independently
+    const VERACK: &str = "f9beb4d976657261636b000000000000000000005df6e0e2";
+    const PING: &str = "f9beb4d970696e6700000000000000000800000033bc15e5efcdab8967452301";
+    const VERSION: &str = concat!(
+        "f9beb4d976657273696f6e000000000066000000b41b67a87f110100090400000000000000f1536500000000",
+        "000000000000000000000000000000000000ffff7f000001208d090400000000000000000000000000000000",
+        "00000000000000008877665544332211102f5361746f7368693a32352e302e302f00350c0001",
+    );
+    // Version 60002 has no relay flag
+    const VERSION_60002: &str = concat!(
+        "f9beb4d976657273696f6e000000000064000000baea23f962ea0000010000000000000011b2d05000000000",
+        "010000000000000000000000000000000000ffff7f000001208d010000000000000000000000000000000000",
+        "00000000000000003b2eb35d8ce617650f2f5361746f7368693a302e372e322fc03e0300",
+    );
+    const GETHEADERS: &str = concat!(
+        "f9beb4d96765746865616465727300004500000084f4958d7f110100016fe28c0ab6f1b372c1a6a246ae63f7",
+        "4f931e8365e15a089c68d619000000000000000000000000000000000000000000000000000000000000000000",
+        "00000000",
+    );
+    // The genesis header
+    const HEADERS: &str = concat!(
+        "f9beb4d9686561646572730000000000520000000b0e13eb0101000000000000000000000000000000000000",
+        "00000000000000000000000000000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8",
+        "aa4b1e5e4a29ab5f49ffff001d1dac2b7c00",
+    );
+    const GETDATA: &str = concat!(
+        "f9beb4d96765746461746100000000002500000097e8f9e901020000406fe28c0ab6f1b372c1a6a246ae63f7",
+        "4f931e8365e15a089c68d6190000000000",
+    );
+    const GENESIS_HASH: &str = "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f";
+
+    fn decode(hex: &str) -> Message {
+        let bytes = utils::hex_to_vec(hex);
+        let message = read_message(&mut Cursor::new(&bytes), MAGIC, BlockFormat::Bitcoin).unwrap();
+        // Encoding gives the same bytes back
+        assert_eq!(hex, utils::arr_to_hex(&message.to_frame(MAGIC)));
+        message
+    }
+
+    #[test]
+    fn test_verack_and_ping() {
+        assert!(matches!(decode(VERACK), Message::Verack));
+        assert!(matches!(decode(PING), Message::Ping(0x0123456789abcdef)));
+        assert_eq!("pong", Message::Pong(1).command(),);
+    }
+
+    #[test]
+    fn test_version() {
+        let version = match decode(VERSION) {
+            Message::Version(version) => version,
+            other => panic!("{:?}", other),
+        };
+        assert_eq!(
+            Version {
+                version: 70015,
+                services: NODE_NETWORK | NODE_WITNESS | NODE_NETWORK_LIMITED,
+                timestamp: 1700000000,
+                receiver: NetAddr::from_socket_addr("127.0.0.1:8333".parse().unwrap(), 0),
+                sender: NetAddr::unspecified(0x409),
+                nonce: 0x1122334455667788,
+                user_agent: String::from("/Satoshi:25.0.0/"),
+                start_height: 800000,
+                relay: true,
+            },
+            version
+        );
+
+        let bytes = utils::hex_to_vec(VERSION_60002);
+        match read_message(&mut Cursor::new(&bytes), MAGIC, BlockFormat::Bitcoin).unwrap() {
+            Message::Version(version) => {
+                assert_eq!(60002, version.version);
+                assert_eq!("/Satoshi:0.7.2/", version.user_agent);
+                assert_eq!(212672, version.start_height);
+                assert!(version.relay);
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_headers_and_getdata() {
+        let genesis = utils::hex_to_arr32_swapped(GENESIS_HASH);
+        match decode(GETHEADERS) {
+            Message::GetHeaders { locator, stop } => {
+                assert_eq!(vec![genesis], locator);
+                assert_eq!([0u8; 32], stop);
+            }
+            other => panic!("{:?}", other),
+        }
+        match decode(HEADERS) {
+            Message::Headers(headers) => {
+                assert_eq!(1, headers.len());
+                assert_eq!(genesis, headers[0].hash);
+                assert_eq!(1231006505, headers[0].value.timestamp);
+            }
+            other => panic!("{:?}", other),
+        }
+        match decode(GETDATA) {
+            Message::GetData(inventory) => {
+                assert_eq!(vec![Inventory::block(genesis, true)], inventory)
+            }
+            other => panic!("{:?}", other),
+        }
+        let notfound = Message::NotFound(vec![Inventory::block(genesis, false)]).to_frame(MAGIC);
+        assert!(matches!(
+            read_message(&mut Cursor::new(&notfound), MAGIC, BlockFormat::Bitcoin).unwrap(),
+            Message::NotFound(inventory) if inventory[0].kind == MSG_BLOCK
+        ));
+    }
+
+    #[test]
+    fn test_block_and_other() {
+        let raw = vec![1, 2, 3];
+        let frame = Message::Block(raw.clone()).to_frame(MAGIC);
+        match read_message(&mut Cursor::new(&frame), MAGIC, BlockFormat::Bitcoin).unwrap() {
+            Message::Block(block) => assert_eq!(raw, block),
+            other => panic!("{:?}", other),
+        }
+        // sendheaders
+        let frame = utils::hex_to_vec("f9beb4d973656e646865616465727300000000005df6e0e2");
+        assert!(matches!(
+            read_message(&mut Cursor::new(&frame), MAGIC, BlockFormat::Bitcoin).unwrap(),
+            Message::Other(command) if command == "sendheaders"
+        ));
+    }
+
+    #[test]
+    fn test_invalid_frames() {
+        let read = |bytes: Vec<u8>| {
+            read_message(&mut Cursor::new(&bytes), MAGIC, BlockFormat::Bitcoin)
+                .unwrap_err()
+                .to_string()
+        };
+        // Testnet magic
+        let mut bytes = utils::hex_to_vec(VERACK);
+        bytes[..4].copy_from_slice(&0x0709110bu32.to_le_bytes());
+        assert!(read(bytes).contains("magic 0709110b"));
+
+        let mut bytes = utils::hex_to_vec(PING);
+        *bytes.last_mut().unwrap() ^= 1;
+        assert!(read(bytes).contains("Checksum"));
+
+        let mut bytes = utils::hex_to_vec(VERACK);
+        bytes[16..20].copy_from_slice(&(MAX_PAYLOAD + 1).to_le_bytes());
+        assert!(read(bytes).contains("bytes"));
+
+        let mut bytes = utils::hex_to_vec(VERACK);
+        bytes[11] = b'x';
+        assert!(read(bytes).contains("Invalid command"));
+
+        // Truncated
+        let bytes = utils::hex_to_vec(PING);
+        assert!(read(bytes[..30].to_vec()).contains("I/O"));
+    }
+}
@@ -0,0 +1,626 @@
+//! Minimal client of the P2P protocol. Downloads the blocks missing in the blockchain directory
+//! from a single node, see `--p2p-fallback`.
+//!
+//! Only the handshake, `getheaders` and `getdata` are implemented. Headers are linked to the
+//! last known block and every block has to match the hash it was requested by, anything else
+//! is treated as misbehavior of the peer.
+
+use std::collections::VecDeque;
+use std::io::{self, BufReader};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::process;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::blockchain::parser::reader::BlockchainRead;
+use crate::blockchain::parser::types::CoinType;
+use crate::blockchain::proto::block::BlockFormat;
+use crate::blockchain::proto::header::BlockHeader;
+use crate::blockchain::proto::Hashed;
+use crate::common::utils;
+use crate::errors::{OpError, OpErrorKind, OpResult};
+
+use self::message::{Inventory, Message, NetAddr, Version};
+
+pub mod message;
+
+/// Blocks requested by a single `getdata`
+const BLOCKS_PER_REQUEST: usize = 16;
+
+/// Connection to a node after the handshake
+pub struct Peer {
+    addr: SocketAddr,
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    magic: u32,
+    format: BlockFormat,
+    // Each answer must arrive within this time
+    timeout: Duration,
+    // The peer serves blocks with their witnesses, as stored in the blk files
+    witness: bool,
+    version: Version,
+}
+
+impl Peer {
+    /// Connects to the first reachable address and exchanges `version` and `verack`
+    pub fn connect(
+        addr: &str,
+        magic: u32,
+        format: BlockFormat,
+        timeout: Duration,
+        start_height: u64,
+    ) -> OpResult<Self> {
+        let mut last_err =
+            io::Error::new(io::ErrorKind::NotFound, "the hostname resolves to nothing");
+        for socket_addr in addr.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&socket_addr, timeout) {
+                Ok(stream) => return Peer::handshake(stream, magic, format, timeout, start_height),
+                Err(e) => last_err = e,
+            }
+        }
+        Err(OpError::from(last_err).join_msg(&format!("Unable to connect to {}.", addr)))
+    }
+
+    fn handshake(
+        stream: TcpStream,
+        magic: u32,
+        format: BlockFormat,
+        timeout: Duration,
+        start_height: u64,
+    ) -> OpResult<Self> {
+        let addr = stream.peer_addr()?;
+        stream.set_nodelay(true)?;
+        stream.set_write_timeout(Some(timeout))?;
+        let writer = stream.try_clone()?;
+        let mut peer = Peer {
+            addr,
+            reader: BufReader::new(stream),
+            writer,
+            magic,
+            format,
+            timeout,
+            witness: false,
+            version: Version {
+                version: 0,
+                services: 0,
+                timestamp: 0,
+                receiver: NetAddr::unspecified(0),
+                sender: NetAddr::unspecified(0),
+                nonce: 0,
+                user_agent: String::new(),
+                start_height: 0,
+                relay: false,
+            },
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        peer.send(&Message::Version(Version {
+            version: message::PROTOCOL_VERSION,
+            // Serves nothing
+            services: 0,
+            timestamp: now.as_secs() as i64,
+            receiver: NetAddr::from_socket_addr(addr, 0),
+            sender: NetAddr::unspecified(0),
+            // Detects connections to ourselves, which can't happen here
+            nonce: now.as_nanos() as u64 ^ process::id() as u64,
+            user_agent: format!("/rusty-blockparser:{}/", env!("CARGO_PKG_VERSION")),
+            start_height: start_height.min(i32::MAX as u64) as i32,
+            relay: false,
+        }))?;
+
+        let deadline = Instant::now() + timeout;
+        let (mut got_version, mut got_verack) = (false, false);
+        while !(got_version && got_verack) {
+            match peer.receive(deadline)? {
+                Message::Version(version) if !got_version => {
+                    if version.version < message::MIN_PEER_VERSION {
+                        return Err(peer.misbehaved(&format!(
+                            "protocol version {} doesn't support getheaders",
+                            version.version
+                        )));
+                    }
+                    if version.services & (message::NODE_NETWORK | message::NODE_NETWORK_LIMITED)
+                        == 0
+                    {
+                        return Err(peer.misbehaved("the node doesn't serve blocks"));
+                    }
+                    if version.services & message::NODE_NETWORK == 0 {
+                        warn!(target: "network", "{} is pruned and only serves recent blocks.", addr);
+                    }
+                    peer.witness = version.services & message::NODE_WITNESS != 0;
+                    peer.version = version;
+                    got_version = true;
+                    peer.send(&Message::Verack)?;
+                }
+                Message::Verack if got_version => got_verack = true,
+                // Addresses, fee filters and the like
+                Message::Other(_) => (),
+                other => {
+                    return Err(peer.misbehaved(&format!(
+                        "unexpected `{}` during the handshake",
+                        other.command()
+                    )))
+                }
+            }
+        }
+        Ok(peer)
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// What the peer sent in its `version`
+    pub fn version(&self) -> &Version {
+        &self.version
+    }
+
+    pub fn send(&mut self, message: &Message) -> OpResult<()> {
+        trace!(target: "network", "Sending `{}` to {}", message.command(), self.addr);
+        message::write_message(&mut self.writer, self.magic, message)
+            .map_err(|e| OpError::from(e).join_msg(&format!("Unable to send to {}.", self.addr)))
+    }
+
+    /// Returns the next message which isn't a ping, pings are answered.
+    /// Fails if nothing arrives before the deadline.
+    pub fn receive(&mut self, deadline: Instant) -> OpResult<Message> {
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(self.timed_out());
+            }
+            self.reader.get_ref().set_read_timeout(Some(remaining))?;
+            let message = match message::read_message(&mut self.reader, self.magic, self.format) {
+                Ok(message) => message,
+                Err(OpError {
+                    kind: OpErrorKind::IoError(e),
+                    ..
+                }) if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+                {
+                    return Err(self.timed_out())
+                }
+                Err(OpError {
+                    kind: OpErrorKind::IoError(e),
+                    ..
+                }) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    return Err(OpError::from(format!(
+                        "{} closed the connection",
+                        self.addr
+                    )))
+                }
+                Err(e) => return Err(self.misbehaved(&e.to_string())),
+            };
+            trace!(target: "network", "Received `{}` from {}", message.command(), self.addr);
+            match message {
+                Message::Ping(nonce) => self.send(&Message::Pong(nonce))?,
+                message => return Ok(message),
+            }
+        }
+    }
+
+    /// Returns the headers following the first hash of the locator the peer knows
+    pub fn get_headers(&mut self, locator: &[[u8; 32]]) -> OpResult<Vec<Hashed<BlockHeader>>> {
+        self.send(&Message::GetHeaders {
+            locator: locator.to_vec(),
+            stop: [0u8; 32],
+        })?;
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            match self.receive(deadline)? {
+                Message::Headers(headers) => return Ok(headers),
+                // Announcements of new blocks and transactions, or requests of the peer
+                Message::Other(_)
+                | Message::GetHeaders { .. }
+                | Message::GetData(_)
+                | Message::Pong(_) => (),
+                other => {
+                    return Err(self.misbehaved(&format!(
+                        "unexpected `{}` while waiting for headers",
+                        other.command()
+                    )))
+                }
+            }
+        }
+    }
+
+    /// Downloads the serialized blocks in the given order, each must match its hash
+    pub fn get_blocks(&mut self, hashes: &[[u8; 32]]) -> OpResult<Vec<Vec<u8>>> {
+        let inventory = hashes
+            .iter()
+            .map(|hash| Inventory::block(*hash, self.witness))
+            .collect();
+        self.send(&Message::GetData(inventory))?;
+        let mut blocks: Vec<Option<Vec<u8>>> = vec![None; hashes.len()];
+        let mut n_received = 0;
+        // Each block may take the full timeout
+        let mut deadline = Instant::now() + self.timeout;
+        while n_received < hashes.len() {
+            match self.receive(deadline)? {
+                Message::Block(raw) => {
+                    let hash = block_hash(&raw, self.format)
+                        .map_err(|e| self.misbehaved(&format!("unreadable block: {}", e)))?;
+                    match hashes.iter().position(|h| *h == hash) {
+                        Some(i) if blocks[i].is_none() => {
+                            blocks[i] = Some(raw);
+                            n_received += 1;
+                            deadline = Instant::now() + self.timeout;
+                        }
+                        _ => {
+                            return Err(self.misbehaved(&format!(
+                                "sent block {} which wasn't requested",
+                                utils::arr_to_hex_swapped(&hash)
+                            )))
+                        }
+                    }
+                }
+                Message::NotFound(inventory) => {
+                    let hash = inventory.first().map_or([0u8; 32], |inv| inv.hash);
+                    return Err(OpError::from(format!(
+                        "{} doesn't have block {}",
+                        self.addr,
+                        utils::arr_to_hex_swapped(&hash)
+                    )));
+                }
+                Message::Other(_)
+                | Message::GetHeaders { .. }
+                | Message::GetData(_)
+                | Message::Headers(_)
+                | Message::Pong(_) => (),
+                other => {
+                    return Err(self.misbehaved(&format!(
+                        "unexpected `{}` while waiting for blocks",
+                        other.command()
+                    )))
+                }
+            }
+        }
+        Ok(blocks.into_iter().flatten().collect())
+    }
+
+    fn timed_out(&self) -> OpError {
+        OpError::from(format!(
+            "{} didn't answer within {} seconds",
+            self.addr,
+            self.timeout.as_secs_f32()
+        ))
+    }
+
+    fn misbehaved(&self, reason: &str) -> OpError {
+        OpError::from(format!("{} misbehaved: {}", self.addr, reason))
+    }
+}
+
+/// Hash of the serialized block, the one of its header
+pub fn block_hash(raw: &[u8], format: BlockFormat) -> OpResult<[u8; 32]> {
+    let header = io::Cursor::new(raw).read_block_header_of(format)?;
+    Ok(Hashed::double_sha256(header).hash)
+}
+
+/// Block downloaded by `Fallback`
+#[derive(Debug)]
+pub struct FetchedBlock {
+    pub height: u64,
+    pub hash: [u8; 32],
+    pub raw: Vec<u8>,
+}
+
+/// Downloads blocks missing on disk and the blocks past the tip of the index from a peer.
+/// The connection is established on first use.
+pub struct Fallback {
+    addr: String,
+    magic: u32,
+    format: BlockFormat,
+    timeout: Duration,
+    peer: Option<Peer>,
+    // Last linked header, the locator of the next `getheaders`
+    tip: (u64, [u8; 32]),
+    // Linked headers whose blocks weren't requested yet
+    headers: VecDeque<(u64, [u8; 32])>,
+    // Downloaded blocks in height order
+    blocks: VecDeque<FetchedBlock>,
+    // Last height to download
+    end: Option<u64>,
+    // The peer sent less than a full batch of headers, so its tip is reached
+    exhausted: bool,
+    n_fetched: u64,
+}
+
+impl Fallback {
+    /// Continues after the given tip, or from the genesis block without one
+    pub fn new(
+        addr: &str,
+        coin_type: &CoinType,
+        timeout: Duration,
+        tip: Option<(u64, [u8; 32])>,
+        end: Option<u64>,
+    ) -> Self {
+        let mut headers = VecDeque::new();
+        let tip = match tip {
+            Some(tip) => tip,
+            None => {
+                headers.push_back((0, coin_type.genesis_hash));
+                (0, coin_type.genesis_hash)
+            }
+        };
+        Fallback {
+            addr: String::from(addr),
+            magic: coin_type.magic,
+            format: coin_type.block_format,
+            timeout,
+            peer: None,
+            tip,
+            headers,
+            blocks: VecDeque::new(),
+            end,
+            exhausted: false,
+            n_fetched: 0,
+        }
+    }
+
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    /// Downloads a single block, e.g. one which couldn't be read from disk
+    pub fn fetch(&mut self, hash: [u8; 32]) -> OpResult<Vec<u8>> {
+        let raw = transform!(self.peer()?.get_blocks(&[hash])?.pop());
+        self.n_fetched += 1;
+        Ok(raw)
+    }
+
+    /// Returns the next block past the tip at or above the given height, None once the tip
+    /// of the peer or the end of the range is reached
+    pub fn next_block(&mut self, height: u64) -> OpResult<Option<FetchedBlock>> {
+        loop {
+            // Heights below were skipped, e.g. when resuming
+            while self.blocks.front().is_some_and(|b| b.height < height) {
+                self.blocks.pop_front();
+            }
+            if let Some(block) = self.blocks.pop_front() {
+                self.n_fetched += 1;
+                return Ok(Some(block));
+            }
+            while self.headers.front().is_some_and(|(h, _)| *h < height) {
+                self.headers.pop_front();
+            }
+            // Keeps the batches full
+            let tip_reached = self.exhausted || self.end.is_some_and(|end| self.tip.0 >= end);
+            if self.headers.len() < BLOCKS_PER_REQUEST && !tip_reached {
+                self.request_headers()?;
+                continue;
+            }
+            if self.headers.is_empty() {
+                info!(target: "network", "Fetched {} blocks from {}, the last one at height {}.",
+                      self.n_fetched, self.addr, self.tip.0);
+                return Ok(None);
+            }
+            let n = self.headers.len().min(BLOCKS_PER_REQUEST);
+            let batch: Vec<(u64, [u8; 32])> = self.headers.drain(..n).collect();
+            let hashes: Vec<[u8; 32]> = batch.iter().map(|(_, hash)| *hash).collect();
+            let raws = self.peer()?.get_blocks(&hashes)?;
+            self.blocks.extend(
+                batch
+                    .into_iter()
+                    .zip(raws)
+                    .map(|((height, hash), raw)| FetchedBlock { height, hash, raw }),
+            );
+        }
+    }
+
+    /// Appends the headers following the tip, they must link to it
+    fn request_headers(&mut self) -> OpResult<()> {
+        let (mut height, mut prev_hash) = self.tip;
+        let headers = self.peer()?.get_headers(&[prev_hash])?;
+        debug!(target: "network", "Received {} headers after block {}.", headers.len(), height);
+        if headers.len() < message::MAX_HEADERS {
+            self.exhausted = true;
+        }
+        for header in headers {
+            if header.value.prev_hash != prev_hash {
+                return Err(OpError::from(format!(
+                    "Headers of {} don't link to block {} ({}), it may follow another chain",
+                    self.addr,
+                    height,
+                    utils::arr_to_hex_swapped(&prev_hash)
+                )));
+            }
+            height += 1;
+            prev_hash = header.hash;
+            if self.end.is_some_and(|end| height > end) {
+                break;
+            }
+            self.headers.push_back((height, header.hash));
+            self.tip = (height, header.hash);
+        }
+        Ok(())
+    }
+
+    fn peer(&mut self) -> OpResult<&mut Peer> {
+        if self.peer.is_none() {
+            info!(target: "network", "Connecting to {} ...", self.addr);
+            let peer = Peer::connect(
+                &self.addr,
+                self.magic,
+                self.format,
+                self.timeout,
+                self.tip.0,
+            )?;
+            info!(target: "network", "Connected to {} ({}, height {}).",
+                  peer.addr(), peer.version().user_agent, peer.version().start_height);
+            self.peer = Some(peer);
+        }
+        Ok(transform!(self.peer.as_mut()))
+    }
+}
+
+/// Serves the chain of serialized blocks like a node would, see `message`.
+/// After sending `stall_after` blocks it stops answering.
+#[cfg(test)]
+pub fn fake_peer(magic: u32, chain: Vec<Vec<u8>>, stall_after: Option<usize>) -> SocketAddr {
+    use std::net::TcpListener;
+    use std::thread;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let hashes: Vec<[u8; 32]> = chain
+        .iter()
+        .map(|raw| block_hash(raw, BlockFormat::Bitcoin).unwrap())
+        .collect();
+    thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut writer = stream;
+        let mut n_sent = 0;
+        let send = |writer: &mut TcpStream, message: Message| {
+            message::write_message(writer, magic, &message).unwrap()
+        };
+        while let Ok(message) = message::read_message(&mut reader, magic, BlockFormat::Bitcoin) {
+            match message {
+                Message::Version(_) => {
+                    send(
+                        &mut writer,
+                        Message::Version(Version {
+                            version: message::PROTOCOL_VERSION,
+                            services: message::NODE_NETWORK | message::NODE_WITNESS,
+                            timestamp: 0,
+                            receiver: NetAddr::unspecified(0),
+                            sender: NetAddr::unspecified(0),
+                            nonce: 1,
+                            user_agent: String::from("/fake:0.1/"),
+                            start_height: chain.len() as i32 - 1,
+                            relay: false,
+                        }),
+                    );
+                    send(&mut writer, Message::Ping(7));
+                    send(&mut writer, Message::Verack);
+                }
+                Message::GetHeaders { locator, .. } => {
+                    // Unknown locators start after the genesis block
+                    let start = hashes
+                        .iter()
+                        .position(|h| *h == locator[0])
+                        .map_or(1, |i| i + 1);
+                    let headers = chain[start..]
+                        .iter()
+                        .take(message::MAX_HEADERS)
+                        .map(|raw| {
+                            let header = io::Cursor::new(raw).read_block_header().unwrap();
+                            Hashed::double_sha256(header)
+                        })
+                        .collect();
+                    send(&mut writer, Message::Headers(headers));
+                }
+                Message::GetData(inventory) => {
+                    for inv in inventory {
+                        if stall_after.is_some_and(|n| n_sent >= n) {
+                            // Keeps the connection open without answering
+                            thread::sleep(Duration::from_secs(60));
+                        }
+                        match hashes.iter().position(|h| *h == inv.hash) {
+                            Some(i) => send(&mut writer, Message::Block(chain[i].clone())),
+                            None => send(&mut writer, Message::NotFound(vec![inv])),
+                        }
+                        n_sent += 1;
+                    }
+                }
+                _ => (),
+            }
+        }
+    });
+    addr
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::parser::types::Bitcoin;
+    use crate::blockchain::proto::ToRaw;
+    use crate::callbacks::fixtures::{new_chain, new_tx};
+
+    fn chain(n_blocks: usize) -> Vec<Vec<u8>> {
+        let blocks = (0..n_blocks)
+            .map(|i| vec![new_tx(&[], &[i as u64 + 1])])
+            .collect();
+        new_chain(blocks).iter().map(|b| b.to_bytes()).collect()
+    }
+
+    fn new_fallback(addr: SocketAddr, tip: Option<(u64, [u8; 32])>) -> Fallback {
+        let mut coin_type = CoinType::from(Bitcoin);
+        coin_type.genesis_hash = block_hash(&chain(1)[0], BlockFormat::Bitcoin).unwrap();
+        Fallback::new(
+            &addr.to_string(),
+            &coin_type,
+            Duration::from_millis(500),
+            tip,
+            None,
+        )
+    }
+
+    #[test]
+    fn test_fetch_past_tip() {
+        let chain = chain(40);
+        let magic = CoinType::from(Bitcoin).magic;
+        let tip_hash = block_hash(&chain[9], BlockFormat::Bitcoin).unwrap();
+        let mut fallback = new_fallback(fake_peer(magic, chain.clone(), None), Some((9, tip_hash)));
+        // Resumed at 12
+        for height in 12..40 {
+            let block = fallback.next_block(height).unwrap().unwrap();
+            assert_eq!(height, block.height);
+            assert_eq!(chain[height as usize], block.raw);
+            assert_eq!(
+                block_hash(&block.raw, BlockFormat::Bitcoin).unwrap(),
+                block.hash
+            );
+        }
+        assert!(fallback.next_block(40).unwrap().is_none());
+        // Missing blocks by their hash
+        let hash = block_hash(&chain[3], BlockFormat::Bitcoin).unwrap();
+        assert_eq!(chain[3], fallback.fetch(hash).unwrap());
+    }
+
+    #[test]
+    fn test_fetch_from_genesis() {
+        let chain = chain(3);
+        let magic = CoinType::from(Bitcoin).magic;
+        let mut fallback = new_fallback(fake_peer(magic, chain.clone(), None), None);
+        for (height, raw) in chain.iter().enumerate() {
+            assert_eq!(
+                raw,
+                &fallback.next_block(height as u64).unwrap().unwrap().raw
+            );
+        }
+        assert!(fallback.next_block(3).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_misbehaving_peers() {
+        let chain = chain(30);
+        let magic = CoinType::from(Bitcoin).magic;
+        // Blocks of another chain
+        let mut fallback =
+            new_fallback(fake_peer(magic, chain.clone(), None), Some((0, [1u8; 32])));
+        let err = fallback.next_block(1).unwrap_err().to_string();
+        assert!(err.contains("don't link to block 0"), "{}", err);
+        // Unknown block
+        let mut fallback = new_fallback(fake_peer(magic, chain.clone(), None), None);
+        assert!(fallback.fetch([1u8; 32]).is_err());
+
+        // Timeout in the middle of a batch
+        let mut fallback = new_fallback(fake_peer(magic, chain.clone(), Some(20)), None);
+        let t_start = Instant::now();
+        for height in 0..16 {
+            assert!(fallback.next_block(height).unwrap().is_some());
+        }
+        let err = fallback.next_block(16).unwrap_err().to_string();
+        assert!(err.contains("didn't answer within 0.5 seconds"), "{}", err);
+        assert!(t_start.elapsed() < Duration::from_secs(10));
+
+        // Wrong network
+        let addr = fake_peer(0x0709110b, chain, None);
+        let mut fallback = new_fallback(addr, None);
+        assert!(fallback.next_block(0).is_err());
+    }
+}
@@ -1,39 +1,148 @@
 use std::collections::HashMap;
 use std::convert::From;
 use std::fs::{self, DirEntry, File};
-use std::io::{self, BufReader, Seek, SeekFrom};
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::ops::Deref;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use byteorder::{LittleEndian, ReadBytesExt};
 
 use crate::blockchain::parser::reader::BlockchainRead;
-use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::block::{Block, BlockFormat};
+use crate::blockchain::proto::script::AddressFormat;
+use crate::common::mmap::Mmap;
+use crate::common::utils;
 use crate::errors::{OpError, OpErrorKind, OpResult};
 
+/// Name of the file with the obfuscation key in the blocks directory (Bitcoin Core 28+)
+const XOR_KEY_FILE: &str = "xor.dat";
+
 /// Holds all necessary data about a raw blk file
 #[derive(Debug)]
 pub struct BlkFile {
     pub path: PathBuf,
     pub size: u64,
+    /// Obfuscation key, all zero if the file is not obfuscated
+    pub xor_key: [u8; 8],
+    /// Network magic of the coin which precedes each block
+    pub magic: u32,
 }
 
 impl BlkFile {
     #[inline]
-    fn new(path: PathBuf, size: u64) -> BlkFile {
-        BlkFile { path, size }
+    fn new(path: PathBuf, size: u64, xor_key: [u8; 8], magic: u32) -> BlkFile {
+        BlkFile {
+            path,
+            size,
+            xor_key,
+            magic,
+        }
+    }
+
+    /// Reads the block at the given offset, only its header if `with_txs` is false
+    #[inline]
+    pub fn read_block(
+        &self,
+        offset: u64,
+        address_format: AddressFormat,
+        format: BlockFormat,
+        with_txs: bool,
+    ) -> OpResult<Block> {
+        let mut f = BufReader::new(XorReader::new(File::open(&self.path)?, self.xor_key));
+        let block_size = self.read_block_size(&mut f, offset)?;
+        if with_txs {
+            f.read_block(block_size, address_format, format)
+        } else {
+            f.read_block_without_txs(block_size, format)
+        }
+    }
+
+    /// Reads the serialized block at the given offset, to be deserialized somewhere else
+    pub fn read_raw_block(&self, offset: u64) -> OpResult<Vec<u8>> {
+        let mut f = BufReader::new(XorReader::new(File::open(&self.path)?, self.xor_key));
+        let block_size = self.read_block_size(&mut f, offset)?;
+        if offset + block_size as u64 > f.get_ref().inner.metadata()?.len() {
+            return Err(self.exceeds_eof(offset));
+        }
+        f.read_u8_vec(block_size)
+    }
+
+    /// Checks the magic in front of the block at `offset` and returns the size which follows it
+    pub fn read_block_size<R: Read + Seek>(&self, f: &mut R, offset: u64) -> OpResult<u32> {
+        if offset < 8 {
+            return Err(OpError::from(format!(
+                "Block offset {} out of bounds",
+                offset
+            )));
+        }
+        f.seek(SeekFrom::Start(offset - 8))?;
+        let magic = f.read_u32::<LittleEndian>()?;
+        if magic != self.magic {
+            return Err(OpError::from(format!(
+                "Invalid magic {:#010x} in front of the block at offset {}",
+                magic, offset
+            )));
+        }
+        Ok(f.read_u32::<LittleEndian>()?)
     }
 
     #[inline]
-    pub fn read_block(&self, offset: u64, version_id: u8) -> OpResult<Block> {
-        let mut f = BufReader::new(File::open(&self.path)?);
-        f.seek(SeekFrom::Start(offset - 4))?;
-        let block_size = f.read_u32::<LittleEndian>()?;
-        f.read_block(block_size, version_id)
+    fn exceeds_eof(&self, offset: u64) -> OpError {
+        OpError::from(format!(
+            "Block at offset {} exceeds the end of {}",
+            offset,
+            self.path.display()
+        ))
+    }
+
+    /// Returns the position of the next magic from `from` on, the file size if there is none.
+    /// Used to skip a corrupt region.
+    pub fn next_magic(&self, from: u64) -> OpResult<u64> {
+        let mut f = XorReader::new(File::open(&self.path)?, self.xor_key);
+        let from = f.seek(SeekFrom::Start(from))?;
+        let mut rest = Vec::new();
+        f.read_to_end(&mut rest)?;
+        Ok(from + find_magic(&rest, self.magic).unwrap_or(rest.len()) as u64)
     }
 
-    /// Collects all blk*.dat paths in the given directory
-    pub fn from_path(path: &Path) -> OpResult<HashMap<usize, BlkFile>> {
+    /// Maps the file into memory, None if it's obfuscated and can't be read in place
+    pub fn map(&self) -> OpResult<Option<Mmap>> {
+        if self.xor_key != [0u8; 8] {
+            return Ok(None);
+        }
+        Ok(Some(Mmap::map(&File::open(&self.path)?)?))
+    }
+
+    /// Borrows the serialized block at the given offset from the mapped file
+    pub fn slice_block(&self, map: &Arc<Mmap>, offset: u64) -> OpResult<RawBlock> {
+        let start = offset as usize;
+        if start > map.len() {
+            return Err(self.exceeds_eof(offset));
+        }
+        let size = self.read_block_size(&mut io::Cursor::new(&map[..]), offset)?;
+        let end = start + size as usize;
+        if end > map.len() {
+            return Err(self.exceeds_eof(offset));
+        }
+        Ok(RawBlock::Mapped(Arc::clone(map), start, end))
+    }
+
+    /// Collects all blk*.dat paths in the given directory.
+    /// The obfuscation key is read from `xor.dat` unless `xor_key` overrides it.
+    pub fn from_path(
+        path: &Path,
+        xor_key: Option<[u8; 8]>,
+        magic: u32,
+    ) -> OpResult<HashMap<usize, BlkFile>> {
         info!(target: "blkfile", "Reading files from {} ...", path.display());
+        let xor_key = match xor_key {
+            Some(key) => key,
+            None => BlkFile::read_xor_key(path)?,
+        };
+        if xor_key != [0u8; 8] {
+            info!(target: "blkfile", "Using obfuscation key {}", utils::arr_to_hex(&xor_key));
+        }
         let mut collected = HashMap::with_capacity(4000);
 
         for entry in fs::read_dir(path)? {
@@ -51,7 +160,7 @@ impl BlkFile {
                         // Build BlkFile structures
                         let size = fs::metadata(path.as_path())?.len();
                         trace!(target: "blkfile", "Adding {}... (index: {}, size: {})", path.display(), index, size);
-                        collected.insert(index, BlkFile::new(path, size));
+                        collected.insert(index, BlkFile::new(path, size, xor_key, magic));
                     }
                 }
                 Err(msg) => {
@@ -68,6 +177,34 @@ impl BlkFile {
         }
     }
 
+    /// Reads the key of `xor.dat`, datadirs of older versions have none and aren't obfuscated
+    fn read_xor_key(path: &Path) -> OpResult<[u8; 8]> {
+        let path = path.join(XOR_KEY_FILE);
+        if !path.is_file() {
+            return Ok([0u8; 8]);
+        }
+        let bytes = fs::read(&path)?;
+        if bytes.len() != 8 {
+            return Err(OpError::from(format!(
+                "{} must contain 8 bytes, found {}",
+                path.display(),
+                bytes.len()
+            )));
+        }
+        let mut key = [0u8; 8];
+        key.copy_from_slice(&bytes);
+        Ok(key)
+    }
+
+    /// Parses the 16 hex characters of `--xor-key`
+    pub fn parse_xor_key(hex: &str) -> OpResult<[u8; 8]> {
+        utils::hex_to_arr(hex).map_err(|_| {
+            OpError::from(String::from(
+                "--xor-key must be 16 hex characters (8 bytes)",
+            ))
+        })
+    }
+
     /// Resolves a PathBuf for the given entry.
     /// Also resolves symlinks if present.
     fn resolve_path(entry: &DirEntry) -> io::Result<PathBuf> {
@@ -92,9 +229,297 @@ impl BlkFile {
     }
 }
 
+/// Finds the first occurrence of the little endian magic
+pub fn find_magic(data: &[u8], magic: u32) -> Option<usize> {
+    let magic = magic.to_le_bytes();
+    let mut pos = 0;
+    while let Some(i) = memchr::memchr(magic[0], &data[pos..]) {
+        let start = pos + i;
+        if data[start..].starts_with(&magic) {
+            return Some(start);
+        }
+        pos = start + 1;
+    }
+    None
+}
+
+/// Serialized block, either copied from the file or borrowed from its memory map
+pub enum RawBlock {
+    Owned(Vec<u8>),
+    Mapped(Arc<Mmap>, usize, usize),
+}
+
+impl Deref for RawBlock {
+    type Target = [u8];
+
+    #[inline]
+    fn deref(&self) -> &[u8] {
+        match self {
+            RawBlock::Owned(bytes) => bytes,
+            RawBlock::Mapped(map, start, end) => &map[*start..*end],
+        }
+    }
+}
+
+/// Reads files obfuscated with a rolling XOR key like blk*.dat files of Bitcoin Core 28+.
+/// The key offset follows the absolute position in the file, seeks included.
+pub struct XorReader<R> {
+    inner: R,
+    key: [u8; 8],
+    pos: u64,
+}
+
+impl<R: Read + Seek> XorReader<R> {
+    pub fn new(inner: R, key: [u8; 8]) -> XorReader<R> {
+        XorReader { inner, key, pos: 0 }
+    }
+}
+
+impl<R: Read> Read for XorReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if self.key != [0u8; 8] {
+            for (i, byte) in buf[..n].iter_mut().enumerate() {
+                *byte ^= self.key[((self.pos + i as u64) % 8) as usize];
+            }
+        }
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: Seek> Seek for XorReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = self.inner.seek(pos)?;
+        Ok(self.pos)
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use std::env;
+    use std::io::Cursor;
+    use std::process;
+
     use super::*;
+    use crate::blockchain::proto::ToRaw;
+
+    const MAGIC: u32 = 0xd9b4bef9;
+    const GENESIS_BLOCK: &str = concat!(
+        "0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b2",
+        "7ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a29ab5f49ffff001d1dac2b7c0101000000010000",
+        "000000000000000000000000000000000000000000000000000000000000ffffffff4d04ffff001d01044554",
+        "68652054696d65732030332f4a616e2f32303039204368616e63656c6c6f72206f6e206272696e6b206f6620",
+        "7365636f6e64206261696c6f757420666f722062616e6b73ffffffff0100f2052a01000000434104678afdb0",
+        "fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de",
+        "5c384df7ba0b8d578a4c702b6bf11d5fac00000000",
+    );
+
+    /// Writes a blk file with the genesis block twice, returns the offsets of both blocks
+    fn write_blk_file(dir: &Path, key: Option<[u8; 8]>) -> Vec<u64> {
+        fs::create_dir_all(dir).unwrap();
+        let block = utils::hex_to_vec(GENESIS_BLOCK);
+        let mut data = Vec::new();
+        let mut offsets = Vec::new();
+        for _ in 0..2 {
+            data.extend_from_slice(&MAGIC.to_le_bytes());
+            data.extend_from_slice(&(block.len() as u32).to_le_bytes());
+            offsets.push(data.len() as u64);
+            data.extend_from_slice(&block);
+        }
+        if let Some(key) = key {
+            for (i, byte) in data.iter_mut().enumerate() {
+                *byte ^= key[i % 8];
+            }
+            fs::write(dir.join(XOR_KEY_FILE), key).unwrap();
+        }
+        fs::write(dir.join("blk00000.dat"), data).unwrap();
+        offsets
+    }
+
+    #[test]
+    fn test_read_obfuscated_blk_file() {
+        let key = [0x5b, 0x13, 0xf4, 0x07, 0xe2, 0xa8, 0x31, 0xcc];
+        let base = env::temp_dir().join(format!("blockparser-blkfile-{}", process::id()));
+        let plain_dir = base.join("plain");
+        let xor_dir = base.join("xor");
+        let offsets = write_blk_file(&plain_dir, None);
+        write_blk_file(&xor_dir, Some(key));
+
+        let plain = BlkFile::from_path(&plain_dir, None, MAGIC).unwrap();
+        let obfuscated = BlkFile::from_path(&xor_dir, None, MAGIC).unwrap();
+        assert_eq!([0u8; 8], plain[&0].xor_key);
+        assert_eq!(key, obfuscated[&0].xor_key);
+
+        for offset in offsets.iter() {
+            let expected = plain[&0]
+                .read_block(
+                    *offset,
+                    AddressFormat::default(),
+                    BlockFormat::Bitcoin,
+                    true,
+                )
+                .unwrap();
+            let block = obfuscated[&0]
+                .read_block(
+                    *offset,
+                    AddressFormat::default(),
+                    BlockFormat::Bitcoin,
+                    true,
+                )
+                .unwrap();
+            assert_eq!(GENESIS_BLOCK, utils::arr_to_hex(&expected.to_bytes()));
+            assert_eq!(expected.to_bytes(), block.to_bytes());
+            assert_eq!(expected.header.hash, block.header.hash);
+        }
+
+        // The override wins over xor.dat, a zero key means no obfuscation
+        let overridden = BlkFile::from_path(&plain_dir, Some([0u8; 8]), MAGIC).unwrap();
+        let block = overridden[&0]
+            .read_block(
+                offsets[1],
+                AddressFormat::default(),
+                BlockFormat::Bitcoin,
+                false,
+            )
+            .unwrap();
+        assert_eq!(
+            "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f",
+            utils::arr_to_hex_swapped(&block.header.hash)
+        );
+        assert!(
+            BlkFile::from_path(&xor_dir, Some([0u8; 8]), MAGIC).unwrap()[&0]
+                .read_block(
+                    offsets[0],
+                    AddressFormat::default(),
+                    BlockFormat::Bitcoin,
+                    true
+                )
+                .is_err()
+        );
+
+        fs::write(xor_dir.join(XOR_KEY_FILE), [1u8; 4]).unwrap();
+        assert!(BlkFile::from_path(&xor_dir, None, MAGIC).is_err());
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_slice_block() {
+        let dir = env::temp_dir().join(format!("blockparser-blkfile-mmap-{}", process::id()));
+        let offsets = write_blk_file(&dir, None);
+        let blk_files = BlkFile::from_path(&dir, None, MAGIC).unwrap();
+        let map = Arc::new(blk_files[&0].map().unwrap().unwrap());
+        for offset in offsets.iter() {
+            let mapped = blk_files[&0].slice_block(&map, *offset).unwrap();
+            assert_eq!(blk_files[&0].read_raw_block(*offset).unwrap(), &mapped[..]);
+            assert_eq!(GENESIS_BLOCK, utils::arr_to_hex(&mapped));
+        }
+
+        // The second block is cut off by the end of the file
+        let path = dir.join("blk00000.dat");
+        let data = fs::read(&path).unwrap();
+        fs::write(&path, &data[..data.len() - 1]).unwrap();
+        let map = Arc::new(blk_files[&0].map().unwrap().unwrap());
+        assert!(blk_files[&0].slice_block(&map, offsets[0]).is_ok());
+        assert!(blk_files[&0].slice_block(&map, offsets[1]).is_err());
+        assert!(blk_files[&0]
+            .slice_block(&map, data.len() as u64 + 8)
+            .is_err());
+
+        // Obfuscated files aren't mapped
+        write_blk_file(&dir, Some([1u8; 8]));
+        let blk_files = BlkFile::from_path(&dir, None, MAGIC).unwrap();
+        assert!(blk_files[&0].map().unwrap().is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_find_magic() {
+        let magic = MAGIC.to_le_bytes();
+        assert_eq!(None, find_magic(&[], MAGIC));
+        assert_eq!(Some(0), find_magic(&magic, MAGIC));
+        // Partial matches are skipped
+        let data = [&[0xf9, 0xbe, 0x00, 0xf9][..], &magic, &magic].concat();
+        assert_eq!(Some(4), find_magic(&data, MAGIC));
+        assert_eq!(None, find_magic(&data[..7], MAGIC));
+    }
+
+    #[test]
+    fn test_corrupt_blk_file() {
+        let key = [0x5b, 0x13, 0xf4, 0x07, 0xe2, 0xa8, 0x31, 0xcc];
+        let dir = env::temp_dir().join(format!("blockparser-blkfile-corrupt-{}", process::id()));
+        for key in [None, Some(key)] {
+            let offsets = write_blk_file(&dir, key);
+            let blk_file = &BlkFile::from_path(&dir, None, MAGIC).unwrap()[&0];
+            assert_eq!(offsets[1] - 8, blk_file.next_magic(1).unwrap());
+            assert_eq!(blk_file.size, blk_file.next_magic(offsets[1]).unwrap());
+
+            // Another coin's magic
+            let other = &BlkFile::from_path(&dir, None, 0x0709110b).unwrap()[&0];
+            assert!(other.read_raw_block(offsets[0]).is_err());
+            assert!(other
+                .read_block(
+                    offsets[0],
+                    AddressFormat::default(),
+                    BlockFormat::Bitcoin,
+                    false
+                )
+                .is_err());
+        }
+
+        // A size past the end of the file
+        fs::remove_dir_all(&dir).unwrap();
+        let path = dir.join("blk00000.dat");
+        let offsets = write_blk_file(&dir, None);
+        let mut data = fs::read(&path).unwrap();
+        let at = offsets[1] as usize - 4;
+        data[at..at + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        fs::write(&path, &data).unwrap();
+        let blk_file = &BlkFile::from_path(&dir, None, MAGIC).unwrap()[&0];
+        assert!(blk_file.read_raw_block(offsets[0]).is_ok());
+        assert!(blk_file.read_raw_block(offsets[1]).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_xor_reader() {
+        let key = [1, 2, 3, 4, 5, 6, 7, 8];
+        let plain: Vec<u8> = (0..32).collect();
+        let data: Vec<u8> = plain
+            .iter()
+            .enumerate()
+            .map(|(i, b)| b ^ key[i % 8])
+            .collect();
+
+        // Unaligned reads and seeks keep the key offset
+        let mut reader = XorReader::new(Cursor::new(data), key);
+        let mut buf = [0u8; 3];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(plain[..3], buf);
+        reader.seek(SeekFrom::Start(13)).unwrap();
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(plain[13..16], buf);
+        reader.seek(SeekFrom::Current(-5)).unwrap();
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert_eq!(plain[11..], rest[..]);
+
+        // A zero key leaves the data as is
+        let mut reader = XorReader::new(Cursor::new(plain.clone()), [0u8; 8]);
+        let mut all = Vec::new();
+        reader.read_to_end(&mut all).unwrap();
+        assert_eq!(plain, all);
+    }
+
+    #[test]
+    fn test_parse_xor_key() {
+        assert_eq!(
+            [0x5b, 0x13, 0xf4, 0x07, 0xe2, 0xa8, 0x31, 0xcc],
+            BlkFile::parse_xor_key("5b13f407e2a831cc").unwrap()
+        );
+        assert!(BlkFile::parse_xor_key("5b13f407e2a831").is_err());
+        assert!(BlkFile::parse_xor_key("5b13f407e2a831zz").is_err());
+    }
 
     #[test]
     fn test_parse_blk_index() {
@@ -117,13 +542,7 @@ mod tests {
             13412451,
             BlkFile::parse_blk_index("blk13412451.dat", prefix, ext).unwrap()
         );
-        assert_eq!(
-            true,
-            BlkFile::parse_blk_index("blkindex.dat", prefix, ext).is_none()
-        );
-        assert_eq!(
-            true,
-            BlkFile::parse_blk_index("invalid.dat", prefix, ext).is_none()
-        );
+        assert!(BlkFile::parse_blk_index("blkindex.dat", prefix, ext).is_none());
+        assert!(BlkFile::parse_blk_index("invalid.dat", prefix, ext).is_none());
     }
 }
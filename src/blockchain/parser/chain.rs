@@ -1,85 +1,858 @@
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Cursor, Read};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
 
-use crate::blockchain::parser::blkfile::BlkFile;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+
+use crate::blockchain::network::{Fallback, FetchedBlock};
+use crate::blockchain::parser::blkfile::{BlkFile, RawBlock};
 use crate::blockchain::parser::index::{get_block_index, BlockIndexRecord};
-use crate::blockchain::proto::block::Block;
+use crate::blockchain::parser::reader::BlockchainRead;
+use crate::blockchain::parser::stream::{self, BlockStream};
+use crate::blockchain::parser::timings::{self, Phase};
+use crate::blockchain::parser::undo;
+use crate::blockchain::proto::block::{Block, BlockFormat};
+use crate::blockchain::proto::script::AddressFormat;
+use crate::blockchain::proto::target;
+use crate::common::mmap::Mmap;
 use crate::common::utils;
-use crate::errors::OpResult;
+use crate::errors::{OpError, OpErrorKind, OpResult};
 use crate::ParserOptions;
 
+/// Blocks each thread may have in flight, read but not yet delivered.
+/// Bounds the memory if a thread is busy with a huge block.
+const BLOCKS_PER_THREAD: usize = 4;
+
+/// Files kept mapped at once, blocks in height order are mostly in the same or neighbouring files
+const MAPPED_FILES: usize = 4;
+
+/// Reads the longest chain of the block index, see `ChainStorage::rescan`
+type IndexLoader = Box<dyn FnMut() -> OpResult<Vec<BlockIndexRecord>>>;
+
 /// Holds the index of longest valid chain
 pub struct ChainStorage<'a> {
     blocks: Vec<BlockIndexRecord>,
+    // Reads the index again while following the tip
+    load_index: IndexLoader,
     index: usize,
     blk_files: HashMap<usize, BlkFile>,
     options: &'a RefCell<ParserOptions>,
+    // False if the callback only needs the block headers
+    with_txs: bool,
+    // Copied into the workers
+    settings: ParseSettings,
+    // Deserializes blocks in parallel, None with a single thread or headers only
+    workers: Option<Workers>,
+    // Reads blocks from memory maps instead of copying them, disabled by --no-mmap
+    mmap: bool,
+    // Recently used memory maps by file number, most recent first
+    maps: Vec<(usize, Arc<Mmap>)>,
+    // Skipped blocks, see `get_next`
+    corrupt: Vec<CorruptRegion>,
+    // Blocks which failed `--verify` and were skipped
+    invalid: Vec<InvalidBlock>,
+    // Hash of the last block returned by `get_next`, see `check_pow`
+    last_hash: Option<[u8; 32]>,
+    // Blocks are read from stdin or a named pipe instead of the blk files, see `from_stream`
+    stream: Option<BlockStream<Box<dyn Read>>>,
+    // Downloads blocks missing on disk and past the tip of the index, see `--p2p-fallback`
+    fallback: Option<Fallback>,
+}
+
+/// Byte range of a blk file skipped because the block in it couldn't be read
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CorruptRegion {
+    /// None if the bytes of a stream couldn't be framed as a block
+    pub height: Option<u64>,
+    pub path: PathBuf,
+    pub start: u64,
+    pub end: u64,
+}
+
+/// Block skipped because it failed a check of `--verify`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidBlock {
+    pub height: u64,
+    pub hash: [u8; 32],
+    pub reason: String,
+}
+
+/// Options of `parse_block`
+#[derive(Clone, Copy)]
+struct ParseSettings {
+    address_format: AddressFormat,
+    format: BlockFormat,
+    classify_inputs: bool,
+    // False if the callback doesn't need the witnesses, see `EvaluatedTx::drop_witnesses`
+    with_witnesses: bool,
+    // Checks the merkle root and witness commitment, see `Block::verify_merkle_root`
+    verify: bool,
+}
+
+/// Pool deserializing raw blocks and the results waiting to be delivered in order
+struct Workers {
+    pool: ThreadPool,
+    sender: Sender<(usize, OpResult<Block>)>,
+    receiver: Receiver<(usize, OpResult<Block>)>,
+    // Height of the next block to read
+    next_read: usize,
+    // Blocks being deserialized
+    in_flight: usize,
+    // Deserialized blocks by height, waiting for their predecessors
+    pending: BTreeMap<usize, OpResult<Block>>,
+    capacity: usize,
 }
 
 impl<'a> ChainStorage<'a> {
     #[inline]
     pub fn new(options: &'a RefCell<ParserOptions>) -> OpResult<Self> {
+        let block_files = options.borrow().block_files.clone();
+        if !block_files.is_empty() {
+            let reader = Box::new(stream::FileSequence::new(&block_files));
+            return ChainStorage::from_stream(options, reader);
+        }
         let blockchain_dir = options.borrow().blockchain_dir.clone();
+        if let Some(reader) = stream::open(&blockchain_dir)? {
+            return ChainStorage::from_stream(options, reader);
+        }
+        let format = options.borrow().coin_type.block_format;
+        let blocks = get_block_index(blockchain_dir.join("index").as_path(), format)?;
+        ChainStorage::from_index(options, blocks)
+    }
+
+    /// Uses the given chain instead of reading the block index
+    pub(super) fn from_index(
+        options: &'a RefCell<ParserOptions>,
+        blocks: Vec<BlockIndexRecord>,
+    ) -> OpResult<Self> {
+        if options.borrow().include_orphans {
+            return Err(OpError::new(OpErrorKind::InvalidArgsError).join_msg(
+                "--include-orphans needs a stream, stale blocks of the block index aren't read",
+            ));
+        }
+        let blockchain_dir = options.borrow().blockchain_dir.clone();
+        let blk_files = BlkFile::from_path(
+            blockchain_dir.as_path(),
+            options.borrow().xor_key,
+            options.borrow().coin_type.magic,
+        )?;
+        ChainStorage::with_source(options, blocks, blk_files, None)
+    }
+
+    /// Reads the blocks from a stream of magic prefixed blocks, e.g. `cat blk*.dat` or the files
+    /// of `--block-file`.
+    /// Their heights come from the longest chain in the stream, see `stream::BlockStream`.
+    pub(super) fn from_stream(
+        options: &'a RefCell<ParserOptions>,
+        reader: Box<dyn Read>,
+    ) -> OpResult<Self> {
+        if options.borrow().xor_key.is_some() {
+            return Err(OpError::new(OpErrorKind::InvalidArgsError).join_msg(
+                "--xor-key can't be used with streams, the blocks must be deobfuscated",
+            ));
+        }
+        if options.borrow().p2p_fallback.is_some() {
+            return Err(OpError::new(OpErrorKind::InvalidArgsError)
+                .join_msg("--p2p-fallback can't be used with streams"));
+        }
+        if options.borrow().use_undo_files {
+            return Err(OpError::new(OpErrorKind::InvalidArgsError).join_msg(
+                "--use-undo-files needs the block index, the undo data of a stream can't be located",
+            ));
+        }
+        if options.borrow().callback.follow_interval().is_some() {
+            return Err(OpError::new(OpErrorKind::InvalidArgsError)
+                .join_msg("The tip of a stream can't be followed, pass the blockchain directory"));
+        }
+        let path = stream::source_name(&options.borrow());
+        let mut stream = BlockStream::new(
+            reader,
+            &path,
+            options.borrow().coin_type.magic,
+            options.borrow().coin_type.block_format,
+            options.borrow().strict,
+        );
+        if options.borrow().include_orphans {
+            stream.keep_stale();
+        }
+        // The block files are logged as they are opened
+        if path == Path::new("-") {
+            info!(target: "chain", "Reading blocks from stdin ...");
+        } else if options.borrow().block_files.is_empty() {
+            info!(target: "chain", "Reading blocks from {} ...", path.display());
+        }
+        ChainStorage::with_source(options, Vec::new(), HashMap::new(), Some(stream))
+    }
+
+    fn with_source(
+        options: &'a RefCell<ParserOptions>,
+        blocks: Vec<BlockIndexRecord>,
+        blk_files: HashMap<usize, BlkFile>,
+        stream: Option<BlockStream<Box<dyn Read>>>,
+    ) -> OpResult<Self> {
+        let with_txs = options.borrow().callback.wants_transactions();
+        let pow_algorithm = options.borrow().coin_type.pow_algorithm;
+        if options.borrow().check_pow && !pow_algorithm.is_checked() {
+            warn!(target: "chain", "The proof of work of {} ({:?}) isn't implemented, --check-pow only checks the chain continuity.",
+                  options.borrow().coin_type.name, pow_algorithm);
+        }
+        let mut address_format = options.borrow().coin_type.address_format();
+        // Unrecognised scripts are only known after the evaluation
+        address_format.evaluate_scripts = options.borrow().callback.wants_script_evaluation()
+            || options.borrow().dump_unknown_scripts.is_some();
+        let settings = ParseSettings {
+            address_format,
+            format: options.borrow().coin_type.block_format,
+            classify_inputs: options.borrow().classify_inputs,
+            with_witnesses: options.borrow().callback.wants_witnesses(),
+            verify: options.borrow().verify,
+        };
+        let threads = options.borrow().threads;
+        // Blocks of a stream are deserialized as they arrive
+        let workers = if with_txs && threads > 1 && stream.is_none() {
+            let pool = ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .map_err(|e| {
+                    OpError::from(e.to_string()).join_msg("Unable to create thread pool.")
+                })?;
+            let (sender, receiver) = mpsc::channel();
+            Some(Workers {
+                pool,
+                sender,
+                receiver,
+                next_read: options.borrow().range.start,
+                in_flight: 0,
+                pending: BTreeMap::new(),
+                capacity: threads * BLOCKS_PER_THREAD,
+            })
+        } else {
+            None
+        };
+        let fallback = options.borrow().p2p_fallback.as_ref().map(|addr| {
+            let tip = blocks
+                .last()
+                .map(|block| (blocks.len() as u64 - 1, block.block_hash));
+            Fallback::new(
+                addr,
+                &options.borrow().coin_type,
+                options.borrow().p2p_timeout,
+                tip,
+                options.borrow().range.end.map(|end| end as u64),
+            )
+        });
+        let index_dir = options.borrow().blockchain_dir.join("index");
+        let format = options.borrow().coin_type.block_format;
         Ok(Self {
-            blocks: get_block_index(blockchain_dir.join("index").as_path())?,
-            blk_files: BlkFile::from_path(blockchain_dir.as_path())?,
+            blocks,
+            load_index: Box::new(move || get_block_index(&index_dir, format)),
+            blk_files,
             index: options.borrow().range.start,
+            with_txs,
+            settings,
+            mmap: options.borrow().mmap,
             options,
+            workers,
+            maps: Vec::with_capacity(MAPPED_FILES),
+            corrupt: Vec::new(),
+            invalid: Vec::new(),
+            last_hash: None,
+            stream,
+            fallback,
+        })
+    }
+
+    /// Returns the next block of the range and its height.
+    /// Blocks are read at their offset from the index, so nothing outside of the range is read.
+    /// Corrupt or truncated blocks, and blocks failing `--verify`, are skipped and recorded
+    /// unless `--strict` is given. With `--p2p-fallback` unreadable blocks are downloaded
+    /// instead, and the blocks past the tip of the index follow.
+    pub fn get_next(&mut self) -> OpResult<Option<(u64, Block)>> {
+        if self.stream.is_some() {
+            return self.next_from_stream();
+        }
+        while self.index < self.end() {
+            let height = self.index;
+            let result = match self.read_next() {
+                Err(e) if !matches!(e.kind, OpErrorKind::ValidateError) => {
+                    self.fetch_missing(height, e)
+                }
+                result => result,
+            }
+            .and_then(|block| {
+                if self.options.borrow().verify {
+                    self.verify(&block)?;
+                }
+                Ok(block)
+            });
+            self.index += 1;
+            match result {
+                Ok(mut block) => {
+                    if self.options.borrow().check_pow {
+                        self.check_pow(height, &block)?;
+                    }
+                    if self.options.borrow().use_undo_files {
+                        self.attach_prevouts(height, &mut block)?;
+                    }
+                    self.last_hash = Some(block.header.hash);
+                    return Ok(Some((height as u64, block)));
+                }
+                Err(e) if matches!(e.kind, OpErrorKind::ValidateError) => {
+                    let hash = transform!(self.blocks.get(height)).block_hash;
+                    self.on_invalid_block(height, hash, e)?
+                }
+                Err(e) => self.on_corrupt_block(height, e)?,
+            }
+        }
+        self.next_from_peer()
+    }
+
+    /// Downloads a block which couldn't be read from disk, returns `err` without a fallback
+    fn fetch_missing(&mut self, height: usize, err: OpError) -> OpResult<Block> {
+        let hash = transform!(self.blocks.get(height)).block_hash;
+        let fallback = match self.fallback.as_mut() {
+            Some(fallback) => fallback,
+            None => return Err(err),
+        };
+        match fallback.fetch(hash) {
+            Ok(raw) => {
+                info!(target: "chain", "Unable to read block {}: {}. Downloaded it from {}.", height, err, fallback.addr());
+                self.parse_raw(&raw)
+            }
+            Err(e) => {
+                self.abort_fallback(e);
+                Err(err)
+            }
+        }
+    }
+
+    /// Continues past the tip of the index with the blocks of the peer.
+    /// Errors of the peer end the fallback, all blocks returned before are complete.
+    fn next_from_peer(&mut self) -> OpResult<Option<(u64, Block)>> {
+        loop {
+            let fallback = match self.fallback.as_mut() {
+                Some(fallback) => fallback,
+                None => return Ok(None),
+            };
+            let FetchedBlock { height, hash, raw } = match fallback.next_block(self.index as u64) {
+                Ok(Some(block)) => block,
+                Ok(None) => return Ok(None),
+                Err(e) => {
+                    self.abort_fallback(e);
+                    return Ok(None);
+                }
+            };
+            self.index = height as usize;
+            let result = self.parse_raw(&raw).and_then(|block| {
+                if self.options.borrow().verify {
+                    self.verify(&block)?;
+                }
+                Ok(block)
+            });
+            self.index += 1;
+            match result {
+                Ok(block) => {
+                    if self.options.borrow().check_pow {
+                        self.check_pow(height as usize, &block)?;
+                    }
+                    self.last_hash = Some(block.header.hash);
+                    return Ok(Some((height, block)));
+                }
+                Err(e) if matches!(e.kind, OpErrorKind::ValidateError) => {
+                    self.on_invalid_block(height as usize, hash, e)?
+                }
+                // The hash matched, so the peer sent a block which is as broken as its header
+                Err(e) => {
+                    self.abort_fallback(OpError::from(format!(
+                        "Unable to read block {}: {}",
+                        height, e
+                    )));
+                    return Ok(None);
+                }
+            }
+        }
+    }
+
+    /// Drops the connection, the following blocks are read from disk only
+    fn abort_fallback(&mut self, err: OpError) {
+        if let Some(fallback) = self.fallback.take() {
+            warn!(target: "chain", "Aborting the P2P fallback to {}: {}", fallback.addr(), err);
+        }
+    }
+
+    /// Deserializes a block which was read at once, with or without its transactions
+    fn parse_raw(&self, raw: &[u8]) -> OpResult<Block> {
+        if self.with_txs {
+            parse_block(raw, self.settings)
+        } else {
+            Cursor::new(raw)
+                .read_block_without_txs(raw.len() as u32, self.settings.format)
+                .map_err(|e| OpError::deserialization(e, 0))
+        }
+    }
+
+    /// Returns the next block of the stream within the range, skipped ones are recorded as
+    /// in `get_next`
+    fn next_from_stream(&mut self) -> OpResult<Option<(u64, Block)>> {
+        let path = stream::source_name(&self.options.borrow());
+        let end = self.options.borrow().range.end;
+        loop {
+            let stream = transform!(self.stream.as_mut());
+            let next = timings::measure(Phase::Read, || stream.next_block())?;
+            for (start, end) in stream.take_skipped() {
+                self.corrupt.push(CorruptRegion {
+                    height: None,
+                    path: path.clone(),
+                    start,
+                    end,
+                });
+            }
+            let next = match next {
+                Some(next) => next,
+                None => {
+                    let n_stale = transform!(self.stream.as_ref()).n_stale();
+                    if n_stale > 0 {
+                        info!(target: "chain", "Dropped {} blocks of the stream which aren't part of the chain with the most work.", n_stale);
+                    }
+                    return Ok(None);
+                }
+            };
+            let height = next.height as usize;
+            // Below the range or a resume height
+            if height < self.index {
+                continue;
+            }
+            if end.is_some_and(|end| height > end) {
+                return Ok(None);
+            }
+            self.index = height;
+            let result = self.parse_raw(&next.raw).and_then(|block| {
+                if self.options.borrow().verify {
+                    self.verify(&block)?;
+                }
+                Ok(block)
+            });
+            self.index += 1;
+            match result {
+                Ok(block) => {
+                    if self.options.borrow().check_pow {
+                        self.check_pow(height, &block)?;
+                    }
+                    self.last_hash = Some(block.header.hash);
+                    return Ok(Some((next.height, block)));
+                }
+                Err(e) if matches!(e.kind, OpErrorKind::ValidateError) => {
+                    self.on_invalid_block(height, next.hash, e)?
+                }
+                Err(e) => {
+                    let err = e.at_block(&path, next.offset, next.height);
+                    if self.options.borrow().strict {
+                        return Err(err);
+                    }
+                    let (start, end) = (next.offset - 8, next.offset + next.raw.len() as u64);
+                    warn!(target: "chain", "Unable to read block: {}. Skipping {} bytes ({}..{}).", err, end - start, start, end);
+                    self.corrupt.push(CorruptRegion {
+                        height: Some(next.height),
+                        path: path.clone(),
+                        start,
+                        end,
+                    });
+                }
+            }
+        }
+    }
+
+    fn read_next(&mut self) -> OpResult<Block> {
+        if self.workers.is_some() {
+            self.next_from_workers()
+        } else if self.with_txs {
+            let index = self.index;
+            let raw = timings::measure(Phase::Read, || self.read_raw(index))?;
+            parse_block(&raw, self.settings)
+        } else {
+            let meta = transform!(self.blocks.get(self.index));
+            transform!(self.blk_files.get(&meta.n_file)).read_block(
+                meta.n_data_pos,
+                self.settings.address_format,
+                self.settings.format,
+                false,
+            )
+        }
+    }
+
+    /// Sets the outputs spent by the inputs from the undo data of the block, see `undo`.
+    /// Blocks without undo data can't be passed on, so the parser stops there.
+    fn attach_prevouts(&self, height: usize, block: &mut Block) -> OpResult<()> {
+        // Headers only, or nothing but the coinbase
+        if block.txs.len() < 2 {
+            return Ok(());
+        }
+        let meta = transform!(self.blocks.get(height));
+        let offset = meta.undo_pos().ok_or_else(|| {
+            OpError::from(format!(
+                "Block {} has no undo data, which --use-undo-files needs",
+                height
+            ))
+        })?;
+        let blk_file = transform!(self.blk_files.get(&meta.n_file));
+        let prev_hash = block.header.value.prev_hash;
+        timings::measure(Phase::Read, || {
+            undo::read_block_undo(blk_file, offset, &prev_hash)
         })
+        .and_then(|undo| undo::attach_prevouts(block, undo, self.settings.address_format))
+        .map_err(|e| e.at_block(&undo::rev_path(&blk_file.path), offset, height as u64))
+    }
+
+    /// Records the region of a block which couldn't be read, an error in strict mode
+    fn on_corrupt_block(&mut self, height: usize, err: OpError) -> OpResult<()> {
+        let meta = transform!(self.blocks.get(height));
+        let blk_file = transform!(self.blk_files.get(&meta.n_file));
+        let err = err.at_block(&blk_file.path, meta.n_data_pos, height as u64);
+        if self.options.borrow().strict {
+            return Err(err);
+        }
+        // The region ends at the next block found by its magic
+        let start = meta.n_data_pos.saturating_sub(8);
+        let end = blk_file.next_magic(start + 1).unwrap_or(blk_file.size);
+        warn!(target: "chain", "Unable to read block: {}. Skipping {} bytes ({}..{}).", err, end - start, start, end);
+        self.corrupt.push(CorruptRegion {
+            height: Some(height as u64),
+            path: blk_file.path.clone(),
+            start,
+            end,
+        });
+        Ok(())
     }
 
-    /// Returns the next hash without removing it
-    pub fn get_next(&mut self) -> Option<Block> {
-        if let Some(end) = self.options.borrow().range.end {
-            if self.index == end {
-                return None;
+    /// Checks the proof of work against the target of the header, and that the block links to the
+    /// previously returned one. Violations are an error unless `--keep-going` is given.
+    /// The work of merge mined blocks is done on the AuxPoW parent header.
+    fn check_pow(&self, height: usize, block: &Block) -> OpResult<()> {
+        let mut violations = Vec::new();
+        if self.options.borrow().coin_type.pow_algorithm.is_checked() {
+            let pow_hash = match &block.aux_pow {
+                Some(aux_pow) => aux_pow.parent_header.hash,
+                None => block.header.hash,
+            };
+            if let Err(msg) = target::check_pow(&pow_hash, block.header.value.bits) {
+                violations.push(format!("invalid proof of work, {}", msg));
             }
         }
+        if let Some(last_hash) = self.last_hash {
+            if block.header.value.prev_hash != last_hash {
+                violations.push(format!(
+                    "prev_hash doesn't match the previous block, expected {}, got {}",
+                    utils::arr_to_hex_swapped(&last_hash),
+                    utils::arr_to_hex_swapped(&block.header.value.prev_hash)
+                ));
+            }
+        }
+        for violation in violations {
+            let msg = format!(
+                "Block {} ({}): {}",
+                height,
+                utils::arr_to_hex_swapped(&block.header.hash),
+                violation
+            );
+            if !self.options.borrow().keep_going {
+                return Err(OpError::new(OpErrorKind::ValidateError).join_msg(&msg));
+            }
+            warn!(target: "chain", "{}", msg);
+        }
+        Ok(())
+    }
 
-        let meta = self.blocks.get(self.index)?;
-        let block = self
-            .blk_files
-            .get(&meta.n_file)?
-            .read_block(meta.n_data_pos, self.options.borrow().coin_type.version_id)
-            .ok()?;
+    /// Records a block which failed verification, an error in strict mode
+    fn on_invalid_block(&mut self, height: usize, hash: [u8; 32], err: OpError) -> OpResult<()> {
+        let msg = format!(
+            "Block {} ({}) failed verification",
+            height,
+            utils::arr_to_hex_swapped(&hash)
+        );
+        if self.options.borrow().strict {
+            return Err(OpError::new(OpErrorKind::ValidateError)
+                .join_msg(&format!("{}: {}", msg, err.message)));
+        }
+        warn!(target: "chain", "{}: {}. Skipping it.", msg, err.message);
+        self.invalid.push(InvalidBlock {
+            height: height as u64,
+            hash,
+            reason: err.message,
+        });
+        Ok(())
+    }
 
-        if self.options.borrow().verify {
-            self.verify(&block);
+    /// Blocks which failed verification so far
+    #[inline]
+    pub fn invalid_blocks(&self) -> &[InvalidBlock] {
+        &self.invalid
+    }
+
+    /// Regions of the blk files skipped so far
+    #[inline]
+    pub fn corrupt_regions(&self) -> &[CorruptRegion] {
+        &self.corrupt
+    }
+
+    /// Keeps the workers busy with the following blocks and waits for the current one.
+    /// Reading stays sequential, only deserialization and script evaluation run in parallel.
+    fn next_from_workers(&mut self) -> OpResult<Block> {
+        let end = self.end();
+        let settings = self.settings;
+        loop {
+            let workers = transform!(self.workers.as_ref());
+            if workers.in_flight + workers.pending.len() >= workers.capacity
+                || workers.next_read >= end
+            {
+                break;
+            }
+            let height = workers.next_read;
+            let raw = timings::measure(Phase::Read, || self.read_raw(height));
+            let workers = transform!(self.workers.as_mut());
+            workers.next_read += 1;
+            match raw {
+                Ok(raw) => {
+                    let sender = workers.sender.clone();
+                    workers.in_flight += 1;
+                    workers.pool.spawn(move || {
+                        let result = parse_block(&raw, settings);
+                        // The receiver is gone if parsing was aborted
+                        let _ = sender.send((height, result));
+                    });
+                }
+                Err(e) => {
+                    workers.pending.insert(height, Err(e));
+                }
+            }
         }
 
-        self.index += 1;
-        Some(block)
+        let workers = transform!(self.workers.as_mut());
+        loop {
+            if let Some(result) = workers.pending.remove(&self.index) {
+                return result;
+            }
+            if workers.in_flight == 0 {
+                return Err(OpError::from(format!(
+                    "Block {} was never read",
+                    self.index
+                )));
+            }
+            let (height, result) = workers
+                .receiver
+                .recv()
+                .map_err(|e| OpError::from(e.to_string()))?;
+            workers.in_flight -= 1;
+            if height >= self.index {
+                workers.pending.insert(height, result);
+            }
+        }
+    }
+
+    /// Stale blocks of the stream dropped since the last call with their heights,
+    /// see `--include-orphans`. Blocks which can't be read are skipped.
+    pub fn take_stale(&mut self) -> Vec<(u64, Block)> {
+        let stale = match self.stream.as_mut() {
+            Some(stream) => stream.take_stale(),
+            None => return Vec::new(),
+        };
+        stale
+            .into_iter()
+            .filter_map(|block| match self.parse_raw(&block.raw) {
+                Ok(parsed) => Some((block.height, parsed)),
+                Err(e) => {
+                    warn!(target: "chain", "Unable to read stale block {} at offset {}: {}", utils::arr_to_hex_swapped(&block.hash), block.offset, e);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Reads the serialized block at the given height, borrowed from the memory map if possible
+    fn read_raw(&mut self, height: usize) -> OpResult<RawBlock> {
+        let meta = transform!(self.blocks.get(height));
+        let (n_file, offset) = (meta.n_file, meta.n_data_pos);
+        let blk_file = transform!(self.blk_files.get(&n_file));
+        if self.mmap {
+            if let Some(pos) = self.maps.iter().position(|(n, _)| *n == n_file) {
+                let (_, map) = self.maps.remove(pos);
+                // The file may have grown since it was mapped, it's mapped again then
+                if let Ok(raw) = blk_file.slice_block(&map, offset) {
+                    self.maps.insert(0, (n_file, map));
+                    return Ok(raw);
+                }
+            }
+            match blk_file.map() {
+                Ok(Some(map)) => {
+                    self.maps.truncate(MAPPED_FILES - 1);
+                    self.maps.insert(0, (n_file, Arc::new(map)));
+                    return blk_file.slice_block(&self.maps[0].1, offset);
+                }
+                // Obfuscated, deobfuscated while copying
+                Ok(None) => (),
+                Err(e) => {
+                    warn!(target: "chain", "Unable to map {}, reading blocks without memory maps: {}", blk_file.path.display(), e);
+                    self.mmap = false;
+                }
+            }
+        }
+        Ok(RawBlock::Owned(blk_file.read_raw_block(offset)?))
     }
 
-    /// Verifies the given block in a chain.
-    /// Panics if not valid
-    fn verify(&self, block: &Block) {
-        block.verify_merkle_root();
+    /// Verifies the header of the given block against the index and its predecessor.
+    /// The merkle root and witness commitment are checked in `parse_block` if the transactions
+    /// were read.
+    fn verify(&self, block: &Block) -> OpResult<()> {
+        let invalid = |msg: String| Err(OpError::new(OpErrorKind::ValidateError).join_msg(&msg));
+        // Blocks of a stream or past the tip are linked by construction, there is no index
+        // entry to compare with
+        if let Some(meta) = self.blocks.get(self.index) {
+            if block.header.hash != meta.block_hash {
+                return invalid(format!(
+                    "Header hash doesn't match the index, expected {}, got {}",
+                    utils::arr_to_hex_swapped(&meta.block_hash),
+                    utils::arr_to_hex_swapped(&block.header.hash)
+                ));
+            }
+        }
+        block.verify_aux_pow().or_else(invalid)?;
         if self.index == 0 {
             let genesis_hash = self.options.borrow().coin_type.genesis_hash;
             if block.header.hash != genesis_hash {
-                panic!(
-                    "Hash of genesis doesn't match!\n  -> expected: {}\n  -> got: {}\n",
+                return invalid(format!(
+                    "Hash of genesis doesn't match, expected {}, got {}",
                     utils::arr_to_hex_swapped(&genesis_hash),
                     utils::arr_to_hex_swapped(&block.header.hash),
-                );
+                ));
             }
-        } else {
-            let prev_hash = self.blocks.get(self.index - 1).unwrap().block_hash;
+        } else if let Some(prev) = self.blocks.get(self.index - 1) {
+            let prev_hash = prev.block_hash;
             if block.header.value.prev_hash != prev_hash {
-                panic!(
-                    "prev_hash for block {} doesn't match!\n  -> expected: {}\n  -> got: {}\n",
-                    utils::arr_to_hex_swapped(&block.header.hash),
-                    utils::arr_to_hex_swapped(&block.header.value.prev_hash),
-                    utils::arr_to_hex_swapped(&prev_hash)
-                );
+                return invalid(format!(
+                    "prev_hash doesn't match, expected {}, got {}",
+                    utils::arr_to_hex_swapped(&prev_hash),
+                    utils::arr_to_hex_swapped(&block.header.value.prev_hash)
+                ));
             }
         }
+        Ok(())
+    }
+
+    /// Reads the block index and the blk files again to pick up blocks added since the start,
+    /// see `Callback::follow_interval`. If the chain was reorganized, the blocks from the fork
+    /// on are returned again by `get_next`. Returns the number of blocks to read.
+    pub fn rescan(&mut self) -> OpResult<usize> {
+        let blocks = (self.load_index)()?;
+        let blockchain_dir = self.options.borrow().blockchain_dir.clone();
+        let blk_files = BlkFile::from_path(
+            blockchain_dir.as_path(),
+            self.options.borrow().xor_key,
+            self.options.borrow().coin_type.magic,
+        )?;
+        // Lowest returned height which isn't part of the new chain anymore
+        let start = self.options.borrow().range.start;
+        let mut fork = self.index.min(blocks.len());
+        while fork > start
+            && self
+                .blocks
+                .get(fork - 1)
+                .is_some_and(|block| block.block_hash != blocks[fork - 1].block_hash)
+        {
+            fork -= 1;
+        }
+        if fork < self.index {
+            warn!(target: "chain", "The chain was reorganized, continuing at block {} ({} blocks replaced).", fork, self.index - fork);
+            self.last_hash = fork.checked_sub(1).map(|height| blocks[height].block_hash);
+        }
+        self.blocks = blocks;
+        self.blk_files = blk_files;
+        self.maps.clear();
+        self.skip_to(fork);
+        Ok(self.remaining())
+    }
+
+    /// Replaces the block index read by `rescan`
+    #[cfg(test)]
+    pub(super) fn set_index_loader(&mut self, load_index: IndexLoader) {
+        self.load_index = load_index;
     }
 
-    /// Returns number of remaining blocks
+    /// Skips all blocks below the given height
+    #[inline]
+    pub fn skip_to(&mut self, height: usize) {
+        self.index = height;
+        if let Some(workers) = self.workers.as_mut() {
+            workers.next_read = height;
+            workers.pending.clear();
+        }
+    }
+
+    /// Returns number of remaining blocks in the range, 0 if unknown
     #[inline]
     pub fn remaining(&self) -> usize {
-        self.blocks.len().saturating_sub(self.index)
+        self.end().saturating_sub(self.index)
+    }
+
+    /// Returns the size of all blk files
+    pub fn total_bytes(&self) -> u64 {
+        self.blk_files.values().map(|blk_file| blk_file.size).sum()
+    }
+
+    /// Height after the last block to parse
+    #[inline]
+    fn end(&self) -> usize {
+        match self.options.borrow().range.end {
+            Some(end) => self.blocks.len().min(end + 1),
+            None => self.blocks.len(),
+        }
     }
 }
+
+/// Deserializes a block read by `ChainStorage::read_raw`.
+/// Errors carry the position in the block at which reading failed.
+fn parse_block(raw: &[u8], settings: ParseSettings) -> OpResult<Block> {
+    // Garbage may trip assertions while evaluating scripts, and a panic
+    // in a worker would abort the process
+    panic::catch_unwind(AssertUnwindSafe(|| {
+        let mut cursor = Cursor::new(raw);
+        // Evaluated afterwards, so the time of both is measured apart
+        let address_format = AddressFormat {
+            evaluate_scripts: false,
+            ..settings.address_format
+        };
+        let mut block = timings::measure(Phase::Deserialize, || {
+            cursor.read_block(raw.len() as u32, address_format, settings.format)
+        })
+        .map_err(|e| OpError::deserialization(e, cursor.position()))?;
+        if settings.address_format.evaluate_scripts {
+            timings::measure(Phase::Evaluate, || {
+                block.evaluate_scripts(settings.address_format)
+            });
+        }
+        if settings.verify {
+            block
+                .verify_merkle_root()
+                .and_then(|_| block.verify_witness_commitment())
+                .map_err(|msg| OpError::new(OpErrorKind::ValidateError).join_msg(&msg))?;
+        }
+        for tx in block.txs.iter_mut() {
+            if settings.classify_inputs {
+                tx.value.classify_inputs();
+            }
+            // Scripts are revealed from the witnesses first
+            if !settings.with_witnesses {
+                tx.value.drop_witnesses();
+            }
+        }
+        Ok(block)
+    }))
+    .unwrap_or_else(|_| {
+        Err(OpError::deserialization(
+            OpError::from(String::from("Panicked while deserializing")),
+            0,
+        ))
+    })
+}
@@ -0,0 +1,380 @@
+//! Coin definitions loaded at runtime with `--coin-file`, for coins with the Bitcoin block format
+//! (or AuxPoW) which aren't built in. See `coins/` for examples.
+//!
+//! The file is either JSON (`.json`) or TOML with one `key = value` per line:
+//!
+//! ```toml
+//! name = "Vertcoin"
+//! magic = "fabfb5da"          # Message start bytes as they appear in the blk files
+//! p2pkh_version = 71
+//! p2sh_version = 5
+//! bech32_hrp = "vtc"          # Optional, without segwit if missing
+//! genesis_hash = "4d96a915f49d40b1e5c2844d1ee2dccb90013a990ccea12c492d22110489f0c4"
+//! datadir = ".vertcoin"       # Relative to the home directory, blocks are in <datadir>/blocks
+//! decimals = 8                # Optional, default 8
+//! genesis_bits = 0x1e0ffff0   # Optional, default 0x1d00ffff
+//! auxpow = false              # Optional, whether blocks can be merge mined
+//! pow = "scrypt"              # Optional, "sha256d" (default) or "scrypt", which isn't checked
+//! ```
+//!
+//! Only this flat subset of TOML is supported: no tables, arrays or multi-line strings.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde_json::{Map, Value};
+
+use crate::blockchain::parser::types::{CoinType, COIN_NAMES};
+use crate::blockchain::proto::block::{BlockFormat, Subsidy};
+use crate::blockchain::proto::target::PowAlgorithm;
+use crate::common::utils;
+use crate::errors::{OpError, OpErrorKind, OpResult};
+
+/// Keys of a coin definition and whether they are required
+const KEYS: &[(&str, bool)] = &[
+    ("name", true),
+    ("magic", true),
+    ("p2pkh_version", true),
+    ("p2sh_version", true),
+    ("bech32_hrp", false),
+    ("genesis_hash", true),
+    ("datadir", true),
+    ("decimals", false),
+    ("genesis_bits", false),
+    ("auxpow", false),
+    ("pow", false),
+];
+
+/// Loads the coin definition at `path`, JSON if the extension is `.json` and TOML otherwise
+pub fn load(path: &Path) -> OpResult<CoinType> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| invalid(&format!("Unable to read {}: {}", path.display(), e)))?;
+    let is_json = path.extension().is_some_and(|ext| ext == "json");
+    let definition = if is_json {
+        match serde_json::from_str(&contents) {
+            Ok(Value::Object(map)) => map,
+            Ok(_) => return Err(invalid("The coin definition must be a JSON object")),
+            Err(e) => return Err(invalid(&format!("Invalid JSON: {}", e))),
+        }
+    } else {
+        parse_toml(&contents)?
+    };
+    from_definition(&definition).map_err(|e| {
+        invalid(&format!(
+            "Invalid coin definition {}: {}",
+            path.display(),
+            e.message
+        ))
+    })
+}
+
+/// Validates the keys and values of a definition and builds the coin
+pub fn from_definition(definition: &Map<String, Value>) -> OpResult<CoinType> {
+    for key in definition.keys() {
+        if !KEYS.iter().any(|(k, _)| k == key) {
+            return Err(invalid(&format!("unknown key `{}`", key)));
+        }
+    }
+    if let Some((key, _)) = KEYS
+        .iter()
+        .find(|(k, required)| *required && !definition.contains_key(*k))
+    {
+        return Err(invalid(&format!("missing key `{}`", key)));
+    }
+
+    let name = string(definition, "name")?;
+    if name.trim().is_empty() {
+        return Err(invalid("`name` must not be empty"));
+    }
+    let magic = parse_magic(&string(definition, "magic")?)?;
+    // Blocks of another coin would be read as blocks of this one
+    // except regtest's, which other coins use for their main network (e.g. Vertcoin)
+    let builtins = COIN_NAMES.iter().filter(|n| **n != "bitcoin-regtest");
+    for builtin in builtins.map(|n| CoinType::from_str(n).unwrap()) {
+        if builtin.magic == magic {
+            return Err(invalid(&format!(
+                "magic {} is already used by the builtin coin {}",
+                utils::arr_to_hex(&magic.to_le_bytes()),
+                builtin.name
+            )));
+        }
+    }
+    let genesis = string(definition, "genesis_hash")?;
+    let genesis_hash = utils::hex_to_arr_swapped(&genesis).map_err(|_| {
+        invalid(&format!(
+            "`genesis_hash` must be 64 hex characters, got `{}`",
+            genesis
+        ))
+    })?;
+    let bech32_hrp = match definition.get("bech32_hrp") {
+        Some(_) => {
+            let hrp = string(definition, "bech32_hrp")?;
+            if hrp.is_empty() || hrp.len() > 83 || !hrp.bytes().all(|b| (33..=126).contains(&b)) {
+                return Err(invalid(&format!("invalid `bech32_hrp` `{}`", hrp)));
+            }
+            // Lives as long as the process, like the prefixes of the builtin coins
+            Some(&*Box::leak(hrp.to_lowercase().into_boxed_str()))
+        }
+        None => None,
+    };
+    let decimals = match definition.get("decimals") {
+        Some(_) => integer(definition, "decimals", 18)? as u8,
+        None => 8,
+    };
+    let genesis_bits = match definition.get("genesis_bits") {
+        Some(_) => integer(definition, "genesis_bits", u32::MAX as u64)? as u32,
+        None => 0x1d00ffff,
+    };
+    let block_format = match definition.get("auxpow") {
+        Some(Value::Bool(true)) => BlockFormat::AuxPow,
+        Some(Value::Bool(false)) | None => BlockFormat::Bitcoin,
+        Some(v) => {
+            return Err(invalid(&format!(
+                "`auxpow` must be true or false, got {}",
+                v
+            )))
+        }
+    };
+    let pow_algorithm = match definition.get("pow") {
+        Some(_) => match string(definition, "pow")?.as_str() {
+            "sha256d" => PowAlgorithm::Sha256d,
+            "scrypt" => PowAlgorithm::Scrypt,
+            pow => {
+                return Err(invalid(&format!(
+                    "`pow` must be \"sha256d\" or \"scrypt\", got `{}`",
+                    pow
+                )))
+            }
+        },
+        None => PowAlgorithm::Sha256d,
+    };
+
+    Ok(CoinType {
+        name,
+        magic,
+        version_id: integer(definition, "p2pkh_version", 0xff)? as u8,
+        p2sh_version: integer(definition, "p2sh_version", 0xff)? as u8,
+        genesis_hash,
+        default_folder: PathBuf::from(string(definition, "datadir")?).join("blocks"),
+        decimals,
+        genesis_bits,
+        bech32_hrp,
+        block_format,
+        pow_algorithm,
+        subsidy: Subsidy::Unknown,
+        ..CoinType::default()
+    })
+}
+
+/// Message start bytes in file order, e.g. `f9beb4d9` for Bitcoin
+pub fn parse_magic(hex: &str) -> OpResult<u32> {
+    let bytes = utils::hex_to_arr(hex).map_err(|_| {
+        invalid(&format!(
+            "The magic must be 4 bytes as 8 hex characters, got `{}`",
+            hex
+        ))
+    })?;
+    let magic = u32::from_le_bytes(bytes);
+    // Zero bytes fill the preallocated space at the end of blk files
+    if magic == 0 {
+        return Err(invalid("The magic must not be zero"));
+    }
+    Ok(magic)
+}
+
+fn string(definition: &Map<String, Value>, key: &str) -> OpResult<String> {
+    match definition.get(key) {
+        Some(Value::String(s)) => Ok(s.clone()),
+        Some(v) => Err(invalid(&format!("`{}` must be a string, got {}", key, v))),
+        None => Err(invalid(&format!("missing key `{}`", key))),
+    }
+}
+
+fn integer(definition: &Map<String, Value>, key: &str, max: u64) -> OpResult<u64> {
+    match definition.get(key).and_then(Value::as_u64) {
+        Some(n) if n <= max => Ok(n),
+        _ => Err(invalid(&format!(
+            "`{}` must be an integer from 0 to {}, got {}",
+            key,
+            max,
+            definition.get(key).unwrap_or(&Value::Null)
+        ))),
+    }
+}
+
+/// Parses `key = value` lines with strings, integers (also hex) and booleans
+fn parse_toml(contents: &str) -> OpResult<Map<String, Value>> {
+    let mut map = Map::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+        let err = |msg: &str| invalid(&format!("line {}: {}", i + 1, msg));
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| err("expected `key = value`"))?;
+        let key = key.trim();
+        if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(err(&format!("invalid key `{}`", key)));
+        }
+        let value = value.trim();
+        let value = if let Some(s) = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+            if s.contains('"') || s.contains('\\') {
+                return Err(err("escapes are not supported"));
+            }
+            Value::from(s)
+        } else if value == "true" || value == "false" {
+            Value::from(value == "true")
+        } else {
+            let digits = value.replace('_', "");
+            let n = match digits.strip_prefix("0x") {
+                Some(hex) => u64::from_str_radix(hex, 16),
+                None => digits.parse::<u64>(),
+            };
+            Value::from(n.map_err(|_| err(&format!("invalid value `{}`", value)))?)
+        };
+        if map.insert(String::from(key), value).is_some() {
+            return Err(err(&format!("duplicate key `{}`", key)));
+        }
+    }
+    Ok(map)
+}
+
+/// Removes a comment, `#` within strings is kept
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+#[inline]
+fn invalid(msg: &str) -> OpError {
+    OpError::new(OpErrorKind::InvalidArgsError).join_msg(msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+    use crate::blockchain::proto::script;
+
+    const DIGIBYTE: &str = include_str!("../../../coins/digibyte.toml");
+
+    fn write(name: &str, contents: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("blockparser-coin-{}-{}", process::id(), name));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn err_of(result: OpResult<CoinType>) -> String {
+        result.err().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_load_toml() {
+        let path = write("digibyte.toml", DIGIBYTE);
+        let coin = load(&path).unwrap();
+        fs::remove_file(path).unwrap();
+
+        assert_eq!("DigiByte", coin.name);
+        assert_eq!(0xdab6c3fa, coin.magic);
+        assert_eq!(
+            "7497ea1b465eb39f1c8f507bc877078fe016d6fcb6dfad3a64c98dcc6e1e8496",
+            utils::arr_to_hex_swapped(&coin.genesis_hash)
+        );
+        assert_eq!(Path::new(".digibyte").join("blocks"), coin.default_folder);
+        assert_eq!(8, coin.decimals);
+        assert_eq!(0x1e0ffff0, coin.genesis_bits);
+        assert_eq!(BlockFormat::Bitcoin, coin.block_format);
+
+        // The address encoding uses the definition
+        let format = coin.address_format();
+        let h160 = [0u8; 20];
+        assert_eq!(script::hash_160_to_address(&h160, 30), format.p2pkh(&h160));
+        assert_eq!(script::hash_160_to_address(&h160, 63), format.p2sh(&h160));
+        assert!(format.segwit(0, &h160).unwrap().starts_with("dgb1q"));
+    }
+
+    #[test]
+    fn test_load_json() {
+        let path = write(
+            "coin.json",
+            r#"{"name": "Mergecoin", "magic": "01020304", "p2pkh_version": 50, "p2sh_version": 80,
+                "genesis_hash": "00000000000000000000000000000000000000000000000000000000000000ff",
+                "datadir": ".mergecoin", "decimals": 6, "auxpow": true}"#,
+        );
+        let coin = load(&path).unwrap();
+        fs::remove_file(path).unwrap();
+        assert_eq!(0x04030201, coin.magic);
+        assert_eq!(0xff, coin.genesis_hash[0]);
+        assert_eq!(None, coin.bech32_hrp);
+        assert_eq!(6, coin.decimals);
+        assert_eq!(0x1d00ffff, coin.genesis_bits);
+        assert_eq!(BlockFormat::AuxPow, coin.block_format);
+        assert_eq!(PowAlgorithm::Sha256d, coin.pow_algorithm);
+        assert_eq!(Subsidy::Unknown, coin.subsidy);
+
+        let vertcoin = load(
+            Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("coins/vertcoin.json")
+                .as_path(),
+        )
+        .unwrap();
+        assert_eq!(0xdab5bffa, vertcoin.magic);
+        assert_eq!(Some("vtc"), vertcoin.bech32_hrp);
+        assert_eq!(0x1e0ffff0, vertcoin.genesis_bits);
+
+        let path = write("list.json", "[]");
+        assert!(err_of(load(&path)).contains("must be a JSON object"));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_definitions() {
+        let replace = |from: &str, to: &str| {
+            assert!(DIGIBYTE.contains(from));
+            parse_toml(&DIGIBYTE.replace(from, to)).and_then(|d| from_definition(&d))
+        };
+        // Bitcoin's magic
+        assert!(err_of(replace("\"fac3b6da\"", "\"f9beb4d9\"")).contains("builtin coin Bitcoin"));
+        assert!(err_of(replace("\"fac3b6da\"", "\"fac3b6\"")).contains("magic must be 4 bytes"));
+        assert!(err_of(replace("\"fac3b6da\"", "\"fac3b6zz\"")).contains("magic must be 4 bytes"));
+        assert!(err_of(replace("\"fac3b6da\"", "\"00000000\"")).contains("must not be zero"));
+        assert!(err_of(replace("\"fac3b6da\"", "4206216186")).contains("`magic` must be a string"));
+        assert!(err_of(replace("7497ea1b", "7497ea1")).contains("`genesis_hash` must be 64 hex"));
+        assert!(err_of(replace("7497ea1b", "7497ea1x")).contains("`genesis_hash` must be 64 hex"));
+        assert!(err_of(replace("p2pkh_version = 30", "p2pkh_version = 256"))
+            .contains("`p2pkh_version` must be an integer from 0 to 255"));
+        assert!(err_of(replace("p2sh_version", "p2sh")).contains("unknown key `p2sh`"));
+        assert!(err_of(replace("datadir", "#datadir")).contains("missing key `datadir`"));
+        assert!(
+            err_of(replace("decimals = 8", "decimals = 8\ndecimals = 2"))
+                .contains("line 13: duplicate key `decimals`")
+        );
+        assert!(err_of(replace("name = \"DigiByte\"", "name \"DigiByte\"")).contains("line 3"));
+
+        let coin = replace("decimals = 8", "pow = \"scrypt\"").unwrap();
+        assert_eq!(PowAlgorithm::Scrypt, coin.pow_algorithm);
+        assert!(err_of(replace("decimals = 8", "pow = \"x11\"")).contains("`pow` must be"));
+
+        // Vertcoin and regtest share the magic
+        assert!(replace("\"fac3b6da\"", "\"fabfb5da\"").is_ok());
+
+        // Comments and hex integers
+        let coin = replace("decimals = 8", "decimals = 0x2 # hundredths").unwrap();
+        assert_eq!(2, coin.decimals);
+
+        let path = env::temp_dir().join("blockparser-coin-missing.toml");
+        assert!(err_of(load(&path)).contains("Unable to read"));
+    }
+}
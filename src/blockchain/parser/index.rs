@@ -1,29 +1,44 @@
+use std::collections::HashMap;
 use std::convert::TryInto;
 use std::fmt;
 use std::io::Cursor;
 use std::path::Path;
 
-use byteorder::ReadBytesExt;
+use byteorder::{LittleEndian, ReadBytesExt};
 use rusty_leveldb::{LdbIterator, Options, DB};
 
-use crate::errors::OpResult;
+use crate::blockchain::parser::reader::BlockchainRead;
+use crate::blockchain::proto::block::BlockFormat;
+use crate::blockchain::proto::target::U256;
+use crate::errors::{OpError, OpResult};
 
+/// Validity levels of the status (BlockStatus in Bitcoin Core's chain.h)
 const BLOCK_VALID_CHAIN: usize = 4;
+const BLOCK_VALID_MASK: usize = 7;
 const BLOCK_HAVE_DATA: usize = 8;
+const BLOCK_HAVE_UNDO: usize = 16;
+/// The block or one of its ancestors failed validation
+const BLOCK_FAILED_MASK: usize = 32 | 64;
+/// Zcash: the block activates a network upgrade, its branch id precedes the header
+const BLOCK_ACTIVATES_UPGRADE: usize = 128;
 
 /// https://bitcoin.stackexchange.com/questions/28168/what-are-the-keys-used-in-the-blockchain-leveldb-ie-what-are-the-keyvalue-pair
 pub struct BlockIndexRecord {
     pub block_hash: [u8; 32],
+    pub prev_hash: [u8; 32],
     version: usize,
-    height: usize,
+    pub height: usize,
     status: usize,
     n_tx: usize,
     pub n_file: usize,
+    /// Offset of the block in the blk file, 0 if the data is missing (e.g. pruned)
     pub n_data_pos: u64,
+    n_undo_pos: u64,
 }
 
 impl BlockIndexRecord {
-    fn from(key: &[u8], values: &[u8]) -> OpResult<Self> {
+    /// Decodes a CDiskBlockIndex, file and positions are only present if the status has the data
+    fn from(key: &[u8], values: &[u8], format: BlockFormat) -> OpResult<Self> {
         let mut reader = Cursor::new(values);
 
         let block_hash: [u8; 32] = key.try_into().expect("leveldb: malformed blockhash");
@@ -31,39 +46,109 @@ impl BlockIndexRecord {
         let height = read_varint(&mut reader)?;
         let status = read_varint(&mut reader)?;
         let n_tx = read_varint(&mut reader)?;
-        let n_file = read_varint(&mut reader)?;
-        let n_data_pos = read_varint(&mut reader)? as u64;
+        let n_file = if status & (BLOCK_HAVE_DATA | BLOCK_HAVE_UNDO) > 0 {
+            read_varint(&mut reader)?
+        } else {
+            0
+        };
+        let n_data_pos = if status & BLOCK_HAVE_DATA > 0 {
+            read_varint(&mut reader)? as u64
+        } else {
+            0
+        };
+        let n_undo_pos = if status & BLOCK_HAVE_UNDO > 0 {
+            read_varint(&mut reader)? as u64
+        } else {
+            0
+        };
+        if format == BlockFormat::Zcash && status & BLOCK_ACTIVATES_UPGRADE > 0 {
+            reader.read_u32::<LittleEndian>()?;
+        }
+        // Only the beginning of Equihash headers, which has the same layout
+        let header = reader.read_block_header()?;
 
         Ok(BlockIndexRecord {
             block_hash,
+            prev_hash: header.prev_hash,
             version,
             height,
             status,
             n_tx,
             n_file,
             n_data_pos,
+            n_undo_pos,
         })
     }
+
+    /// Position of the undo data in the rev file with the number of the blk file, None if the
+    /// block has none (e.g. the genesis block or pruned)
+    pub fn undo_pos(&self) -> Option<u64> {
+        if self.status & BLOCK_HAVE_UNDO > 0 {
+            Some(self.n_undo_pos)
+        } else {
+            None
+        }
+    }
+
+    /// Fully validated up to the chain level with the block data on disk
+    #[inline]
+    fn is_valid_chain(&self) -> bool {
+        self.status & BLOCK_VALID_MASK >= BLOCK_VALID_CHAIN
+            && self.status & BLOCK_HAVE_DATA > 0
+            && self.status & BLOCK_FAILED_MASK == 0
+    }
+}
+
+#[cfg(test)]
+impl BlockIndexRecord {
+    /// Record of a fully validated block in blk file 0
+    pub fn with_data(
+        block_hash: [u8; 32],
+        prev_hash: [u8; 32],
+        height: usize,
+        n_data_pos: u64,
+    ) -> Self {
+        BlockIndexRecord {
+            block_hash,
+            prev_hash,
+            version: 250000,
+            height,
+            status: 5 | BLOCK_HAVE_DATA,
+            n_tx: 0,
+            n_file: 0,
+            n_data_pos,
+            n_undo_pos: 0,
+        }
+    }
+
+    /// Adds the position of the undo data in rev file 0
+    pub fn with_undo(mut self, n_undo_pos: u64) -> Self {
+        self.status |= BLOCK_HAVE_UNDO;
+        self.n_undo_pos = n_undo_pos;
+        self
+    }
 }
 
 impl fmt::Debug for BlockIndexRecord {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("BlockIndexRecord")
             .field("block_hash", &self.block_hash)
+            .field("prev_hash", &self.prev_hash)
             .field("version", &self.version)
             .field("height", &self.height)
             .field("status", &self.status)
             .field("n_tx", &self.n_tx)
             .field("n_file", &self.n_file)
             .field("n_data_pos", &self.n_data_pos)
+            .field("n_undo_pos", &self.n_undo_pos)
             .finish()
     }
 }
 
-pub fn get_block_index(path: &Path) -> OpResult<Vec<BlockIndexRecord>> {
+pub fn get_block_index(path: &Path, format: BlockFormat) -> OpResult<Vec<BlockIndexRecord>> {
     info!(target: "index", "Reading index from {} ...", path.display());
 
-    let mut block_index = Vec::with_capacity(800000);
+    let mut records = Vec::with_capacity(800000);
     let mut db = DB::open(path, Options::default())?;
     let mut iter = db.new_iter()?;
     let (mut k, mut v) = (vec![], vec![]);
@@ -71,35 +156,83 @@ pub fn get_block_index(path: &Path) -> OpResult<Vec<BlockIndexRecord>> {
     while iter.advance() {
         iter.current(&mut k, &mut v);
         if is_block_index_record(&k) {
-            let record = BlockIndexRecord::from(&k[1..], &v)?;
-            if record.status & (BLOCK_VALID_CHAIN | BLOCK_HAVE_DATA | BLOCK_VALID_CHAIN) > 0 {
-                block_index.push(record);
-            }
+            records.push(BlockIndexRecord::from(&k[1..], &v, format)?);
         }
     }
-    block_index.sort_by_key(|b| b.height);
+    let (block_index, n_stale) = best_chain(records);
     info!(target: "index", "Got longest chain with {} blocks ...", block_index.len());
+    if n_stale > 0 {
+        info!(target: "index", "Skipping {} stale blocks which are not part of the chain", n_stale);
+    }
     Ok(block_index)
 }
 
+/// Selects the chain from the genesis to the highest valid block, ordered by height. Of tips at
+/// the same height the one with the lower hash wins, like in a stream.
+/// Returns the chain and the number of blocks with data which are not part of it.
+fn best_chain(records: Vec<BlockIndexRecord>) -> (Vec<BlockIndexRecord>, usize) {
+    let tip = records
+        .iter()
+        .filter(|r| r.is_valid_chain())
+        .max_by(|a, b| {
+            a.height.cmp(&b.height).then_with(|| {
+                U256::from_le_bytes(&b.block_hash).cmp(&U256::from_le_bytes(&a.block_hash))
+            })
+        })
+        .map(|r| r.block_hash);
+    let n_with_data = records
+        .iter()
+        .filter(|r| r.status & BLOCK_HAVE_DATA > 0)
+        .count();
+    let mut by_hash: HashMap<[u8; 32], BlockIndexRecord> =
+        records.into_iter().map(|r| (r.block_hash, r)).collect();
+
+    let mut chain = Vec::with_capacity(
+        tip.and_then(|t| by_hash.get(&t))
+            .map_or(0, |r| r.height + 1),
+    );
+    let mut next = tip;
+    while let Some(record) = next.and_then(|hash| by_hash.remove(&hash)) {
+        next = if record.height > 0 {
+            Some(record.prev_hash)
+        } else {
+            None
+        };
+        chain.push(record);
+    }
+    chain.reverse();
+    if chain.first().is_some_and(|r| r.height != 0) {
+        warn!(target: "index", "Chain doesn't start at the genesis block, the index is incomplete");
+    }
+    let n_stale = n_with_data
+        - chain
+            .iter()
+            .filter(|r| r.status & BLOCK_HAVE_DATA > 0)
+            .count();
+    (chain, n_stale)
+}
+
 #[inline]
 fn is_block_index_record(data: &[u8]) -> bool {
-    *data.get(0).unwrap() == b'b'
+    *data.first().unwrap() == b'b'
 }
 
 /// TODO: this is a wonky 1:1 translation from https://github.com/bitcoin/bitcoin
 /// It is NOT the same as CompactSize.
-fn read_varint(reader: &mut Cursor<&[u8]>) -> OpResult<usize> {
+/// Values which don't fit into usize are an error, like the "ReadVarInt(): size too large"
+/// exception of Bitcoin Core.
+pub fn read_varint(reader: &mut Cursor<&[u8]>) -> OpResult<usize> {
+    let too_large = || OpError::from(String::from("VARINT size too large"));
     let mut n = 0;
     loop {
         let ch_data = reader.read_u8()?;
-        if n > usize::max_value() >> 7 {
-            panic!("size too large");
+        if n > usize::MAX >> 7 {
+            return Err(too_large());
         }
         n = (n << 7) | (ch_data & 0x7F) as usize;
         if ch_data & 0x80 > 0 {
-            if n == usize::max_value() {
-                panic!("size too large");
+            if n == usize::MAX {
+                return Err(too_large());
             }
             n += 1;
         } else {
@@ -108,3 +241,160 @@ fn read_varint(reader: &mut Cursor<&[u8]>) -> OpResult<usize> {
     }
     Ok(n)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::parser::types::{CoinType, Zcash};
+    use crate::common::utils;
+
+    #[test]
+    fn test_read_varint() {
+        // Bit patterns of Bitcoin Core's serialize_tests
+        let vectors: [(&str, usize); 8] = [
+            ("00", 0),
+            ("7f", 0x7f),
+            ("8000", 0x80),
+            ("a334", 0x1234),
+            ("82fe7f", 0xffff),
+            ("c7e756", 0x123456),
+            ("86ffc7e756", 0x80123456),
+            ("8efefefe7f", 0xffffffff),
+        ];
+        for (hex, n) in vectors.iter() {
+            let bytes = utils::hex_to_vec(hex);
+            let mut reader = Cursor::new(bytes.as_slice());
+            assert_eq!(*n, read_varint(&mut reader).unwrap());
+            assert_eq!(bytes.len() as u64, reader.position());
+        }
+        // 10 continuation bytes exceed 64 bits
+        let overlong = utils::hex_to_vec("ffffffffffffffffffff7f");
+        assert!(read_varint(&mut Cursor::new(overlong.as_slice())).is_err());
+        assert!(read_varint(&mut Cursor::new(&[0x80u8][..])).is_err());
+    }
+
+    #[test]
+    fn test_block_index_record() {
+        // CDiskBlockIndex of the genesis block: client version 250000, height 0,
+        // status 13 (VALID_SCRIPTS | HAVE_DATA), 1 tx, file 0, data pos 8 and the header
+        let genesis_hash = utils::hex_to_arr32_swapped(
+            "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f",
+        );
+        let genesis = BlockIndexRecord::from(
+            &genesis_hash,
+            &utils::hex_to_vec(concat!(
+                "8ea010000d010008",
+                "0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b2",
+                "7ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a29ab5f49ffff001d1dac2b7c",
+            )),
+            BlockFormat::Bitcoin,
+        )
+        .unwrap();
+        assert_eq!(250000, genesis.version);
+        assert_eq!(0, genesis.height);
+        assert_eq!(13, genesis.status);
+        assert_eq!(1, genesis.n_tx);
+        assert_eq!(0, genesis.n_file);
+        assert_eq!(8, genesis.n_data_pos);
+        assert_eq!(0, genesis.n_undo_pos);
+        assert_eq!(None, genesis.undo_pos());
+        assert_eq!([0u8; 32], genesis.prev_hash);
+        assert!(genesis.is_valid_chain());
+
+        // Block 1 with undo data: status 29 (VALID_SCRIPTS | HAVE_DATA | HAVE_UNDO), data pos 301, undo pos 8
+        let block_1 = BlockIndexRecord::from(
+            &utils::hex_to_arr32_swapped(
+                "00000000839a8e6886ab5951d76f411475428afc90947ee320161bbf18eb6048",
+            ),
+            &utils::hex_to_vec(concat!(
+                "8ea001011d0100812d08",
+                "010000006fe28c0ab6f1b372c1a6a246ae63f74f931e8365e15a089c68d6190000000000982051fd1e4ba744",
+                "bbbe680e1fee14677ba1a3c3540bf7b1cdb606e857233e0e61bc6649ffff001d01e36299",
+            )),
+            BlockFormat::Bitcoin,
+        )
+        .unwrap();
+        assert_eq!(1, block_1.height);
+        assert_eq!(301, block_1.n_data_pos);
+        assert_eq!(Some(8), block_1.undo_pos());
+        assert_eq!(genesis_hash, block_1.prev_hash);
+
+        // Headers only, neither file nor positions
+        let header_only = BlockIndexRecord::from(
+            &[1u8; 32],
+            &utils::hex_to_vec(concat!(
+                "8ea0010102",
+                "00",
+                "010000006fe28c0ab6f1b372c1a6a246ae63f74f931e8365e15a089c68d6190000000000982051fd1e4ba744",
+                "bbbe680e1fee14677ba1a3c3540bf7b1cdb606e857233e0e61bc6649ffff001d01e36299",
+            )),
+            BlockFormat::Bitcoin,
+        )
+        .unwrap();
+        assert_eq!(0, header_only.n_data_pos);
+        assert!(!header_only.is_valid_chain());
+
+        // Zcash block activating an upgrade: status 141 (VALID_SCRIPTS | HAVE_DATA | ACTIVATES_UPGRADE)
+        // and the branch id before the header
+        let values = utils::hex_to_vec(concat!(
+            "8ea00101800d010008",
+            "bb09b876",
+            "04000000",
+            "08ce3d9731b000c08338455c8a4a6bd05da16e26b11daa1b917184ece80f0400",
+            "0000000000000000000000000000000000000000000000000000000000000000000000000000000000000000",
+        ));
+        let upgrade = BlockIndexRecord::from(&[2u8; 32], &values, BlockFormat::Zcash).unwrap();
+        assert_eq!(141, upgrade.status);
+        assert_eq!(CoinType::from(Zcash).genesis_hash, upgrade.prev_hash);
+        assert!(upgrade.is_valid_chain());
+        // Other coins don't have a branch id
+        let bitcoin = BlockIndexRecord::from(&[2u8; 32], &values, BlockFormat::Bitcoin).unwrap();
+        assert_ne!(upgrade.prev_hash, bitcoin.prev_hash);
+    }
+
+    #[test]
+    fn test_best_chain() {
+        let record = |hash: u8, prev: u8, height: usize, status: usize| BlockIndexRecord {
+            block_hash: [hash; 32],
+            prev_hash: [prev; 32],
+            version: 250000,
+            height,
+            status,
+            n_tx: 1,
+            n_file: 0,
+            n_data_pos: hash as u64,
+            n_undo_pos: 0,
+        };
+        let valid = 5 | BLOCK_HAVE_DATA;
+        let records = vec![
+            record(3, 2, 2, valid),
+            record(1, 0, 0, valid),
+            // Stale block at height 1
+            record(9, 1, 1, valid),
+            record(2, 1, 1, valid | BLOCK_HAVE_UNDO),
+            // Longer branches which aren't valid or have no data
+            record(4, 9, 2, 3 | BLOCK_HAVE_DATA),
+            record(5, 4, 3, valid | 32),
+            record(6, 3, 3, 3 | BLOCK_HAVE_DATA),
+            record(7, 6, 4, 2),
+        ];
+        let (chain, n_stale) = best_chain(records);
+        let hashes: Vec<u8> = chain.iter().map(|r| r.block_hash[0]).collect();
+        // 4 and 6 have their data but aren't connected, they're counted as well
+        assert_eq!(vec![1, 2, 3], hashes);
+        assert_eq!(4, n_stale);
+
+        // Competing tips at the same height, the lower hash wins in any order
+        for (a, b) in [(2, 3), (3, 2)] {
+            let records = vec![
+                record(1, 0, 0, valid),
+                record(a, 1, 1, valid),
+                record(b, 1, 1, valid),
+            ];
+            let (chain, n_stale) = best_chain(records);
+            let hashes: Vec<u8> = chain.iter().map(|r| r.block_hash[0]).collect();
+            assert_eq!(vec![1, 2], hashes);
+            assert_eq!(1, n_stale);
+        }
+    }
+}
@@ -1,39 +1,46 @@
 use std::cell::RefCell;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
 use std::time::{Duration, Instant};
 
+use crate::blockchain::parser::progress::{Metrics, ProgressReporter};
+use crate::blockchain::parser::timings::Phase;
+use crate::blockchain::parser::unknown::UnknownScripts;
 use crate::blockchain::proto::block::Block;
-use crate::errors::OpResult;
+use crate::callbacks;
+use crate::common::logger;
+use crate::common::prometheus::{MetricsServer, Registry};
+use crate::common::utils;
+use crate::errors::{OpError, OpErrorKind, OpResult};
 use crate::ParserOptions;
 
-mod blkfile;
+pub mod blkfile;
 pub mod chain;
+pub mod coinfile;
 mod index;
+pub mod progress;
 pub mod reader;
+pub mod stream;
+pub mod timings;
 pub mod types;
+pub mod undo;
+pub mod unknown;
+pub mod zcash;
 
-/// Small struct to hold statistics together
-struct WorkerStats {
-    pub n_height: u64,
-    pub t_started: Instant,
-    pub t_last_log: Instant,
-    pub t_measure_frame: Duration,
-}
-
-impl Default for WorkerStats {
-    fn default() -> Self {
-        Self {
-            n_height: 0,
-            t_started: Instant::now(),
-            t_last_log: Instant::now(),
-            t_measure_frame: Duration::from_secs(10),
-        }
-    }
-}
+/// Longest sleep while waiting for new blocks, bounds the delay of an interrupt
+const SLEEP_STEP: Duration = Duration::from_millis(100);
 
 pub struct BlockchainParser<'a> {
     options: &'a RefCell<ParserOptions>, // struct to hold cli arguments
     chain_storage: chain::ChainStorage<'a>, // Hash storage with the longest chain
-    stats: WorkerStats,                  // struct for thread management & statistics
+    n_height: u64,                       // height of the next block
+    metrics: Arc<Metrics>,               // counters shared with the callback
+    progress: Option<ProgressReporter>,  // reports the progress to stderr
+    metrics_server: Option<MetricsServer>, // serves the metrics for Prometheus
+    interrupt: Option<&'a AtomicBool>,   // set by SIGINT or SIGTERM
+    callback_name: String,               // names the callback in its errors
+    unknown_scripts: Option<UnknownScripts>, // corpus of unrecognised output scripts
 }
 
 impl<'a> BlockchainParser<'a> {
@@ -43,65 +50,1805 @@ impl<'a> BlockchainParser<'a> {
         chain_storage: chain::ChainStorage<'a>,
     ) -> Self {
         info!(target: "parser", "Parsing {} blockchain (range={}) ...", options.borrow().coin_type.name, options.borrow().range);
+        let progress = options
+            .borrow()
+            .progress_interval
+            .map(ProgressReporter::new);
         Self {
+            n_height: options.borrow().range.start as u64,
             options,
             chain_storage,
-            stats: WorkerStats::default(),
+            metrics: Arc::default(),
+            progress,
+            metrics_server: None,
+            interrupt: None,
+            callback_name: options.borrow().callback_name.clone(),
+            unknown_scripts: None,
         }
     }
 
+    /// Stops parsing after the current block once the flag is set (see `common::signal`)
+    pub fn set_interrupt(&mut self, flag: &'a AtomicBool) {
+        self.interrupt = Some(flag);
+    }
+
     pub fn start(&mut self) -> OpResult<()> {
         debug!(target: "parser", "Starting worker ...");
 
+        let result = self.run();
+        // Stops the metrics server after errors as well
+        if let Some(mut server) = self.metrics_server.take() {
+            server.shutdown();
+            debug!(target: "parser", "Metrics server stopped.");
+        }
+        result
+    }
+
+    fn run(&mut self) -> OpResult<()> {
         self.on_start()?;
-        while let Some(block) = self.chain_storage.get_next() {
-            self.on_block(&block)?;
+        let follow = (*self.options.borrow().callback).follow_interval();
+        loop {
+            if self.interrupted() {
+                return self.on_interrupt();
+            }
+            match self.chain_storage.get_next()? {
+                Some((height, block)) => {
+                    self.n_height = height;
+                    self.on_block(&block)?;
+                    self.on_stale_blocks()?;
+                }
+                None => {
+                    self.on_stale_blocks()?;
+                    match follow {
+                        Some(interval) if !self.end_reached() => self.wait_for_blocks(interval)?,
+                        _ => break,
+                    }
+                }
+            }
         }
         self.on_complete()
     }
 
+    #[inline]
+    fn interrupted(&self) -> bool {
+        self.interrupt
+            .is_some_and(|flag| flag.load(Ordering::SeqCst))
+    }
+
+    /// True if the next block is past the end of the range
+    #[inline]
+    fn end_reached(&self) -> bool {
+        self.options
+            .borrow()
+            .range
+            .end
+            .is_some_and(|end| self.n_height > end as u64)
+    }
+
+    /// Triggers the on_tip() callback and checks the blockchain directory for new blocks in the
+    /// given interval until there are some. Returns early if interrupted.
+    fn wait_for_blocks(&mut self, interval: Duration) -> OpResult<()> {
+        (*self.options.borrow_mut().callback)
+            .on_tip(self.n_height)
+            .map_err(|e| self.callback_failed(e))?;
+        trace!(target: "parser", "on_tip(height={}) called", self.n_height);
+        info!(target: "parser", "Reached the tip at block {}, checking for new blocks every {:?} ...",
+              self.n_height.saturating_sub(1), interval);
+        loop {
+            let deadline = Instant::now() + interval;
+            loop {
+                if self.interrupted() {
+                    return Ok(());
+                }
+                let now = Instant::now();
+                if now >= deadline {
+                    break;
+                }
+                thread::sleep(SLEEP_STEP.min(deadline - now));
+            }
+            // The node may be writing the index, so errors are retried
+            match self.chain_storage.rescan() {
+                Ok(0) => trace!(target: "parser", "No new blocks."),
+                Ok(n_blocks) => {
+                    debug!(target: "parser", "Found {} new blocks.", n_blocks);
+                    return Ok(());
+                }
+                Err(e) => {
+                    warn!(target: "parser", "Unable to check for new blocks: {}. Retrying in {:?} ...", e, interval)
+                }
+            }
+        }
+    }
+
+    /// Lets the callback register its metrics and serves them with the counters of the parser
+    fn start_metrics_server(&mut self) -> OpResult<()> {
+        let addr = match self.options.borrow().metrics_listen.clone() {
+            Some(addr) => addr,
+            None => return Ok(()),
+        };
+        let mut registry = Registry::default();
+        (*self.options.borrow_mut().callback).register_metrics(&mut registry);
+        let metrics = Arc::clone(&self.metrics);
+        let server = MetricsServer::start(addr.as_str(), move || {
+            let mut out = String::new();
+            metrics.render(&mut out);
+            registry.render(&mut out);
+            out
+        })
+        .map_err(|e| OpError::from(e).join_msg(&format!("Unable to serve metrics on {}.", addr)))?;
+        info!(target: "parser", "Serving metrics on http://{}/metrics", server.local_addr());
+        self.metrics_server = Some(server);
+        Ok(())
+    }
+
     /// Triggers the on_start() callback and initializes state.
     fn on_start(&mut self) -> OpResult<()> {
         let coin_type = self.options.borrow().coin_type.clone();
-        self.stats.t_started = Instant::now();
-        self.stats.t_last_log = Instant::now();
-        (*self.options.borrow_mut().callback).on_start(&coin_type, self.stats.n_height)?;
+        (*self.options.borrow_mut().callback).set_metrics(Arc::clone(&self.metrics));
+        if self.options.borrow().use_undo_files {
+            (*self.options.borrow_mut().callback).set_prevouts_attached();
+        }
+        if (*self.options.borrow().callback).wants_timings() {
+            timings::enable();
+        }
+        if let Some(dir) = &self.options.borrow().dump_unknown_scripts {
+            self.unknown_scripts = Some(UnknownScripts::open(
+                dir,
+                self.options.borrow().max_unknown,
+            )?);
+        }
+        self.start_metrics_server()?;
+        (*self.options.borrow_mut().callback)
+            .on_start(&coin_type, self.n_height)
+            .map_err(|e| self.callback_failed(e))?;
         trace!(target: "parser", "on_start() called");
+
+        let mut resumed = false;
+        let resume_height = (*self.options.borrow().callback).resume_height();
+        if let Some(height) = resume_height {
+            if height > self.n_height {
+                info!(target: "parser", "Resuming at block {} ...", height);
+                self.chain_storage.skip_to(height as usize);
+                self.n_height = height;
+                resumed = true;
+            }
+        }
+        // The size of the blk files only tells the remaining bytes if all of them are read
+        let range = &self.options.borrow().range;
+        let total_bytes = if range.start == 0 && range.end.is_none() && !resumed {
+            Some(self.chain_storage.total_bytes())
+        } else {
+            None
+        };
+        self.metrics
+            .set_totals(Some(self.chain_storage.remaining() as u64), total_bytes);
         Ok(())
     }
 
     /// Triggers the on_block() callback and updates statistics.
     fn on_block(&mut self, block: &Block) -> OpResult<()> {
-        (*self.options.borrow_mut().callback).on_block(block, self.stats.n_height)?;
-        trace!(target: "parser", "on_block(height={}) called", self.stats.n_height);
-        self.stats.n_height += 1;
+        logger::set_height(self.n_height);
+        if let Some(unknown_scripts) = self.unknown_scripts.as_mut() {
+            unknown_scripts.add_block(block, self.n_height)?;
+        }
+        timings::measure(Phase::Dispatch, || {
+            callbacks::deliver_block(
+                &mut *self.options.borrow_mut().callback,
+                block,
+                self.n_height,
+            )
+        })
+        .map_err(|e| self.callback_failed(e))?;
+        trace!(target: "parser", "on_block(height={}) called", self.n_height);
+        self.metrics
+            .add_block(self.n_height, block.tx_count.value, block.size as u64);
+        self.n_height += 1;
+
+        if let Some(progress) = self.progress.as_mut() {
+            progress.tick(&self.metrics);
+        }
+        Ok(())
+    }
 
-        // Some performance measurements and logging
-        let now = Instant::now();
-        if now - self.stats.t_last_log > self.stats.t_measure_frame {
-            info!(target: "parser", "Status: {:6} Blocks processed. (left: {:6}, avg: {:5.2} blocks/sec)",
-                  self.stats.n_height, self.chain_storage.remaining(), self.blocks_sec());
-            self.stats.t_last_log = now;
+    /// Triggers the on_stale_block() callback for the blocks dropped since the last call,
+    /// only with `--include-orphans`.
+    fn on_stale_blocks(&mut self) -> OpResult<()> {
+        for (height, block) in self.chain_storage.take_stale() {
+            (*self.options.borrow_mut().callback)
+                .on_stale_block(&block, height)
+                .map_err(|e| self.callback_failed(e))?;
+            trace!(target: "parser", "on_stale_block(height={}) called", height);
         }
         Ok(())
     }
 
+    /// Triggers the on_interrupt() callback and returns an `Interrupted` error once it succeeded.
+    fn on_interrupt(&mut self) -> OpResult<()> {
+        if let Some(progress) = self.progress.as_mut() {
+            progress.finish();
+        }
+        warn!(target: "parser", "Interrupted after {} blocks, finishing callback ...", self.metrics.n_blocks());
+        if let Some(unknown_scripts) = self.unknown_scripts.as_mut() {
+            unknown_scripts.finish()?;
+        }
+        (*self.options.borrow_mut().callback)
+            .on_interrupt(self.n_height)
+            .map_err(|e| self.callback_failed(e))?;
+        trace!(target: "parser", "on_interrupt() called");
+        Err(OpError::new(OpErrorKind::Interrupted).join_msg(&format!(
+            "Stopped before block {}, all blocks below are processed.",
+            self.n_height
+        )))
+    }
+
     /// Triggers the on_complete() callback and updates statistics.
     fn on_complete(&mut self) -> OpResult<()> {
+        if let Some(progress) = self.progress.as_mut() {
+            progress.finish();
+        }
         info!(target: "parser", "Done. Processed {} blocks in {:.2} minutes. (avg: {:5.2} blocks/sec)",
-              self.stats.n_height, (Instant::now() - self.stats.t_started).as_secs_f32() / 60.0,
+              self.metrics.n_blocks(), self.metrics.elapsed().as_secs_f32() / 60.0,
               self.blocks_sec());
+        let corrupt = self.chain_storage.corrupt_regions();
+        if !corrupt.is_empty() {
+            warn!(target: "parser", "Skipped {} corrupt blocks:", corrupt.len());
+            for region in corrupt {
+                let block = region
+                    .height
+                    .map_or_else(|| String::from("unknown"), |h| h.to_string());
+                warn!(target: "parser", "  -> block {}: {} bytes {}..{}",
+                      block, region.path.display(), region.start, region.end);
+            }
+        }
+        let invalid = self.chain_storage.invalid_blocks();
+        if !invalid.is_empty() {
+            warn!(target: "parser", "Skipped {} blocks failing verification:", invalid.len());
+            for block in invalid {
+                warn!(target: "parser", "  -> block {} ({}): {}",
+                      block.height, utils::arr_to_hex_swapped(&block.hash), block.reason);
+            }
+        }
+        if let Some(unknown_scripts) = self.unknown_scripts.as_mut() {
+            unknown_scripts.finish()?;
+        }
 
-        (*self.options.borrow_mut().callback).on_complete(self.stats.n_height)?;
+        (*self.options.borrow_mut().callback)
+            .on_complete(self.n_height)
+            .map_err(|e| self.callback_failed(e))?;
         trace!(target: "parser", "on_complete() called");
         Ok(())
     }
 
+    /// Counts the error of the callback and names the callback in it
+    fn callback_failed(&self, err: OpError) -> OpError {
+        self.metrics.add_callback_error();
+        OpError::callback(&self.callback_name, err)
+    }
+
     /// Returns the number of avg processed blocks
     fn blocks_sec(&self) -> u64 {
-        self.stats
-            .n_height
-            .checked_div((Instant::now() - self.stats.t_started).as_secs())
-            .unwrap_or(self.stats.n_height)
+        self.metrics
+            .n_blocks()
+            .checked_div(self.metrics.elapsed().as_secs())
+            .unwrap_or(self.metrics.n_blocks())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::env;
+    use std::fs;
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::path::{Path, PathBuf};
+    use std::process;
+    use std::rc::Rc;
+    use std::time::{Duration, Instant};
+
+    use clap::{App, ArgMatches, SubCommand};
+
+    use super::*;
+    use crate::blockchain::network;
+    use crate::blockchain::parser::index::BlockIndexRecord;
+    use crate::blockchain::parser::types::{Bitcoin, CoinType};
+    use crate::blockchain::parser::undo;
+    use crate::blockchain::proto::target;
+    use crate::blockchain::proto::tx::EvaluatedTx;
+    use crate::blockchain::proto::Hashed;
+    use crate::blockchain::proto::ToRaw;
+    use crate::callbacks::csvdump::CsvDump;
+    use crate::callbacks::fixtures::{self, new_chain, new_tx};
+    use crate::callbacks::multi::Multi;
+    use crate::callbacks::opreturnanalyze::OpReturnAnalyze;
+    use crate::callbacks::Callback;
+    use crate::common::prometheus::{self, Gauge};
+    use crate::errors::ErrorCategory;
+    use crate::ParseRange;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("blockparser-parser-{}-{}", name, process::id()))
+    }
+
+    /// Blocks with `n_txs` transactions each, every transaction spends one of its predecessor
+    fn spending_chain(n_blocks: usize, n_txs: usize) -> Vec<Block> {
+        let mut prev = [0u8; 32];
+        let blocks: Vec<Vec<Hashed<EvaluatedTx>>> = (0..n_blocks)
+            .map(|height| {
+                (0..n_txs)
+                    .map(|i| {
+                        let values: Vec<u64> =
+                            (0..(height + i) % 5 + 1).map(|v| 1000 + v as u64).collect();
+                        let tx = new_tx(&[(prev, 0)], &values);
+                        prev = tx.hash;
+                        tx
+                    })
+                    .collect()
+            })
+            .collect();
+        new_chain(blocks)
+    }
+
+    /// Writes the chain to `blk00000.dat` and returns its block index
+    fn write_blk_file(dir: &Path, chain: &[Block]) -> Vec<BlockIndexRecord> {
+        fs::create_dir_all(dir).unwrap();
+        let mut blk_file = Vec::new();
+        let mut index = Vec::new();
+        for (height, block) in chain.iter().enumerate() {
+            let bytes = block.to_bytes();
+            blk_file.extend_from_slice(&0xd9b4bef9u32.to_le_bytes());
+            blk_file.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+            index.push(BlockIndexRecord::with_data(
+                block.header.hash,
+                block.header.value.prev_hash,
+                height,
+                blk_file.len() as u64,
+            ));
+            blk_file.extend_from_slice(&bytes);
+        }
+        fs::write(dir.join("blk00000.dat"), blk_file).unwrap();
+        index
+    }
+
+    /// Options of a plain single threaded parse, tests override the fields they need
+    fn test_options() -> ParserOptions {
+        ParserOptions {
+            callback_name: String::from("heights"),
+            callback: Box::new(Heights(Rc::default())),
+            coin_type: CoinType::from(Bitcoin),
+            verify: false,
+            classify_inputs: false,
+            use_undo_files: false,
+            blockchain_dir: PathBuf::new(),
+            block_files: Vec::new(),
+            xor_key: None,
+            threads: 1,
+            mmap: false,
+            strict: false,
+            include_orphans: false,
+            check_pow: false,
+            keep_going: false,
+            progress_interval: None,
+            metrics_listen: None,
+            p2p_fallback: None,
+            p2p_timeout: Duration::from_secs(30),
+            dump_unknown_scripts: None,
+            max_unknown: unknown::DEFAULT_MAX_UNKNOWN,
+            log_level_filter: log::LevelFilter::Off,
+            range: ParseRange::new(0, None).unwrap(),
+        }
+    }
+
+    /// Runs csvdump over the blk file and returns the names and contents of the dumped files
+    fn run_csvdump(
+        blockchain_dir: &Path,
+        chain: &[Block],
+        threads: usize,
+        mmap: bool,
+    ) -> Vec<(String, String)> {
+        let dump_dir = blockchain_dir.join(format!("dump-{}-{}", threads, mmap));
+        fs::create_dir_all(&dump_dir).unwrap();
+        let matches = CsvDump::build_subcommand()
+            .get_matches_from(vec!["csvdump", dump_dir.to_str().unwrap()]);
+        let options = RefCell::new(ParserOptions {
+            callback_name: String::from("csvdump"),
+            callback: Box::new(CsvDump::new(&matches).unwrap()),
+            classify_inputs: true,
+            blockchain_dir: PathBuf::from(blockchain_dir),
+            threads,
+            mmap,
+            ..test_options()
+        });
+        let index = write_blk_file(blockchain_dir, chain);
+        let chain_storage = chain::ChainStorage::from_index(&options, index).unwrap();
+        BlockchainParser::new(&options, chain_storage)
+            .start()
+            .unwrap();
+        // Flushes the writers of the callback
+        drop(options);
+        read_dump(&dump_dir)
+    }
+
+    /// Parses the chain from a blk file with the given callback
+    fn run_callback(
+        blockchain_dir: &Path,
+        chain: &[Block],
+        callback: Box<dyn Callback>,
+        threads: usize,
+    ) {
+        let options = RefCell::new(ParserOptions {
+            callback_name: String::from("test"),
+            callback,
+            blockchain_dir: PathBuf::from(blockchain_dir),
+            threads,
+            ..test_options()
+        });
+        let index = write_blk_file(blockchain_dir, chain);
+        let chain_storage = chain::ChainStorage::from_index(&options, index).unwrap();
+        BlockchainParser::new(&options, chain_storage)
+            .start()
+            .unwrap();
+    }
+
+    /// Names and contents of the files in the directory, sorted by name
+    fn read_dump(dump_dir: &Path) -> Vec<(String, String)> {
+        let mut files: Vec<(String, String)> = fs::read_dir(dump_dir)
+            .unwrap()
+            .map(|entry| {
+                let path = entry.unwrap().path();
+                (
+                    path.file_name().unwrap().to_string_lossy().into_owned(),
+                    fs::read_to_string(&path).unwrap(),
+                )
+            })
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn test_stream_from_pipe() {
+        let dir = temp_dir("stream");
+        // Blocks beyond the confirmations are delivered before the stream ends
+        let chain = spending_chain(stream::CONFIRMATIONS as usize + 20, 3);
+        let expected = run_csvdump(&dir, &chain, 1, false);
+
+        let mut data = fs::read(dir.join("blk00000.dat")).unwrap();
+        // Padding of a preallocated blk file and a stale block on top of block 10
+        data.extend([0u8; 64]);
+        let mut bytes = chain[11].to_bytes();
+        // Another nonce
+        bytes[76] ^= 1;
+        data.extend(0xd9b4bef9u32.to_le_bytes());
+        data.extend((bytes.len() as u32).to_le_bytes());
+        data.extend(bytes);
+
+        let pipe = dir.join("pipe");
+        let c_path = std::ffi::CString::new(pipe.to_str().unwrap()).unwrap();
+        assert_eq!(0, unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) });
+        let writer = {
+            let pipe = pipe.clone();
+            std::thread::spawn(move || fs::write(pipe, data).unwrap())
+        };
+
+        let dump_dir = dir.join("dump-stream");
+        fs::create_dir_all(&dump_dir).unwrap();
+        let matches = CsvDump::build_subcommand()
+            .get_matches_from(vec!["csvdump", dump_dir.to_str().unwrap()]);
+        let options = RefCell::new(ParserOptions {
+            callback_name: String::from("csvdump"),
+            callback: Box::new(CsvDump::new(&matches).unwrap()),
+            classify_inputs: true,
+            blockchain_dir: pipe,
+            threads: 4,
+            mmap: true,
+            strict: true,
+            ..test_options()
+        });
+        let chain_storage = chain::ChainStorage::new(&options).unwrap();
+        let mut parser = BlockchainParser::new(&options, chain_storage);
+        parser.start().unwrap();
+        writer.join().unwrap();
+        // Nothing to size, so no ETA
+        assert_eq!(
+            (None, None),
+            (parser.metrics.total_blocks(), parser.metrics.total_bytes())
+        );
+        assert!(parser.chain_storage.corrupt_regions().is_empty());
+        drop(parser);
+        drop(options);
+        assert_eq!(expected, read_dump(&dump_dir));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Runs csvdump over the block files given by `--block-file` patterns
+    fn run_block_files(dir: &Path, name: &str, patterns: &[&str]) -> Vec<(String, String)> {
+        let dump_dir = dir.join(format!("dump-{}", name));
+        fs::create_dir_all(&dump_dir).unwrap();
+        let matches = CsvDump::build_subcommand()
+            .get_matches_from(vec!["csvdump", dump_dir.to_str().unwrap()]);
+        let options = RefCell::new(ParserOptions {
+            callback_name: String::from("csvdump"),
+            callback: Box::new(CsvDump::new(&matches).unwrap()),
+            classify_inputs: true,
+            blockchain_dir: dir.join("nonexistent"),
+            block_files: stream::expand_block_files(patterns.iter().copied()).unwrap(),
+            threads: 4,
+            mmap: true,
+            strict: true,
+            ..test_options()
+        });
+        let chain_storage = chain::ChainStorage::new(&options).unwrap();
+        BlockchainParser::new(&options, chain_storage)
+            .start()
+            .unwrap();
+        drop(options);
+        read_dump(&dump_dir)
+    }
+
+    #[test]
+    fn test_block_files() {
+        let dir = temp_dir("block-files");
+        let chain = spending_chain(stream::CONFIRMATIONS as usize + 20, 3);
+        let expected = run_csvdump(&dir.join("blocks"), &chain, 1, false);
+
+        // A bootstrap.dat is a blk file with all blocks, or split into several files
+        let data = fs::read(dir.join("blocks").join("blk00000.dat")).unwrap();
+        let files = dir.join("files");
+        fs::create_dir_all(&files).unwrap();
+        fs::write(files.join("bootstrap.dat"), &data).unwrap();
+        let split = write_blk_file(&dir.join("blocks"), &chain)[60].n_data_pos as usize - 8;
+        fs::write(files.join("bootstrap-1.dat"), &data[..split]).unwrap();
+        fs::write(files.join("bootstrap-2.dat"), &data[split..]).unwrap();
+
+        let path = |name: &str| String::from(files.join(name).to_str().unwrap());
+        assert_eq!(
+            expected,
+            run_block_files(&dir, "single", &[&path("bootstrap.dat")])
+        );
+        assert_eq!(
+            expected,
+            run_block_files(&dir, "glob", &[&path("bootstrap-*.dat")])
+        );
+        // The later blocks come first and wait for their parents
+        assert_eq!(
+            expected,
+            run_block_files(
+                &dir,
+                "reversed",
+                &[&path("bootstrap-2.dat"), &path("bootstrap-1.dat")]
+            )
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Records the heights and values of the outputs attached to the inputs
+    #[derive(Default)]
+    struct Prevouts {
+        attached: bool,
+        seen: Rc<RefCell<Vec<String>>>,
+    }
+
+    impl Callback for Prevouts {
+        fn build_subcommand<'a, 'b>() -> App<'a, 'b> {
+            SubCommand::with_name("prevouts")
+        }
+
+        fn new(_: &ArgMatches) -> OpResult<Self> {
+            Ok(Prevouts::default())
+        }
+
+        fn set_prevouts_attached(&mut self) {
+            self.attached = true;
+        }
+
+        fn on_start(&mut self, _: &CoinType, _: u64) -> OpResult<()> {
+            assert!(self.attached);
+            Ok(())
+        }
+
+        fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<()> {
+            for tx in &block.txs {
+                for input in &tx.value.inputs {
+                    let seen = match &input.prevout {
+                        Some(prevout) => format!(
+                            "{}: {} {} {}",
+                            block_height,
+                            prevout.height,
+                            prevout.out.out.value,
+                            prevout.out.script.address.as_deref().unwrap_or("")
+                        ),
+                        None => format!("{}: none", block_height),
+                    };
+                    self.seen.borrow_mut().push(seen);
+                }
+            }
+            Ok(())
+        }
+
+        fn on_complete(&mut self, _: u64) -> OpResult<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_use_undo_files() {
+        let dir = temp_dir("undo");
+        // Each block spends the coinbase of its predecessor
+        let mut blocks = Vec::new();
+        let mut prev_coinbase = None;
+        for height in 0..5u64 {
+            let coinbase = new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[5000 + height]);
+            let mut txs = Vec::new();
+            if let Some(txid) = prev_coinbase {
+                txs.push(new_tx(&[(txid, 0)], &[1000]));
+            }
+            prev_coinbase = Some(coinbase.hash);
+            txs.insert(0, coinbase);
+            blocks.push(txs);
+        }
+        let chain = new_chain(blocks);
+        // Writes the blk and rev file, `without_undo` has no undo data in the index
+        let write_files = |without_undo: usize| {
+            let mut rev_file = Vec::new();
+            let index: Vec<BlockIndexRecord> = write_blk_file(&dir, &chain)
+                .into_iter()
+                .enumerate()
+                .map(|(height, record)| {
+                    if height == 0 || height == without_undo {
+                        return record;
+                    }
+                    let spent = vec![vec![undo::SpentOutput {
+                        height: height as u64 - 1,
+                        is_coinbase: true,
+                        value: 5000 + height as u64 - 1,
+                        script_pubkey: utils::hex_to_vec(fixtures::SCRIPT_A),
+                    }]];
+                    let data = undo::serialize_block_undo(&spent);
+                    let prev_hash = chain[height].header.value.prev_hash;
+                    rev_file.extend_from_slice(&0xd9b4bef9u32.to_le_bytes());
+                    rev_file.extend_from_slice(&(data.len() as u32).to_le_bytes());
+                    let record = record.with_undo(rev_file.len() as u64);
+                    rev_file.extend_from_slice(&data);
+                    rev_file.extend(utils::sha256d(&[&prev_hash[..], &data[..]].concat()));
+                    record
+                })
+                .collect();
+            fs::write(dir.join("rev00000.dat"), rev_file).unwrap();
+            index
+        };
+
+        let run = |index: Vec<BlockIndexRecord>| {
+            let seen: Rc<RefCell<Vec<String>>> = Rc::default();
+            let options = RefCell::new(ParserOptions {
+                callback_name: String::from("prevouts"),
+                callback: Box::new(Prevouts {
+                    attached: false,
+                    seen: Rc::clone(&seen),
+                }),
+                use_undo_files: true,
+                blockchain_dir: dir.clone(),
+                threads: 2,
+                mmap: true,
+                // Block 2 spends an output created before the start
+                range: ParseRange::new(2, None).unwrap(),
+                ..test_options()
+            });
+            let chain_storage = chain::ChainStorage::from_index(&options, index).unwrap();
+            let result = BlockchainParser::new(&options, chain_storage).start();
+            let seen: Vec<String> = seen.borrow().clone();
+            (result, seen)
+        };
+
+        let address = "12higDjoCCNXSA95xZMWUdPvXNmkAduhWv";
+        let (result, seen) = run(write_files(0));
+        result.unwrap();
+        let expected: Vec<String> = (2..5)
+            .flat_map(|height| {
+                [
+                    format!("{}: none", height),
+                    format!(
+                        "{}: {} {} {}",
+                        height,
+                        height - 1,
+                        5000 + height - 1,
+                        address
+                    ),
+                ]
+            })
+            .collect();
+        assert_eq!(expected, seen);
+
+        // Block 3 without undo data stops the parser
+        let (result, seen) = run(write_files(3));
+        assert!(result.is_err());
+        assert_eq!(expected[..2], seen[..]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Runs csvdump with the first `n_on_disk` blocks on disk, block 5 is unreadable.
+    /// The peer serves the whole chain. Returns the dump and the number of skipped regions.
+    fn run_with_fallback(
+        dir: &Path,
+        chain: &[Block],
+        n_on_disk: usize,
+        stall_after: Option<usize>,
+    ) -> (Vec<(String, String)>, usize) {
+        let raw_chain = chain.iter().map(|block| block.to_bytes()).collect();
+        let addr = network::fake_peer(0xd9b4bef9, raw_chain, stall_after);
+        let index = write_blk_file(dir, &chain[..n_on_disk]);
+        let path = dir.join("blk00000.dat");
+        let mut data = fs::read(&path).unwrap();
+        let start = index[5].n_data_pos as usize;
+        data[start + 80..start + 90].fill(0xff);
+        fs::write(&path, data).unwrap();
+
+        let dump_dir = dir.join("dump-p2p");
+        fs::create_dir_all(&dump_dir).unwrap();
+        let matches = CsvDump::build_subcommand()
+            .get_matches_from(vec!["csvdump", dump_dir.to_str().unwrap()]);
+        let options = RefCell::new(ParserOptions {
+            callback_name: String::from("csvdump"),
+            callback: Box::new(CsvDump::new(&matches).unwrap()),
+            classify_inputs: true,
+            blockchain_dir: PathBuf::from(dir),
+            threads: 4,
+            mmap: true,
+            p2p_fallback: Some(addr.to_string()),
+            p2p_timeout: Duration::from_millis(500),
+            ..test_options()
+        });
+        let chain_storage = chain::ChainStorage::from_index(&options, index).unwrap();
+        let mut parser = BlockchainParser::new(&options, chain_storage);
+        parser.start().unwrap();
+        let n_corrupt = parser.chain_storage.corrupt_regions().len();
+        drop(parser);
+        // Flushes the writers of the callback
+        drop(options);
+        (read_dump(&dump_dir), n_corrupt)
+    }
+
+    #[test]
+    fn test_p2p_fallback() {
+        let dir = temp_dir("p2p");
+        let chain = spending_chain(40, 3);
+        let expected = run_csvdump(&dir.join("all"), &chain, 1, false);
+        assert_eq!(
+            (expected, 0),
+            run_with_fallback(&dir.join("p2p"), &chain, 20, None)
+        );
+
+        // The peer stalls in the second batch past the tip, the first one is complete
+        let expected = run_csvdump(&dir.join("partial"), &chain[..36], 1, false);
+        assert_eq!(
+            (expected, 0),
+            run_with_fallback(&dir.join("stalled"), &chain, 20, Some(19))
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_parallel_parsing_is_deterministic() {
+        let dir = temp_dir("determinism");
+        let chain = spending_chain(40, 12);
+        let sequential = run_csvdump(&dir, &chain, 1, false);
+        assert_eq!(4, sequential.len());
+        // Genesis plus 39 blocks
+        assert_eq!("blocks-0-40.csv", sequential[0].0);
+        assert_eq!(40, sequential[0].1.lines().count());
+        for threads in [1, 2, 3, 8] {
+            for mmap in [false, true] {
+                assert_eq!(
+                    sequential,
+                    run_csvdump(&dir, &chain, threads, mmap),
+                    "{} threads, mmap: {}",
+                    threads,
+                    mmap
+                );
+            }
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Collects the heights passed to `on_block`
+    struct Heights(Rc<RefCell<Vec<u64>>>);
+
+    impl Callback for Heights {
+        fn build_subcommand<'a, 'b>() -> App<'a, 'b> {
+            SubCommand::with_name("heights")
+        }
+
+        fn new(_: &ArgMatches) -> OpResult<Self> {
+            Ok(Heights(Rc::default()))
+        }
+
+        fn on_start(&mut self, _: &CoinType, _: u64) -> OpResult<()> {
+            Ok(())
+        }
+
+        fn on_block(&mut self, _: &Block, block_height: u64) -> OpResult<()> {
+            self.0.borrow_mut().push(block_height);
+            Ok(())
+        }
+
+        fn on_complete(&mut self, _: u64) -> OpResult<()> {
+            Ok(())
+        }
+    }
+
+    /// Records the metrics seen in `on_block`
+    #[derive(Default)]
+    struct MetricsReader {
+        metrics: Option<Arc<Metrics>>,
+        seen: Rc<RefCell<Vec<(u64, u64, u64)>>>,
+    }
+
+    impl Callback for MetricsReader {
+        fn build_subcommand<'a, 'b>() -> App<'a, 'b> {
+            SubCommand::with_name("metrics")
+        }
+
+        fn new(_: &ArgMatches) -> OpResult<Self> {
+            Ok(MetricsReader::default())
+        }
+
+        fn set_metrics(&mut self, metrics: Arc<Metrics>) {
+            self.metrics = Some(metrics);
+        }
+
+        fn on_start(&mut self, _: &CoinType, _: u64) -> OpResult<()> {
+            Ok(())
+        }
+
+        fn on_block(&mut self, _: &Block, _: u64) -> OpResult<()> {
+            let metrics = self.metrics.as_ref().unwrap();
+            self.seen
+                .borrow_mut()
+                .push((metrics.height(), metrics.n_blocks(), metrics.n_txs()));
+            Ok(())
+        }
+
+        fn on_complete(&mut self, _: u64) -> OpResult<()> {
+            Ok(())
+        }
+    }
+
+    /// Records the calls of `on_block` and `on_transaction`
+    struct Events {
+        scripts: bool,
+        calls: Rc<RefCell<Vec<String>>>,
+    }
+
+    impl Callback for Events {
+        fn build_subcommand<'a, 'b>() -> App<'a, 'b> {
+            SubCommand::with_name("events")
+        }
+
+        fn new(_: &ArgMatches) -> OpResult<Self> {
+            Ok(Events {
+                scripts: true,
+                calls: Rc::default(),
+            })
+        }
+
+        fn wants_script_evaluation(&self) -> bool {
+            self.scripts
+        }
+
+        fn on_start(&mut self, _: &CoinType, _: u64) -> OpResult<()> {
+            Ok(())
+        }
+
+        fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<()> {
+            self.calls.borrow_mut().push(format!(
+                "block {} ({} txs)",
+                block_height,
+                block.txs.len()
+            ));
+            Ok(())
+        }
+
+        fn on_transaction(
+            &mut self,
+            tx: &Hashed<EvaluatedTx>,
+            block_height: u64,
+            index: usize,
+        ) -> OpResult<()> {
+            let output = &tx.value.outputs[0];
+            assert!(!output.out.script_pubkey.is_empty());
+            self.calls.borrow_mut().push(format!(
+                "tx {}:{} {}",
+                block_height,
+                index,
+                output.script.pattern.type_name()
+            ));
+            Ok(())
+        }
+
+        fn on_complete(&mut self, _: u64) -> OpResult<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_on_transaction() {
+        let dir = temp_dir("on-transaction");
+        let chain = spending_chain(3, 2);
+        for threads in [1, 2] {
+            for scripts in [true, false] {
+                let calls = Rc::new(RefCell::new(Vec::new()));
+                let events = Events {
+                    scripts,
+                    calls: Rc::clone(&calls),
+                };
+                run_callback(&dir, &chain, Box::new(events), threads);
+                let pattern = if scripts { "pubkeyhash" } else { "unknown" };
+                let mut expected = Vec::new();
+                for height in 0..3 {
+                    expected.push(format!("block {} (2 txs)", height));
+                    for index in 0..2 {
+                        expected.push(format!("tx {}:{} {}", height, index, pattern));
+                    }
+                }
+                assert_eq!(expected, *calls.borrow());
+            }
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Compares opreturn-analyze, which doesn't need evaluated scripts, with a run which
+    /// evaluates them, run with `cargo test --release -- --ignored bench`
+    #[test]
+    #[ignore]
+    fn bench_script_evaluation() {
+        let dir = temp_dir("bench-scripts");
+        let chain = spending_chain(200, 2000);
+        for scripts in [true, false] {
+            let matches = OpReturnAnalyze::build_subcommand().get_matches_from(vec![
+                "opreturn-analyze",
+                "--output-dir",
+                dir.join("opreturn").to_str().unwrap(),
+            ]);
+            let mut callbacks: Vec<(String, Box<dyn Callback>)> = vec![(
+                String::from("opreturn-analyze"),
+                Box::new(OpReturnAnalyze::new(&matches).unwrap()),
+            )];
+            if scripts {
+                // Only makes the parser evaluate the scripts
+                callbacks.push((String::from("heights"), Box::new(Heights(Rc::default()))));
+            }
+            let started = Instant::now();
+            run_callback(&dir, &chain, Box::new(Multi::from_callbacks(callbacks)), 1);
+            println!(
+                "scripts evaluated: {:5}: {:.2}s",
+                scripts,
+                started.elapsed().as_secs_f32()
+            );
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_metrics() {
+        let dir = temp_dir("metrics");
+        let chain = spending_chain(4, 3);
+        let reader = MetricsReader::default();
+        let seen = Rc::clone(&reader.seen);
+        let options = RefCell::new(ParserOptions {
+            callback_name: String::from("reader"),
+            callback: Box::new(reader),
+            blockchain_dir: dir.clone(),
+            ..test_options()
+        });
+        let index = write_blk_file(&dir, &chain);
+        let chain_storage = chain::ChainStorage::from_index(&options, index).unwrap();
+        let mut parser = BlockchainParser::new(&options, chain_storage);
+        parser.start().unwrap();
+
+        // Updated after each block
+        assert_eq!(
+            vec![(0, 0, 0), (0, 1, 3), (1, 2, 6), (2, 3, 9)],
+            *seen.borrow()
+        );
+        assert_eq!(
+            (3, 4, 12),
+            (
+                parser.metrics.height(),
+                parser.metrics.n_blocks(),
+                parser.metrics.n_txs()
+            )
+        );
+        assert_eq!(Some(4), parser.metrics.total_blocks());
+        let blk_size = fs::metadata(dir.join("blk00000.dat")).unwrap().len();
+        assert_eq!(Some(blk_size), parser.metrics.total_bytes());
+        // Without the magic and size of each block
+        assert_eq!(blk_size - 4 * 8, parser.metrics.n_bytes());
+        assert_eq!(Some(Duration::ZERO), parser.metrics.eta());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Registers a gauge and scrapes the metrics server while the blocks arrive
+    struct Scraper {
+        addr: SocketAddr,
+        gauge: Option<Gauge>,
+        scrapes: Rc<RefCell<Vec<String>>>,
+    }
+
+    impl Callback for Scraper {
+        fn build_subcommand<'a, 'b>() -> App<'a, 'b> {
+            SubCommand::with_name("scraper")
+        }
+
+        fn new(_: &ArgMatches) -> OpResult<Self> {
+            unimplemented!()
+        }
+
+        fn register_metrics(&mut self, registry: &mut Registry) {
+            self.gauge = Some(registry.gauge("scraper_last_height", "Last height seen"));
+        }
+
+        fn on_start(&mut self, _: &CoinType, _: u64) -> OpResult<()> {
+            Ok(())
+        }
+
+        fn on_block(&mut self, _: &Block, block_height: u64) -> OpResult<()> {
+            self.gauge.as_ref().unwrap().set(block_height as f64);
+            let (status, body) = prometheus::scrape(self.addr, "/metrics");
+            assert_eq!("HTTP/1.1 200 OK", status);
+            self.scrapes.borrow_mut().push(body);
+            if block_height == 3 {
+                return Err(OpError::from(String::from("stop")));
+            }
+            Ok(())
+        }
+
+        fn on_complete(&mut self, _: u64) -> OpResult<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_metrics_server() {
+        let dir = temp_dir("metrics-server");
+        let chain = spending_chain(5, 2);
+        // An ephemeral port which is free again
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let scrapes = Rc::new(RefCell::new(Vec::new()));
+        let options = RefCell::new(ParserOptions {
+            callback_name: String::from("scraper"),
+            callback: Box::new(Scraper {
+                addr,
+                gauge: None,
+                scrapes: Rc::clone(&scrapes),
+            }),
+            blockchain_dir: dir.clone(),
+            metrics_listen: Some(addr.to_string()),
+            ..test_options()
+        });
+        let index = write_blk_file(&dir, &chain);
+        let chain_storage = chain::ChainStorage::from_index(&options, index).unwrap();
+        let mut parser = BlockchainParser::new(&options, chain_storage);
+        let err = parser.start().unwrap_err();
+        assert_eq!(
+            "Callback `scraper` failed",
+            err.report().lines().next().unwrap()
+        );
+        assert_eq!("stop", err.cause().unwrap().message);
+        assert_eq!(1, parser.metrics.n_callback_errors());
+
+        let scrapes = scrapes.borrow();
+        assert_eq!(4, scrapes.len());
+        for name in [
+            "blocks_processed_total",
+            "transactions_processed_total",
+            "bytes_read_total",
+            "current_height",
+            "callback_errors_total",
+            "scraper_last_height",
+        ] {
+            assert!(
+                scrapes[0].contains(&format!("# TYPE {} ", name)),
+                "{}",
+                scrapes[0]
+            );
+        }
+        // Counters of the blocks before the current one
+        assert!(
+            scrapes[2].contains("\nblocks_processed_total 2\n"),
+            "{}",
+            scrapes[2]
+        );
+        assert!(scrapes[2].contains("\ntransactions_processed_total 4\n"));
+        assert!(scrapes[2].contains("\ncurrent_height 1\n"));
+        assert!(scrapes[2].contains("\ncallback_errors_total 0\n"));
+        assert!(scrapes[2].contains("\nscraper_last_height 2\n"));
+        // Shut down after the error
+        assert!(TcpStream::connect(addr).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Sets the interrupt flag like a signal would while handling the block at `at_height`
+    struct Interrupter {
+        flag: Arc<AtomicBool>,
+        at_height: u64,
+        calls: Rc<RefCell<Vec<String>>>,
+    }
+
+    impl Callback for Interrupter {
+        fn build_subcommand<'a, 'b>() -> App<'a, 'b> {
+            SubCommand::with_name("interrupter")
+        }
+
+        fn new(_: &ArgMatches) -> OpResult<Self> {
+            unimplemented!()
+        }
+
+        fn on_start(&mut self, _: &CoinType, _: u64) -> OpResult<()> {
+            Ok(())
+        }
+
+        fn on_block(&mut self, _: &Block, block_height: u64) -> OpResult<()> {
+            if block_height == self.at_height {
+                self.flag.store(true, Ordering::SeqCst);
+            }
+            Ok(())
+        }
+
+        fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+            self.calls
+                .borrow_mut()
+                .push(format!("complete {}", block_height));
+            Ok(())
+        }
+
+        fn on_interrupt(&mut self, block_height: u64) -> OpResult<()> {
+            self.calls
+                .borrow_mut()
+                .push(format!("interrupt {}", block_height));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_interrupt() {
+        let dir = temp_dir("interrupt");
+        let dump_dir = dir.join("dump");
+        fs::create_dir_all(&dump_dir).unwrap();
+        let chain = spending_chain(10, 2);
+        let flag = Arc::new(AtomicBool::new(false));
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let matches = CsvDump::build_subcommand()
+            .get_matches_from(vec!["csvdump", dump_dir.to_str().unwrap()]);
+        let callbacks: Vec<(String, Box<dyn Callback>)> = vec![
+            (
+                String::from("csvdump"),
+                Box::new(CsvDump::new(&matches).unwrap()),
+            ),
+            (
+                String::from("interrupter"),
+                Box::new(Interrupter {
+                    flag: Arc::clone(&flag),
+                    at_height: 3,
+                    calls: Rc::clone(&calls),
+                }),
+            ),
+        ];
+        let options = RefCell::new(ParserOptions {
+            callback_name: String::from("multi"),
+            callback: Box::new(Multi::from_callbacks(callbacks)),
+            blockchain_dir: dir.clone(),
+            threads: 2,
+            ..test_options()
+        });
+        let index = write_blk_file(&dir, &chain);
+        let chain_storage = chain::ChainStorage::from_index(&options, index).unwrap();
+        let mut parser = BlockchainParser::new(&options, chain_storage);
+        parser.set_interrupt(&flag);
+        let err = parser.start().unwrap_err();
+        assert!(matches!(err.kind, OpErrorKind::Interrupted), "{}", err);
+        assert_eq!(4, parser.metrics.n_blocks());
+        assert_eq!(vec!["interrupt 4"], *calls.borrow());
+        drop(parser);
+        drop(options);
+
+        // The files cover the blocks before the interrupt and aren't temporary anymore
+        let mut files: Vec<String> = fs::read_dir(&dump_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        files.sort();
+        assert_eq!(
+            vec![
+                "blocks-0-4.csv",
+                "transactions-0-4.csv",
+                "tx_in-0-4.csv",
+                "tx_out-0-4.csv"
+            ],
+            files
+        );
+        let blocks = fs::read_to_string(dump_dir.join("blocks-0-4.csv")).unwrap();
+        assert_eq!(4, blocks.lines().count());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Records the blocks, stale blocks and tips it sees
+    struct Recorder {
+        calls: Rc<RefCell<Vec<String>>>,
+        follow_interval: Option<Duration>,
+    }
+
+    impl Callback for Recorder {
+        fn build_subcommand<'a, 'b>() -> App<'a, 'b> {
+            SubCommand::with_name("recorder")
+        }
+
+        fn new(_: &ArgMatches) -> OpResult<Self> {
+            unimplemented!()
+        }
+
+        fn on_start(&mut self, _: &CoinType, _: u64) -> OpResult<()> {
+            Ok(())
+        }
+
+        fn follow_interval(&self) -> Option<Duration> {
+            self.follow_interval
+        }
+
+        fn on_tip(&mut self, block_height: u64) -> OpResult<()> {
+            self.calls
+                .borrow_mut()
+                .push(format!("tip {}", block_height));
+            Ok(())
+        }
+
+        fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<()> {
+            self.calls.borrow_mut().push(format!(
+                "block {} {}",
+                block_height,
+                &utils::arr_to_hex_swapped(&block.header.hash)[..8]
+            ));
+            Ok(())
+        }
+
+        fn on_stale_block(&mut self, block: &Block, block_height: u64) -> OpResult<()> {
+            self.calls.borrow_mut().push(format!(
+                "stale {} {}",
+                block_height,
+                &utils::arr_to_hex_swapped(&block.header.hash)[..8]
+            ));
+            Ok(())
+        }
+
+        fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+            self.calls
+                .borrow_mut()
+                .push(format!("complete {}", block_height));
+            Ok(())
+        }
+
+        fn on_interrupt(&mut self, block_height: u64) -> OpResult<()> {
+            self.calls
+                .borrow_mut()
+                .push(format!("interrupt {}", block_height));
+            Ok(())
+        }
+    }
+
+    /// Blocks linked to `prev` and each other, the nonce tells branches apart
+    fn branch(prev: [u8; 32], n_blocks: usize, nonce: u32, bits: u32) -> Vec<Block> {
+        let mut prev = prev;
+        (0..n_blocks)
+            .map(|i| {
+                let coinbase = new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[1000 + i as u64]);
+                let mut block = fixtures::new_block(vec![coinbase]);
+                block.header.value.prev_hash = prev;
+                block.header.value.nonce = nonce;
+                block.header.value.bits = bits;
+                block.header = Hashed::double_sha256(block.header.value.clone());
+                prev = block.header.hash;
+                block
+            })
+            .collect()
+    }
+
+    /// Parses the blocks from `start` to `end` of a blk file, returns the heights passed to the
+    /// callback. They must match the remaining blocks the chain reported up front.
+    fn run_range(dir: &Path, chain: &[Block], start: usize, end: Option<usize>) -> Vec<u64> {
+        let index = write_blk_file(dir, chain);
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let options = RefCell::new(ParserOptions {
+            callback_name: String::from("recorder"),
+            callback: Box::new(Recorder {
+                calls: Rc::clone(&calls),
+                follow_interval: None,
+            }),
+            blockchain_dir: PathBuf::from(dir),
+            threads: 2,
+            mmap: true,
+            strict: true,
+            range: ParseRange::new(start, end).unwrap(),
+            ..test_options()
+        });
+        let chain_storage = chain::ChainStorage::from_index(&options, index).unwrap();
+        let remaining = chain_storage.remaining();
+        BlockchainParser::new(&options, chain_storage)
+            .start()
+            .unwrap();
+        drop(options);
+        let heights: Vec<u64> = calls
+            .borrow()
+            .iter()
+            .filter_map(|call| call.strip_prefix("block "))
+            .map(|call| call.split(' ').next().unwrap().parse().unwrap())
+            .collect();
+        assert_eq!(remaining, heights.len());
+        heights
+    }
+
+    #[test]
+    fn test_range() {
+        let dir = temp_dir("range");
+        let chain = spending_chain(30, 1);
+        // Both ends are inclusive
+        assert_eq!(
+            (10..=20).collect::<Vec<u64>>(),
+            run_range(&dir, &chain, 10, Some(20))
+        );
+        assert_eq!(vec![7], run_range(&dir, &chain, 7, Some(7)));
+        // Clamped at the tip
+        assert_eq!(
+            (25..=29).collect::<Vec<u64>>(),
+            run_range(&dir, &chain, 25, Some(29))
+        );
+        assert_eq!(
+            (25..=29).collect::<Vec<u64>>(),
+            run_range(&dir, &chain, 25, Some(1000))
+        );
+        assert_eq!(
+            (0..=29).collect::<Vec<u64>>(),
+            run_range(&dir, &chain, 0, None)
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Follows the tip of an index which changes with every rescan, see `Recorder`
+    fn run_follower(
+        dir: &Path,
+        blocks: &[Block],
+        indexes: Vec<Vec<usize>>,
+        end: Option<usize>,
+        interrupt: Option<&AtomicBool>,
+    ) -> (OpResult<()>, Vec<String>) {
+        let records = write_blk_file(dir, blocks);
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let options = RefCell::new(ParserOptions {
+            callback_name: String::from("recorder"),
+            callback: Box::new(Recorder {
+                calls: Rc::clone(&calls),
+                follow_interval: Some(Duration::from_millis(10)),
+            }),
+            blockchain_dir: PathBuf::from(dir),
+            threads: 2,
+            mmap: true,
+            strict: true,
+            range: ParseRange::new(0, end).unwrap(),
+            ..test_options()
+        });
+        let mut indexes = VecDeque::from(indexes);
+        let first = index_of(&records, &indexes[0]);
+        let mut chain_storage = chain::ChainStorage::from_index(&options, first).unwrap();
+        // The last index stays
+        chain_storage.set_index_loader(Box::new(move || {
+            if indexes.len() > 1 {
+                indexes.pop_front();
+            }
+            Ok(index_of(&records, &indexes[0]))
+        }));
+        let mut parser = BlockchainParser::new(&options, chain_storage);
+        if let Some(flag) = interrupt {
+            parser.set_interrupt(flag);
+        }
+        let result = parser.start();
+        drop(parser);
+        drop(options);
+        let calls = calls.borrow().clone();
+        (result, calls)
+    }
+
+    /// Index of the blocks at the given positions of the blk file, in height order
+    fn index_of(records: &[BlockIndexRecord], positions: &[usize]) -> Vec<BlockIndexRecord> {
+        positions
+            .iter()
+            .enumerate()
+            .map(|(height, &pos)| {
+                let record = &records[pos];
+                BlockIndexRecord::with_data(
+                    record.block_hash,
+                    record.prev_hash,
+                    height,
+                    record.n_data_pos,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_follow_tip() {
+        let dir = temp_dir("follow");
+        // Heights 0..=10, the block on top of it, and a branch replacing 8..=10
+        let mut blocks = branch([0u8; 32], 11, 0, 0x1d00ffff);
+        blocks.extend(branch(blocks[10].header.hash, 1, 0, 0x1d00ffff));
+        blocks.extend(branch(blocks[7].header.hash, 5, 1, 0x1d00ffff));
+        let short_hash =
+            |pos: usize| String::from(&utils::arr_to_hex_swapped(&blocks[pos].header.hash)[..8]);
+        let block = |height: usize, pos: usize| format!("block {} {}", height, short_hash(pos));
+
+        let main: Vec<usize> = (0..11).collect();
+        let extended: Vec<usize> = (0..12).collect();
+        let reorged: Vec<usize> = (0..8).chain(12..17).collect();
+        let (result, calls) = run_follower(
+            &dir.join("reorg"),
+            &blocks,
+            vec![main.clone(), main.clone(), extended, reorged],
+            Some(12),
+            None,
+        );
+        result.unwrap();
+        let mut expected: Vec<String> = (0..11).map(|height| block(height, height)).collect();
+        // Polled twice before block 11 shows up
+        expected.push(String::from("tip 11"));
+        expected.push(block(11, 11));
+        expected.push(String::from("tip 12"));
+        // Blocks 8 to 11 are orphaned, the replacement ends at 12
+        expected.extend((8..13).map(|height| block(height, height + 4)));
+        expected.push(String::from("complete 13"));
+        assert_eq!(expected, calls);
+
+        // Waiting for blocks which never come until interrupted
+        let flag = Arc::new(AtomicBool::new(false));
+        let setter = {
+            let flag = Arc::clone(&flag);
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(200));
+                flag.store(true, Ordering::SeqCst);
+            })
+        };
+        let (result, calls) = run_follower(
+            &dir.join("interrupt"),
+            &blocks,
+            vec![main],
+            None,
+            Some(&flag),
+        );
+        setter.join().unwrap();
+        let err = result.unwrap_err();
+        assert!(matches!(err.kind, OpErrorKind::Interrupted), "{}", err);
+        assert_eq!(vec!["tip 11", "interrupt 11"], calls[11..]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_include_orphans() {
+        // Two blocks on top of block 3 lose against one with a 256 times lower target
+        let mut blocks = branch([0u8; 32], 4, 0, 0x1d00ffff);
+        blocks.extend(branch(blocks[3].header.hash, 2, 1, 0x1d00ffff));
+        blocks.extend(branch(blocks[3].header.hash, 1, 2, 0x1c00ffff));
+        let data: Vec<u8> = blocks
+            .iter()
+            .flat_map(|block| {
+                let bytes = block.to_bytes();
+                let mut framed = 0xd9b4bef9u32.to_le_bytes().to_vec();
+                framed.extend((bytes.len() as u32).to_le_bytes());
+                framed.extend(bytes);
+                framed
+            })
+            .collect();
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let options = |include_orphans: bool| {
+            RefCell::new(ParserOptions {
+                callback_name: String::from("recorder"),
+                callback: Box::new(Recorder {
+                    calls: Rc::clone(&calls),
+                    follow_interval: None,
+                }),
+                blockchain_dir: PathBuf::from("-"),
+                strict: true,
+                include_orphans,
+                ..test_options()
+            })
+        };
+        let short_hash =
+            |pos: usize| String::from(&utils::arr_to_hex_swapped(&blocks[pos].header.hash)[..8]);
+
+        let with_orphans = options(true);
+        let reader: Box<dyn std::io::Read> = Box::new(std::io::Cursor::new(data.clone()));
+        let chain_storage = chain::ChainStorage::from_stream(&with_orphans, reader).unwrap();
+        BlockchainParser::new(&with_orphans, chain_storage)
+            .start()
+            .unwrap();
+        let mut expected: Vec<String> = (0..4)
+            .map(|height| format!("block {} {}", height, short_hash(height)))
+            .collect();
+        expected.push(format!("block 4 {}", short_hash(6)));
+        expected.push(format!("stale 4 {}", short_hash(4)));
+        expected.push(format!("stale 5 {}", short_hash(5)));
+        expected.push(String::from("complete 5"));
+        assert_eq!(expected, *calls.borrow());
+
+        // Stale blocks are dropped silently by default
+        calls.borrow_mut().clear();
+        let without = options(false);
+        let reader: Box<dyn std::io::Read> = Box::new(std::io::Cursor::new(data));
+        let chain_storage = chain::ChainStorage::from_stream(&without, reader).unwrap();
+        BlockchainParser::new(&without, chain_storage)
+            .start()
+            .unwrap();
+        expected.retain(|call| !call.starts_with("stale"));
+        assert_eq!(expected, *calls.borrow());
+
+        // Stale blocks of the block index aren't read
+        let dir = temp_dir("orphans");
+        fs::create_dir_all(&dir).unwrap();
+        let with_orphans = options(true);
+        with_orphans.borrow_mut().blockchain_dir = dir.clone();
+        let err = chain::ChainStorage::from_index(&with_orphans, Vec::new())
+            .err()
+            .unwrap();
+        assert!(matches!(err.kind, OpErrorKind::InvalidArgsError), "{}", err);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_skip_corrupt_blocks() {
+        let dir = temp_dir("corrupt");
+        let chain = spending_chain(7, 3);
+        let path = dir.join("blk00000.dat");
+        let offsets: Vec<usize> = write_blk_file(&dir, &chain)
+            .iter()
+            .map(|record| record.n_data_pos as usize)
+            .collect();
+        let offset = |height: usize| offsets[height];
+        let mut data = fs::read(&path).unwrap();
+        // Block 2 claims far more transactions than it has
+        data[offset(2) + 80] = 0xff;
+        // Block 4 lost its magic
+        data[offset(4) - 8] ^= 0xff;
+        // Block 6 is cut off
+        data.truncate(offset(6) + 100);
+
+        for threads in [1, 3] {
+            for mmap in [false, true] {
+                for strict in [false, true] {
+                    let heights = Rc::new(RefCell::new(Vec::new()));
+                    let options = RefCell::new(ParserOptions {
+                        callback: Box::new(Heights(Rc::clone(&heights))),
+                        blockchain_dir: dir.clone(),
+                        threads,
+                        mmap,
+                        strict,
+                        ..test_options()
+                    });
+                    let index = write_blk_file(&dir, &chain);
+                    fs::write(&path, &data).unwrap();
+                    let chain_storage = chain::ChainStorage::from_index(&options, index).unwrap();
+                    let mut parser = BlockchainParser::new(&options, chain_storage);
+                    let result = parser.start();
+                    if strict {
+                        // Block 2 fails while reading its transactions
+                        let err = result.unwrap_err();
+                        assert_eq!(ErrorCategory::Deserialization, err.category());
+                        match &err.kind {
+                            OpErrorKind::Deserialization {
+                                file: Some(file),
+                                offset: failed_at,
+                                height: Some(2),
+                                ..
+                            } => {
+                                assert_eq!(&path, file);
+                                assert!(
+                                    (offset(2) + 80..offset(3)).contains(&(*failed_at as usize)),
+                                    "{}",
+                                    err
+                                );
+                            }
+                            _ => panic!("{}", err),
+                        }
+                        assert_eq!(vec![0, 1], *heights.borrow());
+                        continue;
+                    }
+                    result.unwrap();
+                    assert_eq!(vec![0, 1, 3, 5], *heights.borrow());
+
+                    let regions: Vec<(Option<u64>, usize, usize)> = parser
+                        .chain_storage
+                        .corrupt_regions()
+                        .iter()
+                        .map(|r| (r.height, r.start as usize, r.end as usize))
+                        .collect();
+                    assert_eq!(
+                        vec![
+                            (Some(2), offset(2) - 8, offset(3) - 8),
+                            (Some(4), offset(4) - 8, offset(5) - 8),
+                            (Some(6), offset(6) - 8, data.len()),
+                        ],
+                        regions
+                    );
+                }
+            }
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_skip_invalid_blocks() {
+        let dir = temp_dir("invalid");
+        let mut chain = spending_chain(6, 3);
+        for height in 0..chain.len() {
+            let mut header = chain[height].header.value.clone();
+            header.merkle_root = chain[height].compute_merkle_root();
+            if height > 0 {
+                header.prev_hash = chain[height - 1].header.hash;
+            }
+            chain[height].header = Hashed::double_sha256(header);
+        }
+        let path = dir.join("blk00000.dat");
+        let offsets: Vec<usize> = write_blk_file(&dir, &chain)
+            .iter()
+            .map(|record| record.n_data_pos as usize)
+            .collect();
+        let mut data = fs::read(&path).unwrap();
+        // The value of the first output of block 2, after the header, tx count, version and input
+        data[offsets[2] + 80 + 1 + 4 + 1 + 41 + 1] ^= 0x01;
+        // The nonce of block 4
+        data[offsets[4] + 76] ^= 0x01;
+
+        for threads in [1, 3] {
+            for strict in [false, true] {
+                let heights = Rc::new(RefCell::new(Vec::new()));
+                let mut coin_type = CoinType::from(Bitcoin);
+                coin_type.genesis_hash = chain[0].header.hash;
+                let options = RefCell::new(ParserOptions {
+                    callback: Box::new(Heights(Rc::clone(&heights))),
+                    coin_type,
+                    verify: true,
+                    blockchain_dir: dir.clone(),
+                    threads,
+                    strict,
+                    ..test_options()
+                });
+                let index = write_blk_file(&dir, &chain);
+                fs::write(&path, &data).unwrap();
+                let chain_storage = chain::ChainStorage::from_index(&options, index).unwrap();
+                let mut parser = BlockchainParser::new(&options, chain_storage);
+                let result = parser.start();
+                if strict {
+                    let err = result.unwrap_err();
+                    assert!(err.message.starts_with("Block 2 ("), "{}", err);
+                    assert!(err.message.contains("Invalid merkle root"), "{}", err);
+                    assert_eq!(vec![0, 1], *heights.borrow());
+                    continue;
+                }
+                result.unwrap();
+                assert_eq!(vec![0, 1, 3, 5], *heights.borrow());
+                assert!(parser.chain_storage.corrupt_regions().is_empty());
+
+                let invalid = parser.chain_storage.invalid_blocks();
+                assert_eq!(
+                    vec![(2, chain[2].header.hash), (4, chain[4].header.hash)],
+                    invalid
+                        .iter()
+                        .map(|b| (b.height, b.hash))
+                        .collect::<Vec<(u64, [u8; 32])>>()
+                );
+                assert!(invalid[0].reason.starts_with("Invalid merkle root"));
+                assert!(invalid[1]
+                    .reason
+                    .starts_with("Header hash doesn't match the index"));
+            }
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_check_pow() {
+        let dir = temp_dir("pow");
+        let mut chain = spending_chain(6, 2);
+        for height in 0..chain.len() {
+            let mut header = chain[height].header.value.clone();
+            if height > 0 {
+                header.prev_hash = chain[height - 1].header.hash;
+            }
+            // Targets half of the hashes, the last block keeps the one of mainnet
+            if height < 5 {
+                header.bits = 0x207fffff;
+                while target::check_pow(&utils::sha256d(&header.to_bytes()), header.bits).is_err() {
+                    header.nonce += 1;
+                }
+            }
+            chain[height].header = Hashed::double_sha256(header);
+        }
+        let path = dir.join("blk00000.dat");
+        let offsets: Vec<usize> = write_blk_file(&dir, &chain)
+            .iter()
+            .map(|record| record.n_data_pos as usize)
+            .collect();
+
+        for skip_block_3 in [false, true] {
+            let mut data = fs::read(&path).unwrap();
+            if skip_block_3 {
+                // Lost its magic, so block 4 doesn't link to the previously delivered one
+                data[offsets[3] - 8] ^= 0xff;
+            }
+            for keep_going in [false, true] {
+                let heights = Rc::new(RefCell::new(Vec::new()));
+                let options = RefCell::new(ParserOptions {
+                    callback: Box::new(Heights(Rc::clone(&heights))),
+                    blockchain_dir: dir.clone(),
+                    check_pow: true,
+                    keep_going,
+                    ..test_options()
+                });
+                let index = write_blk_file(&dir, &chain);
+                fs::write(&path, &data).unwrap();
+                let chain_storage = chain::ChainStorage::from_index(&options, index).unwrap();
+                let result = BlockchainParser::new(&options, chain_storage).start();
+                let expected = match (skip_block_3, keep_going) {
+                    (false, false) => {
+                        let err = result.unwrap_err().message;
+                        assert!(err.starts_with("Block 5 ("), "{}", err);
+                        assert!(err.contains("invalid proof of work, Hash"), "{}", err);
+                        vec![0, 1, 2, 3, 4]
+                    }
+                    (true, false) => {
+                        let err = result.unwrap_err().message;
+                        assert!(err.starts_with("Block 4 ("), "{}", err);
+                        assert!(
+                            err.contains(&format!(
+                                "prev_hash doesn't match the previous block, expected {}, got {}",
+                                utils::arr_to_hex_swapped(&chain[2].header.hash),
+                                utils::arr_to_hex_swapped(&chain[3].header.hash)
+                            )),
+                            "{}",
+                            err
+                        );
+                        vec![0, 1, 2]
+                    }
+                    (false, true) => {
+                        result.unwrap();
+                        vec![0, 1, 2, 3, 4, 5]
+                    }
+                    (true, true) => {
+                        result.unwrap();
+                        vec![0, 1, 2, 4, 5]
+                    }
+                };
+                assert_eq!(expected, *heights.borrow());
+            }
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Compares single and multi-threaded wall time with and without memory maps, run with `cargo test --release -- --ignored bench`
+    #[test]
+    #[ignore]
+    fn bench_parallel_parsing() {
+        let dir = temp_dir("bench");
+        let chain = spending_chain(200, 2000);
+        let threads = std::thread::available_parallelism().map_or(2, |n| n.get().max(2));
+        for n in [1, threads] {
+            for mmap in [false, true] {
+                let started = Instant::now();
+                run_csvdump(&dir, &chain, n, mmap);
+                println!(
+                    "{:2} threads, mmap: {:5}: {:.2}s",
+                    n,
+                    mmap,
+                    started.elapsed().as_secs_f32()
+                );
+            }
+        }
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
@@ -0,0 +1,393 @@
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use time::OffsetDateTime;
+
+use crate::common::prometheus;
+
+/// Counters of the running parser, shared with the callbacks
+pub struct Metrics {
+    /// Height of the last processed block
+    height: AtomicU64,
+    n_blocks: AtomicU64,
+    n_txs: AtomicU64,
+    /// Serialized size of the processed blocks
+    n_bytes: AtomicU64,
+    /// Errors returned by the callback
+    n_callback_errors: AtomicU64,
+    /// Blocks to process, 0 if unknown
+    total_blocks: AtomicU64,
+    /// Bytes of all blk files if the whole chain is parsed, 0 if unknown
+    total_bytes: AtomicU64,
+    started: Instant,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics {
+            height: AtomicU64::new(0),
+            n_blocks: AtomicU64::new(0),
+            n_txs: AtomicU64::new(0),
+            n_bytes: AtomicU64::new(0),
+            n_callback_errors: AtomicU64::new(0),
+            total_blocks: AtomicU64::new(0),
+            total_bytes: AtomicU64::new(0),
+            started: Instant::now(),
+        }
+    }
+}
+
+impl Metrics {
+    pub fn height(&self) -> u64 {
+        self.height.load(Ordering::Relaxed)
+    }
+
+    pub fn n_blocks(&self) -> u64 {
+        self.n_blocks.load(Ordering::Relaxed)
+    }
+
+    pub fn n_txs(&self) -> u64 {
+        self.n_txs.load(Ordering::Relaxed)
+    }
+
+    pub fn n_bytes(&self) -> u64 {
+        self.n_bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn n_callback_errors(&self) -> u64 {
+        self.n_callback_errors.load(Ordering::Relaxed)
+    }
+
+    pub fn total_blocks(&self) -> Option<u64> {
+        Some(self.total_blocks.load(Ordering::Relaxed)).filter(|n| *n > 0)
+    }
+
+    pub fn total_bytes(&self) -> Option<u64> {
+        Some(self.total_bytes.load(Ordering::Relaxed)).filter(|n| *n > 0)
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Estimates the remaining time from the average throughput so far, by bytes if the total
+    /// size is known and by blocks otherwise
+    pub fn eta(&self) -> Option<Duration> {
+        self.eta_after(self.elapsed())
+    }
+
+    fn eta_after(&self, elapsed: Duration) -> Option<Duration> {
+        // The blk files also contain the magic and size of each block, and maybe stale blocks
+        if self
+            .total_blocks()
+            .is_some_and(|total| self.n_blocks() >= total)
+        {
+            return Some(Duration::ZERO);
+        }
+        let (done, total) = match (self.total_bytes(), self.total_blocks()) {
+            (Some(total), _) => (self.n_bytes(), total),
+            (None, Some(total)) => (self.n_blocks(), total),
+            (None, None) => return None,
+        };
+        if done == 0 {
+            return None;
+        }
+        let remaining = total.saturating_sub(done) as f64;
+        Some(Duration::from_secs_f64(
+            elapsed.as_secs_f64() * remaining / done as f64,
+        ))
+    }
+
+    pub(super) fn set_totals(&self, total_blocks: Option<u64>, total_bytes: Option<u64>) {
+        self.total_blocks
+            .store(total_blocks.unwrap_or(0), Ordering::Relaxed);
+        self.total_bytes
+            .store(total_bytes.unwrap_or(0), Ordering::Relaxed);
+    }
+
+    pub(super) fn add_callback_error(&self) {
+        self.n_callback_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Appends the counters in the Prometheus text format
+    pub fn render(&self, out: &mut String) {
+        for (name, help, kind, value) in [
+            (
+                "blocks_processed_total",
+                "Blocks passed to the callback",
+                "counter",
+                self.n_blocks(),
+            ),
+            (
+                "transactions_processed_total",
+                "Transactions of the processed blocks",
+                "counter",
+                self.n_txs(),
+            ),
+            (
+                "bytes_read_total",
+                "Serialized size of the processed blocks",
+                "counter",
+                self.n_bytes(),
+            ),
+            (
+                "current_height",
+                "Height of the last processed block",
+                "gauge",
+                self.height(),
+            ),
+            (
+                "callback_errors_total",
+                "Errors returned by the callback",
+                "counter",
+                self.n_callback_errors(),
+            ),
+        ] {
+            prometheus::write_metric(out, name, help, kind, value as f64);
+        }
+    }
+
+    pub(super) fn add_block(&self, height: u64, n_txs: u64, size: u64) {
+        self.height.store(height, Ordering::Relaxed);
+        self.n_blocks.fetch_add(1, Ordering::Relaxed);
+        self.n_txs.fetch_add(n_txs, Ordering::Relaxed);
+        self.n_bytes.fetch_add(size, Ordering::Relaxed);
+    }
+}
+
+/// Prints the progress to stderr every interval. On a terminal a single line is updated in
+/// place, otherwise timestamped lines are appended.
+pub struct ProgressReporter {
+    interval: Duration,
+    tty: bool,
+    t_last_report: Instant,
+    last_blocks: u64,
+    last_bytes: u64,
+    // A line is shown on the terminal which wasn't terminated yet
+    line_open: bool,
+}
+
+impl ProgressReporter {
+    pub fn new(interval: Duration) -> Self {
+        ProgressReporter {
+            interval,
+            tty: unsafe { libc::isatty(libc::STDERR_FILENO) } == 1,
+            t_last_report: Instant::now(),
+            last_blocks: 0,
+            last_bytes: 0,
+            line_open: false,
+        }
+    }
+
+    /// Reports the progress to stderr if the interval has passed
+    pub fn tick(&mut self, metrics: &Metrics) {
+        let stderr = io::stderr();
+        // The progress is best effort
+        let _ = self.tick_at(metrics, Instant::now(), &mut stderr.lock());
+    }
+
+    /// Terminates the line updated in place, so following output starts on a new line
+    pub fn finish(&mut self) {
+        let stderr = io::stderr();
+        let _ = self.finish_to(&mut stderr.lock());
+    }
+
+    fn tick_at<W: Write>(
+        &mut self,
+        metrics: &Metrics,
+        now: Instant,
+        out: &mut W,
+    ) -> io::Result<()> {
+        let frame = now.saturating_duration_since(self.t_last_report);
+        if frame < self.interval {
+            return Ok(());
+        }
+        let secs = frame.as_secs_f64().max(f64::EPSILON);
+        let blocks_sec = (metrics.n_blocks() - self.last_blocks) as f64 / secs;
+        let mb_sec = (metrics.n_bytes() - self.last_bytes) as f64 / secs / 1E6;
+        let elapsed = now.saturating_duration_since(metrics.started);
+        let line = format!(
+            "height {} | {:.1} blocks/s | {:.2} MB/s | {} txs | elapsed {} | ETA {}",
+            metrics.height(),
+            blocks_sec,
+            mb_sec,
+            metrics.n_txs(),
+            format_duration(elapsed),
+            metrics
+                .eta_after(elapsed)
+                .map_or_else(|| String::from("unknown"), format_duration)
+        );
+        if self.tty {
+            // Clears the rest of the previous line
+            write!(out, "\r{}\x1b[K", line)?;
+            self.line_open = true;
+        } else {
+            writeln!(
+                out,
+                "[{}] progress: {}",
+                OffsetDateTime::now_local().format("%T"),
+                line
+            )?;
+        }
+        out.flush()?;
+        self.t_last_report = now;
+        self.last_blocks = metrics.n_blocks();
+        self.last_bytes = metrics.n_bytes();
+        Ok(())
+    }
+
+    fn finish_to<W: Write>(&mut self, out: &mut W) -> io::Result<()> {
+        if self.line_open {
+            writeln!(out)?;
+            self.line_open = false;
+        }
+        Ok(())
+    }
+}
+
+/// Formats a duration as `h:mm:ss`
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reporter(tty: bool) -> (ProgressReporter, Metrics) {
+        let metrics = Metrics::default();
+        let reporter = ProgressReporter {
+            interval: Duration::from_secs(10),
+            tty,
+            t_last_report: metrics.started,
+            last_blocks: 0,
+            last_bytes: 0,
+            line_open: false,
+        };
+        (reporter, metrics)
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!("0:00:00", format_duration(Duration::from_millis(999)));
+        assert_eq!("0:01:05", format_duration(Duration::from_secs(65)));
+        assert_eq!("27:46:40", format_duration(Duration::from_secs(100000)));
+    }
+
+    #[test]
+    fn test_eta() {
+        let metrics = Metrics::default();
+        assert_eq!(None, metrics.eta_after(Duration::from_secs(10)));
+        metrics.set_totals(Some(100), None);
+        assert_eq!(None, metrics.eta_after(Duration::from_secs(10)));
+        for height in 0..25 {
+            metrics.add_block(height, 2, 1000);
+        }
+        assert_eq!(24, metrics.height());
+        assert_eq!(
+            (25, 50, 25000),
+            (metrics.n_blocks(), metrics.n_txs(), metrics.n_bytes())
+        );
+        // By blocks: a quarter took 10 seconds
+        assert_eq!(
+            Some(Duration::from_secs(30)),
+            metrics.eta_after(Duration::from_secs(10))
+        );
+        // By bytes if known
+        metrics.set_totals(Some(100), Some(1_000_000));
+        assert_eq!(
+            Some(Duration::from_secs(390)),
+            metrics.eta_after(Duration::from_secs(10))
+        );
+        // Done
+        metrics.set_totals(Some(25), None);
+        assert_eq!(
+            Some(Duration::ZERO),
+            metrics.eta_after(Duration::from_secs(10))
+        );
+    }
+
+    #[test]
+    fn test_tick() {
+        let (mut reporter, metrics) = reporter(false);
+        metrics.set_totals(Some(200), None);
+        for height in 0..50 {
+            metrics.add_block(height, 3, 20000);
+        }
+        let mut out = Vec::new();
+        // Not yet
+        reporter
+            .tick_at(&metrics, metrics.started + Duration::from_secs(9), &mut out)
+            .unwrap();
+        assert!(out.is_empty());
+
+        reporter
+            .tick_at(
+                &metrics,
+                metrics.started + Duration::from_secs(10),
+                &mut out,
+            )
+            .unwrap();
+        let line = String::from_utf8(out).unwrap();
+        assert!(line.starts_with('['), "{}", line);
+        assert!(line.ends_with(
+            "] progress: height 49 | 5.0 blocks/s | 0.10 MB/s | 150 txs | elapsed 0:00:10 | ETA 0:00:30\n"
+        ), "{}", line);
+
+        // Throughput of the last interval
+        for height in 50..60 {
+            metrics.add_block(height, 1, 100000);
+        }
+        let mut out = Vec::new();
+        reporter
+            .tick_at(
+                &metrics,
+                metrics.started + Duration::from_secs(15),
+                &mut out,
+            )
+            .unwrap();
+        assert!(out.is_empty());
+        reporter
+            .tick_at(
+                &metrics,
+                metrics.started + Duration::from_secs(20),
+                &mut out,
+            )
+            .unwrap();
+        assert!(String::from_utf8(out).unwrap().ends_with(
+            "height 59 | 1.0 blocks/s | 0.10 MB/s | 160 txs | elapsed 0:00:20 | ETA 0:00:46\n"
+        ));
+        let mut out = Vec::new();
+        reporter.finish_to(&mut out).unwrap();
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn test_tick_tty() {
+        let (mut reporter, metrics) = reporter(true);
+        metrics.add_block(0, 1, 285);
+        let mut out = Vec::new();
+        reporter
+            .tick_at(
+                &metrics,
+                metrics.started + Duration::from_secs(10),
+                &mut out,
+            )
+            .unwrap();
+        reporter
+            .tick_at(
+                &metrics,
+                metrics.started + Duration::from_secs(20),
+                &mut out,
+            )
+            .unwrap();
+        reporter.finish_to(&mut out).unwrap();
+        assert_eq!(
+            "\rheight 0 | 0.1 blocks/s | 0.00 MB/s | 1 txs | elapsed 0:00:10 | ETA unknown\x1b[K\
+             \rheight 0 | 0.0 blocks/s | 0.00 MB/s | 1 txs | elapsed 0:00:20 | ETA unknown\x1b[K\n",
+            String::from_utf8(out).unwrap()
+        );
+    }
+}
@@ -1,13 +1,42 @@
 use std::borrow::BorrowMut;
-use std::io::{self};
+use std::io::{self, Read};
 
 use byteorder::{LittleEndian, ReadBytesExt};
 
-use crate::blockchain::proto::block::Block;
+use crate::blockchain::parser::zcash::ZcashRead;
+use crate::blockchain::proto::auxpow::{AuxPow, MerkleBranch};
+use crate::blockchain::proto::block::{Block, BlockFormat};
 use crate::blockchain::proto::header::BlockHeader;
-use crate::blockchain::proto::tx::{RawTx, TxInput, TxOutpoint, TxOutput};
+use crate::blockchain::proto::script::AddressFormat;
+use crate::blockchain::proto::tx::{EvaluatedTx, RawTx, TxInput, TxOutpoint, TxOutput};
 use crate::blockchain::proto::varuint::VarUint;
-use crate::errors::OpResult;
+use crate::blockchain::proto::Hashed;
+use crate::errors::{OpError, OpResult};
+
+/// Upper bound for allocations based on counts read from the stream,
+/// corrupt data must not be able to request gigabytes up front
+const MAX_PREALLOC: usize = 1 << 16;
+
+/// Counts the bytes read through it, gives the sizes of transactions in `read_txs`
+struct ByteCounter<R> {
+    inner: R,
+    count: u64,
+}
+
+impl<R: io::Read> ByteCounter<R> {
+    fn new(inner: R) -> Self {
+        ByteCounter { inner, count: 0 }
+    }
+}
+
+impl<R: io::Read> io::Read for ByteCounter<R> {
+    #[inline]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+}
 
 /// Trait for structured reading of blockchain data
 pub trait BlockchainRead: io::Read {
@@ -20,17 +49,106 @@ pub trait BlockchainRead: io::Read {
 
     #[inline]
     fn read_u8_vec(&mut self, count: u32) -> OpResult<Vec<u8>> {
-        let mut arr = vec![0u8; count as usize];
-        self.read_exact(arr.borrow_mut())?;
+        let count = count as usize;
+        if count <= MAX_PREALLOC {
+            let mut arr = vec![0u8; count];
+            self.read_exact(arr.borrow_mut())?;
+            return Ok(arr);
+        }
+        // Grow with the data actually read
+        let mut arr = Vec::with_capacity(MAX_PREALLOC);
+        Read::take(self, count as u64).read_to_end(&mut arr)?;
+        if arr.len() != count {
+            return Err(OpError::from(io::Error::from(io::ErrorKind::UnexpectedEof)));
+        }
         Ok(arr)
     }
 
-    /// Does not pop magic nor blocksize
-    fn read_block(&mut self, size: u32, version_id: u8) -> OpResult<Block> {
-        let header = self.read_block_header()?;
+    /// Does not pop magic nor blocksize, nothing beyond `size` bytes is read.
+    /// The serialization of headers and transactions depends on the `format`, see `CoinType::block_format`.
+    fn read_block(
+        &mut self,
+        size: u32,
+        address_format: AddressFormat,
+        format: BlockFormat,
+    ) -> OpResult<Block> {
+        let mut reader = Read::take(&mut *self, size as u64);
+        let header = reader.read_block_header_of(format)?;
+        let aux_pow = reader.read_aux_pow_of(&header, format, address_format)?;
+        let tx_count = VarUint::read_from(&mut reader)?;
+        let txs = match format {
+            BlockFormat::Zcash => reader.read_zcash_txs(tx_count.value, address_format)?,
+            _ => reader.read_txs(tx_count.value, address_format)?,
+        };
+        // The MWEB extension block follows the HogEx, which is the last transaction.
+        // Its size is the rest of the block.
+        let has_mweb = format == BlockFormat::Litecoin && txs.last().is_some_and(|tx| tx.hogex);
+        let mweb_size = if has_mweb {
+            io::copy(&mut reader, &mut io::sink())? as u32
+        } else {
+            0
+        };
+        let mut block = Block::new(size, header, tx_count, txs);
+        block.aux_pow = aux_pow;
+        block.has_mweb = has_mweb;
+        block.mweb_size = mweb_size;
+        block.measure();
+        Ok(block)
+    }
+
+    /// Reads header, AuxPoW and transaction count only, the block has no transactions
+    fn read_block_without_txs(&mut self, size: u32, format: BlockFormat) -> OpResult<Block> {
+        let header = self.read_block_header_of(format)?;
+        let aux_pow = self.read_aux_pow_of(&header, format, AddressFormat::default())?;
         let tx_count = VarUint::read_from(self)?;
-        let txs = self.read_txs(tx_count.value, version_id)?;
-        Ok(Block::new(size, header, tx_count, txs))
+        let mut block = Block::new(size, header, tx_count, Vec::new());
+        block.aux_pow = aux_pow;
+        Ok(block)
+    }
+
+    #[inline]
+    fn read_block_header_of(&mut self, format: BlockFormat) -> OpResult<BlockHeader> {
+        match format {
+            BlockFormat::Zcash => self.read_equihash_header(),
+            _ => self.read_block_header(),
+        }
+    }
+
+    #[inline]
+    fn read_aux_pow_of(
+        &mut self,
+        header: &BlockHeader,
+        format: BlockFormat,
+        address_format: AddressFormat,
+    ) -> OpResult<Option<AuxPow>> {
+        if format == BlockFormat::AuxPow && header.has_auxpow() {
+            Ok(Some(self.read_aux_pow(address_format)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn read_aux_pow(&mut self, address_format: AddressFormat) -> OpResult<AuxPow> {
+        let coinbase_tx = self.read_txs(1, address_format)?.remove(0);
+        Ok(AuxPow {
+            coinbase_tx: Hashed::double_sha256(EvaluatedTx::from(coinbase_tx)),
+            hash_block: self.read_256hash()?,
+            coinbase_branch: self.read_merkle_branch()?,
+            blockchain_branch: self.read_merkle_branch()?,
+            parent_header: Hashed::double_sha256(self.read_block_header()?),
+        })
+    }
+
+    fn read_merkle_branch(&mut self) -> OpResult<MerkleBranch> {
+        let count = VarUint::read_from(self)?;
+        let mut hashes = Vec::with_capacity(prealloc(count.value));
+        for _ in 0..count.value {
+            hashes.push(self.read_256hash()?);
+        }
+        Ok(MerkleBranch {
+            hashes,
+            side_mask: self.read_u32::<LittleEndian>()?,
+        })
     }
 
     #[inline]
@@ -45,44 +163,73 @@ pub trait BlockchainRead: io::Read {
         ))
     }
 
-    fn read_txs(&mut self, tx_count: u64, version_id: u8) -> OpResult<Vec<RawTx>> {
-        let mut txs = Vec::with_capacity(tx_count as usize);
+    fn read_txs(&mut self, tx_count: u64, address_format: AddressFormat) -> OpResult<Vec<RawTx>> {
+        let mut txs = Vec::with_capacity(prealloc(tx_count));
         for _ in 0..tx_count {
+            let mut reader = ByteCounter::new(&mut *self);
             let mut flags = 0u8;
-            let version = self.read_u32::<LittleEndian>()?;
+            let mut marker_size = 0;
+            let version = reader.read_u32::<LittleEndian>()?;
 
             // Parse transaction inputs and check if this transaction contains segwit data
-            let mut in_count = VarUint::read_from(self)?;
+            let mut in_count = VarUint::read_from(&mut reader)?;
             if in_count.value == 0 {
-                flags = self.read_u8()?;
-                // TODO: handle segwit data
-                in_count = VarUint::read_from(self)?
+                flags = reader.read_u8()?;
+                marker_size = 2;
+                in_count = VarUint::read_from(&mut reader)?
             }
-            let inputs = self.read_tx_inputs(in_count.value)?;
+            let inputs = reader.read_tx_inputs(in_count.value)?;
 
             // Parse transaction outputs
-            let out_count = VarUint::read_from(self)?;
-            let outputs = self.read_tx_outputs(out_count.value)?;
+            let out_count = VarUint::read_from(&mut reader)?;
+            let outputs = reader.read_tx_outputs(out_count.value)?;
 
             // Check if the witness flag is present
+            let mut witnesses = Vec::new();
+            let witness_start = reader.count;
             if flags & 1 > 0 {
+                witnesses.reserve(in_count.value as usize);
                 for _ in 0..in_count.value {
-                    let item_count = VarUint::read_from(self)?;
+                    let item_count = VarUint::read_from(&mut reader)?;
+                    let mut witness = Vec::with_capacity(prealloc(item_count.value));
                     for _ in 0..item_count.value {
-                        let witness_len = VarUint::read_from(self)?;
-                        let _ = self.read_u8_vec(witness_len.value as u32)?;
+                        let witness_len = VarUint::read_from(&mut reader)?;
+                        witness.push(reader.read_u8_vec(witness_len.value as u32)?);
                     }
+                    witnesses.push(witness);
                 }
             }
-            let locktime = self.read_u32::<LittleEndian>()?;
+            let witness_size = reader.count - witness_start;
+            // Litecoin: MWEB flag, transactions in blocks carry no MWEB data except for the HogEx marker
+            let hogex = flags & 8 > 0;
+            if hogex && reader.read_u8()? != 0 {
+                return Err(OpError::from(String::from(
+                    "MWEB transaction data is not supported",
+                )));
+            }
+            let locktime = reader.read_u32::<LittleEndian>()?;
+            // Marker, flag, witnesses and the MWEB byte are not part of the stripped serialization.
+            // Like the weight in Litecoin Core, the size excludes the MWEB flag of the HogEx.
+            let extended_size = marker_size + witness_size + hogex as u64;
+            let stripped_size = (reader.count - extended_size) as u32;
+            let size = if flags & 1 > 0 {
+                stripped_size + 2 + witness_size as u32
+            } else {
+                stripped_size
+            };
             txs.push(RawTx {
                 version,
                 in_count,
                 inputs,
                 out_count,
                 outputs,
+                witnesses,
                 locktime,
-                version_id,
+                address_format,
+                hogex,
+                zcash: None,
+                size,
+                stripped_size,
             });
         }
         Ok(txs)
@@ -97,7 +244,7 @@ pub trait BlockchainRead: io::Read {
     }
 
     fn read_tx_inputs(&mut self, input_count: u64) -> OpResult<Vec<TxInput>> {
-        let mut inputs = Vec::with_capacity(input_count as usize);
+        let mut inputs = Vec::with_capacity(prealloc(input_count));
         for _ in 0..input_count {
             let outpoint = self.read_tx_outpoint()?;
             let script_len = VarUint::read_from(self)?;
@@ -108,13 +255,15 @@ pub trait BlockchainRead: io::Read {
                 script_len,
                 script_sig,
                 seq_no,
+                revealed_script: None,
+                prevout: None,
             });
         }
         Ok(inputs)
     }
 
     fn read_tx_outputs(&mut self, output_count: u64) -> OpResult<Vec<TxOutput>> {
-        let mut outputs = Vec::with_capacity(output_count as usize);
+        let mut outputs = Vec::with_capacity(prealloc(output_count));
         for _ in 0..output_count {
             let value = self.read_u64::<LittleEndian>()?;
             let script_len = VarUint::read_from(self)?;
@@ -133,13 +282,19 @@ pub trait BlockchainRead: io::Read {
 /// for free.
 impl<R: io::Read + ?Sized> BlockchainRead for R {}
 
+#[inline]
+fn prealloc(count: u64) -> usize {
+    (count as usize).min(MAX_PREALLOC)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::blockchain::parser::types::{Bitcoin, Coin};
+    use crate::blockchain::parser::types::{Bitcoin, CoinType, Litecoin};
     use crate::blockchain::proto::script;
     use crate::blockchain::proto::script::ScriptPattern;
     use crate::blockchain::proto::tx::EvaluatedTx;
+    use crate::blockchain::proto::ToRaw;
     use crate::common::utils;
     use byteorder::{LittleEndian, ReadBytesExt};
     use std::io::{BufReader, Cursor};
@@ -206,14 +361,20 @@ mod tests {
             0x14, 0x17, 0x81, 0xe6, 0x22, 0x94, 0x72, 0x11, 0x66, 0xbf, 0x62, 0x1e, 0x73, 0xa8,
             0x2c, 0xbf, 0x23, 0x42, 0xc8, 0x58, 0xee, 0xac, 0x00, 0x00, 0x00, 0x0,
         ];
-        let inner = Cursor::new(raw_data);
+        let inner = Cursor::new(raw_data.clone());
         let mut reader = BufReader::with_capacity(200, inner);
 
         let magic: u32 = reader.read_u32::<LittleEndian>().unwrap();
         let block_size: u32 = reader.read_u32::<LittleEndian>().unwrap();
 
         // Parse block
-        let block = reader.read_block(block_size, Bitcoin.version_id()).unwrap();
+        let block = reader
+            .read_block(
+                block_size,
+                CoinType::from(Bitcoin).address_format(),
+                BlockFormat::Bitcoin,
+            )
+            .unwrap();
 
         // Block Metadata
         assert_eq!(0xd9b4bef9, magic);
@@ -266,13 +427,24 @@ mod tests {
 
         let script_pubkey = &block.txs[0].value.outputs[0].out.script_pubkey;
         assert_eq!("4104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac",
-                                utils::arr_to_hex(&script_pubkey));
+                                utils::arr_to_hex(script_pubkey));
         assert_eq!(0x00000000, block.txs[0].value.locktime);
+        assert!(!block.txs[0].value.has_witness());
+        assert_eq!(None, block.txs[0].value.witness_hash());
 
         assert_eq!(
             Some(String::from("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa")),
-            script::eval_from_bytes(script_pubkey, Bitcoin.version_id()).address
+            script::eval_from_bytes(script_pubkey, CoinType::from(Bitcoin).address_format())
+                .address
         );
+
+        let header_only = Cursor::new(&raw_data[8..])
+            .read_block_without_txs(block_size, BlockFormat::Bitcoin)
+            .unwrap();
+        assert_eq!(block.header.hash, header_only.header.hash);
+        assert_eq!(285, header_only.size);
+        assert_eq!(0x01, header_only.tx_count.value);
+        assert!(header_only.txs.is_empty());
     }
 
     #[test]
@@ -317,13 +489,13 @@ mod tests {
             0xea, 0x86, 0xd0, 0x2f, 0xf8, 0xe3, 0x32, 0x8b, 0xbd, 0x02, 0x42, 0xb2, 0x0a, 0xf3,
             0x42, 0x59, 0x90, 0xac, 0x00, 0x00, 0x00, 0x00,
         ];
-        let inner = Cursor::new(raw_data);
+        let inner = Cursor::new(raw_data.clone());
         let mut reader = BufReader::with_capacity(200, inner);
         let txs: Vec<EvaluatedTx> = reader
-            .read_txs(1, 0x00)
+            .read_txs(1, AddressFormat::default())
             .unwrap()
             .into_iter()
-            .map(|raw| EvaluatedTx::from(raw))
+            .map(EvaluatedTx::from)
             .collect();
         assert_eq!(txs.len(), 1);
 
@@ -358,5 +530,259 @@ mod tests {
         );
 
         assert_eq!(tx.locktime, 0);
+
+        // Assert witness
+        assert!(tx.has_witness());
+        assert_eq!(tx.witnesses.len(), 1);
+        assert_eq!(tx.witnesses[0].len(), 2);
+        assert_eq!(tx.witnesses[0][0].len(), 72);
+        assert_eq!(tx.witnesses[0][1].len(), 33);
+        assert_eq!(tx.to_witness_bytes(), raw_data);
+        // 108 bytes without the 110 bytes of marker, flag and witness, so 108 * 3 + 218 WU
+        assert_eq!(tx.size as usize, raw_data.len());
+        assert_eq!(tx.size, 218);
+        assert_eq!(tx.stripped_size, 108);
+        assert_eq!(tx.stripped_size as usize, tx.to_bytes().len());
+        assert_eq!(tx.weight(), 542);
+        assert_eq!(tx.vsize(), 136);
+
+        // txid excludes the witness data, wtxid commits to it
+        assert_eq!(
+            utils::arr_to_hex_swapped(&utils::sha256(&utils::sha256(&tx.to_bytes()))),
+            "8139979112e894a14f8370438a471d23984061ff83a9eba0bc7a34433327ec21"
+        );
+        assert_eq!(
+            utils::arr_to_hex_swapped(&tx.witness_hash().unwrap()),
+            "6bf4e4dfb860cf0906f49c836700b130ac78cc391c72a0911c94cdec4dcb10ec"
+        );
+    }
+
+    #[test]
+    fn test_parse_mixed_witness_tx() {
+        // Signed transaction of the native P2WPKH example in BIP143: the first input spends
+        // a P2PK output and has an empty witness, the second one spends P2WPKH
+        let raw = utils::hex_to_vec(concat!(
+            "01000000000102fff7f7881a8099afa6940d42d1e7f6362bec38171ea3edf433541db4e4ad969f000000",
+            "00494830450221008b9d1dc26ba6a9cb62127b02742fa9d754cd3bebf337f7a55d114c8e5cdd30be0220",
+            "40529b194ba3f9281a99f2b1c0a19c0489bc22ede944ccf4ecbab4cc618ef3ed01eeffffffef51e1b804",
+            "cc89d182d279655c3aa89e815b1b309fe287d9b2b55d57b90ec68a0100000000ffffffff02202cb20600",
+            "0000001976a9148280b37df378db99f66f85c95a783a76ac7a6d5988ac9093510d000000001976a9143b",
+            "de42dbee7e4dbe6a21b2d50ce2f0167faa815988ac000247304402203609e17b84f6a7d30c80bfa610b5",
+            "b4542f32a8a0d5447a12fb1366d7f01cc44a0220573a954c4518331561406f90300e8f3358f51928d43c",
+            "212a8caed02de67eebee0121025476c2e83188368da1ff3e292e7acafcdb3566bb0ad253f62fc70f07ae",
+            "eb635711000000",
+        ));
+        let mut tx = EvaluatedTx::from(
+            Cursor::new(&raw)
+                .read_txs(1, AddressFormat::default())
+                .unwrap()
+                .remove(0),
+        );
+        assert_eq!(2, tx.witnesses.len());
+        assert!(tx.witnesses[0].is_empty());
+        assert_eq!(2, tx.witnesses[1].len());
+        assert_eq!(0x11, tx.locktime);
+        assert_eq!(raw, tx.to_witness_bytes());
+
+        // The stripped serialization omits marker, flag and the witnesses (2 + 1 + 107 bytes)
+        let stripped = tx.to_bytes();
+        assert_eq!(raw.len() - 110, stripped.len());
+        assert_eq!(stripped.len() as u64 * 4 + 110, tx.weight());
+        assert_eq!(raw.len(), tx.size as usize);
+        assert_eq!(stripped.len(), tx.stripped_size as usize);
+        let txid = utils::sha256(&utils::sha256(&stripped));
+        let wtxid = tx.witness_hash().unwrap();
+        assert_eq!(utils::sha256(&utils::sha256(&raw)), wtxid);
+        assert_ne!(txid, wtxid);
+
+        // Dropping the witnesses keeps wtxid and weight
+        let (weight, vsize) = (tx.weight(), tx.vsize());
+        tx.drop_witnesses();
+        assert!(tx.witnesses.is_empty());
+        assert!(tx.has_witness());
+        assert!(!tx.retains_witness());
+        assert_eq!(Some(wtxid), tx.witness_hash());
+        assert_eq!((weight, vsize), (tx.weight(), tx.vsize()));
+        assert_eq!(stripped, tx.to_bytes());
+    }
+
+    #[test]
+    fn test_parse_mweb_block() {
+        // Synthetic Litecoin block with a coinbase and a HogEx with a pegged in (OP_8 <32 bytes>)
+        // and a pegged out output, serialized with the MWEB flag (0x08) and no MWEB transaction (0x00)
+        let mut raw = utils::hex_to_vec(concat!(
+            "000000200eee47151fdc8714518b77f7002bc0872531d6832ce5dbbb9481f47a9b6e0aa5db9e27dba29bbdec",
+            "8bb6074b6c3592e68bc4b63caaea725ab2cae2e41955950a40c78662e5e3011a110d2c9f0202000000010000",
+            "000000000000000000000000000000000000000000000000000000000000ffffffff0903c0d62603e8ba1900",
+            "ffffffff01b9ac814a00000000160014751e76e8199196d454941c45d1b3a323f1433bd60000000002000000",
+            "000801888f64a12ccf992232227c1d14950d167c4e9401d5a7b364c4241ebb1bade8b90000000000ffffffff",
+            "02141a99be1c000000225820c4578e6f2c860677a8bb5d21329063f8902dfd9ee15ddcbbdcc195a61168587f",
+            "404b4c0000000000160014751e76e8199196d454941c45d1b3a323f1433bd60000000000",
+        ));
+        let txs_len = raw.len();
+        // Extension block: present (0x01) followed by the MWEB header and body, which aren't parsed
+        raw.push(0x01);
+        raw.extend((0..300).map(|i| i as u8));
+        let format = CoinType::from(Litecoin).address_format();
+
+        // Followed by another block to check that the whole extension block is consumed
+        let mut stream = raw.clone();
+        stream.extend_from_slice(&raw);
+        let mut reader = Cursor::new(&stream);
+        for _ in 0..2 {
+            let block = reader
+                .read_block(raw.len() as u32, format, BlockFormat::Litecoin)
+                .unwrap();
+            assert!(block.has_mweb);
+            assert_eq!(301, block.mweb_size);
+            assert_eq!(2, block.txs.len());
+            assert_eq!(block.header.value.merkle_root, block.compute_merkle_root());
+            // Without the extension block
+            assert_eq!(raw[..txs_len], block.to_bytes());
+        }
+        assert_eq!(stream.len() as u64, reader.position());
+
+        let block = Cursor::new(&raw)
+            .read_block(raw.len() as u32, format, BlockFormat::Litecoin)
+            .unwrap();
+        let hogex = &block.txs[1];
+        assert!(hogex.value.hogex);
+        assert!(!block.txs[0].value.hogex);
+        assert!(!hogex.value.has_witness());
+        // The txid doesn't cover marker, flag and the MWEB byte
+        assert_eq!(
+            "056b23430307533b57d7386b89d2ea0f6ef603b87f5873b04f89d4d6dc03eb94",
+            utils::arr_to_hex_swapped(&hogex.hash)
+        );
+        assert_eq!(None, hogex.value.witness_hash());
+        // Neither the MWEB flag nor the extension block count towards size and weight
+        assert_eq!(hogex.value.to_bytes().len() as u32, hogex.value.size);
+        assert_eq!(hogex.value.size, hogex.value.stripped_size);
+        assert_eq!(txs_len as u32 - 3, block.stripped_size);
+        assert_eq!(block.stripped_size as u64 * 4, block.weight);
+        assert_eq!(123456789012, hogex.value.outputs[0].out.value);
+        assert_eq!(
+            ScriptPattern::WitnessUnknown(8),
+            hogex.value.outputs[0].script.pattern
+        );
+        assert_eq!(5000000, hogex.value.outputs[1].out.value);
+        assert_eq!(
+            Some(String::from("ltc1qw508d6qejxtdg4y5r3zarvary0c5xw7kgmn4n9")),
+            hogex.value.outputs[1].script.address
+        );
+
+        // The HogEx alone doesn't make an MWEB block for other coins
+        let block = Cursor::new(&raw)
+            .read_block(raw.len() as u32, format, BlockFormat::Bitcoin)
+            .unwrap();
+        assert!(!block.has_mweb);
+        assert_eq!(0, block.mweb_size);
+
+        // The extension block is whatever follows the HogEx, even if nothing does
+        let block = Cursor::new(&raw[..txs_len])
+            .read_block(txs_len as u32, format, BlockFormat::Litecoin)
+            .unwrap();
+        assert!(block.has_mweb);
+        assert_eq!(0, block.mweb_size);
+
+        // Transactions with MWEB data are only relayed, they are never part of the block
+        let mut with_mweb_tx = raw[..txs_len].to_vec();
+        with_mweb_tx[txs_len - 5] = 0x01;
+        assert!(Cursor::new(&with_mweb_tx)
+            .read_block(txs_len as u32, format, BlockFormat::Litecoin)
+            .is_err());
+    }
+
+    /// Returns the largest block of the regtest fixture, see `RegtestChain`
+    fn largest_fixture_block() -> Vec<u8> {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/regtest.dat");
+        let stream = std::fs::read(path).unwrap();
+        let mut data = stream.as_slice();
+        let mut largest: &[u8] = &[];
+        while !data.is_empty() {
+            let size = (&data[4..8]).read_u32::<LittleEndian>().unwrap() as usize;
+            if size > largest.len() {
+                largest = &data[8..8 + size];
+            }
+            data = &data[8 + size..];
+        }
+        largest.to_vec()
+    }
+
+    #[test]
+    fn test_evaluate_scripts_afterwards() {
+        let raw = largest_fixture_block();
+        let format = CoinType::from(Bitcoin).address_format();
+        let read = |format| {
+            Cursor::new(&raw)
+                .read_block(raw.len() as u32, format, BlockFormat::Bitcoin)
+                .unwrap()
+        };
+        let patterns = |block: &Block| {
+            block
+                .txs
+                .iter()
+                .flat_map(|tx| tx.value.outputs.iter())
+                .map(|output| (output.script.pattern.clone(), output.script.address.clone()))
+                .collect::<Vec<_>>()
+        };
+        let mut block = read(AddressFormat {
+            evaluate_scripts: false,
+            ..format
+        });
+        assert!(patterns(&block)
+            .iter()
+            .all(|(pattern, _)| *pattern == ScriptPattern::NotEvaluated));
+        block.evaluate_scripts(format);
+        assert_eq!(patterns(&read(format)), patterns(&block));
+    }
+
+    /// Deserializes the largest block of the regtest fixture with and without evaluating its
+    /// scripts and reports the time per block of the samples, run with
+    /// `cargo test --release -- --ignored bench`
+    #[test]
+    #[ignore]
+    fn bench_read_block() {
+        use std::time::Instant;
+
+        const SAMPLES: usize = 50;
+        const ITERATIONS: usize = 200;
+        let raw = largest_fixture_block();
+        let format = CoinType::from(Bitcoin).address_format();
+        for evaluate_scripts in [false, true] {
+            let format = AddressFormat {
+                evaluate_scripts,
+                ..format
+            };
+            let read = || {
+                Cursor::new(&raw)
+                    .read_block(raw.len() as u32, format, BlockFormat::Bitcoin)
+                    .unwrap()
+            };
+            // Warms up caches and the thread pool of `Block::new`
+            for _ in 0..ITERATIONS {
+                read();
+            }
+            let mut samples: Vec<f64> = (0..SAMPLES)
+                .map(|_| {
+                    let started = Instant::now();
+                    for _ in 0..ITERATIONS {
+                        read();
+                    }
+                    started.elapsed().as_secs_f64() * 1e6 / ITERATIONS as f64
+                })
+                .collect();
+            samples.sort_by(f64::total_cmp);
+            let mean = samples.iter().sum::<f64>() / SAMPLES as f64;
+            println!(
+                "read_block ({} bytes), scripts evaluated: {:5}: min {:.1}µs, median {:.1}µs, mean {:.1}µs, {:.1} MB/s",
+                raw.len(),
+                evaluate_scripts,
+                samples[0],
+                samples[SAMPLES / 2],
+                mean,
+                raw.len() as f64 / samples[SAMPLES / 2]
+            );
+        }
     }
 }
@@ -0,0 +1,968 @@
+//! Reads blocks prefixed with the network magic and their size from a stream, e.g. stdin or a
+//! named pipe fed by `cat blk*.dat`. There is no block index, so the heights come from the
+//! chain with the most work (summed up from `bits`) reconstructed from the `prev_hash` links,
+//! rooted at the genesis block. Equal work goes to the tip with the lower hash.
+//!
+//! A block is delivered once `CONFIRMATIONS` blocks are on top of it, or when the stream ends.
+//! Forks with less work are dropped then. Blocks arriving ahead of their parents are held back
+//! until the parent shows up. The delivered blocks only depend on the blocks of the stream, not on
+//! their order.
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, Cursor, Read};
+use std::path::{Path, PathBuf};
+
+use crate::blockchain::parser::blkfile::find_magic;
+use crate::blockchain::parser::reader::BlockchainRead;
+use crate::blockchain::proto::block::BlockFormat;
+use crate::blockchain::proto::target::U256;
+use crate::blockchain::proto::Hashed;
+use crate::errors::{OpError, OpErrorKind, OpResult};
+use crate::ParserOptions;
+
+/// Blocks on top of a block before it's final. Stale forks are far less deep.
+pub const CONFIRMATIONS: u64 = 100;
+/// Blocks waiting for their parent. Core downloads at most 1024 blocks ahead, many more mean
+/// the stream doesn't start at the genesis block.
+const MAX_ORPHANS: usize = 10_000;
+/// Sizes outside of these bounds are garbage, not a block
+const MIN_BLOCK_SIZE: u32 = 80;
+const MAX_BLOCK_SIZE: u32 = 32 * 1024 * 1024;
+// Bytes read from the stream at once
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Returns a reader if `path` is `-` (stdin) or a named pipe, None for a blocks directory
+pub fn open(path: &Path) -> OpResult<Option<Box<dyn Read>>> {
+    if path == Path::new("-") {
+        return Ok(Some(Box::new(io::stdin())));
+    }
+    if is_stream(path) {
+        return Ok(Some(Box::new(File::open(path)?)));
+    }
+    Ok(None)
+}
+
+/// True if `open` reads a stream from `path`, without opening it
+pub fn is_stream(path: &Path) -> bool {
+    if path == Path::new("-") {
+        return true;
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+        if path.metadata().is_ok_and(|meta| meta.file_type().is_fifo()) {
+            return true;
+        }
+    }
+    false
+}
+
+/// Expands `*` and `?` in the file names of `--block-file`. The files are kept in the given
+/// order, the matches of a pattern are sorted by name like a shell does.
+pub fn expand_block_files<'a, I: IntoIterator<Item = &'a str>>(
+    patterns: I,
+) -> OpResult<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for pattern in patterns {
+        let path = PathBuf::from(pattern);
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned());
+        let name = match name {
+            Some(name) if name.contains(['*', '?']) && !path.exists() => name,
+            _ => {
+                if !path.exists() {
+                    return Err(OpError::from(format!(
+                        "Block file {} doesn't exist.",
+                        path.display()
+                    )));
+                }
+                if path.is_dir() {
+                    return Err(OpError::from(format!(
+                        "Block file {} is a directory, pass it as --blockchain-dir.",
+                        path.display()
+                    )));
+                }
+                files.push(path);
+                continue;
+            }
+        };
+        let dir = match path.parent() {
+            Some(dir) if dir != Path::new("") => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        let pattern: Vec<char> = name.chars().collect();
+        let mut matches = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let file_name: Vec<char> = entry.file_name().to_string_lossy().chars().collect();
+            if matches_wildcard(&pattern, &file_name) {
+                matches.push(path.with_file_name(entry.file_name()));
+            }
+        }
+        if matches.is_empty() {
+            return Err(OpError::from(format!(
+                "No block file matches {}.",
+                path.display()
+            )));
+        }
+        matches.sort();
+        files.extend(matches);
+    }
+    Ok(files)
+}
+
+/// True if `name` matches `pattern`, `*` stands for any and `?` for one character
+fn matches_wildcard(pattern: &[char], name: &[char]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some('*'), _) => {
+            matches_wildcard(&pattern[1..], name)
+                || (!name.is_empty() && matches_wildcard(pattern, &name[1..]))
+        }
+        (Some('?'), Some(_)) => matches_wildcard(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) if p == n => matches_wildcard(&pattern[1..], &name[1..]),
+        _ => false,
+    }
+}
+
+/// Names the stream in logs and errors: stdin, the pipe, or the block files
+pub fn source_name(options: &ParserOptions) -> PathBuf {
+    match options.block_files.as_slice() {
+        [] => options.blockchain_dir.clone(),
+        [file] => file.clone(),
+        files => PathBuf::from(format!("{} block files", files.len())),
+    }
+}
+
+/// Reads the given files one after another as a single stream, see `--block-file`. The stream
+/// offset at the start of each file is logged, the offsets of skipped bytes refer to the stream.
+pub struct FileSequence {
+    paths: VecDeque<PathBuf>,
+    current: Option<File>,
+    position: u64,
+}
+
+impl FileSequence {
+    pub fn new(paths: &[PathBuf]) -> Self {
+        FileSequence {
+            paths: paths.iter().cloned().collect(),
+            current: None,
+            position: 0,
+        }
+    }
+}
+
+impl Read for FileSequence {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if let Some(file) = self.current.as_mut() {
+                let n = file.read(buf)?;
+                if n > 0 || buf.is_empty() {
+                    self.position += n as u64;
+                    return Ok(n);
+                }
+                self.current = None;
+            }
+            match self.paths.pop_front() {
+                Some(path) => {
+                    info!(target: "stream", "Reading blocks from {} (stream offset {}) ...", path.display(), self.position);
+                    self.current = Some(File::open(&path).map_err(|e| {
+                        io::Error::new(e.kind(), format!("{}: {}", path.display(), e))
+                    })?);
+                }
+                None => return Ok(0),
+            }
+        }
+    }
+}
+
+/// Serialized block at its height of the best chain, or of a stale fork
+pub struct StreamBlock {
+    pub height: u64,
+    pub hash: [u8; 32],
+    pub raw: Vec<u8>,
+    /// Position in the stream, after magic and size
+    pub offset: u64,
+}
+
+/// Hash, bits, serialized block and offset of a block waiting for its parent
+type Orphan = ([u8; 32], u32, Vec<u8>, u64);
+
+/// Position of a connected block in the tree of all blocks
+#[derive(Clone, Copy)]
+struct Link {
+    height: u64,
+    // Work of the block and all its ancestors
+    chainwork: U256,
+    // Dropped, or forking off below a final block. Its children are stale as well.
+    stale: bool,
+}
+
+/// Block which is connected to the chain but not final yet
+struct Pending {
+    prev_hash: [u8; 32],
+    height: u64,
+    raw: Vec<u8>,
+    offset: u64,
+}
+
+pub struct BlockStream<R> {
+    reader: R,
+    // Names the stream in errors
+    path: PathBuf,
+    magic: [u8; 4],
+    format: BlockFormat,
+    strict: bool,
+    // Bytes read ahead, unconsumed from `head` on
+    buf: Vec<u8>,
+    head: usize,
+    // Stream offset of `buf[head]`
+    position: u64,
+    // Start of the bytes skipped while looking for the next magic, and if all of them were zero
+    skipping: Option<(u64, bool)>,
+    skipped: Vec<(u64, u64)>,
+    // All connected blocks, so children can be connected
+    links: HashMap<[u8; 32], Link>,
+    pending: HashMap<[u8; 32], Pending>,
+    pending_by_height: BTreeMap<u64, Vec<[u8; 32]>>,
+    // Blocks by their missing parent
+    orphans: HashMap<[u8; 32], Vec<Orphan>>,
+    n_orphans: usize,
+    // Tip of the chain with the most work
+    tip: Option<([u8; 32], Link)>,
+    next_height: u64,
+    n_stale: u64,
+    // Dropped blocks are kept for `take_stale`, see `--include-orphans`
+    keep_stale: bool,
+    stale: Vec<StreamBlock>,
+    eof: bool,
+}
+
+impl<R: Read> BlockStream<R> {
+    pub fn new(reader: R, path: &Path, magic: u32, format: BlockFormat, strict: bool) -> Self {
+        BlockStream {
+            reader,
+            path: PathBuf::from(path),
+            magic: magic.to_le_bytes(),
+            format,
+            strict,
+            buf: Vec::with_capacity(CHUNK_SIZE),
+            head: 0,
+            position: 0,
+            skipping: None,
+            skipped: Vec::new(),
+            links: HashMap::new(),
+            pending: HashMap::new(),
+            pending_by_height: BTreeMap::new(),
+            orphans: HashMap::new(),
+            n_orphans: 0,
+            tip: None,
+            next_height: 0,
+            n_stale: 0,
+            keep_stale: false,
+            stale: Vec::new(),
+            eof: false,
+        }
+    }
+
+    /// Keeps the dropped blocks until they are taken by `take_stale`
+    pub fn keep_stale(&mut self) {
+        self.keep_stale = true;
+    }
+
+    /// Returns the blocks of the chain with the most work in height order, None once the stream
+    /// ended
+    pub fn next_block(&mut self) -> OpResult<Option<StreamBlock>> {
+        loop {
+            let tip_height = self.tip.map(|(_, tip)| tip.height);
+            match tip_height {
+                Some(tip) if tip >= self.next_height + CONFIRMATIONS => return Ok(self.deliver()),
+                Some(tip) if self.eof && tip >= self.next_height => return Ok(self.deliver()),
+                _ if self.eof => {
+                    // Forks which are longer than the chain with the most work
+                    let rest = std::mem::take(&mut self.pending_by_height);
+                    for hash in rest.into_values().flatten() {
+                        self.drop_block(hash);
+                    }
+                    if self.n_orphans > 0 {
+                        warn!(target: "stream", "{} blocks didn't connect to the chain and were dropped.", self.n_orphans);
+                        self.orphans.clear();
+                        self.n_orphans = 0;
+                    }
+                    return Ok(None);
+                }
+                _ => (),
+            }
+            match self.read_raw()? {
+                Some((offset, hash, prev_hash, bits, raw)) => {
+                    self.add(hash, prev_hash, bits, raw, offset)?
+                }
+                None => self.eof = true,
+            }
+        }
+    }
+
+    /// Byte ranges which were skipped since the last call because they didn't contain a block
+    pub fn take_skipped(&mut self) -> Vec<(u64, u64)> {
+        std::mem::take(&mut self.skipped)
+    }
+
+    /// Blocks which were dropped because they aren't part of the chain with the most work
+    pub fn n_stale(&self) -> u64 {
+        self.n_stale
+    }
+
+    /// Blocks dropped since the last call, if they are kept (see `keep_stale`). They are ordered
+    /// by height and hash, so the order doesn't depend on the order in which they arrived.
+    pub fn take_stale(&mut self) -> Vec<StreamBlock> {
+        let mut stale = std::mem::take(&mut self.stale);
+        stale.sort_unstable_by(|a, b| {
+            a.height
+                .cmp(&b.height)
+                .then_with(|| U256::from_le_bytes(&a.hash).cmp(&U256::from_le_bytes(&b.hash)))
+        });
+        stale
+    }
+
+    /// Connects the block to its parent and its waiting children to it
+    fn add(
+        &mut self,
+        hash: [u8; 32],
+        prev_hash: [u8; 32],
+        bits: u32,
+        raw: Vec<u8>,
+        offset: u64,
+    ) -> OpResult<()> {
+        if self.links.contains_key(&hash) {
+            debug!(target: "stream", "Skipping duplicate block at offset {}.", offset);
+            return Ok(());
+        }
+        let link = if prev_hash == [0u8; 32] {
+            Link {
+                height: 0,
+                chainwork: block_work(bits),
+                stale: false,
+            }
+        } else {
+            match self.links.get(&prev_hash) {
+                Some(parent) => parent.child(bits),
+                None => {
+                    if self.n_orphans >= MAX_ORPHANS {
+                        return Err(OpError::new(OpErrorKind::RuntimeError).join_msg(&format!(
+                            "More than {} blocks of {} don't connect to the chain, does it start with the genesis block?",
+                            MAX_ORPHANS,
+                            self.path.display()
+                        )));
+                    }
+                    self.orphans
+                        .entry(prev_hash)
+                        .or_default()
+                        .push((hash, bits, raw, offset));
+                    self.n_orphans += 1;
+                    return Ok(());
+                }
+            }
+        };
+        let mut connect = vec![(hash, prev_hash, link, raw, offset)];
+        while let Some((hash, prev_hash, mut link, raw, offset)) = connect.pop() {
+            let height = link.height;
+            // Forks off below a final block
+            link.stale |= height < self.next_height;
+            self.links.insert(hash, link);
+            if let Some(children) = self.orphans.remove(&hash) {
+                self.n_orphans -= children.len();
+                for (child, bits, raw, offset) in children {
+                    connect.push((child, hash, link.child(bits), raw, offset));
+                }
+            }
+            if link.stale {
+                debug!(target: "stream", "Dropping stale block at height {} (offset {}).", height, offset);
+                self.n_stale += 1;
+                if self.keep_stale {
+                    self.stale.push(StreamBlock {
+                        height,
+                        hash,
+                        raw,
+                        offset,
+                    });
+                }
+                continue;
+            }
+            if self
+                .tip
+                .is_none_or(|(tip_hash, tip)| is_better(&hash, &link, &tip_hash, &tip))
+            {
+                self.tip = Some((hash, link));
+            }
+            self.pending_by_height.entry(height).or_default().push(hash);
+            self.pending.insert(
+                hash,
+                Pending {
+                    prev_hash,
+                    height,
+                    raw,
+                    offset,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    /// Removes the block at `next_height` of the chain leading to the tip, and its competitors
+    fn deliver(&mut self) -> Option<StreamBlock> {
+        let (mut hash, _) = self.tip?;
+        loop {
+            let block = self.pending.get(&hash)?;
+            if block.height == self.next_height {
+                break;
+            }
+            hash = block.prev_hash;
+        }
+        let competitors = self
+            .pending_by_height
+            .remove(&self.next_height)
+            .unwrap_or_default();
+        for other in competitors.iter().filter(|other| **other != hash) {
+            self.drop_fork(*other);
+        }
+        let block = self.pending.remove(&hash)?;
+        self.next_height += 1;
+        Some(StreamBlock {
+            height: block.height,
+            hash,
+            raw: block.raw,
+            offset: block.offset,
+        })
+    }
+
+    /// Drops a stale block and the blocks built on it
+    fn drop_fork(&mut self, hash: [u8; 32]) {
+        let mut stale = vec![hash];
+        while let Some(hash) = stale.pop() {
+            if let Some(block) = self.drop_block(hash) {
+                if let Some(hashes) = self.pending_by_height.get_mut(&block.height) {
+                    hashes.retain(|other| *other != hash);
+                }
+                let children = self
+                    .pending_by_height
+                    .get(&(block.height + 1))
+                    .into_iter()
+                    .flatten()
+                    .filter(|child| {
+                        self.pending
+                            .get(*child)
+                            .is_some_and(|c| c.prev_hash == hash)
+                    });
+                stale.extend(children.copied().collect::<Vec<_>>());
+            }
+        }
+    }
+
+    /// Removes a pending block which isn't part of the chain. Its bytes are moved to `stale` if
+    /// they are kept.
+    fn drop_block(&mut self, hash: [u8; 32]) -> Option<Pending> {
+        let block = self.pending.remove(&hash)?;
+        debug!(target: "stream", "Dropping stale block at height {} (offset {}).", block.height, block.offset);
+        self.n_stale += 1;
+        if let Some(link) = self.links.get_mut(&hash) {
+            link.stale = true;
+        }
+        if self.keep_stale {
+            self.stale.push(StreamBlock {
+                height: block.height,
+                hash,
+                raw: block.raw,
+                offset: block.offset,
+            });
+            return Some(Pending {
+                raw: Vec::new(),
+                ..block
+            });
+        }
+        Some(block)
+    }
+
+    /// Reads the next block, skipping anything which isn't one.
+    /// Returns its offset, hash, the hash of its parent, its bits and the serialized block.
+    #[allow(clippy::type_complexity)]
+    fn read_raw(&mut self) -> OpResult<Option<(u64, [u8; 32], [u8; 32], u32, Vec<u8>)>> {
+        loop {
+            if !self.fill(4)? {
+                let end = self.position + self.available() as u64;
+                self.consume(self.available());
+                self.end_skip(end)?;
+                return Ok(None);
+            }
+            if self.buf[self.head..self.head + 4] != self.magic {
+                self.skip_byte();
+                continue;
+            }
+            let start = self.position;
+            if !self.fill(8)? {
+                self.skip_byte();
+                continue;
+            }
+            let size = u32::from_le_bytes([
+                self.buf[self.head + 4],
+                self.buf[self.head + 5],
+                self.buf[self.head + 6],
+                self.buf[self.head + 7],
+            ]);
+            if !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&size)
+                || !self.fill(8 + size as usize)?
+            {
+                // Garbage or truncated, the next block may start right after the magic
+                self.skip_byte();
+                continue;
+            }
+            // Peeks at what follows, usually the next magic
+            self.fill(8 + size as usize + 4)?;
+            let end = self.head + 8 + size as usize;
+            let raw = &self.buf[self.head + 8..end];
+            let next = &self.buf[end..self.buf.len().min(end + 4)];
+            let followed = next.is_empty() || next == self.magic;
+            // A wrong size, e.g. of a cut off block, swallows the start of the next block
+            if !followed && find_magic(&raw[1..], u32::from_le_bytes(self.magic)).is_some() {
+                self.skip_byte();
+                continue;
+            }
+            let header = match Cursor::new(raw).read_block_header_of(self.format) {
+                Ok(header) => header,
+                Err(_) => {
+                    self.skip_byte();
+                    continue;
+                }
+            };
+            let raw = raw.to_vec();
+            self.end_skip(start)?;
+            self.consume(8 + size as usize);
+            let (prev_hash, bits) = (header.prev_hash, header.bits);
+            let hash = Hashed::double_sha256(header).hash;
+            return Ok(Some((start + 8, hash, prev_hash, bits, raw)));
+        }
+    }
+
+    fn skip_byte(&mut self) {
+        let zero = self.buf[self.head] == 0;
+        let (start, all_zero) = self.skipping.unwrap_or((self.position, true));
+        self.skipping = Some((start, all_zero && zero));
+        self.consume(1);
+    }
+
+    /// Records the skipped bytes up to `end`. Zeros are the padding of preallocated blk files.
+    fn end_skip(&mut self, end: u64) -> OpResult<()> {
+        if let Some((start, all_zero)) = self.skipping.take() {
+            if all_zero {
+                return Ok(());
+            }
+            if self.strict {
+                return Err(OpError::new(OpErrorKind::Deserialization {
+                    file: Some(self.path.clone()),
+                    offset: start,
+                    height: None,
+                    source: Box::new(OpError::from(format!(
+                        "{} bytes without a block",
+                        end - start
+                    ))),
+                }));
+            }
+            warn!(target: "stream", "Skipping {} bytes without a block ({}..{}) of {}.", end - start, start, end, self.path.display());
+            self.skipped.push((start, end));
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn available(&self) -> usize {
+        self.buf.len() - self.head
+    }
+
+    /// Reads until `n` bytes are available, false if the stream ends before
+    fn fill(&mut self, n: usize) -> OpResult<bool> {
+        if self.available() >= n {
+            return Ok(true);
+        }
+        // Moves the unconsumed bytes to the front
+        self.buf.drain(..self.head);
+        self.head = 0;
+        while self.buf.len() < n {
+            let len = self.buf.len();
+            self.buf.resize(len + CHUNK_SIZE.max(n - len), 0);
+            let read = loop {
+                match self.reader.read(&mut self.buf[len..]) {
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    result => break result,
+                }
+            };
+            match read {
+                Ok(read) => {
+                    self.buf.truncate(len + read);
+                    if read == 0 {
+                        return Ok(false);
+                    }
+                }
+                Err(e) => {
+                    self.buf.truncate(len);
+                    return Err(OpError::from(e));
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    #[inline]
+    fn consume(&mut self, n: usize) {
+        self.head += n;
+        self.position += n as u64;
+    }
+}
+
+impl Link {
+    #[inline]
+    fn child(&self, bits: u32) -> Link {
+        Link {
+            height: self.height + 1,
+            chainwork: self.chainwork + block_work(bits),
+            stale: self.stale,
+        }
+    }
+}
+
+/// Expected number of hashes for a block of the given target, zero if it's invalid
+#[inline]
+fn block_work(bits: u32) -> U256 {
+    U256::from_compact(bits).map_or(U256::ZERO, |target| target.work())
+}
+
+/// True if `a` has more work than `b`, or the same and the lower hash
+fn is_better(a_hash: &[u8; 32], a: &Link, b_hash: &[u8; 32], b: &Link) -> bool {
+    match a.chainwork.cmp(&b.chainwork) {
+        Ordering::Greater => true,
+        Ordering::Less => false,
+        Ordering::Equal => U256::from_le_bytes(a_hash) < U256::from_le_bytes(b_hash),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::proto::block::Block;
+    use crate::blockchain::proto::ToRaw;
+    use crate::callbacks::fixtures::{new_block, new_chain, new_tx, RegtestChain, REGTEST_MAGIC};
+
+    const MAGIC: u32 = 0xd9b4bef9;
+
+    fn chain(n: usize) -> Vec<Block> {
+        new_chain((0..n).map(|i| vec![new_tx(&[], &[i as u64])]).collect())
+    }
+
+    fn frame(block: &Block) -> Vec<u8> {
+        let bytes = block.to_bytes();
+        let mut framed = MAGIC.to_le_bytes().to_vec();
+        framed.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+        framed.extend_from_slice(&bytes);
+        framed
+    }
+
+    /// A block on top of `parent`, the nonce tells it apart from its siblings
+    fn child(parent: &Block, nonce: u32) -> Block {
+        child_with_bits(parent, nonce, 0x1d00ffff)
+    }
+
+    fn child_with_bits(parent: &Block, nonce: u32, bits: u32) -> Block {
+        let mut block = new_block(vec![new_tx(&[], &[nonce as u64])]);
+        block.header.value.prev_hash = parent.header.hash;
+        block.header.value.nonce = nonce;
+        block.header.value.bits = bits;
+        block.header = Hashed::double_sha256(block.header.value.clone());
+        block
+    }
+
+    /// Heights and hashes of the delivered blocks
+    type Delivered = Vec<(u64, [u8; 32])>;
+
+    fn read_all(
+        data: Vec<u8>,
+        strict: bool,
+    ) -> OpResult<(Delivered, BlockStream<Cursor<Vec<u8>>>)> {
+        let mut stream = BlockStream::new(
+            Cursor::new(data),
+            Path::new("-"),
+            MAGIC,
+            BlockFormat::Bitcoin,
+            strict,
+        );
+        let mut blocks = Vec::new();
+        while let Some(block) = stream.next_block()? {
+            blocks.push((block.height, block.hash));
+        }
+        Ok((blocks, stream))
+    }
+
+    fn hashes(chain: &[Block]) -> Delivered {
+        chain
+            .iter()
+            .enumerate()
+            .map(|(height, block)| (height as u64, block.header.hash))
+            .collect()
+    }
+
+    #[test]
+    fn test_in_order() {
+        let chain = chain(CONFIRMATIONS as usize + 20);
+        let data: Vec<u8> = chain.iter().flat_map(frame).collect();
+        let mut stream = BlockStream::new(
+            Cursor::new(data),
+            Path::new("-"),
+            MAGIC,
+            BlockFormat::Bitcoin,
+            false,
+        );
+        let first = stream.next_block().unwrap().unwrap();
+        assert_eq!((0, chain[0].header.hash), (first.height, first.hash));
+        assert_eq!(chain[0].to_bytes(), first.raw);
+        assert_eq!(8, first.offset);
+        // Delivered before the stream ended
+        assert!(!stream.eof);
+
+        let (blocks, stream) = read_all(chain.iter().flat_map(frame).collect(), false).unwrap();
+        assert_eq!(hashes(&chain), blocks);
+        assert_eq!(0, stream.n_stale());
+    }
+
+    #[test]
+    fn test_out_of_order_and_forks() {
+        let chain = chain(8);
+        // A stale fork of two blocks on top of block 3, and a duplicate
+        let stale = child(&chain[3], 1000);
+        let stale_child = child(&stale, 1001);
+        let order = [0, 2, 1, 3, 5, 4, 6, 7];
+        let mut data = Vec::new();
+        for (i, height) in order.iter().enumerate() {
+            data.extend(frame(&chain[*height]));
+            if i == 4 {
+                data.extend(frame(&stale_child));
+                data.extend(frame(&stale));
+                data.extend(frame(&chain[1]));
+            }
+        }
+        let (blocks, stream) = read_all(data, false).unwrap();
+        assert_eq!(hashes(&chain), blocks);
+        assert_eq!(2, stream.n_stale());
+    }
+
+    #[test]
+    fn test_most_work() {
+        let chain = chain(6);
+        // Two blocks on top of block 2 with a 256 times lower target outweigh blocks 3 to 5
+        let heavy = child_with_bits(&chain[2], 1000, 0x1c00ffff);
+        let heavy_child = child_with_bits(&heavy, 1001, 0x1c00ffff);
+        let mut data: Vec<u8> = chain.iter().flat_map(frame).collect();
+        data.extend(frame(&heavy));
+        data.extend(frame(&heavy_child));
+        let mut stream = BlockStream::new(
+            Cursor::new(data),
+            Path::new("-"),
+            MAGIC,
+            BlockFormat::Bitcoin,
+            false,
+        );
+        stream.keep_stale();
+        let mut blocks = Vec::new();
+        while let Some(block) = stream.next_block().unwrap() {
+            blocks.push((block.height, block.hash));
+        }
+        let mut expected = hashes(&chain[..3]);
+        expected.push((3, heavy.header.hash));
+        expected.push((4, heavy_child.header.hash));
+        assert_eq!(expected, blocks);
+        assert_eq!(3, stream.n_stale());
+        let stale: Delivered = stream
+            .take_stale()
+            .into_iter()
+            .map(|block| (block.height, block.hash))
+            .collect();
+        assert_eq!(hashes(&chain)[3..], stale);
+        assert!(stream.take_stale().is_empty());
+
+        // The same work goes to the lower hash, whichever comes first
+        let (a, b) = (child(&chain[5], 1), child(&chain[5], 2));
+        let lower = a.header.hash.iter().rev().lt(b.header.hash.iter().rev());
+        let winner = if lower { &a } else { &b };
+        for order in [[&a, &b], [&b, &a]] {
+            let mut data: Vec<u8> = chain.iter().flat_map(frame).collect();
+            data.extend(order.iter().flat_map(|block| frame(block)));
+            let (blocks, stream) = read_all(data, false).unwrap();
+            assert_eq!((6, winner.header.hash), blocks[6]);
+            assert_eq!(1, stream.n_stale());
+            // Nothing is kept by default
+            assert_eq!(0, stream.stale.len());
+        }
+    }
+
+    #[test]
+    fn test_stale_order() {
+        let chain = chain(4);
+        let tips: Vec<Block> = (1..=3).map(|nonce| child(&chain[3], nonce)).collect();
+        let mut losers: Vec<[u8; 32]> = tips.iter().map(|block| block.header.hash).collect();
+        losers.sort_by_key(U256::from_le_bytes);
+        losers.remove(0);
+        // Stale blocks come by height and hash, in whatever order they arrived
+        for order in [[0, 1, 2], [2, 1, 0], [1, 2, 0]] {
+            let mut data: Vec<u8> = chain.iter().flat_map(frame).collect();
+            data.extend(order.iter().flat_map(|i| frame(&tips[*i])));
+            let mut stream = BlockStream::new(
+                Cursor::new(data),
+                Path::new("-"),
+                MAGIC,
+                BlockFormat::Bitcoin,
+                false,
+            );
+            stream.keep_stale();
+            while stream.next_block().unwrap().is_some() {}
+            let stale: Vec<[u8; 32]> = stream.take_stale().iter().map(|b| b.hash).collect();
+            assert_eq!(losers, stale);
+        }
+    }
+
+    #[test]
+    fn test_regtest_chain() {
+        let chain = RegtestChain::new();
+        let mut stream = BlockStream::new(
+            Cursor::new(chain.to_stream()),
+            Path::new("-"),
+            REGTEST_MAGIC,
+            BlockFormat::Bitcoin,
+            true,
+        );
+        stream.keep_stale();
+        let mut blocks = Vec::new();
+        while let Some(block) = stream.next_block().unwrap() {
+            assert_eq!(chain.blocks[block.height as usize].to_bytes(), block.raw);
+            blocks.push((block.height, block.hash));
+        }
+        assert_eq!(hashes(&chain.blocks), blocks);
+        let stale: Delivered = stream
+            .take_stale()
+            .into_iter()
+            .map(|block| (block.height, block.hash))
+            .collect();
+        let expected: Delivered = chain
+            .stale
+            .iter()
+            .map(|(height, block)| (*height, block.header.hash))
+            .collect();
+        assert_eq!(expected, stale);
+    }
+
+    #[test]
+    fn test_resynchronization() {
+        let chain = chain(5);
+        let mut data = Vec::new();
+        data.extend(frame(&chain[0]));
+        // Padding of a preallocated blk file isn't reported
+        data.extend([0u8; 100]);
+        data.extend(frame(&chain[1]));
+        let garbage_start = data.len() as u64;
+        data.extend(b"garbage");
+        // Magic with a bogus size
+        data.extend(MAGIC.to_le_bytes());
+        data.extend(u32::MAX.to_le_bytes());
+        let garbage_end = data.len() as u64;
+        data.extend(frame(&chain[2]));
+        // Block 3 is cut off, its magic is found again in front of block 4
+        let cut = frame(&chain[3]);
+        let cut_start = data.len() as u64;
+        data.extend(&cut[..cut.len() / 2]);
+        let cut_end = data.len() as u64;
+        data.extend(frame(&chain[4]));
+        // Truncated at the end
+        let tail_start = data.len() as u64;
+        data.extend(&frame(&chain[4])[..50]);
+        let tail_end = data.len() as u64;
+
+        let (blocks, mut stream) = read_all(data.clone(), false).unwrap();
+        // Block 4 doesn't connect without block 3
+        assert_eq!(hashes(&chain[..3]), blocks);
+        assert_eq!(
+            vec![
+                (garbage_start, garbage_end),
+                (cut_start, cut_end),
+                (tail_start, tail_end)
+            ],
+            stream.take_skipped()
+        );
+        assert!(stream.take_skipped().is_empty());
+
+        let err = read_all(data, true).err().unwrap();
+        match err.kind {
+            OpErrorKind::Deserialization { offset, .. } => assert_eq!(garbage_start, offset),
+            kind => panic!("{:?}", kind),
+        }
+    }
+
+    #[test]
+    fn test_orphans() {
+        let chain = chain(3);
+        // Doesn't start with the genesis block
+        let data: Vec<u8> = chain[1..].iter().flat_map(frame).collect();
+        let (blocks, _) = read_all(data, false).unwrap();
+        assert!(blocks.is_empty());
+
+        let mut data = Vec::new();
+        for _ in 0..MAX_ORPHANS + 1 {
+            data.extend(frame(&chain[1]));
+        }
+        // Duplicates of orphans count as well
+        let err = read_all(data, false).err().unwrap();
+        assert!(
+            err.to_string().contains("don't connect to the chain"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_block_files() {
+        let dir = std::env::temp_dir().join(format!("blockparser-stream-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for (name, content) in [
+            ("b-2.dat", "c"),
+            ("b-1.dat", "ab"),
+            ("b-10.dat", ""),
+            ("c.dat", "d"),
+        ] {
+            fs::write(dir.join(name), content).unwrap();
+        }
+        let path = |name: &str| String::from(dir.join(name).to_str().unwrap());
+
+        // Given order, matches sorted by name
+        let files = expand_block_files([path("c.dat").as_str(), &path("b-?.dat")]).unwrap();
+        assert_eq!(
+            vec![dir.join("c.dat"), dir.join("b-1.dat"), dir.join("b-2.dat")],
+            files
+        );
+        let files = expand_block_files([path("b-*").as_str()]).unwrap();
+        assert_eq!(3, files.len());
+        for pattern in ["missing.dat", "x-*.dat", ""] {
+            assert!(
+                expand_block_files([path(pattern).as_str()]).is_err(),
+                "{}",
+                pattern
+            );
+        }
+
+        // Read as one stream, empty files included
+        let mut content = String::new();
+        let mut paths = files;
+        paths.push(dir.join("c.dat"));
+        FileSequence::new(&paths)
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!("abcd", content);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
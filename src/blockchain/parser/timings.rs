@@ -0,0 +1,92 @@
+//! Time spent in the phases of parsing, summed up over all threads. The clock is only read once
+//! a callback asks for the timings (see `Callback::wants_timings`), until then each phase costs
+//! a relaxed load.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Parts of the work on a block, in the order they happen
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// Reading the serialized block from the blk file or stream
+    Read,
+    /// Deserializing the block and hashing its transactions
+    Deserialize,
+    /// Evaluating the output scripts, see `Callback::wants_script_evaluation`
+    Evaluate,
+    /// Passing the block to the callback, including the time the callback takes
+    Dispatch,
+}
+
+pub const PHASES: [Phase; 4] = [
+    Phase::Read,
+    Phase::Deserialize,
+    Phase::Evaluate,
+    Phase::Dispatch,
+];
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static NANOS: [AtomicU64; PHASES.len()] = [
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+    AtomicU64::new(0),
+];
+
+impl Phase {
+    pub fn name(self) -> &'static str {
+        match self {
+            Phase::Read => "read",
+            Phase::Deserialize => "deserialize",
+            Phase::Evaluate => "evaluate",
+            Phase::Dispatch => "dispatch",
+        }
+    }
+}
+
+/// Starts measuring and clears the timings of a previous pass. Measuring stays on for the rest
+/// of the process.
+pub fn enable() {
+    for nanos in &NANOS {
+        nanos.store(0, Ordering::Relaxed);
+    }
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Runs `f` and adds the time it took to the phase if enabled
+#[inline]
+pub fn measure<T>(phase: Phase, f: impl FnOnce() -> T) -> T {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return f();
+    }
+    let started = Instant::now();
+    let result = f();
+    NANOS[phase as usize].fetch_add(started.elapsed().as_nanos() as u64, Ordering::Relaxed);
+    result
+}
+
+/// Time spent in the phase since `enable()`
+pub fn spent(phase: Phase) -> Duration {
+    Duration::from_nanos(NANOS[phase as usize].load(Ordering::Relaxed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_measure() {
+        enable();
+        let value = measure(Phase::Evaluate, || {
+            thread::sleep(Duration::from_millis(5));
+            42
+        });
+        assert_eq!(42, value);
+        assert!(spent(Phase::Evaluate) >= Duration::from_millis(5));
+        assert_eq!(
+            vec!["read", "deserialize", "evaluate", "dispatch"],
+            PHASES.iter().map(|phase| phase.name()).collect::<Vec<_>>()
+        );
+    }
+}
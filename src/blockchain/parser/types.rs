@@ -2,6 +2,9 @@ use std::convert::From;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
+use crate::blockchain::proto::block::{BlockFormat, Subsidy};
+use crate::blockchain::proto::script::AddressFormat;
+use crate::blockchain::proto::target::PowAlgorithm;
 use crate::common::utils;
 use crate::errors::{OpError, OpErrorKind, OpResult};
 
@@ -13,6 +16,51 @@ pub trait Coin {
     fn version_id(&self) -> u8; // https://en.bitcoin.it/wiki/List_of_address_prefixes
     fn genesis(&self) -> [u8; 32]; // Returns genesis hash
     fn default_folder(&self) -> PathBuf; // Default working directory, for example .bitcoin
+
+    // Base58 version byte of P2SH addresses
+    fn p2sh_version(&self) -> u8 {
+        0x05
+    }
+
+    // Number of decimal places of one coin in base units (satoshis)
+    fn decimals(&self) -> u8 {
+        8
+    }
+
+    // Compact target of the genesis block, the reference for difficulty 1
+    fn genesis_bits(&self) -> u32 {
+        0x1d00ffff
+    }
+
+    // Human readable part of bech32 segwit addresses, None if the coin has no segwit
+    fn bech32_hrp(&self) -> Option<&'static str> {
+        None
+    }
+
+    // Prefix of CashAddr addresses, which replace base58 P2PKH and P2SH addresses
+    fn cashaddr_prefix(&self) -> Option<&'static str> {
+        None
+    }
+
+    // Two byte base58 prefixes of P2PKH and P2SH addresses, used instead of the version id if set
+    fn base58_prefixes(&self) -> Option<([u8; 2], [u8; 2])> {
+        None
+    }
+
+    // Serialization of headers and transactions, e.g. whether blocks can carry an AuxPoW
+    fn block_format(&self) -> BlockFormat {
+        BlockFormat::Bitcoin
+    }
+
+    // Hash function of the proof of work, checked with `--check-pow`
+    fn pow_algorithm(&self) -> PowAlgorithm {
+        PowAlgorithm::Sha256d
+    }
+
+    // Block subsidy schedule, checked by the `supplyaudit` callback
+    fn subsidy(&self) -> Subsidy {
+        Subsidy::BITCOIN
+    }
 }
 
 // Implemented blockchain types.
@@ -20,11 +68,16 @@ pub trait Coin {
 // and add the coin name to from_str() below
 pub struct Bitcoin;
 pub struct TestNet3;
+pub struct TestNet4;
+pub struct Signet;
+pub struct Regtest;
 pub struct Namecoin;
 pub struct Litecoin;
 pub struct Dogecoin;
 pub struct Myriadcoin;
 pub struct Unobtanium;
+pub struct BitcoinCash;
+pub struct Zcash;
 //pub struct Dash;
 
 impl Coin for Bitcoin {
@@ -42,6 +95,9 @@ impl Coin for Bitcoin {
             "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f",
         )
     }
+    fn bech32_hrp(&self) -> Option<&'static str> {
+        Some("bc")
+    }
     fn default_folder(&self) -> PathBuf {
         Path::new(".bitcoin").join("blocks")
     }
@@ -58,13 +114,113 @@ impl Coin for TestNet3 {
     fn version_id(&self) -> u8 {
         0x6f
     }
+    fn p2sh_version(&self) -> u8 {
+        0xc4
+    }
     fn genesis(&self) -> [u8; 32] {
         utils::hex_to_arr32_swapped(
             "000000000933ea01ad0ee984209779baaec3ced90fa3f408719526f8d77f4943",
         )
     }
+    fn bech32_hrp(&self) -> Option<&'static str> {
+        Some("tb")
+    }
     fn default_folder(&self) -> PathBuf {
-        Path::new(".bitcoin").join("testnet3")
+        Path::new(".bitcoin").join("testnet3").join("blocks")
+    }
+}
+
+/// Bitcoin testnet4 (BIP94)
+impl Coin for TestNet4 {
+    fn name(&self) -> String {
+        String::from("TestNet4")
+    }
+    fn magic(&self) -> u32 {
+        0x283f161c
+    }
+    fn version_id(&self) -> u8 {
+        0x6f
+    }
+    fn p2sh_version(&self) -> u8 {
+        0xc4
+    }
+    fn genesis(&self) -> [u8; 32] {
+        utils::hex_to_arr32_swapped(
+            "00000000da84f2bafbbc53dee25a72ae507ff4914b867c565be350b0da8bf043",
+        )
+    }
+    fn bech32_hrp(&self) -> Option<&'static str> {
+        Some("tb")
+    }
+    fn default_folder(&self) -> PathBuf {
+        Path::new(".bitcoin").join("testnet4").join("blocks")
+    }
+}
+
+/// Bitcoin signet (BIP325). Each signet has its own magic, derived from the block challenge.
+/// This is the one of the default global signet, others are set with `--signet-magic`.
+impl Coin for Signet {
+    fn name(&self) -> String {
+        String::from("Signet")
+    }
+    fn magic(&self) -> u32 {
+        0x40cf030a
+    }
+    fn version_id(&self) -> u8 {
+        0x6f
+    }
+    fn p2sh_version(&self) -> u8 {
+        0xc4
+    }
+    fn genesis(&self) -> [u8; 32] {
+        utils::hex_to_arr32_swapped(
+            "00000008819873e925422c1ff0f99f7cc9bbb232af63a077a480a3633bee1ef6",
+        )
+    }
+    fn genesis_bits(&self) -> u32 {
+        0x1e0377ae
+    }
+    fn bech32_hrp(&self) -> Option<&'static str> {
+        Some("tb")
+    }
+    fn default_folder(&self) -> PathBuf {
+        Path::new(".bitcoin").join("signet").join("blocks")
+    }
+}
+
+/// Bitcoin regtest
+impl Coin for Regtest {
+    fn name(&self) -> String {
+        String::from("Regtest")
+    }
+    fn magic(&self) -> u32 {
+        0xdab5bffa
+    }
+    fn version_id(&self) -> u8 {
+        0x6f
+    }
+    fn p2sh_version(&self) -> u8 {
+        0xc4
+    }
+    fn genesis(&self) -> [u8; 32] {
+        utils::hex_to_arr32_swapped(
+            "0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206",
+        )
+    }
+    fn genesis_bits(&self) -> u32 {
+        0x207fffff
+    }
+    fn bech32_hrp(&self) -> Option<&'static str> {
+        Some("bcrt")
+    }
+    fn default_folder(&self) -> PathBuf {
+        Path::new(".bitcoin").join("regtest").join("blocks")
+    }
+    fn subsidy(&self) -> Subsidy {
+        Subsidy::Halving {
+            initial: 5000000000,
+            interval: 150,
+        }
     }
 }
 
@@ -83,9 +239,15 @@ impl Coin for Namecoin {
             "000000000062b72c5e2ceb45fbc8587e807c155b0da735e6483dfba2f0a9c770",
         )
     }
+    fn genesis_bits(&self) -> u32 {
+        0x1c007fff
+    }
     fn default_folder(&self) -> PathBuf {
         PathBuf::from(".namecoin")
     }
+    fn block_format(&self) -> BlockFormat {
+        BlockFormat::AuxPow
+    }
 }
 
 impl Coin for Litecoin {
@@ -103,9 +265,27 @@ impl Coin for Litecoin {
             "12a765e31ffd4059bada1e25190f6e98c99d9714d334efa41a195a7e7e04bfe2",
         )
     }
+    fn genesis_bits(&self) -> u32 {
+        0x1e0ffff0
+    }
+    fn bech32_hrp(&self) -> Option<&'static str> {
+        Some("ltc")
+    }
     fn default_folder(&self) -> PathBuf {
         Path::new(".litecoin").join("blocks")
     }
+    fn block_format(&self) -> BlockFormat {
+        BlockFormat::Litecoin
+    }
+    fn pow_algorithm(&self) -> PowAlgorithm {
+        PowAlgorithm::Scrypt
+    }
+    fn subsidy(&self) -> Subsidy {
+        Subsidy::Halving {
+            initial: 5000000000,
+            interval: 840000,
+        }
+    }
 }
 
 impl Coin for Dogecoin {
@@ -123,9 +303,21 @@ impl Coin for Dogecoin {
             "1a91e3dace36e2be3bf030a65679fe821aa1d6ef92e7c9902eb318182c355691",
         )
     }
+    fn genesis_bits(&self) -> u32 {
+        0x1e0ffff0
+    }
     fn default_folder(&self) -> PathBuf {
         Path::new(".dogecoin").join("blocks")
     }
+    fn block_format(&self) -> BlockFormat {
+        BlockFormat::AuxPow
+    }
+    fn pow_algorithm(&self) -> PowAlgorithm {
+        PowAlgorithm::Scrypt
+    }
+    fn subsidy(&self) -> Subsidy {
+        Subsidy::Dogecoin
+    }
 }
 
 impl Coin for Myriadcoin {
@@ -146,6 +338,12 @@ impl Coin for Myriadcoin {
     fn default_folder(&self) -> PathBuf {
         Path::new(".myriadcoin").join("blocks")
     }
+    fn pow_algorithm(&self) -> PowAlgorithm {
+        PowAlgorithm::MultiAlgo
+    }
+    fn subsidy(&self) -> Subsidy {
+        Subsidy::Unknown
+    }
 }
 
 impl Coin for Unobtanium {
@@ -166,6 +364,70 @@ impl Coin for Unobtanium {
     fn default_folder(&self) -> PathBuf {
         Path::new(".unobtanium").join("blocks")
     }
+    fn subsidy(&self) -> Subsidy {
+        Subsidy::Unknown
+    }
+}
+
+/// Bitcoin Cash shares the history and genesis block of Bitcoin until the fork at height 478558
+impl Coin for BitcoinCash {
+    fn name(&self) -> String {
+        String::from("BitcoinCash")
+    }
+    fn magic(&self) -> u32 {
+        0xe8f3e1e3
+    }
+    fn version_id(&self) -> u8 {
+        0x00
+    }
+    fn genesis(&self) -> [u8; 32] {
+        utils::hex_to_arr32_swapped(
+            "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f",
+        )
+    }
+    fn default_folder(&self) -> PathBuf {
+        Path::new(".bitcoin").join("blocks")
+    }
+    fn cashaddr_prefix(&self) -> Option<&'static str> {
+        Some("bitcoincash")
+    }
+}
+
+/// Transparent addresses and value flows only, the shielded sections of transactions are skipped
+impl Coin for Zcash {
+    fn name(&self) -> String {
+        String::from("Zcash")
+    }
+    fn magic(&self) -> u32 {
+        0x6427e924
+    }
+    // First byte of the t1 prefix, see base58_prefixes()
+    fn version_id(&self) -> u8 {
+        0x1c
+    }
+    fn genesis(&self) -> [u8; 32] {
+        utils::hex_to_arr32_swapped(
+            "00040fe8ec8471911baa1db1266ea15dd06b4a8a5c453883c000b031973dce08",
+        )
+    }
+    fn genesis_bits(&self) -> u32 {
+        0x1f07ffff
+    }
+    fn default_folder(&self) -> PathBuf {
+        Path::new(".zcash").join("blocks")
+    }
+    fn base58_prefixes(&self) -> Option<([u8; 2], [u8; 2])> {
+        Some(([0x1c, 0xb8], [0x1c, 0xbd]))
+    }
+    fn block_format(&self) -> BlockFormat {
+        BlockFormat::Zcash
+    }
+    fn pow_algorithm(&self) -> PowAlgorithm {
+        PowAlgorithm::Equihash
+    }
+    fn subsidy(&self) -> Subsidy {
+        Subsidy::Unknown
+    }
 }
 
 /* TODO: implement X11
@@ -183,8 +445,19 @@ pub struct CoinType {
     pub name: String,
     pub magic: u32,
     pub version_id: u8,
+    pub p2sh_version: u8,
     pub genesis_hash: [u8; 32],
     pub default_folder: PathBuf,
+    pub decimals: u8,
+    pub genesis_bits: u32,
+    pub bech32_hrp: Option<&'static str>,
+    pub cashaddr_prefix: Option<&'static str>,
+    pub base58_prefixes: Option<([u8; 2], [u8; 2])>,
+    pub block_format: BlockFormat,
+    pub pow_algorithm: PowAlgorithm,
+    pub subsidy: Subsidy,
+    /// Set by `--p2pk-as-pubkey`, see `AddressFormat`
+    pub p2pk_as_pubkey: bool,
 }
 
 impl Default for CoinType {
@@ -199,23 +472,70 @@ impl<T: Coin> From<T> for CoinType {
             name: coin.name(),
             magic: coin.magic(),
             version_id: coin.version_id(),
+            p2sh_version: coin.p2sh_version(),
             genesis_hash: coin.genesis(),
             default_folder: coin.default_folder(),
+            decimals: coin.decimals(),
+            genesis_bits: coin.genesis_bits(),
+            bech32_hrp: coin.bech32_hrp(),
+            cashaddr_prefix: coin.cashaddr_prefix(),
+            base58_prefixes: coin.base58_prefixes(),
+            block_format: coin.block_format(),
+            pow_algorithm: coin.pow_algorithm(),
+            subsidy: coin.subsidy(),
+            p2pk_as_pubkey: false,
         }
     }
 }
 
+impl CoinType {
+    #[inline]
+    pub fn address_format(&self) -> AddressFormat {
+        AddressFormat {
+            version_id: self.version_id,
+            p2sh_version: self.p2sh_version,
+            bech32_hrp: self.bech32_hrp,
+            cashaddr_prefix: self.cashaddr_prefix,
+            base58_prefixes: self.base58_prefixes,
+            p2pk_as_pubkey: self.p2pk_as_pubkey,
+            evaluate_scripts: true,
+        }
+    }
+}
+
+/// Names of the builtin coins accepted by `CoinType::from_str`
+pub const COIN_NAMES: &[&str] = &[
+    "bitcoin",
+    "bitcoin-testnet3",
+    "bitcoin-testnet4",
+    "bitcoin-signet",
+    "bitcoin-regtest",
+    "namecoin",
+    "litecoin",
+    "dogecoin",
+    "myriadcoin",
+    "unobtanium",
+    "bitcoincash",
+    "zcash",
+];
+
 impl FromStr for CoinType {
     type Err = OpError;
     fn from_str(coin_name: &str) -> OpResult<Self> {
         match coin_name {
             "bitcoin" => Ok(CoinType::from(Bitcoin)),
-            "testnet3" => Ok(CoinType::from(TestNet3)),
+            // `testnet3` is kept for existing scripts
+            "bitcoin-testnet3" | "testnet3" => Ok(CoinType::from(TestNet3)),
+            "bitcoin-testnet4" => Ok(CoinType::from(TestNet4)),
+            "bitcoin-signet" => Ok(CoinType::from(Signet)),
+            "bitcoin-regtest" => Ok(CoinType::from(Regtest)),
             "namecoin" => Ok(CoinType::from(Namecoin)),
             "litecoin" => Ok(CoinType::from(Litecoin)),
             "dogecoin" => Ok(CoinType::from(Dogecoin)),
             "myriadcoin" => Ok(CoinType::from(Myriadcoin)),
             "unobtanium" => Ok(CoinType::from(Unobtanium)),
+            "bitcoincash" => Ok(CoinType::from(BitcoinCash)),
+            "zcash" => Ok(CoinType::from(Zcash)),
             n => {
                 let e = OpError::new(OpErrorKind::InvalidArgsError)
                     .join_msg(&format!("The is no impl for `{}`!", n));
@@ -224,3 +544,79 @@ impl FromStr for CoinType {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bitcoin_network_addresses() {
+        let h160 = utils::hex_to_vec("751e76e8199196d454941c45d1b3a323f1433bd6");
+        let h160: [u8; 20] = h160.try_into().unwrap();
+        let expected = [
+            (
+                "bitcoin",
+                "1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMH",
+                "3CNHUhP3uyB9EUtRLsmvFUmvGdjGdkTxJw",
+                "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4",
+            ),
+            (
+                "bitcoin-testnet3",
+                "mrCDrCybB6J1vRfbwM5hemdJz73FwDBC8r",
+                "2N3vVYSK5XRgVSGWy21PnsRmBUywSQNdCsf",
+                "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx",
+            ),
+            (
+                "bitcoin-testnet4",
+                "mrCDrCybB6J1vRfbwM5hemdJz73FwDBC8r",
+                "2N3vVYSK5XRgVSGWy21PnsRmBUywSQNdCsf",
+                "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx",
+            ),
+            (
+                "bitcoin-signet",
+                "mrCDrCybB6J1vRfbwM5hemdJz73FwDBC8r",
+                "2N3vVYSK5XRgVSGWy21PnsRmBUywSQNdCsf",
+                "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx",
+            ),
+            (
+                "bitcoin-regtest",
+                "mrCDrCybB6J1vRfbwM5hemdJz73FwDBC8r",
+                "2N3vVYSK5XRgVSGWy21PnsRmBUywSQNdCsf",
+                "bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080",
+            ),
+        ];
+        for (name, p2pkh, p2sh, p2wpkh) in expected {
+            let format = CoinType::from_str(name).unwrap().address_format();
+            assert_eq!(p2pkh, format.p2pkh(&h160), "{}", name);
+            assert_eq!(p2sh, format.p2sh(&h160), "{}", name);
+            assert_eq!(p2wpkh, format.segwit(0, &h160).unwrap(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_bitcoin_network_parameters() {
+        let magics: Vec<(u32, PathBuf)> = COIN_NAMES[..5]
+            .iter()
+            .map(|n| CoinType::from_str(n).unwrap())
+            .map(|c| (c.magic, c.default_folder))
+            .collect();
+        let bytes: Vec<String> = magics
+            .iter()
+            .map(|(magic, _)| utils::arr_to_hex(&magic.to_le_bytes()))
+            .collect();
+        assert_eq!(
+            vec!["f9beb4d9", "0b110907", "1c163f28", "0a03cf40", "fabfb5da"],
+            bytes
+        );
+        for (network, (_, folder)) in ["testnet3", "testnet4", "signet", "regtest"]
+            .iter()
+            .zip(&magics[1..])
+        {
+            assert_eq!(&Path::new(".bitcoin").join(network).join("blocks"), folder);
+        }
+        assert_eq!(
+            CoinType::from(TestNet3).genesis_hash,
+            CoinType::from_str("testnet3").unwrap().genesis_hash
+        );
+    }
+}
@@ -0,0 +1,466 @@
+//! Undo data of the blocks (rev*.dat), see `--use-undo-files`. Bitcoin Core stores the outputs
+//! spent by each block to be able to disconnect it, compressed like in the chainstate
+//! (compressor.h), so the inputs can be resolved without tracking the unspent outputs.
+
+use std::fs::File;
+use std::io::{BufReader, Cursor};
+use std::path::{Path, PathBuf};
+
+use crate::blockchain::parser::blkfile::{BlkFile, XorReader};
+use crate::blockchain::parser::index::read_varint;
+use crate::blockchain::parser::reader::BlockchainRead;
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::script::AddressFormat;
+use crate::blockchain::proto::tx::{EvaluatedTxOut, Prevout, TxOutput};
+use crate::blockchain::proto::varuint::VarUint;
+use crate::common::secp256k1::PublicKey;
+use crate::common::utils;
+use crate::errors::{OpError, OpResult};
+
+/// Sizes below are special scripts of which only the hash or key is stored (nSpecialScripts)
+const N_SPECIAL_SCRIPTS: u64 = 6;
+/// Longer scripts are unspendable and stored as OP_RETURN (MAX_SCRIPT_SIZE)
+const MAX_SCRIPT_SIZE: u64 = 10_000;
+
+/// Output spent by an input (Coin in Bitcoin Core)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpentOutput {
+    /// Height of the block which created the output
+    pub height: u64,
+    pub is_coinbase: bool,
+    pub value: u64,
+    pub script_pubkey: Vec<u8>,
+}
+
+/// Path of the rev file with the undo data of the blocks of the given blk file
+pub fn rev_path(blk_path: &Path) -> PathBuf {
+    let name = blk_path.file_name().unwrap_or_default().to_string_lossy();
+    blk_path.with_file_name(name.replacen("blk", "rev", 1))
+}
+
+/// Reads the undo data at `offset` of the rev file of `blk_file` and checks its checksum, which
+/// covers the hash of the previous block as well. Returns the spent outputs of each transaction
+/// except the coinbase, see `parse_block_undo()`.
+pub fn read_block_undo(
+    blk_file: &BlkFile,
+    offset: u64,
+    prev_hash: &[u8; 32],
+) -> OpResult<Vec<Vec<SpentOutput>>> {
+    let mut f = BufReader::new(XorReader::new(
+        File::open(rev_path(&blk_file.path))?,
+        blk_file.xor_key,
+    ));
+    // Undo data is framed by the network magic and its size like a block
+    let size = blk_file.read_block_size(&mut f, offset)?;
+    let data = f.read_u8_vec(size)?;
+    let checksum = f.read_256hash()?;
+    if utils::sha256d(&[&prev_hash[..], &data[..]].concat()) != checksum {
+        return Err(OpError::from(String::from(
+            "Checksum mismatch of the undo data",
+        )));
+    }
+    parse_block_undo(&data).map_err(|e| OpError::deserialization(e, 0))
+}
+
+/// Decodes a CBlockUndo: the outputs spent by the inputs of each transaction but the coinbase
+pub fn parse_block_undo(data: &[u8]) -> OpResult<Vec<Vec<SpentOutput>>> {
+    let mut reader = Cursor::new(data);
+    let n_txs = VarUint::read_from(&mut reader)?.value;
+    let mut txs = Vec::with_capacity(n_txs.min(data.len() as u64) as usize);
+    for _ in 0..n_txs {
+        let n_inputs = VarUint::read_from(&mut reader)?.value;
+        let mut spent = Vec::with_capacity(n_inputs.min(data.len() as u64) as usize);
+        for _ in 0..n_inputs {
+            spent.push(read_spent_output(&mut reader)?);
+        }
+        txs.push(spent);
+    }
+    Ok(txs)
+}
+
+/// Decodes an output of TxInUndoFormatter
+fn read_spent_output(reader: &mut Cursor<&[u8]>) -> OpResult<SpentOutput> {
+    let code = read_varint(reader)? as u64;
+    let height = code >> 1;
+    if height > 0 {
+        // Transaction version of the old undo format, always zero since Bitcoin Core 0.15
+        read_varint(reader)?;
+    }
+    let value = decompress_amount(read_varint(reader)? as u64);
+    Ok(SpentOutput {
+        height,
+        is_coinbase: code & 1 == 1,
+        value,
+        script_pubkey: read_script(reader)?,
+    })
+}
+
+/// Inverse of CompressAmount, which stores the exponent of trailing zeros separately
+pub fn decompress_amount(x: u64) -> u64 {
+    if x == 0 {
+        return 0;
+    }
+    let mut x = x - 1;
+    let mut e = x % 10;
+    x /= 10;
+    let mut n = if e < 9 {
+        let d = x % 9 + 1;
+        x /= 9;
+        x.wrapping_mul(10).wrapping_add(d)
+    } else {
+        x + 1
+    };
+    while e > 0 {
+        n = n.wrapping_mul(10);
+        e -= 1;
+    }
+    n
+}
+
+/// Decodes a script of ScriptCompression. P2PKH, P2SH and P2PK outputs are stored as their
+/// hash or public key, the size of other scripts is offset by the number of special scripts.
+fn read_script(reader: &mut Cursor<&[u8]>) -> OpResult<Vec<u8>> {
+    let n_size = read_varint(reader)? as u64;
+    let script = match n_size {
+        0x00 => {
+            let hash = reader.read_u8_vec(20)?;
+            [&[0x76, 0xa9, 0x14][..], &hash, &[0x88, 0xac]].concat()
+        }
+        0x01 => {
+            let hash = reader.read_u8_vec(20)?;
+            [&[0xa9, 0x14][..], &hash, &[0x87]].concat()
+        }
+        0x02 | 0x03 => {
+            let x = reader.read_u8_vec(32)?;
+            [&[0x21, n_size as u8][..], &x, &[0xac]].concat()
+        }
+        0x04 | 0x05 => {
+            // Uncompressed key, the size tells the parity of y
+            let key = [&[n_size as u8 - 2][..], &reader.read_u8_vec(32)?].concat();
+            match PublicKey::from_slice(&key) {
+                Some(key) => [&[0x41][..], &key.serialize_uncompressed(), &[0xac]].concat(),
+                // Bitcoin Core leaves the script empty as well
+                None => Vec::new(),
+            }
+        }
+        _ => {
+            let len = n_size - N_SPECIAL_SCRIPTS;
+            if len > MAX_SCRIPT_SIZE {
+                let end = reader.position() + len;
+                if end > reader.get_ref().len() as u64 {
+                    return Err(OpError::from(String::from(
+                        "Script exceeds the end of the undo data",
+                    )));
+                }
+                reader.set_position(end);
+                vec![0x6a]
+            } else {
+                reader.read_u8_vec(len as u32)?
+            }
+        }
+    };
+    Ok(script)
+}
+
+/// Sets the prevout of each input but the coinbase to the output it spends
+pub fn attach_prevouts(
+    block: &mut Block,
+    undo: Vec<Vec<SpentOutput>>,
+    address_format: AddressFormat,
+) -> OpResult<()> {
+    let n_txs = block.txs.len().saturating_sub(1);
+    if undo.len() != n_txs {
+        return Err(OpError::from(format!(
+            "Undo data of {} transactions for a block with {} transactions besides the coinbase",
+            undo.len(),
+            n_txs
+        )));
+    }
+    for (tx, spent) in block.txs.iter_mut().skip(1).zip(undo) {
+        if spent.len() != tx.value.inputs.len() {
+            return Err(OpError::from(format!(
+                "Undo data of {} outputs for transaction {} with {} inputs",
+                spent.len(),
+                utils::arr_to_hex_swapped(&tx.hash),
+                tx.value.inputs.len()
+            )));
+        }
+        for (input, spent) in tx.value.inputs.iter_mut().zip(spent) {
+            let out = TxOutput {
+                value: spent.value,
+                script_len: VarUint::compact(spent.script_pubkey.len() as u64),
+                script_pubkey: spent.script_pubkey,
+            };
+            input.prevout = Some(Box::new(Prevout {
+                height: spent.height,
+                is_coinbase: spent.is_coinbase,
+                out: EvaluatedTxOut::eval_script(out, address_format),
+            }));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+/// Inverse of `decompress_amount()`
+fn compress_amount(mut n: u64) -> u64 {
+    if n == 0 {
+        return 0;
+    }
+    let mut e = 0;
+    while n.is_multiple_of(10) && e < 9 {
+        n /= 10;
+        e += 1;
+    }
+    if e < 9 {
+        let d = n % 10;
+        n /= 10;
+        1 + (n * 9 + d - 1) * 10 + e
+    } else {
+        1 + (n - 1) * 10 + 9
+    }
+}
+
+#[cfg(test)]
+/// Appends the VARINT of Bitcoin Core, see `index::read_varint()`
+fn write_varint(bytes: &mut Vec<u8>, mut n: u64) {
+    let mut tmp = Vec::new();
+    loop {
+        tmp.push((n & 0x7f) as u8 | if tmp.is_empty() { 0x00 } else { 0x80 });
+        if n <= 0x7f {
+            break;
+        }
+        n = (n >> 7) - 1;
+    }
+    bytes.extend(tmp.iter().rev());
+}
+
+#[cfg(test)]
+/// Serializes the spent outputs as CBlockUndo, scripts are stored uncompressed
+pub fn serialize_block_undo(txs: &[Vec<SpentOutput>]) -> Vec<u8> {
+    use crate::blockchain::proto::ToRaw;
+
+    let mut bytes = VarUint::compact(txs.len() as u64).to_bytes();
+    for spent in txs {
+        bytes.extend(VarUint::compact(spent.len() as u64).to_bytes());
+        for output in spent {
+            write_varint(&mut bytes, output.height << 1 | output.is_coinbase as u64);
+            if output.height > 0 {
+                bytes.push(0);
+            }
+            write_varint(&mut bytes, compress_amount(output.value));
+            write_varint(
+                &mut bytes,
+                output.script_pubkey.len() as u64 + N_SPECIAL_SCRIPTS,
+            );
+            bytes.extend_from_slice(&output.script_pubkey);
+        }
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use super::*;
+    use crate::blockchain::proto::script::ScriptPattern;
+    use crate::callbacks::fixtures::{new_chain, new_tx, SCRIPT_A};
+
+    #[test]
+    fn test_amount() {
+        // Vectors of Bitcoin Core's compress_tests
+        let coin = 100_000_000;
+        let vectors: [(u64, u64); 6] = [
+            (0, 0x0),
+            (1, 0x1),
+            (1_000_000, 0x7),
+            (coin, 0x9),
+            (50 * coin, 0x32),
+            (21_000_000 * coin, 0x1406f40),
+        ];
+        for (amount, compressed) in vectors {
+            assert_eq!(compressed, compress_amount(amount));
+            assert_eq!(amount, decompress_amount(compressed));
+        }
+        for amount in (0..100_000).chain([123_456_789, 20_999_999_997_690_000]) {
+            assert_eq!(amount, decompress_amount(compress_amount(amount)));
+        }
+    }
+
+    #[test]
+    fn test_varint() {
+        for n in [
+            0,
+            0x7f,
+            0x80,
+            0x1234,
+            0xffff,
+            0x123456,
+            0x80123456,
+            u32::MAX as u64,
+        ] {
+            let mut bytes = Vec::new();
+            write_varint(&mut bytes, n);
+            assert_eq!(
+                n as usize,
+                read_varint(&mut Cursor::new(&bytes[..])).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_block_undo() {
+        // Block 170, the first transaction between addresses, spends the coinbase of block 9,
+        // 50 BTC to an uncompressed key: nCode 19, version 0, amount 0x32, odd y and x
+        let x = "11db93e1dcdb8a016b49840f8c53bc1eb68a382e97b1482ecad7b148a6909a5c";
+        let undo = utils::hex_to_vec(&format!("010113003205{}", x));
+        let expected = SpentOutput {
+            height: 9,
+            is_coinbase: true,
+            value: 5_000_000_000,
+            script_pubkey: utils::hex_to_vec(concat!(
+                "410411db93e1dcdb8a016b49840f8c53bc1eb68a382e97b1482ecad7b148a6909a5c",
+                "b2e0eaddfb84ccf9744464f82e160bfa9b8b64f9d4c03f999b8643f656b412a3ac"
+            )),
+        };
+        assert_eq!(vec![vec![expected]], parse_block_undo(&undo).unwrap());
+
+        // P2PKH, P2SH, a compressed P2PK of height 0 without version and a raw script
+        let hash = "62e907b15cbf27d5425399ebf6f0fb50ebb88f18";
+        let key = "1b6cdc2ee6f6fcf3a8a9ec5e1e6bb6cc550a5d725bb1037cbd6edf0d0105b4f8";
+        let undo = utils::hex_to_vec(&format!(
+            "010402000900{}02000701{}000003{}0200090769",
+            hash, hash, key
+        ));
+        let spent = parse_block_undo(&undo).unwrap();
+        let scripts: Vec<String> = spent[0]
+            .iter()
+            .map(|s| utils::arr_to_hex(&s.script_pubkey))
+            .collect();
+        assert_eq!(
+            vec![
+                format!("76a914{}88ac", hash),
+                format!("a914{}87", hash),
+                format!("2103{}ac", key),
+                String::from("69"),
+            ],
+            scripts
+        );
+        assert_eq!(
+            vec![
+                (1, false, 100_000_000),
+                (1, false, 1_000_000),
+                (0, false, 0)
+            ],
+            spent[0][..3]
+                .iter()
+                .map(|s| (s.height, s.is_coinbase, s.value))
+                .collect::<Vec<_>>()
+        );
+
+        // Oversized scripts are replaced by OP_RETURN
+        let mut undo = utils::hex_to_vec("010100");
+        write_varint(&mut undo, 0);
+        write_varint(&mut undo, MAX_SCRIPT_SIZE + 1 + N_SPECIAL_SCRIPTS);
+        undo.extend(vec![0x51; MAX_SCRIPT_SIZE as usize + 1]);
+        assert_eq!(
+            vec![0x6a],
+            parse_block_undo(&undo).unwrap()[0][0].script_pubkey
+        );
+
+        // x = 5 is not on the curve
+        let mut undo = utils::hex_to_vec("0101000004");
+        undo.extend([0u8; 31]);
+        undo.push(5);
+        assert!(parse_block_undo(&undo).unwrap()[0][0]
+            .script_pubkey
+            .is_empty());
+
+        assert!(parse_block_undo(&utils::hex_to_vec("0101000000aabb")).is_err());
+        assert!(parse_block_undo(&utils::hex_to_vec("010100009f01")).is_err());
+        // Overlong VARINT of the height, the record passed the framing
+        assert!(parse_block_undo(&utils::hex_to_vec("0101ffffffffffffffffffff7f")).is_err());
+    }
+
+    #[test]
+    fn test_read_block_undo() {
+        let dir = env::temp_dir().join(format!("blockparser-undo-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("blk00000.dat"), []).unwrap();
+        let spent = vec![vec![SpentOutput {
+            height: 3,
+            is_coinbase: false,
+            value: 1234,
+            script_pubkey: utils::hex_to_vec(SCRIPT_A),
+        }]];
+        let data = serialize_block_undo(&spent);
+        let prev_hash = [7u8; 32];
+        let mut rev_file = vec![0u8; 16];
+        rev_file.extend(0xd9b4bef9u32.to_le_bytes());
+        rev_file.extend((data.len() as u32).to_le_bytes());
+        rev_file.extend(&data);
+        rev_file.extend(utils::sha256d(&[&prev_hash[..], &data[..]].concat()));
+        let key = [3, 1, 4, 1, 5, 9, 2, 6];
+        let obfuscated: Vec<u8> = rev_file
+            .iter()
+            .enumerate()
+            .map(|(i, b)| b ^ key[i % 8])
+            .collect();
+        fs::write(dir.join("rev00000.dat"), obfuscated).unwrap();
+
+        let blk_files = BlkFile::from_path(&dir, Some(key), 0xd9b4bef9).unwrap();
+        let blk_file = &blk_files[&0];
+        assert_eq!(dir.join("rev00000.dat"), rev_path(&blk_file.path));
+        assert_eq!(spent, read_block_undo(blk_file, 24, &prev_hash).unwrap());
+        // Another previous block
+        assert!(read_block_undo(blk_file, 24, &[8u8; 32]).is_err());
+        // No magic in front
+        assert!(read_block_undo(blk_file, 16, &prev_hash).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_attach_prevouts() {
+        let coinbase = new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[50]);
+        let spend = new_tx(&[(coinbase.hash, 0), ([1u8; 32], 2)], &[30]);
+        let mut block = new_chain(vec![vec![coinbase, spend]]).remove(0);
+        let spent_output = |height| SpentOutput {
+            height,
+            is_coinbase: height == 0,
+            value: 50,
+            script_pubkey: utils::hex_to_vec(SCRIPT_A),
+        };
+        let address_format = AddressFormat::default();
+        assert!(attach_prevouts(&mut block, vec![], address_format).is_err());
+        assert!(attach_prevouts(&mut block, vec![vec![spent_output(0)]], address_format).is_err());
+
+        let undo = vec![vec![spent_output(0), spent_output(1)]];
+        attach_prevouts(&mut block, undo, address_format).unwrap();
+        assert!(block.txs[0].value.inputs[0].prevout.is_none());
+        let prevouts: Vec<&Prevout> = block.txs[1]
+            .value
+            .inputs
+            .iter()
+            .map(|input| input.prevout.as_deref().unwrap())
+            .collect();
+        assert_eq!(
+            vec![(0, true), (1, false)],
+            prevouts
+                .iter()
+                .map(|p| (p.height, p.is_coinbase))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(50, prevouts[0].out.out.value);
+        assert_eq!(
+            Some("12higDjoCCNXSA95xZMWUdPvXNmkAduhWv"),
+            prevouts[1].out.script.address.as_deref()
+        );
+        assert_eq!(
+            ScriptPattern::Pay2PublicKeyHash,
+            prevouts[1].out.script.pattern
+        );
+    }
+}
@@ -0,0 +1,178 @@
+//! Corpus of the output scripts which aren't recognised, see `--dump-unknown-scripts`. Each
+//! script is dumped once with its first occurrence, so the classification can be improved.
+
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::script::ScriptPattern;
+use crate::common::utils;
+use crate::errors::{OpError, OpResult};
+
+pub const FILE_NAME: &str = "unknown_scripts.csv";
+pub const DEFAULT_MAX_UNKNOWN: usize = 10_000;
+const HEADER: &str = "script_hash;outpoint;height;script";
+
+/// Appends the nonstandard output scripts of the parsed blocks to `unknown_scripts.csv`.
+/// The file is kept across runs: scripts dumped before are read on open and skipped, until
+/// the file holds `max` scripts.
+pub struct UnknownScripts {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    /// Hex of the SHA256 of the dumped scripts
+    seen: HashSet<String>,
+    max: usize,
+    /// Scripts dumped by this run
+    n_written: usize,
+}
+
+impl UnknownScripts {
+    pub fn open(dir: &Path, max: usize) -> OpResult<Self> {
+        fs::create_dir_all(dir).map_err(|e| {
+            OpError::from(e).join_msg(&format!("Unable to create {}.", dir.display()))
+        })?;
+        let path = dir.join(FILE_NAME);
+        let mut seen = HashSet::new();
+        if path.exists() {
+            for line in BufReader::new(File::open(&path)?).lines().skip(1) {
+                if let Some(hash) = line?.split(';').next() {
+                    seen.insert(String::from(hash));
+                }
+            }
+        }
+        let is_new = seen.is_empty() && fs::metadata(&path).map_or(true, |m| m.len() == 0);
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|e| {
+                OpError::from(e).join_msg(&format!("Unable to open {}.", path.display()))
+            })?;
+        let mut writer = BufWriter::new(file);
+        if is_new {
+            writeln!(writer, "{}", HEADER)?;
+        }
+        if seen.len() >= max {
+            warn!(target: "parser", "{} already holds {} scripts, no unknown scripts are added (--max-unknown {}).",
+                  path.display(), seen.len(), max);
+        }
+        Ok(UnknownScripts {
+            path,
+            writer,
+            seen,
+            max,
+            n_written: 0,
+        })
+    }
+
+    /// Dumps the output scripts of the block which weren't recognised and weren't seen before
+    pub fn add_block(&mut self, block: &Block, block_height: u64) -> OpResult<()> {
+        for tx in &block.txs {
+            for (index, output) in tx.value.outputs.iter().enumerate() {
+                if output.script.pattern != ScriptPattern::NotRecognised
+                    || self.seen.len() >= self.max
+                {
+                    continue;
+                }
+                let script = &output.out.script_pubkey;
+                let hash = utils::arr_to_hex(&utils::sha256(script));
+                if self.seen.contains(&hash) {
+                    continue;
+                }
+                writeln!(
+                    self.writer,
+                    "{};{}:{};{};{}",
+                    hash,
+                    utils::arr_to_hex_swapped(&tx.hash),
+                    index,
+                    block_height,
+                    utils::arr_to_hex(script)
+                )?;
+                self.seen.insert(hash);
+                self.n_written += 1;
+                if self.seen.len() == self.max {
+                    info!(target: "parser", "Dumped {} unknown scripts, further ones are skipped (--max-unknown).", self.max);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Flushes the file and logs the number of dumped scripts
+    pub fn finish(&mut self) -> OpResult<()> {
+        self.writer.flush()?;
+        info!(target: "parser", "Dumped {} new unknown scripts to {} ({} in total).",
+              self.n_written, self.path.display(), self.seen.len());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::callbacks::fixtures::{new_block, new_tx_to, SCRIPT_A};
+    use std::env;
+    use std::process;
+
+    // OP_1 OP_DROP and OP_2 OP_DROP aren't standard
+    const UNKNOWN_A: &str = "5175";
+    const UNKNOWN_B: &str = "5275";
+
+    fn dump(dir: &Path, max: usize, blocks: &[Block]) -> UnknownScripts {
+        let mut unknown = UnknownScripts::open(dir, max).unwrap();
+        for (height, block) in blocks.iter().enumerate() {
+            unknown.add_block(block, height as u64).unwrap();
+        }
+        unknown.finish().unwrap();
+        unknown
+    }
+
+    fn rows(dir: &Path) -> Vec<String> {
+        fs::read_to_string(dir.join(FILE_NAME))
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect()
+    }
+
+    #[test]
+    fn test_unknown_scripts() {
+        let dir = env::temp_dir().join(format!("blockparser-unknown-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let tx = new_tx_to(
+            &[([1; 32], 0)],
+            &[(1, UNKNOWN_A), (2, SCRIPT_A), (3, UNKNOWN_A)],
+        );
+        let txid = utils::arr_to_hex_swapped(&tx.hash);
+        let blocks = vec![
+            new_block(vec![tx]),
+            new_block(vec![new_tx_to(&[([2; 32], 0)], &[(4, UNKNOWN_B)])]),
+        ];
+        assert_eq!(
+            ScriptPattern::NotRecognised,
+            blocks[0].txs[0].value.outputs[0].script.pattern
+        );
+
+        // Capped at one script, the duplicate within the transaction is skipped
+        let unknown = dump(&dir, 1, &blocks);
+        assert_eq!(1, unknown.n_written);
+        let hash_a = utils::arr_to_hex(&utils::sha256(&utils::hex_to_vec(UNKNOWN_A)));
+        assert_eq!(
+            vec![
+                String::from(HEADER),
+                format!("{};{}:0;0;5175", hash_a, txid)
+            ],
+            rows(&dir)
+        );
+
+        // A later run appends the scripts it didn't see before
+        let unknown = dump(&dir, 10, &blocks);
+        assert_eq!(1, unknown.n_written);
+        let rows = rows(&dir);
+        assert_eq!(3, rows.len());
+        assert!(rows[2].ends_with(";1;5275"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
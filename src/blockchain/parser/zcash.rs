@@ -0,0 +1,623 @@
+//! Zcash blocks: Equihash headers and transactions up to v5 (NU5).
+//! Only the transparent part of a transaction is kept, the shielded sections are skipped,
+//! see https://zips.z.cash/protocol/protocol.pdf#txnencoding
+
+use std::io::{self, Read};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+
+use crate::blockchain::parser::reader::BlockchainRead;
+use crate::blockchain::proto::header::{BlockHeader, EquihashHeader};
+use crate::blockchain::proto::script::AddressFormat;
+use crate::blockchain::proto::tx::{RawTx, TxInput, TxOutput, ZcashTx};
+use crate::blockchain::proto::varuint::VarUint;
+use crate::blockchain::proto::ToRaw;
+use crate::common::blake2b::blake2b_256;
+use crate::common::utils;
+use crate::errors::{OpError, OpResult};
+
+/// Set in the header of transactions from Overwinter (v3) on
+const OVERWINTERED: u32 = 1 << 31;
+
+const OVERWINTER_VERSION_GROUP_ID: u32 = 0x03c48270;
+const SAPLING_VERSION_GROUP_ID: u32 = 0x892f2085;
+const NU5_VERSION_GROUP_ID: u32 = 0x26a7270a;
+
+/// Sizes of the shielded descriptions in bytes
+const JOINSPLIT_PHGR_SIZE: usize = 1802;
+const JOINSPLIT_GROTH_SIZE: usize = 1698;
+const SAPLING_SPEND_V4_SIZE: usize = 384;
+const SAPLING_OUTPUT_V4_SIZE: usize = 948;
+const SAPLING_SPEND_V5_SIZE: usize = 96;
+const SAPLING_OUTPUT_V5_SIZE: usize = 756;
+const ORCHARD_ACTION_SIZE: usize = 820;
+const ENC_CIPHERTEXT_SIZE: usize = 580;
+
+/// Reading of Zcash blocks, see `BlockFormat::Zcash`
+pub trait ZcashRead: BlockchainRead {
+    /// Header with the block commitments and the Equihash nonce and solution.
+    /// The u32 nonce of the header holds the first 4 bytes of the nonce.
+    fn read_equihash_header(&mut self) -> OpResult<BlockHeader> {
+        let version = self.read_u32::<LittleEndian>()?;
+        let prev_hash = self.read_256hash()?;
+        let merkle_root = self.read_256hash()?;
+        let commitments = self.read_256hash()?;
+        let timestamp = self.read_u32::<LittleEndian>()?;
+        let bits = self.read_u32::<LittleEndian>()?;
+        let nonce = self.read_256hash()?;
+        let solution_len = VarUint::read_from(self)?;
+        let solution = self.read_u8_vec(solution_len.value as u32)?;
+
+        let mut header = BlockHeader::new(
+            version,
+            prev_hash,
+            merkle_root,
+            timestamp,
+            bits,
+            u32::from_le_bytes([nonce[0], nonce[1], nonce[2], nonce[3]]),
+        );
+        header.equihash = Some(Box::new(EquihashHeader {
+            commitments,
+            nonce,
+            solution,
+        }));
+        Ok(header)
+    }
+
+    fn read_zcash_txs(
+        &mut self,
+        tx_count: u64,
+        address_format: AddressFormat,
+    ) -> OpResult<Vec<RawTx>> {
+        let mut txs = Vec::with_capacity((tx_count as usize).min(1 << 16));
+        for _ in 0..tx_count {
+            txs.push(self.read_zcash_tx(address_format)?);
+        }
+        Ok(txs)
+    }
+
+    fn read_zcash_tx(&mut self, address_format: AddressFormat) -> OpResult<RawTx> {
+        let mut reader = Recorder {
+            inner: self,
+            bytes: Vec::new(),
+        };
+        let header = reader.read_u32::<LittleEndian>()?;
+        let version = header & !OVERWINTERED;
+        let overwintered = header & OVERWINTERED > 0;
+        let version_group_id = if overwintered {
+            reader.read_u32::<LittleEndian>()?
+        } else {
+            0
+        };
+        let expected_group_id = match version {
+            1 | 2 if !overwintered => None,
+            3 => Some(OVERWINTER_VERSION_GROUP_ID),
+            4 => Some(SAPLING_VERSION_GROUP_ID),
+            5 => Some(NU5_VERSION_GROUP_ID),
+            _ => {
+                return Err(OpError::from(format!(
+                    "Unsupported Zcash transaction version {:#010x}",
+                    header
+                )))
+            }
+        };
+        if overwintered && expected_group_id != Some(version_group_id) {
+            return Err(OpError::from(format!(
+                "Invalid version group id {:#010x} of a v{} transaction",
+                version_group_id, version
+            )));
+        }
+        let mut tx = if version == 5 {
+            reader.read_v5_body(header, version_group_id, address_format)?
+        } else {
+            reader.read_legacy_body(version, version_group_id, address_format)?
+        };
+        // Zcash transactions have no witness data
+        tx.size = reader.bytes.len() as u32;
+        tx.stripped_size = tx.size;
+        if let Some(zcash) = tx.zcash.as_mut() {
+            zcash.raw = reader.bytes;
+            if version < 5 {
+                zcash.txid = utils::sha256d(&zcash.raw);
+            }
+        }
+        Ok(tx)
+    }
+}
+
+/// All types that implement `Read` get methods defined in `ZcashRead` for free.
+impl<R: io::Read + ?Sized> ZcashRead for R {}
+
+/// Keeps a copy of everything read, the raw transaction for its txid
+struct Recorder<'a, R: Read + ?Sized> {
+    inner: &'a mut R,
+    bytes: Vec<u8>,
+}
+
+impl<R: Read + ?Sized> Read for Recorder<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.bytes.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+}
+
+impl<R: Read + ?Sized> Recorder<'_, R> {
+    /// Transactions v1 to v4, which share the Bitcoin layout up to the lock time
+    fn read_legacy_body(
+        &mut self,
+        version: u32,
+        version_group_id: u32,
+        address_format: AddressFormat,
+    ) -> OpResult<RawTx> {
+        let (in_count, inputs, out_count, outputs) = self.read_transparent()?;
+        let locktime = self.read_u32::<LittleEndian>()?;
+        let expiry_height = if version >= 3 {
+            self.read_u32::<LittleEndian>()?
+        } else {
+            0
+        };
+
+        let mut delta = 0i64;
+        let mut n_sapling = 0;
+        if version >= 4 {
+            delta = self.read_i64::<LittleEndian>()?;
+            let n_spends = VarUint::read_from(self)?.value;
+            self.skip(n_spends, SAPLING_SPEND_V4_SIZE)?;
+            let n_outputs = VarUint::read_from(self)?.value;
+            self.skip(n_outputs, SAPLING_OUTPUT_V4_SIZE)?;
+            n_sapling = n_spends + n_outputs;
+        }
+        if version >= 2 {
+            let n_joinsplits = VarUint::read_from(self)?.value;
+            let joinsplit_size = if version >= 4 {
+                JOINSPLIT_GROTH_SIZE
+            } else {
+                JOINSPLIT_PHGR_SIZE
+            };
+            for _ in 0..n_joinsplits {
+                let vpub_old = self.read_u64::<LittleEndian>()? as i64;
+                let vpub_new = self.read_u64::<LittleEndian>()? as i64;
+                delta = delta.saturating_add(vpub_new).saturating_sub(vpub_old);
+                self.skip(1, joinsplit_size - 16)?;
+            }
+            if n_joinsplits > 0 {
+                // joinSplitPubKey and joinSplitSig
+                self.skip(1, 32 + 64)?;
+            }
+        }
+        if n_sapling > 0 {
+            // bindingSigSapling
+            self.skip(1, 64)?;
+        }
+
+        Ok(RawTx {
+            version,
+            in_count,
+            inputs,
+            out_count,
+            outputs,
+            witnesses: Vec::new(),
+            locktime,
+            address_format,
+            hogex: false,
+            size: 0,
+            stripped_size: 0,
+            zcash: Some(Box::new(ZcashTx {
+                version_group_id,
+                consensus_branch_id: 0,
+                expiry_height,
+                shielded_value_delta: delta,
+                txid: [0u8; 32],
+                raw: Vec::new(),
+            })),
+        })
+    }
+
+    /// Transactions v5 (ZIP 225), the txid is computed from the parsed fields (ZIP 244)
+    fn read_v5_body(
+        &mut self,
+        header: u32,
+        version_group_id: u32,
+        address_format: AddressFormat,
+    ) -> OpResult<RawTx> {
+        let consensus_branch_id = self.read_u32::<LittleEndian>()?;
+        let locktime = self.read_u32::<LittleEndian>()?;
+        let expiry_height = self.read_u32::<LittleEndian>()?;
+        let (in_count, inputs, out_count, outputs) = self.read_transparent()?;
+
+        // Sapling
+        let n_spends = VarUint::read_from(self)?.value;
+        let spends = self.read_descriptions(n_spends, SAPLING_SPEND_V5_SIZE)?;
+        let n_outputs = VarUint::read_from(self)?.value;
+        let sapling_outputs = self.read_descriptions(n_outputs, SAPLING_OUTPUT_V5_SIZE)?;
+        let mut sapling_value = 0i64;
+        let mut sapling_anchor = [0u8; 32];
+        if n_spends + n_outputs > 0 {
+            sapling_value = self.read_i64::<LittleEndian>()?;
+        }
+        if n_spends > 0 {
+            sapling_anchor = self.read_256hash()?;
+        }
+        // Spend proofs and signatures, output proofs
+        self.skip(n_spends, 192 + 64)?;
+        self.skip(n_outputs, 192)?;
+        if n_spends + n_outputs > 0 {
+            // bindingSigSapling
+            self.skip(1, 64)?;
+        }
+
+        // Orchard
+        let n_actions = VarUint::read_from(self)?.value;
+        let actions = self.read_descriptions(n_actions, ORCHARD_ACTION_SIZE)?;
+        let mut orchard_flags = 0u8;
+        let mut orchard_value = 0i64;
+        let mut orchard_anchor = [0u8; 32];
+        if n_actions > 0 {
+            orchard_flags = self.read_u8()?;
+            orchard_value = self.read_i64::<LittleEndian>()?;
+            orchard_anchor = self.read_256hash()?;
+            let proof_len = VarUint::read_from(self)?.value;
+            self.skip(1, proof_len as usize)?;
+            // Spend auth signatures and bindingSigOrchard
+            self.skip(n_actions, 64)?;
+            self.skip(1, 64)?;
+        }
+
+        let header_digest = blake2b_256(
+            b"ZTxIdHeadersHash",
+            &[
+                &header.to_le_bytes(),
+                &version_group_id.to_le_bytes(),
+                &consensus_branch_id.to_le_bytes(),
+                &locktime.to_le_bytes(),
+                &expiry_height.to_le_bytes(),
+            ],
+        );
+        let sapling_digest =
+            sapling_digest(&spends, &sapling_outputs, &sapling_anchor, sapling_value);
+        let orchard_digest =
+            orchard_digest(&actions, orchard_flags, orchard_value, &orchard_anchor);
+        let mut personal = *b"ZcashTxHash_\0\0\0\0";
+        personal[12..].copy_from_slice(&consensus_branch_id.to_le_bytes());
+        let txid = blake2b_256(
+            &personal,
+            &[
+                &header_digest,
+                &transparent_digest(&inputs, &outputs),
+                &sapling_digest,
+                &orchard_digest,
+            ],
+        );
+
+        Ok(RawTx {
+            version: 5,
+            in_count,
+            inputs,
+            out_count,
+            outputs,
+            witnesses: Vec::new(),
+            locktime,
+            address_format,
+            hogex: false,
+            size: 0,
+            stripped_size: 0,
+            zcash: Some(Box::new(ZcashTx {
+                version_group_id,
+                consensus_branch_id,
+                expiry_height,
+                shielded_value_delta: sapling_value.saturating_add(orchard_value),
+                txid,
+                raw: Vec::new(),
+            })),
+        })
+    }
+
+    #[inline]
+    fn read_transparent(&mut self) -> OpResult<(VarUint, Vec<TxInput>, VarUint, Vec<TxOutput>)> {
+        let in_count = VarUint::read_from(self)?;
+        let inputs = self.read_tx_inputs(in_count.value)?;
+        let out_count = VarUint::read_from(self)?;
+        let outputs = self.read_tx_outputs(out_count.value)?;
+        Ok((in_count, inputs, out_count, outputs))
+    }
+
+    /// Reads `count` descriptions of `size` bytes each into one buffer
+    #[inline]
+    fn read_descriptions(&mut self, count: u64, size: usize) -> OpResult<Vec<u8>> {
+        let len = checked_len(count, size)?;
+        self.read_u8_vec(len)
+    }
+
+    /// Skips `count` fields of `size` bytes each, they are still recorded
+    #[inline]
+    fn skip(&mut self, count: u64, size: usize) -> OpResult<()> {
+        let len = checked_len(count, size)?;
+        self.read_u8_vec(len)?;
+        Ok(())
+    }
+}
+
+#[inline]
+fn checked_len(count: u64, size: usize) -> OpResult<u32> {
+    count
+        .checked_mul(size as u64)
+        .and_then(|len| u32::try_from(len).ok())
+        .ok_or_else(|| OpError::from(format!("Too many shielded fields: {}", count)))
+}
+
+fn transparent_digest(inputs: &[TxInput], outputs: &[TxOutput]) -> [u8; 32] {
+    if inputs.is_empty() && outputs.is_empty() {
+        return blake2b_256(b"ZTxIdTranspaHash", &[]);
+    }
+    let prevouts: Vec<u8> = inputs.iter().flat_map(|i| i.outpoint.to_bytes()).collect();
+    let sequences: Vec<u8> = inputs.iter().flat_map(|i| i.seq_no.to_le_bytes()).collect();
+    let outputs: Vec<u8> = outputs.iter().flat_map(|o| o.to_bytes()).collect();
+    blake2b_256(
+        b"ZTxIdTranspaHash",
+        &[
+            &blake2b_256(b"ZTxIdPrevoutHash", &[&prevouts]),
+            &blake2b_256(b"ZTxIdSequencHash", &[&sequences]),
+            &blake2b_256(b"ZTxIdOutputsHash", &[&outputs]),
+        ],
+    )
+}
+
+/// Spends are cv, nullifier and rk, outputs are cv, cmu, ephemeralKey, encCiphertext and outCiphertext
+fn sapling_digest(
+    spends: &[u8],
+    outputs: &[u8],
+    anchor: &[u8; 32],
+    value_balance: i64,
+) -> [u8; 32] {
+    if spends.is_empty() && outputs.is_empty() {
+        return blake2b_256(b"ZTxIdSaplingHash", &[]);
+    }
+    let spends_digest = if spends.is_empty() {
+        blake2b_256(b"ZTxIdSSpendsHash", &[])
+    } else {
+        let mut compact = Vec::new();
+        let mut noncompact = Vec::new();
+        for spend in spends.chunks(SAPLING_SPEND_V5_SIZE) {
+            compact.extend_from_slice(&spend[32..64]);
+            noncompact.extend_from_slice(&spend[..32]);
+            noncompact.extend_from_slice(anchor);
+            noncompact.extend_from_slice(&spend[64..]);
+        }
+        blake2b_256(
+            b"ZTxIdSSpendsHash",
+            &[
+                &blake2b_256(b"ZTxIdSSpendCHash", &[&compact]),
+                &blake2b_256(b"ZTxIdSSpendNHash", &[&noncompact]),
+            ],
+        )
+    };
+    let outputs_digest = if outputs.is_empty() {
+        blake2b_256(b"ZTxIdSOutputHash", &[])
+    } else {
+        let (mut compact, mut memos, mut noncompact) = (Vec::new(), Vec::new(), Vec::new());
+        for output in outputs.chunks(SAPLING_OUTPUT_V5_SIZE) {
+            let (cv, rest) = output.split_at(32);
+            let (cmu_epk, rest) = rest.split_at(64);
+            let (enc, out) = rest.split_at(ENC_CIPHERTEXT_SIZE);
+            compact.extend_from_slice(cmu_epk);
+            compact.extend_from_slice(&enc[..52]);
+            memos.extend_from_slice(&enc[52..564]);
+            noncompact.extend_from_slice(cv);
+            noncompact.extend_from_slice(&enc[564..]);
+            noncompact.extend_from_slice(out);
+        }
+        blake2b_256(
+            b"ZTxIdSOutputHash",
+            &[
+                &blake2b_256(b"ZTxIdSOutC__Hash", &[&compact]),
+                &blake2b_256(b"ZTxIdSOutM__Hash", &[&memos]),
+                &blake2b_256(b"ZTxIdSOutN__Hash", &[&noncompact]),
+            ],
+        )
+    };
+    blake2b_256(
+        b"ZTxIdSaplingHash",
+        &[
+            &spends_digest,
+            &outputs_digest,
+            &value_balance.to_le_bytes(),
+        ],
+    )
+}
+
+/// Actions are cv, nullifier, rk, cmx, ephemeralKey, encCiphertext and outCiphertext
+fn orchard_digest(actions: &[u8], flags: u8, value_balance: i64, anchor: &[u8; 32]) -> [u8; 32] {
+    if actions.is_empty() {
+        return blake2b_256(b"ZTxIdOrchardHash", &[]);
+    }
+    let (mut compact, mut memos, mut noncompact) = (Vec::new(), Vec::new(), Vec::new());
+    for action in actions.chunks(ORCHARD_ACTION_SIZE) {
+        let (cv, rest) = action.split_at(32);
+        let (nullifier, rest) = rest.split_at(32);
+        let (rk, rest) = rest.split_at(32);
+        let (cmx_epk, rest) = rest.split_at(64);
+        let (enc, out) = rest.split_at(ENC_CIPHERTEXT_SIZE);
+        compact.extend_from_slice(nullifier);
+        compact.extend_from_slice(cmx_epk);
+        compact.extend_from_slice(&enc[..52]);
+        memos.extend_from_slice(&enc[52..564]);
+        noncompact.extend_from_slice(cv);
+        noncompact.extend_from_slice(rk);
+        noncompact.extend_from_slice(&enc[564..]);
+        noncompact.extend_from_slice(out);
+    }
+    blake2b_256(
+        b"ZTxIdOrchardHash",
+        &[
+            &blake2b_256(b"ZTxIdOrcActCHash", &[&compact]),
+            &blake2b_256(b"ZTxIdOrcActMHash", &[&memos]),
+            &blake2b_256(b"ZTxIdOrcActNHash", &[&noncompact]),
+            &[flags],
+            &value_balance.to_le_bytes(),
+            anchor,
+        ],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::blockchain::parser::types::{CoinType, Zcash};
+    use crate::blockchain::proto::block::BlockFormat;
+    use crate::blockchain::proto::tx::EvaluatedTx;
+    use crate::blockchain::proto::Hashed;
+
+    // Synthetic transactions with random shielded data, the txids were computed independently
+    const V4_SAPLING_TX: &str = include_str!("testdata/zcash_v4_sapling.hex");
+    const V5_ORCHARD_TX: &str = include_str!("testdata/zcash_v5_orchard.hex");
+
+    fn read_tx(raw: &[u8]) -> Hashed<EvaluatedTx> {
+        let mut reader = Cursor::new(raw);
+        let tx = reader
+            .read_zcash_tx(CoinType::from(Zcash).address_format())
+            .unwrap();
+        assert_eq!(raw.len() as u64, reader.position());
+        let txid = tx.zcash.as_ref().unwrap().txid;
+        Hashed::from(txid, EvaluatedTx::from(tx))
+    }
+
+    #[test]
+    fn test_zcash_v4_sapling_tx() {
+        // 1 transparent input and output, 1 Sapling spend, 2 Sapling outputs, valueBalance 10000
+        let raw = utils::hex_to_vec(V4_SAPLING_TX.trim());
+        let tx = read_tx(&raw);
+        assert_eq!(
+            "38f153cc3dcd4fc31b1d1827dab228f6c1809afbbbe34ef465aded7b91c5f706",
+            utils::arr_to_hex_swapped(&tx.hash)
+        );
+        assert_eq!(4, tx.value.version);
+        assert_eq!(1234567, tx.value.locktime);
+        assert_eq!(1, tx.value.inputs.len());
+        assert_eq!(0xfffffffe, tx.value.inputs[0].seq_no);
+        assert_eq!(1, tx.value.inputs[0].outpoint.index);
+        assert_eq!(1, tx.value.outputs.len());
+        assert_eq!(25000000, tx.value.outputs[0].out.value);
+        assert_eq!(
+            Some(String::from("t1VJ7gyeg4s7YrpqXhocdfrwyKgsZbHFEh9")),
+            tx.value.outputs[0].script.address
+        );
+        assert_eq!(10000, tx.value.shielded_value_delta());
+
+        let zcash = tx.value.zcash.as_ref().unwrap();
+        assert_eq!(SAPLING_VERSION_GROUP_ID, zcash.version_group_id);
+        assert_eq!(1234607, zcash.expiry_height);
+        assert!(!tx.value.has_witness());
+        assert_eq!(raw, tx.value.to_bytes());
+        assert_eq!(raw.len() as u64 * 4, tx.value.weight());
+        assert_eq!(raw.len() as u32, tx.value.size);
+    }
+
+    #[test]
+    fn test_zcash_v5_orchard_tx() {
+        // 1 transparent input and output, 1 Sapling output with valueBalance -30000,
+        // 2 Orchard actions with valueBalance 150000
+        let raw = utils::hex_to_vec(V5_ORCHARD_TX.trim());
+        let tx = read_tx(&raw);
+        // ZIP 244, not the hash of the serialization
+        assert_eq!(
+            "3c88ba5b038d7a324cc76c19f352ce2fd0e6e1670f6ebd2e5dccab4bf5c4cf3b",
+            utils::arr_to_hex_swapped(&tx.hash)
+        );
+        assert_ne!(utils::sha256(&utils::sha256(&raw)), tx.hash);
+        assert_eq!(5, tx.value.version);
+        assert_eq!(0, tx.value.locktime);
+        assert_eq!(1, tx.value.inputs.len());
+        assert_eq!(100000, tx.value.outputs[0].out.value);
+        assert_eq!(
+            Some(String::from("t3Nt3GDV2zdXHFMfa2RKos1saeRt6cgJjhV")),
+            tx.value.outputs[0].script.address
+        );
+        assert_eq!(120000, tx.value.shielded_value_delta());
+
+        let zcash = tx.value.zcash.as_ref().unwrap();
+        assert_eq!(NU5_VERSION_GROUP_ID, zcash.version_group_id);
+        assert_eq!(0xc2d6d0b4, zcash.consensus_branch_id);
+        assert_eq!(2000040, zcash.expiry_height);
+        assert_eq!(raw, tx.value.to_bytes());
+    }
+
+    #[test]
+    fn test_zcash_block() {
+        let v4 = utils::hex_to_vec(V4_SAPLING_TX.trim());
+        let v5 = utils::hex_to_vec(V5_ORCHARD_TX.trim());
+        let solution: Vec<u8> = (0..1344).map(|i| i as u8).collect();
+        let mut raw = Vec::new();
+        raw.extend_from_slice(&4u32.to_le_bytes());
+        raw.extend_from_slice(&[1u8; 32]);
+        raw.extend_from_slice(&[2u8; 32]);
+        raw.extend_from_slice(&[3u8; 32]);
+        raw.extend_from_slice(&1712345678u32.to_le_bytes());
+        raw.extend_from_slice(&0x1c01af5du32.to_le_bytes());
+        raw.extend_from_slice(&[0x78, 0x56, 0x34, 0x12]);
+        raw.extend_from_slice(&[4u8; 28]);
+        raw.extend_from_slice(&[0xfd, 0x40, 0x05]);
+        raw.extend_from_slice(&solution);
+        let header_len = raw.len();
+        raw.push(2);
+        raw.extend_from_slice(&v4);
+        raw.extend_from_slice(&v5);
+
+        let block = Cursor::new(&raw)
+            .read_block(
+                raw.len() as u32,
+                CoinType::from(Zcash).address_format(),
+                BlockFormat::Zcash,
+            )
+            .unwrap();
+        let header = &block.header.value;
+        assert_eq!([2u8; 32], header.merkle_root);
+        assert_eq!(1712345678, header.timestamp);
+        assert_eq!(0x12345678, header.nonce);
+        let equihash = header.equihash.as_ref().unwrap();
+        assert_eq!([3u8; 32], equihash.commitments);
+        assert_eq!(solution, equihash.solution);
+        assert_eq!(raw[..header_len], header.to_bytes());
+        assert_eq!(
+            utils::sha256(&utils::sha256(&raw[..header_len])),
+            block.header.hash
+        );
+
+        // Both transactions are read completely, the second one starts after the first
+        assert_eq!(2, block.txs.len());
+        assert_eq!(4, block.txs[0].value.version);
+        assert_eq!(5, block.txs[1].value.version);
+        assert_eq!(raw, block.to_bytes());
+
+        // Without transactions
+        let block = Cursor::new(&raw)
+            .read_block_without_txs(raw.len() as u32, BlockFormat::Zcash)
+            .unwrap();
+        assert_eq!(2, block.tx_count.value);
+        assert!(block.txs.is_empty());
+    }
+
+    #[test]
+    fn test_zcash_truncated_and_unknown() {
+        let raw = utils::hex_to_vec(V5_ORCHARD_TX.trim());
+        for len in [3, 50, raw.len() - 2000, raw.len() - 1] {
+            assert!(Cursor::new(&raw[..len])
+                .read_zcash_tx(AddressFormat::default())
+                .is_err());
+        }
+        // v5 header with the Sapling version group id
+        let mut invalid = raw.clone();
+        invalid[4..8].copy_from_slice(&SAPLING_VERSION_GROUP_ID.to_le_bytes());
+        assert!(Cursor::new(&invalid)
+            .read_zcash_tx(AddressFormat::default())
+            .is_err());
+        // Overwintered v6
+        let mut invalid = raw;
+        invalid[0] = 6;
+        assert!(Cursor::new(&invalid)
+            .read_zcash_tx(AddressFormat::default())
+            .is_err());
+    }
+}
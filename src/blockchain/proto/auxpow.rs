@@ -0,0 +1,183 @@
+use std::fmt;
+
+use crate::blockchain::proto::header::BlockHeader;
+use crate::blockchain::proto::tx::EvaluatedTx;
+use crate::blockchain::proto::varuint::VarUint;
+use crate::blockchain::proto::{Hashed, ToRaw};
+use crate::common::utils;
+
+/// Magic in front of the chain merkle root in the parent coinbase
+const MERGED_MINING_HEADER: [u8; 4] = [0xfa, 0xbe, 0x6d, 0x6d];
+
+/// Merkle path from a leaf to the root, see `root()`
+#[derive(Clone, Default)]
+pub struct MerkleBranch {
+    pub hashes: Vec<[u8; 32]>,
+    /// Bit i is set if the leaf side hash is the right one at level i, i.e. the index of the leaf
+    pub side_mask: u32,
+}
+
+impl MerkleBranch {
+    /// Computes the root of `leaf` following the branch
+    pub fn root(&self, leaf: [u8; 32]) -> [u8; 32] {
+        let mut hash = leaf;
+        for (i, sibling) in self.hashes.iter().enumerate() {
+            let mut pair = Vec::with_capacity(64);
+            if self.side_mask >> i & 1 == 1 {
+                pair.extend_from_slice(sibling);
+                pair.extend_from_slice(&hash);
+            } else {
+                pair.extend_from_slice(&hash);
+                pair.extend_from_slice(sibling);
+            }
+            hash = utils::sha256d(&pair);
+        }
+        hash
+    }
+}
+
+impl ToRaw for MerkleBranch {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = VarUint::compact(self.hashes.len() as u64).to_bytes();
+        for hash in &self.hashes {
+            bytes.extend_from_slice(hash);
+        }
+        bytes.extend_from_slice(&self.side_mask.to_le_bytes());
+        bytes
+    }
+}
+
+impl fmt::Debug for MerkleBranch {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("MerkleBranch")
+            .field("hashes", &self.hashes.len())
+            .field("side_mask", &self.side_mask)
+            .finish()
+    }
+}
+
+/// Auxiliary proof of work of merge mined blocks (Namecoin, Dogecoin).
+/// The work is done on the header of a parent block, whose coinbase commits to this block.
+/// Follows the block header if its version has `BlockHeader::VERSION_AUXPOW` set.
+pub struct AuxPow {
+    pub coinbase_tx: Hashed<EvaluatedTx>,
+    /// Legacy field, not validated by the coins and often zero
+    pub hash_block: [u8; 32],
+    /// Links the coinbase to the merkle root of the parent header
+    pub coinbase_branch: MerkleBranch,
+    /// Links this block to the chain merkle root in the coinbase, the side mask is the chain index
+    pub blockchain_branch: MerkleBranch,
+    pub parent_header: Hashed<BlockHeader>,
+}
+
+impl AuxPow {
+    #[inline]
+    pub fn chain_index(&self) -> u32 {
+        self.blockchain_branch.side_mask
+    }
+
+    /// Checks that the parent coinbase commits to `block_hash` and is part of the parent block.
+    /// Returns the reason if it doesn't.
+    pub fn check(&self, block_hash: &[u8; 32]) -> Result<(), String> {
+        if !self.coinbase_tx.value.is_coinbase() {
+            return Err(String::from("Parent transaction is no coinbase"));
+        }
+        let merkle_root = self.coinbase_branch.root(self.coinbase_tx.hash);
+        if merkle_root != self.parent_header.value.merkle_root {
+            return Err(format!(
+                "Coinbase isn't part of the parent block, merkle root {}",
+                utils::arr_to_hex_swapped(&merkle_root)
+            ));
+        }
+        // The coinbase script contains the root in display order
+        let mut chain_root = self.blockchain_branch.root(*block_hash);
+        chain_root.reverse();
+        let script_sig = &self.coinbase_tx.value.inputs[0].script_sig;
+        let header_pos = find(script_sig, &MERGED_MINING_HEADER);
+        match find(script_sig, &chain_root) {
+            Some(pos) if header_pos.is_none_or(|h| h + MERGED_MINING_HEADER.len() == pos) => Ok(()),
+            Some(_) => Err(String::from(
+                "Merged mining header doesn't precede the chain merkle root",
+            )),
+            None => Err(format!(
+                "Coinbase doesn't contain the chain merkle root {}",
+                utils::arr_to_hex(&chain_root)
+            )),
+        }
+    }
+}
+
+impl ToRaw for AuxPow {
+    fn to_bytes(&self) -> Vec<u8> {
+        let tx = &self.coinbase_tx.value;
+        let mut bytes = if tx.has_extended_serialization() {
+            tx.to_witness_bytes()
+        } else {
+            tx.to_bytes()
+        };
+        bytes.extend_from_slice(&self.hash_block);
+        bytes.extend_from_slice(&self.coinbase_branch.to_bytes());
+        bytes.extend_from_slice(&self.blockchain_branch.to_bytes());
+        bytes.extend_from_slice(&self.parent_header.value.to_bytes());
+        bytes
+    }
+}
+
+impl fmt::Debug for AuxPow {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("AuxPow")
+            .field(
+                "coinbase_tx",
+                &utils::arr_to_hex_swapped(&self.coinbase_tx.hash),
+            )
+            .field("coinbase_branch", &self.coinbase_branch)
+            .field("blockchain_branch", &self.blockchain_branch)
+            .field("parent_header", &self.parent_header)
+            .finish()
+    }
+}
+
+#[inline]
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(byte: u8) -> [u8; 32] {
+        [byte; 32]
+    }
+
+    #[test]
+    fn test_merkle_branch_root() {
+        let leaves: Vec<[u8; 32]> = (1..=4).map(hash).collect();
+        let root = utils::merkle_root(&leaves);
+        let pair =
+            |a: &[u8; 32], b: &[u8; 32]| utils::sha256(&utils::sha256(&[&a[..], &b[..]].concat()));
+
+        // Path of the third leaf: its sibling on the right, then the left pair
+        let branch = MerkleBranch {
+            hashes: vec![leaves[3], pair(&leaves[0], &leaves[1])],
+            side_mask: 2,
+        };
+        assert_eq!(root, branch.root(leaves[2]));
+        assert_ne!(
+            root,
+            MerkleBranch {
+                side_mask: 1,
+                ..branch.clone()
+            }
+            .root(leaves[2])
+        );
+
+        // An empty branch is the leaf itself
+        assert_eq!(hash(7), MerkleBranch::default().root(hash(7)));
+
+        let bytes = branch.to_bytes();
+        assert_eq!(1 + 64 + 4, bytes.len());
+        assert_eq!(2, bytes[0]);
+        assert_eq!([2, 0, 0, 0], bytes[65..]);
+    }
+}
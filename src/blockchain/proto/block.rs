@@ -1,19 +1,47 @@
 use std::fmt;
 
-use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use rayon::iter::{IntoParallelIterator, IntoParallelRefMutIterator, ParallelIterator};
 
+use crate::blockchain::proto::auxpow::AuxPow;
 use crate::blockchain::proto::header::BlockHeader;
+use crate::blockchain::proto::script::AddressFormat;
 use crate::blockchain::proto::tx::{EvaluatedTx, RawTx};
 use crate::blockchain::proto::varuint::VarUint;
-use crate::blockchain::proto::Hashed;
+use crate::blockchain::proto::{Hashed, ToRaw};
 use crate::common::utils;
 
+/// Serialization of the blocks of a coin, see `Coin::block_format`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BlockFormat {
+    #[default]
+    Bitcoin,
+    /// Headers with `BlockHeader::VERSION_AUXPOW` are followed by an AuxPoW (Namecoin, Dogecoin)
+    AuxPow,
+    /// Blocks with a HogEx transaction are followed by an MWEB extension block (Litecoin)
+    Litecoin,
+    /// Equihash headers and Zcash transactions with shielded sections, see `parser::zcash`
+    Zcash,
+}
+
+/// `OP_RETURN` push of 36 bytes starting with the commitment header 0xaa21a9ed (BIP141)
+const WITNESS_COMMITMENT_HEADER: [u8; 6] = [0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed];
+
 /// Basic block structure which holds all information
 pub struct Block {
     pub size: u32,
     pub header: Hashed<BlockHeader>,
+    /// Proof of work of merge mined blocks, see `BlockFormat::AuxPow`
+    pub aux_pow: Option<AuxPow>,
     pub tx_count: VarUint,
     pub txs: Vec<Hashed<EvaluatedTx>>,
+    /// Litecoin: the block has a HogEx transaction and an MWEB extension block, which is skipped
+    pub has_mweb: bool,
+    /// Bytes of the MWEB extension block after the transactions, included in `size`
+    pub mweb_size: u32,
+    /// Bytes of the block without witness data and MWEB extension block, see `measure()`
+    pub stripped_size: u32,
+    /// Weight units (BIP141) of the header, transaction count and transactions
+    pub weight: u64,
 }
 
 impl Block {
@@ -21,16 +49,58 @@ impl Block {
     pub fn new(size: u32, header: BlockHeader, tx_count: VarUint, txs: Vec<RawTx>) -> Block {
         let txs = txs
             .into_par_iter()
-            .map(|raw| Hashed::double_sha256(EvaluatedTx::from(raw)))
+            .map(|raw| {
+                let tx = EvaluatedTx::from(raw);
+                // Zcash txids aren't always the hash of the serialization
+                match tx.zcash.as_ref().map(|zcash| zcash.txid) {
+                    Some(txid) => Hashed::from(txid, tx),
+                    None => Hashed::double_sha256(tx),
+                }
+            })
             .collect();
-        Block {
+        let mut block = Block {
             size,
             header: Hashed::double_sha256(header),
+            aux_pow: None,
             tx_count,
             txs,
+            has_mweb: false,
+            mweb_size: 0,
+            stripped_size: 0,
+            weight: 0,
+        };
+        block.measure();
+        block
+    }
+
+    /// Evaluates the output scripts of a block read with `AddressFormat::evaluate_scripts` unset,
+    /// including the coinbase of the AuxPoW
+    pub fn evaluate_scripts(&mut self, address_format: AddressFormat) {
+        self.txs
+            .par_iter_mut()
+            .for_each(|tx| tx.value.evaluate_scripts(address_format));
+        if let Some(aux_pow) = self.aux_pow.as_mut() {
+            aux_pow.coinbase_tx.value.evaluate_scripts(address_format);
         }
     }
 
+    /// Computes `stripped_size` and `weight` from `size`, `mweb_size` and the sizes of the
+    /// transactions. Blocks read without transactions count as having no witness data.
+    pub fn measure(&mut self) {
+        let (mut witness_size, mut mweb_size) = (0, self.mweb_size as u64);
+        for tx in &self.txs {
+            witness_size += (tx.value.size - tx.value.stripped_size) as u64;
+            // The MWEB byte of the HogEx, and marker and flag if they aren't needed for witnesses
+            if tx.value.hogex {
+                mweb_size += if tx.value.has_witness() { 1 } else { 3 };
+            }
+        }
+        let size = (self.size as u64).saturating_sub(mweb_size);
+        let stripped_size = size.saturating_sub(witness_size);
+        self.stripped_size = stripped_size as u32;
+        self.weight = stripped_size * 3 + size;
+    }
+
     /// Computes merkle root for all containing transactions
     #[inline]
     pub fn compute_merkle_root(&self) -> [u8; 32] {
@@ -38,16 +108,109 @@ impl Block {
     }
 
     /// Calculates merkle root and verifies it against the field in BlockHeader.
-    /// panics if not valid.
-    pub fn verify_merkle_root(&self) {
-        let merkle_root = self.compute_merkle_root();
+    /// Blocks with a mutated merkle tree (CVE-2012-2459) are rejected even if the root matches.
+    /// Returns the reason if not valid.
+    pub fn verify_merkle_root(&self) -> Result<(), String> {
+        let txids: Vec<[u8; 32]> = self.txs.iter().map(|tx| tx.hash).collect();
+        let (merkle_root, mutated) = utils::merkle_root_mutated(&txids);
         if merkle_root != self.header.value.merkle_root {
-            panic!(
-                "Invalid merkle_root!\n  -> expected: {}\n  -> got: {}\n",
-                &utils::arr_to_hex_swapped(&self.header.value.merkle_root),
-                &utils::arr_to_hex_swapped(&merkle_root)
-            );
+            return Err(format!(
+                "Invalid merkle root, expected {}, got {}",
+                utils::arr_to_hex_swapped(&self.header.value.merkle_root),
+                utils::arr_to_hex_swapped(&merkle_root)
+            ));
+        }
+        if mutated {
+            return Err(String::from(
+                "Mutated merkle tree, duplicate transactions (CVE-2012-2459)",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Verifies the witness commitment (BIP141) in the coinbase against the wtxids.
+    /// Blocks without commitment must not contain witness data.
+    /// Requires the witnesses of the coinbase, see `EvaluatedTx::drop_witnesses`.
+    /// Returns the reason if not valid.
+    pub fn verify_witness_commitment(&self) -> Result<(), String> {
+        let coinbase = match self.txs.first() {
+            Some(tx) => &tx.value,
+            None => return Ok(()),
+        };
+        // The output with the highest index counts
+        let commitment = coinbase.outputs.iter().rev().find_map(|output| {
+            let script = &output.out.script_pubkey;
+            if script.len() >= 38 && script.starts_with(&WITNESS_COMMITMENT_HEADER) {
+                Some(&script[6..38])
+            } else {
+                None
+            }
+        });
+        let commitment = match commitment {
+            Some(commitment) => commitment,
+            None if self.txs.iter().any(|tx| tx.value.has_witness()) => {
+                return Err(String::from("Witness data without witness commitment"))
+            }
+            None => return Ok(()),
+        };
+        let reserved = match coinbase.witnesses.first() {
+            Some(witness) if witness.len() == 1 && witness[0].len() == 32 => &witness[0],
+            _ => {
+                return Err(String::from(
+                    "Coinbase witness isn't a single 32 byte reserved value",
+                ))
+            }
+        };
+        // The coinbase wtxid is zero
+        let wtxids: Vec<[u8; 32]> = std::iter::once([0u8; 32])
+            .chain(
+                self.txs[1..]
+                    .iter()
+                    .map(|tx| tx.value.witness_hash().unwrap_or(tx.hash)),
+            )
+            .collect();
+        let witness_root = utils::merkle_root(&wtxids);
+        let expected = utils::sha256d(&[&witness_root[..], &reserved[..]].concat());
+        if commitment != expected {
+            return Err(format!(
+                "Invalid witness commitment, expected {}, got {}",
+                utils::arr_to_hex(&expected),
+                utils::arr_to_hex(commitment)
+            ));
+        }
+        Ok(())
+    }
+
+    /// Verifies that the parent block of the AuxPoW commits to this block, if there is one.
+    /// Returns the reason if not valid.
+    pub fn verify_aux_pow(&self) -> Result<(), String> {
+        match &self.aux_pow {
+            Some(aux_pow) => aux_pow
+                .check(&self.header.hash)
+                .map_err(|msg| format!("Invalid AuxPoW: {}", msg)),
+            None => Ok(()),
+        }
+    }
+}
+
+impl ToRaw for Block {
+    /// Serializes the block in network format, transactions with retained witness data include it.
+    /// The MWEB extension block is not part of it.
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.size as usize);
+        bytes.extend_from_slice(&self.header.value.to_bytes());
+        if let Some(aux_pow) = &self.aux_pow {
+            bytes.extend_from_slice(&aux_pow.to_bytes());
         }
+        bytes.extend_from_slice(&self.tx_count.to_bytes());
+        for tx in &self.txs {
+            if tx.value.has_extended_serialization() {
+                bytes.extend_from_slice(&tx.value.to_witness_bytes());
+            } else {
+                bytes.extend_from_slice(&tx.value.to_bytes());
+            }
+        }
+        bytes
     }
 }
 
@@ -55,6 +218,7 @@ impl fmt::Debug for Block {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         fmt.debug_struct("Block")
             .field("header", &self.header)
+            .field("aux_pow", &self.aux_pow)
             .field("tx_count", &self.tx_count)
             .finish()
     }
@@ -65,9 +229,280 @@ pub fn get_base_reward(block_height: u64) -> u64 {
     (50 * 100000000) >> (block_height / 210000)
 }
 
+const COIN: u64 = 100000000;
+
+/// Block subsidy schedule of a coin, see `Coin::subsidy`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Subsidy {
+    /// `initial` base units, halved every `interval` blocks (Bitcoin, Litecoin)
+    Halving { initial: u64, interval: u64 },
+    /// Random rewards of up to 1000000 DOGE derived from the previous block hash below height
+    /// 145000, fixed rewards halving every 100000 blocks up to 600000, then 10000 DOGE forever
+    Dogecoin,
+    /// Not implemented (e.g. Zcash's founders reward or coins loaded with `--coin-file`)
+    Unknown,
+}
+
+impl Subsidy {
+    pub const BITCOIN: Subsidy = Subsidy::Halving {
+        initial: 50 * COIN,
+        interval: 210000,
+    };
+
+    /// Returns the highest subsidy a block at the height may claim and whether it's exact.
+    /// Dogecoin's random rewards only have an upper bound. None if the schedule is unknown.
+    pub fn max_subsidy(&self, block_height: u64) -> Option<(u64, bool)> {
+        match *self {
+            Subsidy::Halving { initial, interval } => {
+                let halvings = block_height / interval;
+                // Shifting by 64 or more isn't defined, the subsidy is zero long before
+                Some((initial.checked_shr(halvings as u32).unwrap_or(0), true))
+            }
+            Subsidy::Dogecoin => {
+                let halvings = block_height / 100000;
+                match block_height {
+                    0..=144999 => Some(((1000000 >> halvings) * COIN, false)),
+                    145000..=599999 => Some(((500000 * COIN) >> halvings, true)),
+                    _ => Some((10000 * COIN, true)),
+                }
+            }
+            Subsidy::Unknown => None,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::get_base_reward;
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::blockchain::parser::reader::BlockchainRead;
+    use crate::blockchain::proto::script::AddressFormat;
+
+    const GENESIS_BLOCK: &str = concat!(
+        "0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b2",
+        "7ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a29ab5f49ffff001d1dac2b7c0101000000010000",
+        "000000000000000000000000000000000000000000000000000000000000ffffffff4d04ffff001d01044554",
+        "68652054696d65732030332f4a616e2f32303039204368616e63656c6c6f72206f6e206272696e6b206f6620",
+        "7365636f6e64206261696c6f757420666f722062616e6b73ffffffff0100f2052a01000000434104678afdb0",
+        "fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de",
+        "5c384df7ba0b8d578a4c702b6bf11d5fac00000000",
+    );
+    // See reader::tests::test_bitcoin_parse_segwit_tx
+    const SEGWIT_TX: &str = concat!(
+        "0100000000010115e180dc28a2327e687facc33f10f2a20da717e5548406f7ae8b4c811072f8560300000017",
+        "1600141d7cd6c75c2e86f4cbf98eaed221b30bd9a0b928ffffffff019caef505000000001976a9141d7cd6c7",
+        "5c2e86f4cbf98eaed221b30bd9a0b92888ac02483045022100f764287d3e99b1474da9bec7f7ed236d6c81e7",
+        "93b20c4b5aa1f3051b9a7daa63022016a198031d5554dbb855bdbe8534776a4be6958bd8d530dc001c32b828",
+        "f6f0ab0121038262a6c6cec93c2d3ecd6c6072efea86d02ff8e3328bbd0242b20af3425990ac00000000",
+    );
+
+    // Block with a segwit coinbase committing to the wtxid of SEGWIT_TX
+    const SEGWIT_BLOCK: &str = concat!(
+        "0000002000000000000000000000000000000000000000000000000000000000000000004cc383d95f798aa7",
+        "36f0340558bd284a83668082a042d20773d6636e688ce6d4002f6859ffff7f20000000000201000000000101",
+        "0000000000000000000000000000000000000000000000000000000000000000ffffffff0403a08601ffffff",
+        "ff02807c814a000000001976a9141d7cd6c75c2e86f4cbf98eaed221b30bd9a0b92888ac0000000000000000",
+        "266a24aa21a9edb562a8fc45bd278e8ecc15caf1381830e763a0c9bf499bac0aa59068fc1e9d340120000000",
+        "0000000000000000000000000000000000000000000000000000000000000000000100000000010115e180dc",
+        "28a2327e687facc33f10f2a20da717e5548406f7ae8b4c811072f85603000000171600141d7cd6c75c2e86f4",
+        "cbf98eaed221b30bd9a0b928ffffffff019caef505000000001976a9141d7cd6c75c2e86f4cbf98eaed221b3",
+        "0bd9a0b92888ac02483045022100f764287d3e99b1474da9bec7f7ed236d6c81e793b20c4b5aa1f3051b9a7d",
+        "aa63022016a198031d5554dbb855bdbe8534776a4be6958bd8d530dc001c32b828f6f0ab0121038262a6c6ce",
+        "c93c2d3ecd6c6072efea86d02ff8e3328bbd0242b20af3425990ac00000000",
+    );
+
+    // Merge mined Dogecoin block (version 0x00620102) with a Litecoin parent. The parent coinbase
+    // commits to the block at chain index 1, and is the first of two transactions in the parent.
+    const AUXPOW_BLOCK: &str = concat!(
+        "0201620045dc9d03b475f78e1e3cd482e30ec7b57513d8c35e1c70b0bcba5a9bc5ccf8f81576fee930e309ed",
+        "2a000fbd827b80c8e27350e3fba29d11749b15cf2e89b30741fb11548441361b000000000100000001000000",
+        "0000000000000000000000000000000000000000000000000000000000ffffffff3503a0bb0bfabe6d6d35b5",
+        "9d89fd9c9509502d929ce0b340cab31541575ad00f4631538bb386a2ce4a02000000000000002f4c54432fff",
+        "ffffff0100f90295000000001976a91489abcdefabbaabbaabbaabbaabbaabbaabbaabba88ac000000000000",
+        "0000000000000000000000000000000000000000000000000000000000000139f18141c43c56e1933429ae75",
+        "97a2f3688fd07a93709d5ed099917a763743fd0000000001042678ee156ef15a38b4e35660f1ef76712d6aa8",
+        "d89d9ece41ca9c6ad540d1c601000000020000006a21c630eb161fc6bb8e3f0976868cccc5d923f530526d0e",
+        "bce85132e132586ea31fdba0a456b1e79fcc135a4210d4caed89d045e72259fdb4922c4130cbcc3a3afb1154",
+        "4e5b2a1b39300000010100000001000000000000000000000000000000000000000000000000000000000000",
+        "0000ffffffff0f0389aa052f646f676570617274792fffffffff010010a5d4e80000001976a9141d7cd6c75c",
+        "2e86f4cbf98eaed221b30bd9a0b92888ac00000000",
+    );
+
+    fn round_trip(raw: &[u8]) {
+        let block = Cursor::new(raw)
+            .read_block(
+                raw.len() as u32,
+                AddressFormat::default(),
+                BlockFormat::Bitcoin,
+            )
+            .unwrap();
+        let bytes = block.to_bytes();
+        assert_eq!(utils::arr_to_hex(raw), utils::arr_to_hex(&bytes));
+
+        let parsed = Cursor::new(&bytes)
+            .read_block(
+                bytes.len() as u32,
+                AddressFormat::default(),
+                BlockFormat::Bitcoin,
+            )
+            .unwrap();
+        assert_eq!(block.header.hash, parsed.header.hash);
+        assert_eq!(block.txs.len(), parsed.txs.len());
+        for (tx, parsed_tx) in block.txs.iter().zip(parsed.txs.iter()) {
+            assert_eq!(tx.hash, parsed_tx.hash);
+            assert_eq!(tx.value.witness_hash(), parsed_tx.value.witness_hash());
+        }
+    }
+
+    #[test]
+    fn test_to_bytes() {
+        round_trip(&utils::hex_to_vec(GENESIS_BLOCK));
+
+        // Genesis block with an additional segwit transaction
+        let mut raw = utils::hex_to_vec(GENESIS_BLOCK);
+        raw[80] = 0x02;
+        raw.extend_from_slice(&utils::hex_to_vec(SEGWIT_TX));
+        round_trip(&raw);
+    }
+
+    fn parse(hex: &str) -> Block {
+        let raw = utils::hex_to_vec(hex);
+        Cursor::new(&raw)
+            .read_block(
+                raw.len() as u32,
+                AddressFormat::default(),
+                BlockFormat::Bitcoin,
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn test_verify() {
+        let genesis = parse(GENESIS_BLOCK);
+        assert_eq!(Ok(()), genesis.verify_merkle_root());
+        assert_eq!(Ok(()), genesis.verify_witness_commitment());
+
+        let mut block = parse(SEGWIT_BLOCK);
+        assert_eq!(Ok(()), block.verify_merkle_root());
+        assert_eq!(Ok(()), block.verify_witness_commitment());
+
+        // Another witness reserved value
+        block.txs[0].value.witnesses[0][0][0] = 1;
+        assert!(block
+            .verify_witness_commitment()
+            .unwrap_err()
+            .starts_with("Invalid witness commitment, expected"));
+        block.txs[0].value.witnesses[0].push(Vec::new());
+        assert!(block
+            .verify_witness_commitment()
+            .unwrap_err()
+            .starts_with("Coinbase witness isn't"));
+
+        // CVE-2012-2459: the root of [cb, tx, tx] equals the one of [cb, tx, tx, tx]
+        let mut block = parse(SEGWIT_BLOCK);
+        for _ in 0..2 {
+            block.txs.push(parse(SEGWIT_BLOCK).txs.pop().unwrap());
+        }
+        block.header.value.merkle_root = block.compute_merkle_root();
+        assert!(block
+            .verify_merkle_root()
+            .unwrap_err()
+            .contains("CVE-2012-2459"));
+        block.txs.pop();
+        assert_eq!(Ok(()), block.verify_merkle_root());
+
+        // Genesis block with an additional segwit transaction
+        let mut raw = utils::hex_to_vec(GENESIS_BLOCK);
+        raw[80] = 0x02;
+        raw.extend_from_slice(&utils::hex_to_vec(SEGWIT_TX));
+        let block = parse(&utils::arr_to_hex(&raw));
+        assert!(block
+            .verify_merkle_root()
+            .unwrap_err()
+            .starts_with("Invalid merkle root, expected 4a5e1e4b"));
+        assert_eq!(
+            Err(String::from("Witness data without witness commitment")),
+            block.verify_witness_commitment()
+        );
+    }
+
+    #[test]
+    fn test_aux_pow() {
+        let raw = utils::hex_to_vec(AUXPOW_BLOCK);
+        let block = Cursor::new(&raw)
+            .read_block(
+                raw.len() as u32,
+                AddressFormat::default(),
+                BlockFormat::AuxPow,
+            )
+            .unwrap();
+        assert!(block.header.value.has_auxpow());
+        assert_eq!(
+            "15ead577e94c0bc7f9b8dd2c36facf4bb1a745aaf6853b147f1bdf7ee8b7d637",
+            utils::arr_to_hex_swapped(&block.header.hash)
+        );
+        let aux_pow = block.aux_pow.as_ref().unwrap();
+        assert_eq!(
+            "3c5295b4f1182551b94ed8997eb4b3903bcf05449193c3598d0d59033b4a4456",
+            utils::arr_to_hex_swapped(&aux_pow.coinbase_tx.hash)
+        );
+        assert_eq!(
+            "abba6b843053e46e732546f468982df4167417426550d844609f04c3c021551a",
+            utils::arr_to_hex_swapped(&aux_pow.parent_header.hash)
+        );
+        assert_eq!([0u8; 32], aux_pow.hash_block);
+        assert_eq!(
+            (1, 0),
+            (
+                aux_pow.coinbase_branch.hashes.len(),
+                aux_pow.coinbase_branch.side_mask
+            )
+        );
+        assert_eq!(1, aux_pow.chain_index());
+        assert_eq!(1, block.tx_count.value);
+        assert_eq!(1000000000000, block.txs[0].value.outputs[0].out.value);
+        block.verify_merkle_root().unwrap();
+        block.verify_aux_pow().unwrap();
+        assert_eq!(AUXPOW_BLOCK, utils::arr_to_hex(&block.to_bytes()));
+
+        // Committed to another block
+        assert!(aux_pow.check(&block.header.value.prev_hash).is_err());
+
+        // Coins without merged mining read the parent coinbase as transactions
+        let misparsed = Cursor::new(&raw).read_block(
+            raw.len() as u32,
+            AddressFormat::default(),
+            BlockFormat::Bitcoin,
+        );
+        assert!(misparsed.map_or(true, |b| b.aux_pow.is_none() && b.to_bytes() != raw));
+
+        let header_only = Cursor::new(&raw)
+            .read_block_without_txs(raw.len() as u32, BlockFormat::AuxPow)
+            .unwrap();
+        assert_eq!(1, header_only.tx_count.value);
+        assert_eq!(
+            aux_pow.parent_header.hash,
+            header_only.aux_pow.unwrap().parent_header.hash
+        );
+    }
+
+    #[test]
+    fn test_verify_aux_pow() {
+        let raw = utils::hex_to_vec(AUXPOW_BLOCK);
+        let mut block = Cursor::new(&raw)
+            .read_block(
+                raw.len() as u32,
+                AddressFormat::default(),
+                BlockFormat::AuxPow,
+            )
+            .unwrap();
+        block.aux_pow.as_mut().unwrap().blockchain_branch.side_mask = 0;
+        assert!(block
+            .verify_aux_pow()
+            .unwrap_err()
+            .starts_with("Invalid AuxPoW"));
+    }
 
     #[test]
     fn test_get_base_reward() {
@@ -77,6 +512,45 @@ mod tests {
         assert_eq!(get_base_reward(419999), 2500000000);
         assert_eq!(get_base_reward(420000), 1250000000);
         assert_eq!(get_base_reward(629999), 1250000000);
-        assert_eq!(get_base_reward(630000), 0625000000);
+        assert_eq!(get_base_reward(630000), 625000000);
+    }
+
+    #[test]
+    fn test_halving_subsidy() {
+        let bitcoin = Subsidy::BITCOIN;
+        assert_eq!(Some((5000000000, true)), bitcoin.max_subsidy(0));
+        assert_eq!(Some((5000000000, true)), bitcoin.max_subsidy(209999));
+        assert_eq!(Some((2500000000, true)), bitcoin.max_subsidy(210000));
+        assert_eq!(Some((312500000, true)), bitcoin.max_subsidy(840000));
+        // The last satoshi is paid in the 33rd era
+        assert_eq!(Some((1, true)), bitcoin.max_subsidy(32 * 210000));
+        assert_eq!(Some((0, true)), bitcoin.max_subsidy(33 * 210000));
+        assert_eq!(Some((0, true)), bitcoin.max_subsidy(64 * 210000));
+        assert_eq!(Some((0, true)), bitcoin.max_subsidy(u64::MAX));
+
+        let litecoin = Subsidy::Halving {
+            initial: 50 * COIN,
+            interval: 840000,
+        };
+        assert_eq!(Some((5000000000, true)), litecoin.max_subsidy(839999));
+        assert_eq!(Some((2500000000, true)), litecoin.max_subsidy(840000));
+        assert_eq!(None, Subsidy::Unknown.max_subsidy(0));
+    }
+
+    #[test]
+    fn test_dogecoin_subsidy() {
+        let doge = Subsidy::Dogecoin;
+        // Random rewards, only the upper bound halves
+        assert_eq!(Some((1000000 * COIN, false)), doge.max_subsidy(1));
+        assert_eq!(Some((1000000 * COIN, false)), doge.max_subsidy(99999));
+        assert_eq!(Some((500000 * COIN, false)), doge.max_subsidy(100000));
+        assert_eq!(Some((500000 * COIN, false)), doge.max_subsidy(144999));
+        // Fixed rewards from 145000
+        assert_eq!(Some((250000 * COIN, true)), doge.max_subsidy(145000));
+        assert_eq!(Some((250000 * COIN, true)), doge.max_subsidy(199999));
+        assert_eq!(Some((125000 * COIN, true)), doge.max_subsidy(200000));
+        assert_eq!(Some((15625 * COIN, true)), doge.max_subsidy(599999));
+        assert_eq!(Some((10000 * COIN, true)), doge.max_subsidy(600000));
+        assert_eq!(Some((10000 * COIN, true)), doge.max_subsidy(u64::MAX));
     }
 }
@@ -1,9 +1,10 @@
 use std::fmt;
 
+use crate::blockchain::proto::varuint::VarUint;
 use crate::blockchain::proto::ToRaw;
 use crate::common::utils;
 
-/// Block Header definition. Exact 80 bytes long
+/// Block Header definition. Exact 80 bytes long, except for Equihash headers
 #[derive(Clone)]
 pub struct BlockHeader {
     pub version: u32,
@@ -11,10 +12,25 @@ pub struct BlockHeader {
     pub merkle_root: [u8; 32],
     pub timestamp: u32,
     pub bits: u32,
+    /// The first 4 bytes of the nonce of Equihash headers
     pub nonce: u32,
+    /// Fields of Zcash headers, see `BlockFormat::Zcash`
+    pub equihash: Option<Box<EquihashHeader>>,
+}
+
+/// Fields of Equihash headers (Zcash) which don't fit into the Bitcoin layout
+#[derive(Clone)]
+pub struct EquihashHeader {
+    /// hashFinalSaplingRoot, hashLightClientRoot or hashBlockCommitments depending on the network upgrade
+    pub commitments: [u8; 32],
+    pub nonce: [u8; 32],
+    pub solution: Vec<u8>,
 }
 
 impl BlockHeader {
+    /// Version bit of merge mined blocks, which are followed by an `AuxPow`
+    pub const VERSION_AUXPOW: u32 = 1 << 8;
+
     pub fn new(
         version: u32,
         prev_hash: [u8; 32],
@@ -30,8 +46,15 @@ impl BlockHeader {
             timestamp,
             bits,
             nonce,
+            equihash: None,
         }
     }
+
+    /// True if the version signals an auxiliary proof of work, only meaningful for coins with merged mining
+    #[inline]
+    pub fn has_auxpow(&self) -> bool {
+        self.version & Self::VERSION_AUXPOW != 0
+    }
 }
 
 impl ToRaw for BlockHeader {
@@ -40,9 +63,21 @@ impl ToRaw for BlockHeader {
         bytes.extend_from_slice(&self.version.to_le_bytes());
         bytes.extend_from_slice(&self.prev_hash);
         bytes.extend_from_slice(&self.merkle_root);
+        if let Some(equihash) = &self.equihash {
+            bytes.extend_from_slice(&equihash.commitments);
+        }
         bytes.extend_from_slice(&self.timestamp.to_le_bytes());
         bytes.extend_from_slice(&self.bits.to_le_bytes());
-        bytes.extend_from_slice(&self.nonce.to_le_bytes());
+        match &self.equihash {
+            Some(equihash) => {
+                bytes.extend_from_slice(&equihash.nonce);
+                bytes.extend_from_slice(
+                    &VarUint::compact(equihash.solution.len() as u64).to_bytes(),
+                );
+                bytes.extend_from_slice(&equihash.solution);
+            }
+            None => bytes.extend_from_slice(&self.nonce.to_le_bytes()),
+        }
         bytes
     }
 }
@@ -56,6 +91,7 @@ impl fmt::Debug for BlockHeader {
             .field("timestamp", &self.timestamp)
             .field("bits", &self.bits)
             .field("nonce", &self.nonce)
+            .field("equihash", &self.equihash.is_some())
             .finish()
     }
 }
@@ -0,0 +1,290 @@
+//! Ordinals inscriptions, envelopes `OP_FALSE OP_IF "ord" <fields> OP_0 <body> OP_ENDIF` in the
+//! tapscript of a taproot script path spend.
+//!
+//! Fields are pairs of a tag and a value before the empty push which starts the body, the body
+//! is usually split into pushes of 520 bytes. Like ord, envelopes which contain other opcodes
+//! or don't reach OP_ENDIF are skipped, a script may contain several envelopes.
+
+use crate::blockchain::proto::opcodes;
+use crate::blockchain::proto::script;
+
+const OP_FALSE: u8 = opcodes::All::OP_PUSHBYTES_0 as u8;
+const OP_IF: u8 = opcodes::All::OP_IF as u8;
+const OP_ENDIF: u8 = opcodes::All::OP_ENDIF as u8;
+const OP_PUSHNUM_NEG1: u8 = opcodes::All::OP_PUSHNUM_NEG1 as u8;
+const OP_PUSHNUM_1: u8 = opcodes::All::OP_PUSHNUM_1 as u8;
+const OP_PUSHNUM_16: u8 = opcodes::All::OP_PUSHNUM_16 as u8;
+/// First byte of the annex, the optional last witness item of taproot spends (BIP341)
+const ANNEX_TAG: u8 = 0x50;
+
+const PROTOCOL_ID: &[u8] = b"ord";
+const TAG_CONTENT_TYPE: u8 = 1;
+const TAG_POINTER: u8 = 2;
+const TAG_CONTENT_ENCODING: u8 = 9;
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Inscription {
+    pub content_type: Option<Vec<u8>>,
+    /// e.g. `br` or `gzip`, the body is stored encoded
+    pub content_encoding: Option<Vec<u8>>,
+    /// Concatenation of the pushes after the body tag, None without body tag
+    pub body: Option<Vec<u8>>,
+    /// A tag without value, a repeated content type or encoding, or an unknown even tag.
+    /// ord still indexes these inscriptions, but some fields are ignored.
+    pub malformed: bool,
+}
+
+impl Inscription {
+    /// Returns the inscriptions of all envelopes in the tapscript, in script order.
+    /// A truncated push ends the script.
+    pub fn parse(tapscript: &[u8]) -> Vec<Self> {
+        let mut ops: Vec<(u8, Option<&[u8]>)> = Vec::new();
+        let mut ip = 0;
+        while ip < tapscript.len() {
+            match script::read_op(tapscript, &mut ip) {
+                Ok(op) => ops.push(op),
+                Err(_) => break,
+            }
+        }
+
+        let mut inscriptions = Vec::new();
+        let mut i = 0;
+        while i + 3 <= ops.len() {
+            let is_start = matches!(ops[i], (OP_FALSE, _))
+                && ops[i + 1].0 == OP_IF
+                && ops[i + 2].1 == Some(PROTOCOL_ID);
+            if !is_start {
+                i += 1;
+                continue;
+            }
+            match envelope_pushes(&ops[i + 3..]) {
+                Some((pushes, len)) => {
+                    inscriptions.push(Inscription::from_pushes(&pushes));
+                    i += 3 + len;
+                }
+                None => i += 1,
+            }
+        }
+        inscriptions
+    }
+
+    fn from_pushes(pushes: &[Vec<u8>]) -> Self {
+        let mut inscription = Inscription::default();
+        for field in pushes.chunks(2) {
+            let (tag, value) = match field {
+                [tag, ..] if tag.is_empty() => break,
+                [tag, value] => (tag, value),
+                // The last tag has no value
+                _ => {
+                    inscription.malformed = true;
+                    break;
+                }
+            };
+            let target = match tag.as_slice() {
+                [TAG_CONTENT_TYPE] => &mut inscription.content_type,
+                [TAG_CONTENT_ENCODING] => &mut inscription.content_encoding,
+                [TAG_POINTER] => continue,
+                // Unknown odd tags may be ignored
+                [tag, ..] if tag % 2 == 1 => continue,
+                _ => {
+                    inscription.malformed = true;
+                    continue;
+                }
+            };
+            if target.is_some() {
+                inscription.malformed = true;
+            } else {
+                *target = Some(value.clone());
+            }
+        }
+        // Everything after the body tag belongs to the body
+        let body_start = pushes
+            .iter()
+            .enumerate()
+            .step_by(2)
+            .find(|(_, tag)| tag.is_empty())
+            .map(|(i, _)| i + 1);
+        if let Some(start) = body_start {
+            inscription.body = Some(pushes[start..].concat());
+        }
+        inscription
+    }
+}
+
+/// Returns the pushes of an envelope up to OP_ENDIF and the number of ops it spans, including
+/// OP_ENDIF. OP_PUSHNUM opcodes count as pushes of their value. None if the envelope contains
+/// other opcodes or isn't terminated.
+fn envelope_pushes(ops: &[(u8, Option<&[u8]>)]) -> Option<(Vec<Vec<u8>>, usize)> {
+    let mut pushes = Vec::new();
+    for (i, op) in ops.iter().enumerate() {
+        match *op {
+            (_, Some(data)) => pushes.push(data.to_vec()),
+            (OP_ENDIF, None) => return Some((pushes, i + 1)),
+            (OP_PUSHNUM_NEG1, None) => pushes.push(vec![0x81]),
+            (opcode @ OP_PUSHNUM_1..=OP_PUSHNUM_16, None) => {
+                pushes.push(vec![opcode - OP_PUSHNUM_1 + 1])
+            }
+            _ => return None,
+        }
+    }
+    None
+}
+
+/// Returns the tapscript of a taproot script path spend, the witness item before the control
+/// block. Key path spends and other witnesses have none.
+pub fn tapscript(witness: &[Vec<u8>]) -> Option<&[u8]> {
+    let items = match witness {
+        [rest @ .., annex] if !rest.is_empty() && annex.first() == Some(&ANNEX_TAG) => rest,
+        items => items,
+    };
+    match items {
+        [.., script, control] if is_control_block(control) => Some(script),
+        _ => None,
+    }
+}
+
+/// Leaf version 0xc0 (tapscript) with the parity bit, the internal key and the merkle path
+#[inline]
+fn is_control_block(data: &[u8]) -> bool {
+    data.len() >= 33 && (data.len() - 33).is_multiple_of(32) && data[0] & 0xfe == 0xc0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::utils;
+
+    /// Internal key of the control block and the key of the tapscript
+    const KEY: &str = "b6b7fe1a2e4a7a5f8b3f1fbd6c2b4a44cc9b2c4e0a540e11d2f0dc3796c0e4f2";
+
+    /// Witness of a script path spend in the layout ord creates: signature, tapscript
+    /// `<key> OP_CHECKSIG <envelope>` and control block
+    fn witness(envelope: &str) -> Vec<Vec<u8>> {
+        vec![
+            vec![0x11; 64],
+            utils::hex_to_vec(&format!("20{}ac{}", KEY, envelope)),
+            utils::hex_to_vec(&format!("c1{}", KEY)),
+        ]
+    }
+
+    fn parse(envelope: &str) -> Vec<Inscription> {
+        Inscription::parse(tapscript(&witness(envelope)).unwrap())
+    }
+
+    #[test]
+    fn test_text_inscription() {
+        // OP_FALSE OP_IF "ord" 01 "text/plain;charset=utf-8" OP_0 "Hello, world!" OP_ENDIF
+        let inscriptions = parse(
+            "0063036f726401011874657874\
+             2f706c61696e3b636861727365743d7574662d3800\
+             0d48656c6c6f2c20776f726c642168",
+        );
+        assert_eq!(
+            vec![Inscription {
+                content_type: Some(b"text/plain;charset=utf-8".to_vec()),
+                content_encoding: None,
+                body: Some(b"Hello, world!".to_vec()),
+                malformed: false,
+            }],
+            inscriptions
+        );
+    }
+
+    #[test]
+    fn test_chunked_body() {
+        // An image split into pushes of 520 bytes with OP_PUSHDATA2
+        let body: Vec<u8> = (0..1300u32).map(|i| (i % 251) as u8).collect();
+        let mut envelope = String::from("0063036f7264010109696d6167652f706e6700");
+        for chunk in body.chunks(520) {
+            envelope.push_str(&format!(
+                "4d{:02x}{:02x}",
+                chunk.len() & 0xff,
+                chunk.len() >> 8
+            ));
+            envelope.push_str(&utils::arr_to_hex(chunk));
+        }
+        envelope.push_str("68");
+        let inscriptions = parse(&envelope);
+        assert_eq!(1, inscriptions.len());
+        assert_eq!(Some(b"image/png".to_vec()), inscriptions[0].content_type);
+        assert_eq!(Some(body), inscriptions[0].body);
+        assert!(!inscriptions[0].malformed);
+
+        // Content encoding (9), both tags as OP_PUSHNUM
+        let inscriptions = parse("0063036f72645109746578742f68746d6c5902627200040102030468");
+        assert_eq!(Some(b"text/html".to_vec()), inscriptions[0].content_type);
+        assert_eq!(Some(b"br".to_vec()), inscriptions[0].content_encoding);
+        assert_eq!(Some(vec![1, 2, 3, 4]), inscriptions[0].body);
+    }
+
+    #[test]
+    fn test_envelopes() {
+        // Two envelopes, the second one has no body
+        let inscriptions =
+            parse("0063036f7264010104746578740003616263680063036f7264010104746578746800");
+        assert_eq!(2, inscriptions.len());
+        assert_eq!(Some(b"abc".to_vec()), inscriptions[0].body);
+        assert_eq!(Some(b"text".to_vec()), inscriptions[1].content_type);
+        assert_eq!(None, inscriptions[1].body);
+
+        // Empty body, pointer (2) and unknown odd tags (0x0f) are ignored
+        let inscriptions = parse("0063036f72640102020a00010f01780068");
+        assert_eq!(Some(Vec::new()), inscriptions[0].body);
+        assert_eq!(None, inscriptions[0].content_type);
+        assert!(!inscriptions[0].malformed);
+
+        // Envelope in the middle of the script
+        let inscriptions = parse("63516851670063036f72640003616263686875");
+        assert_eq!(Some(b"abc".to_vec()), inscriptions[0].body);
+    }
+
+    #[test]
+    fn test_malformed_envelopes() {
+        // Not terminated
+        assert!(parse("0063036f72640101047465787400036162").is_empty());
+        // Truncated push
+        assert!(parse("0063036f726400066162636468").is_empty());
+        // Other opcode in the envelope
+        assert!(parse("0063036f726400036162637568").is_empty());
+        // Other protocol or no OP_FALSE OP_IF
+        assert!(parse("0063036f7265000361626368").is_empty());
+        assert!(parse("5163036f7264000361626368").is_empty());
+        assert!(parse("").is_empty());
+
+        // A tag without value
+        let inscriptions = parse("0063036f7264010168");
+        assert!(inscriptions[0].malformed);
+        assert_eq!(None, inscriptions[0].body);
+        // Repeated content type, the first one counts
+        let inscriptions = parse("0063036f726401010161010101620001780068");
+        assert!(inscriptions[0].malformed);
+        assert_eq!(Some(b"a".to_vec()), inscriptions[0].content_type);
+        assert_eq!(Some(b"x".to_vec()), inscriptions[0].body);
+        // Unknown even tag
+        let inscriptions = parse("0063036f72640104017868");
+        assert!(inscriptions[0].malformed);
+    }
+
+    #[test]
+    fn test_tapscript() {
+        let script_path = witness("0063036f726400016168");
+        assert_eq!(Some(script_path[1].as_slice()), tapscript(&script_path));
+        // The annex is skipped
+        let mut with_annex = script_path.clone();
+        with_annex.push(vec![ANNEX_TAG, 1, 2]);
+        assert_eq!(Some(script_path[1].as_slice()), tapscript(&with_annex));
+        // Control block with a merkle path of two hashes and odd parity
+        let mut deeper = script_path.clone();
+        deeper[2] = utils::hex_to_vec(&format!("c0{}{}", KEY, "ab".repeat(64)));
+        assert_eq!(Some(script_path[1].as_slice()), tapscript(&deeper));
+
+        // Key path spends, with and without annex
+        assert_eq!(None, tapscript(&[vec![0x11; 64]]));
+        assert_eq!(None, tapscript(&[vec![0x11; 64], vec![ANNEX_TAG]]));
+        // P2WSH and P2WPKH
+        let p2wsh = vec![Vec::new(), vec![0x30; 72], utils::hex_to_vec("5121aa51ae")];
+        assert_eq!(None, tapscript(&p2wsh));
+        assert_eq!(None, tapscript(&[vec![0x30; 72], vec![0x02; 33]]));
+        assert_eq!(None, tapscript(&[]));
+    }
+}
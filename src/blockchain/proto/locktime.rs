@@ -0,0 +1,157 @@
+//! Semantics of nLockTime and nSequence.
+//!
+//! The locktime is a block height below `LOCKTIME_THRESHOLD` and a unix time otherwise. It is only
+//! enforced if an input has a sequence number other than `SEQUENCE_FINAL`. From version 2 on the
+//! sequence numbers also encode relative locks (BIP68) unless the disable flag is set.
+
+/// Locktimes from this value on are unix times, block heights below
+pub const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+/// Inputs with this sequence number don't enable the locktime
+pub const SEQUENCE_FINAL: u32 = 0xFFFFFFFF;
+/// BIP68: the sequence number has no relative lock if set
+pub const SEQUENCE_LOCKTIME_DISABLE_FLAG: u32 = 1 << 31;
+/// BIP68: the relative lock is time based if set, in blocks otherwise
+pub const SEQUENCE_LOCKTIME_TYPE_FLAG: u32 = 1 << 22;
+/// BIP68: the bits holding the relative lock
+pub const SEQUENCE_LOCKTIME_MASK: u32 = 0x0000FFFF;
+/// BIP68: seconds per unit of time based relative locks
+pub const SEQUENCE_LOCKTIME_GRANULARITY: u32 = 512;
+
+/// Interpretation of nLockTime
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LockTimeType {
+    /// Zero, the transaction is valid at any height
+    None,
+    Height,
+    Time,
+}
+
+impl LockTimeType {
+    #[inline]
+    pub fn of(locktime: u32) -> Self {
+        match locktime {
+            0 => LockTimeType::None,
+            n if n < LOCKTIME_THRESHOLD => LockTimeType::Height,
+            _ => LockTimeType::Time,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            LockTimeType::None => "none",
+            LockTimeType::Height => "height",
+            LockTimeType::Time => "time",
+        }
+    }
+}
+
+/// Relative lock of an input (BIP68), the age the spent output must have
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RelativeLock {
+    Blocks(u32),
+    /// Multiple of `SEQUENCE_LOCKTIME_GRANULARITY`
+    Seconds(u32),
+}
+
+impl RelativeLock {
+    /// Decodes the sequence number of an input of a transaction with the given version.
+    /// Returns None for transactions before version 2 and if the disable flag is set.
+    pub fn decode(tx_version: u32, sequence: u32) -> Option<Self> {
+        if tx_version < 2 || sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG != 0 {
+            return None;
+        }
+        let value = sequence & SEQUENCE_LOCKTIME_MASK;
+        match sequence & SEQUENCE_LOCKTIME_TYPE_FLAG {
+            0 => Some(RelativeLock::Blocks(value)),
+            _ => Some(RelativeLock::Seconds(value * SEQUENCE_LOCKTIME_GRANULARITY)),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            RelativeLock::Blocks(_) => "blocks",
+            RelativeLock::Seconds(_) => "time",
+        }
+    }
+
+    /// Number of blocks or seconds
+    #[inline]
+    pub fn value(&self) -> u32 {
+        match *self {
+            RelativeLock::Blocks(value) | RelativeLock::Seconds(value) => value,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::callbacks::fixtures::new_tx;
+
+    #[test]
+    fn test_lock_time_type() {
+        assert_eq!(LockTimeType::None, LockTimeType::of(0));
+        assert_eq!(LockTimeType::Height, LockTimeType::of(1));
+        assert_eq!(
+            LockTimeType::Height,
+            LockTimeType::of(LOCKTIME_THRESHOLD - 1)
+        );
+        assert_eq!(LockTimeType::Time, LockTimeType::of(LOCKTIME_THRESHOLD));
+        assert_eq!(LockTimeType::Time, LockTimeType::of(u32::MAX));
+        assert_eq!("height", LockTimeType::of(630000).name());
+    }
+
+    #[test]
+    fn test_relative_lock() {
+        // Relative locks only apply from version 2 on
+        assert_eq!(None, RelativeLock::decode(1, 10));
+        assert_eq!(Some(RelativeLock::Blocks(10)), RelativeLock::decode(2, 10));
+        assert_eq!(Some(RelativeLock::Blocks(0)), RelativeLock::decode(2, 0));
+        // Disable flag (1 << 31), also set by SEQUENCE_FINAL and 0xFFFFFFFE
+        assert_eq!(None, RelativeLock::decode(2, SEQUENCE_FINAL));
+        assert_eq!(None, RelativeLock::decode(2, 0xFFFFFFFE));
+        assert_eq!(None, RelativeLock::decode(2, (1 << 31) | 10));
+        // Type flag (1 << 22) with 512 second units
+        assert_eq!(
+            Some(RelativeLock::Seconds(512)),
+            RelativeLock::decode(2, (1 << 22) | 1)
+        );
+        assert_eq!(
+            Some(RelativeLock::Seconds(0xFFFF * 512)),
+            RelativeLock::decode(2, 0x0040FFFF)
+        );
+        // Bits outside of the flags and the mask are ignored
+        assert_eq!(
+            Some(RelativeLock::Blocks(0xFFFF)),
+            RelativeLock::decode(2, 0x7FBFFFFF)
+        );
+        assert_eq!(
+            Some(RelativeLock::Seconds(1024)),
+            RelativeLock::decode(3, 0x00C10002)
+        );
+        let lock = RelativeLock::decode(2, (1 << 22) | 3).unwrap();
+        assert_eq!(("time", 1536), (lock.name(), lock.value()));
+    }
+
+    #[test]
+    fn test_is_final() {
+        let mut tx = new_tx(&[([1u8; 32], 0)], &[1000]).value;
+        assert!(tx.is_final(0, 0));
+
+        // Final sequence numbers disable the locktime
+        tx.locktime = 100;
+        assert!(!tx.uses_locktime());
+        assert!(tx.is_final(0, 0));
+
+        // Valid from the next block on
+        tx.inputs[0].seq_no = SEQUENCE_FINAL - 1;
+        assert!(tx.uses_locktime());
+        assert!(!tx.is_final(100, u32::MAX));
+        assert!(tx.is_final(101, 0));
+
+        tx.locktime = LOCKTIME_THRESHOLD + 100;
+        assert_eq!(LockTimeType::Time, tx.lock_time_type());
+        assert!(!tx.is_final(u64::MAX, LOCKTIME_THRESHOLD + 100));
+        assert!(tx.is_final(0, LOCKTIME_THRESHOLD + 101));
+    }
+}
@@ -2,10 +2,15 @@ use std::fmt;
 
 use crate::common::utils;
 
+pub mod auxpow;
 pub mod block;
 pub mod header;
+pub mod inscription;
+pub mod locktime;
 pub mod opcodes;
+pub mod opreturn;
 pub mod script;
+pub mod target;
 pub mod tx;
 pub mod varuint;
 
@@ -25,7 +30,7 @@ impl<T: ToRaw> Hashed<T> {
     #[inline]
     pub fn double_sha256(value: T) -> Hashed<T> {
         Hashed {
-            hash: utils::sha256(&utils::sha256(&value.to_bytes())),
+            hash: utils::sha256d(&value.to_bytes()),
             value,
         }
     }
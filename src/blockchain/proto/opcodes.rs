@@ -599,7 +599,7 @@ impl All {
             Class::PushBytes(*self as u32)
         // 60 opcodes
         } else {
-            Class::Ordinary(unsafe { transmute(*self) })
+            Class::Ordinary(unsafe { transmute::<All, Ordinary>(*self) })
         }
     }
 }
@@ -607,7 +607,7 @@ impl All {
 impl From<u8> for All {
     #[inline]
     fn from(b: u8) -> All {
-        unsafe { transmute(b) }
+        unsafe { transmute::<u8, All>(b) }
     }
 }
 
@@ -0,0 +1,275 @@
+//! Parsing and classification of OP_RETURN (null data) outputs.
+//!
+//! Protocols are recognized by the rules in `RULES`, the first matching rule wins.
+//! Payloads without known marker are classified as text if they are printable ASCII.
+
+use std::fmt;
+
+use crate::blockchain::proto::opcodes;
+
+const OP_RETURN: u8 = opcodes::All::OP_RETURN as u8;
+const OP_PUSHNUM_13: u8 = opcodes::All::OP_PUSHNUM_13 as u8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Protocol {
+    /// Segregated witness commitment in the coinbase (BIP141)
+    WitnessCommitment,
+    /// Runestone: OP_RETURN OP_13 <data>
+    Runes,
+    Omni,
+    OpenAssets,
+    /// Counterparty messages which are not obfuscated
+    Counterparty,
+    Text,
+    Empty,
+    Unknown,
+}
+
+impl Protocol {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Protocol::WitnessCommitment => "witness_commitment",
+            Protocol::Runes => "runes",
+            Protocol::Omni => "omni",
+            Protocol::OpenAssets => "openassets",
+            Protocol::Counterparty => "counterparty",
+            Protocol::Text => "text",
+            Protocol::Empty => "empty",
+            Protocol::Unknown => "unknown",
+        }
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+enum Marker {
+    /// Prefix of the concatenated pushes
+    Payload(&'static [u8]),
+    /// Prefix of the whole script, including OP_RETURN
+    Script(&'static [u8]),
+}
+
+struct Rule {
+    protocol: Protocol,
+    marker: Marker,
+    coinbase_only: bool,
+}
+
+const RULES: &[Rule] = &[
+    Rule {
+        protocol: Protocol::WitnessCommitment,
+        marker: Marker::Payload(&[0xaa, 0x21, 0xa9, 0xed]),
+        coinbase_only: true,
+    },
+    Rule {
+        protocol: Protocol::Runes,
+        marker: Marker::Script(&[OP_RETURN, OP_PUSHNUM_13]),
+        coinbase_only: false,
+    },
+    Rule {
+        protocol: Protocol::Omni,
+        marker: Marker::Payload(b"omni"),
+        coinbase_only: false,
+    },
+    Rule {
+        protocol: Protocol::OpenAssets,
+        // Marker and version 1.0
+        marker: Marker::Payload(b"OA\x01\x00"),
+        coinbase_only: false,
+    },
+    Rule {
+        protocol: Protocol::Counterparty,
+        marker: Marker::Payload(b"CNTRPRTY"),
+        coinbase_only: false,
+    },
+];
+
+/// Data pushed after OP_RETURN
+#[derive(Debug, PartialEq)]
+pub struct NullData {
+    /// Concatenation of all pushes
+    pub payload: Vec<u8>,
+    pub push_count: usize,
+}
+
+impl NullData {
+    /// Returns the pushed data if the script starts with OP_RETURN.
+    /// Opcodes which don't push data are skipped, a truncated push ends the payload.
+    pub fn parse(script: &[u8]) -> Option<Self> {
+        if script.first() != Some(&OP_RETURN) {
+            return None;
+        }
+        let mut data = NullData {
+            payload: Vec::new(),
+            push_count: 0,
+        };
+        let mut i = 1;
+        while i < script.len() {
+            let opcode = script[i];
+            i += 1;
+            let (len_size, len) = match opcode {
+                0x00..=0x4b => (0, opcode as usize),
+                0x4c => (1, read_len(&script[i..], 1)),
+                0x4d => (2, read_len(&script[i..], 2)),
+                0x4e => (4, read_len(&script[i..], 4)),
+                _ => continue,
+            };
+            i += len_size;
+            if i > script.len() || i + len > script.len() {
+                break;
+            }
+            data.payload.extend_from_slice(&script[i..i + len]);
+            data.push_count += 1;
+            i += len;
+        }
+        Some(data)
+    }
+
+    pub fn classify(&self, script: &[u8], is_coinbase: bool) -> Protocol {
+        for rule in RULES {
+            if rule.coinbase_only && !is_coinbase {
+                continue;
+            }
+            let matches = match rule.marker {
+                Marker::Payload(prefix) => self.payload.starts_with(prefix),
+                Marker::Script(prefix) => script.starts_with(prefix),
+            };
+            if matches {
+                return rule.protocol;
+            }
+        }
+        if self.payload.is_empty() {
+            Protocol::Empty
+        } else if self
+            .payload
+            .iter()
+            .all(|b| (0x20..0x7f).contains(b) || b"\t\n\r".contains(b))
+        {
+            Protocol::Text
+        } else {
+            Protocol::Unknown
+        }
+    }
+
+    /// Returns the payload as string if it is valid UTF-8 without control characters
+    /// other than whitespace
+    pub fn text(&self) -> Option<&str> {
+        let text = std::str::from_utf8(&self.payload).ok()?;
+        if text.is_empty()
+            || text
+                .chars()
+                .any(|c| c.is_control() && !matches!(c, '\t' | '\n' | '\r'))
+        {
+            return None;
+        }
+        Some(text)
+    }
+}
+
+/// Little endian length of OP_PUSHDATA1/2/4, usize::MAX if truncated
+fn read_len(data: &[u8], n: usize) -> usize {
+    if data.len() < n {
+        return usize::MAX;
+    }
+    data[..n]
+        .iter()
+        .rev()
+        .fold(0usize, |len, b| (len << 8) | *b as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::utils;
+
+    #[test]
+    fn test_parse() {
+        // (script, payload, push count)
+        let cases: &[(&str, &str, usize)] = &[
+            ("6a", "", 0),
+            ("6a00", "", 1),
+            ("6a0b68656c6c6f20776f726c64", "68656c6c6f20776f726c64", 1),
+            ("6a0201020103", "010203", 2),
+            ("6a4c03010203", "010203", 1),
+            ("6a4d0300010203", "010203", 1),
+            ("6a4e03000000010203", "010203", 1),
+            // OP_13 is skipped
+            ("6a5d0401020304", "01020304", 1),
+            // Truncated push
+            ("6a01aa05bbcc", "aa", 1),
+            ("6a4d03", "", 0),
+        ];
+        for (script, payload, push_count) in cases {
+            let data = NullData::parse(&utils::hex_to_vec(script)).unwrap();
+            assert_eq!(utils::hex_to_vec(payload), data.payload, "{}", script);
+            assert_eq!(*push_count, data.push_count, "{}", script);
+        }
+        assert_eq!(None, NullData::parse(&utils::hex_to_vec("51")));
+        assert_eq!(None, NullData::parse(&[]));
+    }
+
+    #[test]
+    fn test_classify() {
+        // (script, is_coinbase, protocol)
+        let cases: &[(&str, bool, Protocol)] = &[
+            (
+                "6a24aa21a9ede2f61c3f71d1defd3fa999dfa36953755c690689799962b48bebd836974e8cf9",
+                true,
+                Protocol::WitnessCommitment,
+            ),
+            (
+                "6a24aa21a9ede2f61c3f71d1defd3fa999dfa36953755c690689799962b48bebd836974e8cf9",
+                false,
+                Protocol::Unknown,
+            ),
+            ("6a5d0b00c0a2330380cab5ee0101", false, Protocol::Runes),
+            (
+                "6a146f6d6e69000000000000001f000000002faf0800",
+                false,
+                Protocol::Omni,
+            ),
+            ("6a084f41010001904e00", false, Protocol::OpenAssets),
+            (
+                "6a1d434e545250525459000000000000000000000000000000000000000000",
+                false,
+                Protocol::Counterparty,
+            ),
+            // Split over two pushes
+            ("6a04434e5452045052545900", false, Protocol::Counterparty),
+            ("6a0b68656c6c6f20776f726c64", false, Protocol::Text),
+            ("6a", true, Protocol::Empty),
+            ("6a0400ff1234", false, Protocol::Unknown),
+        ];
+        for (script, is_coinbase, protocol) in cases {
+            let script = utils::hex_to_vec(script);
+            let data = NullData::parse(&script).unwrap();
+            assert_eq!(
+                *protocol,
+                data.classify(&script, *is_coinbase),
+                "{}",
+                utils::arr_to_hex(&script)
+            );
+        }
+    }
+
+    #[test]
+    fn test_text() {
+        let text = |payload: &[u8]| {
+            NullData {
+                payload: payload.to_vec(),
+                push_count: 1,
+            }
+            .text()
+            .map(String::from)
+        };
+        assert_eq!(Some(String::from("hello\nworld")), text(b"hello\nworld"));
+        assert_eq!(Some(String::from("比特币")), text("比特币".as_bytes()));
+        assert_eq!(None, text(b"omni\x00\x00"));
+        assert_eq!(None, text(&[0xff, 0xfe]));
+        assert_eq!(None, text(b""));
+    }
+}
@@ -1,10 +1,13 @@
 use std::convert::From;
-use std::error::{self, Error};
-use std::fmt;
+use std::error;
+use std::fmt::{self, Write as _};
 
 use rust_base58::ToBase58;
 
 use crate::blockchain::proto::opcodes;
+use crate::common::bech32;
+use crate::common::cashaddr;
+use crate::common::secp256k1::PublicKey;
 use crate::common::utils;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
@@ -15,19 +18,15 @@ pub enum ScriptError {
 
 impl fmt::Display for ScriptError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.description())
-    }
-}
-
-impl error::Error for ScriptError {
-    fn description(&self) -> &str {
         match *self {
-            ScriptError::UnexpectedEof => "Unexpected EOF",
-            ScriptError::InvalidFormat => "Invalid Script format",
+            ScriptError::UnexpectedEof => write!(f, "Unexpected EOF"),
+            ScriptError::InvalidFormat => write!(f, "Invalid Script format"),
         }
     }
 }
 
+impl error::Error for ScriptError {}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub enum ScriptPattern {
     /// Null Data
@@ -44,8 +43,14 @@ pub enum ScriptPattern {
     /// Pay to Public Key (p2pk) scripts are a simplified form of the p2pkh,
     /// but aren't commonly used in new transactions anymore,
     /// because p2pkh scripts are more secure (the public key is not revealed until the output is spent).
+    /// Pubkey script: <33 byte compressed or 65 byte uncompressed pubkey> OP_CHECKSIG
+    /// The address is the P2PKH address of the key.
     Pay2PublicKey,
 
+    /// A p2pk script whose key has an invalid prefix or is not on the curve, the output can't be spent.
+    /// Like Bitcoin Core's address index the key is hashed to a P2PKH address anyway.
+    Pay2InvalidPublicKey,
+
     /// Pay to Public Key Hash (p2pkh)
     /// This is the most commonly used transaction output script.
     /// It's used to pay to a bitcoin address (a bitcoin address is a public key hash encoded in base58check)
@@ -57,6 +62,24 @@ pub enum ScriptPattern {
     /// Signature script: <sig>[sig][sig...] <redeemScript>
     Pay2ScriptHash,
 
+    /// Pay to Witness Public Key Hash [p2wpkh/BIP141]
+    /// Pubkey script: OP_0 <20 byte Hash160(pubkey)>
+    /// The address is the witness program encoded with bech32 [BIP173]
+    WitnessV0Keyhash,
+
+    /// Pay to Witness Script Hash [p2wsh/BIP141]
+    /// Pubkey script: OP_0 <32 byte SHA256(witnessScript)>
+    WitnessV0Scripthash,
+
+    /// Pay to Taproot [p2tr/BIP341]
+    /// Pubkey script: OP_1 <32 byte x-only output key>
+    /// The address is encoded with bech32m [BIP350], like those of all later witness versions
+    WitnessV1Taproot,
+
+    /// Witness programs of versions without defined semantics (yet), anyone can spend them.
+    /// Pubkey script: OP_1 to OP_16 <2 to 40 bytes>, except the 32 byte taproot programs
+    WitnessUnknown(u8),
+
     /// Sign Multisig script [BIP11]
     //SignMultiSig,
 
@@ -75,6 +98,9 @@ pub enum ScriptPattern {
     NotRecognised,
 
     Error(ScriptError),
+
+    /// The script wasn't evaluated because no callback needs it, see `AddressFormat::evaluate_scripts`
+    NotEvaluated,
 }
 
 impl fmt::Display for ScriptPattern {
@@ -83,10 +109,47 @@ impl fmt::Display for ScriptPattern {
             ScriptPattern::DataOutput(_) => write!(f, "DataOutput (OP_RETURN)"),
             ScriptPattern::Pay2MultiSig => write!(f, "Pay2MultiSig"),
             ScriptPattern::Pay2PublicKey => write!(f, "Pay2PublicKey"),
+            ScriptPattern::Pay2InvalidPublicKey => write!(f, "Pay2InvalidPublicKey"),
             ScriptPattern::Pay2PublicKeyHash => write!(f, "Pay2PublicKeyHash"),
             ScriptPattern::Pay2ScriptHash => write!(f, "Pay2ScriptHash"),
+            ScriptPattern::WitnessV0Keyhash => write!(f, "WitnessV0Keyhash"),
+            ScriptPattern::WitnessV0Scripthash => write!(f, "WitnessV0Scripthash"),
+            ScriptPattern::WitnessV1Taproot => write!(f, "WitnessV1Taproot"),
+            ScriptPattern::WitnessUnknown(version) => write!(f, "WitnessUnknown (v{})", version),
             ScriptPattern::NotRecognised => write!(f, "NotRecognised"),
             ScriptPattern::Error(ref err) => write!(f, "ScriptError: {}", err),
+            ScriptPattern::NotEvaluated => write!(f, "NotEvaluated"),
+        }
+    }
+}
+
+impl ScriptPattern {
+    /// Returns a short machine readable name, following the script types of Bitcoin Core
+    pub fn type_name(&self) -> &'static str {
+        match *self {
+            ScriptPattern::DataOutput(_) => "nulldata",
+            ScriptPattern::Pay2MultiSig => "multisig",
+            ScriptPattern::Pay2PublicKey => "pubkey",
+            ScriptPattern::Pay2InvalidPublicKey => "invalid_pubkey",
+            ScriptPattern::Pay2PublicKeyHash => "pubkeyhash",
+            ScriptPattern::Pay2ScriptHash => "scripthash",
+            ScriptPattern::WitnessV0Keyhash => "witness_v0_keyhash",
+            ScriptPattern::WitnessV0Scripthash => "witness_v0_scripthash",
+            ScriptPattern::WitnessV1Taproot => "witness_v1_taproot",
+            ScriptPattern::WitnessUnknown(_) => "witness_unknown",
+            ScriptPattern::NotRecognised => "nonstandard",
+            ScriptPattern::Error(_) => "error",
+            ScriptPattern::NotEvaluated => "unknown",
+        }
+    }
+
+    /// Returns the witness version of witness program outputs
+    pub fn witness_version(&self) -> Option<u8> {
+        match *self {
+            ScriptPattern::WitnessV0Keyhash | ScriptPattern::WitnessV0Scripthash => Some(0),
+            ScriptPattern::WitnessV1Taproot => Some(1),
+            ScriptPattern::WitnessUnknown(version) => Some(version),
+            _ => None,
         }
     }
 }
@@ -128,7 +191,7 @@ impl fmt::Debug for StackElement {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             StackElement::Op(ref op) => write!(f, "{:?}", &op),
-            StackElement::Data(ref d) => write!(f, "{}", &utils::arr_to_hex(&d)),
+            StackElement::Data(ref d) => write!(f, "{}", &utils::arr_to_hex(d)),
         }
     }
 }
@@ -167,7 +230,7 @@ pub struct ScriptEvaluator<'a> {
 
 impl<'a> ScriptEvaluator<'a> {
     #[inline]
-    pub fn new(bytes: &'a [u8]) -> ScriptEvaluator {
+    pub fn new(bytes: &'a [u8]) -> ScriptEvaluator<'a> {
         ScriptEvaluator {
             bytes,
             n_bytes: bytes.len(),
@@ -202,7 +265,7 @@ impl<'a> ScriptEvaluator<'a> {
             }
         }
         //println!(")\n");
-        let pattern = ScriptEvaluator::eval_script_pattern(&elements);
+        let pattern = ScriptEvaluator::eval_script_pattern(&elements, self.n_bytes);
         Ok(Stack { elements, pattern })
     }
 
@@ -247,7 +310,7 @@ impl<'a> ScriptEvaluator<'a> {
         Ok(data_len)
     }
 
-    fn eval_script_pattern(elements: &[StackElement]) -> ScriptPattern {
+    fn eval_script_pattern(elements: &[StackElement], n_bytes: usize) -> ScriptPattern {
         // Pay to Public Key Hash (p2pkh)
         let p2pkh = [
             StackElement::Op(opcodes::All::OP_DUP),
@@ -256,17 +319,18 @@ impl<'a> ScriptEvaluator<'a> {
             StackElement::Op(opcodes::All::OP_EQUALVERIFY),
             StackElement::Op(opcodes::All::OP_CHECKSIG),
         ];
-        if ScriptEvaluator::match_stack_pattern(&elements, &p2pkh) {
+        if ScriptEvaluator::match_stack_pattern(elements, &p2pkh) {
             return ScriptPattern::Pay2PublicKeyHash;
         }
 
-        // Pay to Public Key (p2pk)
-        let p2pk = [
-            StackElement::Data(Vec::new()),
-            StackElement::Op(opcodes::All::OP_CHECKSIG),
-        ];
-        if ScriptEvaluator::match_stack_pattern(&elements, &p2pk) {
-            return ScriptPattern::Pay2PublicKey;
+        // Pay to Public Key (p2pk): a direct push of a compressed or uncompressed key
+        if let [StackElement::Data(key), StackElement::Op(opcodes::All::OP_CHECKSIG)] = elements {
+            if n_bytes == key.len() + 2 && (key.len() == 33 || key.len() == 65) {
+                if is_valid_public_key(key) {
+                    return ScriptPattern::Pay2PublicKey;
+                }
+                return ScriptPattern::Pay2InvalidPublicKey;
+            }
         }
 
         // Pay to Script Hash (p2sh)
@@ -275,17 +339,32 @@ impl<'a> ScriptEvaluator<'a> {
             StackElement::Data(Vec::new()),
             StackElement::Op(opcodes::All::OP_EQUAL),
         ];
-        if ScriptEvaluator::match_stack_pattern(&elements, &p2sh) {
+        if ScriptEvaluator::match_stack_pattern(elements, &p2sh) {
             return ScriptPattern::Pay2ScriptHash;
         }
 
+        // Witness programs [BIP141]: a version opcode followed by a direct push of 2 to 40 bytes,
+        // the version and the program length decide about the type
+        if let [StackElement::Op(version), StackElement::Data(program)] = elements {
+            if n_bytes == program.len() + 2 && (2..=40).contains(&program.len()) {
+                match (*version as u8, program.len()) {
+                    (0x00, 20) => return ScriptPattern::WitnessV0Keyhash,
+                    (0x00, 32) => return ScriptPattern::WitnessV0Scripthash,
+                    (0x51, 32) => return ScriptPattern::WitnessV1Taproot,
+                    // OP_1 to OP_16
+                    (v @ 0x51..=0x60, _) => return ScriptPattern::WitnessUnknown(v - 0x50),
+                    _ => (),
+                }
+            }
+        }
+
         // Data output
         // pubkey: OP_RETURN <0 to 40 bytes of data>
         let data_output = [
             StackElement::Op(opcodes::All::OP_RETURN),
             StackElement::Data(Vec::new()),
         ];
-        if ScriptEvaluator::match_stack_pattern(&elements, &data_output) {
+        if ScriptEvaluator::match_stack_pattern(elements, &data_output) {
             if let Ok(data) = elements[1].data() {
                 return ScriptPattern::DataOutput(String::from_utf8_lossy(&data).into_owned());
             } else {
@@ -302,7 +381,7 @@ impl<'a> ScriptEvaluator<'a> {
             StackElement::Op(opcodes::All::OP_PUSHNUM_3),
             StackElement::Op(opcodes::All::OP_CHECKMULTISIG),
         ];
-        if ScriptEvaluator::match_stack_pattern(&elements, &multisig_2n3) {
+        if ScriptEvaluator::match_stack_pattern(elements, &multisig_2n3) {
             return ScriptPattern::Pay2MultiSig;
         }
         /* TODO:
@@ -347,10 +426,72 @@ impl<'a> ScriptEvaluator<'a> {
     }
 }
 
+/// Address encodings of a coin, see `CoinType::address_format()`.
+/// The default encodes base58 addresses of Bitcoin only.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AddressFormat {
+    /// Base58 version byte of P2PKH addresses
+    pub version_id: u8,
+    /// Base58 version byte of P2SH addresses
+    pub p2sh_version: u8,
+    /// Human readable part of segwit addresses, None if the coin has no segwit
+    pub bech32_hrp: Option<&'static str>,
+    /// CashAddr prefix, replaces base58 for P2PKH and P2SH addresses if set
+    pub cashaddr_prefix: Option<&'static str>,
+    /// Two byte base58 prefixes of P2PKH and P2SH addresses (Zcash), replace the version bytes if set
+    pub base58_prefixes: Option<([u8; 2], [u8; 2])>,
+    /// Uses the hex encoded public key of P2PK outputs instead of its P2PKH address
+    pub p2pk_as_pubkey: bool,
+    /// False skips the evaluation of output scripts while parsing, their pattern is `NotEvaluated`
+    /// and they have no address. See `Callback::wants_script_evaluation`.
+    pub evaluate_scripts: bool,
+}
+
+impl Default for AddressFormat {
+    fn default() -> Self {
+        AddressFormat {
+            version_id: 0x00,
+            p2sh_version: 0x05,
+            bech32_hrp: None,
+            cashaddr_prefix: None,
+            base58_prefixes: None,
+            p2pk_as_pubkey: false,
+            evaluate_scripts: true,
+        }
+    }
+}
+
+impl AddressFormat {
+    #[inline]
+    pub fn p2pkh(&self, h160: &[u8]) -> String {
+        match (self.cashaddr_prefix, self.base58_prefixes) {
+            (Some(prefix), _) => cashaddr::encode(prefix, cashaddr::P2PKH, h160),
+            (None, Some((p2pkh, _))) => hash_160_to_prefixed_address(h160, &p2pkh),
+            (None, None) => hash_160_to_address(h160, self.version_id),
+        }
+    }
+
+    #[inline]
+    pub fn p2sh(&self, h160: &[u8]) -> String {
+        match (self.cashaddr_prefix, self.base58_prefixes) {
+            (Some(prefix), _) => cashaddr::encode(prefix, cashaddr::P2SH, h160),
+            (None, Some((_, p2sh))) => hash_160_to_prefixed_address(h160, &p2sh),
+            (None, None) => hash_160_to_address(h160, self.p2sh_version),
+        }
+    }
+
+    /// Bech32 for version 0, bech32m for all later versions
+    #[inline]
+    pub fn segwit(&self, version: u8, program: &[u8]) -> Option<String> {
+        self.bech32_hrp
+            .map(|hrp| bech32::encode_segwit(hrp, version, program))
+    }
+}
+
 /// Extracts evaluated address from ScriptPubKey
-pub fn eval_from_bytes(bytes: &[u8], version_id: u8) -> EvaluatedScript {
+pub fn eval_from_bytes(bytes: &[u8], format: AddressFormat) -> EvaluatedScript {
     match ScriptEvaluator::new(bytes).eval() {
-        Ok(stack) => eval_from_stack(stack, version_id),
+        Ok(stack) => eval_from_stack(stack, format),
         Err(err) => EvaluatedScript {
             address: None,
             pattern: ScriptPattern::Error(err),
@@ -359,28 +500,44 @@ pub fn eval_from_bytes(bytes: &[u8], version_id: u8) -> EvaluatedScript {
 }
 
 /// Extracts evaluated address from script stack
-pub fn eval_from_stack(stack: Stack, version_id: u8) -> EvaluatedScript {
+pub fn eval_from_stack(stack: Stack, format: AddressFormat) -> EvaluatedScript {
     // Wrap everything in a closure to early catch try!()
     match (|| -> Result<EvaluatedScript, ScriptError> {
         let script = match stack.pattern {
-            ref p @ ScriptPattern::Pay2PublicKey => {
+            ref p @ (ScriptPattern::Pay2PublicKey | ScriptPattern::Pay2InvalidPublicKey) => {
                 let pub_key = stack.elements[0].data()?;
+                let address = if format.p2pk_as_pubkey {
+                    utils::arr_to_hex(&pub_key)
+                } else {
+                    format.p2pkh(&utils::ridemp160(&utils::sha256(&pub_key)))
+                };
                 EvaluatedScript {
-                    address: Some(public_key_to_addr(&pub_key, version_id)),
+                    address: Some(address),
                     pattern: p.clone(),
                 }
             }
             ref p @ ScriptPattern::Pay2PublicKeyHash => {
                 let h160 = stack.elements[2].data()?;
                 EvaluatedScript {
-                    address: Some(hash_160_to_address(&h160, version_id)),
+                    address: Some(format.p2pkh(&h160)),
                     pattern: p.clone(),
                 }
             }
             ref p @ ScriptPattern::Pay2ScriptHash => {
                 let h160 = stack.elements[1].data()?;
                 EvaluatedScript {
-                    address: Some(hash_160_to_address(&h160, 5)),
+                    address: Some(format.p2sh(&h160)),
+                    pattern: p.clone(),
+                }
+            }
+            ref p @ (ScriptPattern::WitnessV0Keyhash
+            | ScriptPattern::WitnessV0Scripthash
+            | ScriptPattern::WitnessV1Taproot
+            | ScriptPattern::WitnessUnknown(_)) => {
+                let program = stack.elements[1].data()?;
+                let version = p.witness_version().unwrap_or_default();
+                EvaluatedScript {
+                    address: format.segwit(version, &program),
                     pattern: p.clone(),
                 }
             }
@@ -414,26 +571,350 @@ pub fn eval_from_stack(stack: Stack, version_id: u8) -> EvaluatedScript {
     }
 }
 
-/// Takes full ECDSA public key (65 bytes) and a version id
-fn public_key_to_addr(pub_key: &[u8], version: u8) -> String {
-    let h160 = utils::ridemp160(&utils::sha256(pub_key));
-    hash_160_to_address(&h160, version)
-}
-
 /// Takes 20 byte public key and version id
 #[inline]
-fn hash_160_to_address(h160: &[u8], version: u8) -> String {
-    let mut hash = Vec::with_capacity(h160.len() + 5);
-    hash.push(version);
-    hash.extend_from_slice(&h160);
-    let checksum = &utils::sha256(&utils::sha256(&hash))[0..4];
+pub fn hash_160_to_address(h160: &[u8], version: u8) -> String {
+    hash_160_to_prefixed_address(h160, &[version])
+}
+
+/// Takes 20 byte public key and a version prefix of any length
+pub fn hash_160_to_prefixed_address(h160: &[u8], prefix: &[u8]) -> String {
+    let mut hash = Vec::with_capacity(prefix.len() + h160.len() + 4);
+    hash.extend_from_slice(prefix);
+    hash.extend_from_slice(h160);
+    let checksum = &utils::sha256d(&hash)[0..4];
     hash.extend_from_slice(checksum);
     hash.to_base58()
 }
 
+/// Disassembles a script like the `asm` fields of Bitcoin Core: pushes of up to 4 bytes as numbers,
+/// longer pushes as hex and opcodes by name. With `decode_sighash` (used for scriptSigs)
+/// signatures end with their sighash type instead of the last byte, e.g. `[ALL]`.
+pub fn to_asm(bytes: &[u8], decode_sighash: bool) -> String {
+    // Unspendable scripts can't contain signatures
+    let decode_sighash = decode_sighash
+        && bytes.first() != Some(&(opcodes::All::OP_RETURN as u8))
+        && bytes.len() <= 10000;
+    let mut asm = String::with_capacity(bytes.len() * 2);
+    let mut ip = 0;
+    while ip < bytes.len() {
+        if !asm.is_empty() {
+            asm.push(' ');
+        }
+        let data = match read_op(bytes, &mut ip) {
+            Ok((opcode, None)) => {
+                asm.push_str(&asm_name(opcodes::All::from(opcode)));
+                continue;
+            }
+            Ok((_, Some(data))) => data,
+            Err(_) => {
+                asm.push_str("[error]");
+                break;
+            }
+        };
+        if data.len() <= 4 {
+            asm.push_str(&script_num(data).to_string());
+        } else if decode_sighash && is_valid_signature_encoding(data) {
+            let hash_type = match data[data.len() - 1] {
+                0x01 => Some("[ALL]"),
+                0x81 => Some("[ALL|ANYONECANPAY]"),
+                0x02 => Some("[NONE]"),
+                0x82 => Some("[NONE|ANYONECANPAY]"),
+                0x03 => Some("[SINGLE]"),
+                0x83 => Some("[SINGLE|ANYONECANPAY]"),
+                _ => None,
+            };
+            match hash_type {
+                Some(hash_type) => {
+                    asm.push_str(&utils::arr_to_hex(&data[..data.len() - 1]));
+                    asm.push_str(hash_type);
+                }
+                None => asm.push_str(&utils::arr_to_hex(data)),
+            }
+        } else {
+            asm.push_str(&utils::arr_to_hex(data));
+        }
+    }
+    asm
+}
+
+/// Normalizes a script into a template shared by all scripts which only differ in the pushed
+/// data: opcodes by name like `to_asm()`, pushes as `<pushN>` with their length in bytes, e.g.
+/// `OP_DUP OP_HASH160 <push20> OP_EQUALVERIFY OP_CHECKSIG`. Undecodable rest ends in `[error]`.
+pub fn to_template(bytes: &[u8]) -> String {
+    let mut template = String::new();
+    let mut ip = 0;
+    while ip < bytes.len() {
+        if !template.is_empty() {
+            template.push(' ');
+        }
+        match read_op(bytes, &mut ip) {
+            Ok((opcode, None)) => template.push_str(&asm_name(opcodes::All::from(opcode))),
+            // OP_0 pushes nothing
+            Ok((_, Some([]))) => template.push('0'),
+            Ok((_, Some(data))) => {
+                let _ = write!(template, "<push{}>", data.len());
+            }
+            Err(_) => {
+                template.push_str("[error]");
+                break;
+            }
+        }
+    }
+    template
+}
+
+/// Reads the opcode at `ip` and the bytes it pushes, `None` for opcodes which are no pushes
+pub(crate) fn read_op<'a>(
+    bytes: &'a [u8],
+    ip: &mut usize,
+) -> Result<(u8, Option<&'a [u8]>), ScriptError> {
+    let opcode = bytes[*ip];
+    *ip += 1;
+    if opcode > opcodes::All::OP_PUSHDATA4 as u8 {
+        return Ok((opcode, None));
+    }
+    let data_len = match opcode {
+        0x4c => ScriptEvaluator::read_uint(&bytes[*ip..], 1)?,
+        0x4d => ScriptEvaluator::read_uint(&bytes[*ip..], 2)?,
+        0x4e => ScriptEvaluator::read_uint(&bytes[*ip..], 4)?,
+        n => n as usize,
+    };
+    *ip += match opcode {
+        0x4c => 1,
+        0x4d => 2,
+        0x4e => 4,
+        _ => 0,
+    };
+    if bytes.len() - *ip < data_len {
+        return Err(ScriptError::UnexpectedEof);
+    }
+    let data = &bytes[*ip..*ip + data_len];
+    *ip += data_len;
+    Ok((opcode, Some(data)))
+}
+
+/// Parameters of a `<m> <pubkey>... <n> OP_CHECKMULTISIG` script
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MultiSig {
+    /// Number of required signatures (m)
+    pub required: u8,
+    /// Public keys in script order, n = `pubkeys.len()`
+    pub pubkeys: Vec<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MultiSigClass {
+    MultiSig(MultiSig),
+    /// Uses OP_CHECKMULTISIG(VERIFY), but is no plain multisig script,
+    /// e.g. a larger script or invalid m-of-n parameters
+    Embedded,
+    NoMultiSig,
+}
+
+/// Classifies a script like the multisig template of Bitcoin Core: m and n from 1 to 16 as
+/// small numbers, m <= n and n pushes of 33 or 65 bytes. Fails on truncated pushes.
+pub fn parse_multisig(bytes: &[u8]) -> Result<MultiSigClass, ScriptError> {
+    let mut ops = Vec::new();
+    let mut ip = 0;
+    while ip < bytes.len() {
+        ops.push(read_op(bytes, &mut ip)?);
+    }
+    let small_int = |opcode: u8| match opcodes::All::from(opcode).classify() {
+        opcodes::Class::PushNum(n) if (1..=16).contains(&n) => Some(n as usize),
+        _ => None,
+    };
+    if let [(m, None), keys @ .., (n, None), (0xae, None)] = ops.as_slice() {
+        let pubkeys: Vec<Vec<u8>> = keys
+            .iter()
+            .filter_map(|(_, data)| *data)
+            .filter(|data| data.len() == 33 || data.len() == 65)
+            .map(Vec::from)
+            .collect();
+        if let (Some(m), Some(n)) = (small_int(*m), small_int(*n)) {
+            if m <= n && n == keys.len() && n == pubkeys.len() {
+                return Ok(MultiSigClass::MultiSig(MultiSig {
+                    required: m as u8,
+                    pubkeys,
+                }));
+            }
+        }
+    }
+    let uses_multisig = ops.iter().any(|op| {
+        *op == (opcodes::All::OP_CHECKMULTISIG as u8, None)
+            || *op == (opcodes::All::OP_CHECKMULTISIGVERIFY as u8, None)
+    });
+    if uses_multisig {
+        Ok(MultiSigClass::Embedded)
+    } else {
+        Ok(MultiSigClass::NoMultiSig)
+    }
+}
+
+/// Returns the bytes of the last push of a push only script (e.g. the redeem script of a
+/// P2SH scriptSig), `None` if the script is empty or ends with a small number.
+/// Fails with `InvalidFormat` if the script contains other opcodes.
+pub fn last_push(bytes: &[u8]) -> Result<Option<&[u8]>, ScriptError> {
+    let mut last = None;
+    let mut ip = 0;
+    while ip < bytes.len() {
+        last = match read_op(bytes, &mut ip)? {
+            (_, Some(data)) => Some(data),
+            (opcode, None) if opcode <= opcodes::All::OP_PUSHNUM_16 as u8 => None,
+            _ => return Err(ScriptError::InvalidFormat),
+        };
+    }
+    Ok(last)
+}
+
+/// Script revealed by the spend of a P2SH or P2WSH output
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RevealedScript {
+    /// `type_name()` of the script pattern, `multisig` for all m-of-n scripts
+    pub script_type: &'static str,
+    pub script: Vec<u8>,
+}
+
+/// Larger scripts are not classified, witness scripts are limited to 10000 bytes (BIP141)
+const MAX_REVEALED_SCRIPT_SIZE: usize = 10000;
+
+/// Extracts and classifies the redeem script of P2SH spends (the last push of the scriptSig)
+/// or the witness script of P2WSH spends (the last witness item). Without the spent output
+/// this is a heuristic: inputs whose last push is a public key or a signature (P2PKH, P2PK)
+/// and witnesses of P2WPKH and taproot spends reveal nothing.
+pub fn reveal_script(script_sig: &[u8], witness: &[Vec<u8>]) -> Option<RevealedScript> {
+    let script: &[u8] = match last_push(script_sig) {
+        // P2SH-P2WSH, the redeem script is the witness program
+        Ok(Some(push)) if push.len() == 34 && push[..2] == [0x00, 0x20] && !witness.is_empty() => {
+            witness.last()?
+        }
+        Ok(Some(push)) if is_public_key(push) || is_valid_signature_encoding(push) => return None,
+        Ok(Some(push)) => push,
+        Ok(None) if script_sig.is_empty() => match witness {
+            // P2WPKH
+            [_, key] if key.len() == 33 && (key[0] == 0x02 || key[0] == 0x03) => return None,
+            // Taproot key path spend
+            [sig] if sig.len() == 64 || sig.len() == 65 => return None,
+            // Only taproot has an annex
+            [_, .., annex] if annex.first() == Some(&0x50) => return None,
+            // Taproot script path spend with the control block
+            [_, .., control]
+                if control.len() >= 33
+                    && (control.len() - 33) % 32 == 0
+                    && control[0] & 0xfe == 0xc0 =>
+            {
+                return None
+            }
+            [.., script] => script,
+            [] => return None,
+        },
+        _ => return None,
+    };
+    if script.is_empty() || script.len() > MAX_REVEALED_SCRIPT_SIZE {
+        return None;
+    }
+    let script_type = match parse_multisig(script) {
+        Ok(MultiSigClass::MultiSig(_)) => "multisig",
+        _ => match ScriptEvaluator::new(script).eval() {
+            Ok(stack) => match stack.pattern {
+                ScriptPattern::Error(_) => "nonstandard",
+                pattern => pattern.type_name(),
+            },
+            Err(_) => "nonstandard",
+        },
+    };
+    Some(RevealedScript {
+        script_type,
+        script: script.to_vec(),
+    })
+}
+
+/// Compressed, uncompressed or hybrid public key
+#[inline]
+fn is_public_key(data: &[u8]) -> bool {
+    match data.len() {
+        33 => data[0] == 0x02 || data[0] == 0x03,
+        65 => data[0] == 0x04 || data[0] == 0x06 || data[0] == 0x07,
+        _ => false,
+    }
+}
+
+/// Public key with a valid prefix and a point on the curve.
+/// Hybrid keys (0x06/0x07) carry the parity of y in their prefix, which has to match.
+fn is_valid_public_key(data: &[u8]) -> bool {
+    if !is_public_key(data) {
+        return false;
+    }
+    match data[0] {
+        0x06 | 0x07 => {
+            let mut uncompressed = data.to_vec();
+            uncompressed[0] = 0x04;
+            data[64] & 1 == data[0] & 1 && PublicKey::from_slice(&uncompressed).is_some()
+        }
+        _ => PublicKey::from_slice(data).is_some(),
+    }
+}
+
+/// Opcode names of Bitcoin Core, small numbers are written as such
+fn asm_name(opcode: opcodes::All) -> String {
+    match opcode.classify() {
+        opcodes::Class::PushNum(n) => n.to_string(),
+        _ => match opcode {
+            opcodes::All::OP_NOP2 => String::from("OP_CHECKLOCKTIMEVERIFY"),
+            opcodes::All::OP_NOP3 => String::from("OP_CHECKSEQUENCEVERIFY"),
+            opcodes::All::OP_RETURN_186 => String::from("OP_CHECKSIGADD"),
+            op if op as u8 > opcodes::All::OP_RETURN_186 as u8 => String::from("OP_UNKNOWN"),
+            op => format!("{:?}", op),
+        },
+    }
+}
+
+/// Decodes a little endian number with the sign in the most significant bit
+fn script_num(data: &[u8]) -> i64 {
+    let mut n: i64 = 0;
+    for (i, byte) in data.iter().enumerate() {
+        n |= (*byte as i64) << (8 * i);
+    }
+    match data.last() {
+        Some(last) if last & 0x80 != 0 => -(n & !(0x80 << (8 * (data.len() - 1)))),
+        _ => n,
+    }
+}
+
+/// Checks for a strict DER signature followed by the sighash type (BIP66)
+fn is_valid_signature_encoding(sig: &[u8]) -> bool {
+    let len = sig.len();
+    if !(9..=73).contains(&len) || sig[0] != 0x30 || sig[1] as usize != len - 3 {
+        return false;
+    }
+    let len_r = sig[3] as usize;
+    if 5 + len_r >= len {
+        return false;
+    }
+    let len_s = sig[5 + len_r] as usize;
+    if len_r + len_s + 7 != len {
+        return false;
+    }
+    let valid_int = |offset: usize, int_len: usize| {
+        sig[offset - 2] == 0x02
+            && int_len != 0
+            && sig[offset] & 0x80 == 0
+            && !(int_len > 1 && sig[offset] == 0x00 && sig[offset + 1] & 0x80 == 0)
+    };
+    valid_int(4, len_r) && valid_int(len_r + 6, len_s)
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{eval_from_bytes, eval_from_stack, ScriptError, ScriptEvaluator, ScriptPattern};
+    use super::{
+        eval_from_bytes, eval_from_stack, last_push, parse_multisig, reveal_script, to_asm,
+        to_template, AddressFormat, MultiSig, MultiSigClass, ScriptError, ScriptEvaluator,
+        ScriptPattern,
+    };
+    use crate::blockchain::parser::types::{
+        Bitcoin, BitcoinCash, CoinType, Dogecoin, Litecoin, TestNet3,
+    };
+    use crate::common::utils;
 
     #[test]
     fn test_bitcoin_script_p2pkh() {
@@ -450,7 +931,7 @@ mod tests {
             format!("{:?}", stack)
         );
 
-        let script = eval_from_stack(stack, 0x00);
+        let script = eval_from_stack(stack, AddressFormat::default());
         assert_eq!(
             script.address,
             Some(String::from("12higDjoCCNXSA95xZMWUdPvXNmkAduhWv"))
@@ -476,7 +957,7 @@ mod tests {
         assert_eq!("044bca633a91de10df85a63d0a24cb09783148fe0e16c92e937fc4491580c860757148effa0595a955f44078b48ba67fa198782e8bb68115da0daa8fde5301f7f9 OP_CHECKSIG",
             format!("{:?}", stack));
 
-        let script = eval_from_stack(stack, 0x00);
+        let script = eval_from_stack(stack, AddressFormat::default());
         assert_eq!(
             script.address,
             Some(String::from("1LEWwJkDj8xriE87ALzQYcHjTmD8aqDj1f"))
@@ -484,6 +965,81 @@ mod tests {
         assert_eq!(script.pattern, ScriptPattern::Pay2PublicKey);
     }
 
+    #[test]
+    fn test_bitcoin_script_p2pk_keys() {
+        let p2pk = |key: &str| {
+            let mut script = vec![(key.len() / 2) as u8];
+            script.extend(utils::hex_to_vec(key));
+            script.push(0xac);
+            script
+        };
+        let g = "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+        let g_y = "483ada7726a3c4655da4fbfc0e1108a8fd17b448a68554199c47d08ffb10d4b8";
+        let vectors = [
+            // Output of the genesis coinbase
+            (
+                String::from("04678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5f"),
+                ScriptPattern::Pay2PublicKey,
+                "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa",
+            ),
+            // Compressed generator point
+            (
+                format!("02{}", g),
+                ScriptPattern::Pay2PublicKey,
+                "1BgGZ9tcN4rm9KBzDn7KprQz87SZ26SAMH",
+            ),
+            // Hybrid key with an even y
+            (
+                format!("06{}{}", g, g_y),
+                ScriptPattern::Pay2PublicKey,
+                "1H7NX5uHwz2Ks5JSqeDcUpvRPNubMhLoLN",
+            ),
+            // Hybrid key with the wrong parity
+            (
+                format!("07{}{}", g, g_y),
+                ScriptPattern::Pay2InvalidPublicKey,
+                "",
+            ),
+            // Invalid prefix
+            (
+                format!("05{}", g),
+                ScriptPattern::Pay2InvalidPublicKey,
+                "18mAEQYtPJVcJ864uQw7F5Aty36GjBmr4m",
+            ),
+            // (1, 1) is not on the curve
+            (
+                format!("04{:0>64}{:0>64}", "01", "01"),
+                ScriptPattern::Pay2InvalidPublicKey,
+                "15aW4qVLMgQGk5Z7TfXUaoGeJvQvfUtwxd",
+            ),
+        ];
+        for (key, pattern, address) in vectors.iter() {
+            let script = eval_from_bytes(&p2pk(key), AddressFormat::default());
+            assert_eq!(*pattern, script.pattern, "{}", key);
+            if !address.is_empty() {
+                assert_eq!(Some(String::from(*address)), script.address);
+            }
+        }
+
+        // Keys are hashed deterministically, whether valid or not
+        let invalid = format!("07{}{}", g, g_y);
+        let script = eval_from_bytes(&p2pk(&invalid), AddressFormat::default());
+        let h160 = utils::ridemp160(&utils::sha256(&utils::hex_to_vec(&invalid)));
+        assert_eq!(script.address, Some(AddressFormat::default().p2pkh(&h160)));
+
+        // Only compressed or uncompressed keys
+        let script = eval_from_bytes(&p2pk(g), AddressFormat::default());
+        assert_eq!(ScriptPattern::NotRecognised, script.pattern);
+
+        let format = AddressFormat {
+            p2pk_as_pubkey: true,
+            ..AddressFormat::default()
+        };
+        let script = eval_from_bytes(&p2pk(&format!("02{}", g)), format);
+        assert_eq!(script.address, Some(format!("02{}", g)));
+        assert_eq!(ScriptPattern::Pay2PublicKey, script.pattern);
+    }
+
     #[test]
     fn test_bitcoin_script_p2ms() {
         // 2-of-3 Multi sig output
@@ -528,7 +1084,7 @@ mod tests {
             format!("{:?}", stack)
         );
 
-        let script = eval_from_stack(stack, 0x00);
+        let script = eval_from_stack(stack, AddressFormat::default());
         assert_eq!(
             script.address,
             Some(String::from("3P14159f73E4gFr7JterCCQh9QjiTjiZrG"))
@@ -536,6 +1092,53 @@ mod tests {
         assert_eq!(script.pattern, ScriptPattern::Pay2ScriptHash);
     }
 
+    #[test]
+    fn test_bitcoin_script_witness_v0() {
+        // Vectors from BIP173
+        let bitcoin = CoinType::from(Bitcoin).address_format();
+        let p2wpkh = utils::hex_to_vec("0014751e76e8199196d454941c45d1b3a323f1433bd6");
+        let stack = ScriptEvaluator::new(&p2wpkh).eval().unwrap();
+        assert_eq!(
+            "OP_PUSHBYTES_0 751e76e8199196d454941c45d1b3a323f1433bd6",
+            format!("{:?}", stack)
+        );
+        let script = eval_from_stack(stack, bitcoin);
+        assert_eq!(
+            script.address,
+            Some(String::from("bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4"))
+        );
+        assert_eq!(script.pattern, ScriptPattern::WitnessV0Keyhash);
+        assert_eq!(
+            Some(String::from("ltc1qw508d6qejxtdg4y5r3zarvary0c5xw7kgmn4n9")),
+            eval_from_bytes(&p2wpkh, CoinType::from(Litecoin).address_format()).address
+        );
+
+        let p2wsh = utils::hex_to_vec(
+            "00201863143c14c5166804bd19203356da136c985678cd4d27a1b8c6329604903262",
+        );
+        let script = eval_from_bytes(&p2wsh, CoinType::from(TestNet3).address_format());
+        assert_eq!(
+            script.address,
+            Some(String::from(
+                "tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7"
+            ))
+        );
+        assert_eq!(script.pattern, ScriptPattern::WitnessV0Scripthash);
+
+        // Coins without segwit have no address for it
+        let script = eval_from_bytes(&p2wsh, CoinType::from(Dogecoin).address_format());
+        assert_eq!(script.address, None);
+        assert_eq!(script.pattern, ScriptPattern::WitnessV0Scripthash);
+
+        // Version 0 programs must be 20 or 32 bytes long
+        let script = eval_from_bytes(
+            &utils::hex_to_vec("0010751e76e8199196d454941c45d1b3a323"),
+            bitcoin,
+        );
+        assert_eq!(script.address, None);
+        assert_eq!(script.pattern, ScriptPattern::NotRecognised);
+    }
+
     #[test]
     fn test_bitcoin_script_data_output() {
         // Raw output script: 6a13636861726c6579206c6f766573206865696469
@@ -551,7 +1154,7 @@ mod tests {
             format!("{:?}", stack)
         );
 
-        let script = eval_from_stack(stack, 0x00);
+        let script = eval_from_stack(stack, AddressFormat::default());
         assert_eq!(script.address, None);
         assert_eq!(
             script.pattern,
@@ -571,19 +1174,398 @@ mod tests {
             format!("{:?}", stack)
         );
 
-        let script = eval_from_stack(stack, 0x00);
+        let script = eval_from_stack(stack, AddressFormat::default());
         assert_eq!(script.address, None);
         assert_eq!(script.pattern, ScriptPattern::NotRecognised);
     }
 
+    #[test]
+    fn test_bitcoin_script_witness_v1_and_later() {
+        // Vectors from BIP350
+        let bitcoin = CoinType::from(Bitcoin).address_format();
+        let testnet = CoinType::from(TestNet3).address_format();
+        let expected = [
+            (
+                "512079be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+                bitcoin,
+                "bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqzk5jj0",
+                ScriptPattern::WitnessV1Taproot,
+            ),
+            (
+                "5120000000c4a5cad46221b2a187905e5266362b99d5e91c6ce24d165dab93e86433",
+                testnet,
+                "tb1pqqqqp399et2xygdj5xreqhjjvcmzhxw4aywxecjdzew6hylgvsesf3hn0c",
+                ScriptPattern::WitnessV1Taproot,
+            ),
+            (
+                "5128751e76e8199196d454941c45d1b3a323f1433bd6751e76e8199196d454941c45d1b3a323f1433bd6",
+                bitcoin,
+                "bc1pw508d6qejxtdg4y5r3zarvary0c5xw7kw508d6qejxtdg4y5r3zarvary0c5xw7kt5nd6y",
+                ScriptPattern::WitnessUnknown(1),
+            ),
+            (
+                "5210751e76e8199196d454941c45d1b3a323",
+                bitcoin,
+                "bc1zw508d6qejxtdg4y5r3zarvaryvaxxpcs",
+                ScriptPattern::WitnessUnknown(2),
+            ),
+            (
+                "6002751e",
+                bitcoin,
+                "bc1sw50qgdz25j",
+                ScriptPattern::WitnessUnknown(16),
+            ),
+        ];
+        for (script, format, address, pattern) in expected.iter() {
+            let script = eval_from_bytes(&utils::hex_to_vec(script), *format);
+            assert_eq!(Some(String::from(*address)), script.address);
+            assert_eq!(*pattern, script.pattern);
+        }
+
+        let taproot = utils::hex_to_vec(expected[0].0);
+        assert_eq!(
+            None,
+            eval_from_bytes(&taproot, CoinType::from(Dogecoin).address_format()).address
+        );
+        // At most 40 bytes
+        let too_long = utils::hex_to_vec(&format!("5129{}", "00".repeat(41)));
+        assert_eq!(
+            ScriptPattern::NotRecognised,
+            eval_from_bytes(&too_long, bitcoin).pattern
+        );
+    }
+
+    #[test]
+    fn test_bitcoin_cash_script() {
+        let mut format = CoinType::from(BitcoinCash).address_format();
+        let p2pkh = utils::hex_to_vec("76a91476a04053bda0a88bda5177b86a15c3b29f55987388ac");
+        let p2sh = utils::hex_to_vec("a9147f9b1a7fb68d60c536c2fd8aeaa53a8f3cc025a887");
+        assert_eq!(
+            Some(String::from(
+                "bitcoincash:qpm2qsznhks23z7629mms6s4cwef74vcwvy22gdx6a"
+            )),
+            eval_from_bytes(&p2pkh, format).address
+        );
+        assert_eq!(
+            Some(String::from(
+                "bitcoincash:pplekxnlk6xkp3fkct7c4649828nesp94qpdz9mjtx"
+            )),
+            eval_from_bytes(&p2sh, format).address
+        );
+
+        // Legacy addresses
+        format.cashaddr_prefix = None;
+        assert_eq!(
+            Some(String::from("1BpEi6DfDAUFd7GtittLSdBeYJvcoaVggu")),
+            eval_from_bytes(&p2pkh, format).address
+        );
+        assert_eq!(
+            Some(String::from("3DKjYgogiUuH2ngd4HZUqFJTgj7dxXXqGs")),
+            eval_from_bytes(&p2sh, format).address
+        );
+    }
+
+    #[test]
+    fn test_script_pattern_type_name() {
+        let expected = [
+            (ScriptPattern::DataOutput(String::from("data")), "nulldata"),
+            (ScriptPattern::Pay2MultiSig, "multisig"),
+            (ScriptPattern::Pay2PublicKey, "pubkey"),
+            (ScriptPattern::Pay2PublicKeyHash, "pubkeyhash"),
+            (ScriptPattern::Pay2ScriptHash, "scripthash"),
+            (ScriptPattern::WitnessV0Keyhash, "witness_v0_keyhash"),
+            (ScriptPattern::WitnessV0Scripthash, "witness_v0_scripthash"),
+            (ScriptPattern::WitnessV1Taproot, "witness_v1_taproot"),
+            (ScriptPattern::WitnessUnknown(2), "witness_unknown"),
+            (ScriptPattern::NotRecognised, "nonstandard"),
+            (ScriptPattern::Error(ScriptError::UnexpectedEof), "error"),
+            (ScriptPattern::Error(ScriptError::InvalidFormat), "error"),
+        ];
+        for (pattern, name) in expected.iter() {
+            assert_eq!(*name, pattern.type_name());
+        }
+    }
+
     #[test]
     fn test_bitcoin_bogus_script() {
         let bytes = [0x4c, 0xFF, 0x00];
-        let script = eval_from_bytes(&bytes, 0x00);
+        let script = eval_from_bytes(&bytes, AddressFormat::default());
         assert_eq!(script.address, None);
         assert_eq!(
             script.pattern,
             ScriptPattern::Error(ScriptError::UnexpectedEof)
         );
     }
+
+    #[test]
+    fn test_to_asm() {
+        // scriptSig of f4184fc596403b9d638783cf57adfe4c75c605f6356fbc91338530e9831e9e16
+        let script_sig = utils::hex_to_vec(concat!(
+            "47304402204e45e16932b8af514961a1d3a1a25fdf3f4f7732e9d624c6c61548ab5fb8cd410220181522",
+            "ec8eca07de4860a4acdd12909d831cc56cbbac4622082221a8768d1d0901",
+        ));
+        let sig = "304402204e45e16932b8af514961a1d3a1a25fdf3f4f7732e9d624c6c61548ab5fb8cd410220181522ec8eca07de4860a4acdd12909d831cc56cbbac4622082221a8768d1d09";
+        assert_eq!(format!("{}[ALL]", sig), to_asm(&script_sig, true));
+        assert_eq!(format!("{}01", sig), to_asm(&script_sig, false));
+
+        // Genesis coinbase: numbers are little endian with a sign bit
+        let coinbase = utils::hex_to_vec("04ffff001d0104");
+        assert_eq!("486604799 4", to_asm(&coinbase, true));
+        assert_eq!(
+            "0 -1 1 16 -127",
+            to_asm(&[0x00, 0x4f, 0x51, 0x60, 0x01, 0xff], false)
+        );
+
+        let p2pkh = utils::hex_to_vec("76a91412ab8dc588ca9d5787dde7eb29569da63c3a238c88ac");
+        assert_eq!(
+            "OP_DUP OP_HASH160 12ab8dc588ca9d5787dde7eb29569da63c3a238c OP_EQUALVERIFY OP_CHECKSIG",
+            to_asm(&p2pkh, false)
+        );
+        assert_eq!(
+            "OP_CHECKLOCKTIMEVERIFY OP_CHECKSEQUENCEVERIFY OP_NOP1 OP_CHECKSIGADD OP_UNKNOWN",
+            to_asm(&[0xb1, 0xb2, 0xb0, 0xba, 0xff], false)
+        );
+        assert_eq!(
+            "OP_RETURN 0b0c0d0e0f",
+            to_asm(&[0x6a, 0x05, 11, 12, 13, 14, 15], true)
+        );
+        assert_eq!("OP_DUP [error]", to_asm(&[0x76, 0x4c, 0xff, 0x00], false));
+        assert_eq!("[error]", to_asm(&[0x4d, 0x01], false));
+        assert_eq!("", to_asm(&[], false));
+    }
+
+    #[test]
+    fn test_to_template() {
+        let p2pkh = utils::hex_to_vec("76a91412ab8dc588ca9d5787dde7eb29569da63c3a238c88ac");
+        assert_eq!(
+            "OP_DUP OP_HASH160 <push20> OP_EQUALVERIFY OP_CHECKSIG",
+            to_template(&p2pkh)
+        );
+        // Scripts differing only in their data share the template
+        let other = utils::hex_to_vec("76a914000000000000000000000000000000000000000088ac");
+        assert_eq!(to_template(&p2pkh), to_template(&other));
+        // Small numbers are opcodes, OP_0 an empty push and OP_PUSHDATA1 a push like any other
+        assert_eq!(
+            "2 <push33> <push33> 2 OP_CHECKMULTISIG",
+            to_template(
+                &[
+                    &[0x52, 0x21][..],
+                    &[2; 33],
+                    &[0x21],
+                    &[3; 33],
+                    &[0x52, 0xae]
+                ]
+                .concat()
+            )
+        );
+        assert_eq!(
+            "0 -1 16 <push1>",
+            to_template(&[0x00, 0x4f, 0x60, 0x01, 0xff])
+        );
+        let pushdata = [&[0x4c, 0x50][..], &[0; 80]].concat();
+        assert_eq!(
+            "OP_RETURN <push80>",
+            to_template(&[&[0x6a][..], &pushdata].concat())
+        );
+        assert_eq!(
+            "OP_NOP <push2> [error]",
+            to_template(&[0x61, 0x02, 1, 2, 0x05, 1])
+        );
+        assert_eq!("", to_template(&[]));
+    }
+
+    /// `<m> <n pubkeys> <n> OP_CHECKMULTISIG` with keys of the given length
+    fn multisig_script(m: u8, n: u8, key_len: u8) -> (Vec<u8>, Vec<Vec<u8>>) {
+        let pubkeys: Vec<Vec<u8>> = (0..n).map(|i| vec![i; key_len as usize]).collect();
+        let mut script = vec![0x50 + m];
+        for pubkey in &pubkeys {
+            script.push(key_len);
+            script.extend_from_slice(pubkey);
+        }
+        script.extend_from_slice(&[0x50 + n, 0xae]);
+        (script, pubkeys)
+    }
+
+    #[test]
+    fn test_parse_multisig() {
+        for n in 1..=15 {
+            for m in 1..=n {
+                let (script, pubkeys) = multisig_script(m, n, 33);
+                // Up to 15 compressed keys fit into a P2SH redeem script
+                assert!(script.len() <= 520);
+                let expected = MultiSigClass::MultiSig(MultiSig {
+                    required: m,
+                    pubkeys,
+                });
+                assert_eq!(Ok(expected), parse_multisig(&script), "{}-of-{}", m, n);
+            }
+        }
+        let (script, pubkeys) = multisig_script(16, 16, 65);
+        assert_eq!(
+            Ok(MultiSigClass::MultiSig(MultiSig {
+                required: 16,
+                pubkeys
+            })),
+            parse_multisig(&script)
+        );
+
+        // 1-of-2 with uncompressed keys
+        let script = utils::hex_to_vec(concat!(
+            "514104cc71eb30d653c0c3163990c47b976f3fb3f37cccdcbedb169a1dfef58bbfbfaff7d8a473e7e2e6d3",
+            "17b87bafe8bde97e3cf8f065dec022b51d11fcdd0d348ac4410461cbdcc5409fb4b4d42b51d33381354d80",
+            "e550078cb532a34bfa2fcfdeb7d76519aecc62770f5b0e4ef8551946d8a540911abe3e7854a26f39f58b25",
+            "c15342af52ae",
+        ));
+        match parse_multisig(&script) {
+            Ok(MultiSigClass::MultiSig(multisig)) => {
+                assert_eq!(1, multisig.required);
+                assert_eq!(2, multisig.pubkeys.len());
+                assert_eq!(0x04, multisig.pubkeys[1][0]);
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_multisig_malformed() {
+        let (script, _) = multisig_script(2, 3, 33);
+        // Truncated key, direct push and PUSHDATA lengths beyond the end
+        assert_eq!(
+            Err(ScriptError::UnexpectedEof),
+            parse_multisig(&script[..50])
+        );
+        assert_eq!(
+            Err(ScriptError::UnexpectedEof),
+            parse_multisig(&[0x52, 0x21, 0x02])
+        );
+        assert_eq!(
+            Err(ScriptError::UnexpectedEof),
+            parse_multisig(&[0x52, 0x4c])
+        );
+        assert_eq!(
+            Err(ScriptError::UnexpectedEof),
+            parse_multisig(&[0x52, 0x4d, 0x00, 0x01, 0xae])
+        );
+        assert_eq!(
+            Err(ScriptError::UnexpectedEof),
+            parse_multisig(&[0x52, 0x4e, 0xff, 0xff, 0xff, 0xff, 0x00])
+        );
+
+        // Wrong parameters are no multisig
+        let (mut script, _) = multisig_script(3, 2, 33);
+        assert_eq!(Ok(MultiSigClass::Embedded), parse_multisig(&script));
+        script[0] = 0x00;
+        assert_eq!(Ok(MultiSigClass::Embedded), parse_multisig(&script));
+        let (mut script, _) = multisig_script(1, 2, 33);
+        script[69] = 0x53;
+        assert_eq!(Ok(MultiSigClass::Embedded), parse_multisig(&script));
+        let (script, _) = multisig_script(1, 2, 32);
+        assert_eq!(Ok(MultiSigClass::Embedded), parse_multisig(&script));
+        assert_eq!(
+            Ok(MultiSigClass::Embedded),
+            parse_multisig(&[0x51, 0x50, 0xae])
+        );
+
+        // OP_CHECKMULTISIG within larger scripts
+        let (multisig, _) = multisig_script(2, 2, 33);
+        let mut script = vec![0x63];
+        script.extend_from_slice(&multisig);
+        script.extend_from_slice(&[0x67, 0x51, 0x68]);
+        assert_eq!(Ok(MultiSigClass::Embedded), parse_multisig(&script));
+        let mut script = multisig.clone();
+        script[multisig.len() - 1] = 0xaf;
+        script.push(0x51);
+        assert_eq!(Ok(MultiSigClass::Embedded), parse_multisig(&script));
+
+        let p2pkh = utils::hex_to_vec("76a91412ab8dc588ca9d5787dde7eb29569da63c3a238c88ac");
+        assert_eq!(Ok(MultiSigClass::NoMultiSig), parse_multisig(&p2pkh));
+        assert_eq!(Ok(MultiSigClass::NoMultiSig), parse_multisig(&[]));
+        // The key data isn't interpreted
+        assert_eq!(
+            Ok(MultiSigClass::NoMultiSig),
+            parse_multisig(&[0x02, 0xae, 0xaf])
+        );
+    }
+
+    #[test]
+    fn test_last_push() {
+        let (redeem_script, _) = multisig_script(1, 1, 33);
+        let mut script_sig = vec![0x00, 0x02, 0xaa, 0xbb, 0x4c, redeem_script.len() as u8];
+        script_sig.extend_from_slice(&redeem_script);
+        assert_eq!(Ok(Some(&redeem_script[..])), last_push(&script_sig));
+        assert_eq!(Ok(Some(&[][..])), last_push(&[0x01, 0xaa, 0x00]));
+        assert_eq!(Ok(None), last_push(&[0x01, 0xaa, 0x51]));
+        assert_eq!(Ok(None), last_push(&[]));
+        assert_eq!(
+            Err(ScriptError::InvalidFormat),
+            last_push(&[0x01, 0xaa, 0x76])
+        );
+        assert_eq!(
+            Err(ScriptError::UnexpectedEof),
+            last_push(&[0x01, 0xaa, 0x02])
+        );
+    }
+
+    #[test]
+    fn test_reveal_script() {
+        // The 2-of-3 script of test_bitcoin_script_p2ms, key and signature of test_bitcoin_parse_segwit_tx
+        let multisig = "5221022df8750480ad5b26950b25c7ba79d3e37d75f640f8e5d9bcd5b150a0f85014da\
+                        2103e3818b65bcc73a7d64064106a859cc1a5a728c4345ff0b641209fba0d90de6e9\
+                        21021f2f6e1e50cb6a953935c3601284925decd3fd21bc445712576873fb8c6ebc1853ae";
+        let sig = "3045022100f764287d3e99b1474da9bec7f7ed236d6c81e793b20c4b5aa1f3051b9a7daa63\
+                   022016a198031d5554dbb855bdbe8534776a4be6958bd8d530dc001c32b828f6f0ab01";
+        let key = "038262a6c6cec93c2d3ecd6c6072efea86d02ff8e3328bbd0242b20af3425990ac";
+        let reveal = |script_sig: &str, witness: &[&str]| {
+            let witness: Vec<Vec<u8>> = witness.iter().map(|i| utils::hex_to_vec(i)).collect();
+            reveal_script(&utils::hex_to_vec(script_sig), &witness)
+                .map(|r| (r.script_type, utils::arr_to_hex(&r.script)))
+        };
+        let revealed_multisig = Some(("multisig", String::from(multisig)));
+
+        // P2SH multisig: OP_0 <sig> <sig> OP_PUSHDATA1 <redeem script>
+        let script_sig = format!("0048{}48{}4c69{}", sig, sig, multisig);
+        assert_eq!(revealed_multisig, reveal(&script_sig, &[]));
+        // P2WSH and P2SH-P2WSH
+        let witness = ["", sig, sig, multisig];
+        assert_eq!(revealed_multisig, reveal("", &witness));
+        let program = utils::arr_to_hex(&utils::sha256(&utils::hex_to_vec(multisig)));
+        assert_eq!(
+            revealed_multisig,
+            reveal(&format!("220020{}", program), &witness)
+        );
+        // P2SH-P2WPKH reveals the witness program
+        assert_eq!(
+            Some((
+                "witness_v0_keyhash",
+                String::from("00141d7cd6c75c2e86f4cbf98eaed221b30bd9a0b928")
+            )),
+            reveal(
+                "1600141d7cd6c75c2e86f4cbf98eaed221b30bd9a0b928",
+                &[sig, key]
+            )
+        );
+        // A hash puzzle
+        let puzzle = format!("a820{}87", "11".repeat(32));
+        assert_eq!(
+            Some(("nonstandard", puzzle.clone())),
+            reveal("", &["01", &puzzle])
+        );
+
+        // P2PKH, P2PK, P2WPKH, taproot key and script path spends
+        assert_eq!(None, reveal(&format!("48{}21{}", sig, key), &[]));
+        assert_eq!(None, reveal(&format!("48{}", sig), &[]));
+        assert_eq!(None, reveal("", &[sig, key]));
+        assert_eq!(None, reveal("", &[&"22".repeat(64)]));
+        let control_block = format!("c0{}", "33".repeat(32));
+        assert_eq!(None, reveal("", &[&"22".repeat(64), "51", &control_block]));
+        assert_eq!(None, reveal("", &[&"22".repeat(64), "50aa"]));
+        // Truncated pushes, empty and oversized scripts
+        assert_eq!(None, reveal("4c050102", &[]));
+        assert_eq!(None, reveal("", &[]));
+        assert_eq!(None, reveal("", &["", &"00".repeat(10001)]));
+        assert_eq!(
+            Some(("nonstandard", String::from("4c"))),
+            reveal("", &["", "4c"])
+        );
+    }
 }
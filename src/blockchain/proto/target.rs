@@ -0,0 +1,403 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::ops::Add;
+
+/// Hash function of the proof of work of a coin, see `Coin::pow_algorithm`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PowAlgorithm {
+    /// Double SHA256 of the header, the block hash
+    #[default]
+    Sha256d,
+    /// Not implemented, the proof of work is unchecked (Litecoin, Dogecoin)
+    Scrypt,
+    /// One of several algorithms selected by the block version, unchecked (Myriadcoin)
+    MultiAlgo,
+    /// The block hash must meet the target, the Equihash solution itself is unchecked (Zcash)
+    Equihash,
+}
+
+impl PowAlgorithm {
+    /// Returns true if `check_pow` applies to the block hash (or AuxPoW parent hash)
+    #[inline]
+    pub fn is_checked(&self) -> bool {
+        matches!(self, PowAlgorithm::Sha256d | PowAlgorithm::Equihash)
+    }
+}
+
+/// 256-bit unsigned integer as used for proof-of-work targets and chain work.
+/// Limbs are stored most significant first.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub struct U256([u64; 4]);
+
+impl U256 {
+    pub const ZERO: U256 = U256([0; 4]);
+
+    #[inline]
+    pub fn from_u64(value: u64) -> U256 {
+        U256([0, 0, 0, value])
+    }
+
+    /// Expands the compact representation (`nBits`) of a target.
+    /// Returns None for negative or overflowing values, like `arith_uint256::SetCompact()`.
+    pub fn from_compact(bits: u32) -> Option<U256> {
+        let exponent = bits >> 24;
+        let mut mantissa = bits & 0x007fffff;
+        if exponent <= 3 {
+            mantissa >>= 8 * (3 - exponent);
+        }
+        // The sign bit only matters if something is left of the mantissa
+        if mantissa != 0 && bits & 0x00800000 != 0 {
+            return None;
+        }
+        if exponent <= 3 {
+            return Some(U256::from_u64(mantissa as u64));
+        }
+        let shift = 8 * (exponent - 3);
+        let bit_len = 32 - mantissa.leading_zeros();
+        if mantissa != 0 && bit_len + shift > 256 {
+            return None;
+        }
+        Some(U256::from_u64(mantissa as u64).shl(shift))
+    }
+
+    /// Interprets a hash in internal byte order (reversed display order) as number
+    pub fn from_le_bytes(bytes: &[u8; 32]) -> U256 {
+        let mut limbs = [0u64; 4];
+        for (i, limb) in limbs.iter_mut().enumerate() {
+            let start = 32 - (i + 1) * 8;
+            *limb = u64::from_le_bytes(bytes[start..start + 8].try_into().unwrap());
+        }
+        U256(limbs)
+    }
+
+    /// Parses a big-endian hex string of up to 64 characters
+    pub fn from_hex(hex: &str) -> Option<U256> {
+        if hex.is_empty() || hex.len() > 64 {
+            return None;
+        }
+        let padded = format!("{:0>64}", hex);
+        let mut limbs = [0u64; 4];
+        for (i, limb) in limbs.iter_mut().enumerate() {
+            *limb = u64::from_str_radix(padded.get(i * 16..(i + 1) * 16)?, 16).ok()?;
+        }
+        Some(U256(limbs))
+    }
+
+    /// Returns the value as 64 char big-endian hex string
+    pub fn to_hex(&self) -> String {
+        self.0.iter().map(|limb| format!("{:016x}", limb)).collect()
+    }
+
+    /// Approximates the value as float
+    pub fn to_f64(&self) -> f64 {
+        self.0
+            .iter()
+            .fold(0.0, |acc, limb| acc * 18446744073709551616.0 + *limb as f64)
+    }
+
+    #[inline]
+    pub fn is_zero(&self) -> bool {
+        self.0 == [0; 4]
+    }
+
+    /// Expected number of hashes to find a block with this target, 2^256 / (target + 1).
+    /// Same as `GetBlockProof()` in Bitcoin Core.
+    pub fn work(&self) -> U256 {
+        if self.is_zero() {
+            return U256::ZERO;
+        }
+        // 2^256 doesn't fit, but 2^256 / (x + 1) == ~x / (x + 1) + 1
+        let (divisor, _) = self.overflowing_add(&U256::from_u64(1));
+        if divisor.is_zero() {
+            return U256::from_u64(1);
+        }
+        let (quotient, _) = self.not().div(&divisor).overflowing_add(&U256::from_u64(1));
+        quotient
+    }
+
+    fn not(&self) -> U256 {
+        let mut limbs = self.0;
+        limbs.iter_mut().for_each(|limb| *limb = !*limb);
+        U256(limbs)
+    }
+
+    fn shl(&self, shift: u32) -> U256 {
+        let mut limbs = [0u64; 4];
+        let (words, bits) = ((shift / 64) as usize, shift % 64);
+        for (i, limb) in limbs
+            .iter_mut()
+            .enumerate()
+            .take(4usize.saturating_sub(words))
+        {
+            // Source limb that ends up at position i
+            let src = i + words;
+            *limb = self.0[src] << bits;
+            if bits > 0 && src + 1 < 4 {
+                *limb |= self.0[src + 1] >> (64 - bits);
+            }
+        }
+        U256(limbs)
+    }
+
+    fn bit(&self, index: usize) -> bool {
+        self.0[3 - index / 64] >> (index % 64) & 1 == 1
+    }
+
+    fn set_bit(&mut self, index: usize) {
+        self.0[3 - index / 64] |= 1 << (index % 64);
+    }
+
+    fn overflowing_add(&self, other: &U256) -> (U256, bool) {
+        let mut limbs = [0u64; 4];
+        let mut carry = false;
+        for i in (0..4).rev() {
+            let (sum, c1) = self.0[i].overflowing_add(other.0[i]);
+            let (sum, c2) = sum.overflowing_add(carry as u64);
+            limbs[i] = sum;
+            carry = c1 || c2;
+        }
+        (U256(limbs), carry)
+    }
+
+    fn wrapping_sub(&self, other: &U256) -> U256 {
+        let mut limbs = [0u64; 4];
+        let mut borrow = false;
+        for i in (0..4).rev() {
+            let (diff, b1) = self.0[i].overflowing_sub(other.0[i]);
+            let (diff, b2) = diff.overflowing_sub(borrow as u64);
+            limbs[i] = diff;
+            borrow = b1 || b2;
+        }
+        U256(limbs)
+    }
+
+    /// Binary long division, `divisor` must not be zero
+    fn div(&self, divisor: &U256) -> U256 {
+        let mut quotient = U256::ZERO;
+        let mut remainder = U256::ZERO;
+        for i in (0..256).rev() {
+            // The remainder is smaller than the divisor, so a lost top bit means it's larger now
+            let overflow = remainder.bit(255);
+            remainder = remainder.shl(1);
+            if self.bit(i) {
+                remainder.0[3] |= 1;
+            }
+            if overflow || remainder >= *divisor {
+                remainder = remainder.wrapping_sub(divisor);
+                quotient.set_bit(i);
+            }
+        }
+        quotient
+    }
+}
+
+impl Ord for U256 {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.cmp(&other.0)
+    }
+}
+
+impl PartialOrd for U256 {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Saturates at 2^256 - 1
+impl Add for U256 {
+    type Output = U256;
+
+    fn add(self, other: U256) -> U256 {
+        match self.overflowing_add(&other) {
+            (sum, false) => sum,
+            (_, true) => U256([u64::MAX; 4]),
+        }
+    }
+}
+
+impl fmt::Debug for U256 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "U256({})", self.to_hex())
+    }
+}
+
+/// Calculates the difficulty of the given compact target relative to the coin's
+/// easiest target (usually the one of the genesis block).
+/// Returns None if one of the targets is invalid or zero.
+pub fn difficulty(bits: u32, genesis_bits: u32) -> Option<f64> {
+    let target = U256::from_compact(bits)?;
+    let genesis = U256::from_compact(genesis_bits)?;
+    if target.is_zero() {
+        return None;
+    }
+    Some(genesis.to_f64() / target.to_f64())
+}
+
+/// Checks that the proof of work `hash` is at most the target of the compact `bits`, like
+/// `CheckProofOfWork()` in Bitcoin Core without the limit of the network.
+/// Returns the reason if not.
+pub fn check_pow(hash: &[u8; 32], bits: u32) -> Result<(), String> {
+    let target = match U256::from_compact(bits) {
+        Some(target) if !target.is_zero() => target,
+        _ => return Err(format!("Invalid nBits {:08x}", bits)),
+    };
+    let value = U256::from_le_bytes(hash);
+    if value > target {
+        return Err(format!(
+            "Hash {} is above the target {} of nBits {:08x}",
+            value.to_hex(),
+            target.to_hex(),
+            bits
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::utils;
+
+    #[test]
+    fn test_from_compact() {
+        // Bitcoin genesis block
+        assert_eq!(
+            "00000000ffff0000000000000000000000000000000000000000000000000000",
+            U256::from_compact(0x1d00ffff).unwrap().to_hex()
+        );
+        // Bitcoin block 100000
+        assert_eq!(
+            "000000000004864c000000000000000000000000000000000000000000000000",
+            U256::from_compact(0x1b04864c).unwrap().to_hex()
+        );
+        // Bitcoin block 800000
+        assert_eq!(
+            "0000000000000000000538940000000000000000000000000000000000000000",
+            U256::from_compact(0x17053894).unwrap().to_hex()
+        );
+        // Litecoin genesis block
+        assert_eq!(
+            "00000ffff0000000000000000000000000000000000000000000000000000000",
+            U256::from_compact(0x1e0ffff0).unwrap().to_hex()
+        );
+    }
+
+    #[test]
+    fn test_from_compact_edge_cases() {
+        assert_eq!(Some(U256::ZERO), U256::from_compact(0));
+        assert_eq!(Some(U256::ZERO), U256::from_compact(0x00123456));
+        // The mantissa is shifted right for exponents <= 3
+        assert_eq!(Some(U256::from_u64(0x12)), U256::from_compact(0x01123456));
+        assert_eq!(Some(U256::from_u64(0x1234)), U256::from_compact(0x02123456));
+        assert_eq!(
+            Some(U256::from_u64(0x123456)),
+            U256::from_compact(0x03123456)
+        );
+        assert_eq!(
+            Some(U256::from_u64(0x12345600)),
+            U256::from_compact(0x04123456)
+        );
+        assert_eq!(Some(U256::ZERO), U256::from_compact(0x01003456));
+
+        // Negative flag
+        assert_eq!(None, U256::from_compact(0x04923456));
+        assert_eq!(None, U256::from_compact(0x01fedcba));
+        // ... is ignored for zero
+        assert_eq!(Some(U256::ZERO), U256::from_compact(0x04800000));
+        assert_eq!(Some(U256::ZERO), U256::from_compact(0x01803456));
+
+        // Overflow
+        assert_eq!(None, U256::from_compact(0xff123456));
+        assert_eq!(None, U256::from_compact(0x21010000));
+        assert_eq!(
+            "ff00000000000000000000000000000000000000000000000000000000000000",
+            U256::from_compact(0x2100ff00).unwrap().to_hex()
+        );
+        assert_eq!(Some(U256::ZERO), U256::from_compact(0xff000000));
+    }
+
+    #[test]
+    fn test_difficulty() {
+        assert_eq!(Some(1.0), difficulty(0x1d00ffff, 0x1d00ffff));
+        let diff = difficulty(0x1b04864c, 0x1d00ffff).unwrap();
+        assert!((diff - 14484.1623612254).abs() < 1e-6, "{}", diff);
+        let diff = difficulty(0x17053894, 0x1d00ffff).unwrap();
+        assert!((diff - 53911173001054.586).abs() < 1.0, "{}", diff);
+        // Relative to Litecoin's genesis target
+        assert_eq!(Some(1.0), difficulty(0x1e0ffff0, 0x1e0ffff0));
+        assert_eq!(Some(4096.0), difficulty(0x1d00ffff, 0x1e0ffff0));
+
+        assert_eq!(None, difficulty(0, 0x1d00ffff));
+        assert_eq!(None, difficulty(0x04923456, 0x1d00ffff));
+    }
+
+    #[test]
+    fn test_work() {
+        // Chain work of the Bitcoin genesis block
+        let genesis = U256::from_compact(0x1d00ffff).unwrap();
+        assert_eq!(U256::from_u64(0x100010001), genesis.work());
+        assert_eq!(U256::ZERO, U256::ZERO.work());
+        assert_eq!(U256::from_u64(1), U256([u64::MAX; 4]).work());
+        assert_eq!(U256([1 << 63, 0, 0, 0]), U256::from_u64(1).work());
+        assert_eq!(
+            U256::from_hex("38946224e37e").unwrap(),
+            U256::from_compact(0x1b04864c).unwrap().work()
+        );
+    }
+
+    #[test]
+    fn test_hex() {
+        let value = U256::from_compact(0x1b04864c).unwrap();
+        assert_eq!(Some(value), U256::from_hex(&value.to_hex()));
+        assert_eq!(
+            Some(U256::from_u64(0x100010001)),
+            U256::from_hex("100010001")
+        );
+        assert_eq!(None, U256::from_hex(""));
+        assert_eq!(None, U256::from_hex("xyz"));
+        assert_eq!(None, U256::from_hex(&"0".repeat(65)));
+    }
+
+    #[test]
+    fn test_add() {
+        let one = U256::from_u64(1);
+        assert_eq!(U256([0, 0, 1, 0]), U256::from_u64(u64::MAX) + one);
+        assert_eq!(U256([u64::MAX; 4]), U256([u64::MAX; 4]) + one);
+    }
+
+    #[test]
+    fn test_check_pow() {
+        let check =
+            |header: &str, bits| check_pow(&utils::sha256d(&utils::hex_to_vec(header)), bits);
+        // Bitcoin genesis block
+        let genesis = concat!(
+            "0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b2",
+            "7ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a29ab5f49ffff001d1dac2b7c",
+        );
+        assert_eq!(Ok(()), check(genesis, 0x1d00ffff));
+        // Bitcoin block 100000, hash 000000000003ba27aa200b1cecaad478d2b00432346c3f1f3986da1afd33e506
+        let block = concat!(
+            "0100000050120119172a610421a6c3011dd330d9df07b63616c2cc1f1cd00200000000006657a9252aacd5c0",
+            "b2940996ecff952228c3067cc38d4885efb5a4ac4247e9f337221b4d4c86041b0f2b5710",
+        );
+        assert_eq!(Ok(()), check(block, 0x1b04864c));
+        // Just below the hash
+        assert_eq!(
+            Err(String::from(concat!(
+                "Hash 000000000003ba27aa200b1cecaad478d2b00432346c3f1f3986da1afd33e506 is above the ",
+                "target 000000000003ba27000000000000000000000000000000000000000000000000 of nBits 1b03ba27"
+            ))),
+            check(block, 0x1b03ba27)
+        );
+        // Another nonce
+        let mut header = utils::hex_to_vec(block);
+        header[76] ^= 1;
+        assert!(check_pow(&utils::sha256d(&header), 0x1b04864c).is_err());
+
+        assert_eq!(
+            Err(String::from("Invalid nBits 1d80ffff")),
+            check(genesis, 0x1d80ffff)
+        );
+        assert!(check(genesis, 0).is_err());
+    }
+}
@@ -1,18 +1,50 @@
 use std::fmt;
 
+use crate::blockchain::proto::locktime::{self, LockTimeType, RelativeLock};
 use crate::blockchain::proto::script;
 use crate::blockchain::proto::varuint::VarUint;
 use crate::blockchain::proto::ToRaw;
 use crate::common::utils;
 
+/// Stack items of a single input
+pub type Witness = Vec<Vec<u8>>;
+
 pub struct RawTx {
     pub version: u32,
     pub in_count: VarUint,
     pub inputs: Vec<TxInput>,
     pub out_count: VarUint,
     pub outputs: Vec<TxOutput>,
+    // One witness per input, empty for non-segwit transactions
+    pub witnesses: Vec<Witness>,
     pub locktime: u32,
-    pub version_id: u8,
+    pub address_format: script::AddressFormat,
+    /// Litecoin: serialized with the MWEB flag, see `EvaluatedTx::hogex`
+    pub hogex: bool,
+    /// Fields of Zcash transactions, see `BlockFormat::Zcash`
+    pub zcash: Option<Box<ZcashTx>>,
+    /// Bytes consumed by the deserialization, see `EvaluatedTx::size`
+    pub size: u32,
+    /// Bytes consumed without marker, flag and witnesses, see `EvaluatedTx::stripped_size`
+    pub stripped_size: u32,
+}
+
+/// Fields of Zcash transactions beyond the transparent part, which is stored in the transaction
+/// like a Bitcoin transaction. The shielded sections are skipped.
+pub struct ZcashTx {
+    /// Zero before Overwinter (v3)
+    pub version_group_id: u32,
+    /// Only serialized by v5 transactions, zero otherwise
+    pub consensus_branch_id: u32,
+    /// Zero before Overwinter (v3) and if the transaction doesn't expire
+    pub expiry_height: u32,
+    /// Value moved from the shielded pools to the transparent outputs, negative if value is shielded.
+    /// The sum of the Sapling and Orchard valueBalance and the JoinSplit vpub_new - vpub_old.
+    pub shielded_value_delta: i64,
+    /// Hash of the serialization up to v4, defined by ZIP 244 for v5
+    pub txid: [u8; 32],
+    /// The complete serialization
+    pub raw: Vec<u8>,
 }
 
 /// Simple transaction struct
@@ -23,23 +55,40 @@ pub struct EvaluatedTx {
     pub inputs: Vec<TxInput>,
     pub out_count: VarUint,
     pub outputs: Vec<EvaluatedTxOut>,
+    pub witnesses: Vec<Witness>,
     pub locktime: u32,
+    /// Litecoin: the integrating transaction (HogEx) of MWEB blocks. It moves the pegged in and out
+    /// coins and is serialized with the MWEB flag, see `BlockFormat::Litecoin`.
+    pub hogex: bool,
+    /// Fields of Zcash transactions, see `BlockFormat::Zcash`
+    pub zcash: Option<Box<ZcashTx>>,
+    /// Bytes of the serialization including marker, flag and witnesses (BIP144).
+    /// Litecoin: without the MWEB flag and byte of the HogEx.
+    pub size: u32,
+    /// Bytes of the serialization without witness data, the length of `to_bytes()`
+    pub stripped_size: u32,
+    // wtxid, kept once the witnesses are dropped
+    dropped_witness: Option<[u8; 32]>,
 }
 
 impl EvaluatedTx {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         version: u32,
         in_count: VarUint,
         inputs: Vec<TxInput>,
         out_count: VarUint,
         outputs: Vec<TxOutput>,
+        witnesses: Vec<Witness>,
         locktime: u32,
-        version_id: u8,
+        address_format: script::AddressFormat,
+        size: u32,
+        stripped_size: u32,
     ) -> Self {
         // Evaluate and wrap all outputs to process them later
         let outputs = outputs
             .into_iter()
-            .map(|o| EvaluatedTxOut::eval_script(o, version_id))
+            .map(|o| EvaluatedTxOut::eval_script(o, address_format))
             .collect();
         EvaluatedTx {
             version,
@@ -47,8 +96,146 @@ impl EvaluatedTx {
             inputs,
             out_count,
             outputs,
+            witnesses,
             locktime,
+            hogex: false,
+            zcash: None,
+            size,
+            stripped_size,
+            dropped_witness: None,
+        }
+    }
+
+    /// Returns true if the transaction was serialized with witness data
+    #[inline]
+    pub fn has_witness(&self) -> bool {
+        !self.witnesses.is_empty() || self.dropped_witness.is_some()
+    }
+
+    /// Returns true if the witness data is still available, see `drop_witnesses()`
+    #[inline]
+    pub fn retains_witness(&self) -> bool {
+        !self.witnesses.is_empty()
+    }
+
+    /// Computes the wtxid (BIP141), which is None for transactions without witness data,
+    /// their wtxid equals the txid.
+    pub fn witness_hash(&self) -> Option<[u8; 32]> {
+        if let Some(hash) = self.dropped_witness {
+            return Some(hash);
+        }
+        if !self.has_witness() {
+            return None;
+        }
+        Some(utils::sha256d(&self.to_witness_bytes()))
+    }
+
+    /// Evaluates the output scripts of a transaction read with `AddressFormat::evaluate_scripts`
+    /// unset
+    pub fn evaluate_scripts(&mut self, address_format: script::AddressFormat) {
+        for output in self.outputs.iter_mut() {
+            output.script = script::eval_from_bytes(&output.out.script_pubkey, address_format);
+        }
+    }
+
+    /// Frees the witness data. The wtxid is computed beforehand and stays available,
+    /// `to_witness_bytes()` is no longer possible.
+    pub fn drop_witnesses(&mut self) {
+        if !self.retains_witness() {
+            return;
+        }
+        self.dropped_witness = Some(utils::sha256d(&self.to_witness_bytes()));
+        self.witnesses = Vec::new();
+    }
+
+    /// Returns true if the transaction is serialized with marker and flag, see `to_witness_bytes()`
+    #[inline]
+    pub fn has_extended_serialization(&self) -> bool {
+        self.retains_witness() || self.hogex
+    }
+
+    /// Serializes the transaction including marker, flag and witness data.
+    /// Requires the witnesses, see `retains_witness()`.
+    pub fn to_witness_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.to_bytes();
+        // Locktime is appended again after the witnesses
+        bytes.truncate(bytes.len() - 4);
+        // Marker and flag follow the version
+        let flag = if self.retains_witness() { 0x01 } else { 0x00 };
+        let flag = if self.hogex { flag | 0x08 } else { flag };
+        bytes.splice(4..4, [0x00, flag]);
+        for witness in &self.witnesses {
+            bytes.extend_from_slice(&VarUint::compact(witness.len() as u64).to_bytes());
+            for item in witness {
+                bytes.extend_from_slice(&VarUint::compact(item.len() as u64).to_bytes());
+                bytes.extend_from_slice(item);
+            }
+        }
+        // HogEx transactions have no MWEB transaction data
+        if self.hogex {
+            bytes.push(0x00);
+        }
+        bytes.extend_from_slice(&self.locktime.to_le_bytes());
+        bytes
+    }
+
+    /// Weight units (BIP141): the size without witness data counts four times, witness data once
+    #[inline]
+    pub fn weight(&self) -> u64 {
+        self.stripped_size as u64 * 3 + self.size as u64
+    }
+
+    /// Virtual size in vbytes, the weight divided by four and rounded up
+    #[inline]
+    pub fn vsize(&self) -> u64 {
+        self.weight().div_ceil(4)
+    }
+
+    /// Whether the locktime is a block height or a unix time
+    #[inline]
+    pub fn lock_time_type(&self) -> LockTimeType {
+        LockTimeType::of(self.locktime)
+    }
+
+    /// Returns true if the locktime is enforced, which requires an input with a sequence number
+    /// other than `SEQUENCE_FINAL`
+    #[inline]
+    pub fn uses_locktime(&self) -> bool {
+        self.locktime != 0
+            && self
+                .inputs
+                .iter()
+                .any(|input| input.seq_no != locktime::SEQUENCE_FINAL)
+    }
+
+    /// Returns true if the transaction may be included in the block at `block_height`, whose
+    /// locktime cutoff is `block_time` (the median time past since BIP113)
+    pub fn is_final(&self, block_height: u64, block_time: u32) -> bool {
+        let reached = match self.lock_time_type() {
+            LockTimeType::None => return true,
+            LockTimeType::Height => (self.locktime as u64) < block_height,
+            LockTimeType::Time => self.locktime < block_time,
+        };
+        reached || !self.uses_locktime()
+    }
+
+    /// Extracts the redeem and witness scripts of all inputs, see `script::reveal_script`
+    pub fn classify_inputs(&mut self) {
+        if self.is_coinbase() {
+            return;
         }
+        for (i, input) in self.inputs.iter_mut().enumerate() {
+            let witness = self.witnesses.get(i).map(|w| &w[..]).unwrap_or(&[]);
+            input.revealed_script = script::reveal_script(&input.script_sig, witness);
+        }
+    }
+
+    /// Value the transaction takes from the shielded pools of Zcash, see `ZcashTx`
+    #[inline]
+    pub fn shielded_value_delta(&self) -> i64 {
+        self.zcash
+            .as_ref()
+            .map_or(0, |zcash| zcash.shielded_value_delta)
     }
 
     #[inline]
@@ -74,20 +261,29 @@ impl fmt::Debug for EvaluatedTx {
 
 impl From<RawTx> for EvaluatedTx {
     fn from(tx: RawTx) -> Self {
-        Self::new(
+        let mut evaluated = Self::new(
             tx.version,
             tx.in_count,
             tx.inputs,
             tx.out_count,
             tx.outputs,
+            tx.witnesses,
             tx.locktime,
-            tx.version_id,
-        )
+            tx.address_format,
+            tx.size,
+            tx.stripped_size,
+        );
+        evaluated.hogex = tx.hogex;
+        evaluated.zcash = tx.zcash;
+        evaluated
     }
 }
 
 impl ToRaw for EvaluatedTx {
     fn to_bytes(&self) -> Vec<u8> {
+        if let Some(zcash) = &self.zcash {
+            return zcash.raw.clone();
+        }
         let mut bytes =
             Vec::with_capacity((4 + self.in_count.value + self.out_count.value + 4) as usize);
 
@@ -147,6 +343,10 @@ pub struct TxInput {
     pub script_len: VarUint,
     pub script_sig: Vec<u8>,
     pub seq_no: u32,
+    /// Redeem or witness script, only set with `--classify-inputs`
+    pub revealed_script: Option<script::RevealedScript>,
+    /// Spent output, only set with `--use-undo-files`
+    pub prevout: Option<Box<Prevout>>,
 }
 
 impl ToRaw for TxInput {
@@ -161,6 +361,14 @@ impl ToRaw for TxInput {
     }
 }
 
+impl TxInput {
+    /// Decodes the relative lock of the sequence number (BIP68), see `RelativeLock::decode`
+    #[inline]
+    pub fn relative_lock(&self, tx_version: u32) -> Option<RelativeLock> {
+        RelativeLock::decode(tx_version, self.seq_no)
+    }
+}
+
 impl fmt::Debug for TxInput {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         fmt.debug_struct("TxInput")
@@ -168,6 +376,26 @@ impl fmt::Debug for TxInput {
             .field("script_len", &self.script_len)
             .field("script_sig", &self.script_sig)
             .field("seq_no", &self.seq_no)
+            .field("revealed_script", &self.revealed_script)
+            .field("prevout", &self.prevout)
+            .finish()
+    }
+}
+
+/// Output spent by an input, read from the undo data of the block (rev*.dat)
+pub struct Prevout {
+    /// Height of the block which created the output
+    pub height: u64,
+    pub is_coinbase: bool,
+    pub out: EvaluatedTxOut,
+}
+
+impl fmt::Debug for Prevout {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Prevout")
+            .field("height", &self.height)
+            .field("is_coinbase", &self.is_coinbase)
+            .field("out", &self.out.out)
             .finish()
     }
 }
@@ -180,12 +408,32 @@ pub struct EvaluatedTxOut {
 
 impl EvaluatedTxOut {
     #[inline]
-    pub fn eval_script(out: TxOutput, version_id: u8) -> EvaluatedTxOut {
+    pub fn eval_script(out: TxOutput, address_format: script::AddressFormat) -> EvaluatedTxOut {
+        if !address_format.evaluate_scripts {
+            return EvaluatedTxOut {
+                script: script::EvaluatedScript {
+                    address: None,
+                    pattern: script::ScriptPattern::NotEvaluated,
+                },
+                out,
+            };
+        }
         EvaluatedTxOut {
-            script: script::eval_from_bytes(&out.script_pubkey, version_id),
+            script: script::eval_from_bytes(&out.script_pubkey, address_format),
             out,
         }
     }
+
+    /// Returns the bytes pushed after OP_RETURN.
+    /// The pattern only holds a lossy utf8 representation, so the script is evaluated again.
+    #[inline]
+    pub fn data_output_payload(&self) -> Vec<u8> {
+        script::ScriptEvaluator::new(&self.out.script_pubkey)
+            .eval()
+            .ok()
+            .and_then(|stack| stack.elements.get(1).and_then(|e| e.data().ok()))
+            .unwrap_or_default()
+    }
 }
 
 /// Holds TxOutput informations
@@ -17,10 +17,7 @@ pub struct VarUint {
 impl VarUint {
     #[inline]
     fn new(value: u64, buf: Vec<u8>) -> VarUint {
-        VarUint {
-            value: value as u64,
-            buf,
-        }
+        VarUint { value, buf }
     }
 
     pub fn read_from<R: Read + ?Sized>(reader: &mut R) -> io::Result<VarUint> {
@@ -33,6 +30,16 @@ impl VarUint {
         };
         Ok(vint)
     }
+
+    /// Uses the shortest encoding for the given value
+    pub fn compact(value: u64) -> VarUint {
+        match value {
+            0..=0xfc => VarUint::from(value as u8),
+            0xfd..=0xffff => VarUint::from(value as u16),
+            0x10000..=0xffffffff => VarUint::from(value as u32),
+            _ => VarUint::from(value),
+        }
+    }
 }
 
 impl From<u8> for VarUint {
@@ -65,7 +72,7 @@ impl From<u64> for VarUint {
         let mut buf: Vec<u8> = Vec::with_capacity(9);
         buf.push(0xff);
         buf.extend_from_slice(&value.to_le_bytes());
-        VarUint::new(value as u64, buf)
+        VarUint::new(value, buf)
     }
 }
 
@@ -131,7 +138,7 @@ mod tests {
 
         let test = VarUint::from(v);
         assert_eq!(9000000000000000000, test.value);
-        assert_eq!(v, test.value as u64);
+        assert_eq!(v, test.value);
         assert_eq!(9, test.to_bytes().len());
         assert_eq!(
             vec![0xff, 0x00, 0x00, 0x84, 0xe2, 0x50, 0x6c, 0xe6, 0x7c],
@@ -139,6 +146,18 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_varuint_compact() {
+        assert_eq!(vec![0xfc], VarUint::compact(0xfc).to_bytes());
+        assert_eq!(vec![0xfd, 0xfd, 0x00], VarUint::compact(0xfd).to_bytes());
+        assert_eq!(vec![0xfd, 0xff, 0xff], VarUint::compact(0xffff).to_bytes());
+        assert_eq!(
+            vec![0xfe, 0x00, 0x00, 0x01, 0x00],
+            VarUint::compact(0x10000).to_bytes()
+        );
+        assert_eq!(9, VarUint::compact(0x100000000).to_bytes().len());
+    }
+
     #[test]
     fn test_varuint_read() {
         let mut cursor = io::Cursor::new([0xfe, 0x55, 0xa1, 0xae, 0xc6]);
@@ -0,0 +1,291 @@
+//! Activity of each address, shared by `balances` and `addressindex`. The unspent outputs are
+//! kept in memory or spilled to disk by an `OutputStore`, so the inputs can be resolved to the
+//! address and value they spend.
+
+use std::collections::HashMap;
+
+use clap::ArgMatches;
+
+use crate::blockchain::proto::tx::{EvaluatedTx, TxOutpoint};
+use crate::blockchain::proto::Hashed;
+use crate::callbacks::common::{self, UnspentValue};
+use crate::common::outputstore::{outpoint_key, Key, OutputStore};
+use crate::common::utils;
+use crate::errors::OpResult;
+
+/// Summary of the transactions which pay to or spend from an address
+#[derive(Clone, Debug, PartialEq)]
+pub struct AddressSummary {
+    pub first_seen_height: u64,
+    pub first_seen_txid: [u8; 32],
+    pub last_seen_height: u64,
+    pub total_received: u64,
+    pub total_sent: u64,
+    /// Value of the unspent outputs. Lower than received minus sent if an output was
+    /// replaced by a duplicate txid, see `common::warn_duplicate_txid()`.
+    pub balance: u64,
+    pub tx_count: u64,
+}
+
+impl AddressSummary {
+    fn new(block_height: u64, txid: [u8; 32]) -> Self {
+        AddressSummary {
+            first_seen_height: block_height,
+            first_seen_txid: txid,
+            last_seen_height: block_height,
+            total_received: 0,
+            total_sent: 0,
+            balance: 0,
+            tx_count: 0,
+        }
+    }
+}
+
+/// Backend of the unspent outputs
+enum Outputs {
+    Memory(HashMap<Key, UnspentValue>),
+    // Values are encoded by `common::encode_unspent()`
+    Disk(OutputStore),
+}
+
+pub struct AddressTracker {
+    // None once closed
+    outputs: Option<Outputs>,
+    addresses: HashMap<String, AddressSummary>,
+}
+
+impl AddressTracker {
+    /// Keeps the unspent outputs in memory
+    pub fn in_memory() -> Self {
+        AddressTracker {
+            outputs: Some(Outputs::Memory(HashMap::with_capacity(10000000))),
+            addresses: HashMap::new(),
+        }
+    }
+
+    /// Opens the backend given by the arguments of `OutputStore::backend_args()`
+    pub fn from_matches(matches: &ArgMatches) -> OpResult<Self> {
+        match OutputStore::backend_from_matches(matches)? {
+            Some(store) => Ok(AddressTracker {
+                outputs: Some(Outputs::Disk(store)),
+                addresses: HashMap::new(),
+            }),
+            None => Ok(AddressTracker::in_memory()),
+        }
+    }
+
+    pub fn addresses(&self) -> &HashMap<String, AddressSummary> {
+        &self.addresses
+    }
+
+    /// Spends the outputs of the inputs and adds the outputs with an address. Inputs of outputs
+    /// which weren't seen, e.g. before the start height, are ignored.
+    pub fn add_tx(&mut self, tx: &Hashed<EvaluatedTx>, block_height: u64) -> OpResult<()> {
+        let mut touched: Vec<String> = Vec::new();
+        if !tx.value.is_coinbase() {
+            for input in &tx.value.inputs {
+                if let Some((value, address)) = self.take(&outpoint_key(&input.outpoint))? {
+                    if let Some(summary) = self.addresses.get_mut(&address) {
+                        summary.total_sent += value;
+                        summary.balance -= value;
+                    }
+                    touched.push(address);
+                }
+            }
+        }
+
+        let mut duplicate = false;
+        for (i, output) in tx.value.outputs.iter().enumerate() {
+            let address = match &output.script.address {
+                Some(address) => address,
+                None => {
+                    debug!(
+                        target: "callback", "Ignoring invalid utxo in: {} ({})",
+                        utils::arr_to_hex_swapped(&tx.hash),
+                        output.script.pattern
+                    );
+                    continue;
+                }
+            };
+            let key = outpoint_key(&TxOutpoint::new(tx.hash, i as u32));
+            let value = output.out.value;
+            if let Some((replaced, owner)) =
+                self.insert(key, block_height, value, address, tx.value.is_coinbase())?
+            {
+                if let Some(summary) = self.addresses.get_mut(&owner) {
+                    summary.balance -= replaced;
+                }
+                duplicate = true;
+            }
+            let summary = self
+                .addresses
+                .entry(address.clone())
+                .or_insert_with(|| AddressSummary::new(block_height, tx.hash));
+            summary.total_received += value;
+            summary.balance += value;
+            touched.push(address.clone());
+        }
+        if duplicate {
+            common::warn_duplicate_txid(tx, block_height);
+        }
+
+        touched.sort_unstable();
+        touched.dedup();
+        for address in touched {
+            if let Some(summary) = self.addresses.get_mut(&address) {
+                summary.tx_count += 1;
+                summary.last_seen_height = block_height;
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes the output and returns its value and address
+    fn take(&mut self, key: &Key) -> OpResult<Option<(u64, String)>> {
+        match self.outputs.as_mut().unwrap() {
+            Outputs::Memory(unspents) => Ok(unspents
+                .remove(key)
+                .map(|unspent| (unspent.value, unspent.address))),
+            Outputs::Disk(store) => match store.take(key)? {
+                Some(data) => {
+                    let (_, value, address) = common::decode_unspent(&data)?;
+                    Ok(Some((value, String::from(address))))
+                }
+                None => Ok(None),
+            },
+        }
+    }
+
+    /// Stores the output and returns value and address of the one it replaced. Only coinbases
+    /// are checked on disk, other transactions can't repeat a txid as they spend unique outputs.
+    fn insert(
+        &mut self,
+        key: Key,
+        block_height: u64,
+        value: u64,
+        address: &str,
+        is_coinbase: bool,
+    ) -> OpResult<Option<(u64, String)>> {
+        match self.outputs.as_mut().unwrap() {
+            Outputs::Memory(unspents) => {
+                let unspent = UnspentValue {
+                    block_height,
+                    value,
+                    address: String::from(address),
+                };
+                Ok(unspents
+                    .insert(key, unspent)
+                    .map(|replaced| (replaced.value, replaced.address)))
+            }
+            Outputs::Disk(store) => {
+                let mut replaced = None;
+                if is_coinbase {
+                    if let Some(data) = store.get(&key)? {
+                        let (_, value, address) = common::decode_unspent(&data)?;
+                        replaced = Some((value, String::from(address)));
+                    }
+                }
+                store.insert(key, common::encode_unspent(block_height, value, address))?;
+                Ok(replaced)
+            }
+        }
+    }
+
+    /// Drops the unspent outputs and removes the spill folder
+    pub fn close(&mut self) -> OpResult<()> {
+        match self.outputs.take() {
+            Some(Outputs::Disk(store)) => store.destroy(),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+    use crate::callbacks::fixtures::{new_chain, new_tx_to, SCRIPT_A, SCRIPT_B};
+
+    const ADDRESS_A: &str = "12higDjoCCNXSA95xZMWUdPvXNmkAduhWv";
+    const ADDRESS_B: &str = "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa";
+
+    #[test]
+    fn test_backends() {
+        // 0: A gets 50, 1: A sends 30 to B and 20 back to itself, 2: B spends 30 to A
+        let coinbase = new_tx_to(&[([0u8; 32], 0xFFFFFFFF)], &[(50, SCRIPT_A)]);
+        let spend_1 = new_tx_to(&[(coinbase.hash, 0)], &[(20, SCRIPT_A), (30, SCRIPT_B)]);
+        let spend_2 = new_tx_to(&[(spend_1.hash, 1)], &[(30, SCRIPT_A), (0, "51")]);
+        let first_txid = coinbase.hash;
+        let spend_1_txid = spend_1.hash;
+        let chain = new_chain(vec![vec![coinbase], vec![spend_1], vec![spend_2]]);
+
+        let dir = env::temp_dir().join(format!("blockparser-tracker-{}", process::id()));
+        let args = vec![
+            "addressindex",
+            "--db-backend",
+            "disk",
+            "--db-path",
+            dir.to_str().unwrap(),
+            "--buffer-size",
+            "1",
+        ];
+        let app = clap::App::new("addressindex").args(&OutputStore::backend_args());
+        let disk = AddressTracker::from_matches(&app.get_matches_from(args)).unwrap();
+        for mut tracker in [AddressTracker::in_memory(), disk] {
+            for (height, block) in chain.iter().enumerate() {
+                for tx in &block.txs {
+                    tracker.add_tx(tx, height as u64).unwrap();
+                }
+            }
+            tracker.close().unwrap();
+            assert_eq!(2, tracker.addresses().len());
+            assert_eq!(
+                AddressSummary {
+                    first_seen_height: 0,
+                    first_seen_txid: first_txid,
+                    last_seen_height: 2,
+                    total_received: 100,
+                    total_sent: 50,
+                    balance: 50,
+                    tx_count: 3,
+                },
+                tracker.addresses()[ADDRESS_A]
+            );
+            assert_eq!(
+                AddressSummary {
+                    first_seen_height: 1,
+                    first_seen_txid: spend_1_txid,
+                    last_seen_height: 2,
+                    total_received: 30,
+                    total_sent: 30,
+                    balance: 0,
+                    tx_count: 2,
+                },
+                tracker.addresses()[ADDRESS_B]
+            );
+        }
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn test_invalid_args() {
+        for args in [
+            vec!["--db-backend", "disk"],
+            vec!["--db-path", "/tmp/db"],
+            vec!["--db-backend", "memory", "--db-path", "/tmp/db"],
+            vec!["--buffer-size", "0"],
+        ] {
+            let mut all_args = vec!["addressindex"];
+            all_args.extend_from_slice(&args);
+            let app = clap::App::new("addressindex").args(&OutputStore::backend_args());
+            let matches = app.get_matches_from(all_args);
+            assert!(
+                AddressTracker::from_matches(&matches).is_err(),
+                "{:?}",
+                args
+            );
+        }
+    }
+}
@@ -0,0 +1,260 @@
+use std::cmp::Ordering;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use crate::blockchain::parser::types::CoinType;
+use crate::blockchain::proto::block::Block;
+use crate::callbacks::address_tracker::{AddressSummary, AddressTracker};
+use crate::callbacks::Callback;
+use crate::common::outputstore::OutputStore;
+use crate::common::utils;
+use crate::errors::OpResult;
+
+const HEADER: &str = "address;first_seen_height;first_seen_txid;last_seen_height;total_received;total_sent;current_balance;tx_count";
+
+/// Order of the rows, ties are broken by address
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SortOrder {
+    /// Highest balance first
+    Balance,
+    /// Earliest first
+    FirstSeen,
+    /// Most transactions first
+    TxCount,
+}
+
+impl SortOrder {
+    fn compare(self, a: &(&String, &AddressSummary), b: &(&String, &AddressSummary)) -> Ordering {
+        let order = match self {
+            SortOrder::Balance => b.1.balance.cmp(&a.1.balance),
+            SortOrder::FirstSeen => a.1.first_seen_height.cmp(&b.1.first_seen_height),
+            SortOrder::TxCount => b.1.tx_count.cmp(&a.1.tx_count),
+        };
+        order.then_with(|| a.0.cmp(b.0))
+    }
+}
+
+/// Writes a summary of the activity of every address seen in a csv file
+pub struct AddressIndex {
+    output_dir: PathBuf,
+    sort: SortOrder,
+    tracker: AddressTracker,
+
+    start_height: u64,
+}
+
+/// Writes the addresses in the given order, returns the number of addresses
+fn write_index<W: Write>(
+    tracker: &AddressTracker,
+    sort: SortOrder,
+    writer: &mut W,
+) -> OpResult<usize> {
+    let mut addresses: Vec<(&String, &AddressSummary)> = tracker.addresses().iter().collect();
+    addresses.sort_unstable_by(|a, b| sort.compare(a, b));
+
+    writeln!(writer, "{}", HEADER)?;
+    for (address, summary) in &addresses {
+        writeln!(
+            writer,
+            "{};{};{};{};{};{};{};{}",
+            address,
+            summary.first_seen_height,
+            utils::arr_to_hex_swapped(&summary.first_seen_txid),
+            summary.last_seen_height,
+            summary.total_received,
+            summary.total_sent,
+            summary.balance,
+            summary.tx_count
+        )?;
+    }
+    Ok(addresses.len())
+}
+
+impl Callback for AddressIndex {
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b>
+    where
+        Self: Sized,
+    {
+        SubCommand::with_name("addressindex")
+            .about("Dumps first and last activity, received, sent and balance of each address to CSV file")
+            .version("0.1")
+            .arg(
+                Arg::with_name("output-dir")
+                    .long("output-dir")
+                    .value_name("PATH")
+                    .help("Folder to store addressindex.csv, created if necessary")
+                    .takes_value(true)
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name("sort")
+                    .long("sort")
+                    .value_name("ORDER")
+                    .help("Orders the addresses by highest balance, first activity or most transactions (default: first_seen)")
+                    .possible_values(&["balance", "first_seen", "tx_count"])
+                    .takes_value(true),
+            )
+            .args(&OutputStore::backend_args())
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let sort = match matches.value_of("sort") {
+            Some("balance") => SortOrder::Balance,
+            Some("tx_count") => SortOrder::TxCount,
+            _ => SortOrder::FirstSeen,
+        };
+        Ok(AddressIndex {
+            output_dir: PathBuf::from(matches.value_of("output-dir").unwrap()),
+            sort,
+            tracker: AddressTracker::from_matches(matches)?,
+            start_height: 0,
+        })
+    }
+
+    fn on_start(&mut self, _: &CoinType, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        fs::create_dir_all(&self.output_dir)?;
+        info!(target: "callback", "Using `addressindex` with output dir: {} ...", &self.output_dir.display());
+        Ok(())
+    }
+
+    fn wants_witnesses(&self) -> bool {
+        false
+    }
+
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<()> {
+        for tx in &block.txs {
+            self.tracker.add_tx(tx, block_height)?;
+        }
+        Ok(())
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        self.tracker.close()?;
+
+        let tmp_path = self.output_dir.join("addressindex.csv.tmp");
+        let mut writer = BufWriter::with_capacity(4000000, File::create(&tmp_path)?);
+        let n_addresses = write_index(&self.tracker, self.sort, &mut writer)?;
+        writer.flush()?;
+        fs::rename(
+            &tmp_path,
+            self.output_dir.join(format!(
+                "addressindex-{}-{}.csv",
+                self.start_height, block_height
+            )),
+        )?;
+
+        info!(target: "callback", "Done.\nDumped {} addresses.", n_addresses);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+    use crate::blockchain::parser::types::Bitcoin;
+    use crate::callbacks::fixtures::{new_chain, new_tx_to, SCRIPT_A, SCRIPT_B};
+
+    const ADDRESS_A: &str = "12higDjoCCNXSA95xZMWUdPvXNmkAduhWv";
+    const ADDRESS_B: &str = "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa";
+
+    /// Indexes the chain and returns the lines of the csv file
+    fn run_index(name: &str, args: &[&str], chain: &[Block]) -> Vec<String> {
+        let dir = env::temp_dir().join(format!(
+            "blockparser-addressindex-{}-{}",
+            name,
+            process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let db_path = dir.join("db");
+        let mut all_args = vec!["addressindex", "--output-dir", dir.to_str().unwrap()];
+        all_args.extend_from_slice(args);
+        if args.contains(&"disk") {
+            all_args.extend_from_slice(&["--db-path", db_path.to_str().unwrap()]);
+        }
+        let matches = AddressIndex::build_subcommand().get_matches_from(all_args);
+        let mut cb = AddressIndex::new(&matches).unwrap();
+        cb.on_start(&CoinType::from(Bitcoin), 0).unwrap();
+        for (height, block) in chain.iter().enumerate() {
+            cb.on_block(block, height as u64).unwrap();
+        }
+        cb.on_complete(chain.len() as u64).unwrap();
+        assert!(!db_path.exists());
+
+        let content =
+            fs::read_to_string(dir.join(format!("addressindex-0-{}.csv", chain.len()))).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        content.lines().map(String::from).collect()
+    }
+
+    #[test]
+    fn test_address_index() {
+        // 0: A gets 50, 1: B gets 50 and 30 of A, 2: B gets 51, 3: B sends 10 to A
+        let coinbase = |value, script| new_tx_to(&[([0u8; 32], 0xFFFFFFFF)], &[(value, script)]);
+        let coinbase_0 = coinbase(50, SCRIPT_A);
+        let coinbase_1 = coinbase(50, SCRIPT_B);
+        let spend_1 = new_tx_to(&[(coinbase_0.hash, 0)], &[(20, SCRIPT_A), (30, SCRIPT_B)]);
+        let spend_3 = new_tx_to(
+            &[(coinbase_1.hash, 0), (spend_1.hash, 1)],
+            &[(10, SCRIPT_A), (70, SCRIPT_B)],
+        );
+        let txid_0 = utils::arr_to_hex_swapped(&coinbase_0.hash);
+        let txid_1 = utils::arr_to_hex_swapped(&coinbase_1.hash);
+        let chain = new_chain(vec![
+            vec![coinbase_0],
+            vec![coinbase_1, spend_1],
+            vec![coinbase(51, SCRIPT_B)],
+            vec![spend_3],
+        ]);
+
+        let header = String::from(HEADER);
+        let row_a = format!("{};0;{};3;80;50;30;3", ADDRESS_A, txid_0);
+        let row_b = format!("{};1;{};3;201;80;121;4", ADDRESS_B, txid_1);
+        for backend in [vec![], vec!["--db-backend", "disk", "--buffer-size", "1"]] {
+            assert_eq!(
+                vec![header.clone(), row_a.clone(), row_b.clone()],
+                run_index("first-seen", &backend, &chain)
+            );
+        }
+        for sort in ["balance", "tx_count"] {
+            assert_eq!(
+                vec![header.clone(), row_b.clone(), row_a.clone()],
+                run_index(sort, &["--sort", sort], &chain)
+            );
+        }
+    }
+
+    #[test]
+    fn test_sort_order() {
+        let mut early = AddressSummary {
+            first_seen_height: 1,
+            first_seen_txid: [0; 32],
+            last_seen_height: 5,
+            total_received: 10,
+            total_sent: 0,
+            balance: 10,
+            tx_count: 1,
+        };
+        let mut late = early.clone();
+        late.first_seen_height = 2;
+        late.balance = 20;
+        late.tx_count = 3;
+        early.tx_count = 3;
+        let (a, b) = (String::from("a"), String::from("b"));
+        let early = (&b, &early);
+        let late = (&a, &late);
+        assert_eq!(Ordering::Less, SortOrder::FirstSeen.compare(&early, &late));
+        assert_eq!(Ordering::Greater, SortOrder::Balance.compare(&early, &late));
+        // Same count, "a" goes first
+        assert_eq!(Ordering::Greater, SortOrder::TxCount.compare(&early, &late));
+    }
+}
@@ -0,0 +1,358 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use crate::blockchain::parser::types::CoinType;
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::tx::{EvaluatedTx, TxOutpoint};
+use crate::blockchain::proto::Hashed;
+use crate::callbacks::common::{OutputType, OUTPUT_TYPES};
+use crate::callbacks::Callback;
+use crate::common::outputstore::{outpoint_key, OutputStore};
+use crate::errors::{OpError, OpResult};
+
+const DEFAULT_BUCKET_SIZE: u64 = 1000;
+
+/// Counts of the blocks `first_height..=last_height`
+#[derive(Debug, Default, PartialEq)]
+struct Bucket {
+    first_height: u64,
+    last_height: u64,
+    txs: u64,
+    segwit_txs: u64,
+    outputs: [u64; OUTPUT_TYPES.len()],
+    inputs: u64,
+    witness_inputs: u64,
+    unresolved_inputs: u64,
+}
+
+impl Bucket {
+    fn new(height: u64) -> Self {
+        Bucket {
+            first_height: height,
+            last_height: height,
+            ..Default::default()
+        }
+    }
+
+    fn header() -> String {
+        let mut header = String::from("first_height;last_height;txs;segwit_txs;segwit_txs_pct");
+        for output_type in OUTPUT_TYPES.iter() {
+            header.push(';');
+            header.push_str(output_type.name());
+        }
+        header.push_str(";inputs;witness_inputs;witness_inputs_pct;unresolved_inputs\n");
+        header
+    }
+
+    fn to_row(&self) -> String {
+        let mut row = format!(
+            "{};{};{};{};{}",
+            self.first_height,
+            self.last_height,
+            self.txs,
+            self.segwit_txs,
+            percentage(self.segwit_txs, self.txs)
+        );
+        for count in self.outputs.iter() {
+            row.push_str(&format!(";{}", count));
+        }
+        row.push_str(&format!(
+            ";{};{};{};{}\n",
+            self.inputs,
+            self.witness_inputs,
+            // Unresolved inputs are unknown either way
+            percentage(self.witness_inputs, self.inputs - self.unresolved_inputs),
+            self.unresolved_inputs
+        ));
+        row
+    }
+}
+
+/// Counts transactions, outputs per script type and inputs spending witness programs
+/// per bucket of blocks, to plot the adoption of segwit and taproot.
+pub struct Adoption {
+    output_dir: PathBuf,
+    bucket_size: u64,
+    // Output type of the outputs which are not spent yet
+    outputs: Option<OutputStore>,
+    writer: Option<BufWriter<File>>,
+    bucket: Option<Bucket>,
+
+    start_height: u64,
+}
+
+impl Adoption {
+    fn apply_tx(&mut self, tx: &Hashed<EvaluatedTx>) -> OpResult<()> {
+        let outputs = self.outputs.as_mut().unwrap();
+        let bucket = self.bucket.as_mut().unwrap();
+        bucket.txs += 1;
+        if tx.value.has_witness() {
+            bucket.segwit_txs += 1;
+        }
+        if !tx.value.is_coinbase() {
+            for (i, input) in tx.value.inputs.iter().enumerate() {
+                bucket.inputs += 1;
+                let output_type = match outputs.take(&outpoint_key(&input.outpoint))? {
+                    Some(value) => OUTPUT_TYPES[value[0] as usize],
+                    None => {
+                        bucket.unresolved_inputs += 1;
+                        continue;
+                    }
+                };
+                // P2SH wrapped witness programs are spent with a witness
+                let nested = output_type == OutputType::Pay2ScriptHash
+                    && tx.value.witnesses.get(i).is_some_and(|w| !w.is_empty());
+                if output_type.is_witness_program() || nested {
+                    bucket.witness_inputs += 1;
+                }
+            }
+        }
+        for (i, output) in tx.value.outputs.iter().enumerate() {
+            let output_type = OutputType::from_output(output);
+            bucket.outputs[output_type.index()] += 1;
+            if output_type != OutputType::NullData {
+                let key = outpoint_key(&TxOutpoint::new(tx.hash, i as u32));
+                outputs.insert(key, vec![output_type.index() as u8])?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_bucket(&mut self) -> OpResult<()> {
+        if let Some(bucket) = self.bucket.take() {
+            let writer = self.writer.as_mut().unwrap();
+            writer.write_all(bucket.to_row().as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+/// Share of `part` in percent with two decimals, 0 if `total` is 0
+fn percentage(part: u64, total: u64) -> String {
+    if total == 0 {
+        return String::from("0.00");
+    }
+    format!("{:.2}", part as f64 * 100.0 / total as f64)
+}
+
+impl Callback for Adoption {
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b>
+    where
+        Self: Sized,
+    {
+        SubCommand::with_name("adoption")
+            .about("Writes script type and segwit usage per bucket of blocks to CSV file")
+            .version("0.1")
+            .arg(
+                Arg::with_name("output-dir")
+                    .long("output-dir")
+                    .value_name("PATH")
+                    .help("Folder to store adoption.csv, created if necessary")
+                    .takes_value(true)
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name("bucket-size")
+                    .long("bucket-size")
+                    .value_name("N_BLOCKS")
+                    .help("Number of blocks per row (default: 1000)")
+                    .takes_value(true),
+            )
+            .args(&OutputStore::args())
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let bucket_size = match matches.value_of("bucket-size") {
+            Some(_) => match value_t!(matches, "bucket-size", u64) {
+                Ok(n) if n > 0 => n,
+                _ => {
+                    return Err(OpError::from(String::from(
+                        "--bucket-size must be a positive number of blocks.",
+                    )))
+                }
+            },
+            None => DEFAULT_BUCKET_SIZE,
+        };
+        let outputs = OutputStore::from_matches(matches)?;
+        let cb = Adoption {
+            output_dir: PathBuf::from(matches.value_of("output-dir").unwrap()),
+            bucket_size,
+            outputs: Some(outputs),
+            writer: None,
+            bucket: None,
+            start_height: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, _: &CoinType, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        if block_height != 0 {
+            warn!(target: "callback", "Starting at height {}, spends of earlier outputs are unresolved.", block_height);
+        }
+        fs::create_dir_all(&self.output_dir)?;
+        let mut writer = BufWriter::with_capacity(
+            4000000,
+            File::create(self.output_dir.join("adoption.csv.tmp"))?,
+        );
+        writer.write_all(Bucket::header().as_bytes())?;
+        self.writer = Some(writer);
+        info!(target: "callback", "Using `adoption` with output dir: {} and bucket size: {} ...",
+              &self.output_dir.display(), self.bucket_size);
+        Ok(())
+    }
+
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<()> {
+        // Buckets are aligned to multiples of the bucket size, starting with the genesis block
+        let same_bucket = self.bucket.as_ref().is_some_and(|bucket| {
+            bucket.first_height / self.bucket_size == block_height / self.bucket_size
+        });
+        if !same_bucket {
+            self.write_bucket()?;
+            self.bucket = Some(Bucket::new(block_height));
+        }
+        self.bucket.as_mut().unwrap().last_height = block_height;
+        for tx in &block.txs {
+            self.apply_tx(tx)?;
+        }
+        Ok(())
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        self.write_bucket()?;
+        let mut writer = self.writer.take().unwrap();
+        writer.flush()?;
+        drop(writer);
+        let path = self.output_dir.join("adoption.csv");
+        fs::rename(path.with_extension("csv.tmp"), &path)?;
+        self.outputs.take().unwrap().destroy()?;
+
+        info!(target: "callback", "Done.\nDumped blocks {} to {} into {}.",
+              self.start_height, block_height, path.display());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+    use crate::blockchain::parser::types::Bitcoin;
+    use crate::callbacks::fixtures::{new_chain, new_tx, new_tx_to};
+
+    const P2WPKH: &str = "0014751e76e8199196d454941c45d1b3a323f1433bd6";
+    const P2TR: &str = "5120a60869f0dbcf1dc659c9cecbaf8050135ea9e8cdc487053f1dc6880949dc684c";
+    const P2SH: &str = "a914748284390f9e263a4b766a75d0633c50426eb87587";
+    const DATA: &str = "6a0b68656c6c6f20776f726c64";
+
+    #[test]
+    fn test_percentage() {
+        assert_eq!("0.00", percentage(0, 0));
+        assert_eq!("0.00", percentage(0, 7));
+        assert_eq!("33.33", percentage(1, 3));
+        assert_eq!("66.67", percentage(2, 3));
+        assert_eq!("100.00", percentage(5, 5));
+    }
+
+    fn run(bucket_size: &str, start_height: u64) -> Vec<String> {
+        let dir = env::temp_dir().join(format!(
+            "blockparser-adoption-{}-{}-{}",
+            bucket_size,
+            start_height,
+            process::id()
+        ));
+        let matches = Adoption::build_subcommand().get_matches_from(vec![
+            "adoption",
+            "--output-dir",
+            dir.to_str().unwrap(),
+            "--bucket-size",
+            bucket_size,
+        ]);
+        let coinbase_0 = new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[5000000000]);
+        let coinbase_1 = new_tx_to(&[([0u8; 32], 0xFFFFFFFF)], &[(5000000000, P2WPKH)]);
+        let spend = new_tx_to(
+            &[(coinbase_0.hash, 0)],
+            &[(1000, P2TR), (1000, P2SH), (0, DATA)],
+        );
+        let coinbase_2 = new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[5000000001]);
+        let mut spend_witness = new_tx(
+            &[
+                (coinbase_1.hash, 0),
+                (spend.hash, 0),
+                (spend.hash, 1),
+                ([9u8; 32], 0),
+            ],
+            &[900],
+        );
+        // Witnesses of the P2WPKH, P2TR and nested P2SH inputs
+        spend_witness.value.witnesses = vec![vec![vec![1]], vec![vec![2]], vec![vec![3]], vec![]];
+        let chain = new_chain(vec![
+            vec![coinbase_0],
+            vec![coinbase_1, spend],
+            vec![coinbase_2, spend_witness],
+        ]);
+
+        let mut adoption = Adoption::new(&matches).unwrap();
+        adoption
+            .on_start(&CoinType::from(Bitcoin), start_height)
+            .unwrap();
+        for (height, block) in chain.iter().enumerate() {
+            adoption
+                .on_block(block, start_height + height as u64)
+                .unwrap();
+        }
+        adoption.on_complete(start_height + 2).unwrap();
+        let csv = fs::read_to_string(dir.join("adoption.csv")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        csv.lines().map(String::from).collect()
+    }
+
+    #[test]
+    fn test_buckets() {
+        let header = "first_height;last_height;txs;segwit_txs;segwit_txs_pct;p2pk;p2pkh;p2sh;\
+                      p2wpkh;p2wsh;p2tr;multisig;nulldata;nonstandard;\
+                      inputs;witness_inputs;witness_inputs_pct;unresolved_inputs";
+        // The genesis block starts the first bucket
+        assert_eq!(
+            vec![
+                header,
+                "0;1;3;0;0.00;0;1;1;1;0;1;0;1;0;1;0;0.00;0",
+                "2;2;2;1;50.00;0;2;0;0;0;0;0;0;0;4;3;100.00;1",
+            ],
+            run("2", 0)
+        );
+        assert_eq!(
+            vec![header, "0;2;5;1;20.00;0;3;1;1;0;1;0;1;0;5;3;75.00;1"],
+            run("1000", 0)
+        );
+        // Buckets stay aligned when starting in the middle of one
+        assert_eq!(
+            vec![
+                header,
+                "999;999;1;0;0.00;0;1;0;0;0;0;0;0;0;0;0;0.00;0",
+                "1000;1001;4;1;25.00;0;2;1;1;0;1;0;1;0;5;3;75.00;1",
+            ],
+            run("1000", 999)
+        );
+    }
+
+    #[test]
+    fn test_invalid_bucket_size() {
+        let matches = Adoption::build_subcommand().get_matches_from(vec![
+            "adoption",
+            "--output-dir",
+            "/tmp",
+            "--bucket-size",
+            "0",
+        ]);
+        assert!(Adoption::new(&matches).is_err());
+    }
+}
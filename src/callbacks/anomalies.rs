@@ -0,0 +1,338 @@
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use crate::blockchain::parser::types::CoinType;
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::tx::EvaluatedTx;
+use crate::blockchain::proto::Hashed;
+use crate::callbacks::Callback;
+use crate::common::bloom::BloomFilter;
+use crate::common::outputstore::{outpoint_key, read_varint, write_varint, OutputStore};
+use crate::common::utils;
+use crate::errors::{OpError, OpResult};
+
+/// Coinbase transactions which were mined twice before BIP30
+const DUPLICATE_COINBASES: [&str; 2] = [
+    "d5d27987d2a3dfc724e359870c6644b40e497bdc0589a033220fe15429d88599",
+    "e3bf3d07d4b0375638d5f1db5255fe07ba2c4cb067cd81b84ee974b6585fb468",
+];
+/// Inputs with a lower sequence number signal replaceability (BIP125)
+const MAX_RBF_SEQUENCE: u32 = 0xfffffffd;
+const DEFAULT_BLOOM_SIZE: usize = 1024;
+const BLOOM_HASHES: u32 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Category {
+    DoubleSpend,
+    DuplicateInput,
+    RbfSignal,
+    DuplicateCoinbase,
+}
+
+impl Category {
+    const ALL: [Category; 4] = [
+        Category::DoubleSpend,
+        Category::DuplicateInput,
+        Category::RbfSignal,
+        Category::DuplicateCoinbase,
+    ];
+
+    fn name(&self) -> &'static str {
+        match *self {
+            Category::DoubleSpend => "double_spend",
+            Category::DuplicateInput => "duplicate_input",
+            Category::RbfSignal => "rbf_signal",
+            Category::DuplicateCoinbase => "duplicate_coinbase",
+        }
+    }
+}
+
+/// Flags double spends, duplicate inputs, RBF signaling and the duplicate coinbases
+pub struct Anomalies {
+    output_dir: PathBuf,
+    writer: Option<BufWriter<File>>,
+    // All outpoints spent so far, a hit of the filter is verified with the spending heights
+    spent_filter: BloomFilter,
+    spends: Option<OutputStore>,
+
+    start_height: u64,
+    counts: [u64; 4],
+}
+
+impl Anomalies {
+    fn check_tx(&mut self, tx: &Hashed<EvaluatedTx>, block_height: u64) -> OpResult<()> {
+        if tx.value.is_coinbase() {
+            let txid = utils::arr_to_hex_swapped(&tx.hash);
+            if DUPLICATE_COINBASES.contains(&txid.as_str()) {
+                self.add(tx, block_height, Category::DuplicateCoinbase)?;
+            }
+            return Ok(());
+        }
+
+        let spends = self.spends.as_mut().unwrap();
+        let mut keys = HashSet::with_capacity(tx.value.inputs.len());
+        let mut found = Vec::new();
+        for input in &tx.value.inputs {
+            if input.seq_no <= MAX_RBF_SEQUENCE && !found.contains(&Category::RbfSignal) {
+                found.push(Category::RbfSignal);
+            }
+            let key = outpoint_key(&input.outpoint);
+            if !keys.insert(key) {
+                if !found.contains(&Category::DuplicateInput) {
+                    found.push(Category::DuplicateInput);
+                }
+                continue;
+            }
+            if self.spent_filter.insert(&key) {
+                if let Some(height) = spends.get(&key)? {
+                    warn!(target: "callback", "Outpoint {}:{} spent in block {} was already spent in block {}.",
+                          utils::arr_to_hex_swapped(&input.outpoint.txid), input.outpoint.index,
+                          block_height, read_varint(&mut height.as_slice())?);
+                    if !found.contains(&Category::DoubleSpend) {
+                        found.push(Category::DoubleSpend);
+                    }
+                    continue;
+                }
+            }
+            let mut height = Vec::with_capacity(5);
+            write_varint(&mut height, block_height);
+            spends.insert(key, height)?;
+        }
+        for category in Category::ALL {
+            if found.contains(&category) {
+                self.add(tx, block_height, category)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn add(
+        &mut self,
+        tx: &Hashed<EvaluatedTx>,
+        block_height: u64,
+        category: Category,
+    ) -> OpResult<()> {
+        self.counts[category as usize] += 1;
+        let row = format!(
+            "{};{};{}\n",
+            block_height,
+            utils::arr_to_hex_swapped(&tx.hash),
+            category.name()
+        );
+        self.writer.as_mut().unwrap().write_all(row.as_bytes())?;
+        Ok(())
+    }
+}
+
+impl Callback for Anomalies {
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b>
+    where
+        Self: Sized,
+    {
+        SubCommand::with_name("anomalies")
+            .about("Writes double spends, duplicate inputs, RBF signaling and duplicate coinbase transactions to CSV file")
+            .version("0.1")
+            .arg(
+                Arg::with_name("output-dir")
+                    .long("output-dir")
+                    .value_name("PATH")
+                    .help("Folder to store anomalies.csv, created if necessary")
+                    .takes_value(true)
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name("bloom-size")
+                    .long("bloom-size")
+                    .value_name("MB")
+                    .help("Size of the filter of spent outpoints, hits are verified with the exact spends (default: 1024)")
+                    .takes_value(true),
+            )
+            .args(&OutputStore::args())
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let bloom_size = match matches.value_of("bloom-size") {
+            Some(_) => match value_t!(matches, "bloom-size", usize) {
+                Ok(n) if n > 0 => n,
+                _ => {
+                    return Err(OpError::from(String::from(
+                        "--bloom-size must be a positive number of megabytes.",
+                    )))
+                }
+            },
+            None => DEFAULT_BLOOM_SIZE,
+        };
+        let spends = OutputStore::from_matches(matches)?;
+        let cb = Anomalies {
+            output_dir: PathBuf::from(matches.value_of("output-dir").unwrap()),
+            writer: None,
+            spent_filter: BloomFilter::new(bloom_size * 8 * 1024 * 1024, BLOOM_HASHES),
+            spends: Some(spends),
+            start_height: 0,
+            counts: [0; 4],
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, _: &CoinType, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        if block_height != 0 {
+            warn!(target: "callback", "Starting at height {}, double spends of earlier spends are not detected.", block_height);
+        }
+        fs::create_dir_all(&self.output_dir)?;
+        let mut writer = BufWriter::with_capacity(
+            4000000,
+            File::create(self.output_dir.join("anomalies.csv.tmp"))?,
+        );
+        writer.write_all(b"height;txid;category\n")?;
+        self.writer = Some(writer);
+        info!(target: "callback", "Using `anomalies` with output dir: {} ...", &self.output_dir.display());
+        Ok(())
+    }
+
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<()> {
+        for tx in &block.txs {
+            self.check_tx(tx, block_height)?;
+        }
+        Ok(())
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        let mut writer = self.writer.take().unwrap();
+        writer.flush()?;
+        drop(writer);
+        let path = self.output_dir.join("anomalies.csv");
+        fs::rename(path.with_extension("csv.tmp"), &path)?;
+        self.spends.take().unwrap().destroy()?;
+
+        info!(target: "callback", "Done.\nDumped blocks {} to {} into {}:\n\
+                                   \t-> double_spend:       {:9}\n\
+                                   \t-> duplicate_input:    {:9}\n\
+                                   \t-> rbf_signal:         {:9}\n\
+                                   \t-> duplicate_coinbase: {:9}",
+              self.start_height, block_height, path.display(),
+              self.counts[0], self.counts[1], self.counts[2], self.counts[3]);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::path::Path;
+    use std::process;
+
+    use super::*;
+    use crate::blockchain::parser::types::Bitcoin;
+    use crate::callbacks::fixtures::{new_chain, new_tx};
+
+    fn new_anomalies(name: &str, extra_args: &[&str]) -> (Anomalies, PathBuf) {
+        let dir = env::temp_dir().join(format!("blockparser-anomalies-{}-{}", name, process::id()));
+        let mut args = vec!["anomalies", "--output-dir", dir.to_str().unwrap()];
+        args.extend_from_slice(extra_args);
+        let matches = Anomalies::build_subcommand().get_matches_from(args);
+        (Anomalies::new(&matches).unwrap(), dir)
+    }
+
+    /// Scans three blocks with a RBF signaling transaction, a double spend and a duplicate input
+    fn run(mut anomalies: Anomalies, dir: &Path) -> Anomalies {
+        let coinbase_0 = new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[5000000000, 100]);
+        let coinbase_1 = new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[5000000001]);
+        let spend = new_tx(&[(coinbase_0.hash, 0)], &[4999990000]);
+        let mut rbf = new_tx(&[(coinbase_1.hash, 0)], &[5000000000]).value;
+        rbf.inputs[0].seq_no = 0xfffffffd;
+        let rbf = Hashed::double_sha256(rbf);
+        let double_spend = new_tx(&[(coinbase_0.hash, 0)], &[4999980000]);
+        let duplicate_input = new_tx(&[(coinbase_0.hash, 1), (coinbase_0.hash, 1)], &[150]);
+        let txids: Vec<String> = [&rbf, &double_spend, &duplicate_input]
+            .iter()
+            .map(|tx| utils::arr_to_hex_swapped(&tx.hash))
+            .collect();
+        let chain = new_chain(vec![
+            vec![coinbase_0],
+            vec![coinbase_1, spend, rbf],
+            vec![double_spend, duplicate_input],
+        ]);
+
+        anomalies.on_start(&CoinType::from(Bitcoin), 0).unwrap();
+        for (height, block) in chain.iter().enumerate() {
+            anomalies.on_block(block, height as u64).unwrap();
+        }
+        anomalies.on_complete(2).unwrap();
+        let csv = fs::read_to_string(dir.join("anomalies.csv")).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+
+        assert_eq!(
+            vec![
+                String::from("height;txid;category"),
+                format!("1;{};rbf_signal", txids[0]),
+                format!("2;{};double_spend", txids[1]),
+                format!("2;{};duplicate_input", txids[2]),
+            ],
+            csv.lines().map(String::from).collect::<Vec<String>>()
+        );
+        anomalies
+    }
+
+    #[test]
+    fn test_anomalies() {
+        let (anomalies, dir) = new_anomalies("default", &[]);
+        let anomalies = run(anomalies, &dir);
+        assert_eq!([1, 1, 1, 0], anomalies.counts);
+    }
+
+    #[test]
+    fn test_false_positives() {
+        // Every outpoint hits the filter, only the spilled spends tell the double spend apart
+        let db_path = env::temp_dir().join(format!("blockparser-anomalies-db-{}", process::id()));
+        let (mut anomalies, dir) = new_anomalies(
+            "filter",
+            &["--db-path", db_path.to_str().unwrap(), "--buffer-size", "1"],
+        );
+        anomalies.spent_filter = BloomFilter::new(1, 1);
+        for n in 0..64u8 {
+            anomalies.spent_filter.insert(&[n]);
+        }
+        let anomalies = run(anomalies, &dir);
+        assert_eq!([1, 1, 1, 0], anomalies.counts);
+        assert!(!db_path.exists());
+    }
+
+    #[test]
+    fn test_duplicate_coinbase() {
+        let (mut anomalies, dir) = new_anomalies("coinbase", &[]);
+        let mut coinbase = new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[5000000000]);
+        coinbase.hash = utils::hex_to_arr32_swapped(DUPLICATE_COINBASES[1]);
+        anomalies.on_start(&CoinType::from(Bitcoin), 91722).unwrap();
+        anomalies.check_tx(&coinbase, 91722).unwrap();
+        anomalies.check_tx(&coinbase, 91880).unwrap();
+        anomalies.on_complete(91880).unwrap();
+        let csv = fs::read_to_string(dir.join("anomalies.csv")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(3, csv.lines().count());
+        assert!(csv.ends_with(&format!(
+            "91880;{};duplicate_coinbase\n",
+            DUPLICATE_COINBASES[1]
+        )));
+        assert_eq!([0, 0, 0, 2], anomalies.counts);
+    }
+
+    #[test]
+    fn test_invalid_args() {
+        let matches = Anomalies::build_subcommand().get_matches_from(vec![
+            "anomalies",
+            "--output-dir",
+            "out",
+            "--bloom-size",
+            "0",
+        ]);
+        assert!(Anomalies::new(&matches).is_err());
+    }
+}
@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 use std::fs::{self, File};
 use std::io::{BufWriter, Write};
 use std::path::PathBuf;
@@ -7,16 +7,21 @@ use clap::{App, Arg, ArgMatches, SubCommand};
 
 use crate::blockchain::parser::types::CoinType;
 use crate::blockchain::proto::block::Block;
-use crate::callbacks::{common, Callback};
-use crate::errors::OpResult;
+use crate::callbacks::address_tracker::{AddressSummary, AddressTracker};
+use crate::callbacks::Callback;
+use crate::errors::{OpError, OpResult};
 
 /// Dumps all addresses with non-zero balance in a csv file
 pub struct Balances {
     dump_folder: PathBuf,
     writer: BufWriter<File>,
 
-    // key: txid + index
-    unspents: HashMap<Vec<u8>, common::UnspentValue>,
+    tracker: AddressTracker,
+
+    // Heights after which `balances_<height>.csv` is written
+    snapshot_heights: BTreeSet<u64>,
+    snapshot_every: Option<u64>,
+    n_snapshots: usize,
 
     start_height: u64,
     end_height: u64,
@@ -26,6 +31,49 @@ impl Balances {
     fn create_writer(cap: usize, path: PathBuf) -> OpResult<BufWriter<File>> {
         Ok(BufWriter::with_capacity(cap, File::create(&path)?))
     }
+
+    fn is_snapshot_height(&self, block_height: u64) -> bool {
+        self.snapshot_heights.contains(&block_height)
+            || self
+                .snapshot_every
+                .is_some_and(|n| block_height > 0 && block_height.is_multiple_of(n))
+    }
+
+    /// Writes the balances after the given block into `balances_<height>.csv`
+    fn write_snapshot(&mut self, block_height: u64) -> OpResult<()> {
+        let tmp_path = self
+            .dump_folder
+            .join(format!("balances_{}.csv.tmp", block_height));
+        let mut writer = Balances::create_writer(4000000, tmp_path.clone())?;
+        let n_addresses = write_balances(self.tracker.addresses(), &mut writer)?;
+        writer
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+        fs::rename(
+            &tmp_path,
+            self.dump_folder
+                .join(format!("balances_{}.csv", block_height)),
+        )?;
+        self.n_snapshots += 1;
+        info!(target: "callback", "Wrote snapshot of {} addresses at height {}.", n_addresses, block_height);
+        Ok(())
+    }
+}
+
+/// Writes the addresses with a non-zero balance, returns the number of addresses
+fn write_balances<W: Write>(
+    addresses: &HashMap<String, AddressSummary>,
+    writer: &mut W,
+) -> OpResult<usize> {
+    writer.write_all(format!("{};{}\n", "address", "balance").as_bytes())?;
+
+    let mut n_addresses = 0;
+    for (address, summary) in addresses.iter().filter(|(_, s)| s.balance > 0) {
+        writer.write_all(format!("{};{}\n", address, summary.balance).as_bytes())?;
+        n_addresses += 1;
+    }
+    Ok(n_addresses)
 }
 
 impl Callback for Balances {
@@ -43,17 +91,59 @@ impl Callback for Balances {
                     .index(1)
                     .required(true),
             )
+            .arg(
+                Arg::with_name("snapshot-heights")
+                    .long("snapshot-heights")
+                    .value_name("HEIGHTS")
+                    .help("Also write balances_<height>.csv after each of the given blocks, e.g. 200000,300000")
+                    .use_delimiter(true)
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("snapshot-every")
+                    .long("snapshot-every")
+                    .value_name("N_BLOCKS")
+                    .help("Also write balances_<height>.csv after every block at a multiple of N")
+                    .takes_value(true),
+            )
     }
 
     fn new(matches: &ArgMatches) -> OpResult<Self>
     where
         Self: Sized,
     {
+        let snapshot_heights = match matches.values_of("snapshot-heights") {
+            Some(heights) => heights
+                .map(|height| {
+                    height.trim().parse::<u64>().map_err(|_| {
+                        OpError::from(format!(
+                            "Invalid height `{}` in --snapshot-heights.",
+                            height
+                        ))
+                    })
+                })
+                .collect::<OpResult<BTreeSet<u64>>>()?,
+            None => BTreeSet::new(),
+        };
+        let snapshot_every = match matches.value_of("snapshot-every") {
+            Some(_) => match value_t!(matches, "snapshot-every", u64) {
+                Ok(n) if n > 0 => Some(n),
+                _ => {
+                    return Err(OpError::from(String::from(
+                        "--snapshot-every must be a positive number of blocks.",
+                    )))
+                }
+            },
+            None => None,
+        };
         let dump_folder = &PathBuf::from(matches.value_of("dump-folder").unwrap());
         let cb = Balances {
             dump_folder: PathBuf::from(dump_folder),
             writer: Balances::create_writer(4000000, dump_folder.join("balances.csv.tmp"))?,
-            unspents: HashMap::with_capacity(10000000),
+            tracker: AddressTracker::in_memory(),
+            snapshot_heights,
+            snapshot_every,
+            n_snapshots: 0,
             start_height: 0,
             end_height: 0,
         };
@@ -69,14 +159,22 @@ impl Callback for Balances {
     /// For each transaction in the block
     ///   1. apply input transactions (remove (TxID == prevTxIDOut and prevOutID == spentOutID))
     ///   2. apply output transactions (add (TxID + curOutID -> HashMapVal))
+    ///
     /// For each address, retain:
     ///   * block height as "last modified"
     ///   * output_val
     ///   * address
+    fn wants_witnesses(&self) -> bool {
+        false
+    }
+
     fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<()> {
         for tx in &block.txs {
-            common::remove_unspents(&tx, &mut self.unspents);
-            common::insert_unspents(&tx, block_height, &mut self.unspents);
+            self.tracker.add_tx(tx, block_height)?;
+        }
+        // Ingestion pauses while the live map is streamed out
+        if self.is_snapshot_height(block_height) {
+            self.write_snapshot(block_height)?;
         }
         Ok(())
     }
@@ -84,21 +182,9 @@ impl Callback for Balances {
     fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
         self.end_height = block_height;
 
-        self.writer
-            .write_all(format!("{};{}\n", "address", "balance").as_bytes())?;
-
-        // Collect balances for each address
-        let mut balances: HashMap<String, u64> = HashMap::new();
-        for value in self.unspents.values() {
-            let entry = balances.entry(value.address.clone()).or_insert(0);
-            *entry += value.value
-        }
-
-        for (address, balance) in balances.iter() {
-            self.writer
-                .write_all(format!("{};{}\n", address, balance).as_bytes())?;
-        }
-
+        let n_addresses = write_balances(self.tracker.addresses(), &mut self.writer)?;
+        self.tracker.close()?;
+        self.writer.flush()?;
         fs::rename(
             self.dump_folder.as_path().join("balances.csv.tmp"),
             self.dump_folder.as_path().join(format!(
@@ -108,7 +194,129 @@ impl Callback for Balances {
         )
         .expect("Unable to rename tmp file!");
 
-        info!(target: "callback", "Done.\nDumped {} addresses.", balances.len());
+        info!(target: "callback", "Done.\nDumped {} addresses and {} snapshots.", n_addresses, self.n_snapshots);
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::path::Path;
+    use std::process;
+
+    use super::*;
+    use crate::blockchain::parser::types::Bitcoin;
+    use crate::callbacks::fixtures::{new_chain, new_tx_to, SCRIPT_A, SCRIPT_B};
+
+    const ADDRESS_A: &str = "12higDjoCCNXSA95xZMWUdPvXNmkAduhWv";
+    const ADDRESS_B: &str = "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa";
+
+    fn read_balances(path: &Path) -> HashMap<String, u64> {
+        let content = fs::read_to_string(path).unwrap();
+        let mut lines = content.lines();
+        assert_eq!(Some("address;balance"), lines.next());
+        lines
+            .map(|line| {
+                let (address, balance) = line.split_once(';').unwrap();
+                (String::from(address), balance.parse().unwrap())
+            })
+            .collect()
+    }
+
+    fn balances(entries: &[(&str, u64)]) -> HashMap<String, u64> {
+        entries
+            .iter()
+            .map(|(address, balance)| (String::from(*address), *balance))
+            .collect()
+    }
+
+    #[test]
+    fn test_snapshots() {
+        let coinbase = |value| new_tx_to(&[([0u8; 32], 0xFFFFFFFF)], &[(value, SCRIPT_A)]);
+        // 0: A gets 50
+        let coinbase_0 = coinbase(50);
+        // 1: A sends 30 to B, B gets 50 from the coinbase
+        let coinbase_1 = new_tx_to(&[([0u8; 32], 0xFFFFFFFF)], &[(50, SCRIPT_B)]);
+        let spend_1 = new_tx_to(&[(coinbase_0.hash, 0)], &[(20, SCRIPT_A), (30, SCRIPT_B)]);
+        // 2: B sends 30 back to A, A gets 51
+        let spend_2 = new_tx_to(&[(spend_1.hash, 1)], &[(30, SCRIPT_A)]);
+        // 3: A sends everything to B
+        let coinbase_3 = new_tx_to(&[([0u8; 32], 0xFFFFFFFF)], &[(52, SCRIPT_B)]);
+        let spend_3 = new_tx_to(&[(spend_1.hash, 0), (spend_2.hash, 0)], &[(50, SCRIPT_B)]);
+        let chain = new_chain(vec![
+            vec![coinbase_0],
+            vec![coinbase_1, spend_1],
+            vec![coinbase(51), spend_2],
+            vec![coinbase_3, spend_3],
+        ]);
+
+        let dir = env::temp_dir().join(format!("blockparser-balances-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let matches = Balances::build_subcommand().get_matches_from(vec![
+            "balances",
+            dir.to_str().unwrap(),
+            "--snapshot-heights",
+            "0,1",
+            "--snapshot-every",
+            "2",
+        ]);
+        let mut cb = Balances::new(&matches).unwrap();
+        cb.on_start(&CoinType::from(Bitcoin), 0).unwrap();
+        for (height, block) in chain.iter().enumerate() {
+            cb.on_block(block, height as u64).unwrap();
+            if height == 1 {
+                // Written while the scan continues
+                assert!(dir.join("balances_1.csv").exists());
+                assert!(!dir.join("balances_2.csv").exists());
+            }
+        }
+        cb.on_complete(4).unwrap();
+        assert_eq!(3, cb.n_snapshots);
+
+        assert_eq!(
+            balances(&[(ADDRESS_A, 50)]),
+            read_balances(&dir.join("balances_0.csv"))
+        );
+        assert_eq!(
+            balances(&[(ADDRESS_A, 20), (ADDRESS_B, 80)]),
+            read_balances(&dir.join("balances_1.csv"))
+        );
+        assert_eq!(
+            balances(&[(ADDRESS_A, 101), (ADDRESS_B, 50)]),
+            read_balances(&dir.join("balances_2.csv"))
+        );
+        assert_eq!(
+            balances(&[(ADDRESS_A, 51), (ADDRESS_B, 152)]),
+            read_balances(&dir.join("balances-0-4.csv"))
+        );
+        let mut files: Vec<String> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        files.sort();
+        assert_eq!(
+            vec![
+                "balances-0-4.csv",
+                "balances_0.csv",
+                "balances_1.csv",
+                "balances_2.csv"
+            ],
+            files
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_args() {
+        for args in [
+            vec!["--snapshot-heights", "100,abc"],
+            vec!["--snapshot-every", "0"],
+        ] {
+            let mut all_args = vec!["balances", "/nonexistent"];
+            all_args.extend_from_slice(&args);
+            let matches = Balances::build_subcommand().get_matches_from(all_args);
+            assert!(Balances::new(&matches).is_err(), "{:?}", args);
+        }
+    }
+}
@@ -0,0 +1,262 @@
+use std::fmt::Write as _;
+use std::time::{Duration, Instant};
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use crate::blockchain::parser::timings::{self, PHASES};
+use crate::blockchain::parser::types::CoinType;
+use crate::blockchain::proto::block::Block;
+use crate::callbacks::Callback;
+use crate::errors::{OpError, OpResult};
+
+/// Counts and timings of one pass over the range
+#[derive(Default, Clone, Copy)]
+struct Pass {
+    n_blocks: u64,
+    n_txs: u64,
+    n_inputs: u64,
+    n_outputs: u64,
+    /// Serialized size of the blocks
+    n_bytes: u64,
+    wall: Duration,
+    /// Time spent in each of `timings::PHASES`
+    phases: [Duration; PHASES.len()],
+}
+
+/// Measures the throughput of the parser. Does nothing with the blocks except counting them, and
+/// prints the wall time and the time spent in each phase of parsing once all passes are done.
+pub struct Bench {
+    repeat: usize,
+    started: Instant,
+    current: Pass,
+    finished: Vec<Pass>,
+}
+
+impl Bench {
+    /// Returns the report of the finished passes: the counts of the first pass, and the minimum
+    /// and median of the times and the throughput
+    fn report(&self) -> String {
+        let mut out = String::new();
+        let first = match self.finished.first() {
+            Some(pass) => pass,
+            None => return out,
+        };
+        let _ = writeln!(
+            out,
+            "Parsed {} blocks, {} transactions, {} inputs, {} outputs, {} bytes in {} passes",
+            first.n_blocks,
+            first.n_txs,
+            first.n_inputs,
+            first.n_outputs,
+            first.n_bytes,
+            self.finished.len()
+        );
+        let _ = writeln!(out, "{:<12} {:>12} {:>12}", "", "min", "median");
+        let seconds = |f: &dyn Fn(&Pass) -> Duration| {
+            min_median(
+                self.finished
+                    .iter()
+                    .map(|pass| f(pass).as_secs_f64())
+                    .collect(),
+            )
+        };
+        let (fastest, median) = seconds(&|pass| pass.wall);
+        let _ = writeln!(out, "{:<12} {:>11.3}s {:>11.3}s", "wall", fastest, median);
+        for (i, phase) in PHASES.iter().enumerate() {
+            let (min, median) = seconds(&|pass| pass.phases[i]);
+            let _ = writeln!(out, "{:<12} {:>11.3}s {:>11.3}s", phase.name(), min, median);
+        }
+        // The fastest pass has the highest throughput
+        for (name, count) in [
+            ("blocks/s", first.n_blocks as f64),
+            ("txs/s", first.n_txs as f64),
+            ("MB/s", first.n_bytes as f64 / 1e6),
+        ] {
+            let _ = writeln!(
+                out,
+                "{:<12} {:>12.1} {:>12.1}",
+                name,
+                rate(count, fastest),
+                rate(count, median)
+            );
+        }
+        out
+    }
+}
+
+/// Returns the minimum and the median, averaging the two middle values of an even count
+fn min_median(mut values: Vec<f64>) -> (f64, f64) {
+    values.sort_by(f64::total_cmp);
+    let mid = values.len() / 2;
+    let median = if values.len().is_multiple_of(2) {
+        (values[mid - 1] + values[mid]) / 2.0
+    } else {
+        values[mid]
+    };
+    (values[0], median)
+}
+
+fn rate(count: f64, seconds: f64) -> f64 {
+    if seconds > 0.0 {
+        count / seconds
+    } else {
+        0.0
+    }
+}
+
+impl Callback for Bench {
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b>
+    where
+        Self: Sized,
+    {
+        SubCommand::with_name("bench")
+            .about("Measures the parse throughput without any output and prints a timing breakdown")
+            .version("0.1")
+            .arg(
+                Arg::with_name("repeat")
+                    .long("repeat")
+                    .value_name("N")
+                    .help(
+                        "Parses the range N times and reports the minimum and median (default: 1)",
+                    )
+                    .takes_value(true),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let repeat = match matches.value_of("repeat") {
+            Some(_) => value_t!(matches, "repeat", usize)
+                .ok()
+                .filter(|n| *n > 0)
+                .ok_or_else(|| OpError::from(String::from("--repeat must be a positive number")))?,
+            None => 1,
+        };
+        Ok(Bench {
+            repeat,
+            started: Instant::now(),
+            current: Pass::default(),
+            finished: Vec::with_capacity(repeat),
+        })
+    }
+
+    fn wants_timings(&self) -> bool {
+        true
+    }
+
+    fn passes(&self) -> usize {
+        self.repeat
+    }
+
+    fn on_start(&mut self, _: &CoinType, _: u64) -> OpResult<()> {
+        self.current = Pass::default();
+        self.started = Instant::now();
+        Ok(())
+    }
+
+    fn on_block(&mut self, block: &Block, _: u64) -> OpResult<()> {
+        self.current.n_blocks += 1;
+        self.current.n_bytes += block.size as u64;
+        for tx in &block.txs {
+            self.current.n_txs += 1;
+            self.current.n_inputs += tx.value.in_count.value;
+            self.current.n_outputs += tx.value.out_count.value;
+        }
+        Ok(())
+    }
+
+    fn on_complete(&mut self, _: u64) -> OpResult<()> {
+        self.current.wall = self.started.elapsed();
+        for (i, phase) in PHASES.iter().enumerate() {
+            self.current.phases[i] = timings::spent(*phase);
+        }
+        self.finished.push(self.current);
+        info!(target: "callback", "Pass {} of {} took {:.3}s.", self.finished.len(), self.repeat, self.current.wall.as_secs_f64());
+        if self.finished.len() == self.repeat {
+            print!("{}", self.report());
+        }
+        Ok(())
+    }
+
+    /// Reports the passes finished before, the interrupted one is incomplete
+    fn on_interrupt(&mut self, _: u64) -> OpResult<()> {
+        print!("{}", self.report());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::parser::types::Bitcoin;
+    use crate::callbacks::fixtures::{new_chain, new_tx};
+
+    fn bench(args: &[&str]) -> OpResult<Bench> {
+        let mut all_args = vec!["bench"];
+        all_args.extend_from_slice(args);
+        Bench::new(&Bench::build_subcommand().get_matches_from(all_args))
+    }
+
+    #[test]
+    fn test_bench() {
+        let mut bench = bench(&["--repeat", "2"]).unwrap();
+        assert_eq!(2, bench.passes());
+        let chain = new_chain(vec![
+            vec![new_tx(&[], &[50])],
+            vec![new_tx(&[], &[50]), new_tx(&[([1; 32], 0)], &[10, 20])],
+        ]);
+        for _ in 0..2 {
+            bench.on_start(&CoinType::from(Bitcoin), 0).unwrap();
+            for (height, block) in chain.iter().enumerate() {
+                bench.on_block(block, height as u64).unwrap();
+            }
+            bench.on_complete(chain.len() as u64).unwrap();
+        }
+        let pass = bench.finished[1];
+        assert_eq!(
+            (2, 3, 1, 4),
+            (pass.n_blocks, pass.n_txs, pass.n_inputs, pass.n_outputs)
+        );
+        let size: u32 = chain.iter().map(|block| block.size).sum();
+        assert_eq!(size as u64, pass.n_bytes);
+
+        bench.finished[0].wall = Duration::from_millis(500);
+        bench.finished[1].wall = Duration::from_millis(1500);
+        bench.finished[0].phases[0] = Duration::from_millis(100);
+        bench.finished[1].phases[0] = Duration::from_millis(200);
+        let report = bench.report();
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(
+            format!(
+                "Parsed 2 blocks, 3 transactions, 1 inputs, 4 outputs, {} bytes in 2 passes",
+                size
+            ),
+            lines[0]
+        );
+        assert_eq!(
+            vec![
+                "wall               0.500s       1.000s",
+                "read               0.100s       0.150s",
+                "blocks/s              4.0          2.0",
+                "txs/s                 6.0          3.0",
+            ],
+            vec![lines[2], lines[3], lines[7], lines[8]]
+        );
+    }
+
+    #[test]
+    fn test_min_median() {
+        assert_eq!((1.0, 2.0), min_median(vec![3.0, 1.0, 2.0]));
+        assert_eq!((1.0, 2.5), min_median(vec![4.0, 1.0, 3.0, 2.0]));
+        assert_eq!((5.0, 5.0), min_median(vec![5.0]));
+    }
+
+    #[test]
+    fn test_invalid_args() {
+        assert!(bench(&["--repeat", "0"]).is_err());
+        assert!(bench(&["--repeat", "x"]).is_err());
+        assert_eq!(1, bench(&[]).unwrap().repeat);
+    }
+}
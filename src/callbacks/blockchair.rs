@@ -0,0 +1,768 @@
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::PathBuf;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use crate::blockchain::parser::types::CoinType;
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::script::{self, AddressFormat, ScriptPattern};
+use crate::blockchain::proto::target::{self, U256};
+use crate::blockchain::proto::tx::TxOutpoint;
+use crate::callbacks::Callback;
+use crate::callbacks::{coinbase, common};
+use crate::common::outputstore::{outpoint_key, read_varint, write_varint, OutputStore};
+use crate::common::utils;
+use crate::errors::{OpError, OpResult};
+
+/// Documented columns of the Blockchair dumps, keep in sync with the rows of `on_block()`
+const BLOCK_COLUMNS: [&str; 36] = [
+    "id",
+    "hash",
+    "time",
+    "median_time",
+    "size",
+    "stripped_size",
+    "weight",
+    "version",
+    "version_hex",
+    "version_bits",
+    "merkle_root",
+    "nonce",
+    "bits",
+    "difficulty",
+    "chainwork",
+    "coinbase_data_hex",
+    "transaction_count",
+    "witness_count",
+    "input_count",
+    "output_count",
+    "input_total",
+    "input_total_usd",
+    "output_total",
+    "output_total_usd",
+    "fee_total",
+    "fee_total_usd",
+    "fee_per_kb",
+    "fee_per_kb_usd",
+    "fee_per_kwu",
+    "fee_per_kwu_usd",
+    "cdd_total",
+    "generation",
+    "generation_usd",
+    "reward",
+    "reward_usd",
+    "guessed_miner",
+];
+const TX_COLUMNS: [&str; 22] = [
+    "block_id",
+    "hash",
+    "time",
+    "size",
+    "weight",
+    "version",
+    "lock_time",
+    "is_coinbase",
+    "has_witness",
+    "input_count",
+    "output_count",
+    "input_total",
+    "input_total_usd",
+    "output_total",
+    "output_total_usd",
+    "fee",
+    "fee_usd",
+    "fee_per_kb",
+    "fee_per_kb_usd",
+    "fee_per_kwu",
+    "fee_per_kwu_usd",
+    "cdd_total",
+];
+/// Columns of inputs.tsv and outputs.tsv, the first `N_OUTPUT_FIELDS` describe the output,
+/// the others the input spending it
+const OUTPUT_COLUMNS: [&str; 21] = [
+    "block_id",
+    "transaction_hash",
+    "index",
+    "time",
+    "value",
+    "value_usd",
+    "recipient",
+    "type",
+    "script_hex",
+    "is_from_coinbase",
+    "is_spendable",
+    "spending_block_id",
+    "spending_transaction_hash",
+    "spending_index",
+    "spending_time",
+    "spending_value_usd",
+    "spending_sequence",
+    "spending_signature_hex",
+    "spending_witness",
+    "lifespan",
+    "cdd",
+];
+const N_OUTPUT_FIELDS: usize = 11;
+/// Blocks of the median time past
+const MEDIAN_TIME_SPAN: usize = 11;
+
+/// Output as kept until it is spent
+struct StoredOutput {
+    block_id: u64,
+    time: u32,
+    value: u64,
+    is_from_coinbase: bool,
+    script_pubkey: Vec<u8>,
+}
+
+impl StoredOutput {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.script_pubkey.len() + 20);
+        write_varint(&mut bytes, self.block_id);
+        write_varint(&mut bytes, self.time as u64);
+        write_varint(&mut bytes, self.value);
+        bytes.push(self.is_from_coinbase as u8);
+        bytes.extend_from_slice(&self.script_pubkey);
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> OpResult<Self> {
+        let mut reader = bytes;
+        let block_id = read_varint(&mut reader)?;
+        let time = read_varint(&mut reader)? as u32;
+        let value = read_varint(&mut reader)?;
+        let (is_from_coinbase, script_pubkey) = reader
+            .split_first()
+            .ok_or_else(|| OpError::from(String::from("Truncated output record.")))?;
+        Ok(StoredOutput {
+            block_id,
+            time,
+            value,
+            is_from_coinbase: *is_from_coinbase != 0,
+            script_pubkey: script_pubkey.to_vec(),
+        })
+    }
+}
+
+/// Dumps the blockchain into the TSV files of Blockchair (https://gz.blockchair.com)
+pub struct Blockchair {
+    output_dir: PathBuf,
+    block_writer: BufWriter<File>,
+    tx_writer: BufWriter<File>,
+    input_writer: BufWriter<File>,
+    output_writer: BufWriter<File>,
+    // Outputs which are not spent yet
+    outputs: Option<OutputStore>,
+    // Spending columns of each spent output, only with --two-pass
+    spends: Option<OutputStore>,
+    address_format: AddressFormat,
+    genesis_bits: u32,
+    chainwork: Option<U256>,
+    recent_times: VecDeque<u32>,
+
+    start_height: u64,
+    end_height: u64,
+    tx_count: u64,
+    in_count: u64,
+    out_count: u64,
+    // Spent outputs created before the start height
+    n_unresolved: u64,
+}
+
+impl Blockchair {
+    fn create_writer(path: PathBuf, columns: &[&str]) -> OpResult<BufWriter<File>> {
+        let mut writer = BufWriter::with_capacity(4000000, File::create(path)?);
+        write_row(&mut writer, columns)?;
+        Ok(writer)
+    }
+
+    fn store_output(&mut self, outpoint: &TxOutpoint, output: &StoredOutput) -> OpResult<()> {
+        self.outputs
+            .as_mut()
+            .unwrap()
+            .insert(outpoint_key(outpoint), output.to_bytes())
+    }
+
+    /// Fills the spending columns of outputs.tsv from the spends recorded in the first pass
+    fn fill_spending_columns(&mut self, mut spends: OutputStore) -> OpResult<()> {
+        let path = self.output_dir.join("outputs.tsv.tmp");
+        let filled_path = self.output_dir.join("outputs.tsv.tmp2");
+        let reader = BufReader::with_capacity(4000000, File::open(&path)?);
+        let mut writer = BufWriter::with_capacity(4000000, File::create(&filled_path)?);
+        let mut n_filled = 0;
+        for (i, line) in reader.lines().enumerate() {
+            let line = line?;
+            let fields: Vec<&str> = line.splitn(N_OUTPUT_FIELDS + 1, '\t').collect();
+            if i == 0 || fields.len() <= N_OUTPUT_FIELDS {
+                writeln!(writer, "{}", line)?;
+                continue;
+            }
+            let index = fields[2]
+                .parse::<u32>()
+                .map_err(|_| OpError::from(format!("Invalid output index in {}", line)))?;
+            let txid = utils::hex_to_arr_swapped(fields[1])
+                .map_err(|_| OpError::from(format!("Invalid txid in {}", line)))?;
+            let outpoint = TxOutpoint::new(txid, index);
+            match spends.take(&outpoint_key(&outpoint))? {
+                Some(spending) => {
+                    writer.write_all(fields[..N_OUTPUT_FIELDS].join("\t").as_bytes())?;
+                    writer.write_all(b"\t")?;
+                    writer.write_all(&spending)?;
+                    writer.write_all(b"\n")?;
+                    n_filled += 1;
+                }
+                None => writeln!(writer, "{}", line)?,
+            }
+        }
+        writer.flush()?;
+        fs::rename(&filled_path, &path)?;
+        spends.destroy()?;
+        debug!(target: "callback", "Filled the spending columns of {} outputs.", n_filled);
+        Ok(())
+    }
+}
+
+/// Writes the fields separated by tabs, none of them can contain tabs or line breaks
+fn write_row<W: Write, S: AsRef<str>>(writer: &mut W, fields: &[S]) -> OpResult<()> {
+    let row: Vec<&str> = fields.iter().map(|field| field.as_ref()).collect();
+    writer.write_all(row.join("\t").as_bytes())?;
+    writer.write_all(b"\n")?;
+    Ok(())
+}
+
+#[inline]
+fn flag(value: bool) -> String {
+    String::from(if value { "1" } else { "0" })
+}
+
+#[inline]
+fn optional<T: ToString>(value: Option<T>) -> String {
+    value.map(|value| value.to_string()).unwrap_or_default()
+}
+
+/// Amount per 1000 units (bytes or weight units)
+#[inline]
+fn per_kilo(fee: Option<u64>, units: u64) -> String {
+    match fee {
+        Some(fee) if units > 0 => (fee as f64 * 1000.0 / units as f64).to_string(),
+        _ => String::new(),
+    }
+}
+
+/// Describes an output by the first `N_OUTPUT_FIELDS` columns
+fn output_fields(
+    output: &StoredOutput,
+    txid: &str,
+    index: u32,
+    address_format: AddressFormat,
+) -> Vec<String> {
+    let evaluated = script::eval_from_bytes(&output.script_pubkey, address_format);
+    // Outputs without an address get a synthetic one derived from their script
+    let recipient = match (&evaluated.address, &evaluated.pattern) {
+        (Some(address), _) => address.clone(),
+        (None, pattern) => format!(
+            "{}-{}",
+            if let ScriptPattern::DataOutput(_) = pattern {
+                "d"
+            } else {
+                "s"
+            },
+            utils::arr_to_hex(&utils::sha256(&output.script_pubkey)[..16])
+        ),
+    };
+    let (type_name, is_spendable) = match &evaluated.pattern {
+        ScriptPattern::DataOutput(_) => ("nulldata", Some(false)),
+        ScriptPattern::NotRecognised | ScriptPattern::Error(_) => ("nonstandard", None),
+        pattern => (pattern.type_name(), Some(true)),
+    };
+    vec![
+        output.block_id.to_string(),
+        String::from(txid),
+        index.to_string(),
+        utils::timestamp_to_datetime(output.time),
+        output.value.to_string(),
+        String::new(),
+        recipient,
+        String::from(type_name),
+        utils::arr_to_hex(&output.script_pubkey),
+        flag(output.is_from_coinbase),
+        optional(is_spendable.map(flag)),
+    ]
+}
+
+impl Callback for Blockchair {
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b>
+    where
+        Self: Sized,
+    {
+        SubCommand::with_name("blockchair")
+            .about("Dumps the blockchain into the TSV files of Blockchair")
+            .version("0.1")
+            .arg(
+                Arg::with_name("output-dir")
+                    .long("output-dir")
+                    .value_name("PATH")
+                    .help("Folder to store blocks.tsv, transactions.tsv, inputs.tsv and outputs.tsv, created if necessary")
+                    .takes_value(true)
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name("two-pass")
+                    .long("two-pass")
+                    .help("Fill the spending columns of outputs.tsv in a second pass at the end, spends are indexed on disk in the dump folder"),
+            )
+            .args(&OutputStore::args())
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let output_dir = PathBuf::from(matches.value_of("output-dir").unwrap());
+        fs::create_dir_all(&output_dir)?;
+        let outputs = OutputStore::from_matches(matches)?;
+        let buffer_size = OutputStore::buffer_size(matches)?;
+        let spends = match matches.is_present("two-pass") {
+            true => Some(OutputStore::open(
+                Some(&output_dir.join("spends.tmp")),
+                buffer_size,
+            )?),
+            false => None,
+        };
+        let cb = Blockchair {
+            block_writer: Blockchair::create_writer(
+                output_dir.join("blocks.tsv.tmp"),
+                &BLOCK_COLUMNS,
+            )?,
+            tx_writer: Blockchair::create_writer(
+                output_dir.join("transactions.tsv.tmp"),
+                &TX_COLUMNS,
+            )?,
+            input_writer: Blockchair::create_writer(
+                output_dir.join("inputs.tsv.tmp"),
+                &OUTPUT_COLUMNS,
+            )?,
+            output_writer: Blockchair::create_writer(
+                output_dir.join("outputs.tsv.tmp"),
+                &OUTPUT_COLUMNS,
+            )?,
+            output_dir,
+            outputs: Some(outputs),
+            spends,
+            address_format: AddressFormat::default(),
+            genesis_bits: 0,
+            chainwork: None,
+            recent_times: VecDeque::with_capacity(MEDIAN_TIME_SPAN),
+            start_height: 0,
+            end_height: 0,
+            tx_count: 0,
+            in_count: 0,
+            out_count: 0,
+            n_unresolved: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, coin_type: &CoinType, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        self.address_format = coin_type.address_format();
+        self.genesis_bits = coin_type.genesis_bits;
+        // Chain work is only known if the dump starts at the genesis block
+        self.chainwork = if block_height == 0 {
+            Some(U256::ZERO)
+        } else {
+            None
+        };
+        if block_height != 0 {
+            warn!(target: "callback", "Starting at height {}, inputs spending earlier outputs are incomplete.", block_height);
+        }
+        info!(target: "callback", "Using `blockchair` with output dir: {} ...", &self.output_dir.display());
+        Ok(())
+    }
+
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<()> {
+        let header = &block.header.value;
+        if let Some(chainwork) = self.chainwork.as_mut() {
+            let target = U256::from_compact(header.bits).unwrap_or_default();
+            *chainwork = *chainwork + target.work();
+        }
+        if self.recent_times.len() == MEDIAN_TIME_SPAN {
+            self.recent_times.pop_front();
+        }
+        self.recent_times.push_back(header.timestamp);
+        let mut times: Vec<u32> = self.recent_times.iter().copied().collect();
+        times.sort_unstable();
+        let median_time = times[times.len() / 2];
+
+        let time = utils::timestamp_to_datetime(header.timestamp);
+        let block_hash = utils::arr_to_hex_swapped(&block.header.hash);
+        let (mut input_count, mut output_count, mut witness_count) = (0, 0, 0);
+        let (mut input_total, mut output_total, mut fee_total) = (0, 0, Some(0));
+        let mut cdd_total = 0.0;
+        let mut reward = 0;
+        for tx in &block.txs {
+            let txid = utils::arr_to_hex_swapped(&tx.hash);
+            let is_coinbase = tx.value.is_coinbase();
+            let tx_size = tx.value.size as u64;
+            let tx_weight = tx.value.weight();
+
+            let mut tx_input_total = Some(0);
+            let mut tx_cdd = 0.0;
+            // The coinbase input doesn't spend an output
+            for (i, input) in tx.value.inputs.iter().enumerate().filter(|_| !is_coinbase) {
+                let lookup = self
+                    .outputs
+                    .as_mut()
+                    .unwrap()
+                    .take(&outpoint_key(&input.outpoint))?;
+                let spent = match lookup {
+                    Some(bytes) => Some(StoredOutput::from_bytes(&bytes)?),
+                    None => {
+                        self.n_unresolved += 1;
+                        None
+                    }
+                };
+                let lifespan = spent
+                    .as_ref()
+                    .map(|spent| (header.timestamp as u64).saturating_sub(spent.time as u64));
+                let cdd = spent
+                    .as_ref()
+                    .zip(lifespan)
+                    .map(|(spent, lifespan)| common::coin_days(spent.value, lifespan));
+                tx_input_total = tx_input_total
+                    .zip(spent.as_ref())
+                    .map(|(total, spent)| total + spent.value);
+                tx_cdd += cdd.unwrap_or_default();
+
+                let witness = tx
+                    .value
+                    .witnesses
+                    .get(i)
+                    .map(|items| {
+                        let items: Vec<String> =
+                            items.iter().map(|item| utils::arr_to_hex(item)).collect();
+                        items.join(",")
+                    })
+                    .unwrap_or_default();
+                let spending = [
+                    block_height.to_string(),
+                    txid.clone(),
+                    i.to_string(),
+                    time.clone(),
+                    String::new(),
+                    input.seq_no.to_string(),
+                    utils::arr_to_hex(&input.script_sig),
+                    witness,
+                    optional(lifespan),
+                    optional(cdd),
+                ];
+
+                let spent_txid = utils::arr_to_hex_swapped(&input.outpoint.txid);
+                let mut row = match &spent {
+                    Some(spent) => output_fields(
+                        spent,
+                        &spent_txid,
+                        input.outpoint.index,
+                        self.address_format,
+                    ),
+                    // Only the outpoint is known
+                    None => {
+                        let mut row = vec![String::new(); N_OUTPUT_FIELDS];
+                        row[1] = spent_txid;
+                        row[2] = input.outpoint.index.to_string();
+                        row
+                    }
+                };
+                row.extend_from_slice(&spending);
+                write_row(&mut self.input_writer, &row)?;
+                if let Some(spends) = self.spends.as_mut() {
+                    spends.insert(
+                        outpoint_key(&input.outpoint),
+                        spending.join("\t").into_bytes(),
+                    )?;
+                }
+            }
+
+            let mut tx_output_total = 0;
+            for (i, output) in tx.value.outputs.iter().enumerate() {
+                let stored = StoredOutput {
+                    block_id: block_height,
+                    time: header.timestamp,
+                    value: output.out.value,
+                    is_from_coinbase: is_coinbase,
+                    script_pubkey: output.out.script_pubkey.clone(),
+                };
+                let mut row = output_fields(&stored, &txid, i as u32, self.address_format);
+                row.resize(OUTPUT_COLUMNS.len(), String::new());
+                write_row(&mut self.output_writer, &row)?;
+                tx_output_total += output.out.value;
+                // Data outputs can't be spent
+                if let ScriptPattern::DataOutput(_) = output.script.pattern {
+                    continue;
+                }
+                self.store_output(&TxOutpoint::new(tx.hash, i as u32), &stored)?;
+            }
+
+            let fee = match is_coinbase {
+                true => Some(0),
+                false => tx_input_total.map(|total| total.saturating_sub(tx_output_total)),
+            };
+            write_row(
+                &mut self.tx_writer,
+                &[
+                    block_height.to_string(),
+                    txid,
+                    time.clone(),
+                    tx_size.to_string(),
+                    tx_weight.to_string(),
+                    tx.value.version.to_string(),
+                    tx.value.locktime.to_string(),
+                    flag(is_coinbase),
+                    flag(tx.value.has_witness()),
+                    tx.value.inputs.len().to_string(),
+                    tx.value.outputs.len().to_string(),
+                    optional(tx_input_total),
+                    String::new(),
+                    tx_output_total.to_string(),
+                    String::new(),
+                    optional(fee),
+                    String::new(),
+                    per_kilo(fee, tx_size),
+                    String::new(),
+                    per_kilo(fee, tx_weight),
+                    String::new(),
+                    tx_cdd.to_string(),
+                ],
+            )?;
+
+            input_count += tx.value.inputs.len();
+            output_count += tx.value.outputs.len();
+            witness_count += tx.value.has_witness() as usize;
+            input_total += tx_input_total.unwrap_or_default();
+            output_total += tx_output_total;
+            fee_total = fee_total.zip(fee).map(|(total, fee)| total + fee);
+            cdd_total += tx_cdd;
+            if is_coinbase {
+                reward = tx_output_total;
+            }
+        }
+
+        let coinbase_script = block
+            .txs
+            .first()
+            .filter(|tx| tx.value.is_coinbase())
+            .map(|tx| tx.value.inputs[0].script_sig.as_slice())
+            .unwrap_or_default();
+        let guessed_miner = match coinbase::pool_name(coinbase_script) {
+            coinbase::UNKNOWN_POOL => "Unknown",
+            pool => pool,
+        };
+        write_row(
+            &mut self.block_writer,
+            &[
+                block_height.to_string(),
+                block_hash,
+                time,
+                utils::timestamp_to_datetime(median_time),
+                block.size.to_string(),
+                block.stripped_size.to_string(),
+                block.weight.to_string(),
+                header.version.to_string(),
+                format!("{:x}", header.version),
+                format!("{:032b}", header.version),
+                utils::arr_to_hex_swapped(&header.merkle_root),
+                header.nonce.to_string(),
+                header.bits.to_string(),
+                optional(target::difficulty(header.bits, self.genesis_bits)),
+                optional(self.chainwork.map(|chainwork| chainwork.to_hex())),
+                utils::arr_to_hex(coinbase_script),
+                block.txs.len().to_string(),
+                witness_count.to_string(),
+                input_count.to_string(),
+                output_count.to_string(),
+                input_total.to_string(),
+                String::new(),
+                output_total.to_string(),
+                String::new(),
+                optional(fee_total),
+                String::new(),
+                per_kilo(fee_total, block.size as u64),
+                String::new(),
+                per_kilo(fee_total, block.weight),
+                String::new(),
+                cdd_total.to_string(),
+                optional(fee_total.map(|fee_total| reward.saturating_sub(fee_total))),
+                String::new(),
+                reward.to_string(),
+                String::new(),
+                String::from(guessed_miner),
+            ],
+        )?;
+
+        self.tx_count += block.txs.len() as u64;
+        self.in_count += input_count as u64;
+        self.out_count += output_count as u64;
+        Ok(())
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        self.end_height = block_height;
+        for writer in [
+            &mut self.block_writer,
+            &mut self.tx_writer,
+            &mut self.input_writer,
+            &mut self.output_writer,
+        ] {
+            writer.flush()?;
+        }
+        self.outputs.take().unwrap().destroy()?;
+        if let Some(spends) = self.spends.take() {
+            info!(target: "callback", "Filling the spending columns of outputs.tsv ...");
+            self.fill_spending_columns(spends)?;
+        }
+
+        // Keep in sync with c'tor
+        for f in &["blocks", "transactions", "inputs", "outputs"] {
+            fs::rename(
+                self.output_dir.join(format!("{}.tsv.tmp", f)),
+                self.output_dir.join(format!("{}.tsv", f)),
+            )?;
+        }
+
+        info!(target: "callback", "Done.\nDumped blocks {} to {}:\n\
+                                   \t-> transactions: {:9}\n\
+                                   \t-> inputs:       {:9}\n\
+                                   \t-> outputs:      {:9}\n\
+                                   \t-> unresolved:   {:9}",
+             self.start_height, self.end_height, self.tx_count, self.in_count, self.out_count,
+             self.n_unresolved);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+    use crate::blockchain::parser::types::Bitcoin;
+    use crate::blockchain::proto::Hashed;
+    use crate::blockchain::proto::ToRaw;
+    use crate::callbacks::fixtures::{
+        new_chain, new_tx, new_tx_to, set_witnesses, SCRIPT_A, SCRIPT_B,
+    };
+
+    const FILES: [&str; 4] = ["blocks", "transactions", "inputs", "outputs"];
+
+    fn chain() -> Vec<Block> {
+        let coinbase_0 = new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[5000000000]);
+        let coinbase_1 = new_tx_to(
+            &[([0u8; 32], 0xFFFFFFFF)],
+            &[(5000010000, SCRIPT_B), (0, "6a0401020304")],
+        );
+        let mut spend = new_tx_to(
+            &[(coinbase_0.hash, 0)],
+            &[(3000000000, SCRIPT_B), (1999990000, SCRIPT_A)],
+        );
+        // The witness doesn't change the txid
+        set_witnesses(&mut spend.value, vec![vec![vec![0x30, 0x44], vec![0x02]]]);
+        // Spends an output of the same block
+        let spend_again = new_tx(&[(spend.hash, 1)], &[1999990000]);
+        let mut chain = new_chain(vec![vec![coinbase_0], vec![coinbase_1, spend, spend_again]]);
+        // Ten days later
+        chain[1].header.value.timestamp += 864000;
+        chain[1].header = Hashed::double_sha256(chain[1].header.value.clone());
+        for block in chain.iter_mut() {
+            block.size = block.to_bytes().len() as u32;
+            block.measure();
+        }
+        chain
+    }
+
+    /// Dumps the chain from the given height and returns the contents of the files
+    fn run(args: &[&str], start_height: u64) -> (Blockchair, Vec<String>) {
+        let folder = env::temp_dir().join(format!(
+            "blockparser-blockchair-{}-{}-{}",
+            args.len(),
+            start_height,
+            process::id()
+        ));
+        fs::create_dir_all(&folder).unwrap();
+        let mut all_args = vec!["blockchair", "--output-dir", folder.to_str().unwrap()];
+        all_args.extend_from_slice(args);
+        let matches = Blockchair::build_subcommand().get_matches_from(all_args);
+        let mut blockchair = Blockchair::new(&matches).unwrap();
+        blockchair
+            .on_start(&CoinType::from(Bitcoin), start_height)
+            .unwrap();
+        for (height, block) in chain().iter().enumerate().skip(start_height as usize) {
+            blockchair.on_block(block, height as u64).unwrap();
+        }
+        blockchair.on_complete(1).unwrap();
+        let contents: Vec<String> = FILES
+            .iter()
+            .map(|f| fs::read_to_string(folder.join(format!("{}.tsv", f))).unwrap())
+            .collect();
+        assert!(!folder.join("spends.tmp").exists());
+        fs::remove_dir_all(&folder).unwrap();
+        (blockchair, contents)
+    }
+
+    #[test]
+    fn test_golden_files() {
+        let (blockchair, contents) = run(&[], 0);
+        assert_eq!(0, blockchair.n_unresolved);
+        assert_eq!(include_str!("testdata/blockchair_blocks.tsv"), contents[0]);
+        assert_eq!(
+            include_str!("testdata/blockchair_transactions.tsv"),
+            contents[1]
+        );
+        assert_eq!(include_str!("testdata/blockchair_inputs.tsv"), contents[2]);
+        assert_eq!(include_str!("testdata/blockchair_outputs.tsv"), contents[3]);
+
+        // Only the spending columns of the outputs differ
+        let (_, two_pass) = run(&["--two-pass", "--buffer-size", "1"], 0);
+        assert_eq!(contents[..3], two_pass[..3]);
+        assert_eq!(
+            include_str!("testdata/blockchair_outputs_two_pass.tsv"),
+            two_pass[3]
+        );
+    }
+
+    #[test]
+    fn test_start_height() {
+        let (blockchair, contents) = run(&["--two-pass"], 1);
+        assert_eq!(1, blockchair.n_unresolved);
+        // The input spending the output of block 0 only knows the outpoint
+        let txid_0 = utils::arr_to_hex_swapped(&chain()[0].txs[0].hash);
+        let input = contents[2].lines().nth(1).unwrap();
+        assert!(input.starts_with(&format!("\t{}\t0\t\t\t\t\t\t\t\t\t1\t", txid_0)));
+        // Neither fee nor input total of the transaction
+        let tx = contents[1].lines().nth(2).unwrap();
+        assert_eq!("", tx.split('\t').nth(11).unwrap());
+        assert_eq!("", tx.split('\t').nth(15).unwrap());
+        let block = contents[0].lines().nth(1).unwrap();
+        assert_eq!("", block.split('\t').nth(14).unwrap());
+    }
+
+    #[test]
+    fn test_stored_output() {
+        let output = StoredOutput {
+            block_id: 700000,
+            time: 1631333672,
+            value: 2100000000000000,
+            is_from_coinbase: true,
+            script_pubkey: utils::hex_to_vec(SCRIPT_A),
+        };
+        let parsed = StoredOutput::from_bytes(&output.to_bytes()).unwrap();
+        assert_eq!(output.block_id, parsed.block_id);
+        assert_eq!(output.time, parsed.time);
+        assert_eq!(output.value, parsed.value);
+        assert!(parsed.is_from_coinbase);
+        assert_eq!(output.script_pubkey, parsed.script_pubkey);
+        assert!(StoredOutput::from_bytes(&[0x01, 0x02, 0x03]).is_err());
+    }
+}
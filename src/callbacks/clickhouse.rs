@@ -0,0 +1,612 @@
+use std::env;
+use std::thread;
+use std::time::Duration;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use url::Url;
+
+use crate::blockchain::parser::types::CoinType;
+use crate::blockchain::proto::block::Block;
+use crate::callbacks::common::base_units;
+use crate::callbacks::Callback;
+use crate::common::http::{self, HttpError, Response};
+use crate::errors::{OpError, OpResult};
+
+const DEFAULT_URL: &str = "http://localhost:8123";
+const DEFAULT_DATABASE: &str = "default";
+const DEFAULT_USER: &str = "default";
+const PASSWORD_ENV: &str = "CLICKHOUSE_PASSWORD";
+const DEFAULT_BATCH_SIZE: usize = 500;
+// Large inserts can take a while until the server answers
+const TIMEOUT: Duration = Duration::from_secs(300);
+const MAX_RETRIES: u32 = 3;
+// Doubled after every attempt
+const RETRY_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Tables and their columns in RowBinary order, see `create_tables()`.
+/// Hashes are stored in display order, i.e. `hex(txid)` equals the usual txid string.
+const TABLES: [(&str, &str); 4] = [
+    (
+        "blocks",
+        "block_height, hash, version, size, prev_hash, merkle_root, timestamp, bits, nonce, tx_count",
+    ),
+    (
+        "transactions",
+        "txid, block_height, block_time, tx_index, version, lock_time, is_coinbase, has_witness, input_count, output_count",
+    ),
+    (
+        "tx_in",
+        "txid, block_height, block_time, index_in, prev_txid, prev_index, script_sig, sequence",
+    ),
+    (
+        "tx_out",
+        "txid, block_height, block_time, index_out, value, script_pub_key, address, script_type",
+    ),
+];
+
+/// Dumps the whole blockchain into ClickHouse, rows are sent with `INSERT ... FORMAT RowBinary`
+pub struct ClickHouse {
+    url: Url,
+    // Quoted identifier of the database
+    database: String,
+    user: String,
+    password: String,
+    create_tables: bool,
+    retry_backoff: Duration,
+
+    // Buffered rows in RowBinary format, one buffer per table (see TABLES)
+    rows: [Vec<u8>; 4],
+    batch_size: usize,
+    n_buffered: usize,
+    n_flushes: u64,
+
+    start_height: u64,
+    end_height: u64,
+    tx_count: u64,
+    in_count: u64,
+    out_count: u64,
+}
+
+impl Callback for ClickHouse {
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b>
+    where
+        Self: Sized,
+    {
+        SubCommand::with_name("clickhouse")
+            .about("Dumps the whole blockchain into a ClickHouse database")
+            .version("0.1")
+            .arg(
+                Arg::with_name("url")
+                    .long("url")
+                    .value_name("URL")
+                    .help("URL of the HTTP interface (default: http://localhost:8123)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("database")
+                    .long("database")
+                    .value_name("NAME")
+                    .help("Database containing the tables (default: default)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("user")
+                    .long("user")
+                    .value_name("USER")
+                    .help("User name (default: default)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("password")
+                    .long("password")
+                    .value_name("PASSWORD")
+                    .help("Password (default: $CLICKHOUSE_PASSWORD)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("create-tables")
+                    .long("create-tables")
+                    .help("Create database and MergeTree tables if necessary"),
+            )
+            .arg(
+                Arg::with_name("batch-size")
+                    .long("batch-size")
+                    .value_name("N")
+                    .help("Number of blocks to buffer before inserting (default: 500)")
+                    .takes_value(true),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let url = Url::parse(matches.value_of("url").unwrap_or(DEFAULT_URL))
+            .map_err(|e| OpError::from(format!("Invalid URL: {}.", e)))?;
+        http::validate_url(&url)?;
+        let password = match matches.value_of("password") {
+            Some(password) => String::from(password),
+            None => env::var(PASSWORD_ENV).unwrap_or_default(),
+        };
+        let cb = ClickHouse {
+            url,
+            database: quote_ident(matches.value_of("database").unwrap_or(DEFAULT_DATABASE)),
+            user: String::from(matches.value_of("user").unwrap_or(DEFAULT_USER)),
+            password,
+            create_tables: matches.is_present("create-tables"),
+            retry_backoff: RETRY_BACKOFF,
+            rows: Default::default(),
+            batch_size: value_t!(matches, "batch-size", usize)
+                .unwrap_or(DEFAULT_BATCH_SIZE)
+                .max(1),
+            n_buffered: 0,
+            n_flushes: 0,
+            start_height: 0,
+            end_height: 0,
+            tx_count: 0,
+            in_count: 0,
+            out_count: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, _: &CoinType, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        let version = self.execute("SELECT version()", &[]).map_err(|e| {
+            e.join_msg(&format!("Unable to connect to ClickHouse on {}.", self.url))
+        })?;
+        info!(target: "callback", "Using `clickhouse` with database {} on {} (version {}) ...",
+              self.database, self.url, String::from_utf8_lossy(&version.body).trim());
+        if self.create_tables {
+            for statement in create_tables(&self.database) {
+                self.execute(&statement, &[])?;
+            }
+        }
+        Ok(())
+    }
+
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<()> {
+        let [blocks, txs, inputs, outputs] = &mut self.rows;
+        let header = &block.header.value;
+        row_binary::uint64(blocks, block_height);
+        row_binary::hash(blocks, &block.header.hash);
+        row_binary::uint32(blocks, header.version);
+        row_binary::uint32(blocks, block.size);
+        row_binary::hash(blocks, &header.prev_hash);
+        row_binary::hash(blocks, &header.merkle_root);
+        row_binary::uint32(blocks, header.timestamp);
+        row_binary::uint32(blocks, header.bits);
+        row_binary::uint32(blocks, header.nonce);
+        row_binary::uint64(blocks, block.tx_count.value);
+
+        for (tx_index, tx) in block.txs.iter().enumerate() {
+            row_binary::hash(txs, &tx.hash);
+            row_binary::uint64(txs, block_height);
+            row_binary::uint32(txs, header.timestamp);
+            row_binary::uint32(txs, tx_index as u32);
+            row_binary::uint32(txs, tx.value.version);
+            row_binary::uint32(txs, tx.value.locktime);
+            row_binary::uint8(txs, tx.value.is_coinbase() as u8);
+            row_binary::uint8(txs, tx.value.has_witness() as u8);
+            row_binary::uint64(txs, tx.value.in_count.value);
+            row_binary::uint64(txs, tx.value.out_count.value);
+
+            for (i, input) in tx.value.inputs.iter().enumerate() {
+                row_binary::hash(inputs, &tx.hash);
+                row_binary::uint64(inputs, block_height);
+                row_binary::uint32(inputs, header.timestamp);
+                row_binary::uint32(inputs, i as u32);
+                row_binary::hash(inputs, &input.outpoint.txid);
+                row_binary::uint32(inputs, input.outpoint.index);
+                row_binary::string(inputs, &input.script_sig);
+                row_binary::uint32(inputs, input.seq_no);
+            }
+            for (i, output) in tx.value.outputs.iter().enumerate() {
+                row_binary::hash(outputs, &tx.hash);
+                row_binary::uint64(outputs, block_height);
+                row_binary::uint32(outputs, header.timestamp);
+                row_binary::uint32(outputs, i as u32);
+                row_binary::int64(outputs, base_units(output.out.value));
+                row_binary::string(outputs, &output.out.script_pubkey);
+                row_binary::nullable_string(
+                    outputs,
+                    output.script.address.as_ref().map(|a| a.as_bytes()),
+                );
+                row_binary::string(outputs, output.script.pattern.type_name().as_bytes());
+            }
+            self.in_count += tx.value.in_count.value;
+            self.out_count += tx.value.out_count.value;
+        }
+        self.tx_count += block.tx_count.value;
+
+        self.n_buffered += 1;
+        if self.n_buffered >= self.batch_size {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        self.end_height = block_height;
+        self.flush()?;
+
+        info!(target: "callback", "Done.\nDumped all {} blocks into database {}:\n\
+                                   \t-> transactions: {:9}\n\
+                                   \t-> inputs:       {:9}\n\
+                                   \t-> outputs:      {:9}\n\
+                                   \t-> flushes:      {:9}",
+             self.end_height, self.database, self.tx_count, self.in_count, self.out_count, self.n_flushes);
+        Ok(())
+    }
+}
+
+impl ClickHouse {
+    /// Inserts all buffered rows. The blocks table comes last,
+    /// so a stored block implies that its transactions are stored as well.
+    fn flush(&mut self) -> OpResult<()> {
+        if self.n_buffered == 0 {
+            return Ok(());
+        }
+        let rows = std::mem::take(&mut self.rows);
+        for ((table, columns), data) in TABLES.iter().zip(rows.iter()).rev() {
+            let query = format!(
+                "INSERT INTO {}.{} ({}) FORMAT RowBinary",
+                self.database, table, columns
+            );
+            self.execute(&query, data)
+                .map_err(|e| e.join_msg(&format!("Unable to insert rows into `{}`.", table)))?;
+            trace!(target: "clickhouse", "Inserted {} bytes into {}", data.len(), table);
+        }
+        debug!(target: "clickhouse", "Flushed {} blocks", self.n_buffered);
+        self.n_buffered = 0;
+        self.n_flushes += 1;
+        Ok(())
+    }
+
+    /// Runs a query with `data` as its input, retries on connection errors and
+    /// unavailable servers. Retried inserts might be stored twice if the connection broke
+    /// after the server received them.
+    fn execute(&self, query: &str, data: &[u8]) -> OpResult<Response> {
+        let mut url = self.url.clone();
+        url.query_pairs_mut().append_pair("query", query);
+        let headers = [
+            ("X-ClickHouse-User", self.user.as_str()),
+            ("X-ClickHouse-Key", self.password.as_str()),
+        ];
+        let mut attempt = 0;
+        loop {
+            let result = http::post(&url, &headers, data, TIMEOUT);
+            let retriable = match &result {
+                Ok(response) => matches!(response.status, 408 | 429 | 502 | 503 | 504),
+                Err(HttpError::Io(_)) => true,
+                Err(_) => false,
+            };
+            if retriable && attempt < MAX_RETRIES {
+                let backoff = self.retry_backoff * 2u32.pow(attempt);
+                match &result {
+                    Ok(response) => {
+                        warn!(target: "clickhouse", "Server returned {}, retrying in {:?} ...", response.status, backoff)
+                    }
+                    Err(err) => {
+                        warn!(target: "clickhouse", "{}, retrying in {:?} ...", err, backoff)
+                    }
+                }
+                thread::sleep(backoff);
+                attempt += 1;
+                continue;
+            }
+            let response = result?;
+            if !response.is_success() {
+                // The body contains the exception, e.g. `Code: 60. DB::Exception: Table ... doesn't exist`
+                return Err(OpError::from(format!(
+                    "ClickHouse returned {}: {}",
+                    response.status,
+                    String::from_utf8_lossy(&response.body).trim()
+                )));
+            }
+            return Ok(response);
+        }
+    }
+}
+
+/// Quotes an identifier, e.g. my`db -> `my\`db`
+fn quote_ident(ident: &str) -> String {
+    format!("`{}`", ident.replace('\\', "\\\\").replace('`', "\\`"))
+}
+
+/// Database and MergeTree tables, partitioned by the month of the block timestamp
+fn create_tables(database: &str) -> Vec<String> {
+    vec![
+        format!("CREATE DATABASE IF NOT EXISTS {}", database),
+        format!(
+            "CREATE TABLE IF NOT EXISTS {}.blocks (
+                block_height UInt64,
+                hash FixedString(32),
+                version UInt32,
+                size UInt32,
+                prev_hash FixedString(32),
+                merkle_root FixedString(32),
+                timestamp DateTime,
+                bits UInt32,
+                nonce UInt32,
+                tx_count UInt64
+            ) ENGINE = MergeTree PARTITION BY toYYYYMM(timestamp) ORDER BY (block_height)",
+            database
+        ),
+        format!(
+            "CREATE TABLE IF NOT EXISTS {}.transactions (
+                txid FixedString(32),
+                block_height UInt64,
+                block_time DateTime,
+                tx_index UInt32,
+                version UInt32,
+                lock_time UInt32,
+                is_coinbase UInt8,
+                has_witness UInt8,
+                input_count UInt64,
+                output_count UInt64
+            ) ENGINE = MergeTree PARTITION BY toYYYYMM(block_time) ORDER BY (txid)",
+            database
+        ),
+        format!(
+            "CREATE TABLE IF NOT EXISTS {}.tx_in (
+                txid FixedString(32),
+                block_height UInt64,
+                block_time DateTime,
+                index_in UInt32,
+                prev_txid FixedString(32),
+                prev_index UInt32,
+                script_sig String,
+                sequence UInt32
+            ) ENGINE = MergeTree PARTITION BY toYYYYMM(block_time) ORDER BY (txid, index_in)",
+            database
+        ),
+        format!(
+            "CREATE TABLE IF NOT EXISTS {}.tx_out (
+                txid FixedString(32),
+                block_height UInt64,
+                block_time DateTime,
+                index_out UInt32,
+                value Int64,
+                script_pub_key String,
+                address Nullable(String),
+                script_type LowCardinality(String)
+            ) ENGINE = MergeTree PARTITION BY toYYYYMM(block_time) ORDER BY (txid, index_out)",
+            database
+        ),
+    ]
+}
+
+/// Encoding of the RowBinary format: little-endian integers,
+/// strings prefixed with their length as unsigned LEB128
+mod row_binary {
+    #[inline]
+    pub fn uint8(buf: &mut Vec<u8>, value: u8) {
+        buf.push(value);
+    }
+
+    /// Also used for DateTime (seconds since the epoch)
+    #[inline]
+    pub fn uint32(buf: &mut Vec<u8>, value: u32) {
+        buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    #[inline]
+    pub fn uint64(buf: &mut Vec<u8>, value: u64) {
+        buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    #[inline]
+    pub fn int64(buf: &mut Vec<u8>, value: i64) {
+        buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn string(buf: &mut Vec<u8>, value: &[u8]) {
+        let mut len = value.len();
+        while len >= 0x80 {
+            buf.push((len as u8) | 0x80);
+            len >>= 7;
+        }
+        buf.push(len as u8);
+        buf.extend_from_slice(value);
+    }
+
+    /// Null flag followed by the string if it's not null
+    pub fn nullable_string(buf: &mut Vec<u8>, value: Option<&[u8]>) {
+        match value {
+            Some(value) => {
+                buf.push(0);
+                string(buf, value);
+            }
+            None => buf.push(1),
+        }
+    }
+
+    /// FixedString(32) in display order (reversed)
+    #[inline]
+    pub fn hash(buf: &mut Vec<u8>, hash: &[u8; 32]) {
+        buf.extend(hash.iter().rev());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::parser::types::Bitcoin;
+    use crate::callbacks::fixtures::{new_chain, new_tx, new_tx_to, SCRIPT_A};
+
+    fn new_clickhouse(url: &Url, args: &[&str]) -> ClickHouse {
+        let mut all_args = vec!["clickhouse", "--url", url.as_str(), "--password", "secret"];
+        all_args.extend_from_slice(args);
+        let matches = ClickHouse::build_subcommand().get_matches_from(all_args);
+        let mut clickhouse = ClickHouse::new(&matches).unwrap();
+        clickhouse.retry_backoff = Duration::from_millis(1);
+        clickhouse
+    }
+
+    /// Returns the `query` parameter of a request target
+    fn query(target: &str) -> String {
+        let url = Url::parse("http://localhost")
+            .unwrap()
+            .join(target)
+            .unwrap();
+        let (_, query) = url.query_pairs().find(|(key, _)| key == "query").unwrap();
+        query.into_owned()
+    }
+
+    #[test]
+    fn test_row_binary() {
+        let mut buf = Vec::new();
+        row_binary::uint8(&mut buf, 1);
+        row_binary::uint32(&mut buf, 0x01020304);
+        row_binary::uint64(&mut buf, 5000000000);
+        row_binary::int64(&mut buf, -2);
+        assert_eq!(
+            vec![
+                0x01, 0x04, 0x03, 0x02, 0x01, 0x00, 0xf2, 0x05, 0x2a, 0x01, 0x00, 0x00, 0x00, 0xfe,
+                0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff
+            ],
+            buf
+        );
+
+        let mut buf = Vec::new();
+        row_binary::string(&mut buf, b"abc");
+        row_binary::string(&mut buf, b"");
+        row_binary::nullable_string(&mut buf, None);
+        row_binary::nullable_string(&mut buf, Some(b"a"));
+        assert_eq!(
+            vec![0x03, b'a', b'b', b'c', 0x00, 0x01, 0x00, 0x01, b'a'],
+            buf
+        );
+
+        let mut buf = Vec::new();
+        row_binary::string(&mut buf, &[0u8; 300]);
+        assert_eq!(&[0xac, 0x02, 0x00], &buf[..3]);
+        assert_eq!(302, buf.len());
+
+        let mut hash = [0u8; 32];
+        hash[0] = 0xff;
+        let mut buf = Vec::new();
+        row_binary::hash(&mut buf, &hash);
+        assert_eq!(32, buf.len());
+        assert_eq!(0xff, buf[31]);
+    }
+
+    #[test]
+    fn test_quote_ident() {
+        assert_eq!("`btc`", quote_ident("btc"));
+        assert_eq!("`my\\`db\\\\`", quote_ident("my`db\\"));
+    }
+
+    #[test]
+    fn test_insert() {
+        let (url, requests) = http::test_server(vec![(200, "23.8.1.1")]);
+        let mut clickhouse = new_clickhouse(
+            &url,
+            &["--database", "btc", "--create-tables", "--batch-size", "2"],
+        );
+        let coinbase_0 = new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[5000000000]);
+        let spending_tx = new_tx_to(&[(coinbase_0.hash, 0)], &[(4999990000, SCRIPT_A)]);
+        let chain = new_chain(vec![
+            vec![coinbase_0],
+            vec![
+                new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[5000010000]),
+                spending_tx,
+            ],
+            vec![new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[5000000000])],
+        ]);
+        clickhouse.on_start(&CoinType::from(Bitcoin), 0).unwrap();
+        for (height, block) in chain.iter().enumerate() {
+            clickhouse.on_block(block, height as u64).unwrap();
+        }
+        clickhouse.on_complete(2).unwrap();
+        assert_eq!(2, clickhouse.n_flushes);
+
+        let requests = requests.lock().unwrap();
+        let queries: Vec<String> = requests.iter().map(|r| query(&r.target)).collect();
+        assert_eq!("SELECT version()", queries[0]);
+        assert_eq!("CREATE DATABASE IF NOT EXISTS `btc`", queries[1]);
+        assert!(queries[2].starts_with("CREATE TABLE IF NOT EXISTS `btc`.blocks"));
+        assert!(queries[5].starts_with("CREATE TABLE IF NOT EXISTS `btc`.tx_out"));
+        assert_eq!(
+            format!(
+                "INSERT INTO `btc`.tx_out ({}) FORMAT RowBinary",
+                TABLES[3].1
+            ),
+            queries[6]
+        );
+        assert!(queries[9].starts_with("INSERT INTO `btc`.blocks ("));
+        assert_eq!(14, requests.len());
+        assert!(requests[0]
+            .headers
+            .contains(&(String::from("x-clickhouse-key"), String::from("secret"))));
+
+        // Blocks 0 and 1: height, hash, version and size
+        let blocks = &requests[9].body;
+        assert_eq!(2 * (8 + 32 + 4 + 4 + 32 + 32 + 4 * 3 + 8), blocks.len());
+        assert_eq!(&1u64.to_le_bytes(), &blocks[132..140]);
+        let mut hash = chain[1].header.hash;
+        hash.reverse();
+        assert_eq!(&hash, &blocks[140..172]);
+
+        // Output of the spending transaction, the last one of the first batch
+        let outputs = &requests[6].body;
+        let address = b"12higDjoCCNXSA95xZMWUdPvXNmkAduhWv";
+        let script_type = b"pubkeyhash";
+        let tail_len = 1 + address.len() + 1 + 1 + script_type.len();
+        let tail = &outputs[outputs.len() - tail_len..];
+        assert_eq!(&[0, address.len() as u8], &tail[..2]);
+        assert_eq!(address, &tail[2..2 + address.len()]);
+        let value_start = outputs.len() - tail_len - 26 - 8;
+        assert_eq!(
+            &4999990000i64.to_le_bytes(),
+            &outputs[value_start..value_start + 8]
+        );
+    }
+
+    #[test]
+    fn test_retry() {
+        let (url, requests) = http::test_server(vec![
+            (200, "23.8.1.1"),
+            (503, "unavailable"),
+            (502, "bad gateway"),
+            (200, ""),
+            (
+                500,
+                "Code: 60. DB::Exception: Table btc.tx_in doesn't exist.",
+            ),
+        ]);
+        let mut clickhouse = new_clickhouse(&url, &["--batch-size", "1"]);
+        let chain = new_chain(vec![vec![new_tx(
+            &[([0u8; 32], 0xFFFFFFFF)],
+            &[5000000000],
+        )]]);
+        clickhouse.on_start(&CoinType::from(Bitcoin), 0).unwrap();
+        let err = clickhouse.on_block(&chain[0], 0).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Unable to insert rows into `tx_in`."));
+        assert!(err.to_string().contains("DB::Exception: Table btc.tx_in"));
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(5, requests.len());
+        // The same insert three times, errors are not retried
+        assert!(query(&requests[1].target).starts_with("INSERT INTO `default`.tx_out"));
+        assert_eq!(requests[1].body, requests[3].body);
+        assert!(query(&requests[4].target).starts_with("INSERT INTO `default`.tx_in"));
+    }
+
+    #[test]
+    fn test_unreachable() {
+        let url = Url::parse("http://127.0.0.1:1/").unwrap();
+        let mut clickhouse = new_clickhouse(&url, &[]);
+        assert!(clickhouse.on_start(&CoinType::from(Bitcoin), 0).is_err());
+
+        let matches = ClickHouse::build_subcommand().get_matches_from(vec![
+            "clickhouse",
+            "--url",
+            "https://localhost:8443",
+        ]);
+        assert!(ClickHouse::new(&matches).is_err());
+    }
+}
@@ -0,0 +1,655 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::fmt::Write as FmtWrite;
+use std::fs::{self, File};
+use std::hash::BuildHasher;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use crate::blockchain::parser::types::CoinType;
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::tx::{EvaluatedTx, TxOutpoint};
+use crate::blockchain::proto::Hashed;
+use crate::callbacks::Callback;
+use crate::common::outputstore::{outpoint_key, read_varint, write_varint, OutputStore};
+use crate::common::utils;
+use crate::errors::{OpError, OpResult};
+
+/// Empty slot of the interner table and cluster of ids without one yet
+const NONE: u32 = u32::MAX;
+/// Initial number of slots of the interner table, a power of two
+const INITIAL_SLOTS: usize = 1024;
+/// Values which are a multiple of this (0.001 BTC) look like payments to the change heuristic
+const ROUND_VALUE: u64 = 100_000;
+/// Outputs of the same value a transaction needs at least to look like a coinjoin
+const COINJOIN_MIN_EQUAL: usize = 3;
+
+/// Assigns consecutive u32 ids to addresses. The address bytes are appended to one buffer and
+/// the open addressing table only holds ids, which takes about 50 bytes per address instead of
+/// a String and a hash map entry.
+struct Interner {
+    bytes: Vec<u8>,
+    // End of the address of each id in `bytes`
+    ends: Vec<u64>,
+    // Ids by hash of their address, NONE if empty. Never more than half full.
+    table: Vec<u32>,
+    hasher: RandomState,
+}
+
+impl Interner {
+    fn new() -> Self {
+        Interner {
+            bytes: Vec::new(),
+            ends: Vec::new(),
+            table: vec![NONE; INITIAL_SLOTS],
+            hasher: RandomState::new(),
+        }
+    }
+
+    #[inline]
+    fn len(&self) -> usize {
+        self.ends.len()
+    }
+
+    #[inline]
+    fn get(&self, id: u32) -> &[u8] {
+        let start = match id {
+            0 => 0,
+            id => self.ends[id as usize - 1] as usize,
+        };
+        &self.bytes[start..self.ends[id as usize] as usize]
+    }
+
+    /// Returns the id of the address and whether it was added by this call
+    fn intern(&mut self, address: &[u8]) -> OpResult<(u32, bool)> {
+        let slot = self.slot(address);
+        if self.table[slot] != NONE {
+            return Ok((self.table[slot], false));
+        }
+        if self.len() >= NONE as usize {
+            return Err(OpError::from(format!(
+                "More than {} addresses, ids are exhausted.",
+                NONE
+            )));
+        }
+        let id = self.len() as u32;
+        self.bytes.extend_from_slice(address);
+        self.ends.push(self.bytes.len() as u64);
+        self.table[slot] = id;
+        if self.len() * 2 > self.table.len() {
+            self.grow();
+        }
+        Ok((id, true))
+    }
+
+    /// Slot of the address, or the empty slot it belongs into (linear probing)
+    fn slot(&self, address: &[u8]) -> usize {
+        let mask = self.table.len() - 1;
+        let mut slot = self.hasher.hash_one(address) as usize & mask;
+        loop {
+            match self.table[slot] {
+                NONE => return slot,
+                id if self.get(id) == address => return slot,
+                _ => slot = (slot + 1) & mask,
+            }
+        }
+    }
+
+    fn grow(&mut self) {
+        self.table = vec![NONE; self.table.len() * 2];
+        for id in 0..self.len() as u32 {
+            let slot = self.slot(self.get(id));
+            self.table[slot] = id;
+        }
+    }
+}
+
+/// Disjoint sets of ids, union by size with path halving
+#[derive(Default)]
+struct UnionFind {
+    parent: Vec<u32>,
+    // Size of the set, only valid for roots
+    size: Vec<u32>,
+}
+
+impl UnionFind {
+    /// Adds the next id as a set of its own
+    fn push(&mut self) {
+        self.parent.push(self.parent.len() as u32);
+        self.size.push(1);
+    }
+
+    fn find(&mut self, mut id: u32) -> u32 {
+        while self.parent[id as usize] != id {
+            let grandparent = self.parent[self.parent[id as usize] as usize];
+            self.parent[id as usize] = grandparent;
+            id = grandparent;
+        }
+        id
+    }
+
+    /// Merges the sets of both ids, returns false if they were the same set
+    fn union(&mut self, a: u32, b: u32) -> bool {
+        let (a, b) = (self.find(a), self.find(b));
+        if a == b {
+            return false;
+        }
+        let (root, child) = if self.size[a as usize] >= self.size[b as usize] {
+            (a, b)
+        } else {
+            (b, a)
+        };
+        self.parent[child as usize] = root;
+        self.size[root as usize] += self.size[child as usize];
+        true
+    }
+}
+
+/// Clusters addresses with the common-input-ownership heuristic: all input addresses of a
+/// transaction are assumed to belong to the same entity. Optionally the change output joins
+/// the cluster of the inputs, and coinjoin-like transactions aren't merged.
+///
+/// Writes clusters.csv with the cluster of each address and cluster_summary.csv with the number
+/// of addresses and the total value received of each cluster. A cluster is identified by the
+/// id of its first seen address, ids are assigned in order of appearance.
+pub struct Cluster {
+    output_dir: PathBuf,
+    change_heuristic: bool,
+    skip_coinjoin: bool,
+    // Address id of the outputs which are not spent yet, empty for outputs without address
+    outputs: Option<OutputStore>,
+    addresses: Interner,
+    clusters: UnionFind,
+    // Sum of the output values of each address
+    received: Vec<u64>,
+
+    start_height: u64,
+    tx_count: u64,
+    merged_tx_count: u64,
+    change_count: u64,
+    coinjoin_count: u64,
+    unresolved_count: u64,
+}
+
+impl Cluster {
+    /// Resolves the inputs and returns their distinct address ids
+    fn input_addresses(&mut self, tx: &Hashed<EvaluatedTx>) -> OpResult<Vec<u32>> {
+        let mut ids = Vec::new();
+        let outputs = self.outputs.as_mut().unwrap();
+        for input in &tx.value.inputs {
+            match outputs.take(&outpoint_key(&input.outpoint))? {
+                Some(output) if output.is_empty() => (),
+                Some(output) => {
+                    let id = read_varint(&mut &output[..])? as u32;
+                    if !ids.contains(&id) {
+                        ids.push(id);
+                    }
+                }
+                None => {
+                    self.unresolved_count += 1;
+                    debug!(target: "callback", "Unable to resolve previous output {}:{}",
+                           utils::arr_to_hex_swapped(&input.outpoint.txid), input.outpoint.index);
+                }
+            }
+        }
+        Ok(ids)
+    }
+
+    /// Interns the output addresses and stores the outputs for later resolution.
+    /// Returns the address id and whether it's new for each output.
+    fn output_addresses(&mut self, tx: &Hashed<EvaluatedTx>) -> OpResult<Vec<Option<(u32, bool)>>> {
+        let mut ids = Vec::with_capacity(tx.value.outputs.len());
+        let outputs = self.outputs.as_mut().unwrap();
+        for (i, output) in tx.value.outputs.iter().enumerate() {
+            let mut value = Vec::new();
+            let id = match &output.script.address {
+                Some(address) => {
+                    let (id, is_new) = self.addresses.intern(address.as_bytes())?;
+                    if is_new {
+                        self.clusters.push();
+                        self.received.push(0);
+                    }
+                    self.received[id as usize] += output.out.value;
+                    write_varint(&mut value, id as u64);
+                    Some((id, is_new))
+                }
+                None => None,
+            };
+            outputs.insert(outpoint_key(&TxOutpoint::new(tx.hash, i as u32)), value)?;
+            ids.push(id);
+        }
+        Ok(ids)
+    }
+
+    /// Writes both files and returns the number of clusters
+    fn write_clusters(&mut self) -> OpResult<u64> {
+        let mut writer = BufWriter::with_capacity(
+            4000000,
+            File::create(self.output_dir.join("clusters.csv.tmp"))?,
+        );
+        writer.write_all(b"address;cluster\n")?;
+        let mut row = String::new();
+        // The first id of a cluster is its id, its received value becomes the cluster total
+        let mut cluster_ids = vec![NONE; self.addresses.len()];
+        for id in 0..self.addresses.len() as u32 {
+            let root = self.clusters.find(id) as usize;
+            if cluster_ids[root] == NONE {
+                cluster_ids[root] = id;
+            } else {
+                self.received[cluster_ids[root] as usize] += self.received[id as usize];
+            }
+            row.clear();
+            let address = std::str::from_utf8(self.addresses.get(id)).unwrap();
+            let _ = writeln!(row, "{};{}", address, cluster_ids[root]);
+            writer.write_all(row.as_bytes())?;
+        }
+        writer.flush()?;
+
+        let mut summary = BufWriter::with_capacity(
+            4000000,
+            File::create(self.output_dir.join("cluster_summary.csv.tmp"))?,
+        );
+        summary.write_all(b"cluster;size;total_received\n")?;
+        let mut cluster_count = 0;
+        for id in 0..self.addresses.len() as u32 {
+            let root = self.clusters.find(id) as usize;
+            if cluster_ids[root] != id {
+                continue;
+            }
+            row.clear();
+            let _ = writeln!(
+                row,
+                "{};{};{}",
+                id, self.clusters.size[root], self.received[id as usize]
+            );
+            summary.write_all(row.as_bytes())?;
+            cluster_count += 1;
+        }
+        summary.flush()?;
+
+        for name in ["clusters", "cluster_summary"] {
+            fs::rename(
+                self.output_dir.join(format!("{}.csv.tmp", name)),
+                self.output_dir.join(format!("{}.csv", name)),
+            )?;
+        }
+        Ok(cluster_count)
+    }
+}
+
+/// Returns the output which is likely the change: the only one paying a new address while the
+/// others pay round values. Change with a round value can't be told apart from a payment.
+fn change_output(tx: &EvaluatedTx, outputs: &[Option<(u32, bool)>]) -> Option<u32> {
+    if tx.outputs.len() < 2 {
+        return None;
+    }
+    let mut change = None;
+    for (output, address) in tx.outputs.iter().zip(outputs) {
+        let is_round = output.out.value % ROUND_VALUE == 0;
+        match address {
+            Some((id, true)) if !is_round && change.is_none() => change = Some(*id),
+            _ if is_round => (),
+            _ => return None,
+        }
+    }
+    change
+}
+
+/// Returns true if at least COINJOIN_MIN_EQUAL outputs have the same value and there are at least
+/// as many inputs as equal outputs, one per participant
+fn is_coinjoin_like(tx: &EvaluatedTx) -> bool {
+    let mut counts: HashMap<u64, usize> = HashMap::new();
+    for output in &tx.outputs {
+        *counts.entry(output.out.value).or_insert(0) += 1;
+    }
+    counts
+        .values()
+        .any(|n| *n >= COINJOIN_MIN_EQUAL && tx.inputs.len() >= *n)
+}
+
+impl Callback for Cluster {
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b>
+    where
+        Self: Sized,
+    {
+        SubCommand::with_name("cluster")
+            .about("Clusters addresses with the common-input-ownership heuristic")
+            .version("0.1")
+            .arg(
+                Arg::with_name("output-dir")
+                    .long("output-dir")
+                    .value_name("PATH")
+                    .help("Folder to store clusters.csv and cluster_summary.csv, created if necessary")
+                    .takes_value(true)
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name("with-change-heuristic")
+                    .long("with-change-heuristic")
+                    .help("Add the only output to a new address to the input cluster if the other outputs are round values"),
+            )
+            .arg(
+                Arg::with_name("skip-coinjoin-like")
+                    .long("skip-coinjoin-like")
+                    .help("Don't merge the inputs of transactions with 3 or more outputs of equal value"),
+            )
+            .args(&OutputStore::args())
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let outputs = OutputStore::from_matches(matches)?;
+        let cb = Cluster {
+            output_dir: PathBuf::from(matches.value_of("output-dir").unwrap()),
+            change_heuristic: matches.is_present("with-change-heuristic"),
+            skip_coinjoin: matches.is_present("skip-coinjoin-like"),
+            outputs: Some(outputs),
+            addresses: Interner::new(),
+            clusters: UnionFind::default(),
+            received: Vec::new(),
+            start_height: 0,
+            tx_count: 0,
+            merged_tx_count: 0,
+            change_count: 0,
+            coinjoin_count: 0,
+            unresolved_count: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, _: &CoinType, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        if block_height != 0 {
+            warn!(target: "callback", "Starting at height {}, inputs spending earlier outputs are not clustered.", block_height);
+        }
+        fs::create_dir_all(&self.output_dir)?;
+        info!(target: "callback", "Using `cluster` with output dir: {} ...", &self.output_dir.display());
+        Ok(())
+    }
+
+    fn wants_witnesses(&self) -> bool {
+        false
+    }
+
+    fn on_block(&mut self, _: &Block, _: u64) -> OpResult<()> {
+        Ok(())
+    }
+
+    fn on_transaction(&mut self, tx: &Hashed<EvaluatedTx>, _: u64, _: usize) -> OpResult<()> {
+        self.tx_count += 1;
+        let inputs = if tx.value.is_coinbase() {
+            Vec::new()
+        } else {
+            self.input_addresses(tx)?
+        };
+        let outputs = self.output_addresses(tx)?;
+        if inputs.is_empty() {
+            return Ok(());
+        }
+        if self.skip_coinjoin && is_coinjoin_like(&tx.value) {
+            self.coinjoin_count += 1;
+            return Ok(());
+        }
+        let mut merged = false;
+        for id in &inputs[1..] {
+            merged |= self.clusters.union(inputs[0], *id);
+        }
+        if self.change_heuristic {
+            if let Some(change) = change_output(&tx.value, &outputs) {
+                merged |= self.clusters.union(inputs[0], change);
+                self.change_count += 1;
+            }
+        }
+        if merged {
+            self.merged_tx_count += 1;
+        }
+        Ok(())
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        self.outputs.take().unwrap().destroy()?;
+        let cluster_count = self.write_clusters()?;
+
+        info!(target: "callback", "Done.\nClustered blocks {} to {} into {}:\n\
+                                   \t-> transactions:        {:9}\n\
+                                   \t-> merging:             {:9}\n\
+                                   \t-> change outputs:      {:9}\n\
+                                   \t-> coinjoin-like:       {:9}\n\
+                                   \t-> unresolved inputs:   {:9}\n\
+                                   \t-> addresses:           {:9}\n\
+                                   \t-> clusters:            {:9}",
+             self.start_height, block_height, self.output_dir.display(), self.tx_count,
+             self.merged_tx_count, self.change_count, self.coinjoin_count, self.unresolved_count,
+             self.addresses.len(), cluster_count);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+    use crate::blockchain::parser::types::Bitcoin;
+    use crate::callbacks::deliver_block;
+    use crate::callbacks::fixtures::{new_chain, new_tx_to};
+
+    const COINBASE: ([u8; 32], u32) = ([0u8; 32], 0xFFFFFFFF);
+
+    /// P2PKH script of a distinct address per number
+    fn script(n: u8) -> String {
+        format!("76a914{}88ac", utils::arr_to_hex(&[n; 20]))
+    }
+
+    /// Runs the callback over the chain, returns the cluster of each address by the number of its
+    /// script, and the summary rows
+    fn run(
+        args: &[&str],
+        name: &str,
+        blocks: Vec<Vec<Hashed<EvaluatedTx>>>,
+    ) -> (Cluster, HashMap<u8, u32>, Vec<String>) {
+        let dir = env::temp_dir().join(format!("blockparser-cluster-{}-{}", name, process::id()));
+        let mut all_args = vec!["cluster", "--output-dir", dir.to_str().unwrap()];
+        all_args.extend_from_slice(args);
+        let matches = Cluster::build_subcommand().get_matches_from(all_args);
+        // Scripts were generated from numbers, map the addresses back to them
+        let names: HashMap<String, u8> = (0..=255)
+            .filter_map(|n| {
+                let tx = new_tx_to(&[COINBASE], &[(0, &script(n))]);
+                tx.value.outputs[0].script.address.clone().map(|a| (a, n))
+            })
+            .collect();
+
+        let mut cluster = Cluster::new(&matches).unwrap();
+        cluster.on_start(&CoinType::from(Bitcoin), 0).unwrap();
+        let chain = new_chain(blocks);
+        for (height, block) in chain.iter().enumerate() {
+            deliver_block(&mut cluster, block, height as u64).unwrap();
+        }
+        cluster.on_complete(chain.len() as u64 - 1).unwrap();
+
+        let clusters = fs::read_to_string(dir.join("clusters.csv")).unwrap();
+        let mut lines = clusters.lines();
+        assert_eq!(Some("address;cluster"), lines.next());
+        let clusters = lines
+            .map(|line| {
+                let (address, id) = line.split_once(';').unwrap();
+                (names[address], id.parse().unwrap())
+            })
+            .collect();
+        let summary = fs::read_to_string(dir.join("cluster_summary.csv")).unwrap();
+        let summary = summary.lines().skip(1).map(String::from).collect();
+        fs::remove_dir_all(&dir).unwrap();
+        (cluster, clusters, summary)
+    }
+
+    /// Addresses 1 to 4 are mined. Tx a spends 1 and 2 to 5 (payment) and 6 (change), tx b spends
+    /// 3 to 7 (change) and two equal outputs to 8 and 9, tx c joins 4, 8, 9 and an unknown input
+    /// into three equal outputs and one to 4. Tx d spends the change of a and the output of c to 4.
+    fn graph() -> Vec<Vec<Hashed<EvaluatedTx>>> {
+        let coinbases: Vec<Hashed<EvaluatedTx>> = (1..=4)
+            .map(|n| new_tx_to(&[COINBASE], &[(5000000000 + n as u64, &script(n))]))
+            .collect();
+        let hashes: Vec<[u8; 32]> = coinbases.iter().map(|tx| tx.hash).collect();
+        let a = new_tx_to(
+            &[(hashes[0], 0), (hashes[1], 0)],
+            &[(300000000, &script(5)), (7000001234, &script(6))],
+        );
+        let b = new_tx_to(
+            &[(hashes[2], 0)],
+            &[
+                (123456, &script(7)),
+                (100000000, &script(8)),
+                (100000000, &script(9)),
+            ],
+        );
+        let c = new_tx_to(
+            &[(hashes[3], 0), (b.hash, 1), (b.hash, 2), ([9u8; 32], 0)],
+            &[
+                (100000000, &script(8)),
+                (100000000, &script(9)),
+                (100000000, &script(10)),
+                (4999000000, &script(4)),
+            ],
+        );
+        let d = new_tx_to(
+            &[(a.hash, 1), (c.hash, 3)],
+            &[(7000000000, &script(11)), (99000000, &script(12))],
+        );
+        vec![coinbases, vec![a, b], vec![c], vec![d]]
+    }
+
+    fn assert_single(clusters: &HashMap<u8, u32>, addresses: &[u8]) {
+        for n in addresses {
+            let size = clusters.values().filter(|id| **id == clusters[n]).count();
+            assert_eq!(1, size, "address {}", n);
+        }
+    }
+
+    #[test]
+    fn test_interner() {
+        let mut interner = Interner::new();
+        let addresses: Vec<String> = (0..5000).map(|i| format!("address{}", i)).collect();
+        for (i, address) in addresses.iter().enumerate() {
+            assert_eq!(
+                (i as u32, true),
+                interner.intern(address.as_bytes()).unwrap()
+            );
+        }
+        assert_eq!(16384, interner.table.len());
+        for (i, address) in addresses.iter().enumerate().rev() {
+            assert_eq!(
+                (i as u32, false),
+                interner.intern(address.as_bytes()).unwrap()
+            );
+            assert_eq!(address.as_bytes(), interner.get(i as u32));
+        }
+        assert_eq!((5000, true), interner.intern(b"").unwrap());
+        assert_eq!(b"", interner.get(5000));
+    }
+
+    #[test]
+    fn test_union_find() {
+        let mut sets = UnionFind::default();
+        (0..6).for_each(|_| sets.push());
+        assert!(sets.union(0, 1));
+        assert!(sets.union(2, 3));
+        assert!(sets.union(3, 1));
+        assert!(!sets.union(0, 2));
+        assert_eq!(sets.find(0), sets.find(3));
+        let root = sets.find(2);
+        assert_eq!(4, sets.size[root as usize]);
+        assert_ne!(sets.find(4), sets.find(0));
+        assert_ne!(sets.find(4), sets.find(5));
+    }
+
+    #[test]
+    fn test_multi_input() {
+        let (cluster, clusters, summary) = run(&[], "multi", graph());
+        assert_eq!(12, clusters.len());
+        // 1 and 2 by tx a, 4, 8 and 9 by tx c, 6 by tx d
+        assert_eq!(clusters[&1], clusters[&2]);
+        for n in [6, 8, 9] {
+            assert_eq!(clusters[&4], clusters[&n], "address {}", n);
+        }
+        assert_ne!(clusters[&1], clusters[&4]);
+        assert_single(&clusters, &[3, 5, 7, 10, 11, 12]);
+        // Ids in order of appearance, 4 is the fourth address
+        assert_eq!(8, summary.len());
+        assert_eq!("0;2;10000000003", summary[0]);
+        assert_eq!(3, clusters[&4]);
+        assert_eq!(
+            format!(
+                "3;4;{}",
+                5000000004u64 + 4999000000 + 7000001234 + 400000000
+            ),
+            summary[2]
+        );
+        assert_eq!(1, cluster.unresolved_count);
+        assert_eq!(3, cluster.merged_tx_count);
+        assert_eq!(0, cluster.change_count);
+    }
+
+    #[test]
+    fn test_heuristics() {
+        let (cluster, clusters, summary) = run(
+            &["--with-change-heuristic", "--skip-coinjoin-like"],
+            "heuristics",
+            graph(),
+        );
+        // Change 6 of tx a joins 1 and 2, tx d links it to 4. Tx b has the change 7 beside round
+        // outputs, tx c is skipped, tx d has only round outputs.
+        for n in [2, 4, 6] {
+            assert_eq!(clusters[&1], clusters[&n], "address {}", n);
+        }
+        assert_eq!(clusters[&3], clusters[&7]);
+        assert_ne!(clusters[&1], clusters[&3]);
+        assert_single(&clusters, &[5, 8, 9, 10, 11, 12]);
+        assert_eq!(8, summary.len());
+        assert_eq!(1, cluster.coinjoin_count);
+        assert_eq!(2, cluster.change_count);
+        assert_eq!(3, cluster.merged_tx_count);
+    }
+
+    #[test]
+    fn test_change_output() {
+        let tx = new_tx_to(
+            &[([1u8; 32], 0)],
+            &[
+                (100000000, &script(1)),
+                (12345, &script(2)),
+                (0, &script(3)),
+            ],
+        );
+        let new = |flags: [bool; 3]| -> Vec<Option<(u32, bool)>> {
+            flags
+                .iter()
+                .enumerate()
+                .map(|(i, f)| Some((i as u32, *f)))
+                .collect()
+        };
+        assert_eq!(
+            Some(1),
+            change_output(&tx.value, &new([false, true, false]))
+        );
+        assert_eq!(Some(1), change_output(&tx.value, &new([true, true, true])));
+        // Seen before, or another output isn't round
+        assert_eq!(None, change_output(&tx.value, &new([false, false, false])));
+        let tx = new_tx_to(
+            &[([1u8; 32], 0)],
+            &[(100000001, &script(1)), (12345, &script(2))],
+        );
+        assert_eq!(
+            None,
+            change_output(&tx.value, &new([false, true, false])[..2])
+        );
+        // A single output is no change
+        let tx = new_tx_to(&[([1u8; 32], 0)], &[(12345, &script(2))]);
+        assert_eq!(None, change_output(&tx.value, &[Some((0, true))]));
+    }
+}
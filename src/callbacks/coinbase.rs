@@ -0,0 +1,337 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use crate::blockchain::parser::types::CoinType;
+use crate::blockchain::proto::block::Block;
+use crate::callbacks::{common, Callback};
+use crate::common::utils;
+use crate::errors::OpResult;
+
+/// Shorter printable runs are most likely part of the extranonce
+const MIN_TAG_LEN: usize = 4;
+
+/// Substrings of the coinbase scriptSig (compared case insensitive) and the pool they belong to,
+/// the first match wins
+const POOLS: &[(&str, &str)] = &[
+    ("foundry usa", "Foundry USA"),
+    ("antpool", "AntPool"),
+    ("f2pool", "F2Pool"),
+    ("七彩神仙鱼", "F2Pool"),
+    ("viabtc", "ViaBTC"),
+    ("binance", "Binance Pool"),
+    ("mara pool", "MARA Pool"),
+    ("spiderpool", "SpiderPool"),
+    ("poolin", "Poolin"),
+    ("luxor", "Luxor"),
+    ("/slush/", "Braiins Pool"),
+    ("btc.com", "BTC.com"),
+    ("btc.top", "BTC.TOP"),
+    ("bitfury", "BitFury"),
+    ("ghash.io", "GHash.IO"),
+    ("btcguild", "BTC Guild"),
+    ("eligius", "Eligius"),
+    ("ckpool", "CKPool"),
+];
+pub(crate) const UNKNOWN_POOL: &str = "unknown";
+
+/// Returns the pool name of the first matching entry in `POOLS`
+pub(crate) fn pool_name(script_sig: &[u8]) -> &'static str {
+    let script_sig = String::from_utf8_lossy(script_sig).to_lowercase();
+    POOLS
+        .iter()
+        .find(|(tag, _)| script_sig.contains(tag))
+        .map(|(_, pool)| *pool)
+        .unwrap_or(UNKNOWN_POOL)
+}
+
+/// Returns the longest run of printable ASCII characters, or "" if it is shorter than `MIN_TAG_LEN`
+fn extract_tag(script_sig: &[u8]) -> String {
+    let tag = script_sig
+        .split(|b| !(0x20..0x7f).contains(b))
+        .fold(&[][..], |longest, run| {
+            if run.len() > longest.len() {
+                run
+            } else {
+                longest
+            }
+        });
+    if tag.len() < MIN_TAG_LEN {
+        return String::new();
+    }
+    String::from_utf8_lossy(tag).into_owned()
+}
+
+/// Decodes the height pushed first by the coinbase scriptSig (BIP34) as script number
+fn bip34_height(script_sig: &[u8]) -> Option<u64> {
+    let opcode = *script_sig.first()?;
+    match opcode {
+        // OP_0
+        0x00 => Some(0),
+        // OP_1 to OP_16
+        0x51..=0x60 => Some((opcode - 0x50) as u64),
+        0x01..=0x08 => {
+            let number = script_sig.get(1..1 + opcode as usize)?;
+            // Negative numbers are no heights
+            if number.last().unwrap() & 0x80 != 0 {
+                return None;
+            }
+            Some(
+                number
+                    .iter()
+                    .rev()
+                    .fold(0u64, |height, b| (height << 8) | *b as u64),
+            )
+        }
+        _ => None,
+    }
+}
+
+/// Writes the coinbase scriptSig of each block with its miner tag and BIP34 height to a csv file
+pub struct Coinbase {
+    output_dir: PathBuf,
+    writer: Option<BufWriter<File>>,
+
+    blocks_per_pool: HashMap<&'static str, u64>,
+    start_height: u64,
+    block_count: u64,
+    mismatch_count: u64,
+}
+
+impl Callback for Coinbase {
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b>
+    where
+        Self: Sized,
+    {
+        SubCommand::with_name("coinbase")
+            .about(
+                "Writes the coinbase message, miner tag and BIP34 height of each block to CSV file",
+            )
+            .version("0.1")
+            .arg(
+                Arg::with_name("output-dir")
+                    .long("output-dir")
+                    .value_name("PATH")
+                    .help("Folder to store coinbase.csv, created if necessary")
+                    .takes_value(true)
+                    .required(true),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let cb = Coinbase {
+            output_dir: PathBuf::from(matches.value_of("output-dir").unwrap()),
+            writer: None,
+            blocks_per_pool: HashMap::new(),
+            start_height: 0,
+            block_count: 0,
+            mismatch_count: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, _: &CoinType, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        fs::create_dir_all(&self.output_dir)?;
+        let mut writer = BufWriter::with_capacity(
+            4000000,
+            File::create(self.output_dir.join("coinbase.csv.tmp"))?,
+        );
+        writer.write_all(
+            b"height;hash;timestamp;script_sig;tag;pool;bip34_height;bip34_match;value\n",
+        )?;
+        self.writer = Some(writer);
+        info!(target: "callback", "Using `coinbase` with output dir: {} ...", &self.output_dir.display());
+        Ok(())
+    }
+
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<()> {
+        let tx = match block.txs.first() {
+            Some(tx) if tx.value.is_coinbase() => tx,
+            _ => {
+                warn!(target: "callback", "Block {} has no coinbase transaction.", block_height);
+                return Ok(());
+            }
+        };
+        let script_sig = &tx.value.inputs[0].script_sig;
+        let pool = pool_name(script_sig);
+        // Heights don't match before the activation of BIP34 and in a few blocks after it
+        let (bip34_height, bip34_match) = match bip34_height(script_sig) {
+            Some(height) => {
+                if height != block_height {
+                    self.mismatch_count += 1;
+                }
+                (height.to_string(), (height == block_height).to_string())
+            }
+            None => (String::new(), String::new()),
+        };
+        let value: u64 = tx.value.outputs.iter().map(|o| o.out.value).sum();
+        self.writer.as_mut().unwrap().write_all(
+            format!(
+                "{};{};{};{};{};{};{};{};{}\n",
+                block_height,
+                utils::arr_to_hex_swapped(&block.header.hash),
+                block.header.value.timestamp,
+                utils::arr_to_hex(script_sig),
+                common::escape_csv(&extract_tag(script_sig)),
+                pool,
+                bip34_height,
+                bip34_match,
+                value
+            )
+            .as_bytes(),
+        )?;
+        *self.blocks_per_pool.entry(pool).or_insert(0) += 1;
+        self.block_count += 1;
+        Ok(())
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        let mut writer = self.writer.take().unwrap();
+        writer.flush()?;
+        drop(writer);
+        let path = self.output_dir.join("coinbase.csv");
+        fs::rename(path.with_extension("csv.tmp"), &path)?;
+
+        let mut pools: Vec<(&&str, &u64)> = self.blocks_per_pool.iter().collect();
+        pools.sort_unstable_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+        let mut buffer = Vec::with_capacity(4096);
+        writeln!(buffer, "Blocks per pool:")?;
+        for (pool, count) in pools {
+            writeln!(buffer, "\t-> {:20} {:9}", pool, count)?;
+        }
+        info!(target: "callback", "Done.\nDumped {} coinbases of blocks {} to {} into {}, {} with a different BIP34 height.\n{}",
+              self.block_count, self.start_height, block_height, path.display(), self.mismatch_count,
+              String::from_utf8_lossy(&buffer));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+    use crate::blockchain::parser::types::Bitcoin;
+    use crate::callbacks::fixtures::{new_chain, new_tx};
+
+    #[test]
+    fn test_pool_name() {
+        let cases = [
+            (
+                "03a0bb0d2f5669614254432f4d696e6564206279206f6c6c69652f",
+                "ViaBTC",
+            ),
+            ("03b84e0d2f4632506f6f6c2f", "F2Pool"),
+            (
+                "0340bb0d04e7a6ab650a4d696e656420627920416e74506f6f6c",
+                "AntPool",
+            ),
+            ("03a0bb0d466f756e6472792055534120506f6f6c", "Foundry USA"),
+            ("03a0bb0d2f736c7573682f", "Braiins Pool"),
+            ("04ffff001d0104", UNKNOWN_POOL),
+            ("", UNKNOWN_POOL),
+        ];
+        for (script_sig, pool) in cases {
+            assert_eq!(
+                pool,
+                pool_name(&utils::hex_to_vec(script_sig)),
+                "{}",
+                script_sig
+            );
+        }
+        assert_eq!("F2Pool", pool_name("七彩神仙鱼".as_bytes()));
+    }
+
+    #[test]
+    fn test_extract_tag() {
+        assert_eq!(
+            "/ViaBTC/Mined by ollie/",
+            extract_tag(&utils::hex_to_vec(
+                "03a0bb0d2f5669614254432f4d696e6564206279206f6c6c69652f"
+            ))
+        );
+        assert_eq!("", extract_tag(&utils::hex_to_vec("03a0bb0d41424300ff")));
+        assert_eq!("", extract_tag(&[]));
+    }
+
+    #[test]
+    fn test_bip34_height() {
+        let cases = [
+            ("03a0bb0d2f5669614254432f", Some(900000)),
+            ("035b6503", Some(222555)),
+            ("01010203", Some(1)),
+            ("0200010100", Some(256)),
+            ("00", Some(0)),
+            ("51", Some(1)),
+            ("60", Some(16)),
+            // Negative script number
+            ("0181", None),
+            // Truncated push
+            ("03a0bb", None),
+            ("04ffff001d0104", Some(0x1d00ffff)),
+            ("4c", None),
+            ("", None),
+        ];
+        for (script_sig, height) in cases {
+            assert_eq!(
+                height,
+                bip34_height(&utils::hex_to_vec(script_sig)),
+                "{}",
+                script_sig
+            );
+        }
+    }
+
+    #[test]
+    fn test_coinbase() {
+        let dir = env::temp_dir().join(format!("blockparser-coinbase-{}", process::id()));
+        let matches = Coinbase::build_subcommand().get_matches_from(vec![
+            "coinbase",
+            "--output-dir",
+            dir.to_str().unwrap(),
+        ]);
+        let mut coinbase_0 = new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[5000000000]);
+        coinbase_0.value.inputs[0].script_sig = utils::hex_to_vec("04ffff001d0104");
+        let mut coinbase_1 = new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[2500000000, 10000]);
+        // Height 1, "/F2Pool;/"
+        coinbase_1.value.inputs[0].script_sig = utils::hex_to_vec("01012f4632506f6f6c3b2f");
+        let chain = new_chain(vec![vec![coinbase_0], vec![coinbase_1]]);
+
+        let mut coinbase = Coinbase::new(&matches).unwrap();
+        coinbase.on_start(&CoinType::from(Bitcoin), 0).unwrap();
+        for (height, block) in chain.iter().enumerate() {
+            coinbase.on_block(block, height as u64).unwrap();
+        }
+        coinbase.on_complete(1).unwrap();
+        assert_eq!(1, coinbase.mismatch_count);
+        assert_eq!(Some(&1), coinbase.blocks_per_pool.get("F2Pool"));
+
+        let csv = fs::read_to_string(dir.join("coinbase.csv")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(
+            vec![
+                String::from(
+                    "height;hash;timestamp;script_sig;tag;pool;bip34_height;bip34_match;value"
+                ),
+                format!(
+                    "0;{};1231006505;04ffff001d0104;;unknown;486604799;false;5000000000",
+                    utils::arr_to_hex_swapped(&chain[0].header.hash)
+                ),
+                format!(
+                    "1;{};1231006505;01012f4632506f6f6c3b2f;\"/F2Pool;/\";F2Pool;1;true;2500010000",
+                    utils::arr_to_hex_swapped(&chain[1].header.hash)
+                ),
+            ],
+            lines
+        );
+    }
+}
@@ -0,0 +1,345 @@
+use std::fmt::Write as FmtWrite;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use crate::blockchain::parser::types::CoinType;
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::tx::TxOutpoint;
+use crate::callbacks::{common, Callback};
+use crate::common::outputstore::{outpoint_key, read_varint, write_varint, OutputStore};
+use crate::common::utils;
+use crate::errors::OpResult;
+
+const DAY: u64 = 86400;
+/// Upper bounds of the age buckets in seconds, a month has 30 and a year 365 days
+const AGE_LIMITS: [u64; 5] = [DAY, 7 * DAY, 30 * DAY, 365 * DAY, 5 * 365 * DAY];
+const AGE_LABELS: [&str; AGE_LIMITS.len() + 1] =
+    ["lt_1d", "1d_1w", "1w_1m", "1m_1y", "1y_5y", "gt_5y"];
+// Outputs starting with OP_RETURN can never be spent
+const OP_RETURN: u8 = 0x6a;
+
+/// Index of the age bucket of an age in seconds
+#[inline]
+fn age_bucket(age: u64) -> usize {
+    AGE_LIMITS
+        .iter()
+        .position(|limit| age < *limit)
+        .unwrap_or(AGE_LIMITS.len())
+}
+
+/// Writes the coin days destroyed and the value spent per age of the spent outputs for each
+/// block, and the unspent value per age at the end.
+///
+/// The age of an output is the time between the timestamps of the block creating it and the
+/// block spending it, zero if the spending block has the earlier timestamp.
+pub struct CoinDays {
+    output_dir: PathBuf,
+    // Value and creation timestamp of the outputs which are not spent yet
+    unspent: Option<OutputStore>,
+    writer: Option<BufWriter<File>>,
+    // Reused for all rows
+    row: String,
+    // Timestamp of the last block
+    timestamp: u32,
+
+    start_height: u64,
+    n_inputs: u64,
+    n_unresolved: u64,
+    coin_days: f64,
+}
+
+impl CoinDays {
+    /// Writes the outputs and value which are unspent at the timestamp of the last block per age
+    fn write_dormant(&self, path: &Path) -> OpResult<()> {
+        let mut outputs = [0u64; AGE_LABELS.len()];
+        let mut values = [0u64; AGE_LABELS.len()];
+        self.unspent.as_ref().unwrap().for_each(|_, stored| {
+            let mut stored = stored;
+            let value = read_varint(&mut stored)?;
+            let created = read_varint(&mut stored)?;
+            let bucket = age_bucket((self.timestamp as u64).saturating_sub(created));
+            outputs[bucket] += 1;
+            values[bucket] += value;
+            Ok(())
+        })?;
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(b"age;outputs;value\n")?;
+        for (i, label) in AGE_LABELS.iter().enumerate() {
+            writer.write_all(format!("{};{};{}\n", label, outputs[i], values[i]).as_bytes())?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+impl Callback for CoinDays {
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b>
+    where
+        Self: Sized,
+    {
+        SubCommand::with_name("coindays")
+            .about("Writes the coin days destroyed and the age of the spent coins per block to CSV files")
+            .version("0.1")
+            .arg(
+                Arg::with_name("output-dir")
+                    .long("output-dir")
+                    .value_name("PATH")
+                    .help("Folder to store coindays.csv and dormant.csv, created if necessary")
+                    .takes_value(true)
+                    .required(true),
+            )
+            .args(&OutputStore::args())
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let unspent = OutputStore::from_matches(matches)?;
+        let cb = CoinDays {
+            output_dir: PathBuf::from(matches.value_of("output-dir").unwrap()),
+            unspent: Some(unspent),
+            writer: None,
+            row: String::new(),
+            timestamp: 0,
+            start_height: 0,
+            n_inputs: 0,
+            n_unresolved: 0,
+            coin_days: 0.0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, _: &CoinType, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        if block_height != 0 {
+            warn!(target: "callback", "Starting at height {}, inputs spending earlier outputs are not counted.", block_height);
+        }
+        fs::create_dir_all(&self.output_dir)?;
+        let mut writer = BufWriter::with_capacity(
+            4000000,
+            File::create(self.output_dir.join("coindays.csv.tmp"))?,
+        );
+        let mut header = String::from("height;timestamp;inputs;value_spent;coin_days_destroyed");
+        for label in AGE_LABELS {
+            header.push_str(";spent_");
+            header.push_str(label);
+        }
+        header.push('\n');
+        writer.write_all(header.as_bytes())?;
+        self.writer = Some(writer);
+        info!(target: "callback", "Using `coindays` with output dir: {} ...", &self.output_dir.display());
+        Ok(())
+    }
+
+    fn wants_witnesses(&self) -> bool {
+        false
+    }
+
+    fn wants_script_evaluation(&self) -> bool {
+        false
+    }
+
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<()> {
+        let timestamp = block.header.value.timestamp;
+        let unspent = self.unspent.as_mut().unwrap();
+        let (mut n_inputs, mut value_spent, mut coin_days) = (0u64, 0u64, 0.0);
+        let mut spent = [0u64; AGE_LABELS.len()];
+        for tx in &block.txs {
+            // The coinbase input doesn't spend an output
+            for input in tx.value.inputs.iter().filter(|_| !tx.value.is_coinbase()) {
+                let stored = match unspent.take(&outpoint_key(&input.outpoint))? {
+                    Some(stored) => stored,
+                    None => {
+                        self.n_unresolved += 1;
+                        debug!(target: "callback", "Unable to resolve previous output {}:{}",
+                               utils::arr_to_hex_swapped(&input.outpoint.txid), input.outpoint.index);
+                        continue;
+                    }
+                };
+                let mut stored = stored.as_slice();
+                let value = read_varint(&mut stored)?;
+                let age = (timestamp as u64).saturating_sub(read_varint(&mut stored)?);
+                n_inputs += 1;
+                value_spent += value;
+                coin_days += common::coin_days(value, age);
+                spent[age_bucket(age)] += value;
+            }
+            // Outputs of the genesis block can't be spent and are not dormant
+            for (i, output) in tx.value.outputs.iter().enumerate() {
+                if block_height == 0 || output.out.script_pubkey.first() == Some(&OP_RETURN) {
+                    continue;
+                }
+                let mut stored = Vec::with_capacity(10);
+                write_varint(&mut stored, output.out.value);
+                write_varint(&mut stored, timestamp as u64);
+                unspent.insert(outpoint_key(&TxOutpoint::new(tx.hash, i as u32)), stored)?;
+            }
+        }
+        self.timestamp = timestamp;
+        self.n_inputs += n_inputs;
+        self.coin_days += coin_days;
+
+        self.row.clear();
+        let _ = write!(
+            self.row,
+            "{};{};{};{};{}",
+            block_height, timestamp, n_inputs, value_spent, coin_days
+        );
+        for value in spent {
+            let _ = write!(self.row, ";{}", value);
+        }
+        self.row.push('\n');
+        self.writer
+            .as_mut()
+            .unwrap()
+            .write_all(self.row.as_bytes())?;
+        Ok(())
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        let mut writer = self.writer.take().unwrap();
+        writer.flush()?;
+        drop(writer);
+        let path = self.output_dir.join("coindays.csv");
+        fs::rename(path.with_extension("csv.tmp"), &path)?;
+        let dormant_path = self.output_dir.join("dormant.csv");
+        self.write_dormant(&dormant_path.with_extension("csv.tmp"))?;
+        fs::rename(dormant_path.with_extension("csv.tmp"), &dormant_path)?;
+        self.unspent.take().unwrap().destroy()?;
+
+        info!(target: "callback", "Done.\nDumped blocks {} to {} into {}:\n\
+                                   \t-> spent outputs:       {:9}\n\
+                                   \t-> unresolved inputs:   {:9}\n\
+                                   \t-> coin days destroyed: {:9.0}",
+              self.start_height, block_height, path.display(), self.n_inputs,
+              self.n_unresolved, self.coin_days);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+    use crate::blockchain::parser::types::Bitcoin;
+    use crate::callbacks::fixtures::{new_chain, new_tx, new_tx_to};
+
+    const COIN: u64 = 100000000;
+
+    #[test]
+    fn test_age_bucket() {
+        assert_eq!(0, age_bucket(0));
+        assert_eq!(0, age_bucket(DAY - 1));
+        assert_eq!(1, age_bucket(DAY));
+        assert_eq!(2, age_bucket(7 * DAY));
+        assert_eq!(3, age_bucket(364 * DAY));
+        assert_eq!(4, age_bucket(365 * DAY));
+        assert_eq!(5, age_bucket(5 * 365 * DAY));
+        assert_eq!(5, age_bucket(u64::MAX));
+    }
+
+    fn run(args: &[&str], name: &str) -> (CoinDays, Vec<String>, Vec<String>) {
+        let dir = env::temp_dir().join(format!("blockparser-coindays-{}-{}", name, process::id()));
+        let mut all_args = vec!["coindays", "--output-dir", dir.to_str().unwrap()];
+        all_args.extend_from_slice(args);
+        let matches = CoinDays::build_subcommand().get_matches_from(all_args);
+
+        // Two coinbases of 50 coins at day 0, one of 10 coins at day 10
+        let coinbase_a = new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[50 * COIN]);
+        let coinbase_b = new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[50 * COIN, 0]);
+        let coinbase_c = new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[10 * COIN]);
+        // Day 10: spends 50 coins of age 10 days and the zero output, creates 20 + 30 coins
+        let spend_a = new_tx(
+            &[(coinbase_a.hash, 0), (coinbase_b.hash, 1)],
+            &[20 * COIN, 30 * COIN],
+        );
+        // Spent in the same block, age 0
+        let spend_b = new_tx(&[(spend_a.hash, 0)], &[20 * COIN]);
+        // Day 400: spends 30 coins of age 390 days, 50 coins of age 400 days and an unknown
+        // output. The OP_RETURN output isn't unspent.
+        let spend_c = new_tx_to(
+            &[(spend_a.hash, 1), (coinbase_b.hash, 0), ([1u8; 32], 0)],
+            &[
+                (
+                    80 * COIN,
+                    "76a914000000000000000000000000000000000000000088ac",
+                ),
+                (0, "6a00"),
+            ],
+        );
+        let genesis = new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[COIN]);
+        let mut chain = new_chain(vec![
+            vec![genesis],
+            vec![coinbase_a, coinbase_b],
+            vec![coinbase_c, spend_a, spend_b],
+            vec![spend_c],
+        ]);
+        let start = chain[0].header.value.timestamp;
+        chain[2].header.value.timestamp = start + 10 * DAY as u32;
+        chain[3].header.value.timestamp = start + 400 * DAY as u32;
+
+        let mut coindays = CoinDays::new(&matches).unwrap();
+        coindays.on_start(&CoinType::from(Bitcoin), 0).unwrap();
+        for (height, block) in chain.iter().enumerate() {
+            coindays.on_block(block, height as u64).unwrap();
+        }
+        coindays.on_complete(3).unwrap();
+        let read = |name: &str| -> Vec<String> {
+            let content = fs::read_to_string(dir.join(name)).unwrap();
+            content.lines().map(String::from).collect()
+        };
+        let (rows, dormant) = (read("coindays.csv"), read("dormant.csv"));
+        fs::remove_dir_all(&dir).unwrap();
+        (coindays, rows, dormant)
+    }
+
+    #[test]
+    fn test_coin_days() {
+        for (name, args) in [("memory", vec![]), ("disk", vec!["--buffer-size", "1"])] {
+            let db_path = env::temp_dir().join(format!(
+                "blockparser-coindays-db-{}-{}",
+                name,
+                process::id()
+            ));
+            let mut args = args.clone();
+            if name == "disk" {
+                args.extend(["--db-path", db_path.to_str().unwrap()]);
+            }
+            let (coindays, rows, dormant) = run(&args, name);
+            assert!(!db_path.exists());
+            assert_eq!(
+                "height;timestamp;inputs;value_spent;coin_days_destroyed;spent_lt_1d;\
+                 spent_1d_1w;spent_1w_1m;spent_1m_1y;spent_1y_5y;spent_gt_5y",
+                rows[0]
+            );
+            assert_eq!("0;1231006505;0;0;0;0;0;0;0;0;0", rows[1]);
+            assert_eq!("1;1231006505;0;0;0;0;0;0;0;0;0", rows[2]);
+            // 50 coins * 10 days, the 20 coins spent in the same block destroy nothing
+            let row = format!(
+                "2;1231870505;3;{};500;{};0;{};0;0;0",
+                70 * COIN,
+                20 * COIN,
+                50 * COIN
+            );
+            assert_eq!(row, rows[3]);
+            // 30 coins * 390 days + 50 coins * 400 days
+            let row = format!("3;1265566505;2;{};31700;0;0;0;0;{};0", 80 * COIN, 80 * COIN);
+            assert_eq!(row, rows[4]);
+            assert_eq!(1, coindays.n_unresolved);
+            assert_eq!(5, coindays.n_inputs);
+
+            // The output of the last block, the coinbase of day 10 and the output of spend_b
+            assert_eq!("age;outputs;value", dormant[0]);
+            assert_eq!(format!("lt_1d;1;{}", 80 * COIN), dormant[1]);
+            assert_eq!("1m_1y;0;0", dormant[4]);
+            assert_eq!(format!("1y_5y;2;{}", 30 * COIN), dormant[5]);
+            assert_eq!(7, dormant.len());
+        }
+    }
+}
@@ -1,10 +1,19 @@
 use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
 
-use crate::blockchain::proto::tx::EvaluatedTx;
-use crate::blockchain::proto::tx::TxOutpoint;
+use crate::blockchain::proto::script::ScriptPattern;
+use crate::blockchain::proto::tx::{EvaluatedTx, EvaluatedTxOut, TxOutpoint};
 use crate::blockchain::proto::Hashed;
 use crate::blockchain::proto::ToRaw;
+#[cfg(feature = "gzip")]
+use crate::common::gzip::GzWriter;
+use crate::common::outputstore::{read_varint, write_varint};
 use crate::common::utils;
+#[cfg(feature = "zstd")]
+use crate::common::zstd::ZstdWriter;
+use crate::errors::{OpError, OpResult};
 
 pub struct UnspentValue {
     pub block_height: u64,
@@ -12,6 +21,162 @@ pub struct UnspentValue {
     pub address: String,
 }
 
+/// Block height and value as varints, followed by the address
+pub fn encode_unspent(block_height: u64, value: u64, address: &str) -> Vec<u8> {
+    let mut data = Vec::with_capacity(address.len() + 12);
+    write_varint(&mut data, block_height);
+    write_varint(&mut data, value);
+    data.extend_from_slice(address.as_bytes());
+    data
+}
+
+pub fn decode_unspent(mut data: &[u8]) -> OpResult<(u64, u64, &str)> {
+    let block_height = read_varint(&mut data)?;
+    let value = read_varint(&mut data)?;
+    let address = std::str::from_utf8(data)
+        .map_err(|_| OpError::from(String::from("Invalid address in unspent output.")))?;
+    Ok((block_height, value, address))
+}
+
+/// Script type of an output, witness programs included
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputType {
+    Pay2PublicKey,
+    Pay2PublicKeyHash,
+    Pay2ScriptHash,
+    Pay2WitnessPublicKeyHash,
+    Pay2WitnessScriptHash,
+    Pay2Taproot,
+    MultiSig,
+    NullData,
+    NonStandard,
+}
+
+pub const OUTPUT_TYPES: [OutputType; 9] = [
+    OutputType::Pay2PublicKey,
+    OutputType::Pay2PublicKeyHash,
+    OutputType::Pay2ScriptHash,
+    OutputType::Pay2WitnessPublicKeyHash,
+    OutputType::Pay2WitnessScriptHash,
+    OutputType::Pay2Taproot,
+    OutputType::MultiSig,
+    OutputType::NullData,
+    OutputType::NonStandard,
+];
+
+impl OutputType {
+    pub fn from_output(output: &EvaluatedTxOut) -> Self {
+        // OP_RETURN scripts are null data even if their pushes can't be evaluated
+        if output.out.script_pubkey.first() == Some(&0x6a) {
+            return OutputType::NullData;
+        }
+        match output.script.pattern {
+            ScriptPattern::Pay2PublicKey | ScriptPattern::Pay2InvalidPublicKey => {
+                OutputType::Pay2PublicKey
+            }
+            ScriptPattern::Pay2PublicKeyHash => OutputType::Pay2PublicKeyHash,
+            ScriptPattern::Pay2ScriptHash => OutputType::Pay2ScriptHash,
+            ScriptPattern::WitnessV0Keyhash => OutputType::Pay2WitnessPublicKeyHash,
+            ScriptPattern::WitnessV0Scripthash => OutputType::Pay2WitnessScriptHash,
+            ScriptPattern::WitnessV1Taproot => OutputType::Pay2Taproot,
+            ScriptPattern::Pay2MultiSig => OutputType::MultiSig,
+            ScriptPattern::DataOutput(_) => OutputType::NullData,
+            ScriptPattern::WitnessUnknown(_)
+            | ScriptPattern::NotRecognised
+            | ScriptPattern::Error(_)
+            | ScriptPattern::NotEvaluated => OutputType::NonStandard,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        OUTPUT_TYPES.iter().find(|t| t.name() == name).copied()
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            OutputType::Pay2PublicKey => "p2pk",
+            OutputType::Pay2PublicKeyHash => "p2pkh",
+            OutputType::Pay2ScriptHash => "p2sh",
+            OutputType::Pay2WitnessPublicKeyHash => "p2wpkh",
+            OutputType::Pay2WitnessScriptHash => "p2wsh",
+            OutputType::Pay2Taproot => "p2tr",
+            OutputType::MultiSig => "multisig",
+            OutputType::NullData => "nulldata",
+            OutputType::NonStandard => "nonstandard",
+        }
+    }
+
+    pub fn is_witness_program(&self) -> bool {
+        matches!(
+            *self,
+            OutputType::Pay2WitnessPublicKeyHash
+                | OutputType::Pay2WitnessScriptHash
+                | OutputType::Pay2Taproot
+        )
+    }
+
+    pub fn index(&self) -> usize {
+        OUTPUT_TYPES.iter().position(|t| t == self).unwrap()
+    }
+}
+
+/// Converts a value into a signed 64-bit integer of base units (satoshis).
+/// Values above i64::MAX can't be represented and are capped.
+#[inline]
+pub fn base_units(value: u64) -> i64 {
+    i64::try_from(value).unwrap_or_else(|_| {
+        warn!(target: "callback", "Value {} exceeds int64, capping it to {}.", value, i64::MAX);
+        i64::MAX
+    })
+}
+
+/// Coin days destroyed, the value in coins times the days since the output was created.
+/// `lifespan` is in seconds.
+#[inline]
+pub fn coin_days(value: u64, lifespan: u64) -> f64 {
+    value as f64 / 100000000.0 * lifespan as f64 / 86400.0
+}
+
+/// Returns the wtxid, which is all-zero for coinbase transactions (BIP141)
+/// and equals the txid for transactions without witness data.
+#[inline]
+pub fn wtx_hash(tx: &Hashed<EvaluatedTx>) -> String {
+    if tx.value.is_coinbase() {
+        return utils::arr_to_hex(&[0u8; 32]);
+    }
+    match tx.value.witness_hash() {
+        Some(hash) => utils::arr_to_hex_swapped(&hash),
+        None => utils::arr_to_hex_swapped(&tx.hash),
+    }
+}
+
+/// Quotes a csv field if it contains the separator `;`, quotes or line breaks
+pub fn escape_csv(text: &str) -> String {
+    escape_csv_field(text, ';')
+}
+
+/// Quotes a csv field as in RFC 4180 if it contains the delimiter, quotes or line breaks
+pub fn escape_csv_field(text: &str, delimiter: char) -> String {
+    if text.contains([delimiter, '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        String::from(text)
+    }
+}
+
+/// Joins the escaped fields into a line terminated by `\n`
+pub fn csv_row<S: AsRef<str>>(fields: &[S], delimiter: char) -> String {
+    let mut row = String::with_capacity(fields.iter().map(|f| f.as_ref().len() + 1).sum());
+    for (i, field) in fields.iter().enumerate() {
+        if i > 0 {
+            row.push(delimiter);
+        }
+        row.push_str(&escape_csv_field(field.as_ref(), delimiter));
+    }
+    row.push('\n');
+    row
+}
+
 /// Iterates over transaction inputs and removes spent outputs from HashMap.
 /// Returns the total number of processed inputs.
 pub fn remove_unspents(
@@ -27,7 +192,19 @@ pub fn remove_unspents(
     tx.value.in_count.value
 }
 
-/// Iterates over transaction outputs and adds valid unspents to HashMap.
+/// Logs a transaction which repeats the txid of one with unspent outputs. Before BIP30 the
+/// coinbases of blocks 91842 and 91880 repeated those of 91812 and 91722. Like in Bitcoin Core
+/// the later outputs replace the earlier ones, which can't be spent anymore.
+pub fn warn_duplicate_txid(tx: &Hashed<EvaluatedTx>, block_height: u64) {
+    warn!(
+        target: "callback", "Duplicate txid {} at height {}, its outputs replace the unspent outputs of the earlier transaction.",
+        utils::arr_to_hex_swapped(&tx.hash),
+        block_height
+    );
+}
+
+/// Iterates over transaction outputs and adds valid unspents to HashMap, see
+/// `warn_duplicate_txid()` for outputs which are already there.
 /// Returns the total number of valid outputs.
 pub fn insert_unspents(
     tx: &Hashed<EvaluatedTx>,
@@ -35,6 +212,7 @@ pub fn insert_unspents(
     unspents: &mut HashMap<Vec<u8>, UnspentValue>,
 ) -> u64 {
     let mut count = 0;
+    let mut duplicate = false;
     for (i, output) in tx.value.outputs.iter().enumerate() {
         match &output.script.address {
             Some(address) => {
@@ -45,7 +223,7 @@ pub fn insert_unspents(
                 };
 
                 let key = TxOutpoint::new(tx.hash, i as u32).to_bytes();
-                unspents.insert(key, unspent);
+                duplicate |= unspents.insert(key, unspent).is_some();
                 count += 1;
             }
             None => {
@@ -57,18 +235,224 @@ pub fn insert_unspents(
             }
         }
     }
+    if duplicate {
+        warn_duplicate_txid(tx, block_height);
+    }
     count
 }
 
+/// Compression of dumped files
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Parses `none`, `gzip` or `zstd`, fails if the codec wasn't compiled in
+    pub fn from_name(name: &str) -> OpResult<Self> {
+        let (compression, enabled) = match name {
+            "none" => (Compression::None, true),
+            "gzip" => (Compression::Gzip, cfg!(feature = "gzip")),
+            "zstd" => (Compression::Zstd, cfg!(feature = "zstd")),
+            _ => {
+                return Err(OpError::from(format!(
+                    "Unknown compression `{}`, expected gzip, zstd or none.",
+                    name
+                )))
+            }
+        };
+        if !enabled {
+            return Err(OpError::from(format!(
+                "{} is not available, the parser was built without the `{}` feature.",
+                name, name
+            )));
+        }
+        Ok(compression)
+    }
+
+    /// Suffix appended to the file name, e.g. `.gz`
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Compression::None => "",
+            Compression::Gzip => ".gz",
+            Compression::Zstd => ".zst",
+        }
+    }
+
+    /// Levels accepted by the encoder and the one used by default
+    pub fn levels(&self) -> (u32, u32, u32) {
+        match self {
+            Compression::None => (0, 0, 0),
+            Compression::Gzip => (1, 9, 6),
+            Compression::Zstd => (1, 22, 3),
+        }
+    }
+}
+
+/// Buffered output file, optionally compressed
+pub trait OutputFile: Write {
+    /// Writes all buffered data (and the trailer of the compressed stream), returns the underlying file
+    fn into_file(self: Box<Self>) -> io::Result<File>;
+}
+
+impl OutputFile for BufWriter<File> {
+    fn into_file(self: Box<Self>) -> io::Result<File> {
+        self.into_inner().map_err(|e| e.into_error())
+    }
+}
+
+#[cfg(feature = "gzip")]
+impl OutputFile for BufWriter<GzWriter<File>> {
+    fn into_file(self: Box<Self>) -> io::Result<File> {
+        self.into_inner().map_err(|e| e.into_error())?.finish()
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl OutputFile for BufWriter<ZstdWriter<File>> {
+    fn into_file(self: Box<Self>) -> io::Result<File> {
+        self.into_inner().map_err(|e| e.into_error())?.finish()
+    }
+}
+
+/// Creates the file, everything written to it gets compressed with the given level
+pub fn create_file(
+    path: &Path,
+    compression: Compression,
+    level: u32,
+    capacity: usize,
+) -> OpResult<Box<dyn OutputFile>> {
+    let file = File::create(path)?;
+    match compression {
+        #[cfg(feature = "gzip")]
+        Compression::Gzip => {
+            let encoder = GzWriter::new(file, level)?;
+            Ok(Box::new(BufWriter::with_capacity(capacity, encoder)))
+        }
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => {
+            let encoder = ZstdWriter::new(file, level)?;
+            Ok(Box::new(BufWriter::with_capacity(capacity, encoder)))
+        }
+        _ => {
+            debug_assert_eq!(Compression::None, compression, "feature is disabled");
+            let _ = level;
+            Ok(Box::new(BufWriter::with_capacity(capacity, file)))
+        }
+    }
+}
+
+/// Completes the file and syncs it to disk, it is truncated (and invalid if compressed) otherwise
+pub fn finish_file(writer: Box<dyn OutputFile>) -> OpResult<()> {
+    writer.into_file()?.sync_all()?;
+    Ok(())
+}
+
+/// Reads a dumped file, decompressing it according to its extension
+#[cfg(test)]
+pub fn read_file(path: &Path) -> Vec<u8> {
+    let data = std::fs::read(path).unwrap();
+    match path.extension() {
+        #[cfg(feature = "gzip")]
+        Some(ext) if ext == "gz" => crate::common::gzip::gunzip(&data),
+        #[cfg(feature = "zstd")]
+        Some(ext) if ext == "zst" => crate::common::zstd::unzstd(&data),
+        _ => data,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::blockchain::parser::reader::BlockchainRead;
     use crate::blockchain::proto::block::Block;
     use crate::blockchain::proto::header::BlockHeader;
+    use crate::blockchain::proto::script::AddressFormat;
     use crate::blockchain::proto::varuint::VarUint;
+    use crate::callbacks::fixtures::{new_tx_to, SCRIPT_A};
     use std::io::{BufReader, Cursor};
 
+    #[test]
+    fn test_encode_unspent() {
+        let data = encode_unspent(700000, 5000000000, "12higDjoCCNXSA95xZMWUdPvXNmkAduhWv");
+        assert_eq!(8 + 34, data.len());
+        assert_eq!(
+            (700000, 5000000000, "12higDjoCCNXSA95xZMWUdPvXNmkAduhWv"),
+            decode_unspent(&data).unwrap()
+        );
+        assert_eq!(
+            (0, 0, ""),
+            decode_unspent(&encode_unspent(0, 0, "")).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_base_units() {
+        assert_eq!(0, base_units(0));
+        assert_eq!(2100000000000000, base_units(2_100_000_000_000_000));
+        assert_eq!(i64::MAX, base_units(i64::MAX as u64));
+        assert_eq!(i64::MAX, base_units(u64::MAX));
+    }
+
+    #[test]
+    fn test_escape_csv() {
+        assert_eq!("hello world", escape_csv("hello world"));
+        assert_eq!("\"a;b\"", escape_csv("a;b"));
+        assert_eq!("\"say \"\"hi\"\"\"", escape_csv("say \"hi\""));
+        assert_eq!("\"a\nb\"", escape_csv("a\nb"));
+        assert_eq!("a;b", escape_csv_field("a;b", ','));
+        assert_eq!("\"a,b\"", escape_csv_field("a,b", ','));
+    }
+
+    #[test]
+    fn test_csv_row() {
+        assert_eq!("a;1;;c\n", csv_row(&["a", "1", "", "c"], ';'));
+        assert_eq!(
+            "\"x,y\",\"say \"\"hi\"\"\",z;\n",
+            csv_row(&["x,y", "say \"hi\"", "z;"], ',')
+        );
+        assert_eq!("\n", csv_row::<&str>(&[], ';'));
+    }
+
+    #[test]
+    fn test_output_type() {
+        let cases = [
+            (SCRIPT_A, OutputType::Pay2PublicKeyHash),
+            (
+                "a914748284390f9e263a4b766a75d0633c50426eb87587",
+                OutputType::Pay2ScriptHash,
+            ),
+            (
+                "0014751e76e8199196d454941c45d1b3a323f1433bd6",
+                OutputType::Pay2WitnessPublicKeyHash,
+            ),
+            (
+                "0020701a8d401c84fb13e6baf169d59684e17abd9fa216c8cc5b9fc63d622ff8c58d",
+                OutputType::Pay2WitnessScriptHash,
+            ),
+            (
+                "5120a60869f0dbcf1dc659c9cecbaf8050135ea9e8cdc487053f1dc6880949dc684c",
+                OutputType::Pay2Taproot,
+            ),
+            ("6a0b68656c6c6f20776f726c64", OutputType::NullData),
+            ("6a0102010203", OutputType::NullData),
+            ("51", OutputType::NonStandard),
+        ];
+        for (script, expected) in cases {
+            let tx = new_tx_to(&[([0u8; 32], 0xFFFFFFFF)], &[(1, script)]);
+            assert_eq!(
+                expected,
+                OutputType::from_output(&tx.value.outputs[0]),
+                "{}",
+                script
+            );
+            assert_eq!(Some(expected), OutputType::from_name(expected.name()));
+        }
+        assert_eq!(None, OutputType::from_name("p2wpkh2"));
+    }
+
     #[test]
     fn test_callback() {
         let mut unspents: HashMap<Vec<u8>, UnspentValue> = HashMap::new();
@@ -79,6 +463,7 @@ mod tests {
             timestamp: 0,
             bits: 0,
             nonce: 0,
+            equihash: None,
         };
 
         // Create a mock of txid fff2525b8931402dd09222c50775608f75787bd2b87e56995a7bdd30f79702c4,
@@ -105,12 +490,12 @@ mod tests {
             0x7c, 0x88, 0xac, 0x00, 0x00, 0x00, 0x00,
         ];
         let mut reader = BufReader::new(Cursor::new(raw_data));
-        let txs = reader.read_txs(1, 0x00).unwrap();
+        let txs = reader.read_txs(1, AddressFormat::default()).unwrap();
         let block1 = Block::new(0, header.clone(), VarUint::from(1u8), txs);
 
         for tx in &block1.txs {
-            remove_unspents(&tx, &mut unspents);
-            insert_unspents(&tx, 100000, &mut unspents);
+            remove_unspents(tx, &mut unspents);
+            insert_unspents(tx, 100000, &mut unspents);
         }
         let value = unspents
             .get(&TxOutpoint::new(block1.txs[0].hash, 0).to_bytes())
@@ -243,18 +628,16 @@ mod tests {
             0x72, 0xdc, 0x35, 0x92, 0x88, 0xac, 0x00, 0x00, 0x00, 0x00,
         ];
         let mut reader = BufReader::new(Cursor::new(raw_data));
-        let txs = reader.read_txs(1, 0x00).unwrap();
+        let txs = reader.read_txs(1, AddressFormat::default()).unwrap();
         let block2 = Block::new(0, header.clone(), VarUint::from(1u8), txs);
 
         for tx in &block2.txs {
-            remove_unspents(&tx, &mut unspents);
-            insert_unspents(&tx, 105001, &mut unspents);
+            remove_unspents(tx, &mut unspents);
+            insert_unspents(tx, 105001, &mut unspents);
         }
 
         // Original unspent should no longer exist in the hashmap
-        assert!(unspents
-            .get(&TxOutpoint::new(block1.txs[0].hash, 0).to_bytes())
-            .is_none());
+        assert!(!unspents.contains_key(&TxOutpoint::new(block1.txs[0].hash, 0).to_bytes()));
 
         let value = unspents
             .get(&TxOutpoint::new(block2.txs[0].hash, 0).to_bytes())
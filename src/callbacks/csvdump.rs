@@ -1,5 +1,5 @@
-use std::fs::{self, File};
-use std::io::{BufWriter, Write};
+use std::fs;
+use std::io::Write;
 use std::path::PathBuf;
 
 use clap::{App, Arg, ArgMatches, SubCommand};
@@ -8,18 +8,127 @@ use crate::blockchain::parser::types::CoinType;
 use crate::blockchain::proto::block::Block;
 use crate::blockchain::proto::tx::{EvaluatedTx, EvaluatedTxOut, TxInput};
 use crate::blockchain::proto::Hashed;
+use crate::callbacks::common::{create_file, csv_row, finish_file, Compression, OutputFile};
+use crate::callbacks::sqlschema::ColumnType::{Bool, Bytes, Hash, Text, U32, U64};
+use crate::callbacks::sqlschema::{self, Column, CsvFormat, Dialect, Index, Table};
 use crate::callbacks::Callback;
 use crate::common::utils;
-use crate::errors::OpResult;
+use crate::errors::{OpError, OpResult};
+
+const WRITER_CAPACITY: usize = 4000000;
+// Each structure gets stored in a separate csv file, in the order of `Chunk::writers`.
+// The column names are the ones of sql/schema.sql
+const TABLES: [(&str, &[Column], &[Index]); 4] = [
+    (
+        "blocks",
+        &[
+            Column::new("hash", Hash),
+            Column::new("height", U64),
+            Column::new("version", U32),
+            Column::new("blocksize", U32),
+            Column::new("hashPrev", Hash),
+            Column::new("hashMerkleRoot", Hash),
+            Column::new("nTime", U32),
+            Column::new("nBits", U32),
+            Column::new("nNonce", U32),
+        ],
+        &[Index::new(&["height"]), Index::unique(&["hash"])],
+    ),
+    (
+        "transactions",
+        &[
+            Column::new("txid", Hash),
+            Column::new("hashBlock", Hash),
+            Column::new("version", U32),
+            Column::new("lockTime", U32),
+        ],
+        // Not unique, see BIP30
+        &[Index::new(&["txid"]), Index::new(&["hashBlock"])],
+    ),
+    (
+        "tx_in",
+        &[
+            Column::new("txid", Hash),
+            Column::new("hashPrevOut", Hash),
+            Column::new("indexPrevOut", U32),
+            Column::new("scriptSig", Bytes),
+            Column::new("sequence", U32),
+        ],
+        &[
+            Index::new(&["hashPrevOut", "indexPrevOut"]),
+            Index::new(&["txid"]),
+        ],
+    ),
+    (
+        "tx_out",
+        &[
+            Column::new("txid", Hash),
+            Column::new("indexOut", U32),
+            Column::new("value", U64),
+            Column::new("scriptPubKey", Bytes),
+            Column::nullable("address", Text(90)),
+        ],
+        &[Index::new(&["txid", "indexOut"]), Index::new(&["address"])],
+    ),
+];
+/// Appended to the blocks table by `--extended`, 0 without MWEB extension block (Litecoin)
+const EXTENDED_BLOCK_COLUMNS: [Column; 1] = [Column::new("mwebSize", U32)];
+/// Appended to the transactions table by `--extended`
+const EXTENDED_TX_COLUMNS: [Column; 6] = [
+    Column::new("size", U32),
+    Column::new("strippedSize", U32),
+    Column::new("vsize", U32),
+    Column::new("weight", U32),
+    Column::new("lockTimeType", Text(8)),
+    Column::new("usesLockTime", Bool),
+];
+/// Appended to the tx_in table by `--extended`, the revealed script is empty without
+/// `--classify-inputs` and the relative lock without BIP68
+const EXTENDED_TXIN_COLUMNS: [Column; 4] = [
+    Column::nullable("revealedScriptType", Text(32)),
+    Column::nullable("revealedScriptHex", Bytes),
+    Column::nullable("relativeLockType", Text(8)),
+    Column::nullable("relativeLockValue", U32),
+];
+
+/// Files of the blocks from `start_height` on, renamed to `<table>-<start>-<end>.csv` when done
+struct Chunk {
+    start_height: u64,
+    block_writer: Box<dyn OutputFile>,
+    tx_writer: Box<dyn OutputFile>,
+    txin_writer: Box<dyn OutputFile>,
+    txout_writer: Box<dyn OutputFile>,
+}
+
+impl Chunk {
+    fn writers(self) -> [Box<dyn OutputFile>; 4] {
+        [
+            self.block_writer,
+            self.tx_writer,
+            self.txin_writer,
+            self.txout_writer,
+        ]
+    }
+}
 
 /// Dumps the whole blockchain into csv files
 pub struct CsvDump {
-    // Each structure gets stored in a separate csv file
     dump_folder: PathBuf,
-    block_writer: BufWriter<File>,
-    tx_writer: BufWriter<File>,
-    txin_writer: BufWriter<File>,
-    txout_writer: BufWriter<File>,
+    compression: Compression,
+    compression_level: u32,
+    delimiter: char,
+    // Writes the column names as first row of each file
+    header: bool,
+    // Adds the columns of `EXTENDED_BLOCK_COLUMNS`, `EXTENDED_TX_COLUMNS` and `EXTENDED_TXIN_COLUMNS`
+    extended: bool,
+    // Number of blocks per chunk, everything goes into a single chunk if None
+    split_every: Option<u64>,
+    chunk: Option<Chunk>,
+    n_chunks: u64,
+    // Writes schema.sql and import.sql for the database
+    emit_sql: Option<Dialect>,
+    // Table and name of each finished file
+    files: Vec<(&'static str, String)>,
 
     start_height: u64,
     end_height: u64,
@@ -29,8 +138,81 @@ pub struct CsvDump {
 }
 
 impl CsvDump {
-    fn create_writer(cap: usize, path: PathBuf) -> OpResult<BufWriter<File>> {
-        Ok(BufWriter::with_capacity(cap, File::create(&path)?))
+    /// Returns the path of e.g. `tx_out-1000-2000.csv.gz`, or of the temp file
+    /// `tx_out-1000.csv.gz.tmp` while the chunk is written
+    fn file_path(&self, table: &str, start_height: u64, end_height: Option<u64>) -> PathBuf {
+        let ext = self.compression.extension();
+        let file_name = match end_height {
+            Some(end) => format!("{}-{}-{}.csv{}", table, start_height, end, ext),
+            None => format!("{}-{}.csv{}.tmp", table, start_height, ext),
+        };
+        self.dump_folder.join(file_name)
+    }
+
+    /// Tables in the order of `Chunk::writers`, with the columns of `--extended`
+    fn tables(&self) -> Vec<Table> {
+        TABLES
+            .iter()
+            .map(|(name, columns, indexes)| {
+                let mut columns = columns.to_vec();
+                match *name {
+                    "blocks" if self.extended => columns.extend_from_slice(&EXTENDED_BLOCK_COLUMNS),
+                    "transactions" if self.extended => {
+                        columns.extend_from_slice(&EXTENDED_TX_COLUMNS)
+                    }
+                    "tx_in" if self.extended => columns.extend_from_slice(&EXTENDED_TXIN_COLUMNS),
+                    _ => (),
+                }
+                Table {
+                    name,
+                    columns,
+                    indexes,
+                }
+            })
+            .collect()
+    }
+
+    /// Creates the temp files of the chunk, starting with a header row if requested
+    fn open_chunk(&mut self, start_height: u64) -> OpResult<()> {
+        let create = |table: &Table| {
+            let mut writer = create_file(
+                &self.file_path(table.name, start_height, None),
+                self.compression,
+                self.compression_level,
+                WRITER_CAPACITY,
+            )?;
+            if self.header {
+                writer.write_all(csv_row(&table.column_names(), self.delimiter).as_bytes())?;
+            }
+            Ok::<_, OpError>(writer)
+        };
+        let tables = self.tables();
+        self.chunk = Some(Chunk {
+            start_height,
+            block_writer: create(&tables[0])?,
+            tx_writer: create(&tables[1])?,
+            txin_writer: create(&tables[2])?,
+            txout_writer: create(&tables[3])?,
+        });
+        Ok(())
+    }
+
+    /// Finishes the encoders and renames the temp files, `end_height` is exclusive
+    fn finish_chunk(&mut self, end_height: u64) -> OpResult<()> {
+        let chunk = match self.chunk.take() {
+            Some(chunk) => chunk,
+            None => return Ok(()),
+        };
+        let start_height = chunk.start_height;
+        for ((table, _, _), writer) in TABLES.iter().zip(chunk.writers()) {
+            finish_file(writer)?;
+            let path = self.file_path(table, start_height, Some(end_height));
+            fs::rename(self.file_path(table, start_height, None), &path)?;
+            let file_name = path.file_name().unwrap().to_string_lossy().into_owned();
+            self.files.push((table, file_name));
+        }
+        self.n_chunks += 1;
+        Ok(())
     }
 }
 
@@ -49,20 +231,124 @@ impl Callback for CsvDump {
                     .index(1)
                     .required(true),
             )
+            .arg(
+                Arg::with_name("delimiter")
+                    .long("delimiter")
+                    .value_name("CHAR")
+                    .help("Field delimiter, `tab` for tabs (default: ;)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("header")
+                    .long("header")
+                    .help("Writes the column names as first row of each file"),
+            )
+            .arg(
+                Arg::with_name("extended")
+                    .long("extended")
+                    .help("Adds the MWEB size of the blocks, sizes and locktime semantics of the transactions and the revealed scripts and relative locks of the inputs"),
+            )
+            .arg(
+                Arg::with_name("split-every")
+                    .long("split-every")
+                    .value_name("N_BLOCKS")
+                    .help(
+                        "Start new files at every multiple of N blocks, e.g. tx_out-1000-2000.csv",
+                    )
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("compress")
+                    .long("compress")
+                    .value_name("CODEC")
+                    .help("Compresses the files while they are written (default: none)")
+                    .possible_values(&["gzip", "zstd", "none"])
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("compress-level")
+                    .long("compress-level")
+                    .value_name("LEVEL")
+                    .help("Compression level, 1-9 for gzip and 1-22 for zstd (default: 6 and 3)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("emit-sql")
+                    .long("emit-sql")
+                    .value_name("DIALECT")
+                    .help("Writes the tables to schema.sql and the statements loading the files to import.sql")
+                    .possible_values(&["postgres", "mysql", "sqlite", "clickhouse"])
+                    .takes_value(true),
+            )
     }
 
     fn new(matches: &ArgMatches) -> OpResult<Self>
     where
         Self: Sized,
     {
-        let dump_folder = &PathBuf::from(matches.value_of("dump-folder").unwrap());
-        let cap = 4000000;
+        let compression = Compression::from_name(matches.value_of("compress").unwrap_or("none"))?;
+        let (min_level, max_level, default_level) = compression.levels();
+        let compression_level = match matches.value_of("compress-level") {
+            Some(_) if compression == Compression::None => {
+                return Err(OpError::from(String::from(
+                    "--compress-level requires --compress gzip or zstd.",
+                )))
+            }
+            Some(_) => match value_t!(matches, "compress-level", u32) {
+                Ok(level) if (min_level..=max_level).contains(&level) => level,
+                _ => {
+                    return Err(OpError::from(format!(
+                        "--compress-level must be between {} and {}.",
+                        min_level, max_level
+                    )))
+                }
+            },
+            None => default_level,
+        };
+        let delimiter = match matches.value_of("delimiter") {
+            Some("tab") | Some("\\t") => '\t',
+            Some(delimiter) => {
+                let mut chars = delimiter.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) if !['"', '\n', '\r'].contains(&c) => c,
+                    _ => return Err(OpError::from(String::from(
+                        "--delimiter must be a single character other than a quote or line break.",
+                    ))),
+                }
+            }
+            None => ';',
+        };
+        let emit_sql = match matches.value_of("emit-sql") {
+            Some(name) => {
+                let dialect = Dialect::from_name(name)?;
+                dialect.check_compression(compression)?;
+                Some(dialect)
+            }
+            None => None,
+        };
+        let split_every = match matches.value_of("split-every") {
+            Some(_) => match value_t!(matches, "split-every", u64) {
+                Ok(n) if n > 0 => Some(n),
+                _ => {
+                    return Err(OpError::from(String::from(
+                        "--split-every must be a positive number of blocks.",
+                    )))
+                }
+            },
+            None => None,
+        };
         let cb = CsvDump {
-            dump_folder: PathBuf::from(dump_folder),
-            block_writer: CsvDump::create_writer(cap, dump_folder.join("blocks.csv.tmp"))?,
-            tx_writer: CsvDump::create_writer(cap, dump_folder.join("transactions.csv.tmp"))?,
-            txin_writer: CsvDump::create_writer(cap, dump_folder.join("tx_in.csv.tmp"))?,
-            txout_writer: CsvDump::create_writer(cap, dump_folder.join("tx_out.csv.tmp"))?,
+            dump_folder: PathBuf::from(matches.value_of("dump-folder").unwrap()),
+            compression,
+            compression_level,
+            delimiter,
+            header: matches.is_present("header"),
+            extended: matches.is_present("extended"),
+            split_every,
+            chunk: None,
+            n_chunks: 0,
+            emit_sql,
+            files: Vec::new(),
             start_height: 0,
             end_height: 0,
             tx_count: 0,
@@ -74,33 +360,55 @@ impl Callback for CsvDump {
 
     fn on_start(&mut self, _: &CoinType, block_height: u64) -> OpResult<()> {
         self.start_height = block_height;
+        self.open_chunk(block_height)?;
         info!(target: "callback", "Using `csvdump` with dump folder: {} ...", &self.dump_folder.display());
         Ok(())
     }
 
     fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<()> {
+        if let (Some(n), Some(chunk)) = (self.split_every, self.chunk.as_ref()) {
+            if block_height / n != chunk.start_height / n {
+                self.finish_chunk(block_height)?;
+                self.open_chunk(block_height)?;
+            }
+        }
+        let (delimiter, extended) = (self.delimiter, self.extended);
+        let chunk = self.chunk.as_mut().unwrap();
+
         // serialize block
-        self.block_writer
-            .write_all(block.as_csv(block_height).as_bytes())?;
+        let row = match extended {
+            true => block.as_extended_csv(block_height, delimiter),
+            false => block.as_csv(block_height, delimiter),
+        };
+        chunk.block_writer.write_all(row.as_bytes())?;
 
         // serialize transaction
         let block_hash = utils::arr_to_hex_swapped(&block.header.hash);
+        let mut txid_str = String::with_capacity(64);
         for tx in &block.txs {
-            self.tx_writer
-                .write_all(tx.as_csv(&block_hash).as_bytes())?;
-            let txid_str = utils::arr_to_hex_swapped(&tx.hash);
+            let row = match extended {
+                true => tx.as_extended_csv(&block_hash, delimiter),
+                false => tx.as_csv(&block_hash, delimiter),
+            };
+            chunk.tx_writer.write_all(row.as_bytes())?;
+            txid_str.clear();
+            utils::hex_into_swapped(&mut txid_str, &tx.hash);
 
             // serialize inputs
             for input in &tx.value.inputs {
-                self.txin_writer
-                    .write_all(input.as_csv(&txid_str).as_bytes())?;
+                let row = match extended {
+                    true => input.as_extended_csv(&txid_str, tx.value.version, delimiter),
+                    false => input.as_csv(&txid_str, delimiter),
+                };
+                chunk.txin_writer.write_all(row.as_bytes())?;
             }
             self.in_count += tx.value.in_count.value;
 
             // serialize outputs
             for (i, output) in tx.value.outputs.iter().enumerate() {
-                self.txout_writer
-                    .write_all(output.as_csv(&txid_str, i as u32).as_bytes())?;
+                chunk
+                    .txout_writer
+                    .write_all(output.as_csv(&txid_str, i as u32, delimiter).as_bytes())?;
             }
             self.out_count += tx.value.out_count.value;
         }
@@ -110,79 +418,138 @@ impl Callback for CsvDump {
 
     fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
         self.end_height = block_height;
-
-        // Keep in sync with c'tor
-        for f in &["blocks", "transactions", "tx_in", "tx_out"] {
-            // Rename temp files
-            fs::rename(
-                self.dump_folder.as_path().join(format!("{}.csv.tmp", f)),
-                self.dump_folder.as_path().join(format!(
-                    "{}-{}-{}.csv",
-                    f, self.start_height, self.end_height
-                )),
+        self.finish_chunk(block_height)?;
+        if let Some(dialect) = self.emit_sql {
+            let format = CsvFormat {
+                delimiter: self.delimiter,
+                header: self.header,
+                compression: self.compression,
+            };
+            sqlschema::write_sql(
+                &self.dump_folder,
+                dialect,
+                &self.tables(),
+                &self.files,
+                &format,
             )?;
         }
 
-        info!(target: "callback", "Done.\nDumped all {} blocks:\n\
+        info!(target: "callback", "Done.\nDumped all {} blocks into {} chunks:\n\
                                    \t-> transactions: {:9}\n\
                                    \t-> inputs:       {:9}\n\
                                    \t-> outputs:      {:9}",
-             self.end_height, self.tx_count, self.in_count, self.out_count);
+             self.end_height, self.n_chunks, self.tx_count, self.in_count, self.out_count);
         Ok(())
     }
 }
 
 impl Block {
     #[inline]
-    fn as_csv(&self, block_height: u64) -> String {
+    fn as_csv(&self, block_height: u64, delimiter: char) -> String {
+        csv_row(&self.csv_fields(block_height), delimiter)
+    }
+
+    /// Row of `--extended` dumps, followed by the columns of `EXTENDED_BLOCK_COLUMNS`
+    #[inline]
+    fn as_extended_csv(&self, block_height: u64, delimiter: char) -> String {
+        let mut fields = self.csv_fields(block_height);
+        // (mwebSize)
+        fields.push(self.mweb_size.to_string());
+        csv_row(&fields, delimiter)
+    }
+
+    #[inline]
+    fn csv_fields(&self, block_height: u64) -> Vec<String> {
         // (@hash, height, version, blocksize, @hashPrev, @hashMerkleRoot, nTime, nBits, nNonce)
-        format!(
-            "{};{};{};{};{};{};{};{};{}\n",
-            &utils::arr_to_hex_swapped(&self.header.hash),
-            &block_height,
-            &self.header.value.version,
-            &self.size,
-            &utils::arr_to_hex_swapped(&self.header.value.prev_hash),
-            &utils::arr_to_hex_swapped(&self.header.value.merkle_root),
-            &self.header.value.timestamp,
-            &self.header.value.bits,
-            &self.header.value.nonce
-        )
+        vec![
+            utils::arr_to_hex_swapped(&self.header.hash),
+            block_height.to_string(),
+            self.header.value.version.to_string(),
+            self.size.to_string(),
+            utils::arr_to_hex_swapped(&self.header.value.prev_hash),
+            utils::arr_to_hex_swapped(&self.header.value.merkle_root),
+            self.header.value.timestamp.to_string(),
+            self.header.value.bits.to_string(),
+            self.header.value.nonce.to_string(),
+        ]
     }
 }
 
 impl Hashed<EvaluatedTx> {
     #[inline]
-    fn as_csv(&self, block_hash: &str) -> String {
+    pub(crate) fn as_csv(&self, block_hash: &str, delimiter: char) -> String {
+        csv_row(&self.csv_fields(block_hash), delimiter)
+    }
+
+    /// Row of `--extended` dumps, followed by the columns of `EXTENDED_TX_COLUMNS`
+    #[inline]
+    fn as_extended_csv(&self, block_hash: &str, delimiter: char) -> String {
+        let mut fields = self.csv_fields(block_hash);
+        // (size, strippedSize, vsize, weight, lockTimeType, usesLockTime)
+        fields.extend([
+            self.value.size.to_string(),
+            self.value.stripped_size.to_string(),
+            self.value.vsize().to_string(),
+            self.value.weight().to_string(),
+            String::from(self.value.lock_time_type().name()),
+            self.value.uses_locktime().to_string(),
+        ]);
+        csv_row(&fields, delimiter)
+    }
+
+    #[inline]
+    fn csv_fields(&self, block_hash: &str) -> Vec<String> {
         // (@txid, @hashBlock, version, lockTime)
-        format!(
-            "{};{};{};{}\n",
-            &utils::arr_to_hex_swapped(&self.hash),
-            &block_hash,
-            &self.value.version,
-            &self.value.locktime
-        )
+        vec![
+            utils::arr_to_hex_swapped(&self.hash),
+            String::from(block_hash),
+            self.value.version.to_string(),
+            self.value.locktime.to_string(),
+        ]
     }
 }
 
 impl TxInput {
     #[inline]
-    fn as_csv(&self, txid: &str) -> String {
+    pub(crate) fn as_csv(&self, txid: &str, delimiter: char) -> String {
+        csv_row(&self.csv_fields(txid), delimiter)
+    }
+
+    /// Row of `--extended` dumps, followed by the columns of `EXTENDED_TXIN_COLUMNS`
+    #[inline]
+    fn as_extended_csv(&self, txid: &str, tx_version: u32, delimiter: char) -> String {
+        let mut fields = self.csv_fields(txid);
+        // (revealedScriptType, revealedScriptHex, relativeLockType, relativeLockValue)
+        match &self.revealed_script {
+            Some(revealed) => fields.extend([
+                String::from(revealed.script_type),
+                utils::arr_to_hex(&revealed.script),
+            ]),
+            None => fields.extend([String::new(), String::new()]),
+        }
+        match self.relative_lock(tx_version) {
+            Some(lock) => fields.extend([String::from(lock.name()), lock.value().to_string()]),
+            None => fields.extend([String::new(), String::new()]),
+        }
+        csv_row(&fields, delimiter)
+    }
+
+    #[inline]
+    fn csv_fields(&self, txid: &str) -> Vec<String> {
         // (@txid, @hashPrevOut, indexPrevOut, scriptSig, sequence)
-        format!(
-            "{};{};{};{};{}\n",
-            &txid,
-            &utils::arr_to_hex_swapped(&self.outpoint.txid),
-            &self.outpoint.index,
-            &utils::arr_to_hex(&self.script_sig),
-            &self.seq_no
-        )
+        vec![
+            String::from(txid),
+            utils::arr_to_hex_swapped(&self.outpoint.txid),
+            self.outpoint.index.to_string(),
+            utils::arr_to_hex(&self.script_sig),
+            self.seq_no.to_string(),
+        ]
     }
 }
 
 impl EvaluatedTxOut {
     #[inline]
-    fn as_csv(&self, txid: &str, index: u32) -> String {
+    pub(crate) fn as_csv(&self, txid: &str, index: u32, delimiter: char) -> String {
         let address = match self.script.address.clone() {
             Some(address) => address,
             None => {
@@ -192,13 +559,385 @@ impl EvaluatedTxOut {
         };
 
         // (@txid, indexOut, value, @scriptPubKey, address)
-        format!(
-            "{};{};{};{};{}\n",
-            &txid,
-            &index,
-            &self.out.value,
-            &utils::arr_to_hex(&self.out.script_pubkey),
-            &address
-        )
+        let fields = [
+            String::from(txid),
+            index.to_string(),
+            self.out.value.to_string(),
+            utils::arr_to_hex(&self.out.script_pubkey),
+            address,
+        ];
+        csv_row(&fields, delimiter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::path::Path;
+    use std::process;
+
+    use super::*;
+    use crate::blockchain::parser::types::Bitcoin;
+    use crate::blockchain::proto::script::RevealedScript;
+    use crate::callbacks::common::read_file;
+    use crate::callbacks::fixtures::{new_chain, new_tx, new_tx_to, SCRIPT_A};
+
+    fn temp_dir(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("blockparser-csvdump-{}-{}", name, process::id()))
+    }
+
+    /// Blocks with a coinbase each, every block but the first spends the previous coinbase
+    fn chain(n: u64) -> Vec<Block> {
+        let mut blocks = Vec::new();
+        let mut prev_coinbase = None;
+        for height in 0..n {
+            let coinbase = new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[5000000000 + height]);
+            let coinbase_hash = coinbase.hash;
+            let mut txs = vec![coinbase];
+            if let Some(prev) = prev_coinbase {
+                txs.push(new_tx_to(&[(prev, 0)], &[(4999990000, SCRIPT_A)]));
+            }
+            prev_coinbase = Some(coinbase_hash);
+            blocks.push(txs);
+        }
+        new_chain(blocks)
+    }
+
+    /// `chain(3)` and a block with OP_RETURN, P2WPKH and non-standard outputs
+    fn golden_chain() -> Vec<Block> {
+        let mut blocks: Vec<Vec<Hashed<EvaluatedTx>>> =
+            chain(3).into_iter().map(|b| b.txs).collect();
+        let coinbase = new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[5000000003]);
+        let spend = new_tx_to(
+            &[(blocks[2][0].hash, 0), (blocks[1][1].hash, 0)],
+            &[
+                (0, "6a0b68656c6c6f20776f726c64"),
+                (1000, "0014751e76e8199196d454941c45d1b3a323f1433bd6"),
+                (1, "51"),
+            ],
+        );
+        blocks.push(vec![coinbase, spend]);
+        new_chain(blocks)
+    }
+
+    fn new_dump(dir: &Path, args: &[&str]) -> OpResult<CsvDump> {
+        let mut all_args = vec!["csvdump", dir.to_str().unwrap()];
+        all_args.extend_from_slice(args);
+        CsvDump::new(&CsvDump::build_subcommand().get_matches_from(all_args))
+    }
+
+    /// Dumps the chain from `start` on and returns the decompressed files sorted by name
+    fn run_dump(name: &str, args: &[&str], chain: &[Block], start: u64) -> Vec<(String, String)> {
+        let dir = temp_dir(name);
+        fs::create_dir_all(&dir).unwrap();
+        let mut dump = new_dump(&dir, args).unwrap();
+        dump.on_start(&CoinType::from(Bitcoin), start).unwrap();
+        for (height, block) in chain.iter().enumerate().skip(start as usize) {
+            dump.on_block(block, height as u64).unwrap();
+        }
+        dump.on_complete(chain.len() as u64).unwrap();
+
+        let mut files: Vec<(String, String)> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| {
+                let path = entry.unwrap().path();
+                (
+                    path.file_name().unwrap().to_string_lossy().into_owned(),
+                    String::from_utf8(read_file(&path)).unwrap(),
+                )
+            })
+            .collect();
+        files.sort();
+        fs::remove_dir_all(&dir).unwrap();
+        files
+    }
+
+    #[test]
+    fn test_golden() {
+        // The default format must not change, users' import scripts rely on it
+        let files = run_dump("golden", &[], &golden_chain(), 0);
+        let golden = [
+            (
+                "blocks-0-4.csv",
+                include_str!("testdata/csvdump_blocks.csv"),
+            ),
+            (
+                "transactions-0-4.csv",
+                include_str!("testdata/csvdump_transactions.csv"),
+            ),
+            ("tx_in-0-4.csv", include_str!("testdata/csvdump_tx_in.csv")),
+            (
+                "tx_out-0-4.csv",
+                include_str!("testdata/csvdump_tx_out.csv"),
+            ),
+        ];
+        assert_eq!(golden.len(), files.len());
+        for ((name, content), (golden_name, golden_content)) in files.iter().zip(golden) {
+            assert_eq!(golden_name, name);
+            assert_eq!(golden_content, content, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_default_header() {
+        // The columns of previous versions, new ones are only added by --extended
+        let files = run_dump("default", &["--header"], &golden_chain(), 0);
+        let headers: Vec<&str> = files
+            .iter()
+            .map(|(_, content)| content.lines().next().unwrap())
+            .collect();
+        assert_eq!(
+            vec![
+                "hash;height;version;blocksize;hashPrev;hashMerkleRoot;nTime;nBits;nNonce",
+                "txid;hashBlock;version;lockTime",
+                "txid;hashPrevOut;indexPrevOut;scriptSig;sequence",
+                "txid;indexOut;value;scriptPubKey;address",
+            ],
+            headers
+        );
+    }
+
+    #[test]
+    fn test_delimiter_and_header() {
+        let files = run_dump(
+            "header",
+            &["--delimiter", ",", "--header"],
+            &golden_chain(),
+            0,
+        );
+        let golden = [
+            include_str!("testdata/csvdump_blocks.csv"),
+            include_str!("testdata/csvdump_transactions.csv"),
+            include_str!("testdata/csvdump_tx_in.csv"),
+            include_str!("testdata/csvdump_tx_out.csv"),
+        ];
+        let tables = new_dump(&temp_dir("header"), &[]).unwrap().tables();
+        for (((_, content), table), golden) in files.iter().zip(tables).zip(golden) {
+            let (header, rows) = content.split_once('\n').unwrap();
+            assert_eq!(table.column_names().join(","), header);
+            assert_eq!(golden.replace(';', ","), rows);
+        }
+        assert_eq!(
+            "hash,height,version,blocksize,hashPrev,hashMerkleRoot,nTime,nBits,nNonce",
+            files[0].1.lines().next().unwrap()
+        );
+
+        // Fields containing the delimiter are quoted
+        let chain = golden_chain();
+        let txid = utils::arr_to_hex_swapped(&chain[3].txs[1].hash);
+        assert_eq!(
+            format!(
+                "{}q1q1000q0014751e76e8199196d454941c45d1b3a323f1433bd6q\"bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4\"\n",
+                txid
+            ),
+            chain[3].txs[1].value.outputs[1].as_csv(&txid, 1, 'q')
+        );
+        let tab = new_dump(&temp_dir("tab"), &["--delimiter", "tab"]).unwrap();
+        assert_eq!('\t', tab.delimiter);
+        for delimiter in ["\"", ";;", ""] {
+            assert!(new_dump(&temp_dir("tab"), &["--delimiter", delimiter]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_extended() {
+        let chain = golden_chain();
+        let files = run_dump("extended", &["--extended", "--header"], &chain, 0);
+        let (header, rows) = files[1].1.split_once('\n').unwrap();
+        assert_eq!(
+            "txid;hashBlock;version;lockTime;size;strippedSize;vsize;weight;lockTimeType;usesLockTime",
+            header
+        );
+        // The old columns are unchanged, the coinbase transactions have 85 bytes
+        let golden = include_str!("testdata/csvdump_transactions.csv");
+        for (row, golden) in rows.lines().zip(golden.lines()) {
+            assert_eq!(golden, row.rsplitn(7, ';').last().unwrap());
+        }
+        assert_eq!(
+            format!("{};85;85;85;340;none;false", golden.lines().next().unwrap()),
+            rows.lines().next().unwrap()
+        );
+
+        // Inputs of version 1 transactions have no relative lock, without --classify-inputs
+        // none reveals a script
+        let (header, rows) = files[2].1.split_once('\n').unwrap();
+        assert!(header.ends_with(
+            ";sequence;revealedScriptType;revealedScriptHex;relativeLockType;relativeLockValue"
+        ));
+        let golden = include_str!("testdata/csvdump_tx_in.csv");
+        for (row, golden) in rows.lines().zip(golden.lines()) {
+            assert_eq!(format!("{};;;;", golden), row);
+        }
+        let mut input = new_tx(&[([1u8; 32], 0)], &[1]).value.inputs.remove(0);
+        input.seq_no = 144;
+        assert!(input
+            .as_extended_csv("", 2, ';')
+            .ends_with(";144;;;blocks;144\n"));
+        input.revealed_script = Some(RevealedScript {
+            script_type: "multisig",
+            script: vec![0x51, 0xae],
+        });
+        assert!(input
+            .as_extended_csv("", 2, ';')
+            .ends_with(";144;multisig;51ae;blocks;144\n"));
+        assert!(input.as_csv("", ';').ends_with(";;144\n"));
+
+        // Blocks end with the size of the MWEB extension block
+        let (header, rows) = files[0].1.split_once('\n').unwrap();
+        assert!(header.ends_with(";nNonce;mwebSize"));
+        let golden = include_str!("testdata/csvdump_blocks.csv");
+        for (row, golden) in rows.lines().zip(golden.lines()) {
+            assert_eq!(format!("{};0", golden), row);
+        }
+
+        // Outputs keep their format
+        let tables = new_dump(&temp_dir("extended"), &[]).unwrap().tables();
+        let header = tables[3].column_names().join(";");
+        assert_eq!(header, files[3].1.lines().next().unwrap());
+    }
+
+    #[test]
+    fn test_split_and_compress() {
+        let chain = chain(8);
+        let whole = run_dump("whole", &[], &chain, 0);
+        let names: Vec<&str> = whole.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(
+            vec![
+                "blocks-0-8.csv",
+                "transactions-0-8.csv",
+                "tx_in-0-8.csv",
+                "tx_out-0-8.csv"
+            ],
+            names
+        );
+        assert_eq!(8, whole[0].1.lines().count());
+        assert_eq!(15, whole[1].1.lines().count());
+
+        let mut codecs = vec![("none", "")];
+        if cfg!(feature = "gzip") {
+            codecs.push(("gzip", ".gz"));
+        }
+        if cfg!(feature = "zstd") {
+            codecs.push(("zstd", ".zst"));
+        }
+        for (codec, ext) in codecs {
+            let chunks = run_dump(
+                codec,
+                &["--split-every", "3", "--compress", codec],
+                &chain,
+                0,
+            );
+            assert_eq!(12, chunks.len());
+            for (i, (table, content)) in whole.iter().enumerate() {
+                let table = table.split('-').next().unwrap();
+                let parts = &chunks[i * 3..i * 3 + 3];
+                let names: Vec<String> = parts.iter().map(|(name, _)| name.clone()).collect();
+                // Sorted by name, so 0-3 < 3-6 < 6-8
+                assert_eq!(
+                    vec![
+                        format!("{}-0-3.csv{}", table, ext),
+                        format!("{}-3-6.csv{}", table, ext),
+                        format!("{}-6-8.csv{}", table, ext)
+                    ],
+                    names
+                );
+                let joined: String = parts.iter().map(|(_, part)| part.as_str()).collect();
+                assert_eq!(content, &joined, "{} {}", codec, table);
+            }
+            // Chunk boundaries are exact
+            for (part, heights) in chunks[..3].iter().zip([0..3, 3..6, 6..8]) {
+                let dumped: Vec<u64> = part
+                    .1
+                    .lines()
+                    .map(|line| line.split(';').nth(1).unwrap().parse().unwrap())
+                    .collect();
+                assert_eq!(heights.collect::<Vec<u64>>(), dumped);
+            }
+        }
+    }
+
+    #[test]
+    fn test_partial_chunk() {
+        let chain = chain(5);
+        let dir = temp_dir("partial");
+        fs::create_dir_all(&dir).unwrap();
+        let mut dump = new_dump(&dir, &["--split-every", "3"]).unwrap();
+        dump.on_start(&CoinType::from(Bitcoin), 2).unwrap();
+        dump.on_block(&chain[2], 2).unwrap();
+        assert!(dir.join("blocks-2.csv.tmp").exists());
+        dump.on_block(&chain[3], 3).unwrap();
+        // The first chunk ends at the next multiple of 3
+        assert!(dir.join("blocks-2-3.csv").exists());
+        assert!(!dir.join("blocks-2.csv.tmp").exists());
+        assert!(dir.join("tx_out-3.csv.tmp").exists());
+        dump.on_block(&chain[4], 4).unwrap();
+        dump.on_complete(5).unwrap();
+        assert!(dir.join("tx_out-3-5.csv").exists());
+        assert!(!dir.join("tx_out-3.csv.tmp").exists());
+        assert_eq!(2, dump.n_chunks);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_emit_sql() {
+        // Chunks of the extended dump, compressed unless the database can't read it
+        let golden = [
+            (
+                "postgres",
+                include_str!("testdata/csvdump_postgres_schema.sql"),
+                include_str!("testdata/csvdump_postgres_import.sql"),
+            ),
+            (
+                "mysql",
+                include_str!("testdata/csvdump_mysql_schema.sql"),
+                include_str!("testdata/csvdump_mysql_import.sql"),
+            ),
+            (
+                "sqlite",
+                include_str!("testdata/csvdump_sqlite_schema.sql"),
+                include_str!("testdata/csvdump_sqlite_import.sql"),
+            ),
+            (
+                "clickhouse",
+                include_str!("testdata/csvdump_clickhouse_schema.sql"),
+                include_str!("testdata/csvdump_clickhouse_import.sql"),
+            ),
+        ];
+        for (dialect, schema, import) in golden {
+            let mut args = vec!["--emit-sql", dialect, "--extended", "--split-every", "2"];
+            match dialect {
+                "mysql" => args.push("--header"),
+                _ if cfg!(feature = "gzip") => args.extend(["--compress", "gzip"]),
+                _ => continue,
+            }
+            let files = run_dump(dialect, &args, &golden_chain(), 0);
+            let file = |name: &str| &files.iter().find(|(file, _)| file == name).unwrap().1;
+            assert_eq!(schema, file("schema.sql"), "{}", dialect);
+            assert_eq!(import, file("import.sql"), "{}", dialect);
+        }
+        let args = ["--emit-sql", "mysql", "--compress", "gzip"];
+        assert!(new_dump(&temp_dir("sql"), &args).is_err());
+    }
+
+    #[test]
+    fn test_invalid_args() {
+        let dir = temp_dir("args");
+        let dump = new_dump(&dir, &[]).unwrap();
+        assert_eq!(Compression::None, dump.compression);
+        assert_eq!(None, dump.split_every);
+        assert!(new_dump(&dir, &["--split-every", "0"]).is_err());
+        assert!(new_dump(&dir, &["--compress-level", "3"]).is_err());
+        if cfg!(feature = "gzip") {
+            let dump = new_dump(&dir, &["--compress", "gzip"]).unwrap();
+            assert_eq!(6, dump.compression_level);
+            assert!(new_dump(&dir, &["--compress", "gzip", "--compress-level", "10"]).is_err());
+        }
+        let zstd = new_dump(&dir, &["--compress", "zstd", "--compress-level", "19"]);
+        if cfg!(feature = "zstd") {
+            assert_eq!(19, zstd.unwrap().compression_level);
+            assert!(new_dump(&dir, &["--compress", "zstd", "--compress-level", "23"]).is_err());
+        } else {
+            assert!(zstd.is_err());
+        }
     }
 }
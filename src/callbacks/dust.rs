@@ -0,0 +1,507 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use crate::blockchain::parser::types::CoinType;
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::opreturn::{NullData, Protocol};
+use crate::blockchain::proto::tx::{EvaluatedTx, TxOutpoint};
+use crate::blockchain::proto::Hashed;
+use crate::callbacks::common::{self, OutputType, OUTPUT_TYPES};
+use crate::callbacks::Callback;
+use crate::common::outputstore::{outpoint_key, read_varint, write_varint, OutputStore};
+use crate::errors::{OpError, OpResult};
+
+const DEFAULT_BUCKET_SIZE: u64 = 10000;
+/// Dust threshold of Bitcoin Core for P2PKH outputs
+const DEFAULT_DUST_LIMIT: u64 = 546;
+const DEFAULT_PREFIX_LENGTH: usize = 5;
+const DEFAULT_TOP: usize = 100;
+
+/// Counts of the blocks `first_height..=last_height`
+#[derive(Debug, Default, PartialEq)]
+struct Bucket {
+    first_height: u64,
+    last_height: u64,
+    outputs: u64,
+    dust_outputs: [u64; OUTPUT_TYPES.len()],
+    dust_value: u64,
+    spent_dust: u64,
+}
+
+impl Bucket {
+    fn new(height: u64) -> Self {
+        Bucket {
+            first_height: height,
+            last_height: height,
+            ..Default::default()
+        }
+    }
+
+    fn header() -> String {
+        let mut header = String::from("first_height;last_height;outputs;dust_outputs;dust_value");
+        // Null data outputs can't be dust
+        for output_type in OUTPUT_TYPES.iter().filter(|t| **t != OutputType::NullData) {
+            header.push_str(";dust_");
+            header.push_str(output_type.name());
+        }
+        header.push_str(";spent_dust;unspent_dust;unspent_dust_value\n");
+        header
+    }
+
+    fn to_row(&self, unspent_dust: u64, unspent_dust_value: u64) -> String {
+        let mut row = format!(
+            "{};{};{};{};{}",
+            self.first_height,
+            self.last_height,
+            self.outputs,
+            self.dust_outputs.iter().sum::<u64>(),
+            self.dust_value
+        );
+        for output_type in OUTPUT_TYPES.iter().filter(|t| **t != OutputType::NullData) {
+            row.push_str(&format!(";{}", self.dust_outputs[output_type.index()]));
+        }
+        row.push_str(&format!(
+            ";{};{};{}\n",
+            self.spent_dust, unspent_dust, unspent_dust_value
+        ));
+        row
+    }
+}
+
+/// Counts outputs below the dust limit per bucket of blocks, tracks which of them stay
+/// unspent and who creates them.
+pub struct Dust {
+    output_dir: PathBuf,
+    bucket_size: u64,
+    // Dust limit per output type, outputs below it are dust
+    limits: [u64; OUTPUT_TYPES.len()],
+    prefix_length: usize,
+    top: usize,
+    // Values of the dust outputs which are not spent yet
+    unspent: Option<OutputStore>,
+    writer: Option<BufWriter<File>>,
+    bucket: Option<Bucket>,
+    // (dust outputs, dust value) per creator
+    creators: HashMap<String, (u64, u64)>,
+
+    start_height: u64,
+    n_dust: u64,
+    unspent_dust: u64,
+    unspent_dust_value: u64,
+}
+
+impl Dust {
+    fn apply_tx(&mut self, tx: &Hashed<EvaluatedTx>) -> OpResult<()> {
+        let unspent = self.unspent.as_mut().unwrap();
+        let bucket = self.bucket.as_mut().unwrap();
+        if !tx.value.is_coinbase() {
+            for input in &tx.value.inputs {
+                if let Some(value) = unspent.take(&outpoint_key(&input.outpoint))? {
+                    bucket.spent_dust += 1;
+                    self.unspent_dust -= 1;
+                    self.unspent_dust_value -= read_varint(&mut value.as_slice())?;
+                }
+            }
+        }
+
+        // Protocols embedding data are the creators of all dust of their transactions
+        let protocol = tx.value.outputs.iter().find_map(|output| {
+            let script = &output.out.script_pubkey;
+            let protocol = NullData::parse(script)?.classify(script, tx.value.is_coinbase());
+            Some(protocol).filter(|p| *p != Protocol::WitnessCommitment)
+        });
+        for (i, output) in tx.value.outputs.iter().enumerate() {
+            bucket.outputs += 1;
+            let output_type = OutputType::from_output(output);
+            let value = output.out.value;
+            if output_type == OutputType::NullData || value >= self.limits[output_type.index()] {
+                continue;
+            }
+            bucket.dust_outputs[output_type.index()] += 1;
+            bucket.dust_value += value;
+            self.n_dust += 1;
+            self.unspent_dust += 1;
+            self.unspent_dust_value += value;
+            let mut stored = Vec::with_capacity(2);
+            write_varint(&mut stored, value);
+            unspent.insert(outpoint_key(&TxOutpoint::new(tx.hash, i as u32)), stored)?;
+
+            let creator = match (protocol, &output.script.address) {
+                (Some(protocol), _) => format!("opreturn:{}", protocol),
+                (None, Some(address)) => format!(
+                    "address:{}",
+                    address.chars().take(self.prefix_length).collect::<String>()
+                ),
+                (None, None) => format!("type:{}", output_type.name()),
+            };
+            let entry = self.creators.entry(creator).or_insert((0, 0));
+            entry.0 += 1;
+            entry.1 += value;
+        }
+        Ok(())
+    }
+
+    fn write_bucket(&mut self) -> OpResult<()> {
+        if let Some(bucket) = self.bucket.take() {
+            let row = bucket.to_row(self.unspent_dust, self.unspent_dust_value);
+            self.writer.as_mut().unwrap().write_all(row.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Writes the creators of the most dust outputs, ties ordered by name
+    fn write_creators(&self, path: &Path) -> OpResult<()> {
+        let mut creators: Vec<(&String, &(u64, u64))> = self.creators.iter().collect();
+        creators.sort_unstable_by(|a, b| b.1 .0.cmp(&a.1 .0).then(a.0.cmp(b.0)));
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(b"creator;dust_outputs;dust_value\n")?;
+        for (creator, (count, value)) in creators.into_iter().take(self.top) {
+            writer.write_all(
+                format!("{};{};{}\n", common::escape_csv(creator), count, value).as_bytes(),
+            )?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+impl Callback for Dust {
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b>
+    where
+        Self: Sized,
+    {
+        SubCommand::with_name("dust")
+            .about("Writes outputs below the dust limit per bucket of blocks and their top creators to CSV files")
+            .version("0.1")
+            .arg(
+                Arg::with_name("output-dir")
+                    .long("output-dir")
+                    .value_name("PATH")
+                    .help("Folder to store dust.csv and dust_creators.csv, created if necessary")
+                    .takes_value(true)
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name("bucket-size")
+                    .long("bucket-size")
+                    .value_name("N_BLOCKS")
+                    .help("Number of blocks per row (default: 10000)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("dust-limit")
+                    .long("dust-limit")
+                    .value_name("SATS")
+                    .help("Outputs with a lower value are dust (default: 546)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("type-limit")
+                    .long("type-limit")
+                    .value_name("TYPE=SATS")
+                    .help("Dust limit of a script type instead of --dust-limit, e.g. p2wpkh=294 (repeatable)")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1),
+            )
+            .arg(
+                Arg::with_name("prefix-length")
+                    .long("prefix-length")
+                    .value_name("N_CHARS")
+                    .help("Characters of the address to group dust creators by (default: 5)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("top")
+                    .long("top")
+                    .value_name("N")
+                    .help("Number of creators to write (default: 100)")
+                    .takes_value(true),
+            )
+            .args(&OutputStore::args())
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let bucket_size = match matches.value_of("bucket-size") {
+            Some(_) => match value_t!(matches, "bucket-size", u64) {
+                Ok(n) if n > 0 => n,
+                _ => {
+                    return Err(OpError::from(String::from(
+                        "--bucket-size must be a positive number of blocks.",
+                    )))
+                }
+            },
+            None => DEFAULT_BUCKET_SIZE,
+        };
+        let dust_limit = match matches.value_of("dust-limit") {
+            Some(_) => value_t!(matches, "dust-limit", u64).map_err(|_| {
+                OpError::from(String::from("--dust-limit must be a number of satoshis."))
+            })?,
+            None => DEFAULT_DUST_LIMIT,
+        };
+        let mut limits = [dust_limit; OUTPUT_TYPES.len()];
+        for type_limit in matches.values_of("type-limit").into_iter().flatten() {
+            let parsed = type_limit.split_once('=').and_then(|(name, limit)| {
+                Some((OutputType::from_name(name)?, limit.parse::<u64>().ok()?))
+            });
+            match parsed {
+                Some((output_type, limit)) if output_type != OutputType::NullData => {
+                    limits[output_type.index()] = limit
+                }
+                _ => {
+                    return Err(OpError::from(format!(
+                        "Invalid --type-limit {}, expected TYPE=SATS with TYPE one of: {}.",
+                        type_limit,
+                        OUTPUT_TYPES
+                            .iter()
+                            .filter(|t| **t != OutputType::NullData)
+                            .map(|t| t.name())
+                            .collect::<Vec<&str>>()
+                            .join(", ")
+                    )))
+                }
+            }
+        }
+        let prefix_length = match matches.value_of("prefix-length") {
+            Some(_) => match value_t!(matches, "prefix-length", usize) {
+                Ok(n) if n > 0 => n,
+                _ => {
+                    return Err(OpError::from(String::from(
+                        "--prefix-length must be a positive number of characters.",
+                    )))
+                }
+            },
+            None => DEFAULT_PREFIX_LENGTH,
+        };
+        let top = match matches.value_of("top") {
+            Some(_) => match value_t!(matches, "top", usize) {
+                Ok(n) if n > 0 => n,
+                _ => {
+                    return Err(OpError::from(String::from(
+                        "--top must be a positive number of creators.",
+                    )))
+                }
+            },
+            None => DEFAULT_TOP,
+        };
+        let unspent = OutputStore::from_matches(matches)?;
+        let cb = Dust {
+            output_dir: PathBuf::from(matches.value_of("output-dir").unwrap()),
+            bucket_size,
+            limits,
+            prefix_length,
+            top,
+            unspent: Some(unspent),
+            writer: None,
+            bucket: None,
+            creators: HashMap::new(),
+            start_height: 0,
+            n_dust: 0,
+            unspent_dust: 0,
+            unspent_dust_value: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, _: &CoinType, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        if block_height != 0 {
+            warn!(target: "callback", "Starting at height {}, earlier dust outputs are not counted as unspent.", block_height);
+        }
+        fs::create_dir_all(&self.output_dir)?;
+        let mut writer =
+            BufWriter::with_capacity(4000000, File::create(self.output_dir.join("dust.csv.tmp"))?);
+        writer.write_all(Bucket::header().as_bytes())?;
+        self.writer = Some(writer);
+        info!(target: "callback", "Using `dust` with output dir: {} and bucket size: {} ...",
+              &self.output_dir.display(), self.bucket_size);
+        Ok(())
+    }
+
+    fn wants_witnesses(&self) -> bool {
+        false
+    }
+
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<()> {
+        // Buckets are aligned to multiples of the bucket size, starting with the genesis block
+        let same_bucket = self.bucket.as_ref().is_some_and(|bucket| {
+            bucket.first_height / self.bucket_size == block_height / self.bucket_size
+        });
+        if !same_bucket {
+            self.write_bucket()?;
+            self.bucket = Some(Bucket::new(block_height));
+        }
+        self.bucket.as_mut().unwrap().last_height = block_height;
+        for tx in &block.txs {
+            self.apply_tx(tx)?;
+        }
+        Ok(())
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        self.write_bucket()?;
+        let mut writer = self.writer.take().unwrap();
+        writer.flush()?;
+        drop(writer);
+        let path = self.output_dir.join("dust.csv");
+        fs::rename(path.with_extension("csv.tmp"), &path)?;
+        let creators_path = self.output_dir.join("dust_creators.csv");
+        self.write_creators(&creators_path.with_extension("csv.tmp"))?;
+        fs::rename(creators_path.with_extension("csv.tmp"), &creators_path)?;
+        self.unspent.take().unwrap().destroy()?;
+
+        info!(target: "callback", "Done.\nDumped blocks {} to {} into {}:\n\
+                                   \t-> dust outputs:       {:9}\n\
+                                   \t-> unspent dust:       {:9}\n\
+                                   \t-> unspent dust value: {:9}\n\
+                                   \t-> creators:           {:9}",
+              self.start_height, block_height, path.display(), self.n_dust,
+              self.unspent_dust, self.unspent_dust_value, self.creators.len());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+    use crate::blockchain::parser::types::Bitcoin;
+    use crate::callbacks::fixtures::{new_chain, new_tx, new_tx_to, SCRIPT_A, SCRIPT_B};
+
+    const P2WPKH: &str = "0014751e76e8199196d454941c45d1b3a323f1433bd6";
+    // Omni Layer marker
+    const OMNI: &str = "6a146f6d6e69000000000000001f000000002b752ee0";
+
+    /// Runs three blocks at heights 0 to 2 and returns the rows of both files
+    fn run(args: &[&str]) -> (Dust, Vec<String>, Vec<String>) {
+        let dir = env::temp_dir().join(format!(
+            "blockparser-dust-{}-{}",
+            args.join("-").replace('=', "_"),
+            process::id()
+        ));
+        let mut all_args = vec!["dust", "--output-dir", dir.to_str().unwrap()];
+        all_args.extend_from_slice(args);
+        let matches = Dust::build_subcommand().get_matches_from(all_args);
+        let mut dust = Dust::new(&matches).unwrap();
+
+        let coinbase_0 = new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[5000000000]);
+        // Values at and around the default limit
+        let split = new_tx_to(
+            &[(coinbase_0.hash, 0)],
+            &[
+                (545, SCRIPT_A),
+                (546, SCRIPT_A),
+                (547, SCRIPT_B),
+                (293, P2WPKH),
+                (294, P2WPKH),
+                (4999900000, SCRIPT_B),
+            ],
+        );
+        let omni = new_tx_to(
+            &[(split.hash, 5)],
+            &[
+                (546, SCRIPT_B),
+                (0, OMNI),
+                (1, SCRIPT_B),
+                (4999800000, SCRIPT_B),
+            ],
+        );
+        let coinbase_2 = new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[5000000001]);
+        let consolidate = new_tx(&[(split.hash, 0), (split.hash, 4)], &[800]);
+        let chain = new_chain(vec![
+            vec![coinbase_0],
+            vec![split, omni],
+            vec![coinbase_2, consolidate],
+        ]);
+
+        dust.on_start(&CoinType::from(Bitcoin), 0).unwrap();
+        for (height, block) in chain.iter().enumerate() {
+            dust.on_block(block, height as u64).unwrap();
+        }
+        dust.on_complete(2).unwrap();
+        let rows = fs::read_to_string(dir.join("dust.csv")).unwrap();
+        let creators = fs::read_to_string(dir.join("dust_creators.csv")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        (
+            dust,
+            rows.lines().map(String::from).collect(),
+            creators.lines().map(String::from).collect(),
+        )
+    }
+
+    #[test]
+    fn test_dust() {
+        let header =
+            "first_height;last_height;outputs;dust_outputs;dust_value;dust_p2pk;dust_p2pkh;\
+                      dust_p2sh;dust_p2wpkh;dust_p2wsh;dust_p2tr;dust_multisig;dust_nonstandard;\
+                      spent_dust;unspent_dust;unspent_dust_value";
+        // 546 and above is no dust, the data output neither
+        let (dust, rows, creators) = run(&["--bucket-size", "2"]);
+        assert_eq!(
+            vec![
+                header,
+                "0;1;11;4;1133;0;2;0;2;0;0;0;0;0;4;1133",
+                "2;2;2;0;0;0;0;0;0;0;0;0;0;2;2;294",
+            ],
+            rows
+        );
+        // Dust of transactions with a data output belongs to its protocol
+        assert_eq!(
+            vec![
+                "creator;dust_outputs;dust_value",
+                "address:bc1qw;2;587",
+                "address:12hig;1;545",
+                "opreturn:omni;1;1",
+            ],
+            creators
+        );
+        assert_eq!(
+            (4, 2, 294),
+            (dust.n_dust, dust.unspent_dust, dust.unspent_dust_value)
+        );
+
+        // Segwit outputs have their own limit
+        let (dust, rows, creators) = run(&[
+            "--type-limit",
+            "p2wpkh=294",
+            "--type-limit",
+            "p2pkh=547",
+            "--top",
+            "1",
+            "--prefix-length",
+            "1",
+        ]);
+        assert_eq!(vec![header, "0;2;13;5;1931;0;4;0;1;0;0;0;0;1;4;1386"], rows);
+        assert_eq!(
+            vec!["creator;dust_outputs;dust_value", "address:1;2;1091"],
+            creators
+        );
+        assert_eq!(5, dust.n_dust);
+    }
+
+    #[test]
+    fn test_invalid_args() {
+        let cases: [&[&str]; 5] = [
+            &["--type-limit", "p2wpkh"],
+            &["--type-limit", "p2wpkh=-1"],
+            &["--type-limit", "nulldata=100"],
+            &["--type-limit", "segwit=100"],
+            &["--dust-limit", "abc"],
+        ];
+        for args in cases {
+            let mut all_args = vec!["dust", "--output-dir", "out"];
+            all_args.extend_from_slice(args);
+            let matches = Dust::build_subcommand().get_matches_from(all_args);
+            assert!(Dust::new(&matches).is_err(), "{:?}", args);
+        }
+    }
+}
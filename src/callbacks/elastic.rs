@@ -0,0 +1,816 @@
+use std::env;
+use std::mem;
+use std::thread;
+use std::time::Duration;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use serde_json::{json, Value};
+use url::Url;
+
+use crate::blockchain::parser::types::CoinType;
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::target::U256;
+use crate::callbacks::Callback;
+use crate::common::http::{self, HttpError, Response};
+use crate::common::utils;
+use crate::errors::{OpError, OpResult};
+
+const DEFAULT_URL: &str = "http://localhost:9200";
+const DEFAULT_INDEX_PREFIX: &str = "blockparser";
+const PASSWORD_ENV: &str = "ELASTIC_PASSWORD";
+const DEFAULT_BATCH_BYTES: usize = 5 * 1024 * 1024;
+const NDJSON: &str = "application/x-ndjson";
+const JSON: &str = "application/json";
+// Large bulk requests can take a while until the cluster answers
+const TIMEOUT: Duration = Duration::from_secs(300);
+const MAX_RETRIES: u32 = 3;
+// Doubled after every attempt
+const RETRY_BACKOFF: Duration = Duration::from_secs(1);
+// Longer scripts are stored in `_source` but not indexed
+const MAX_SCRIPT_KEYWORD: u32 = 1024;
+
+/// Sends requests to the cluster, implemented over HTTP and mocked in tests
+pub trait Transport {
+    /// Posts `body` to `path` relative to the cluster URL.
+    /// Responses with an error status are returned as error.
+    fn post(&mut self, path: &str, content_type: &str, body: &[u8]) -> OpResult<Response>;
+}
+
+struct HttpTransport {
+    url: Url,
+    // Value of the Authorization header
+    authorization: Option<String>,
+    retry_backoff: Duration,
+}
+
+impl Transport for HttpTransport {
+    /// Retries on connection errors and unavailable or overloaded nodes
+    fn post(&mut self, path: &str, content_type: &str, body: &[u8]) -> OpResult<Response> {
+        let mut url = self.url.clone();
+        url.path_segments_mut()
+            .map_err(|_| OpError::from(format!("Invalid URL: {}.", self.url)))?
+            .pop_if_empty()
+            .extend(path.split('/'));
+        let mut headers = vec![("Content-Type", content_type)];
+        if let Some(authorization) = &self.authorization {
+            headers.push(("Authorization", authorization.as_str()));
+        }
+        let mut attempt = 0;
+        loop {
+            let result = http::post(&url, &headers, body, TIMEOUT);
+            let retriable = match &result {
+                Ok(response) => matches!(response.status, 408 | 429 | 502 | 503 | 504),
+                Err(HttpError::Io(_)) => true,
+                Err(_) => false,
+            };
+            if retriable && attempt < MAX_RETRIES {
+                let backoff = self.retry_backoff * 2u32.pow(attempt);
+                match &result {
+                    Ok(response) => {
+                        warn!(target: "elastic", "Server returned {}, retrying in {:?} ...", response.status, backoff)
+                    }
+                    Err(err) => {
+                        warn!(target: "elastic", "{}, retrying in {:?} ...", err, backoff)
+                    }
+                }
+                thread::sleep(backoff);
+                attempt += 1;
+                continue;
+            }
+            let response = result?;
+            if !response.is_success() {
+                return Err(OpError::from(format!(
+                    "Elasticsearch returned {} for `{}`: {}",
+                    response.status,
+                    path,
+                    String::from_utf8_lossy(&response.body).trim()
+                )));
+            }
+            return Ok(response);
+        }
+    }
+}
+
+/// Document waiting for the next bulk request
+struct BulkDoc {
+    index: String,
+    id: String,
+    source: Vec<u8>,
+}
+
+/// Indexes blocks and transactions into Elasticsearch with the `_bulk` API.
+/// Documents have the same fields as the `jsondump` lines plus `@timestamp`.
+pub struct Elastic {
+    url: Url,
+    block_index: String,
+    tx_index: String,
+    create_templates: bool,
+    genesis_bits: u32,
+    chainwork: Option<U256>,
+    transport: Box<dyn Transport>,
+    retry_backoff: Duration,
+
+    docs: Vec<BulkDoc>,
+    batch_bytes: usize,
+    buffered_bytes: usize,
+    n_requests: u64,
+
+    start_height: u64,
+    end_height: u64,
+    indexed_count: u64,
+    failed_count: u64,
+}
+
+impl Callback for Elastic {
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b>
+    where
+        Self: Sized,
+    {
+        SubCommand::with_name("elastic")
+            .about("Indexes blocks and transactions into Elasticsearch")
+            .version("0.1")
+            .arg(
+                Arg::with_name("url")
+                    .long("url")
+                    .value_name("URL")
+                    .help("URL of the cluster (default: http://localhost:9200)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("index-prefix")
+                    .long("index-prefix")
+                    .value_name("PREFIX")
+                    .help("Documents go to <PREFIX>-blocks and <PREFIX>-transactions (default: blockparser)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("user")
+                    .long("user")
+                    .value_name("USER")
+                    .help("User name for basic authentication")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("password")
+                    .long("password")
+                    .value_name("PASSWORD")
+                    .help("Password (default: $ELASTIC_PASSWORD)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("batch-bytes")
+                    .long("batch-bytes")
+                    .value_name("BYTES")
+                    .help("Size of the buffered documents which triggers a bulk request (default: 5242880)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("create-templates")
+                    .long("create-templates")
+                    .help("Create or replace the index templates with explicit mappings"),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let url = Url::parse(matches.value_of("url").unwrap_or(DEFAULT_URL))
+            .map_err(|e| OpError::from(format!("Invalid URL: {}.", e)))?;
+        http::validate_url(&url)?;
+        let prefix = matches
+            .value_of("index-prefix")
+            .unwrap_or(DEFAULT_INDEX_PREFIX);
+        validate_index_prefix(prefix)?;
+        let batch_bytes = match matches.value_of("batch-bytes") {
+            Some(_) => match value_t!(matches, "batch-bytes", usize) {
+                Ok(n) if n > 0 => n,
+                _ => {
+                    return Err(OpError::from(String::from(
+                        "--batch-bytes must be a positive number of bytes.",
+                    )))
+                }
+            },
+            None => DEFAULT_BATCH_BYTES,
+        };
+        let authorization = matches.value_of("user").map(|user| {
+            let password = match matches.value_of("password") {
+                Some(password) => String::from(password),
+                None => env::var(PASSWORD_ENV).unwrap_or_default(),
+            };
+            format!("Basic {}", base64::encode(format!("{}:{}", user, password)))
+        });
+        let transport = HttpTransport {
+            url: url.clone(),
+            authorization,
+            retry_backoff: RETRY_BACKOFF,
+        };
+        let cb = Elastic {
+            url,
+            block_index: format!("{}-blocks", prefix),
+            tx_index: format!("{}-transactions", prefix),
+            create_templates: matches.is_present("create-templates"),
+            genesis_bits: 0,
+            chainwork: None,
+            transport: Box::new(transport),
+            retry_backoff: RETRY_BACKOFF,
+            docs: Vec::new(),
+            batch_bytes,
+            buffered_bytes: 0,
+            n_requests: 0,
+            start_height: 0,
+            end_height: 0,
+            indexed_count: 0,
+            failed_count: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, coin_type: &CoinType, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        self.genesis_bits = coin_type.genesis_bits;
+        // Chain work is only known if the dump starts at the genesis block
+        self.chainwork = if block_height == 0 {
+            Some(U256::ZERO)
+        } else {
+            None
+        };
+        if self.create_templates {
+            for (index, mappings) in [
+                (&self.block_index, block_mappings()),
+                (&self.tx_index, tx_mappings()),
+            ] {
+                let template = json!({
+                    "index_patterns": [index],
+                    "template": {"mappings": mappings}
+                });
+                self.transport
+                    .post(
+                        &format!("_index_template/{}", index),
+                        JSON,
+                        &serde_json::to_vec(&template).unwrap(),
+                    )
+                    .map_err(|e| {
+                        e.join_msg(&format!("Unable to create the index template `{}`.", index))
+                    })?;
+            }
+        }
+        info!(target: "callback", "Using `elastic` with indices `{}` and `{}` on {} ...",
+              self.block_index, self.tx_index, self.url);
+        Ok(())
+    }
+
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<()> {
+        if let Some(chainwork) = self.chainwork.as_mut() {
+            let target = U256::from_compact(block.header.value.bits).unwrap_or_default();
+            *chainwork = *chainwork + target.work();
+        }
+        let timestamp = utils::timestamp_to_iso8601(block.header.value.timestamp);
+        let block_hash = utils::arr_to_hex_swapped(&block.header.hash);
+        // Transactions come first, so an indexed block implies that its transactions are indexed as well
+        for tx in &block.txs {
+            let mut tx_json = tx.as_json(&block_hash, block_height);
+            tx_json["@timestamp"] = Value::from(timestamp.as_str());
+            let id = utils::arr_to_hex_swapped(&tx.hash);
+            self.push(self.tx_index.clone(), id, &tx_json);
+        }
+        let mut block_json = block.as_json(block_height, self.genesis_bits, self.chainwork);
+        block_json["@timestamp"] = Value::from(timestamp);
+        self.push(self.block_index.clone(), block_hash, &block_json);
+
+        if self.buffered_bytes >= self.batch_bytes {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        self.end_height = block_height;
+        self.flush()?;
+
+        info!(target: "callback", "Done.\nIndexed all {} blocks into `{}` and `{}`:\n\
+                                   \t-> documents:    {:9}\n\
+                                   \t-> failed:       {:9}\n\
+                                   \t-> requests:     {:9}",
+             self.end_height, self.block_index, self.tx_index, self.indexed_count, self.failed_count,
+             self.n_requests);
+        Ok(())
+    }
+}
+
+impl Elastic {
+    fn push(&mut self, index: String, id: String, doc: &Value) {
+        let source = serde_json::to_vec(doc).unwrap();
+        self.buffered_bytes += source.len();
+        self.docs.push(BulkDoc { index, id, source });
+    }
+
+    /// Sends the buffered documents. Rejected documents are sent again up to `MAX_RETRIES` times,
+    /// the remaining ones are logged and counted as failed.
+    fn flush(&mut self) -> OpResult<()> {
+        let mut docs = mem::take(&mut self.docs);
+        self.buffered_bytes = 0;
+        let mut attempt = 0;
+        while !docs.is_empty() {
+            let response = self
+                .transport
+                .post("_bulk", NDJSON, &bulk_body(&docs))
+                .map_err(|e| e.join_msg("Unable to index documents."))?;
+            self.n_requests += 1;
+            let failed = failed_items(&response.body, docs.len())?;
+            self.indexed_count += (docs.len() - failed.len()) as u64;
+            if failed.is_empty() {
+                break;
+            }
+            if attempt >= MAX_RETRIES {
+                for (position, reason) in &failed {
+                    let doc = &docs[*position];
+                    warn!(target: "elastic", "Unable to index document {} into `{}`: {}", doc.id, doc.index, reason);
+                }
+                self.failed_count += failed.len() as u64;
+                break;
+            }
+            let backoff = self.retry_backoff * 2u32.pow(attempt);
+            warn!(target: "elastic", "{} documents were rejected ({}), retrying in {:?} ...",
+                  failed.len(), failed[0].1, backoff);
+            thread::sleep(backoff);
+            attempt += 1;
+
+            let mut failed = failed.into_iter().peekable();
+            docs = docs
+                .into_iter()
+                .enumerate()
+                .filter(|(i, _)| failed.next_if(|(position, _)| position == i).is_some())
+                .map(|(_, doc)| doc)
+                .collect();
+        }
+        debug!(target: "elastic", "Flushed documents with {} requests", self.n_requests);
+        Ok(())
+    }
+}
+
+/// Index names must be lowercase and must not contain separators or wildcards
+fn validate_index_prefix(prefix: &str) -> OpResult<()> {
+    let invalid = prefix.is_empty()
+        || prefix.starts_with(['-', '_', '+'])
+        || prefix.contains(['\\', '/', '*', '?', '"', '<', '>', '|', ' ', ',', '#', ':'])
+        || prefix.chars().any(|c| c.is_uppercase());
+    if invalid {
+        return Err(OpError::from(format!(
+            "Invalid --index-prefix `{}`, index names must be lowercase without special characters.",
+            prefix
+        )));
+    }
+    Ok(())
+}
+
+/// Newline delimited pairs of action and document. The documents are indexed with their hash
+/// as id, so existing documents (e.g. of an earlier run) are replaced.
+fn bulk_body(docs: &[BulkDoc]) -> Vec<u8> {
+    let size = docs.iter().map(|doc| doc.source.len() + 128).sum();
+    let mut body = Vec::with_capacity(size);
+    for doc in docs {
+        let action = json!({"index": {"_index": doc.index, "_id": doc.id}});
+        serde_json::to_writer(&mut body, &action).unwrap();
+        body.push(b'\n');
+        body.extend_from_slice(&doc.source);
+        body.push(b'\n');
+    }
+    body
+}
+
+/// Returns position and reason of the documents which weren't indexed, in request order
+fn failed_items(body: &[u8], n_docs: usize) -> OpResult<Vec<(usize, String)>> {
+    let response: Value = serde_json::from_slice(body)
+        .map_err(|e| OpError::from(format!("Invalid bulk response: {}.", e)))?;
+    let items = response["items"]
+        .as_array()
+        .ok_or_else(|| OpError::from(String::from("Bulk response without items.")))?;
+    if items.len() != n_docs {
+        return Err(OpError::from(format!(
+            "Bulk response contains {} items for {} documents.",
+            items.len(),
+            n_docs
+        )));
+    }
+    let mut failed = Vec::new();
+    if response["errors"] != Value::Bool(true) {
+        return Ok(failed);
+    }
+    for (position, item) in items.iter().enumerate() {
+        let result = &item["index"];
+        let error = &result["error"];
+        if error.is_null() {
+            continue;
+        }
+        // `error` is an object with type and reason, or a plain string in some versions
+        let reason = match error["type"].as_str() {
+            Some(error_type) => format!(
+                "{} {}: {}",
+                result["status"],
+                error_type,
+                error["reason"].as_str().unwrap_or_default()
+            ),
+            None => format!("{} {}", result["status"], error),
+        };
+        failed.push((position, reason));
+    }
+    Ok(failed)
+}
+
+fn keyword() -> Value {
+    json!({"type": "keyword"})
+}
+
+fn long() -> Value {
+    json!({"type": "long"})
+}
+
+fn boolean() -> Value {
+    json!({"type": "boolean"})
+}
+
+/// Hex encoded scripts, too long ones aren't searchable
+fn script() -> Value {
+    json!({"type": "keyword", "ignore_above": MAX_SCRIPT_KEYWORD})
+}
+
+/// Mappings of the block documents, see `Block::as_json()`
+fn block_mappings() -> Value {
+    json!({
+        "properties": {
+            "@timestamp": {"type": "date"},
+            "hash": keyword(),
+            "blockHeight": long(),
+            "version": long(),
+            "size": long(),
+            "previousHash": keyword(),
+            "merkleRootHash": keyword(),
+            "timestamp": {"type": "date", "format": "epoch_second"},
+            "nBits": long(),
+            "difficulty": {"type": "double"},
+            "target": keyword(),
+            "chainwork": keyword(),
+            "txCount": long(),
+            "nNonce": long()
+        }
+    })
+}
+
+/// Mappings of the transaction documents, see `Hashed<EvaluatedTx>::as_json()`
+fn tx_mappings() -> Value {
+    json!({
+        "properties": {
+            "@timestamp": {"type": "date"},
+            "txHash": keyword(),
+            "wtxHash": keyword(),
+            "hasWitness": boolean(),
+            "blockHash": keyword(),
+            "blockHeight": long(),
+            "version": long(),
+            "lockTime": long(),
+            "isCoinbase": boolean(),
+            "inputCount": long(),
+            "txInputs": {
+                "properties": {
+                    "txHash": keyword(),
+                    "hashPrevOut": keyword(),
+                    "indexPrevOut": long(),
+                    "indexIn": long(),
+                    "scriptSig": script(),
+                    "sequenceNumber": long()
+                }
+            },
+            "outputCount": long(),
+            "txOutputs": {
+                "properties": {
+                    "txHash": keyword(),
+                    "indexOut": long(),
+                    "value": long(),
+                    "scriptPubKey": script(),
+                    "address": keyword(),
+                    "scriptType": keyword(),
+                    "dataHex": script()
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::blockchain::parser::types::Bitcoin;
+    use crate::callbacks::fixtures::{new_chain, new_tx};
+
+    /// (path, body) of the sent requests
+    type Requests = Rc<RefCell<Vec<(String, String)>>>;
+
+    /// Records (path, body) of each request and answers with the next response,
+    /// a successful bulk response once they are used up
+    struct MockTransport {
+        requests: Requests,
+        responses: VecDeque<String>,
+    }
+
+    impl Transport for MockTransport {
+        fn post(&mut self, path: &str, _: &str, body: &[u8]) -> OpResult<Response> {
+            let body = String::from_utf8(body.to_vec()).unwrap();
+            let n_docs = body.lines().count() / 2;
+            self.requests.borrow_mut().push((String::from(path), body));
+            let body = self.responses.pop_front().unwrap_or_else(|| {
+                let items = vec![json!({"index": {"status": 201}}); n_docs];
+                json!({"errors": false, "items": items}).to_string()
+            });
+            Ok(Response {
+                status: 200,
+                headers: Vec::new(),
+                body: body.into_bytes(),
+            })
+        }
+    }
+
+    fn new_elastic(args: &[&str], responses: Vec<Value>) -> (Elastic, Requests) {
+        let mut all_args = vec!["elastic"];
+        all_args.extend_from_slice(args);
+        let matches = Elastic::build_subcommand().get_matches_from(all_args);
+        let mut elastic = Elastic::new(&matches).unwrap();
+        let requests = Rc::new(RefCell::new(Vec::new()));
+        elastic.transport = Box::new(MockTransport {
+            requests: requests.clone(),
+            responses: responses.iter().map(|r| r.to_string()).collect(),
+        });
+        elastic.retry_backoff = Duration::from_millis(1);
+        (elastic, requests)
+    }
+
+    fn run(elastic: &mut Elastic) -> Vec<Block> {
+        let coinbase_0 = new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[5000000000]);
+        let coinbase_1 = new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[5000000001]);
+        let chain = new_chain(vec![vec![coinbase_0], vec![coinbase_1]]);
+        elastic.on_start(&CoinType::from(Bitcoin), 0).unwrap();
+        for (height, block) in chain.iter().enumerate() {
+            elastic.on_block(block, height as u64).unwrap();
+        }
+        elastic.on_complete(1).unwrap();
+        chain
+    }
+
+    /// Parses the (action, document) pairs of a bulk body
+    fn parse_body(body: &str) -> Vec<(Value, Value)> {
+        let lines: Vec<Value> = body
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        lines
+            .chunks(2)
+            .map(|pair| (pair[0].clone(), pair[1].clone()))
+            .collect()
+    }
+
+    fn rejected(status: u16, error_type: &str) -> Value {
+        json!({"index": {"_id": "x", "status": status, "error": {"type": error_type, "reason": "rejected"}}})
+    }
+
+    #[test]
+    fn test_bulk_body() {
+        let docs = vec![
+            BulkDoc {
+                index: String::from("p-transactions"),
+                id: String::from("aa"),
+                source: br#"{"txHash":"aa"}"#.to_vec(),
+            },
+            BulkDoc {
+                index: String::from("p-blocks"),
+                id: String::from("bb"),
+                source: br#"{"hash":"bb"}"#.to_vec(),
+            },
+        ];
+        assert_eq!(
+            "{\"index\":{\"_index\":\"p-transactions\",\"_id\":\"aa\"}}\n{\"txHash\":\"aa\"}\n\
+             {\"index\":{\"_index\":\"p-blocks\",\"_id\":\"bb\"}}\n{\"hash\":\"bb\"}\n",
+            String::from_utf8(bulk_body(&docs)).unwrap()
+        );
+        assert!(bulk_body(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_failed_items() {
+        let response = json!({
+            "took": 3,
+            "errors": true,
+            "items": [
+                {"index": {"_index": "p-blocks", "_id": "a", "status": 201, "result": "created"}},
+                {"index": {"_index": "p-blocks", "_id": "b", "status": 400, "error": {
+                    "type": "mapper_parsing_exception",
+                    "reason": "failed to parse field [size] of type [long]"
+                }}},
+                {"index": {"_index": "p-blocks", "_id": "c", "status": 429, "error": "es_rejected_execution_exception"}}
+            ]
+        })
+        .to_string();
+        assert_eq!(
+            vec![
+                (
+                    1,
+                    String::from(
+                        "400 mapper_parsing_exception: failed to parse field [size] of type [long]"
+                    )
+                ),
+                (2, String::from("429 \"es_rejected_execution_exception\"")),
+            ],
+            failed_items(response.as_bytes(), 3).unwrap()
+        );
+        let response = json!({"errors": false, "items": [{"index": {"status": 200}}]}).to_string();
+        assert!(failed_items(response.as_bytes(), 1).unwrap().is_empty());
+        // Every document needs an item
+        assert!(failed_items(response.as_bytes(), 2).is_err());
+        assert!(failed_items(b"{\"error\":\"oops\"}", 0).is_err());
+        assert!(failed_items(b"<html>", 0).is_err());
+    }
+
+    #[test]
+    fn test_validate_index_prefix() {
+        assert!(validate_index_prefix("blockparser").is_ok());
+        assert!(validate_index_prefix("btc-main.v1").is_ok());
+        for prefix in ["", "Upper", "_hidden", "a*", "a b", "a/b", "a,b"] {
+            assert!(validate_index_prefix(prefix).is_err(), "{}", prefix);
+        }
+    }
+
+    #[test]
+    fn test_elastic() {
+        let (mut elastic, requests) = new_elastic(&["--index-prefix", "btc"], vec![]);
+        let chain = run(&mut elastic);
+        assert_eq!(4, elastic.indexed_count);
+        assert_eq!(0, elastic.failed_count);
+
+        let requests = requests.borrow();
+        assert_eq!(1, requests.len());
+        assert_eq!("_bulk", requests[0].0);
+        let docs = parse_body(&requests[0].1);
+        let ids: Vec<(&str, String)> = docs
+            .iter()
+            .map(|(action, _)| {
+                (
+                    action["index"]["_index"].as_str().unwrap(),
+                    String::from(action["index"]["_id"].as_str().unwrap()),
+                )
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                (
+                    "btc-transactions",
+                    utils::arr_to_hex_swapped(&chain[0].txs[0].hash)
+                ),
+                (
+                    "btc-blocks",
+                    utils::arr_to_hex_swapped(&chain[0].header.hash)
+                ),
+                (
+                    "btc-transactions",
+                    utils::arr_to_hex_swapped(&chain[1].txs[0].hash)
+                ),
+                (
+                    "btc-blocks",
+                    utils::arr_to_hex_swapped(&chain[1].header.hash)
+                ),
+            ],
+            ids
+        );
+        let (_, block) = &docs[3];
+        assert_eq!("2009-01-03T18:15:05Z", block["@timestamp"]);
+        assert_eq!(1231006505, block["timestamp"]);
+        assert_eq!(1, block["blockHeight"]);
+        assert!(block["chainwork"].is_string());
+        let (_, tx) = &docs[2];
+        assert_eq!("2009-01-03T18:15:05Z", tx["@timestamp"]);
+        assert_eq!(ids[3].1, tx["blockHash"]);
+        assert_eq!(5000000001u64, tx["txOutputs"][0]["value"]);
+    }
+
+    #[test]
+    fn test_batch_bytes() {
+        let (mut elastic, requests) = new_elastic(&["--batch-bytes", "1"], vec![]);
+        run(&mut elastic);
+        // One request per block
+        assert_eq!(2, requests.borrow().len());
+        assert_eq!(4, elastic.indexed_count);
+
+        let matches =
+            Elastic::build_subcommand().get_matches_from(vec!["elastic", "--batch-bytes", "0"]);
+        assert!(Elastic::new(&matches).is_err());
+    }
+
+    #[test]
+    fn test_retry_rejected() {
+        let ok = json!({"index": {"status": 201}});
+        let responses = vec![
+            // Transaction of block 0 and block 1 rejected
+            json!({"errors": true, "items": [rejected(429, "es_rejected_execution_exception"), ok, ok, rejected(429, "es_rejected_execution_exception")]}),
+            // Block 1 still rejected
+            json!({"errors": true, "items": [ok, rejected(429, "es_rejected_execution_exception")]}),
+        ];
+        let (mut elastic, requests) = new_elastic(&[], responses);
+        let chain = run(&mut elastic);
+        assert_eq!(4, elastic.indexed_count);
+        assert_eq!(0, elastic.failed_count);
+
+        let requests = requests.borrow();
+        assert_eq!(3, requests.len());
+        let retried: Vec<Vec<String>> = requests[1..]
+            .iter()
+            .map(|(_, body)| {
+                parse_body(body)
+                    .iter()
+                    .map(|(action, _)| String::from(action["index"]["_id"].as_str().unwrap()))
+                    .collect()
+            })
+            .collect();
+        let block_1 = utils::arr_to_hex_swapped(&chain[1].header.hash);
+        assert_eq!(
+            vec![
+                vec![
+                    utils::arr_to_hex_swapped(&chain[0].txs[0].hash),
+                    block_1.clone()
+                ],
+                vec![block_1],
+            ],
+            retried
+        );
+    }
+
+    #[test]
+    fn test_give_up_rejected() {
+        let ok = json!({"index": {"status": 201}});
+        let mapping_error = rejected(400, "mapper_parsing_exception");
+        let mut responses = vec![json!({"errors": true, "items": [ok, mapping_error, ok, ok]})];
+        responses
+            .extend((0..MAX_RETRIES).map(|_| json!({"errors": true, "items": [mapping_error]})));
+        let (mut elastic, requests) = new_elastic(&[], responses);
+        run(&mut elastic);
+        assert_eq!(3, elastic.indexed_count);
+        assert_eq!(1, elastic.failed_count);
+        assert_eq!(1 + MAX_RETRIES as usize, requests.borrow().len());
+        assert_eq!(1 + MAX_RETRIES as u64, elastic.n_requests);
+    }
+
+    #[test]
+    fn test_create_templates() {
+        let (mut elastic, requests) = new_elastic(&["--create-templates"], vec![]);
+        elastic.on_start(&CoinType::from(Bitcoin), 0).unwrap();
+        let requests = requests.borrow();
+        assert_eq!(2, requests.len());
+        assert_eq!("_index_template/blockparser-blocks", requests[0].0);
+        assert_eq!("_index_template/blockparser-transactions", requests[1].0);
+
+        let template: Value = serde_json::from_str(&requests[0].1).unwrap();
+        assert_eq!(json!(["blockparser-blocks"]), template["index_patterns"]);
+        let properties = &template["template"]["mappings"]["properties"];
+        assert_eq!("keyword", properties["hash"]["type"]);
+        assert_eq!("long", properties["size"]["type"]);
+        assert_eq!("date", properties["@timestamp"]["type"]);
+        assert_eq!("epoch_second", properties["timestamp"]["format"]);
+
+        let template: Value = serde_json::from_str(&requests[1].1).unwrap();
+        let outputs = &template["template"]["mappings"]["properties"]["txOutputs"]["properties"];
+        assert_eq!("keyword", outputs["address"]["type"]);
+        assert_eq!("long", outputs["value"]["type"]);
+    }
+
+    #[test]
+    fn test_http_transport() {
+        let (url, requests) = http::test_server(vec![
+            (503, "unavailable"),
+            (200, "{}"),
+            (400, "{\"error\":\"bad\"}"),
+        ]);
+        let mut transport = HttpTransport {
+            url: url.join("es/").unwrap(),
+            authorization: Some(String::from("Basic dXNlcjpwdw==")),
+            retry_backoff: Duration::from_millis(1),
+        };
+        let response = transport.post("_bulk", NDJSON, b"{}\n").unwrap();
+        assert_eq!(b"{}".to_vec(), response.body);
+        assert!(transport.post("_index_template/a", JSON, b"{}").is_err());
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(3, requests.len());
+        assert_eq!("/es/_bulk", requests[1].target);
+        assert_eq!("/es/_index_template/a", requests[2].target);
+        let header = |name: &str| {
+            requests[1]
+                .headers
+                .iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.as_str())
+        };
+        assert_eq!(Some(NDJSON), header("content-type"));
+        assert_eq!(Some("Basic dXNlcjpwdw=="), header("authorization"));
+    }
+}
@@ -0,0 +1,770 @@
+use std::borrow::Cow;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use serde_json::{json, Value};
+
+use crate::blockchain::parser::types::CoinType;
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::script::{self, AddressFormat, EvaluatedScript, ScriptPattern};
+use crate::blockchain::proto::tx::{EvaluatedTx, TxOutpoint};
+use crate::blockchain::proto::Hashed;
+use crate::callbacks::Callback;
+use crate::common::outputstore::{outpoint_key, read_varint, write_varint, OutputStore};
+use crate::common::utils;
+use crate::errors::OpResult;
+
+/// Columns of bitcoin-etl as in testdata/etl_schema.txt, keep in sync with EtlDump::block_row and
+/// EtlDump::tx_row
+const BLOCK_COLUMNS: [&str; 12] = [
+    "hash",
+    "size",
+    "stripped_size",
+    "weight",
+    "number",
+    "version",
+    "merkle_root",
+    "timestamp",
+    "nonce",
+    "bits",
+    "coinbase_param",
+    "transaction_count",
+];
+const TX_COLUMNS: [&str; 17] = [
+    "hash",
+    "size",
+    "virtual_size",
+    "version",
+    "lock_time",
+    "block_number",
+    "block_hash",
+    "block_timestamp",
+    "is_coinbase",
+    "index",
+    "inputs",
+    "outputs",
+    "input_count",
+    "output_count",
+    "input_value",
+    "output_value",
+    "fee",
+];
+
+/// Dumps blocks and transactions into the CSV files of bitcoin-etl
+pub struct EtlDump {
+    output_dir: PathBuf,
+    block_writer: BufWriter<File>,
+    tx_writer: BufWriter<File>,
+    // Value and scriptPubKey of the outputs which are not spent yet
+    outputs: Option<OutputStore>,
+    address_format: AddressFormat,
+
+    start_height: u64,
+    end_height: u64,
+    tx_count: u64,
+    // Spent outputs created before the start height
+    n_unresolved: u64,
+}
+
+impl EtlDump {
+    fn create_writer(path: PathBuf) -> OpResult<BufWriter<File>> {
+        Ok(BufWriter::with_capacity(4000000, File::create(path)?))
+    }
+
+    fn block_row(&self, block: &Block, block_height: u64) -> Vec<String> {
+        let header = &block.header.value;
+        let coinbase_param = block
+            .txs
+            .first()
+            .filter(|tx| tx.value.is_coinbase())
+            .map(|tx| utils::arr_to_hex(&tx.value.inputs[0].script_sig))
+            .unwrap_or_default();
+        vec![
+            utils::arr_to_hex_swapped(&block.header.hash),
+            block.size.to_string(),
+            block.stripped_size.to_string(),
+            block.weight.to_string(),
+            block_height.to_string(),
+            header.version.to_string(),
+            utils::arr_to_hex_swapped(&header.merkle_root),
+            utils::timestamp_to_iso8601(header.timestamp),
+            format!("{:08x}", header.nonce),
+            format!("{:08x}", header.bits),
+            coinbase_param,
+            block.txs.len().to_string(),
+        ]
+    }
+
+    /// Resolves the spent outputs and stores the new ones, so transactions can spend outputs of
+    /// earlier transactions in the same block.
+    fn tx_row(
+        &mut self,
+        tx: &Hashed<EvaluatedTx>,
+        index: usize,
+        block: &Block,
+        block_height: u64,
+    ) -> OpResult<Vec<String>> {
+        let is_coinbase = tx.value.is_coinbase();
+        let mut inputs = Vec::with_capacity(tx.value.inputs.len());
+        let mut input_value = Some(0);
+        // The coinbase input is part of the block (coinbase_param)
+        if !is_coinbase {
+            for (i, input) in tx.value.inputs.iter().enumerate() {
+                let spent = self
+                    .outputs
+                    .as_mut()
+                    .unwrap()
+                    .take(&outpoint_key(&input.outpoint))?;
+                let (value, script_pubkey) = match spent {
+                    Some(spent) => {
+                        let mut reader = spent.as_slice();
+                        let value = read_varint(&mut reader)?;
+                        (Some(value), reader.to_vec())
+                    }
+                    None => {
+                        self.n_unresolved += 1;
+                        (None, Vec::new())
+                    }
+                };
+                input_value = input_value.zip(value).map(|(sum, value)| sum + value);
+                let evaluated =
+                    value.map(|_| script::eval_from_bytes(&script_pubkey, self.address_format));
+                inputs.push(json!({
+                    "index": i,
+                    "spent_transaction_hash": utils::arr_to_hex_swapped(&input.outpoint.txid),
+                    "spent_output_index": input.outpoint.index,
+                    "script_asm": script::to_asm(&input.script_sig, true),
+                    "script_hex": utils::arr_to_hex(&input.script_sig),
+                    "sequence": input.seq_no,
+                    "required_signatures": evaluated.as_ref().and_then(|e| required_signatures(e, &script_pubkey)),
+                    "type": evaluated.as_ref().map(|e| type_name(&e.pattern)),
+                    "addresses": evaluated.as_ref().map(|e| addresses(e, &script_pubkey)).unwrap_or_default(),
+                    "value": value,
+                }));
+            }
+        }
+
+        let mut outputs = Vec::with_capacity(tx.value.outputs.len());
+        let mut output_value = 0;
+        for (i, output) in tx.value.outputs.iter().enumerate() {
+            let script_pubkey = &output.out.script_pubkey;
+            outputs.push(json!({
+                "index": i,
+                "script_asm": script::to_asm(script_pubkey, false),
+                "script_hex": utils::arr_to_hex(script_pubkey),
+                "required_signatures": required_signatures(&output.script, script_pubkey),
+                "type": type_name(&output.script.pattern),
+                "addresses": addresses(&output.script, script_pubkey),
+                "value": output.out.value,
+            }));
+            output_value += output.out.value;
+
+            let mut stored = Vec::with_capacity(script_pubkey.len() + 9);
+            write_varint(&mut stored, output.out.value);
+            stored.extend_from_slice(script_pubkey);
+            self.outputs
+                .as_mut()
+                .unwrap()
+                .insert(outpoint_key(&TxOutpoint::new(tx.hash, i as u32)), stored)?;
+        }
+
+        let fee = match is_coinbase {
+            true => Some(0),
+            false => input_value.map(|input_value| input_value.saturating_sub(output_value)),
+        };
+        let optional = |n: Option<u64>| n.map(|n| n.to_string()).unwrap_or_default();
+        let (input_count, output_count) = (inputs.len(), outputs.len());
+        Ok(vec![
+            utils::arr_to_hex_swapped(&tx.hash),
+            tx.value.size.to_string(),
+            tx.value.vsize().to_string(),
+            tx.value.version.to_string(),
+            tx.value.locktime.to_string(),
+            block_height.to_string(),
+            utils::arr_to_hex_swapped(&block.header.hash),
+            utils::timestamp_to_iso8601(block.header.value.timestamp),
+            is_coinbase.to_string(),
+            index.to_string(),
+            Value::from(inputs).to_string(),
+            Value::from(outputs).to_string(),
+            input_count.to_string(),
+            output_count.to_string(),
+            optional(input_value),
+            output_value.to_string(),
+            optional(fee),
+        ])
+    }
+}
+
+/// Script type names of Bitcoin Core, scripts which fail to parse are nonstandard
+#[inline]
+fn type_name(pattern: &ScriptPattern) -> &'static str {
+    match pattern {
+        ScriptPattern::Error(_) => "nonstandard",
+        pattern => pattern.type_name(),
+    }
+}
+
+/// `m` of multisig scripts, None for scripts which can't be spent with signatures
+fn required_signatures(script: &EvaluatedScript, script_pubkey: &[u8]) -> Option<u8> {
+    match script.pattern {
+        ScriptPattern::Pay2PublicKey
+        | ScriptPattern::Pay2PublicKeyHash
+        | ScriptPattern::Pay2ScriptHash
+        | ScriptPattern::WitnessV0Keyhash
+        | ScriptPattern::WitnessV0Scripthash => Some(1),
+        // Starts with OP_1 to OP_16
+        ScriptPattern::Pay2MultiSig => script_pubkey.first().map(|op| op - 0x50),
+        _ => None,
+    }
+}
+
+/// Outputs without an address get `nonstandard` followed by the first 20 bytes of the sha256
+/// of their script, like in bitcoin-etl. Null data outputs have no address.
+fn addresses(script: &EvaluatedScript, script_pubkey: &[u8]) -> Vec<String> {
+    match (&script.address, &script.pattern) {
+        (Some(address), _) => vec![address.clone()],
+        (None, ScriptPattern::DataOutput(_)) => Vec::new(),
+        (None, _) => vec![format!(
+            "nonstandard{}",
+            utils::arr_to_hex(&utils::sha256(script_pubkey)[..20])
+        )],
+    }
+}
+
+/// Writes a CSV row, fields with separators, quotes or line breaks are quoted
+fn write_row<W: Write, S: AsRef<str>>(writer: &mut W, fields: &[S]) -> OpResult<()> {
+    let row: Vec<Cow<str>> = fields
+        .iter()
+        .map(|field| {
+            let field = field.as_ref();
+            if field.contains([',', '"', '\n', '\r']) {
+                Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+            } else {
+                Cow::Borrowed(field)
+            }
+        })
+        .collect();
+    writer.write_all(row.join(",").as_bytes())?;
+    writer.write_all(b"\n")?;
+    Ok(())
+}
+
+impl Callback for EtlDump {
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b>
+    where
+        Self: Sized,
+    {
+        SubCommand::with_name("etldump")
+            .about("Dumps blocks and transactions into the CSV files of bitcoin-etl")
+            .version("0.1")
+            .arg(
+                Arg::with_name("output-dir")
+                    .long("output-dir")
+                    .value_name("PATH")
+                    .help("Folder to store blocks.csv and transactions.csv, created if necessary")
+                    .takes_value(true)
+                    .required(true),
+            )
+            .args(&OutputStore::args())
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let output_dir = PathBuf::from(matches.value_of("output-dir").unwrap());
+        fs::create_dir_all(&output_dir)?;
+        let outputs = OutputStore::from_matches(matches)?;
+        let mut block_writer = EtlDump::create_writer(output_dir.join("blocks.csv.tmp"))?;
+        let mut tx_writer = EtlDump::create_writer(output_dir.join("transactions.csv.tmp"))?;
+        write_row(&mut block_writer, &BLOCK_COLUMNS)?;
+        write_row(&mut tx_writer, &TX_COLUMNS)?;
+        let cb = EtlDump {
+            output_dir,
+            block_writer,
+            tx_writer,
+            outputs: Some(outputs),
+            address_format: AddressFormat::default(),
+            start_height: 0,
+            end_height: 0,
+            tx_count: 0,
+            n_unresolved: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, coin_type: &CoinType, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        self.address_format = coin_type.address_format();
+        if block_height != 0 {
+            warn!(target: "callback", "Starting at height {}, inputs spending earlier outputs have no value.", block_height);
+        }
+        info!(target: "callback", "Using `etldump` with output dir: {} ...", &self.output_dir.display());
+        Ok(())
+    }
+
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<()> {
+        let row = self.block_row(block, block_height);
+        write_row(&mut self.block_writer, &row)?;
+        for (index, tx) in block.txs.iter().enumerate() {
+            let row = self.tx_row(tx, index, block, block_height)?;
+            write_row(&mut self.tx_writer, &row)?;
+        }
+        self.tx_count += block.txs.len() as u64;
+        Ok(())
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        self.end_height = block_height;
+        self.block_writer.flush()?;
+        self.tx_writer.flush()?;
+        self.outputs.take().unwrap().destroy()?;
+        for f in &["blocks", "transactions"] {
+            fs::rename(
+                self.output_dir.join(format!("{}.csv.tmp", f)),
+                self.output_dir.join(format!("{}.csv", f)),
+            )?;
+        }
+
+        info!(target: "callback", "Done.\nDumped blocks {} to {}:\n\
+                                   \t-> transactions: {:9}\n\
+                                   \t-> unresolved:   {:9}",
+             self.start_height, self.end_height, self.tx_count, self.n_unresolved);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::io::Cursor;
+    use std::process;
+
+    use super::*;
+    use crate::blockchain::parser::reader::BlockchainRead;
+    use crate::blockchain::parser::types::Bitcoin;
+    use crate::blockchain::proto::block::BlockFormat;
+
+    /// Block 0, 170 (first transaction between persons) and the coinbase of block 9 spent by it
+    const GENESIS_BLOCK: &str = concat!(
+        "0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b2",
+        "7ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a29ab5f49ffff001d1dac2b7c0101000000010000",
+        "000000000000000000000000000000000000000000000000000000000000ffffffff4d04ffff001d01044554",
+        "68652054696d65732030332f4a616e2f32303039204368616e63656c6c6f72206f6e206272696e6b206f6620",
+        "7365636f6e64206261696c6f757420666f722062616e6b73ffffffff0100f2052a01000000434104678afdb0",
+        "fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de",
+        "5c384df7ba0b8d578a4c702b6bf11d5fac00000000",
+    );
+    const BLOCK_170: &str = concat!(
+        "0100000055bd840a78798ad0da853f68974f3d183e2bd1db6a842c1feecf222a00000000ff104ccb05421ab9",
+        "3e63f8c3ce5c2c2e9dbb37de2764b3a3175c8166562cac7d51b96a49ffff001d283e9e700201000000010000",
+        "000000000000000000000000000000000000000000000000000000000000ffffffff0704ffff001d0102ffff",
+        "ffff0100f2052a01000000434104d46c4968bde02899d2aa0963367c7a6ce34eec332b32e42e5f3407e052d6",
+        "4ac625da6f0718e7b302140434bd725706957c092db53805b821a85b23a7ac61725bac000000000100000001",
+        "c997a5e56e104102fa209c6a852dd90660a20b2d9c352423edce25857fcd3704000000004847304402204e45",
+        "e16932b8af514961a1d3a1a25fdf3f4f7732e9d624c6c61548ab5fb8cd410220181522ec8eca07de4860a4ac",
+        "dd12909d831cc56cbbac4622082221a8768d1d0901ffffffff0200ca9a3b00000000434104ae1a62fe09c5f5",
+        "1b13905f07f06b99a2f7159b2225f374cd378d71302fa28414e7aab37397f554a7df5f142c21c1b7303b8a06",
+        "26f1baded5c72a704f7e6cd84cac00286bee0000000043410411db93e1dcdb8a016b49840f8c53bc1eb68a38",
+        "2e97b1482ecad7b148a6909a5cb2e0eaddfb84ccf9744464f82e160bfa9b8b64f9d4c03f999b8643f656b412",
+        "a3ac00000000",
+    );
+    const COINBASE_9_TXID: &str =
+        "0437cd7f8525ceed2324359c2d0ba26006d92d856a9c20fa0241106ee5a597c9";
+    const COINBASE_9_SCRIPT: &str = concat!(
+        "410411db93e1dcdb8a016b49840f8c53bc1eb68a382e97b1482ecad7b148a6909a5cb2e0eaddfb84ccf97444",
+        "64f82e160bfa9b8b64f9d4c03f999b8643f656b412a3ac",
+    );
+
+    fn read_block(hex: &str) -> Block {
+        let raw = utils::hex_to_vec(hex);
+        Cursor::new(&raw)
+            .read_block(
+                raw.len() as u32,
+                AddressFormat::default(),
+                BlockFormat::Bitcoin,
+            )
+            .unwrap()
+    }
+
+    fn output_dir(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("blockparser-etldump-{}-{}", name, process::id()));
+        fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    /// Fields of a record of `testdata/etl_schema.txt` and their types, in order
+    fn reference(record: &str) -> Vec<(&'static str, &'static str)> {
+        include_str!("testdata/etl_schema.txt")
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .filter_map(|line| {
+                let fields: Vec<&str> = line.split(' ').collect();
+                match fields[..] {
+                    [name, field, kind] if name == record => Some((field, kind)),
+                    _ => None,
+                }
+            })
+            .collect()
+    }
+
+    /// Splits the lines into fields, the opposite of `write_row()`
+    fn read_csv(text: &str) -> Vec<Vec<String>> {
+        text.lines()
+            .map(|line| {
+                let mut fields = vec![String::new()];
+                let mut quoted = false;
+                let mut chars = line.chars().peekable();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' if quoted && chars.peek() == Some(&'"') => {
+                            chars.next();
+                            fields.last_mut().unwrap().push('"');
+                        }
+                        '"' => quoted = !quoted,
+                        ',' if !quoted => fields.push(String::new()),
+                        c => fields.last_mut().unwrap().push(c),
+                    }
+                }
+                fields
+            })
+            .collect()
+    }
+
+    fn is_hex(s: &str) -> bool {
+        !s.is_empty()
+            && s.len().is_multiple_of(2)
+            && s.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f'))
+    }
+
+    /// Asserts that the CSV field has the encoding of its type in the reference
+    fn check_field(record: &str, name: &str, kind: &str, field: &str) {
+        if let Some(item) = kind.strip_prefix("[]") {
+            let objects: Vec<Value> = serde_json::from_str(field).unwrap();
+            objects.iter().for_each(|object| check_object(item, object));
+            return;
+        }
+        let valid = match kind {
+            "hex_string" => is_hex(field),
+            "bigint" => field.parse::<u64>().is_ok(),
+            "boolean" => field == "true" || field == "false",
+            // YYYY-MM-DDTHH:MM:SSZ
+            "timestamp" => {
+                field.len() == 20
+                    && field
+                        .chars()
+                        .zip("0000-00-00T00:00:00Z".chars())
+                        .all(|(c, pattern)| match pattern {
+                            '0' => c.is_ascii_digit(),
+                            _ => c == pattern,
+                        })
+            }
+            _ => false,
+        };
+        assert!(valid, "{}.{} isn't a {}: {}", record, name, kind, field);
+    }
+
+    /// Asserts that the object has the fields of the record in the reference, in order
+    fn check_object(record: &str, object: &Value) {
+        let object = object.as_object().unwrap();
+        let fields = reference(record);
+        let names: Vec<&str> = fields.iter().map(|(name, _)| *name).collect();
+        assert_eq!(
+            names,
+            object.keys().map(String::as_str).collect::<Vec<&str>>()
+        );
+        for (name, kind) in fields {
+            let value = &object[name];
+            let valid = match kind {
+                "hex_string" => value.as_str().is_some_and(is_hex),
+                "bigint" => value.is_u64(),
+                "string" => value.is_string(),
+                "[]string" => value
+                    .as_array()
+                    .is_some_and(|values| values.iter().all(Value::is_string)),
+                _ => false,
+            };
+            assert!(valid, "{}.{} isn't a {}: {}", record, name, kind, value);
+        }
+    }
+
+    #[test]
+    fn test_reference() {
+        let folder = output_dir("reference");
+        let matches = EtlDump::build_subcommand().get_matches_from([
+            "etldump",
+            "--output-dir",
+            folder.to_str().unwrap(),
+        ]);
+        let mut etldump = EtlDump::new(&matches).unwrap();
+        etldump.on_start(&CoinType::from(Bitcoin), 0).unwrap();
+        etldump.on_block(&read_block(GENESIS_BLOCK), 0).unwrap();
+
+        // Blocks 1 to 169 are left out, only the output spent by block 170 is needed
+        let mut stored = Vec::new();
+        write_varint(&mut stored, 5000000000);
+        stored.extend_from_slice(&utils::hex_to_vec(COINBASE_9_SCRIPT));
+        let outpoint = TxOutpoint::new(utils::hex_to_arr32_swapped(COINBASE_9_TXID), 0);
+        etldump
+            .outputs
+            .as_mut()
+            .unwrap()
+            .insert(outpoint_key(&outpoint), stored)
+            .unwrap();
+        etldump.on_block(&read_block(BLOCK_170), 170).unwrap();
+        etldump.on_complete(170).unwrap();
+        assert_eq!(0, etldump.n_unresolved);
+
+        let blocks = read_csv(&fs::read_to_string(folder.join("blocks.csv")).unwrap());
+        let txs = read_csv(&fs::read_to_string(folder.join("transactions.csv")).unwrap());
+        fs::remove_dir_all(&folder).unwrap();
+        for (record, rows) in [("blocks", &blocks), ("transactions", &txs)] {
+            let fields = reference(record);
+            let names: Vec<&str> = fields.iter().map(|(name, _)| *name).collect();
+            assert_eq!(names, rows[0]);
+            for row in &rows[1..] {
+                assert_eq!(fields.len(), row.len());
+                for ((name, kind), field) in fields.iter().zip(row) {
+                    check_field(record, name, kind, field);
+                }
+            }
+        }
+
+        // Values of getblock and getrawtransaction of Bitcoin Core, checked by hand
+        assert_eq!(3, blocks.len());
+        assert_eq!(
+            vec![
+                "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f",
+                "285",
+                "285",
+                "1140",
+                "0",
+                "1",
+                "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b",
+                // 1231006505
+                "2009-01-03T18:15:05Z",
+                // 2083236893
+                "7c2bac1d",
+                "1d00ffff",
+                concat!(
+                    "04ffff001d0104455468652054696d65732030332f4a616e2f32303039204368616e63656c6c",
+                    "6f72206f6e206272696e6b206f66207365636f6e64206261696c6f757420666f722062616e6b73",
+                ),
+                "1",
+            ],
+            blocks[1]
+        );
+        assert_eq!(
+            vec![
+                "00000000d1145790a8694403d4063f323d499e655c83426834d4ce2f8dd4a2ee",
+                "490",
+                "490",
+                "1960",
+                "170",
+                "1",
+                "7dac2c5666815c17a3b36427de37bb9d2e2c5ccec3f8633eb91a4205cb4c10ff",
+                // 1231731025
+                "2009-01-12T03:30:25Z",
+                // 1889418792
+                "709e3e28",
+                "1d00ffff",
+                "04ffff001d0102",
+                "2",
+            ],
+            blocks[2]
+        );
+
+        assert_eq!(4, txs.len());
+        let (genesis_coinbase, coinbase, spend) = (&txs[1], &txs[2], &txs[3]);
+        // Without the inputs and outputs columns
+        let scalars = |row: &[String]| [&row[..10], &row[12..]].concat();
+        assert_eq!(
+            vec![
+                "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b",
+                "204",
+                "204",
+                "1",
+                "0",
+                "0",
+                "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f",
+                "2009-01-03T18:15:05Z",
+                "true",
+                "0",
+                "0",
+                "1",
+                "0",
+                "5000000000",
+                "0",
+            ],
+            scalars(genesis_coinbase)
+        );
+        assert_eq!(
+            vec![
+                "b1fea52486ce0c62bb442b530a3f0132b826c74e473d1f2c220bfa78111c5082",
+                "134",
+                "134",
+                "1",
+                "0",
+                "170",
+                "00000000d1145790a8694403d4063f323d499e655c83426834d4ce2f8dd4a2ee",
+                "2009-01-12T03:30:25Z",
+                "true",
+                "0",
+                "0",
+                "1",
+                "0",
+                "5000000000",
+                "0",
+            ],
+            scalars(coinbase)
+        );
+        assert_eq!(
+            vec![
+                "f4184fc596403b9d638783cf57adfe4c75c605f6356fbc91338530e9831e9e16",
+                "275",
+                "275",
+                "1",
+                "0",
+                "170",
+                "00000000d1145790a8694403d4063f323d499e655c83426834d4ce2f8dd4a2ee",
+                "2009-01-12T03:30:25Z",
+                "false",
+                "1",
+                "1",
+                "2",
+                "5000000000",
+                "5000000000",
+                "0",
+            ],
+            scalars(spend)
+        );
+
+        // The coinbase input is the block's coinbase_param
+        assert_eq!("[]", genesis_coinbase[10]);
+        assert_eq!("[]", coinbase[10]);
+        let objects = |field: &str| -> Vec<Value> { serde_json::from_str(field).unwrap() };
+        let outputs = objects(&genesis_coinbase[11]);
+        assert_eq!(
+            json!(["1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa"]),
+            outputs[0]["addresses"]
+        );
+        assert_eq!(
+            json!(["1PSSGeFHDnKNxiEyFrD1wcEaHr9hrQDDWc"]),
+            objects(&coinbase[11])[0]["addresses"]
+        );
+
+        let inputs = objects(&spend[10]);
+        assert_eq!(1, inputs.len());
+        assert_eq!(0, inputs[0]["index"]);
+        assert_eq!(COINBASE_9_TXID, inputs[0]["spent_transaction_hash"]);
+        assert_eq!(0, inputs[0]["spent_output_index"]);
+        assert_eq!(
+            concat!(
+                "304402204e45e16932b8af514961a1d3a1a25fdf3f4f7732e9d624c6c61548ab5fb8cd41022018",
+                "1522ec8eca07de4860a4acdd12909d831cc56cbbac4622082221a8768d1d09[ALL]"
+            ),
+            inputs[0]["script_asm"]
+        );
+        assert_eq!(4294967295u32, inputs[0]["sequence"]);
+        assert_eq!(1, inputs[0]["required_signatures"]);
+        assert_eq!("pubkey", inputs[0]["type"]);
+        assert_eq!(
+            json!(["12cbQLTFMXRnSzktFkuoG3eHoMeFtpTu3S"]),
+            inputs[0]["addresses"]
+        );
+        assert_eq!(5000000000u64, inputs[0]["value"]);
+
+        let outputs = objects(&spend[11]);
+        let paid: Vec<(Value, Value, Value)> = outputs
+            .iter()
+            .map(|output| {
+                (
+                    output["index"].clone(),
+                    output["addresses"].clone(),
+                    output["value"].clone(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                (
+                    json!(0),
+                    json!(["1Q2TWHE3GMdB6BZKafqwxXtWAWgFt5Jvm3"]),
+                    json!(1000000000)
+                ),
+                (
+                    json!(1),
+                    json!(["12cbQLTFMXRnSzktFkuoG3eHoMeFtpTu3S"]),
+                    json!(4000000000u64)
+                ),
+            ],
+            paid
+        );
+        for output in &outputs {
+            assert_eq!("pubkey", output["type"]);
+            assert_eq!(1, output["required_signatures"]);
+            let script_hex = output["script_hex"].as_str().unwrap();
+            // <65 byte key> OP_CHECKSIG
+            assert_eq!(
+                format!("{} OP_CHECKSIG", &script_hex[2..132]),
+                output["script_asm"]
+            );
+        }
+    }
+
+    #[test]
+    fn test_unresolved() {
+        let folder = output_dir("unresolved");
+        let matches = EtlDump::build_subcommand().get_matches_from([
+            "etldump",
+            "--output-dir",
+            folder.to_str().unwrap(),
+        ]);
+        let mut etldump = EtlDump::new(&matches).unwrap();
+        etldump.on_start(&CoinType::from(Bitcoin), 170).unwrap();
+        etldump.on_block(&read_block(BLOCK_170), 170).unwrap();
+        etldump.on_complete(170).unwrap();
+        assert_eq!(1, etldump.n_unresolved);
+
+        let txs = fs::read_to_string(folder.join("transactions.csv")).unwrap();
+        fs::remove_dir_all(&folder).unwrap();
+        let spend = txs.lines().nth(2).unwrap();
+        // No input value and fee, the input has no type and addresses
+        assert!(spend.ends_with(",1,2,,5000000000,"), "{}", spend);
+        assert!(spend.contains(
+            r#"""required_signatures"":null,""type"":null,""addresses"":[],""value"":null}"#
+        ));
+    }
+
+    #[test]
+    fn test_write_row() {
+        let mut out = Vec::new();
+        write_row(&mut out, &["a", "b,c", "d\"e", ""]).unwrap();
+        assert_eq!("a,\"b,c\",\"d\"\"e\",\n", String::from_utf8(out).unwrap());
+    }
+
+    #[test]
+    fn test_addresses() {
+        // 2 of 3 keys
+        let key = format!("21{}", "02".repeat(33));
+        let p2ms = utils::hex_to_vec(&format!("52{}{}{}53ae", key, key, key));
+        let script = script::eval_from_bytes(&p2ms, AddressFormat::default());
+        assert_eq!(Some(2), required_signatures(&script, &p2ms));
+        assert_eq!("multisig", type_name(&script.pattern));
+        let address = &addresses(&script, &p2ms)[0];
+        assert_eq!(51, address.len());
+        assert!(address.starts_with("nonstandard"));
+
+        let op_return = utils::hex_to_vec("6a0401020304");
+        let script = script::eval_from_bytes(&op_return, AddressFormat::default());
+        assert_eq!(None, required_signatures(&script, &op_return));
+        assert!(addresses(&script, &op_return).is_empty());
+
+        let bogus = [0x4c, 0xff];
+        let script = script::eval_from_bytes(&bogus, AddressFormat::default());
+        assert_eq!("nonstandard", type_name(&script.pattern));
+    }
+}
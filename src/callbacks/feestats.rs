@@ -0,0 +1,317 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use crate::blockchain::parser::types::CoinType;
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::tx::{EvaluatedTx, TxOutpoint};
+use crate::blockchain::proto::Hashed;
+use crate::callbacks::Callback;
+use crate::common::outputstore::{outpoint_key, read_varint, write_varint, OutputStore};
+use crate::errors::OpResult;
+
+/// Percentiles written between min and max
+const PERCENTILES: [u64; 4] = [25, 50, 75, 90];
+
+/// Writes fee rate statistics in sat/vbyte per block to a csv file.
+///
+/// Transactions spending an output which couldn't be resolved, e.g. because the parser started
+/// after the genesis block, are excluded and reported as fraction of the block's transactions.
+pub struct FeeStats {
+    output_dir: PathBuf,
+    // Values of the outputs which are not spent yet, None if the inputs carry them
+    outputs: Option<OutputStore>,
+    prevouts_attached: bool,
+    writer: Option<BufWriter<File>>,
+
+    start_height: u64,
+    tx_count: u64,
+    excluded_count: u64,
+    total_fees: u64,
+}
+
+impl FeeStats {
+    /// Returns the fee of the transaction, or None if an input value is unknown.
+    /// Inputs are resolved by their attached prevout first, then by the store.
+    fn apply_tx(
+        mut outputs: Option<&mut OutputStore>,
+        tx: &Hashed<EvaluatedTx>,
+    ) -> OpResult<Option<u64>> {
+        let is_coinbase = tx.value.is_coinbase();
+        let mut input_value = Some(0u64);
+        if !is_coinbase {
+            for input in &tx.value.inputs {
+                let value = match (&input.prevout, outputs.as_deref_mut()) {
+                    (Some(prevout), _) => Some(prevout.out.out.value),
+                    (None, Some(outputs)) => match outputs.take(&outpoint_key(&input.outpoint))? {
+                        Some(value) => Some(read_varint(&mut value.as_slice())?),
+                        None => None,
+                    },
+                    (None, None) => None,
+                };
+                input_value = input_value.zip(value).map(|(sum, value)| sum + value);
+            }
+        }
+        let mut output_value = 0;
+        for (i, output) in tx.value.outputs.iter().enumerate() {
+            if let Some(outputs) = outputs.as_deref_mut() {
+                let mut value = Vec::with_capacity(9);
+                write_varint(&mut value, output.out.value);
+                outputs.insert(outpoint_key(&TxOutpoint::new(tx.hash, i as u32)), value)?;
+            }
+            output_value += output.out.value;
+        }
+        if is_coinbase {
+            return Ok(None);
+        }
+        // Zcash transactions may take value out of or put it into the shielded pools
+        let delta = tx.value.shielded_value_delta();
+        Ok(input_value.map(|input_value| {
+            input_value
+                .saturating_add_signed(delta)
+                .saturating_sub(output_value)
+        }))
+    }
+}
+
+impl Callback for FeeStats {
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b>
+    where
+        Self: Sized,
+    {
+        SubCommand::with_name("feestats")
+            .about("Writes fee rate statistics per block to CSV file")
+            .version("0.1")
+            .arg(
+                Arg::with_name("output-dir")
+                    .long("output-dir")
+                    .value_name("PATH")
+                    .help("Folder to store feestats.csv, created if necessary")
+                    .takes_value(true)
+                    .required(true),
+            )
+            .args(&OutputStore::args())
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let outputs = OutputStore::from_matches(matches)?;
+        let cb = FeeStats {
+            output_dir: PathBuf::from(matches.value_of("output-dir").unwrap()),
+            outputs: Some(outputs),
+            prevouts_attached: false,
+            writer: None,
+            start_height: 0,
+            tx_count: 0,
+            excluded_count: 0,
+            total_fees: 0,
+        };
+        Ok(cb)
+    }
+
+    fn set_prevouts_attached(&mut self) {
+        self.prevouts_attached = true;
+    }
+
+    fn on_start(&mut self, _: &CoinType, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        if self.prevouts_attached {
+            // Nothing to track, the inputs carry the values
+            if let Some(outputs) = self.outputs.take() {
+                outputs.destroy()?;
+            }
+        } else if block_height != 0 {
+            warn!(target: "callback", "Starting at height {}, transactions spending earlier outputs are excluded.", block_height);
+        }
+        fs::create_dir_all(&self.output_dir)?;
+        let mut writer = BufWriter::with_capacity(
+            4000000,
+            File::create(self.output_dir.join("feestats.csv.tmp"))?,
+        );
+        writer.write_all(b"height;tx_count;total_fees;min;p25;median;p75;p90;max;excluded\n")?;
+        self.writer = Some(writer);
+        info!(target: "callback", "Using `feestats` with output dir: {} ...", &self.output_dir.display());
+        Ok(())
+    }
+
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<()> {
+        let mut fee_rates = Vec::with_capacity(block.txs.len());
+        let (mut total_fees, mut excluded) = (0u64, 0u64);
+        for tx in &block.txs {
+            let is_coinbase = tx.value.is_coinbase();
+            match FeeStats::apply_tx(self.outputs.as_mut(), tx)? {
+                Some(fee) => {
+                    total_fees += fee;
+                    fee_rates.push(fee as f64 / tx.value.vsize() as f64);
+                }
+                None if !is_coinbase => excluded += 1,
+                None => {}
+            }
+        }
+        fee_rates.sort_unstable_by(f64::total_cmp);
+
+        let mut row = format!("{};{};{}", block_height, fee_rates.len(), total_fees);
+        let mut rates = vec![fee_rates.first().copied()];
+        rates.extend(PERCENTILES.iter().map(|p| percentile(&fee_rates, *p)));
+        rates.push(fee_rates.last().copied());
+        for rate in rates {
+            row.push(';');
+            if let Some(rate) = rate {
+                row.push_str(&format!("{:.3}", rate));
+            }
+        }
+        let n_txs = fee_rates.len() as u64 + excluded;
+        let excluded_fraction = if n_txs == 0 {
+            0.0
+        } else {
+            excluded as f64 / n_txs as f64
+        };
+        row.push_str(&format!(";{:.3}\n", excluded_fraction));
+        self.writer.as_mut().unwrap().write_all(row.as_bytes())?;
+
+        self.tx_count += fee_rates.len() as u64;
+        self.excluded_count += excluded;
+        self.total_fees += total_fees;
+        Ok(())
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        let mut writer = self.writer.take().unwrap();
+        writer.flush()?;
+        drop(writer);
+        let path = self.output_dir.join("feestats.csv");
+        fs::rename(path.with_extension("csv.tmp"), &path)?;
+        if let Some(outputs) = self.outputs.take() {
+            outputs.destroy()?;
+        }
+
+        info!(target: "callback", "Done.\nDumped blocks {} to {} into {}:\n\
+                                   \t-> transactions: {:9}\n\
+                                   \t-> excluded:     {:9}\n\
+                                   \t-> total fees:   {:9}",
+             self.start_height, block_height, path.display(), self.tx_count, self.excluded_count, self.total_fees);
+        Ok(())
+    }
+}
+
+/// Nearest-rank percentile of the sorted values
+fn percentile(sorted: &[f64], p: u64) -> Option<f64> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = (p * sorted.len() as u64).div_ceil(100).max(1);
+    Some(sorted[rank as usize - 1])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+    use crate::blockchain::parser::types::Bitcoin;
+    use crate::blockchain::parser::undo::{self, SpentOutput};
+    use crate::blockchain::proto::script::AddressFormat;
+    use crate::callbacks::fixtures::{new_block, new_chain, new_tx, SCRIPT_A};
+    use crate::common::utils;
+
+    #[test]
+    fn test_percentile() {
+        let values: Vec<f64> = (1..=10).map(|n| n as f64).collect();
+        assert_eq!(Some(3.0), percentile(&values, 25));
+        assert_eq!(Some(5.0), percentile(&values, 50));
+        assert_eq!(Some(9.0), percentile(&values, 90));
+        assert_eq!(Some(1.0), percentile(&values, 0));
+        assert_eq!(Some(10.0), percentile(&values, 100));
+        assert_eq!(Some(7.0), percentile(&[7.0], 25));
+        assert_eq!(None, percentile(&[], 50));
+    }
+
+    fn run(args: &[&str], name: &str) -> Vec<String> {
+        let dir = env::temp_dir().join(format!("blockparser-feestats-{}-{}", name, process::id()));
+        let mut all_args = vec!["feestats", "--output-dir", dir.to_str().unwrap()];
+        all_args.extend_from_slice(args);
+        let matches = FeeStats::build_subcommand().get_matches_from(all_args);
+
+        let coinbase_0 = new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[5000000000]);
+        let coinbase_1 = new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[5000015000]);
+        // Each transaction has 85 vbytes
+        let spend = new_tx(&[(coinbase_0.hash, 0)], &[4999990000]);
+        let spend_again = new_tx(&[(spend.hash, 0)], &[4999985000]);
+        let unresolved = new_tx(&[([7u8; 32], 0)], &[1000]);
+        assert_eq!(85, spend.value.vsize());
+        let chain = new_chain(vec![
+            vec![coinbase_0],
+            vec![coinbase_1, spend, spend_again, unresolved],
+        ]);
+
+        let mut feestats = FeeStats::new(&matches).unwrap();
+        feestats.on_start(&CoinType::from(Bitcoin), 0).unwrap();
+        for (height, block) in chain.iter().enumerate() {
+            feestats.on_block(block, height as u64).unwrap();
+        }
+        feestats.on_complete(1).unwrap();
+        assert_eq!(2, feestats.tx_count);
+        assert_eq!(1, feestats.excluded_count);
+        assert_eq!(15000, feestats.total_fees);
+
+        let csv = fs::read_to_string(dir.join("feestats.csv")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        csv.lines().map(String::from).collect()
+    }
+
+    #[test]
+    fn test_feestats() {
+        let expected = vec![
+            "height;tx_count;total_fees;min;p25;median;p75;p90;max;excluded",
+            // Coinbase only
+            "0;0;0;;;;;;;0.000",
+            "1;2;15000;58.824;58.824;58.824;117.647;117.647;117.647;0.333",
+        ];
+        assert_eq!(expected, run(&[], "memory"));
+
+        let db_path = env::temp_dir().join(format!("blockparser-feestats-db-{}", process::id()));
+        let args = ["--db-path", db_path.to_str().unwrap(), "--buffer-size", "1"];
+        assert_eq!(expected, run(&args, "spill"));
+        assert!(!db_path.exists());
+    }
+
+    #[test]
+    fn test_feestats_attached_prevouts() {
+        let dir = env::temp_dir().join(format!("blockparser-feestats-undo-{}", process::id()));
+        let db_path = dir.join("db");
+        let matches = FeeStats::build_subcommand().get_matches_from(vec![
+            "feestats",
+            "--output-dir",
+            dir.to_str().unwrap(),
+            "--db-path",
+            db_path.to_str().unwrap(),
+        ]);
+        // Spends an output of a block before the start height
+        let coinbase = new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[5000001000]);
+        let spend = new_tx(&[([7u8; 32], 0)], &[1000]);
+        let mut block = new_block(vec![coinbase, spend]);
+        let spent = SpentOutput {
+            height: 3,
+            is_coinbase: false,
+            value: 2000,
+            script_pubkey: utils::hex_to_vec(SCRIPT_A),
+        };
+        undo::attach_prevouts(&mut block, vec![vec![spent]], AddressFormat::default()).unwrap();
+
+        let mut feestats = FeeStats::new(&matches).unwrap();
+        feestats.set_prevouts_attached();
+        feestats.on_start(&CoinType::from(Bitcoin), 5).unwrap();
+        assert!(!db_path.exists());
+        feestats.on_block(&block, 5).unwrap();
+        feestats.on_complete(5).unwrap();
+        assert_eq!(1, feestats.tx_count);
+        assert_eq!(0, feestats.excluded_count);
+        assert_eq!(1000, feestats.total_fees);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,699 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use rust_base58::FromBase58;
+
+use crate::blockchain::parser::types::CoinType;
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::script::AddressFormat;
+use crate::blockchain::proto::tx::{EvaluatedTx, TxOutpoint};
+use crate::blockchain::proto::Hashed;
+use crate::callbacks::Callback;
+use crate::common::bech32;
+use crate::common::bloom::BloomFilter;
+use crate::common::cashaddr;
+use crate::common::outputstore::{outpoint_key, Key};
+use crate::common::utils;
+use crate::errors::{OpError, OpResult};
+
+/// Destination of an output. Base58 and bech32 addresses of the same key hash
+/// (P2PKH and P2WPKH) map to the same key.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum WatchKey {
+    /// P2PK, P2PKH and P2WPKH
+    KeyHash([u8; 20]),
+    /// P2SH
+    ScriptHash([u8; 20]),
+    /// P2WSH, P2TR and future witness versions
+    WitnessProgram(u8, Vec<u8>),
+}
+
+impl WatchKey {
+    /// Hash or witness program, used as the key of the bloom filter
+    #[inline]
+    pub fn payload(&self) -> &[u8] {
+        match self {
+            WatchKey::KeyHash(hash) | WatchKey::ScriptHash(hash) => hash,
+            WatchKey::WitnessProgram(_, program) => program,
+        }
+    }
+
+    /// Resolves the address for a coin, the version byte of base58 addresses must match
+    /// the coin's P2PKH or P2SH version. CashAddr addresses are accepted
+    /// for coins with a CashAddr prefix.
+    fn from_address(address: &str, format: &AddressFormat) -> OpResult<WatchKey> {
+        let (version_id, p2sh_version) = (format.version_id, format.p2sh_version);
+        if let Ok(decoded) = address.from_base58() {
+            let checksum = &utils::sha256(&utils::sha256(&decoded[..decoded.len().min(21)]))[..4];
+            if decoded.len() == 25 && checksum == &decoded[21..] {
+                let mut hash = [0u8; 20];
+                hash.copy_from_slice(&decoded[1..21]);
+                return match decoded[0] {
+                    v if v == version_id => Ok(WatchKey::KeyHash(hash)),
+                    v if v == p2sh_version => Ok(WatchKey::ScriptHash(hash)),
+                    v => Err(OpError::from(format!(
+                        "Address {} has version {}, expected {} or {}.",
+                        address, v, version_id, p2sh_version
+                    ))),
+                };
+            }
+        }
+        if let Some(prefix) = format.cashaddr_prefix {
+            if let Ok(decoded) = cashaddr::decode(address, prefix) {
+                let mut hash = [0u8; 20];
+                return match (decoded.kind, decoded.hash.len()) {
+                    _ if decoded.prefix != prefix => Err(OpError::from(format!(
+                        "Address {} has prefix {}, expected {}.",
+                        address, decoded.prefix, prefix
+                    ))),
+                    (cashaddr::P2PKH, 20) => {
+                        hash.copy_from_slice(&decoded.hash);
+                        Ok(WatchKey::KeyHash(hash))
+                    }
+                    (cashaddr::P2SH, 20) => {
+                        hash.copy_from_slice(&decoded.hash);
+                        Ok(WatchKey::ScriptHash(hash))
+                    }
+                    _ => Err(OpError::from(format!(
+                        "Address {} is no P2PKH or P2SH address.",
+                        address
+                    ))),
+                };
+            }
+        }
+        match bech32::decode_segwit(address) {
+            Ok(segwit) if segwit.version == 0 && segwit.program.len() == 20 => {
+                let mut hash = [0u8; 20];
+                hash.copy_from_slice(&segwit.program);
+                Ok(WatchKey::KeyHash(hash))
+            }
+            Ok(segwit) => Ok(WatchKey::WitnessProgram(segwit.version, segwit.program)),
+            Err(e) => Err(OpError::from(format!(
+                "Invalid address {}, neither base58check nor bech32 ({}).",
+                address, e
+            ))),
+        }
+    }
+
+    pub fn from_script(script: &[u8]) -> Option<WatchKey> {
+        let hash = |data: &[u8]| {
+            let mut hash = [0u8; 20];
+            hash.copy_from_slice(data);
+            hash
+        };
+        match script {
+            [0x76, 0xa9, 0x14, h @ .., 0x88, 0xac] if h.len() == 20 => {
+                Some(WatchKey::KeyHash(hash(h)))
+            }
+            [0xa9, 0x14, h @ .., 0x87] if h.len() == 20 => Some(WatchKey::ScriptHash(hash(h))),
+            [len, pub_key @ .., 0xac]
+                if (*len == 33 || *len == 65) && pub_key.len() == *len as usize =>
+            {
+                Some(WatchKey::KeyHash(utils::ridemp160(&utils::sha256(pub_key))))
+            }
+            [0x00, 0x14, h @ ..] if h.len() == 20 => Some(WatchKey::KeyHash(hash(h))),
+            // OP_0 and OP_1 to OP_16 followed by a single push of 2 to 40 bytes
+            [version, len, program @ ..]
+                if (*version == 0 || (0x51..=0x60).contains(version))
+                    && (2..=40).contains(len)
+                    && program.len() == *len as usize =>
+            {
+                let version = if *version == 0 { 0 } else { version - 0x50 };
+                Some(WatchKey::WitnessProgram(version, program.to_vec()))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Received and sent totals of a watched destination
+#[derive(Default)]
+pub(crate) struct Activity {
+    pub received: u64,
+    pub sent: u64,
+    pub txs: u64,
+}
+
+/// Unspent output of a watched destination
+pub(crate) struct WatchedOutput {
+    /// Index of the destination
+    pub index: usize,
+    pub value: u64,
+    pub height: u64,
+}
+
+/// Matches transactions against a set of destinations and dumps the matching ones
+/// into `transactions.csv`, `tx_in.csv` and `tx_out.csv` in the csvdump format.
+pub(crate) struct TxFilter {
+    dir: PathBuf,
+    keys: HashMap<WatchKey, usize>,
+    /// Prefilter of `keys`, most outputs don't pay to a watched destination
+    bloom: BloomFilter,
+    bloom_capacity: usize,
+    pub activity: Vec<Activity>,
+    pub outputs: HashMap<Key, WatchedOutput>,
+    tx_writer: Option<BufWriter<File>>,
+    txin_writer: Option<BufWriter<File>>,
+    txout_writer: Option<BufWriter<File>>,
+
+    pub tx_count: u64,
+    pub in_count: u64,
+    pub out_count: u64,
+}
+
+impl TxFilter {
+    const FILES: [&'static str; 3] = ["transactions", "tx_in", "tx_out"];
+    const BLOOM_CAPACITY: usize = 1024;
+    const BLOOM_FP_RATE: f64 = 0.001;
+
+    /// Creates the folder if necessary and the temporary csv files
+    pub fn create(dir: &Path) -> OpResult<TxFilter> {
+        fs::create_dir_all(dir)?;
+        let create_writer = |name: &str| -> OpResult<BufWriter<File>> {
+            let path = dir.join(format!("{}.csv.tmp", name));
+            Ok(BufWriter::with_capacity(4000000, File::create(path)?))
+        };
+        Ok(TxFilter {
+            dir: dir.to_path_buf(),
+            keys: HashMap::new(),
+            bloom: BloomFilter::with_rate(TxFilter::BLOOM_CAPACITY, TxFilter::BLOOM_FP_RATE),
+            bloom_capacity: TxFilter::BLOOM_CAPACITY,
+            activity: Vec::new(),
+            outputs: HashMap::new(),
+            tx_writer: Some(create_writer(TxFilter::FILES[0])?),
+            txin_writer: Some(create_writer(TxFilter::FILES[1])?),
+            txout_writer: Some(create_writer(TxFilter::FILES[2])?),
+            tx_count: 0,
+            in_count: 0,
+            out_count: 0,
+        })
+    }
+
+    /// Returns the index of the destination, it's added if it isn't watched yet
+    pub fn watch(&mut self, key: WatchKey) -> usize {
+        if let Some(index) = self.keys.get(&key) {
+            return *index;
+        }
+        let index = self.activity.len();
+        self.bloom.insert(key.payload());
+        self.keys.insert(key, index);
+        self.activity.push(Activity::default());
+        if self.keys.len() > self.bloom_capacity {
+            // Rebuild with twice the capacity to keep the false positive rate
+            self.bloom_capacity *= 2;
+            self.bloom = BloomFilter::with_rate(self.bloom_capacity, TxFilter::BLOOM_FP_RATE);
+            for key in self.keys.keys() {
+                self.bloom.insert(key.payload());
+            }
+        }
+        index
+    }
+
+    /// Returns the index of a watched destination
+    pub fn index_of(&self, key: &WatchKey) -> Option<usize> {
+        if !self.bloom.contains(key.payload()) {
+            return None;
+        }
+        self.keys.get(key).copied()
+    }
+
+    /// Dumps the transaction if it pays to or spends from a watched destination
+    pub fn filter_tx(
+        &mut self,
+        tx: &Hashed<EvaluatedTx>,
+        block_hash: &str,
+        height: u64,
+    ) -> OpResult<()> {
+        let mut touched = Vec::new();
+        for input in &tx.value.inputs {
+            if let Some(output) = self.outputs.remove(&outpoint_key(&input.outpoint)) {
+                self.activity[output.index].sent += output.value;
+                touched.push(output.index);
+            }
+        }
+        for (i, output) in tx.value.outputs.iter().enumerate() {
+            let index = match WatchKey::from_script(&output.out.script_pubkey)
+                .and_then(|key| self.index_of(&key))
+            {
+                Some(index) => index,
+                None => continue,
+            };
+            self.activity[index].received += output.out.value;
+            let outpoint = TxOutpoint::new(tx.hash, i as u32);
+            self.outputs.insert(
+                outpoint_key(&outpoint),
+                WatchedOutput {
+                    index,
+                    value: output.out.value,
+                    height,
+                },
+            );
+            touched.push(index);
+        }
+        if touched.is_empty() {
+            return Ok(());
+        }
+        touched.sort_unstable();
+        touched.dedup();
+        for index in touched {
+            self.activity[index].txs += 1;
+        }
+
+        let txid_str = utils::arr_to_hex_swapped(&tx.hash);
+        self.tx_writer
+            .as_mut()
+            .unwrap()
+            .write_all(tx.as_csv(block_hash, ';').as_bytes())?;
+        let txin_writer = self.txin_writer.as_mut().unwrap();
+        for input in &tx.value.inputs {
+            txin_writer.write_all(input.as_csv(&txid_str, ';').as_bytes())?;
+        }
+        let txout_writer = self.txout_writer.as_mut().unwrap();
+        for (i, output) in tx.value.outputs.iter().enumerate() {
+            txout_writer.write_all(output.as_csv(&txid_str, i as u32, ';').as_bytes())?;
+        }
+        self.tx_count += 1;
+        self.in_count += tx.value.in_count.value;
+        self.out_count += tx.value.out_count.value;
+        Ok(())
+    }
+
+    /// Flushes and renames the csv files
+    pub fn finish(&mut self) -> OpResult<()> {
+        let writers = [
+            self.tx_writer.take(),
+            self.txin_writer.take(),
+            self.txout_writer.take(),
+        ];
+        for (name, writer) in TxFilter::FILES.iter().zip(writers) {
+            let mut writer = match writer {
+                Some(writer) => writer,
+                None => continue,
+            };
+            writer.flush()?;
+            drop(writer);
+            fs::rename(
+                self.dir.join(format!("{}.csv.tmp", name)),
+                self.dir.join(format!("{}.csv", name)),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Dumps all transactions paying to or spending from a list of addresses,
+/// in the same format as csvdump.
+pub struct Filter {
+    output_dir: PathBuf,
+    // Resolved in on_start() once the coin is known
+    addresses: Vec<String>,
+    // Address of each destination in `tx_filter`
+    watched: Vec<String>,
+    tx_filter: Option<TxFilter>,
+
+    start_height: u64,
+}
+
+impl Filter {
+    /// Reads one address per line, everything after `#` is a comment
+    fn load_watchlist(path: &Path) -> OpResult<Vec<String>> {
+        let reader = BufReader::new(File::open(path)?);
+        let mut addresses = Vec::new();
+        for line in reader.lines() {
+            let line = line?;
+            let address = line.split('#').next().unwrap_or_default().trim();
+            if !address.is_empty() {
+                addresses.push(String::from(address));
+            }
+        }
+        Ok(addresses)
+    }
+}
+
+impl Callback for Filter {
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b>
+    where
+        Self: Sized,
+    {
+        SubCommand::with_name("filter")
+            .about("Dumps all transactions touching a list of addresses into CSV files")
+            .version("0.1")
+            .arg(
+                Arg::with_name("output-dir")
+                    .long("output-dir")
+                    .value_name("PATH")
+                    .help("Folder to store csv files, created if necessary")
+                    .takes_value(true)
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name("addresses")
+                    .long("addresses")
+                    .value_name("FILE")
+                    .help("File with one address per line, `#` starts a comment")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("address")
+                    .long("address")
+                    .value_name("ADDRESSES")
+                    .help("Comma separated list of addresses (repeatable)")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let mut addresses = match matches.value_of("addresses") {
+            Some(path) => Filter::load_watchlist(Path::new(path))
+                .map_err(|e| e.join_msg(&format!("Unable to read --addresses {}.", path)))?,
+            None => Vec::new(),
+        };
+        for list in matches.values_of("address").into_iter().flatten() {
+            addresses.extend(
+                list.split(',')
+                    .map(str::trim)
+                    .filter(|a| !a.is_empty())
+                    .map(String::from),
+            );
+        }
+        if addresses.is_empty() {
+            return Err(OpError::from(String::from(
+                "No addresses to watch, use --addresses or --address.",
+            )));
+        }
+
+        let cb = Filter {
+            output_dir: PathBuf::from(matches.value_of("output-dir").unwrap()),
+            addresses,
+            watched: Vec::new(),
+            tx_filter: None,
+            start_height: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, coin_type: &CoinType, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        let mut tx_filter = TxFilter::create(&self.output_dir)?;
+        for address in self.addresses.drain(..) {
+            let index = tx_filter.watch(WatchKey::from_address(
+                &address,
+                &coin_type.address_format(),
+            )?);
+            if index < self.watched.len() {
+                if self.watched[index] != address {
+                    warn!(target: "callback", "{} has the same hash as {}, reporting both as {}.",
+                          address, self.watched[index], self.watched[index]);
+                }
+                continue;
+            }
+            self.watched.push(address);
+        }
+        self.tx_filter = Some(tx_filter);
+        info!(target: "callback", "Using `filter` with {} addresses and output dir: {} ...",
+              self.watched.len(), &self.output_dir.display());
+        Ok(())
+    }
+
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<()> {
+        let block_hash = utils::arr_to_hex_swapped(&block.header.hash);
+        let tx_filter = self.tx_filter.as_mut().unwrap();
+        for tx in &block.txs {
+            tx_filter.filter_tx(tx, &block_hash, block_height)?;
+        }
+        Ok(())
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        let tx_filter = self.tx_filter.as_mut().unwrap();
+        tx_filter.finish()?;
+
+        let mut totals = String::new();
+        let mut n_inactive = 0;
+        for (address, activity) in self.watched.iter().zip(&tx_filter.activity) {
+            if activity.txs == 0 {
+                n_inactive += 1;
+                continue;
+            }
+            totals.push_str(&format!(
+                "\n\t{}: received {} sent {} in {} transactions",
+                address, activity.received, activity.sent, activity.txs
+            ));
+        }
+        info!(target: "callback", "Done.\nDumped blocks {} to {} into {}:\n\
+                                   \t-> transactions: {:9}\n\
+                                   \t-> inputs:       {:9}\n\
+                                   \t-> outputs:      {:9}\n\
+                                   Totals of the watched addresses ({} without transactions):{}",
+              self.start_height, block_height, self.output_dir.display(),
+              tx_filter.tx_count, tx_filter.in_count, tx_filter.out_count, n_inactive, totals);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+    use crate::blockchain::parser::types::{Bitcoin, BitcoinCash};
+    use crate::callbacks::fixtures::{new_chain, new_tx, new_tx_to, SCRIPT_A, SCRIPT_B};
+
+    // P2WPKH with the same key hash as SCRIPT_A
+    const SCRIPT_A_P2WPKH: &str = "001412ab8dc588ca9d5787dde7eb29569da63c3a238c";
+
+    fn temp_dir(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("blockparser-filter-{}-{}", name, process::id()))
+    }
+
+    fn run(args: &[&str], chain: &[Block]) -> (Filter, PathBuf) {
+        let dir = temp_dir(args[1].split(',').next().unwrap());
+        let mut full_args = vec!["filter", "--output-dir", dir.to_str().unwrap()];
+        full_args.extend_from_slice(args);
+        let matches = Filter::build_subcommand().get_matches_from(full_args);
+        let mut filter = Filter::new(&matches).unwrap();
+        filter.on_start(&CoinType::from(Bitcoin), 0).unwrap();
+        for (height, block) in chain.iter().enumerate() {
+            filter.on_block(block, height as u64).unwrap();
+        }
+        filter.on_complete(chain.len() as u64 - 1).unwrap();
+        (filter, dir)
+    }
+
+    fn read_rows(dir: &Path, name: &str) -> Vec<String> {
+        let content = fs::read_to_string(dir.join(format!("{}.csv", name))).unwrap();
+        content.lines().map(String::from).collect()
+    }
+
+    #[test]
+    fn test_watch_key() {
+        let format = AddressFormat::default();
+        let a = WatchKey::from_address("12higDjoCCNXSA95xZMWUdPvXNmkAduhWv", &format).unwrap();
+        assert_eq!(
+            Some(a.clone()),
+            WatchKey::from_script(&utils::hex_to_vec(SCRIPT_A_P2WPKH))
+        );
+        assert_eq!(
+            a,
+            WatchKey::from_address("bc1qz24cm3vge2w40p7aul4jj45a5c7r5guvgyzrn3", &format).unwrap()
+        );
+        assert_eq!(
+            Some(WatchKey::ScriptHash([0x12; 20])),
+            WatchKey::from_script(&utils::hex_to_vec(
+                "a914121212121212121212121212121212121212121287"
+            ))
+        );
+        let taproot = "bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqzk5jj0";
+        assert_eq!(
+            WatchKey::from_address(taproot, &format).unwrap(),
+            WatchKey::from_script(&utils::hex_to_vec(
+                "512079be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798"
+            ))
+            .unwrap()
+        );
+        // Litecoin address on Bitcoin, broken checksum and garbage
+        assert!(WatchKey::from_address("LVuDpNCSSj6pQ7t9Pv6d6sUkLKoqDEVUnJ", &format).is_err());
+        assert!(WatchKey::from_address("12higDjoCCNXSA95xZMWUdPvXNmkAduhWw", &format).is_err());
+        assert!(WatchKey::from_address("not an address", &format).is_err());
+
+        // CashAddr with and without prefix, only on Bitcoin Cash
+        let format = CoinType::from(BitcoinCash).address_format();
+        let cash_address = "bitcoincash:qqf2hrw93r9f64u8mhn7k22knknrcw3r3s0mkt0zxa";
+        assert_eq!(a, WatchKey::from_address(cash_address, &format).unwrap());
+        assert_eq!(
+            a,
+            WatchKey::from_address(&cash_address[12..], &format).unwrap()
+        );
+        assert_eq!(
+            a,
+            WatchKey::from_address("12higDjoCCNXSA95xZMWUdPvXNmkAduhWv", &format).unwrap()
+        );
+        assert!(WatchKey::from_address(cash_address, &AddressFormat::default()).is_err());
+    }
+
+    #[test]
+    fn test_watch_bloom() {
+        let dir = temp_dir("bloom");
+        let mut tx_filter = TxFilter::create(&dir).unwrap();
+        let hash = |n: u32| {
+            let mut hash = [0u8; 20];
+            hash[..4].copy_from_slice(&n.to_le_bytes());
+            hash
+        };
+        let key = |n: u32| WatchKey::KeyHash(hash(n));
+        for n in 0..3000 {
+            assert_eq!(n as usize, tx_filter.watch(key(n)));
+        }
+        assert_eq!(0, tx_filter.watch(key(0)));
+        // Grown twice, all watched keys survive the rebuilds
+        assert_eq!(4096, tx_filter.bloom_capacity);
+        for n in 0..3000 {
+            assert_eq!(Some(n as usize), tx_filter.index_of(&key(n)));
+        }
+        // Same payload of another kind passes the bloom but not the exact set
+        assert_eq!(None, tx_filter.index_of(&WatchKey::ScriptHash(hash(1))));
+        let false_positives = (3000..103000)
+            .filter(|&n| tx_filter.bloom.contains(key(n).payload()))
+            .count();
+        assert!(false_positives < 300, "{} false positives", false_positives);
+        assert!((3000..103000).all(|n| tx_filter.index_of(&key(n)).is_none()));
+        tx_filter.finish().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_filter() {
+        let coinbase = new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[5000000000]);
+        let to_b = new_tx_to(
+            &[(coinbase.hash, 0)],
+            &[(4000000000, SCRIPT_B), (999990000, SCRIPT_A_P2WPKH)],
+        );
+        let unrelated = new_tx_to(&[([0x10; 32], 0)], &[(1000, SCRIPT_B)]);
+        let from_segwit = new_tx_to(&[(to_b.hash, 1)], &[(999980000, SCRIPT_B)]);
+        let txids: Vec<String> = [&coinbase, &to_b, &from_segwit]
+            .iter()
+            .map(|tx| utils::arr_to_hex_swapped(&tx.hash))
+            .collect();
+        let chain = new_chain(vec![
+            vec![coinbase],
+            vec![to_b, unrelated],
+            vec![from_segwit],
+        ]);
+
+        // Both forms of the same key hash are reported as the first one
+        let (filter, dir) = run(
+            &[
+                "--address",
+                "12higDjoCCNXSA95xZMWUdPvXNmkAduhWv,bc1qz24cm3vge2w40p7aul4jj45a5c7r5guvgyzrn3",
+            ],
+            &chain,
+        );
+        let txs = read_rows(&dir, "transactions");
+        let tx_in = read_rows(&dir, "tx_in");
+        let tx_out = read_rows(&dir, "tx_out");
+        fs::remove_dir_all(&dir).unwrap();
+        let tx_filter = filter.tx_filter.unwrap();
+        assert_eq!(1, filter.watched.len());
+        assert_eq!(5000000000 + 999990000, tx_filter.activity[0].received);
+        assert_eq!(5000000000 + 999990000, tx_filter.activity[0].sent);
+        assert_eq!(3, tx_filter.activity[0].txs);
+        assert!(tx_filter.outputs.is_empty());
+
+        assert_eq!(
+            txids,
+            txs.iter()
+                .map(|row| String::from(row.split(';').next().unwrap()))
+                .collect::<Vec<String>>()
+        );
+        assert_eq!(3, tx_in.len());
+        assert_eq!(
+            vec![
+                format!(
+                    "{};0;5000000000;{};12higDjoCCNXSA95xZMWUdPvXNmkAduhWv",
+                    txids[0], SCRIPT_A
+                ),
+                format!(
+                    "{};0;4000000000;{};1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa",
+                    txids[1], SCRIPT_B
+                ),
+                format!(
+                    "{};1;999990000;{};bc1qz24cm3vge2w40p7aul4jj45a5c7r5guvgyzrn3",
+                    txids[1], SCRIPT_A_P2WPKH
+                ),
+                format!(
+                    "{};0;999980000;{};1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa",
+                    txids[2], SCRIPT_B
+                ),
+            ],
+            tx_out
+        );
+
+        // SCRIPT_B only receives
+        let (filter, dir) = run(&["--address", "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa"], &chain);
+        assert_eq!(3, read_rows(&dir, "transactions").len());
+        fs::remove_dir_all(&dir).unwrap();
+        let tx_filter = filter.tx_filter.unwrap();
+        assert_eq!(
+            4000000000 + 1000 + 999980000,
+            tx_filter.activity[0].received
+        );
+        assert_eq!(0, tx_filter.activity[0].sent);
+        assert_eq!(3, tx_filter.outputs.len());
+    }
+
+    #[test]
+    fn test_watchlist() {
+        let dir = temp_dir("watchlist");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("addresses.txt");
+        fs::write(
+            &path,
+            "# exchange wallets\n\n  12higDjoCCNXSA95xZMWUdPvXNmkAduhWv  \r\n\
+             1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa # genesis\n   \n#\n",
+        )
+        .unwrap();
+        assert_eq!(
+            vec![
+                "12higDjoCCNXSA95xZMWUdPvXNmkAduhWv",
+                "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa"
+            ],
+            Filter::load_watchlist(&path).unwrap()
+        );
+
+        let out = dir.join("out");
+        let matches = Filter::build_subcommand().get_matches_from(vec![
+            "filter",
+            "--output-dir",
+            out.to_str().unwrap(),
+            "--addresses",
+            path.to_str().unwrap(),
+            "--address",
+            "bc1qz24cm3vge2w40p7aul4jj45a5c7r5guvgyzrn3",
+        ]);
+        let mut filter = Filter::new(&matches).unwrap();
+        filter.on_start(&CoinType::from(Bitcoin), 0).unwrap();
+        assert_eq!(2, filter.watched.len());
+        fs::remove_dir_all(&dir).unwrap();
+
+        let invalid: [&[&str]; 3] = [
+            &[],
+            &["--address", " , "],
+            &["--addresses", "/nonexistent/addresses.txt"],
+        ];
+        for args in invalid.iter() {
+            let mut full_args = vec!["filter", "--output-dir", "/tmp"];
+            full_args.extend_from_slice(args);
+            let matches = Filter::build_subcommand().get_matches_from(full_args);
+            assert!(Filter::new(&matches).is_err());
+        }
+    }
+}
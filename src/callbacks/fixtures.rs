@@ -0,0 +1,854 @@
+//! Blocks and transactions for callback tests
+
+use std::collections::VecDeque;
+
+use crate::blockchain::parser::types::{Bitcoin, Coin, CoinType, Regtest};
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::header::BlockHeader;
+use crate::blockchain::proto::target::{check_pow, U256};
+use crate::blockchain::proto::tx::{EvaluatedTx, RawTx, TxInput, TxOutpoint, TxOutput, Witness};
+use crate::blockchain::proto::varuint::VarUint;
+use crate::blockchain::proto::{Hashed, ToRaw};
+use crate::common::bip32::ExtendedPubKey;
+use crate::common::secp256k1::PublicKey;
+use crate::common::utils;
+
+/// P2PKH script of 12higDjoCCNXSA95xZMWUdPvXNmkAduhWv
+pub const SCRIPT_A: &str = "76a91412ab8dc588ca9d5787dde7eb29569da63c3a238c88ac";
+/// P2PKH script of 1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa
+pub const SCRIPT_B: &str = "76a91462e907b15cbf27d5425399ebf6f0fb50ebb88f1888ac";
+
+/// Builds a transaction spending the given outpoints, all outputs pay to the same P2PKH address
+pub fn new_tx(inputs: &[([u8; 32], u32)], outputs: &[u64]) -> Hashed<EvaluatedTx> {
+    let outputs: Vec<(u64, &str)> = outputs.iter().map(|value| (*value, SCRIPT_A)).collect();
+    new_tx_to(inputs, &outputs)
+}
+
+/// Builds a transaction spending the given outpoints with outputs of (value, hex script)
+pub fn new_tx_to(inputs: &[([u8; 32], u32)], outputs: &[(u64, &str)]) -> Hashed<EvaluatedTx> {
+    let raw = RawTx {
+        version: 1,
+        in_count: VarUint::compact(inputs.len() as u64),
+        inputs: inputs
+            .iter()
+            .map(|(txid, index)| TxInput {
+                outpoint: TxOutpoint::new(*txid, *index),
+                script_len: VarUint::from(0u8),
+                script_sig: Vec::new(),
+                seq_no: 0xFFFFFFFF,
+                revealed_script: None,
+                prevout: None,
+            })
+            .collect(),
+        out_count: VarUint::compact(outputs.len() as u64),
+        outputs: outputs
+            .iter()
+            .map(|(value, script)| {
+                let script_pubkey = utils::hex_to_vec(script);
+                TxOutput {
+                    value: *value,
+                    script_len: VarUint::from(script_pubkey.len() as u8),
+                    script_pubkey,
+                }
+            })
+            .collect(),
+        witnesses: Vec::new(),
+        locktime: 0,
+        address_format: CoinType::from(Bitcoin).address_format(),
+        hogex: false,
+        zcash: None,
+        size: 0,
+        stripped_size: 0,
+    };
+    let mut tx = EvaluatedTx::from(raw);
+    // Without witnesses both sizes are the length of the serialization
+    tx.stripped_size = tx.to_bytes().len() as u32;
+    tx.size = tx.stripped_size;
+    Hashed::double_sha256(tx)
+}
+
+/// Sets the witnesses of a transaction and updates its size, the txid stays the same
+pub fn set_witnesses(tx: &mut EvaluatedTx, witnesses: Vec<Witness>) {
+    tx.witnesses = witnesses;
+    tx.size = tx.to_witness_bytes().len() as u32;
+}
+
+pub fn new_block(txs: Vec<Hashed<EvaluatedTx>>) -> Block {
+    let header = BlockHeader::new(1, [0u8; 32], [0u8; 32], 1231006505, 0x1d00ffff, 0);
+    Block {
+        size: 0,
+        header: Hashed::double_sha256(header),
+        aux_pow: None,
+        tx_count: VarUint::compact(txs.len() as u64),
+        txs,
+        has_mweb: false,
+        mweb_size: 0,
+        stripped_size: 0,
+        weight: 0,
+    }
+}
+
+/// Builds one block per list of transactions, each linked to its predecessor
+pub fn new_chain(blocks: Vec<Vec<Hashed<EvaluatedTx>>>) -> Vec<Block> {
+    let mut chain: Vec<Block> = Vec::new();
+    for txs in blocks {
+        let mut block = new_block(txs);
+        if let Some(prev) = chain.last() {
+            block.header.value.prev_hash = prev.header.hash;
+            block.header = Hashed::double_sha256(block.header.value.clone());
+        }
+        chain.push(block);
+    }
+    chain
+}
+
+/// Serialized `RegtestChain`, parsed by the integration tests in `tests/integration.rs`
+pub const REGTEST_STREAM: &str = "testdata/regtest.dat";
+/// Height of the tip of `RegtestChain`
+pub const REGTEST_TIP: u64 = 299;
+/// Serialized `bip30_chain()`
+pub const BIP30_STREAM: &str = "testdata/bip30.dat";
+/// Account key (m/44'/0'/0' of `abandon abandon ... about`) with payments to its first six receive
+/// and change addresses
+pub const REGTEST_WALLET: &str = "xpub6BosfCnifzxcFwrSzQiqu2DBVTshkCXacvNsWGYJVVhhawA7d4R5WSWGFNbi8Aw6ZRc1brxMyWMzG3DSSSSoekkudhUd9yLb6qx39T9nMdj";
+/// Account key of all other addresses
+const REGTEST_KEYS: &str = "xpub6BgBgsespWvERF3LHQu6CnqdvfEvtMcQjYrcRzx53QJjSxarj2afYWcLteoGVky7D3UKDP9QyrLprQ3VCECoY49yfdDEHGCtMMj92pReUsQ";
+/// Network magic in front of each block of `RegtestChain::to_stream`
+pub const REGTEST_MAGIC: u32 = 0xdab5bffa;
+const REGTEST_BITS: u32 = 0x207fffff;
+const REGTEST_GENESIS_TIME: u32 = 1296688602;
+/// Keys are reused, so addresses receive several times
+const N_KEYS: u32 = 64;
+const N_WALLET_KEYS: u32 = 6;
+const COINBASE_MATURITY: u64 = 100;
+const DUST_VALUE: u64 = 300;
+
+/// How an output of the regtest chain is locked, with the index of its key
+#[derive(Clone, Copy, Debug)]
+enum Lock {
+    P2pk(u32),
+    P2pkh(u32),
+    P2wpkh(u32),
+    P2shP2wpkh(u32),
+    /// 2-of-3
+    P2shMultisig(u32),
+    /// 2-of-3
+    P2wshMultisig(u32),
+    /// 2-of-3 for key indexes divisible by three, 1-of-2 otherwise
+    BareMultisig(u32),
+    P2tr(u32),
+    /// P2PKH of a receive (0) or change (1) address of `REGTEST_WALLET`
+    Wallet(u32, u32),
+    /// OP_TRUE, spent with an empty script
+    AnyoneCanSpend,
+}
+
+/// Output of the regtest chain which is spent later on
+struct Utxo {
+    outpoint: ([u8; 32], u32),
+    value: u64,
+    lock: Lock,
+    height: u64,
+}
+
+fn push(script: &mut Vec<u8>, data: &[u8]) {
+    if data.len() >= 0x4c {
+        script.push(0x4c);
+    }
+    script.push(data.len() as u8);
+    script.extend_from_slice(data);
+}
+
+fn hash160(data: &[u8]) -> [u8; 20] {
+    utils::ridemp160(&utils::sha256(data))
+}
+
+/// DER encoded signature with SIGHASH_ALL, made up from `n`
+fn signature(n: u64) -> Vec<u8> {
+    let (mut r, mut s) = (
+        utils::sha256(&n.to_le_bytes()),
+        utils::sha256(&n.to_be_bytes()),
+    );
+    // Positive and without leading zeros
+    r[0] = (r[0] & 0x7f) | 0x01;
+    s[0] = (s[0] & 0x3f) | 0x01;
+    let mut sig = vec![0x30, 0x44, 0x02, 0x20];
+    sig.extend_from_slice(&r);
+    sig.extend_from_slice(&[0x02, 0x20]);
+    sig.extend_from_slice(&s);
+    sig.push(0x01);
+    sig
+}
+
+fn multisig(m: u8, keys: &[&[u8]]) -> Vec<u8> {
+    let mut script = vec![0x50 + m];
+    for key in keys {
+        push(&mut script, key);
+    }
+    script.extend_from_slice(&[0x50 + keys.len() as u8, 0xae]);
+    script
+}
+
+/// Public keys and taproot output keys of the regtest chain
+struct RegtestKeys {
+    keys: Vec<[u8; 33]>,
+    taproot: Vec<[u8; 32]>,
+    wallet: [Vec<[u8; 33]>; 2],
+}
+
+impl RegtestKeys {
+    fn new() -> Self {
+        let derive = |xpub: &str, chain: u32, n: u32| -> Vec<[u8; 33]> {
+            let key = ExtendedPubKey::from_base58(xpub)
+                .and_then(|key| key.derive_child(chain))
+                .unwrap();
+            (0..n)
+                .map(|i| key.derive_child(i).unwrap().public_key.serialize())
+                .collect()
+        };
+        let keys = derive(REGTEST_KEYS, 0, N_KEYS);
+        let taproot = keys
+            .iter()
+            .map(|key| {
+                PublicKey::from_slice(key)
+                    .and_then(|key| key.taproot_output_key())
+                    .unwrap()
+            })
+            .collect();
+        RegtestKeys {
+            keys,
+            taproot,
+            wallet: [
+                derive(REGTEST_WALLET, 0, N_WALLET_KEYS),
+                derive(REGTEST_WALLET, 1, N_WALLET_KEYS),
+            ],
+        }
+    }
+
+    fn key(&self, i: u32) -> &[u8] {
+        &self.keys[(i % N_KEYS) as usize]
+    }
+
+    fn redeem_script(&self, lock: Lock) -> Vec<u8> {
+        match lock {
+            Lock::P2shP2wpkh(i) => [&[0x00, 0x14][..], &hash160(self.key(i))].concat(),
+            Lock::P2shMultisig(i) | Lock::P2wshMultisig(i) => {
+                multisig(2, &[self.key(i), self.key(i + 1), self.key(i + 2)])
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn script_pubkey(&self, lock: Lock) -> Vec<u8> {
+        let mut script = Vec::new();
+        match lock {
+            Lock::P2pk(i) => {
+                push(&mut script, self.key(i));
+                script.push(0xac);
+            }
+            Lock::P2pkh(i) => {
+                script.extend_from_slice(&[0x76, 0xa9, 0x14]);
+                script.extend_from_slice(&hash160(self.key(i)));
+                script.extend_from_slice(&[0x88, 0xac]);
+            }
+            Lock::Wallet(chain, i) => {
+                let key = &self.wallet[chain as usize][(i % N_WALLET_KEYS) as usize];
+                script.extend_from_slice(&[0x76, 0xa9, 0x14]);
+                script.extend_from_slice(&hash160(key));
+                script.extend_from_slice(&[0x88, 0xac]);
+            }
+            Lock::P2wpkh(i) => {
+                script.extend_from_slice(&[0x00, 0x14]);
+                script.extend_from_slice(&hash160(self.key(i)));
+            }
+            Lock::P2shP2wpkh(_) | Lock::P2shMultisig(_) => {
+                script.extend_from_slice(&[0xa9, 0x14]);
+                script.extend_from_slice(&hash160(&self.redeem_script(lock)));
+                script.push(0x87);
+            }
+            Lock::P2wshMultisig(_) => {
+                script.extend_from_slice(&[0x00, 0x20]);
+                script.extend_from_slice(&utils::sha256(&self.redeem_script(lock)));
+            }
+            Lock::BareMultisig(i) if i % 3 == 0 => {
+                script = multisig(2, &[self.key(i), self.key(i + 1), self.key(i + 2)])
+            }
+            Lock::BareMultisig(i) => script = multisig(1, &[self.key(i), self.key(i + 1)]),
+            Lock::P2tr(i) => {
+                script.extend_from_slice(&[0x51, 0x20]);
+                script.extend_from_slice(&self.taproot[(i % N_KEYS) as usize]);
+            }
+            Lock::AnyoneCanSpend => script.push(0x51),
+        }
+        script
+    }
+
+    /// Script sig and witness spending an output, the signatures are made up from `n`
+    fn unlock(&self, lock: Lock, n: u64) -> (Vec<u8>, Witness) {
+        let mut script_sig = Vec::new();
+        let witness = match lock {
+            Lock::P2pk(_) => {
+                push(&mut script_sig, &signature(n));
+                Vec::new()
+            }
+            Lock::P2pkh(i) => {
+                push(&mut script_sig, &signature(n));
+                push(&mut script_sig, self.key(i));
+                Vec::new()
+            }
+            Lock::Wallet(chain, i) => {
+                push(&mut script_sig, &signature(n));
+                push(
+                    &mut script_sig,
+                    &self.wallet[chain as usize][(i % N_WALLET_KEYS) as usize],
+                );
+                Vec::new()
+            }
+            Lock::P2wpkh(i) => vec![signature(n), self.key(i).to_vec()],
+            Lock::P2shP2wpkh(i) => {
+                push(&mut script_sig, &self.redeem_script(lock));
+                vec![signature(n), self.key(i).to_vec()]
+            }
+            Lock::P2shMultisig(_) => {
+                script_sig.push(0x00);
+                push(&mut script_sig, &signature(n));
+                push(&mut script_sig, &signature(n + 1));
+                push(&mut script_sig, &self.redeem_script(lock));
+                Vec::new()
+            }
+            Lock::P2wshMultisig(_) => vec![
+                Vec::new(),
+                signature(n),
+                signature(n + 1),
+                self.redeem_script(lock),
+            ],
+            Lock::BareMultisig(i) => {
+                script_sig.push(0x00);
+                push(&mut script_sig, &signature(n));
+                if i % 3 == 0 {
+                    push(&mut script_sig, &signature(n + 1));
+                }
+                Vec::new()
+            }
+            Lock::P2tr(_) => {
+                let sig = utils::sha256(&n.to_le_bytes());
+                vec![[sig, utils::sha256(&sig)].concat()]
+            }
+            Lock::AnyoneCanSpend => Vec::new(),
+        };
+        (script_sig, witness)
+    }
+}
+
+/// Input of `regtest_tx`: outpoint, script sig, sequence number and witness
+type RegtestInput = (([u8; 32], u32), Vec<u8>, u32, Witness);
+
+fn regtest_tx(
+    version: u32,
+    inputs: Vec<RegtestInput>,
+    outputs: &[(u64, Vec<u8>)],
+    locktime: u32,
+) -> Hashed<EvaluatedTx> {
+    let has_witness = inputs.iter().any(|(_, _, _, witness)| !witness.is_empty());
+    let mut witnesses = Vec::new();
+    let raw = RawTx {
+        version,
+        in_count: VarUint::compact(inputs.len() as u64),
+        inputs: inputs
+            .into_iter()
+            .map(|((txid, index), script_sig, seq_no, witness)| {
+                witnesses.push(witness);
+                TxInput {
+                    outpoint: TxOutpoint::new(txid, index),
+                    script_len: VarUint::compact(script_sig.len() as u64),
+                    script_sig,
+                    seq_no,
+                    revealed_script: None,
+                    prevout: None,
+                }
+            })
+            .collect(),
+        out_count: VarUint::compact(outputs.len() as u64),
+        outputs: outputs
+            .iter()
+            .map(|(value, script_pubkey)| TxOutput {
+                value: *value,
+                script_len: VarUint::compact(script_pubkey.len() as u64),
+                script_pubkey: script_pubkey.clone(),
+            })
+            .collect(),
+        witnesses: Vec::new(),
+        locktime,
+        address_format: CoinType::from(Regtest).address_format(),
+        hogex: false,
+        zcash: None,
+        size: 0,
+        stripped_size: 0,
+    };
+    let mut tx = EvaluatedTx::from(raw);
+    tx.stripped_size = tx.to_bytes().len() as u32;
+    tx.size = tx.stripped_size;
+    if has_witness {
+        set_witnesses(&mut tx, witnesses);
+    }
+    Hashed::double_sha256(tx)
+}
+
+/// BIP34 height followed by the miner's tag
+fn coinbase_script(height: u64, tag: &str) -> Vec<u8> {
+    let mut script = Vec::new();
+    match height {
+        1..=16 => script.push(0x50 + height as u8),
+        _ => {
+            let mut bytes: Vec<u8> = height.to_le_bytes().to_vec();
+            while bytes.len() > 1 && bytes[bytes.len() - 1] == 0 {
+                bytes.pop();
+            }
+            if bytes[bytes.len() - 1] & 0x80 != 0 {
+                bytes.push(0);
+            }
+            push(&mut script, &bytes);
+        }
+    }
+    push(&mut script, tag.as_bytes());
+    script
+}
+
+/// Subsidy of a regtest block, which halves every 150 blocks
+fn regtest_subsidy(height: u64) -> u64 {
+    Regtest.subsidy().max_subsidy(height).unwrap().0
+}
+
+/// Builds the block on top of `prev` and mines it for the regtest target. The coinbase pays
+/// to `lock` and commits to the witnesses of the other transactions if there are any.
+fn mine_block(
+    keys: &RegtestKeys,
+    prev: &Block,
+    height: u64,
+    tag: &str,
+    lock: Lock,
+    txs: Vec<Hashed<EvaluatedTx>>,
+    fees: u64,
+) -> Block {
+    let mut outputs = vec![(regtest_subsidy(height) + fees, keys.script_pubkey(lock))];
+    let has_witness = txs.iter().any(|tx| tx.value.has_witness());
+    if has_witness {
+        let wtxids: Vec<[u8; 32]> = std::iter::once([0u8; 32])
+            .chain(
+                txs.iter()
+                    .map(|tx| tx.value.witness_hash().unwrap_or(tx.hash)),
+            )
+            .collect();
+        let root = utils::merkle_root(&wtxids);
+        let mut script = vec![0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed];
+        script.extend_from_slice(&utils::sha256d(&[&root[..], &[0u8; 32]].concat()));
+        outputs.push((0, script));
+    }
+    // The reserved value of the commitment
+    let witness = if has_witness {
+        vec![vec![0u8; 32]]
+    } else {
+        Vec::new()
+    };
+    let coinbase = regtest_tx(
+        1,
+        vec![(
+            ([0u8; 32], 0xFFFFFFFF),
+            coinbase_script(height, tag),
+            0xFFFFFFFF,
+            witness,
+        )],
+        &outputs,
+        0,
+    );
+    mine(prev, std::iter::once(coinbase).chain(txs).collect())
+}
+
+/// Builds the block of the given transactions on top of `prev` and mines it
+fn mine(prev: &Block, txs: Vec<Hashed<EvaluatedTx>>) -> Block {
+    let mut block = new_block(txs);
+    let mut header = BlockHeader::new(
+        0x20000000,
+        prev.header.hash,
+        block.compute_merkle_root(),
+        prev.header.value.timestamp + 600,
+        REGTEST_BITS,
+        0,
+    );
+    while check_pow(&utils::sha256d(&header.to_bytes()), REGTEST_BITS).is_err() {
+        header.nonce += 1;
+    }
+    block.header = Hashed::double_sha256(header);
+    block.size = block.to_bytes().len() as u32;
+    block.measure();
+    block
+}
+
+/// The regtest genesis block, which pays to the key of the bitcoin genesis block
+fn regtest_genesis() -> Block {
+    let coinbase = regtest_tx(
+        1,
+        vec![(
+            ([0u8; 32], 0xFFFFFFFF),
+            utils::hex_to_vec("04ffff001d0104455468652054696d65732030332f4a616e2f32303039204368616e63656c6c6f72206f6e206272696e6b206f66207365636f6e64206261696c6f757420666f722062616e6b73"),
+            0xFFFFFFFF,
+            Vec::new(),
+        )],
+        &[(regtest_subsidy(0), utils::hex_to_vec("4104678afdb0fe5548271967f1a67130b7105cd6a828e03909a67962e0ea1f61deb649f6bc3f4cef38c4f35504e51ec112de5c384df7ba0b8d578a4c702b6bf11d5fac"))],
+        0,
+    );
+    let mut block = new_block(vec![coinbase]);
+    let header = BlockHeader::new(
+        1,
+        [0u8; 32],
+        block.compute_merkle_root(),
+        REGTEST_GENESIS_TIME,
+        REGTEST_BITS,
+        2,
+    );
+    block.header = Hashed::double_sha256(header);
+    block.size = block.to_bytes().len() as u32;
+    block.measure();
+    block
+}
+
+/// Deterministic regtest chain of `REGTEST_TIP + 1` blocks for end to end tests. From height
+/// 101 on each block spends a mature coinbase and older outputs into all kinds of scripts,
+/// witness programs, bare multisig, OP_RETURN and dust outputs, with version 2, RBF and locktime
+/// transactions in between. Blocks 150, 200, 201 and the tip have competing forks.
+pub struct RegtestChain {
+    /// Blocks of the best chain by height
+    pub blocks: Vec<Block>,
+    /// Blocks of the forks with their heights, ordered by height
+    pub stale: Vec<(u64, Block)>,
+}
+
+impl RegtestChain {
+    pub fn new() -> Self {
+        let keys = RegtestKeys::new();
+        let mut blocks = vec![regtest_genesis()];
+        let mut stale = Vec::new();
+        let mut utxos: VecDeque<Utxo> = VecDeque::new();
+        let mut coinbases: VecDeque<Utxo> = VecDeque::new();
+        // Counts outputs and signatures, so all of them differ
+        let mut n: u64 = 0;
+        let coinbase_locks = [Lock::P2pkh, Lock::P2wpkh, Lock::P2pk, Lock::P2tr];
+        let output_locks = [
+            Lock::P2pkh,
+            Lock::P2wpkh,
+            Lock::P2shMultisig,
+            Lock::P2tr,
+            Lock::P2wshMultisig,
+            Lock::P2shP2wpkh,
+            Lock::P2pk,
+            Lock::BareMultisig,
+        ];
+        let tags = ["/rusty/", "/slush/", "/ViaBTC/Mined by regtest/"];
+        for height in 1..=REGTEST_TIP {
+            let prev = blocks.last().unwrap();
+            let time = prev.header.value.timestamp + 600;
+            let mut txs = Vec::new();
+            let mut fees = 0;
+            let lock_of = |i: u64| output_locks[(i % output_locks.len() as u64) as usize](i as u32);
+            if height > COINBASE_MATURITY {
+                // Splits the mature coinbase into three outputs, some with OP_RETURN or dust
+                let coinbase: Utxo = coinbases.pop_front().unwrap();
+                let fee = 1_000 + (height % 13) * 250;
+                let value = coinbase.value - fee;
+                let locks = [lock_of(n), lock_of(n + 3), lock_of(n + 5)];
+                let mut values = [value / 2, value / 4, value - value / 2 - value / 4];
+                if height % 7 == 0 {
+                    values[0] -= DUST_VALUE;
+                }
+                let mut scripts: Vec<(u64, Vec<u8>)> = locks
+                    .iter()
+                    .zip(values)
+                    .map(|(lock, value)| (value, keys.script_pubkey(*lock)))
+                    .collect();
+                if height % 4 == 0 {
+                    let payload: Vec<u8> = match height % 12 {
+                        0 => [&b"omni"[..], &height.to_be_bytes()].concat(),
+                        4 => format!("block {} says hello", height).into_bytes(),
+                        _ => utils::sha256(&height.to_le_bytes()).to_vec(),
+                    };
+                    let mut script = vec![0x6a];
+                    push(&mut script, &payload);
+                    scripts.push((0, script));
+                }
+                if height % 7 == 0 {
+                    scripts.push((DUST_VALUE, keys.script_pubkey(Lock::P2pkh(height as u32))));
+                }
+                let (script_sig, witness) = keys.unlock(coinbase.lock, n);
+                let tx = regtest_tx(
+                    1,
+                    vec![(coinbase.outpoint, script_sig, 0xFFFFFFFF, witness)],
+                    &scripts,
+                    0,
+                );
+                for (index, (lock, value)) in locks.into_iter().zip(values).enumerate() {
+                    utxos.push_back(Utxo {
+                        outpoint: (tx.hash, index as u32),
+                        value,
+                        lock,
+                        height,
+                    });
+                }
+                // Spends its last output in the same block
+                if height % 10 == 5 {
+                    let utxo = utxos.pop_back().unwrap();
+                    let (script_sig, witness) = keys.unlock(utxo.lock, n + 1);
+                    let fee = 500;
+                    let child = regtest_tx(
+                        1,
+                        vec![(utxo.outpoint, script_sig, 0xFFFFFFFF, witness)],
+                        &[(utxo.value - fee, keys.script_pubkey(Lock::AnyoneCanSpend))],
+                        0,
+                    );
+                    utxos.push_back(Utxo {
+                        outpoint: (child.hash, 0),
+                        value: utxo.value - fee,
+                        lock: Lock::AnyoneCanSpend,
+                        height,
+                    });
+                    txs.push(tx);
+                    txs.push(child);
+                    fees += fee;
+                } else {
+                    txs.push(tx);
+                }
+                fees += fee;
+                n += 6;
+
+                // Merges two older outputs, paying to the wallet now and then
+                let (version, seq_no, locktime) = match height % 5 {
+                    // Signals RBF, valid from the next block on
+                    0 => (2, 0xFFFFFFFD, (height - 1) as u32),
+                    // Relative lock (BIP68) of five blocks
+                    1 => (2, 5, 0),
+                    // Locked to a time in the past
+                    2 => (1, 0xFFFFFFFE, time - 3600),
+                    _ => (1, 0xFFFFFFFF, 0),
+                };
+                let mut inputs = Vec::new();
+                let mut value = 0;
+                while inputs.len() < 2 && utxos.front().is_some_and(|utxo| utxo.height + 5 < height)
+                {
+                    let utxo = utxos.pop_front().unwrap();
+                    let (script_sig, witness) = keys.unlock(utxo.lock, n);
+                    inputs.push((utxo.outpoint, script_sig, seq_no, witness));
+                    value += utxo.value;
+                    n += 2;
+                }
+                if !inputs.is_empty() {
+                    let fee = 2_000 + (height % 7) * 500;
+                    let lock = match height % 10 {
+                        3 => Lock::Wallet(0, (height / 10) as u32),
+                        8 => Lock::Wallet(1, (height / 10) as u32),
+                        _ => lock_of(n),
+                    };
+                    let change = lock_of(n + 1);
+                    let scripts = [
+                        (value / 3, keys.script_pubkey(lock)),
+                        (value - value / 3 - fee, keys.script_pubkey(change)),
+                    ];
+                    let tx = regtest_tx(version, inputs, &scripts, locktime);
+                    for (index, lock) in [lock, change].into_iter().enumerate() {
+                        utxos.push_back(Utxo {
+                            outpoint: (tx.hash, index as u32),
+                            value: scripts[index].0,
+                            lock,
+                            height,
+                        });
+                    }
+                    txs.push(tx);
+                    fees += fee;
+                    n += 2;
+                }
+            }
+            let tag = tags[(height % tags.len() as u64) as usize];
+            let lock = coinbase_locks[(height % 4) as usize](height as u32);
+            let block = mine_block(&keys, prev, height, tag, lock, txs, fees);
+            coinbases.push_back(Utxo {
+                outpoint: (block.txs[0].hash, 0),
+                value: block.txs[0].value.outputs[0].out.value,
+                lock,
+                height,
+            });
+            let fork = |prev: &Block, height: u64, tag: &str| {
+                mine_block(&keys, prev, height, tag, Lock::P2pkh(0), Vec::new(), 0)
+            };
+            match height {
+                150 => stale.push((height, fork(prev, height, "/stale/"))),
+                200 => {
+                    let first = fork(prev, height, "/stale/");
+                    let second = fork(&first, height + 1, "/stale/");
+                    stale.push((height, first));
+                    stale.push((height + 1, second));
+                }
+                REGTEST_TIP => {
+                    // The same work, the lower hash wins
+                    let other = fork(prev, height, "/tip/");
+                    if U256::from_le_bytes(&other.header.hash)
+                        < U256::from_le_bytes(&block.header.hash)
+                    {
+                        stale.push((height, block));
+                        blocks.push(other);
+                        continue;
+                    }
+                    stale.push((height, other));
+                }
+                _ => (),
+            }
+            blocks.push(block);
+        }
+        RegtestChain { blocks, stale }
+    }
+
+    /// The blocks in network format, each prefixed with magic and size like in blk files.
+    /// A few come before their parent, block 10 is duplicated and the forks are in between.
+    pub fn to_stream(&self) -> Vec<u8> {
+        let stale = |height: u64| {
+            self.stale
+                .iter()
+                .filter(move |(h, _)| *h == height)
+                .map(|(_, b)| b)
+        };
+        let mut order: Vec<&Block> = Vec::new();
+        for (height, block) in self.blocks.iter().enumerate() {
+            let height = height as u64;
+            match height {
+                12 => order.extend([block, &self.blocks[10]]),
+                31 | 78 | 161 => order.insert(order.len() - 1, block),
+                150 => order.extend(std::iter::once(block).chain(stale(150))),
+                205 => order.extend(std::iter::once(block).chain(stale(201)).chain(stale(200))),
+                REGTEST_TIP => order.extend(stale(REGTEST_TIP).chain(std::iter::once(block))),
+                _ => order.push(block),
+            }
+        }
+        to_stream(order)
+    }
+}
+
+/// Prefixes each block with magic and size like in blk files
+fn to_stream<'a>(blocks: impl IntoIterator<Item = &'a Block>) -> Vec<u8> {
+    let mut stream = Vec::new();
+    for block in blocks {
+        let bytes = block.to_bytes();
+        stream.extend_from_slice(&REGTEST_MAGIC.to_le_bytes());
+        stream.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+        stream.extend_from_slice(&bytes);
+    }
+    stream
+}
+
+/// Regtest chain with the duplicate coinbases of mainnet before BIP30, which have no BIP34
+/// height. Block 1 is repeated by block 4 like 91722 by 91880, block 2 by block 3 like 91812
+/// by 91842. Block 5 spends the output of the first pair, which exists only once.
+pub fn bip30_chain() -> Vec<Block> {
+    let keys = RegtestKeys::new();
+    let coinbase = |tag: &str, lock: Lock| {
+        let mut script_sig = Vec::new();
+        push(&mut script_sig, tag.as_bytes());
+        regtest_tx(
+            1,
+            vec![(([0u8; 32], 0xFFFFFFFF), script_sig, 0xFFFFFFFF, Vec::new())],
+            &[(regtest_subsidy(0), keys.script_pubkey(lock))],
+            0,
+        )
+    };
+    let first = || coinbase("/91722/", Lock::P2pkh(0));
+    let second = || coinbase("/91812/", Lock::P2pkh(1));
+    let mut blocks = vec![regtest_genesis()];
+    for tx in [first(), second(), second(), first()] {
+        let block = mine(blocks.last().unwrap(), vec![tx]);
+        blocks.push(block);
+    }
+
+    let (script_sig, witness) = keys.unlock(Lock::P2pkh(0), 0);
+    let fee = 1_000;
+    let spending = regtest_tx(
+        1,
+        vec![((first().hash, 0), script_sig, 0xFFFFFFFF, witness)],
+        &[(
+            regtest_subsidy(0) - fee,
+            keys.script_pubkey(Lock::P2wpkh(2)),
+        )],
+        0,
+    );
+    let prev = blocks.last().unwrap();
+    let block = mine_block(
+        &keys,
+        prev,
+        5,
+        "/rusty/",
+        Lock::P2pkh(3),
+        vec![spending],
+        fee,
+    );
+    blocks.push(block);
+    blocks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn test_regtest_chain() {
+        let chain = RegtestChain::new();
+        assert_eq!(REGTEST_TIP as usize + 1, chain.blocks.len());
+        assert_eq!(Regtest.genesis(), chain.blocks[0].header.hash);
+        let stale = chain.stale.iter().map(|(_, block)| block);
+        for (i, block) in chain.blocks.iter().chain(stale).enumerate() {
+            block.verify_merkle_root().unwrap();
+            block.verify_witness_commitment().unwrap();
+            check_pow(&block.header.hash, block.header.value.bits).unwrap();
+            if i > 0 && i <= REGTEST_TIP as usize {
+                assert_eq!(
+                    chain.blocks[i - 1].header.hash,
+                    block.header.value.prev_hash
+                );
+            }
+        }
+        let heights: Vec<u64> = chain.stale.iter().map(|(height, _)| *height).collect();
+        assert_eq!(vec![150, 200, 201, REGTEST_TIP], heights);
+    }
+
+    /// Compares the stream with the committed one, or rewrites it with `BLESS=1`
+    fn check_stream(name: &str, stream: Vec<u8>) {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(name);
+        if std::env::var_os("BLESS").is_some() {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, &stream).unwrap();
+        }
+        assert!(
+            fs::read(&path).is_ok_and(|committed| committed == stream),
+            "{} is outdated, regenerate it with `BLESS=1 cargo test`",
+            path.display()
+        );
+    }
+
+    #[test]
+    fn test_regtest_stream() {
+        check_stream(REGTEST_STREAM, RegtestChain::new().to_stream());
+    }
+
+    #[test]
+    fn test_bip30_chain() {
+        let chain = bip30_chain();
+        assert_eq!(6, chain.len());
+        for (prev, block) in chain.iter().zip(&chain[1..]) {
+            block.verify_merkle_root().unwrap();
+            check_pow(&block.header.hash, block.header.value.bits).unwrap();
+            assert_eq!(prev.header.hash, block.header.value.prev_hash);
+        }
+        let coinbase = |height: usize| chain[height].txs[0].hash;
+        assert_eq!(coinbase(1), coinbase(4));
+        assert_eq!(coinbase(2), coinbase(3));
+        assert_ne!(coinbase(1), coinbase(2));
+        assert_eq!(coinbase(1), chain[5].txs[1].value.inputs[0].outpoint.txid);
+        check_stream(BIP30_STREAM, to_stream(&chain));
+    }
+}
@@ -0,0 +1,401 @@
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use crate::blockchain::parser::types::CoinType;
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::tx::{EvaluatedTx, TxOutpoint};
+use crate::blockchain::proto::Hashed;
+use crate::callbacks::Callback;
+use crate::common::outputstore::{outpoint_key, read_varint, write_varint, OutputStore};
+use crate::common::utils;
+use crate::errors::OpResult;
+
+/// Source of the edges from coinbase inputs
+const COINBASE: &str = "COINBASE";
+/// Source of inputs which couldn't be resolved and target of outputs without address
+const UNKNOWN: &str = "UNKNOWN";
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Format {
+    Csv,
+    GraphMl,
+}
+
+impl Format {
+    fn extension(&self) -> &'static str {
+        match self {
+            Format::Csv => "csv",
+            Format::GraphMl => "graphml",
+        }
+    }
+}
+
+/// Exports the flow between addresses as one edge per pair of input and output address
+/// of each transaction, as CSV or GraphML.
+///
+/// The value of each output is split between the input addresses in proportion to their input
+/// values, or evenly if an input couldn't be resolved. Fees are not part of any edge.
+pub struct Graph {
+    output_dir: PathBuf,
+    format: Format,
+    split: bool,
+    // Value and address of the outputs which are not spent yet
+    outputs: Option<OutputStore>,
+    writer: Option<BufWriter<File>>,
+    // Nodes already written to the GraphML file
+    nodes: HashSet<String>,
+
+    start_height: u64,
+    tx_count: u64,
+    edge_count: u64,
+    unresolved_count: u64,
+}
+
+impl Graph {
+    fn file_path(&self) -> PathBuf {
+        self.output_dir
+            .join(format!("edges.{}", self.format.extension()))
+    }
+
+    /// Resolves the inputs and returns the (address, value) sources of the transaction.
+    /// Values of unresolved inputs are None.
+    fn sources(&mut self, tx: &Hashed<EvaluatedTx>) -> OpResult<Vec<(String, Option<u64>)>> {
+        let mut sources = Vec::new();
+        if tx.value.is_coinbase() {
+            let value = tx.value.outputs.iter().map(|output| output.out.value).sum();
+            add_value(&mut sources, COINBASE, Some(value));
+            return Ok(sources);
+        }
+        let outputs = self.outputs.as_mut().unwrap();
+        for input in &tx.value.inputs {
+            match outputs.take(&outpoint_key(&input.outpoint))? {
+                Some(output) => {
+                    let mut output = &output[..];
+                    let value = read_varint(&mut output)?;
+                    let address = match output {
+                        [] => UNKNOWN,
+                        address => std::str::from_utf8(address).unwrap_or(UNKNOWN),
+                    };
+                    add_value(&mut sources, address, Some(value));
+                }
+                None => {
+                    self.unresolved_count += 1;
+                    debug!(target: "callback", "Unable to resolve previous output {}:{}",
+                           utils::arr_to_hex_swapped(&input.outpoint.txid), input.outpoint.index);
+                    add_value(&mut sources, UNKNOWN, None);
+                }
+            }
+        }
+        Ok(sources)
+    }
+
+    /// Stores the outputs for later resolution and returns the (address, value) targets
+    fn targets(&mut self, tx: &Hashed<EvaluatedTx>) -> OpResult<Vec<(String, Option<u64>)>> {
+        let mut targets = Vec::new();
+        let outputs = self.outputs.as_mut().unwrap();
+        for (i, output) in tx.value.outputs.iter().enumerate() {
+            let address = output.script.address.as_deref().unwrap_or("");
+            let mut value = Vec::with_capacity(address.len() + 9);
+            write_varint(&mut value, output.out.value);
+            value.extend_from_slice(address.as_bytes());
+            outputs.insert(outpoint_key(&TxOutpoint::new(tx.hash, i as u32)), value)?;
+
+            let address = if address.is_empty() { UNKNOWN } else { address };
+            add_value(&mut targets, address, Some(output.out.value));
+        }
+        Ok(targets)
+    }
+
+    fn write_header(&mut self) -> OpResult<()> {
+        let writer = self.writer.as_mut().unwrap();
+        match self.format {
+            Format::Csv => writer.write_all(b"source;target;value;txid;height\n")?,
+            Format::GraphMl => writer.write_all(
+                b"<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+                  <graphml xmlns=\"http://graphml.graphdrawing.org/xmlns\">\n\
+                  <key id=\"value\" for=\"edge\" attr.name=\"value\" attr.type=\"long\"/>\n\
+                  <key id=\"txid\" for=\"edge\" attr.name=\"txid\" attr.type=\"string\"/>\n\
+                  <key id=\"height\" for=\"edge\" attr.name=\"height\" attr.type=\"long\"/>\n\
+                  <graph id=\"transactions\" edgedefault=\"directed\">\n",
+            )?,
+        }
+        Ok(())
+    }
+
+    fn write_edge(
+        &mut self,
+        source: &str,
+        target: &str,
+        value: Option<u64>,
+        txid: &str,
+        block_height: u64,
+    ) -> OpResult<()> {
+        let value = value.map(|value| value.to_string()).unwrap_or_default();
+        match self.format {
+            Format::Csv => {
+                let writer = self.writer.as_mut().unwrap();
+                writer.write_all(
+                    format!(
+                        "{};{};{};{};{}\n",
+                        source, target, value, txid, block_height
+                    )
+                    .as_bytes(),
+                )?;
+            }
+            Format::GraphMl => {
+                for node in [source, target] {
+                    if !self.nodes.contains(node) {
+                        let writer = self.writer.as_mut().unwrap();
+                        writer.write_all(format!("<node id=\"{}\"/>\n", node).as_bytes())?;
+                        self.nodes.insert(String::from(node));
+                    }
+                }
+                let mut edge = format!("<edge source=\"{}\" target=\"{}\">", source, target);
+                if !value.is_empty() {
+                    edge.push_str(&format!("<data key=\"value\">{}</data>", value));
+                }
+                edge.push_str(&format!(
+                    "<data key=\"txid\">{}</data><data key=\"height\">{}</data></edge>\n",
+                    txid, block_height
+                ));
+                self.writer.as_mut().unwrap().write_all(edge.as_bytes())?;
+            }
+        }
+        self.edge_count += 1;
+        Ok(())
+    }
+}
+
+impl Callback for Graph {
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b>
+    where
+        Self: Sized,
+    {
+        SubCommand::with_name("graph")
+            .about("Exports the address transaction graph as edge list")
+            .version("0.1")
+            .arg(
+                Arg::with_name("output-dir")
+                    .long("output-dir")
+                    .value_name("PATH")
+                    .help("Folder to store edges.csv or edges.graphml, created if necessary")
+                    .takes_value(true)
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name("format")
+                    .long("format")
+                    .value_name("FORMAT")
+                    .help("Output format (default: csv)")
+                    .possible_values(&["csv", "graphml"])
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("no-split").long("no-split").help(
+                    "Don't split output values between the inputs, edges only link addresses",
+                ),
+            )
+            .args(&OutputStore::args())
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let format = match matches.value_of("format") {
+            Some("graphml") => Format::GraphMl,
+            _ => Format::Csv,
+        };
+        let outputs = OutputStore::from_matches(matches)?;
+        let cb = Graph {
+            output_dir: PathBuf::from(matches.value_of("output-dir").unwrap()),
+            format,
+            split: !matches.is_present("no-split"),
+            outputs: Some(outputs),
+            writer: None,
+            nodes: HashSet::new(),
+            start_height: 0,
+            tx_count: 0,
+            edge_count: 0,
+            unresolved_count: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, _: &CoinType, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        if block_height != 0 {
+            warn!(target: "callback", "Starting at height {}, inputs spending earlier outputs are written as {}.", block_height, UNKNOWN);
+        }
+        fs::create_dir_all(&self.output_dir)?;
+        let path = self.file_path().with_extension("tmp");
+        self.writer = Some(BufWriter::with_capacity(4000000, File::create(path)?));
+        self.write_header()?;
+        info!(target: "callback", "Using `graph` with output dir: {} ...", &self.output_dir.display());
+        Ok(())
+    }
+
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<()> {
+        for tx in &block.txs {
+            let txid = utils::arr_to_hex_swapped(&tx.hash);
+            let sources = self.sources(tx)?;
+            let targets = self.targets(tx)?;
+            let weights: Vec<Option<u64>> = sources.iter().map(|(_, value)| *value).collect();
+            for (target, value) in &targets {
+                let shares = split_value(value.unwrap_or(0), &weights);
+                for ((source, _), share) in sources.iter().zip(shares) {
+                    let share = if self.split { Some(share) } else { None };
+                    self.write_edge(source, target, share, &txid, block_height)?;
+                }
+            }
+        }
+        self.tx_count += block.tx_count.value;
+        Ok(())
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        let mut writer = self.writer.take().unwrap();
+        if self.format == Format::GraphMl {
+            writer.write_all(b"</graph>\n</graphml>\n")?;
+        }
+        writer.flush()?;
+        drop(writer);
+        let path = self.file_path();
+        fs::rename(path.with_extension("tmp"), &path)?;
+        self.outputs.take().unwrap().destroy()?;
+
+        info!(target: "callback", "Done.\nDumped blocks {} to {} into {}:\n\
+                                   \t-> transactions:      {:9}\n\
+                                   \t-> edges:             {:9}\n\
+                                   \t-> unresolved inputs: {:9}",
+             self.start_height, block_height, path.display(), self.tx_count, self.edge_count, self.unresolved_count);
+        Ok(())
+    }
+}
+
+/// Adds the value to the entry of the address, the sum is None if any value is unknown
+fn add_value(entries: &mut Vec<(String, Option<u64>)>, address: &str, value: Option<u64>) {
+    match entries.iter_mut().find(|(entry, _)| entry == address) {
+        Some((_, sum)) => *sum = sum.zip(value).map(|(sum, value)| sum + value),
+        None => entries.push((String::from(address), value)),
+    }
+}
+
+/// Splits the amount in proportion to the weights, or evenly if a weight is unknown
+/// or all are zero. The rounding remainder is added to the last share.
+fn split_value(amount: u64, weights: &[Option<u64>]) -> Vec<u64> {
+    let total = weights
+        .iter()
+        .try_fold(0u64, |sum, weight| weight.map(|weight| sum + weight))
+        .filter(|total| *total > 0);
+    let mut shares: Vec<u64> = match total {
+        Some(total) => weights
+            .iter()
+            .map(|weight| (amount as u128 * weight.unwrap() as u128 / total as u128) as u64)
+            .collect(),
+        None => vec![amount / weights.len() as u64; weights.len()],
+    };
+    let rest = amount - shares.iter().sum::<u64>();
+    if let Some(last) = shares.last_mut() {
+        *last += rest;
+    }
+    shares
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+    use crate::blockchain::parser::types::Bitcoin;
+    use crate::callbacks::fixtures::{new_chain, new_tx, new_tx_to, SCRIPT_A, SCRIPT_B};
+
+    const ADDRESS_A: &str = "12higDjoCCNXSA95xZMWUdPvXNmkAduhWv";
+    const ADDRESS_B: &str = "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa";
+
+    fn run(args: &[&str], name: &str) -> (Graph, String) {
+        let dir = env::temp_dir().join(format!("blockparser-graph-{}-{}", name, process::id()));
+        let mut all_args = vec!["graph", "--output-dir", dir.to_str().unwrap()];
+        all_args.extend_from_slice(args);
+        let matches = Graph::build_subcommand().get_matches_from(all_args);
+
+        let coinbase_a = new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[5000000000]);
+        let coinbase_b = new_tx_to(&[([0u8; 32], 0xFFFFFFFF)], &[(1000000000, SCRIPT_B)]);
+        // Inputs of A (50 BTC), B (10 BTC) and an unknown one
+        let spend = new_tx_to(
+            &[(coinbase_a.hash, 0), (coinbase_b.hash, 0), ([1u8; 32], 0)],
+            &[(2999999000, SCRIPT_B), (3000000000, SCRIPT_A)],
+        );
+        let chain = new_chain(vec![vec![coinbase_a, coinbase_b], vec![spend]]);
+
+        let mut graph = Graph::new(&matches).unwrap();
+        graph.on_start(&CoinType::from(Bitcoin), 0).unwrap();
+        for (height, block) in chain.iter().enumerate() {
+            graph.on_block(block, height as u64).unwrap();
+        }
+        graph.on_complete(1).unwrap();
+        let content = fs::read_to_string(graph.file_path()).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        (graph, content)
+    }
+
+    #[test]
+    fn test_split_value() {
+        assert_eq!(vec![1, 2], split_value(3, &[Some(10), Some(20)]));
+        assert_eq!(vec![3, 4], split_value(7, &[Some(1), Some(1)]));
+        assert_eq!(vec![2, 3], split_value(5, &[Some(100), None]));
+        assert_eq!(vec![2, 3], split_value(5, &[Some(0), Some(0)]));
+        assert_eq!(vec![u64::MAX], split_value(u64::MAX, &[Some(u64::MAX)]));
+    }
+
+    #[test]
+    fn test_csv() {
+        for buffer_size in ["100", "1"] {
+            let db_path = env::temp_dir().join(format!(
+                "blockparser-graph-db-{}-{}",
+                buffer_size,
+                process::id()
+            ));
+            let (graph, csv) = run(
+                &[
+                    "--db-path",
+                    db_path.to_str().unwrap(),
+                    "--buffer-size",
+                    buffer_size,
+                ],
+                buffer_size,
+            );
+            assert!(!db_path.exists());
+            assert_eq!(1, graph.unresolved_count);
+            let lines: Vec<&str> = csv.lines().collect();
+            assert_eq!("source;target;value;txid;height", lines[0]);
+            assert_eq!(9, lines.len());
+            assert!(lines[1].starts_with(&format!("COINBASE;{};5000000000;", ADDRESS_A)));
+            assert!(lines[2].starts_with(&format!("COINBASE;{};1000000000;", ADDRESS_B)));
+            // The unknown input value makes it an even split
+            assert!(lines[3].starts_with(&format!("{};{};999999666;", ADDRESS_A, ADDRESS_B)));
+            assert!(lines[5].starts_with(&format!("UNKNOWN;{};999999668;", ADDRESS_B)));
+            assert!(lines[8].ends_with(";1"));
+        }
+    }
+
+    #[test]
+    fn test_no_split_graphml() {
+        let (graph, xml) = run(&["--format", "graphml", "--no-split"], "graphml");
+        assert_eq!(8, graph.edge_count);
+        assert!(xml.starts_with("<?xml"));
+        assert!(xml.ends_with("</graph>\n</graphml>\n"));
+        assert_eq!(4, xml.matches("<node ").count());
+        assert!(xml.contains("<node id=\"COINBASE\"/>"));
+        assert!(!xml.contains("key=\"value\">"));
+        assert!(xml.contains(&format!(
+            "<edge source=\"UNKNOWN\" target=\"{}\"><data key=\"txid\">",
+            ADDRESS_A
+        )));
+    }
+}
@@ -0,0 +1,242 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use crate::blockchain::parser::types::CoinType;
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::target::U256;
+use crate::blockchain::proto::ToRaw;
+use crate::callbacks::Callback;
+use crate::common::utils;
+use crate::errors::{OpError, OpResult};
+
+/// Writes the 80 byte block headers in chain order, e.g. for SPV clients.
+/// Transactions are not parsed.
+pub struct Headers {
+    output_dir: PathBuf,
+    write_csv: bool,
+    writer: Option<BufWriter<File>>,
+    csv_writer: Option<BufWriter<File>>,
+    prev_hash: Option<[u8; 32]>,
+    // Only known when starting at the genesis block
+    chainwork: Option<U256>,
+
+    start_height: u64,
+    n_headers: u64,
+}
+
+impl Callback for Headers {
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b>
+    where
+        Self: Sized,
+    {
+        SubCommand::with_name("headers")
+            .about("Writes the raw block headers to a binary file, skipping transactions")
+            .version("0.1")
+            .arg(
+                Arg::with_name("output-dir")
+                    .long("output-dir")
+                    .value_name("PATH")
+                    .help("Folder to store headers.bin, created if necessary")
+                    .takes_value(true)
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name("csv").long("csv").help(
+                    "Also write headers.csv with height, hash, time, bits, nonce and chainwork",
+                ),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let cb = Headers {
+            output_dir: PathBuf::from(matches.value_of("output-dir").unwrap()),
+            write_csv: matches.is_present("csv"),
+            writer: None,
+            csv_writer: None,
+            prev_hash: None,
+            chainwork: None,
+            start_height: 0,
+            n_headers: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, _: &CoinType, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        if block_height == 0 {
+            self.chainwork = Some(U256::ZERO);
+            // The genesis block has no predecessor
+            self.prev_hash = Some([0u8; 32]);
+        } else {
+            warn!(target: "callback", "Starting at height {}, the chainwork is unknown.", block_height);
+        }
+        fs::create_dir_all(&self.output_dir)?;
+        self.writer = Some(BufWriter::with_capacity(
+            4000000,
+            File::create(self.output_dir.join("headers.bin.tmp"))?,
+        ));
+        if self.write_csv {
+            let mut writer = BufWriter::with_capacity(
+                4000000,
+                File::create(self.output_dir.join("headers.csv.tmp"))?,
+            );
+            writer.write_all(b"height;hash;time;bits;nonce;chainwork\n")?;
+            self.csv_writer = Some(writer);
+        }
+        info!(target: "callback", "Using `headers` with output dir: {} ...", &self.output_dir.display());
+        Ok(())
+    }
+
+    fn wants_transactions(&self) -> bool {
+        false
+    }
+
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<()> {
+        let header = &block.header.value;
+        if let Some(prev_hash) = self.prev_hash {
+            if header.prev_hash != prev_hash {
+                return Err(OpError::from(format!(
+                    "Block {} at height {} doesn't link to the previous block, expected prev_hash {} but got {}.",
+                    utils::arr_to_hex_swapped(&block.header.hash),
+                    block_height,
+                    utils::arr_to_hex_swapped(&prev_hash),
+                    utils::arr_to_hex_swapped(&header.prev_hash)
+                )));
+            }
+        }
+        self.prev_hash = Some(block.header.hash);
+        self.writer
+            .as_mut()
+            .unwrap()
+            .write_all(&header.to_bytes())?;
+
+        if let Some(chainwork) = self.chainwork.as_mut() {
+            let target = U256::from_compact(header.bits).unwrap_or_default();
+            *chainwork = *chainwork + target.work();
+        }
+        if let Some(writer) = self.csv_writer.as_mut() {
+            writer.write_all(
+                format!(
+                    "{};{};{};{:08x};{};{}\n",
+                    block_height,
+                    utils::arr_to_hex_swapped(&block.header.hash),
+                    header.timestamp,
+                    header.bits,
+                    header.nonce,
+                    self.chainwork
+                        .map(|chainwork| chainwork.to_hex())
+                        .unwrap_or_default()
+                )
+                .as_bytes(),
+            )?;
+        }
+        self.n_headers += 1;
+        Ok(())
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        let files = [
+            ("headers.bin", self.writer.take()),
+            ("headers.csv", self.csv_writer.take()),
+        ];
+        for (name, writer) in files {
+            if let Some(mut writer) = writer {
+                writer.flush()?;
+                drop(writer);
+                let path = self.output_dir.join(name);
+                fs::rename(self.output_dir.join(format!("{}.tmp", name)), &path)?;
+            }
+        }
+
+        info!(target: "callback", "Done.\nDumped {} headers of blocks {} to {} into {}.",
+              self.n_headers, self.start_height, block_height, self.output_dir.display());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::io::Cursor;
+    use std::process;
+
+    use super::*;
+    use crate::blockchain::parser::reader::BlockchainRead;
+    use crate::blockchain::parser::types::Bitcoin;
+    use crate::blockchain::proto::Hashed;
+    use crate::callbacks::fixtures::{new_chain, new_tx};
+
+    fn new_headers(name: &str, csv: bool) -> (Headers, PathBuf) {
+        let dir = env::temp_dir().join(format!("blockparser-headers-{}-{}", name, process::id()));
+        let mut args = vec!["headers", "--output-dir", dir.to_str().unwrap()];
+        if csv {
+            args.push("--csv");
+        }
+        let matches = Headers::build_subcommand().get_matches_from(args);
+        (Headers::new(&matches).unwrap(), dir)
+    }
+
+    fn chain() -> Vec<Block> {
+        new_chain(
+            (0..3)
+                .map(|i| vec![new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[5000000000 + i])])
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_headers() {
+        let (mut headers, dir) = new_headers("csv", true);
+        assert!(!headers.wants_transactions());
+        let chain = chain();
+        headers.on_start(&CoinType::from(Bitcoin), 0).unwrap();
+        for (height, block) in chain.iter().enumerate() {
+            headers.on_block(block, height as u64).unwrap();
+        }
+        headers.on_complete(2).unwrap();
+
+        let raw = fs::read(dir.join("headers.bin")).unwrap();
+        assert_eq!(3 * 80, raw.len());
+        for (i, block) in chain.iter().enumerate() {
+            let header = Cursor::new(&raw[i * 80..]).read_block_header().unwrap();
+            assert_eq!(block.header.hash, Hashed::double_sha256(header).hash);
+        }
+        let csv = fs::read_to_string(dir.join("headers.csv")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        let rows: Vec<&str> = csv.lines().collect();
+        assert_eq!("height;hash;time;bits;nonce;chainwork", rows[0]);
+        assert_eq!(
+            format!(
+                "2;{};1231006505;1d00ffff;0;{}",
+                utils::arr_to_hex_swapped(&chain[2].header.hash),
+                "0000000000000000000000000000000000000000000000000000000300030003"
+            ),
+            rows[3]
+        );
+    }
+
+    #[test]
+    fn test_discontinuity() {
+        let (mut headers, dir) = new_headers("gap", false);
+        let chain = chain();
+        headers.on_start(&CoinType::from(Bitcoin), 1).unwrap();
+        headers.on_block(&chain[0], 1).unwrap();
+        assert!(headers.on_block(&chain[2], 2).is_err());
+        headers.on_complete(2).unwrap();
+        assert_eq!(80, fs::read(dir.join("headers.bin")).unwrap().len());
+        assert!(!dir.join("headers.csv").exists());
+        fs::remove_dir_all(&dir).unwrap();
+
+        // The genesis block must not have a predecessor
+        let (mut headers, dir) = new_headers("genesis", false);
+        headers.on_start(&CoinType::from(Bitcoin), 0).unwrap();
+        assert!(headers.on_block(&chain[1], 0).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,511 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as FmtWrite;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use crate::blockchain::parser::types::CoinType;
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::inscription::{self, Inscription};
+use crate::blockchain::proto::tx::EvaluatedTx;
+use crate::blockchain::proto::Hashed;
+use crate::callbacks::{common, Callback};
+use crate::common::utils;
+use crate::errors::OpResult;
+
+/// Media types of BRC-20 payloads, parameters like `;charset=utf-8` are allowed
+const BRC20_CONTENT_TYPES: [&str; 2] = ["text/plain", "application/json"];
+
+/// BRC-20 operation, the values are kept as inscribed
+#[derive(Debug, PartialEq, Eq)]
+struct Brc20 {
+    op: String,
+    tick: String,
+    amt: Option<String>,
+    max: Option<String>,
+    lim: Option<String>,
+}
+
+impl Brc20 {
+    /// Parses a JSON object with `"p": "brc-20"`, its fields have to be strings.
+    /// Encoded bodies and other content types are no BRC-20 operations.
+    fn parse(inscription: &Inscription) -> Option<Self> {
+        if inscription.content_encoding.is_some() {
+            return None;
+        }
+        let content_type = std::str::from_utf8(inscription.content_type.as_ref()?).ok()?;
+        let media_type = content_type.split(';').next().unwrap_or_default().trim();
+        if !BRC20_CONTENT_TYPES.contains(&media_type) {
+            return None;
+        }
+        let json: serde_json::Value = serde_json::from_slice(inscription.body.as_ref()?).ok()?;
+        let object = json.as_object()?;
+        if object.get("p")?.as_str()? != "brc-20" {
+            return None;
+        }
+        let field = |name: &str| match object.get(name) {
+            Some(value) => value.as_str().map(|value| Some(String::from(value))),
+            None => Some(None),
+        };
+        Some(Brc20 {
+            op: field("op")??,
+            tick: field("tick")??,
+            amt: field("amt")?,
+            max: field("max")?,
+            lim: field("lim")?,
+        })
+    }
+}
+
+/// Extracts ordinals inscriptions from taproot script path spends into a csv file, BRC-20
+/// operations into a second one and the bodies into files named by their hash
+pub struct Inscriptions {
+    output_dir: PathBuf,
+    content_dir: Option<PathBuf>,
+    writer: Option<BufWriter<File>>,
+    brc20_writer: Option<BufWriter<File>>,
+    // Reused for all rows
+    row: String,
+
+    n_inscriptions: u64,
+    n_malformed: u64,
+    n_bytes: u64,
+    n_files: u64,
+    // Number of inscriptions per media type
+    content_types: BTreeMap<String, u64>,
+    // Number of BRC-20 operations per `op`
+    brc20_ops: BTreeMap<String, u64>,
+
+    start_height: u64,
+}
+
+impl Inscriptions {
+    fn write_inscription(
+        &mut self,
+        txid: &str,
+        input: usize,
+        envelope: usize,
+        block_height: u64,
+        inscription: &Inscription,
+    ) -> OpResult<()> {
+        let content_type = inscription
+            .content_type
+            .as_ref()
+            .map(|content_type| String::from_utf8_lossy(content_type).into_owned());
+        let content_encoding = inscription
+            .content_encoding
+            .as_ref()
+            .map(|encoding| String::from_utf8_lossy(encoding).into_owned());
+        let hash = inscription
+            .body
+            .as_ref()
+            .map(|body| utils::arr_to_hex(&utils::sha256(body)));
+        self.row.clear();
+        let _ = writeln!(
+            self.row,
+            "{};{};{};{};{};{};{};{}",
+            txid,
+            input,
+            envelope,
+            block_height,
+            content_type
+                .as_deref()
+                .map(common::escape_csv)
+                .unwrap_or_default(),
+            content_encoding
+                .as_deref()
+                .map(common::escape_csv)
+                .unwrap_or_default(),
+            inscription
+                .body
+                .as_ref()
+                .map(|body| body.len().to_string())
+                .unwrap_or_default(),
+            hash.as_deref().unwrap_or_default()
+        );
+        self.writer
+            .as_mut()
+            .unwrap()
+            .write_all(self.row.as_bytes())?;
+
+        self.n_inscriptions += 1;
+        if inscription.malformed {
+            self.n_malformed += 1;
+        }
+        let media_type = content_type
+            .as_deref()
+            .map(|content_type| content_type.split(';').next().unwrap_or_default().trim())
+            .unwrap_or_default();
+        *self
+            .content_types
+            .entry(String::from(media_type))
+            .or_insert(0) += 1;
+        if let (Some(body), Some(hash)) = (&inscription.body, &hash) {
+            self.n_bytes += body.len() as u64;
+            self.write_content(hash, body)?;
+        }
+
+        if let Some(brc20) = Brc20::parse(inscription) {
+            self.row.clear();
+            let _ = writeln!(
+                self.row,
+                "{};{};{};{};{};{};{};{};{}",
+                txid,
+                input,
+                envelope,
+                block_height,
+                common::escape_csv(&brc20.op),
+                common::escape_csv(&brc20.tick),
+                brc20
+                    .amt
+                    .as_deref()
+                    .map(common::escape_csv)
+                    .unwrap_or_default(),
+                brc20
+                    .max
+                    .as_deref()
+                    .map(common::escape_csv)
+                    .unwrap_or_default(),
+                brc20
+                    .lim
+                    .as_deref()
+                    .map(common::escape_csv)
+                    .unwrap_or_default()
+            );
+            self.brc20_writer
+                .as_mut()
+                .unwrap()
+                .write_all(self.row.as_bytes())?;
+            *self.brc20_ops.entry(brc20.op).or_insert(0) += 1;
+        }
+        Ok(())
+    }
+
+    /// Writes the body to `<content-dir>/<first byte>/<hash>` unless it exists already
+    fn write_content(&mut self, hash: &str, body: &[u8]) -> OpResult<()> {
+        let dir = match &self.content_dir {
+            Some(content_dir) => content_dir.join(&hash[..2]),
+            None => return Ok(()),
+        };
+        let path = dir.join(hash);
+        if path.exists() {
+            return Ok(());
+        }
+        fs::create_dir_all(&dir)?;
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, body)?;
+        fs::rename(&tmp_path, &path)?;
+        self.n_files += 1;
+        Ok(())
+    }
+}
+
+impl Callback for Inscriptions {
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b>
+    where
+        Self: Sized,
+    {
+        SubCommand::with_name("inscriptions")
+            .about("Extracts ordinals inscriptions and BRC-20 operations into CSV files")
+            .version("0.1")
+            .arg(
+                Arg::with_name("output-dir")
+                    .long("output-dir")
+                    .value_name("PATH")
+                    .help("Folder to store inscriptions.csv and brc20.csv, created if necessary")
+                    .takes_value(true)
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name("content-dir")
+                    .long("content-dir")
+                    .value_name("PATH")
+                    .help("Store the bodies in PATH/<first byte>/<sha256>, each one once")
+                    .takes_value(true),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let cb = Inscriptions {
+            output_dir: PathBuf::from(matches.value_of("output-dir").unwrap()),
+            content_dir: matches.value_of("content-dir").map(PathBuf::from),
+            writer: None,
+            brc20_writer: None,
+            row: String::new(),
+            n_inscriptions: 0,
+            n_malformed: 0,
+            n_bytes: 0,
+            n_files: 0,
+            content_types: BTreeMap::new(),
+            brc20_ops: BTreeMap::new(),
+            start_height: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, _: &CoinType, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        fs::create_dir_all(&self.output_dir)?;
+        let mut writer = BufWriter::with_capacity(
+            4000000,
+            File::create(self.output_dir.join("inscriptions.csv.tmp"))?,
+        );
+        writer.write_all(
+            b"txid;input;envelope;height;content_type;content_encoding;content_length;sha256\n",
+        )?;
+        self.writer = Some(writer);
+        let mut brc20_writer = BufWriter::new(File::create(self.output_dir.join("brc20.csv.tmp"))?);
+        brc20_writer.write_all(b"txid;input;envelope;height;op;tick;amt;max;lim\n")?;
+        self.brc20_writer = Some(brc20_writer);
+        if let Some(content_dir) = &self.content_dir {
+            fs::create_dir_all(content_dir)?;
+        }
+        info!(target: "callback", "Using `inscriptions` with output dir: {} ...", &self.output_dir.display());
+        Ok(())
+    }
+
+    /// Envelopes are parsed from the witnesses
+    fn wants_script_evaluation(&self) -> bool {
+        false
+    }
+
+    fn on_block(&mut self, _: &Block, _: u64) -> OpResult<()> {
+        Ok(())
+    }
+
+    fn on_transaction(
+        &mut self,
+        tx: &Hashed<EvaluatedTx>,
+        block_height: u64,
+        _: usize,
+    ) -> OpResult<()> {
+        let mut txid: Option<String> = None;
+        for (input, witness) in tx.value.witnesses.iter().enumerate() {
+            let tapscript = match inscription::tapscript(witness) {
+                Some(tapscript) => tapscript,
+                None => continue,
+            };
+            for (envelope, inscription) in Inscription::parse(tapscript).iter().enumerate() {
+                let txid = txid.get_or_insert_with(|| utils::arr_to_hex_swapped(&tx.hash));
+                self.write_inscription(txid, input, envelope, block_height, inscription)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        for (writer, name) in [
+            (self.writer.take(), "inscriptions.csv"),
+            (self.brc20_writer.take(), "brc20.csv"),
+        ] {
+            let mut writer = writer.unwrap();
+            writer.flush()?;
+            drop(writer);
+            let path = self.output_dir.join(name);
+            fs::rename(path.with_extension("csv.tmp"), &path)?;
+        }
+
+        let content_types: Vec<String> = self
+            .content_types
+            .iter()
+            .map(|(content_type, count)| format!("{}={}", content_type, count))
+            .collect();
+        let brc20_ops: Vec<String> = self
+            .brc20_ops
+            .iter()
+            .map(|(op, count)| format!("{}={}", op, count))
+            .collect();
+        info!(target: "callback", "Done.\nDumped inscriptions of blocks {} to {} into {}:\n\
+                                   \t-> inscriptions:  {:9} (malformed: {})\n\
+                                   \t-> body bytes:    {:9} (new files: {})\n\
+                                   \t-> content types: {}\n\
+                                   \t-> brc-20:        {}",
+              self.start_height, block_height, self.output_dir.display(),
+              self.n_inscriptions, self.n_malformed, self.n_bytes, self.n_files,
+              content_types.join(", "), brc20_ops.join(", "));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+    use crate::blockchain::parser::types::Bitcoin;
+    use crate::callbacks;
+    use crate::callbacks::fixtures::{new_chain, new_tx, set_witnesses};
+
+    /// Key of the tapscripts and the control blocks
+    const KEY: &str = "b6b7fe1a2e4a7a5f8b3f1fbd6c2b4a44cc9b2c4e0a540e11d2f0dc3796c0e4f2";
+
+    /// Witness of a script path spend revealing an inscription with the given content type and
+    /// body, the body is split into pushes of 520 bytes
+    fn reveal(content_type: &str, body: &[u8]) -> Vec<Vec<u8>> {
+        let mut script = utils::hex_to_vec(&format!("20{}ac0063036f72640101", KEY));
+        script.push(content_type.len() as u8);
+        script.extend_from_slice(content_type.as_bytes());
+        script.push(0);
+        for chunk in body.chunks(520) {
+            script.push(0x4d);
+            script.extend_from_slice(&(chunk.len() as u16).to_le_bytes());
+            script.extend_from_slice(chunk);
+        }
+        script.push(0x68);
+        vec![
+            vec![0x11; 64],
+            script,
+            utils::hex_to_vec(&format!("c0{}", KEY)),
+        ]
+    }
+
+    fn inscription(content_type: &[u8], body: &[u8]) -> Inscription {
+        Inscription {
+            content_type: Some(content_type.to_vec()),
+            body: Some(body.to_vec()),
+            ..Inscription::default()
+        }
+    }
+
+    #[test]
+    fn test_brc20_parse() {
+        let deploy = inscription(
+            b"text/plain;charset=utf-8",
+            br#"{"p":"brc-20","op":"deploy","tick":"ordi","max":"21000000","lim":"1000"}"#,
+        );
+        assert_eq!(
+            Some(Brc20 {
+                op: String::from("deploy"),
+                tick: String::from("ordi"),
+                amt: None,
+                max: Some(String::from("21000000")),
+                lim: Some(String::from("1000")),
+            }),
+            Brc20::parse(&deploy)
+        );
+        let mint = inscription(
+            b"application/json",
+            br#" { "p": "brc-20", "op": "mint", "tick": "ordi", "amt": "1000" } "#,
+        );
+        let mint = Brc20::parse(&mint).unwrap();
+        assert_eq!(
+            ("mint", Some("1000")),
+            (mint.op.as_str(), mint.amt.as_deref())
+        );
+
+        // Amounts have to be strings
+        let numeric = inscription(
+            b"text/plain",
+            br#"{"p":"brc-20","op":"mint","tick":"ordi","amt":1000}"#,
+        );
+        assert_eq!(None, Brc20::parse(&numeric));
+        for (content_type, body) in [
+            (
+                &b"text/plain"[..],
+                &br#"{"p":"brc-21","op":"mint","tick":"ordi"}"#[..],
+            ),
+            (b"text/plain", br#"{"p":"brc-20","tick":"ordi"}"#),
+            (b"text/plain", br#"["brc-20"]"#),
+            (b"text/plain", br#"{"p":"brc-20","op":"mint""#),
+            (b"image/png", br#"{"p":"brc-20","op":"mint","tick":"ordi"}"#),
+        ] {
+            assert_eq!(None, Brc20::parse(&inscription(content_type, body)));
+        }
+        let mut encoded = deploy;
+        encoded.content_encoding = Some(b"br".to_vec());
+        assert_eq!(None, Brc20::parse(&encoded));
+        assert_eq!(None, Brc20::parse(&Inscription::default()));
+    }
+
+    #[test]
+    fn test_inscriptions() {
+        let dir = env::temp_dir().join(format!("blockparser-inscriptions-{}", process::id()));
+        let content_dir = dir.join("content");
+        let matches = Inscriptions::build_subcommand().get_matches_from(vec![
+            "inscriptions",
+            "--output-dir",
+            dir.to_str().unwrap(),
+            "--content-dir",
+            content_dir.to_str().unwrap(),
+        ]);
+        let image: Vec<u8> = (0..1200u32).map(|i| (i % 256) as u8).collect();
+        let mint = br#"{"p":"brc-20","op":"mint","tick":"ordi","amt":"1000"}"#;
+
+        let coinbase_0 = new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[5000000000]);
+        let coinbase_1 = new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[5000000000]);
+        // A key path spend and two reveals, the same mint in the next transaction
+        let mut reveal_tx = new_tx(&[([1; 32], 0), ([2; 32], 0), ([3; 32], 0)], &[546, 546]);
+        set_witnesses(
+            &mut reveal_tx.value,
+            vec![
+                vec![vec![0x11; 64]],
+                reveal("image/png", &image),
+                reveal("text/plain;charset=utf-8", mint),
+            ],
+        );
+        let mut repeated_tx = new_tx(&[([4; 32], 0)], &[546]);
+        set_witnesses(
+            &mut repeated_tx.value,
+            vec![reveal("text/plain;charset=utf-8", mint)],
+        );
+        let chain = new_chain(vec![
+            vec![coinbase_0],
+            vec![coinbase_1, reveal_tx, repeated_tx],
+        ]);
+
+        let mut inscriptions = Inscriptions::new(&matches).unwrap();
+        assert!(!inscriptions.wants_script_evaluation());
+        inscriptions.on_start(&CoinType::from(Bitcoin), 0).unwrap();
+        for (height, block) in chain.iter().enumerate() {
+            callbacks::deliver_block(&mut inscriptions, block, height as u64).unwrap();
+        }
+        inscriptions.on_complete(2).unwrap();
+
+        let reveal_txid = utils::arr_to_hex_swapped(&chain[1].txs[1].hash);
+        let repeated_txid = utils::arr_to_hex_swapped(&chain[1].txs[2].hash);
+        let image_hash = utils::arr_to_hex(&utils::sha256(&image));
+        let mint_hash = utils::arr_to_hex(&utils::sha256(mint));
+        let csv = fs::read_to_string(dir.join("inscriptions.csv")).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(
+            vec![
+                "txid;input;envelope;height;content_type;content_encoding;content_length;sha256",
+                &format!("{};1;0;1;image/png;;1200;{}", reveal_txid, image_hash),
+                &format!(
+                    "{};2;0;1;\"text/plain;charset=utf-8\";;{};{}",
+                    reveal_txid,
+                    mint.len(),
+                    mint_hash
+                ),
+                &format!(
+                    "{};0;0;1;\"text/plain;charset=utf-8\";;{};{}",
+                    repeated_txid,
+                    mint.len(),
+                    mint_hash
+                ),
+            ],
+            lines
+        );
+        let csv = fs::read_to_string(dir.join("brc20.csv")).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(3, lines.len());
+        assert_eq!(format!("{};2;0;1;mint;ordi;1000;;", reveal_txid), lines[1]);
+
+        // Each body is stored once
+        let image_path = content_dir.join(&image_hash[..2]).join(&image_hash);
+        assert_eq!(image, fs::read(image_path).unwrap());
+        let mint_path = content_dir.join(&mint_hash[..2]).join(&mint_hash);
+        assert_eq!(mint.to_vec(), fs::read(mint_path).unwrap());
+        assert_eq!(2, inscriptions.n_files);
+        assert_eq!(3, inscriptions.n_inscriptions);
+        assert_eq!(Some(&2), inscriptions.content_types.get("text/plain"));
+        assert_eq!(Some(&2), inscriptions.brc20_ops.get("mint"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,475 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use serde_json::{json, Value};
+
+use crate::blockchain::parser::types::CoinType;
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::script::ScriptPattern;
+use crate::blockchain::proto::target::{self, U256};
+use crate::blockchain::proto::tx::{EvaluatedTx, EvaluatedTxOut, TxInput};
+use crate::blockchain::proto::Hashed;
+use crate::callbacks::common::{
+    base_units, create_file as create_file_with, finish_file, wtx_hash, Compression, OutputFile,
+};
+use crate::callbacks::Callback;
+use crate::common::utils;
+use crate::errors::{OpError, OpResult};
+
+const WRITER_CAPACITY: usize = 8 * 1024 * 1024;
+const GZIP_LEVEL: u32 = 6;
+
+fn create_file(path: &Path, gzip: bool) -> OpResult<Box<dyn OutputFile>> {
+    let compression = if gzip {
+        Compression::Gzip
+    } else {
+        Compression::None
+    };
+    create_file_with(path, compression, GZIP_LEVEL, WRITER_CAPACITY)
+}
+
+/// Writes the value as a single line
+fn write_line(writer: &mut dyn OutputFile, value: &Value) -> OpResult<()> {
+    serde_json::to_writer(&mut *writer, value)
+        .map_err(|e| OpError::from(format!("Unable to serialize JSON: {}", e)))?;
+    writer.write_all(b"\n")?;
+    Ok(())
+}
+
+/// Dumps blocks and transactions as newline-delimited JSON
+pub struct JsonDump {
+    output_dir: PathBuf,
+    gzip: bool,
+    // Number of blocks per file, everything goes into a single file if None
+    split_every: Option<u64>,
+    genesis_bits: u32,
+    chainwork: Option<U256>,
+
+    // Writers for blocks and transactions of the current part, opened on the first block
+    writers: Option<(Box<dyn OutputFile>, Box<dyn OutputFile>)>,
+    part: Option<u64>,
+    n_files: u64,
+
+    start_height: u64,
+    end_height: u64,
+    tx_count: u64,
+    in_count: u64,
+    out_count: u64,
+}
+
+impl Callback for JsonDump {
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b>
+    where
+        Self: Sized,
+    {
+        SubCommand::with_name("jsondump")
+            .about("Dumps the whole blockchain into newline-delimited JSON files")
+            .version("0.1")
+            .arg(
+                Arg::with_name("output-dir")
+                    .long("output-dir")
+                    .value_name("PATH")
+                    .help(
+                        "Folder to store blocks.jsonl and transactions.jsonl, created if necessary",
+                    )
+                    .takes_value(true)
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name("gzip")
+                    .long("gzip")
+                    .help("Compress the files with gzip (*.jsonl.gz)"),
+            )
+            .arg(
+                Arg::with_name("split-every")
+                    .long("split-every")
+                    .value_name("N_BLOCKS")
+                    .help("Start new numbered files every N blocks, e.g. blocks.00001.jsonl")
+                    .takes_value(true),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let gzip = matches.is_present("gzip");
+        if gzip && cfg!(not(feature = "gzip")) {
+            return Err(OpError::from(String::from(
+                "--gzip is not available, the parser was built without the `gzip` feature.",
+            )));
+        }
+        let split_every = match matches.value_of("split-every") {
+            Some(_) => match value_t!(matches, "split-every", u64) {
+                Ok(n) if n > 0 => Some(n),
+                _ => {
+                    return Err(OpError::from(String::from(
+                        "--split-every must be a positive number of blocks.",
+                    )))
+                }
+            },
+            None => None,
+        };
+        let cb = JsonDump {
+            output_dir: PathBuf::from(matches.value_of("output-dir").unwrap()),
+            gzip,
+            split_every,
+            genesis_bits: 0,
+            chainwork: None,
+            writers: None,
+            part: None,
+            n_files: 0,
+            start_height: 0,
+            end_height: 0,
+            tx_count: 0,
+            in_count: 0,
+            out_count: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, coin_type: &CoinType, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        self.genesis_bits = coin_type.genesis_bits;
+        // Chain work is only known if the dump starts at the genesis block
+        self.chainwork = if block_height == 0 {
+            Some(U256::ZERO)
+        } else {
+            None
+        };
+        fs::create_dir_all(&self.output_dir)?;
+        info!(target: "callback", "Using `jsondump` with output dir: {} ...", &self.output_dir.display());
+        Ok(())
+    }
+
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<()> {
+        let part = self.split_every.map(|n| block_height / n);
+        if self.writers.is_none() || part != self.part {
+            self.finish_files()?;
+            self.open_files(part)?;
+        }
+        if let Some(chainwork) = self.chainwork.as_mut() {
+            let target = U256::from_compact(block.header.value.bits).unwrap_or_default();
+            *chainwork = *chainwork + target.work();
+        }
+        let block_json = block.as_json(block_height, self.genesis_bits, self.chainwork);
+        let (block_writer, tx_writer) = self.writers.as_mut().unwrap();
+        write_line(block_writer.as_mut(), &block_json)?;
+
+        let block_hash = utils::arr_to_hex_swapped(&block.header.hash);
+        for tx in &block.txs {
+            write_line(tx_writer.as_mut(), &tx.as_json(&block_hash, block_height))?;
+            self.in_count += tx.value.in_count.value;
+            self.out_count += tx.value.out_count.value;
+        }
+        self.tx_count += block.tx_count.value;
+        Ok(())
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        self.end_height = block_height;
+        self.finish_files()?;
+
+        info!(target: "callback", "Done.\nDumped all {} blocks into {} files:\n\
+                                   \t-> transactions: {:9}\n\
+                                   \t-> inputs:       {:9}\n\
+                                   \t-> outputs:      {:9}",
+             self.end_height, self.n_files, self.tx_count, self.in_count, self.out_count);
+        Ok(())
+    }
+}
+
+impl JsonDump {
+    /// Returns the path of e.g. `blocks.jsonl` or `blocks.00001.jsonl.gz`
+    fn file_path(&self, name: &str, part: Option<u64>) -> PathBuf {
+        let mut file_name = String::from(name);
+        if let Some(part) = part {
+            file_name.push_str(&format!(".{:05}", part));
+        }
+        file_name.push_str(".jsonl");
+        if self.gzip {
+            file_name.push_str(".gz");
+        }
+        self.output_dir.join(file_name)
+    }
+
+    fn open_files(&mut self, part: Option<u64>) -> OpResult<()> {
+        let blocks_path = self.file_path("blocks", part);
+        let txs_path = self.file_path("transactions", part);
+        debug!(target: "callback", "Writing to {} and {}", blocks_path.display(), txs_path.display());
+        self.writers = Some((
+            create_file(&blocks_path, self.gzip)?,
+            create_file(&txs_path, self.gzip)?,
+        ));
+        self.part = part;
+        self.n_files += 2;
+        Ok(())
+    }
+
+    fn finish_files(&mut self) -> OpResult<()> {
+        if let Some((block_writer, tx_writer)) = self.writers.take() {
+            finish_file(block_writer)?;
+            finish_file(tx_writer)?;
+        }
+        Ok(())
+    }
+}
+
+impl Block {
+    #[inline]
+    pub(super) fn as_json(
+        &self,
+        block_height: u64,
+        genesis_bits: u32,
+        chainwork: Option<U256>,
+    ) -> Value {
+        let bits = self.header.value.bits;
+        json!({
+            "hash": utils::arr_to_hex_swapped(&self.header.hash),
+            "blockHeight": block_height,
+            "version": self.header.value.version,
+            "size": self.size,
+            "previousHash": utils::arr_to_hex_swapped(&self.header.value.prev_hash),
+            "merkleRootHash": utils::arr_to_hex_swapped(&self.header.value.merkle_root),
+            "timestamp": self.header.value.timestamp,
+            "nBits": bits,
+            "difficulty": target::difficulty(bits, genesis_bits),
+            "target": U256::from_compact(bits).map(|target| target.to_hex()),
+            "chainwork": chainwork.map(|work| work.to_hex()),
+            "txCount": self.tx_count.value,
+            "nNonce": self.header.value.nonce
+        })
+    }
+}
+
+impl Hashed<EvaluatedTx> {
+    #[inline]
+    pub(super) fn as_json(&self, block_hash: &str, block_height: u64) -> Value {
+        let txid_str = utils::arr_to_hex_swapped(&self.hash);
+        let inputs: Vec<Value> = self
+            .value
+            .inputs
+            .iter()
+            .enumerate()
+            .map(|(i, input)| input.as_json(&txid_str, i as u32))
+            .collect();
+        let outputs: Vec<Value> = self
+            .value
+            .outputs
+            .iter()
+            .enumerate()
+            .map(|(i, output)| output.as_json(&txid_str, i as u32))
+            .collect();
+        json!({
+            "txHash": txid_str,
+            "wtxHash": wtx_hash(self),
+            "hasWitness": self.value.has_witness(),
+            "blockHash": block_hash,
+            "blockHeight": block_height,
+            "version": self.value.version,
+            "lockTime": self.value.locktime,
+            "isCoinbase": self.value.is_coinbase(),
+            "inputCount": self.value.in_count.value,
+            "txInputs": inputs,
+            "outputCount": self.value.out_count.value,
+            "txOutputs": outputs
+        })
+    }
+}
+
+impl TxInput {
+    #[inline]
+    fn as_json(&self, txid: &str, index: u32) -> Value {
+        json!({
+            "txHash": txid,
+            "hashPrevOut": utils::arr_to_hex_swapped(&self.outpoint.txid),
+            "indexPrevOut": self.outpoint.index,
+            "indexIn": index,
+            "scriptSig": utils::arr_to_hex(&self.script_sig),
+            "sequenceNumber": self.seq_no
+        })
+    }
+}
+
+impl EvaluatedTxOut {
+    #[inline]
+    fn as_json(&self, txid: &str, index: u32) -> Value {
+        let mut json = json!({
+            "txHash": txid,
+            "indexOut": index,
+            "value": base_units(self.out.value),
+            "scriptPubKey": utils::arr_to_hex(&self.out.script_pubkey),
+            "address": self.script.address,
+            "scriptType": self.script.pattern.type_name()
+        });
+        if let ScriptPattern::DataOutput(_) = self.script.pattern {
+            json["dataHex"] = Value::from(utils::arr_to_hex(&self.data_output_payload()));
+        }
+        json
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+    use crate::blockchain::parser::types::Bitcoin;
+    use crate::callbacks::common::read_file;
+    use crate::callbacks::fixtures::{new_block, new_chain, new_tx, new_tx_to, SCRIPT_A};
+
+    // OP_RETURN "hello world"
+    const DATA_SCRIPT: &str = "6a0b68656c6c6f20776f726c64";
+
+    fn temp_dir(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("blockparser-{}-{}", name, process::id()))
+    }
+
+    fn read_lines(path: &Path) -> Vec<Value> {
+        String::from_utf8(read_file(path))
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_tx_json() {
+        let coinbase = new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[5000000000]);
+        let data_tx = new_tx_to(
+            &[(coinbase.hash, 0)],
+            &[(0, DATA_SCRIPT), (4999990000, SCRIPT_A)],
+        );
+        let block = new_block(vec![coinbase, data_tx]);
+        let block_hash = utils::arr_to_hex_swapped(&block.header.hash);
+
+        let json = block.txs[0].as_json(&block_hash, 0);
+        assert_eq!(Value::Bool(true), json["isCoinbase"]);
+        assert_eq!(json!("0".repeat(64)), json["wtxHash"]);
+        assert_eq!(json!(block_hash), json["blockHash"]);
+        assert_eq!(json!("0".repeat(64)), json["txInputs"][0]["hashPrevOut"]);
+        assert_eq!(json!(0xFFFFFFFFu32), json["txInputs"][0]["indexPrevOut"]);
+        assert_eq!(
+            json!({
+                "txHash": utils::arr_to_hex_swapped(&block.txs[0].hash),
+                "indexOut": 0,
+                "value": 5000000000u64,
+                "scriptPubKey": SCRIPT_A,
+                "address": "12higDjoCCNXSA95xZMWUdPvXNmkAduhWv",
+                "scriptType": "pubkeyhash"
+            }),
+            json["txOutputs"][0]
+        );
+
+        let json = block.txs[1].as_json(&block_hash, 0);
+        assert_eq!(Value::Bool(false), json["isCoinbase"]);
+        assert_eq!(json!(2), json["outputCount"]);
+        let data_out = &json["txOutputs"][0];
+        assert_eq!(json!(0), data_out["value"]);
+        assert_eq!(json!(DATA_SCRIPT), data_out["scriptPubKey"]);
+        assert_eq!(Value::Null, data_out["address"]);
+        assert_eq!(json!("nulldata"), data_out["scriptType"]);
+        assert_eq!(json!("68656c6c6f20776f726c64"), data_out["dataHex"]);
+        assert!(json["txOutputs"][1].get("dataHex").is_none());
+    }
+
+    #[test]
+    fn test_block_json() {
+        let block = new_block(vec![new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[5000000000])]);
+        let json = block.as_json(7, 0x1d00ffff, Some(U256::from_u64(0x100010001)));
+        assert_eq!(json!(7), json["blockHeight"]);
+        assert_eq!(json!(1), json["txCount"]);
+        assert_eq!(json!(0x1d00ffff), json["nBits"]);
+        assert_eq!(json!(1.0), json["difficulty"]);
+        assert_eq!(
+            json!("00000000ffff0000000000000000000000000000000000000000000000000000"),
+            json["target"]
+        );
+        assert_eq!(
+            json!(U256::from_u64(0x100010001).to_hex()),
+            json["chainwork"]
+        );
+        assert_eq!(Value::Null, block.as_json(7, 0x1d00ffff, None)["chainwork"]);
+    }
+
+    #[test]
+    fn test_file_path() {
+        let matches = JsonDump::build_subcommand().get_matches_from(vec![
+            "jsondump",
+            "--output-dir",
+            "/tmp/dump",
+        ]);
+        let mut dump = JsonDump::new(&matches).unwrap();
+        assert_eq!(
+            PathBuf::from("/tmp/dump/blocks.jsonl"),
+            dump.file_path("blocks", None)
+        );
+        dump.gzip = true;
+        assert_eq!(
+            PathBuf::from("/tmp/dump/transactions.00001.jsonl.gz"),
+            dump.file_path("transactions", Some(1))
+        );
+
+        let matches = JsonDump::build_subcommand().get_matches_from(vec![
+            "jsondump",
+            "--output-dir",
+            "/tmp/dump",
+            "--split-every",
+            "0",
+        ]);
+        assert!(JsonDump::new(&matches).is_err());
+    }
+
+    #[test]
+    fn test_dump() {
+        let dir = temp_dir("jsondump");
+        let mut args = vec![
+            "jsondump",
+            "--output-dir",
+            dir.to_str().unwrap(),
+            "--split-every",
+            "2",
+        ];
+        if cfg!(feature = "gzip") {
+            args.push("--gzip");
+        }
+        let matches = JsonDump::build_subcommand().get_matches_from(args);
+        let coinbase_0 = new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[5000000000]);
+        let data_tx = new_tx_to(&[(coinbase_0.hash, 0)], &[(0, DATA_SCRIPT)]);
+        let chain = new_chain(vec![
+            vec![coinbase_0],
+            vec![new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[5000000001]), data_tx],
+            vec![new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[5000000002])],
+        ]);
+
+        let mut dump = JsonDump::new(&matches).unwrap();
+        dump.on_start(&CoinType::from(Bitcoin), 0).unwrap();
+        for (height, block) in chain.iter().enumerate() {
+            dump.on_block(block, height as u64).unwrap();
+        }
+        dump.on_complete(2).unwrap();
+        assert_eq!(4, dump.n_files);
+
+        let blocks = read_lines(&dump.file_path("blocks", Some(0)));
+        assert_eq!(2, blocks.len());
+        assert_eq!(json!(1), blocks[1]["blockHeight"]);
+        assert_eq!(json!(blocks[0]["hash"]), blocks[1]["previousHash"]);
+        let txs = read_lines(&dump.file_path("transactions", Some(0)));
+        assert_eq!(3, txs.len());
+        assert_eq!(json!("nulldata"), txs[2]["txOutputs"][0]["scriptType"]);
+
+        let blocks = read_lines(&dump.file_path("blocks", Some(1)));
+        assert_eq!(1, blocks.len());
+        assert_eq!(json!(2), blocks[0]["blockHeight"]);
+        // 3 blocks with the minimum difficulty
+        assert_eq!(
+            json!(U256::from_u64(3 * 0x100010001).to_hex()),
+            blocks[0]["chainwork"]
+        );
+        let txs = read_lines(&dump.file_path("transactions", Some(1)));
+        assert_eq!(1, txs.len());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
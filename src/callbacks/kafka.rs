@@ -0,0 +1,415 @@
+use std::str::FromStr;
+use std::time::Duration;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use serde_json::Value;
+
+use crate::blockchain::parser::types::CoinType;
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::target::U256;
+use crate::callbacks::Callback;
+use crate::common::kafka::{Producer, ProducerConfig};
+use crate::common::{msgpack, utils};
+use crate::errors::{OpError, OpResult};
+
+const DEFAULT_TOPIC_BLOCKS: &str = "blocks";
+const DEFAULT_TOPIC_TXS: &str = "transactions";
+const DEFAULT_TIMEOUT: u64 = 30;
+// Messages waiting for acknowledgements, `on_block()` blocks while they exceed this size
+const MAX_BUFFERED_BYTES: usize = 64 * 1024 * 1024;
+
+/// Destination of the messages, implemented by the Kafka producer and mocked in tests
+pub trait MessageProducer {
+    /// Queues a message, blocks while the queue is full
+    fn send(&mut self, topic: &str, key: &[u8], payload: &[u8]) -> OpResult<()>;
+    /// Waits until all queued messages are acknowledged
+    fn flush(&mut self, timeout: Duration) -> OpResult<()>;
+}
+
+impl MessageProducer for Producer {
+    fn send(&mut self, topic: &str, key: &[u8], payload: &[u8]) -> OpResult<()> {
+        Producer::send(self, topic, key, payload).map_err(|e| {
+            OpError::from(e).join_msg(&format!("Unable to deliver messages to `{}`.", topic))
+        })
+    }
+
+    fn flush(&mut self, timeout: Duration) -> OpResult<()> {
+        Ok(Producer::flush(self, timeout)?)
+    }
+}
+
+/// Message key, transactions use the key of their block unless keyed by txid
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum KeyBy {
+    BlockHash,
+    Height,
+    TxId,
+}
+
+impl FromStr for KeyBy {
+    type Err = OpError;
+
+    fn from_str(s: &str) -> OpResult<Self> {
+        match s {
+            "blockhash" => Ok(KeyBy::BlockHash),
+            "height" => Ok(KeyBy::Height),
+            "txid" => Ok(KeyBy::TxId),
+            _ => Err(OpError::from(format!("Invalid key `{}`.", s))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Json,
+    MsgPack,
+}
+
+impl Format {
+    fn serialize(&self, value: &Value) -> Vec<u8> {
+        match self {
+            Format::Json => serde_json::to_vec(value).unwrap(),
+            Format::MsgPack => msgpack::to_vec(value),
+        }
+    }
+}
+
+/// Streams blocks and transactions into Kafka topics, one message each.
+/// Messages have the same fields as the `jsondump` lines.
+pub struct Kafka {
+    brokers: String,
+    topic_blocks: String,
+    topic_txs: String,
+    key_by: KeyBy,
+    format: Format,
+    timeout: Duration,
+    genesis_bits: u32,
+    chainwork: Option<U256>,
+    // Connected in on_start
+    producer: Option<Box<dyn MessageProducer>>,
+
+    start_height: u64,
+    end_height: u64,
+    tx_count: u64,
+}
+
+impl Callback for Kafka {
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b>
+    where
+        Self: Sized,
+    {
+        SubCommand::with_name("kafka")
+            .about("Streams blocks and transactions into Kafka topics")
+            .version("0.1")
+            .arg(
+                Arg::with_name("brokers")
+                    .long("brokers")
+                    .value_name("HOST:PORT,...")
+                    .help("Comma separated list of bootstrap brokers")
+                    .takes_value(true)
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name("topic-blocks")
+                    .long("topic-blocks")
+                    .value_name("TOPIC")
+                    .help("Topic for block messages (default: blocks)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("topic-txs")
+                    .long("topic-txs")
+                    .value_name("TOPIC")
+                    .help("Topic for transaction messages (default: transactions)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("key-by")
+                    .long("key-by")
+                    .value_name("KEY")
+                    .help("Message key, transactions use the key of their block unless `txid` is given (default: blockhash)")
+                    .possible_values(&["blockhash", "height", "txid"])
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("format")
+                    .long("format")
+                    .value_name("FORMAT")
+                    .help("Message encoding (default: json)")
+                    .possible_values(&["json", "msgpack"])
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("timeout")
+                    .long("timeout")
+                    .value_name("SECONDS")
+                    .help("Timeout for broker requests and the final flush (default: 30)")
+                    .takes_value(true),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let format = match matches.value_of("format") {
+            Some("msgpack") => Format::MsgPack,
+            _ => Format::Json,
+        };
+        let timeout = match matches.value_of("timeout") {
+            Some(_) => match value_t!(matches, "timeout", u64) {
+                Ok(secs) if secs > 0 => secs,
+                _ => {
+                    return Err(OpError::from(String::from(
+                        "--timeout must be a positive number of seconds.",
+                    )))
+                }
+            },
+            None => DEFAULT_TIMEOUT,
+        };
+        let cb = Kafka {
+            brokers: String::from(matches.value_of("brokers").unwrap()),
+            topic_blocks: String::from(
+                matches
+                    .value_of("topic-blocks")
+                    .unwrap_or(DEFAULT_TOPIC_BLOCKS),
+            ),
+            topic_txs: String::from(matches.value_of("topic-txs").unwrap_or(DEFAULT_TOPIC_TXS)),
+            key_by: KeyBy::from_str(matches.value_of("key-by").unwrap_or("blockhash"))?,
+            format,
+            timeout: Duration::from_secs(timeout),
+            genesis_bits: 0,
+            chainwork: None,
+            producer: None,
+            start_height: 0,
+            end_height: 0,
+            tx_count: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, coin_type: &CoinType, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        self.genesis_bits = coin_type.genesis_bits;
+        // Chain work is only known if the stream starts at the genesis block
+        self.chainwork = if block_height == 0 {
+            Some(U256::ZERO)
+        } else {
+            None
+        };
+        if self.producer.is_none() {
+            let config = ProducerConfig::new(&self.brokers, self.timeout, MAX_BUFFERED_BYTES)?;
+            let producer = Producer::connect(config, &[&self.topic_blocks, &self.topic_txs])
+                .map_err(|e| {
+                    OpError::from(e)
+                        .join_msg(&format!("Unable to connect to Kafka on {}.", &self.brokers))
+                })?;
+            self.producer = Some(Box::new(producer));
+        }
+        info!(target: "callback", "Using `kafka` with brokers: {} ...", &self.brokers);
+        Ok(())
+    }
+
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<()> {
+        if let Some(chainwork) = self.chainwork.as_mut() {
+            let target = U256::from_compact(block.header.value.bits).unwrap_or_default();
+            *chainwork = *chainwork + target.work();
+        }
+        let producer = self
+            .producer
+            .as_mut()
+            .ok_or_else(|| OpError::from(String::from("Not connected to Kafka.")))?;
+
+        let block_hash = utils::arr_to_hex_swapped(&block.header.hash);
+        let block_key = match self.key_by {
+            KeyBy::Height => block_height.to_string(),
+            _ => block_hash.clone(),
+        };
+        let block_json = block.as_json(block_height, self.genesis_bits, self.chainwork);
+        producer.send(
+            &self.topic_blocks,
+            block_key.as_bytes(),
+            &self.format.serialize(&block_json),
+        )?;
+        for tx in &block.txs {
+            let tx_json = tx.as_json(&block_hash, block_height);
+            let payload = self.format.serialize(&tx_json);
+            match self.key_by {
+                KeyBy::TxId => {
+                    let txid = utils::arr_to_hex_swapped(&tx.hash);
+                    producer.send(&self.topic_txs, txid.as_bytes(), &payload)?;
+                }
+                _ => producer.send(&self.topic_txs, block_key.as_bytes(), &payload)?,
+            }
+        }
+        self.tx_count += block.tx_count.value;
+        Ok(())
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        self.end_height = block_height;
+        if let Some(producer) = self.producer.as_mut() {
+            producer.flush(self.timeout).map_err(|e| {
+                e.join_msg("Unable to flush the Kafka producer, messages might be missing.")
+            })?;
+        }
+        info!(target: "callback", "Done.\nStreamed all {} blocks:\n\
+                                   \t-> transactions: {:9}",
+             self.end_height, self.tx_count);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::blockchain::parser::types::Bitcoin;
+    use crate::callbacks::fixtures::{new_chain, new_tx, new_tx_to, SCRIPT_A};
+
+    type Messages = Rc<RefCell<Vec<(String, String, Vec<u8>)>>>;
+
+    /// Stores all messages, `send()` fails once `fail_after` messages were sent
+    struct MockProducer {
+        messages: Messages,
+        fail_after: Option<usize>,
+        flushed: Rc<RefCell<bool>>,
+    }
+
+    impl MessageProducer for MockProducer {
+        fn send(&mut self, topic: &str, key: &[u8], payload: &[u8]) -> OpResult<()> {
+            if self.fail_after == Some(self.messages.borrow().len()) {
+                return Err(OpError::from(String::from("broker unreachable")));
+            }
+            self.messages.borrow_mut().push((
+                String::from(topic),
+                String::from_utf8(key.to_vec()).unwrap(),
+                payload.to_vec(),
+            ));
+            Ok(())
+        }
+
+        fn flush(&mut self, _: Duration) -> OpResult<()> {
+            *self.flushed.borrow_mut() = true;
+            Ok(())
+        }
+    }
+
+    fn new_kafka(args: &[&str], fail_after: Option<usize>) -> (Kafka, Messages, Rc<RefCell<bool>>) {
+        let mut all_args = vec!["kafka", "--brokers", "localhost:9092"];
+        all_args.extend_from_slice(args);
+        let matches = Kafka::build_subcommand().get_matches_from(all_args);
+        let mut kafka = Kafka::new(&matches).unwrap();
+        let messages = Messages::default();
+        let flushed = Rc::new(RefCell::new(false));
+        kafka.producer = Some(Box::new(MockProducer {
+            messages: messages.clone(),
+            fail_after,
+            flushed: flushed.clone(),
+        }));
+        (kafka, messages, flushed)
+    }
+
+    fn chain() -> Vec<Block> {
+        let coinbase_0 = new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[5000000000]);
+        let spending_tx = new_tx_to(&[(coinbase_0.hash, 0)], &[(4999990000, SCRIPT_A)]);
+        new_chain(vec![
+            vec![coinbase_0],
+            vec![
+                new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[5000010000]),
+                spending_tx,
+            ],
+        ])
+    }
+
+    #[test]
+    fn test_stream() {
+        let chain = chain();
+        let (mut kafka, messages, flushed) = new_kafka(&["--topic-txs", "txs"], None);
+        kafka.on_start(&CoinType::from(Bitcoin), 0).unwrap();
+        for (height, block) in chain.iter().enumerate() {
+            kafka.on_block(block, height as u64).unwrap();
+        }
+        assert!(!*flushed.borrow());
+        kafka.on_complete(1).unwrap();
+        assert!(*flushed.borrow());
+
+        let messages = messages.borrow();
+        let topics: Vec<&str> = messages.iter().map(|m| m.0.as_str()).collect();
+        assert_eq!(vec!["blocks", "txs", "blocks", "txs", "txs"], topics);
+        let block_hash = utils::arr_to_hex_swapped(&chain[1].header.hash);
+        // Transactions are keyed by their block hash
+        assert!(messages[2..].iter().all(|m| m.1 == block_hash));
+
+        let block: Value = serde_json::from_slice(&messages[2].2).unwrap();
+        assert_eq!(json!(1), block["blockHeight"]);
+        assert_eq!(json!(2), block["txCount"]);
+        assert_eq!(
+            json!(U256::from_u64(2 * 0x100010001).to_hex()),
+            block["chainwork"]
+        );
+        let tx: Value = serde_json::from_slice(&messages[4].2).unwrap();
+        assert_eq!(
+            json!(utils::arr_to_hex_swapped(&chain[1].txs[1].hash)),
+            tx["txHash"]
+        );
+        assert_eq!(json!(block_hash), tx["blockHash"]);
+        assert_eq!(
+            json!("12higDjoCCNXSA95xZMWUdPvXNmkAduhWv"),
+            tx["txOutputs"][0]["address"]
+        );
+    }
+
+    #[test]
+    fn test_keys_and_format() {
+        let chain = chain();
+        for (key_by, format) in [("height", "msgpack"), ("txid", "json")] {
+            let (mut kafka, messages, _) =
+                new_kafka(&["--key-by", key_by, "--format", format], None);
+            kafka.on_start(&CoinType::from(Bitcoin), 1).unwrap();
+            kafka.on_block(&chain[1], 1).unwrap();
+            let messages = messages.borrow();
+            let keys: Vec<&str> = messages.iter().map(|m| m.1.as_str()).collect();
+            let block_json = chain[1].as_json(1, CoinType::from(Bitcoin).genesis_bits, None);
+            let block_hash = utils::arr_to_hex_swapped(&chain[1].header.hash);
+            let tx_json = chain[1].txs[1].as_json(&block_hash, 1);
+            if key_by == "height" {
+                assert_eq!(vec!["1", "1", "1"], keys);
+                assert_eq!(msgpack::to_vec(&block_json), messages[0].2);
+                assert_eq!(msgpack::to_vec(&tx_json), messages[2].2);
+            } else {
+                let txid = utils::arr_to_hex_swapped(&chain[1].txs[1].hash);
+                assert_eq!(block_hash, keys[0]);
+                assert_eq!(txid, keys[2]);
+                assert_eq!(serde_json::to_vec(&tx_json).unwrap(), messages[2].2);
+                // Unknown chainwork when starting in the middle of the chain
+                assert_eq!(Value::Null, block_json["chainwork"]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_producer_error() {
+        let chain = chain();
+        let (mut kafka, messages, _) = new_kafka(&[], Some(1));
+        kafka.on_start(&CoinType::from(Bitcoin), 0).unwrap();
+        assert!(kafka.on_block(&chain[0], 0).is_err());
+        assert_eq!(1, messages.borrow().len());
+    }
+
+    #[test]
+    fn test_invalid_timeout() {
+        let matches = Kafka::build_subcommand().get_matches_from(vec![
+            "kafka",
+            "--brokers",
+            "localhost:9092",
+            "--timeout",
+            "0",
+        ]);
+        assert!(Kafka::new(&matches).is_err());
+    }
+}
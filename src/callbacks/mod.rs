@@ -1,15 +1,99 @@
+use std::sync::Arc;
+use std::time::Duration;
+
 use clap::{App, ArgMatches};
 
+use crate::blockchain::parser::progress::Metrics;
 use crate::blockchain::parser::types::CoinType;
 use crate::blockchain::proto::block::Block;
-use crate::errors::OpResult;
+use crate::blockchain::proto::tx::EvaluatedTx;
+use crate::blockchain::proto::Hashed;
+use crate::callbacks::addressindex::AddressIndex;
+use crate::callbacks::adoption::Adoption;
+use crate::callbacks::anomalies::Anomalies;
+use crate::callbacks::balances::Balances;
+use crate::callbacks::bench::Bench;
+use crate::callbacks::blockchair::Blockchair;
+use crate::callbacks::clickhouse::ClickHouse;
+use crate::callbacks::cluster::Cluster;
+use crate::callbacks::coinbase::Coinbase;
+use crate::callbacks::coindays::CoinDays;
+use crate::callbacks::csvdump::CsvDump;
+use crate::callbacks::dust::Dust;
+use crate::callbacks::elastic::Elastic;
+use crate::callbacks::etldump::EtlDump;
+use crate::callbacks::feestats::FeeStats;
+use crate::callbacks::filter::Filter;
+use crate::callbacks::graph::Graph;
+use crate::callbacks::headers::Headers;
+use crate::callbacks::inscriptions::Inscriptions;
+use crate::callbacks::jsondump::JsonDump;
+use crate::callbacks::kafka::Kafka;
+use crate::callbacks::mongo::Mongo;
+use crate::callbacks::multi::Multi;
+use crate::callbacks::multisig::MultiSigDump;
+use crate::callbacks::neo4j::Neo4j;
+use crate::callbacks::opreturnanalyze::OpReturnAnalyze;
+use crate::callbacks::parquetdump::ParquetDump;
+use crate::callbacks::postgres::Postgres;
+use crate::callbacks::rawdump::RawDump;
+use crate::callbacks::redis::Redis;
+use crate::callbacks::richlist::RichList;
+#[cfg(feature = "sqlite")]
+use crate::callbacks::sqlite::Sqlite;
+use crate::callbacks::stats::SimpleStats;
+use crate::callbacks::supplyaudit::SupplyAudit;
+use crate::callbacks::timeseries::TimeSeries;
+use crate::callbacks::unspentcsvdump::UnspentCsvDump;
+use crate::callbacks::utxosnapshot::UtxoSnapshot;
+use crate::callbacks::walletscan::WalletScan;
+use crate::common::prometheus::Registry;
+use crate::errors::{OpError, OpErrorKind, OpResult};
 
+pub mod address_tracker;
+pub mod addressindex;
+pub mod adoption;
+pub mod anomalies;
 pub mod balances;
+pub mod bench;
+pub mod blockchair;
+pub mod clickhouse;
+pub mod cluster;
+pub mod coinbase;
+pub mod coindays;
 mod common;
 pub mod csvdump;
+pub mod dust;
+pub mod elastic;
+pub mod etldump;
+pub mod feestats;
+pub mod filter;
+#[cfg(test)]
+pub(crate) mod fixtures;
+pub mod graph;
+pub mod headers;
+pub mod inscriptions;
+pub mod jsondump;
+pub mod kafka;
 pub mod mongo;
+pub mod multi;
+pub mod multisig;
+pub mod neo4j;
+pub mod opreturnanalyze;
+pub mod parquetdump;
+pub mod postgres;
+pub mod rawdump;
+pub mod redis;
+pub mod richlist;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+mod sqlschema;
 pub mod stats;
+pub mod supplyaudit;
+pub mod timeseries;
 pub mod unspentcsvdump;
+pub mod utxosnapshot;
+pub mod walletscan;
 
 /// Implement this trait for a custom Callback.
 /// The parser ensures that the blocks arrive in the correct order.
@@ -28,12 +112,216 @@ pub trait Callback {
     where
         Self: Sized;
 
+    /// Gets called before on_start() with the counters of the parser, which are updated after
+    /// each block (e.g. to report the progress).
+    fn set_metrics(&mut self, _metrics: Arc<Metrics>) {}
+
+    /// Gets called before on_start() if `--metrics-listen` is given. Gauges and counters
+    /// registered here are exported along with the counters of the parser.
+    fn register_metrics(&mut self, _registry: &mut Registry) {}
+
+    /// Gets called before on_start() with `--use-undo-files`. Inputs passed to on_block() then
+    /// have `prevout` set, except those of coinbases, so the spent outputs don't have to be
+    /// tracked by the callback.
+    fn set_prevouts_attached(&mut self) {}
+
     /// Gets called shortly before the blocks are parsed.
     fn on_start(&mut self, coin_type: &CoinType, block_height: u64) -> OpResult<()>;
 
+    /// Gets called right after on_start().
+    /// Returns the height the callback wants to continue from (e.g. to resume a previous run),
+    /// all blocks below it are skipped. Defaults to the start height of the parser.
+    fn resume_height(&self) -> Option<u64> {
+        None
+    }
+
+    /// Returns false if the callback only reads block headers. The parser then skips the
+    /// transactions, blocks passed to on_block() have no `txs` (`tx_count` is still set).
+    fn wants_transactions(&self) -> bool {
+        true
+    }
+
+    /// Returns false if the callback doesn't read the witness stacks. The parser then drops them
+    /// after deserialization to save memory, wtxid and weight are still available.
+    fn wants_witnesses(&self) -> bool {
+        true
+    }
+
+    /// Returns false if the callback doesn't read the evaluated output scripts (`script.pattern`
+    /// and `script.address`). The parser then skips their evaluation, the pattern of all outputs
+    /// is `NotEvaluated`. The raw `script_pubkey` is always available.
+    fn wants_script_evaluation(&self) -> bool {
+        true
+    }
+
+    /// Returns true if the callback reads the time the parser spends in each phase, see
+    /// `parser::timings`. They are only measured then and start from zero with each on_start().
+    fn wants_timings(&self) -> bool {
+        false
+    }
+
+    /// Returns how often the range is parsed, on_start() to on_complete() are called for each
+    /// pass. More than one pass needs a blocks directory, a stream can only be read once.
+    fn passes(&self) -> usize {
+        1
+    }
+
+    /// Returns the interval in which the parser checks the blockchain directory for new blocks
+    /// once it reached the tip, None to stop there. While following the tip, the blocks of a
+    /// reorg are passed to on_block() again from the fork on, so heights may go back and the
+    /// callback has to replace what it has from there.
+    fn follow_interval(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Gets called while following the tip each time the parser caught up, before it waits for
+    /// new blocks. `block_height` is the height after the last block passed to on_block().
+    /// Buffers should be flushed here, so the output is current.
+    fn on_tip(&mut self, _block_height: u64) -> OpResult<()> {
+        Ok(())
+    }
+
     /// Gets called if a new block is available.
     fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<()>;
 
+    /// Gets called after on_block() for each transaction of the block, `index` is its position
+    /// in the block. Callbacks which handle transactions one by one can implement this instead
+    /// of iterating over `block.txs`.
+    fn on_transaction(
+        &mut self,
+        _tx: &Hashed<EvaluatedTx>,
+        _block_height: u64,
+        _index: usize,
+    ) -> OpResult<()> {
+        Ok(())
+    }
+
+    /// Gets called with `--include-orphans` for blocks of a stream which aren't part of the chain
+    /// with the most work, at the height they'd have on their fork. They are never passed to
+    /// on_block() and arrive once the parser dropped them, after the block of the chain at their
+    /// height or at the end.
+    fn on_stale_block(&mut self, _block: &Block, _block_height: u64) -> OpResult<()> {
+        Ok(())
+    }
+
     /// Gets called if the parser has finished and all blocks are handled
     fn on_complete(&mut self, block_height: u64) -> OpResult<()>;
+
+    /// Gets called instead of on_complete() if the parser is stopped by SIGINT or SIGTERM.
+    /// `block_height` is the height after the last block passed to on_block(), like for
+    /// on_complete(), which it calls by default to flush buffers and finalize files.
+    fn on_interrupt(&mut self, block_height: u64) -> OpResult<()> {
+        self.on_complete(block_height)
+    }
+}
+
+/// Passes the block to the callback like the parser does: on_block() first, then
+/// on_transaction() for each of its transactions.
+pub fn deliver_block(
+    callback: &mut dyn Callback,
+    block: &Block,
+    block_height: u64,
+) -> OpResult<()> {
+    callback.on_block(block, block_height)?;
+    for (index, tx) in block.txs.iter().enumerate() {
+        callback.on_transaction(tx, block_height, index)?;
+    }
+    Ok(())
+}
+
+/// Subcommands of all callbacks, in the order they are listed by `--help`
+pub fn subcommands<'a, 'b>() -> Vec<App<'a, 'b>> {
+    #[allow(unused_mut)]
+    let mut subcommands = vec![
+        UnspentCsvDump::build_subcommand(),
+        CsvDump::build_subcommand(),
+        SimpleStats::build_subcommand(),
+        Mongo::build_subcommand(),
+        Postgres::build_subcommand(),
+        JsonDump::build_subcommand(),
+        ParquetDump::build_subcommand(),
+        Kafka::build_subcommand(),
+        ClickHouse::build_subcommand(),
+        UtxoSnapshot::build_subcommand(),
+        Graph::build_subcommand(),
+        Cluster::build_subcommand(),
+        RichList::build_subcommand(),
+        OpReturnAnalyze::build_subcommand(),
+        Inscriptions::build_subcommand(),
+        FeeStats::build_subcommand(),
+        SupplyAudit::build_subcommand(),
+        AddressIndex::build_subcommand(),
+        Adoption::build_subcommand(),
+        Coinbase::build_subcommand(),
+        RawDump::build_subcommand(),
+        Elastic::build_subcommand(),
+        Neo4j::build_subcommand(),
+        Redis::build_subcommand(),
+        EtlDump::build_subcommand(),
+        Blockchair::build_subcommand(),
+        TimeSeries::build_subcommand(),
+        Anomalies::build_subcommand(),
+        MultiSigDump::build_subcommand(),
+        Dust::build_subcommand(),
+        CoinDays::build_subcommand(),
+        Headers::build_subcommand(),
+        Filter::build_subcommand(),
+        WalletScan::build_subcommand(),
+        Balances::build_subcommand(),
+        Bench::build_subcommand(),
+        Multi::build_subcommand(),
+    ];
+    #[cfg(feature = "sqlite")]
+    subcommands.push(Sqlite::build_subcommand());
+    subcommands
+}
+
+/// Instantiates the callback of the subcommand `name` with its arguments
+pub fn new_callback(name: &str, matches: &ArgMatches) -> OpResult<Box<dyn Callback>> {
+    let callback: Box<dyn Callback> = match name {
+        "simplestats" => Box::new(SimpleStats::new(matches)?),
+        "csvdump" => Box::new(CsvDump::new(matches)?),
+        "unspentcsvdump" => Box::new(UnspentCsvDump::new(matches)?),
+        "balances" => Box::new(Balances::new(matches)?),
+        "mongo" => Box::new(Mongo::new(matches)?),
+        "postgres" => Box::new(Postgres::new(matches)?),
+        "jsondump" => Box::new(JsonDump::new(matches)?),
+        "parquetdump" => Box::new(ParquetDump::new(matches)?),
+        "kafka" => Box::new(Kafka::new(matches)?),
+        "clickhouse" => Box::new(ClickHouse::new(matches)?),
+        "utxosnapshot" => Box::new(UtxoSnapshot::new(matches)?),
+        "graph" => Box::new(Graph::new(matches)?),
+        "cluster" => Box::new(Cluster::new(matches)?),
+        "richlist" => Box::new(RichList::new(matches)?),
+        "opreturn-analyze" => Box::new(OpReturnAnalyze::new(matches)?),
+        "inscriptions" => Box::new(Inscriptions::new(matches)?),
+        "feestats" => Box::new(FeeStats::new(matches)?),
+        "supplyaudit" => Box::new(SupplyAudit::new(matches)?),
+        "addressindex" => Box::new(AddressIndex::new(matches)?),
+        "adoption" => Box::new(Adoption::new(matches)?),
+        "coinbase" => Box::new(Coinbase::new(matches)?),
+        "rawdump" => Box::new(RawDump::new(matches)?),
+        "elastic" => Box::new(Elastic::new(matches)?),
+        "neo4j" => Box::new(Neo4j::new(matches)?),
+        "redis" => Box::new(Redis::new(matches)?),
+        "etldump" => Box::new(EtlDump::new(matches)?),
+        "blockchair" => Box::new(Blockchair::new(matches)?),
+        "timeseries" => Box::new(TimeSeries::new(matches)?),
+        "anomalies" => Box::new(Anomalies::new(matches)?),
+        "multisig" => Box::new(MultiSigDump::new(matches)?),
+        "dust" => Box::new(Dust::new(matches)?),
+        "coindays" => Box::new(CoinDays::new(matches)?),
+        "headers" => Box::new(Headers::new(matches)?),
+        "filter" => Box::new(Filter::new(matches)?),
+        "wallet-scan" => Box::new(WalletScan::new(matches)?),
+        "bench" => Box::new(Bench::new(matches)?),
+        #[cfg(feature = "sqlite")]
+        "sqlite" => Box::new(Sqlite::new(matches)?),
+        "multi" => Box::new(Multi::new(matches)?),
+        _ => {
+            return Err(OpError::new(OpErrorKind::InvalidArgsError)
+                .join_msg(&format!("Unknown callback `{}`", name)))
+        }
+    };
+    Ok(callback)
 }
@@ -0,0 +1,538 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+
+use crate::blockchain::parser::progress::Metrics;
+use crate::blockchain::parser::types::CoinType;
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::tx::EvaluatedTx;
+use crate::blockchain::proto::Hashed;
+use crate::callbacks::{self, Callback};
+use crate::common::prometheus::Registry;
+use crate::errors::{OpError, OpErrorKind, OpResult};
+
+/// Runs several callbacks in a single pass over the chain.
+/// Every call is forwarded to all of them in the order given. If one fails, the others get
+/// `on_complete` to flush what they have so far and the first error aborts the run.
+pub struct Multi {
+    callbacks: Vec<Child>,
+}
+
+struct Child {
+    name: String,
+    callback: Box<dyn Callback>,
+    // Blocks below are skipped, see `Callback::resume_height`
+    resume_height: Option<u64>,
+    // on_start() succeeded, so there is something to flush
+    started: bool,
+}
+
+impl Multi {
+    /// Dispatches to the given callbacks with their names, used in error messages
+    pub fn from_callbacks(callbacks: Vec<(String, Box<dyn Callback>)>) -> Self {
+        Multi {
+            callbacks: callbacks
+                .into_iter()
+                .map(|(name, callback)| Child {
+                    name,
+                    callback,
+                    resume_height: None,
+                    started: false,
+                })
+                .collect(),
+        }
+    }
+
+    /// Builds a callback from `<name>[:<args>]`, the args are split at whitespace
+    fn parse_spec(spec: &str) -> OpResult<(String, Box<dyn Callback>)> {
+        let (name, args) = spec.split_once(':').unwrap_or((spec, ""));
+        let invalid = |msg: String| OpError::new(OpErrorKind::InvalidArgsError).join_msg(&msg);
+        if name == "multi" {
+            return Err(invalid(String::from("multi can't be nested")));
+        }
+        let app = callbacks::subcommands()
+            .into_iter()
+            .find(|app| app.get_name() == name)
+            .ok_or_else(|| invalid(format!("Unknown callback `{}` in `{}`", name, spec)))?;
+        let matches = app
+            .setting(AppSettings::NoBinaryName)
+            .get_matches_from_safe(args.split_whitespace())
+            .map_err(|e| invalid(format!("Invalid arguments for {}: {}", name, e.message)))?;
+        Ok((String::from(name), callbacks::new_callback(name, &matches)?))
+    }
+
+    /// Calls `on_complete` of the other started callbacks and returns the error of the failed one
+    fn abort(&mut self, failed: usize, err: OpError, block_height: u64) -> OpError {
+        let failed_name = self.callbacks[failed].name.clone();
+        for (i, child) in self.callbacks.iter_mut().enumerate() {
+            if i == failed || !child.started {
+                continue;
+            }
+            if let Err(e) = child.callback.on_complete(block_height) {
+                error!(target: "multi", "{} failed to complete after {} failed: {}", child.name, failed_name, e);
+            }
+        }
+        OpError::callback(&failed_name, err)
+    }
+
+    /// Completes or interrupts all callbacks, failing ones don't stop the others
+    fn finish(&mut self, block_height: u64, interrupted: bool) -> OpResult<()> {
+        let mut result = Ok(());
+        for child in self.callbacks.iter_mut() {
+            let finished = if interrupted {
+                child.callback.on_interrupt(block_height)
+            } else {
+                child.callback.on_complete(block_height)
+            };
+            if let Err(e) = finished {
+                error!(target: "multi", "{} failed to complete: {}", child.name, e);
+                if result.is_ok() {
+                    result = Err(OpError::callback(&child.name, e));
+                }
+            }
+        }
+        result
+    }
+}
+
+impl Callback for Multi {
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b>
+    where
+        Self: Sized,
+    {
+        SubCommand::with_name("multi")
+            .about("Runs several callbacks in a single pass")
+            .version("0.1")
+            .arg(
+                Arg::with_name("with")
+                    .long("with")
+                    .value_name("CALLBACK:ARGS")
+                    .help("Callback with its arguments, e.g. `csvdump:/out` or `balances:/out2`. Repeat for each one")
+                    .multiple(true)
+                    .number_of_values(1)
+                    .required(true),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let callbacks = matches
+            .values_of("with")
+            .unwrap()
+            .map(Multi::parse_spec)
+            .collect::<OpResult<Vec<_>>>()?;
+        Ok(Multi::from_callbacks(callbacks))
+    }
+
+    fn on_start(&mut self, coin_type: &CoinType, block_height: u64) -> OpResult<()> {
+        for i in 0..self.callbacks.len() {
+            let child = &mut self.callbacks[i];
+            match child.callback.on_start(coin_type, block_height) {
+                Ok(()) => {
+                    child.started = true;
+                    child.resume_height = child.callback.resume_height();
+                }
+                Err(e) => return Err(self.abort(i, e, block_height)),
+            }
+        }
+        Ok(())
+    }
+
+    /// The lowest height any of the callbacks wants to continue from
+    fn resume_height(&self) -> Option<u64> {
+        self.callbacks
+            .iter()
+            .map(|child| child.resume_height)
+            .min()
+            .flatten()
+    }
+
+    fn set_metrics(&mut self, metrics: Arc<Metrics>) {
+        for child in &mut self.callbacks {
+            child.callback.set_metrics(Arc::clone(&metrics));
+        }
+    }
+
+    fn register_metrics(&mut self, registry: &mut Registry) {
+        for child in &mut self.callbacks {
+            child.callback.register_metrics(registry);
+        }
+    }
+
+    fn set_prevouts_attached(&mut self) {
+        for child in &mut self.callbacks {
+            child.callback.set_prevouts_attached();
+        }
+    }
+
+    fn wants_transactions(&self) -> bool {
+        self.callbacks
+            .iter()
+            .any(|child| child.callback.wants_transactions())
+    }
+
+    fn wants_witnesses(&self) -> bool {
+        self.callbacks
+            .iter()
+            .any(|child| child.callback.wants_witnesses())
+    }
+
+    fn wants_script_evaluation(&self) -> bool {
+        self.callbacks
+            .iter()
+            .any(|child| child.callback.wants_script_evaluation())
+    }
+
+    fn wants_timings(&self) -> bool {
+        self.callbacks
+            .iter()
+            .any(|child| child.callback.wants_timings())
+    }
+
+    /// The most passes any of the callbacks asks for, all of them see each pass
+    fn passes(&self) -> usize {
+        self.callbacks
+            .iter()
+            .map(|child| child.callback.passes())
+            .max()
+            .unwrap_or(1)
+    }
+
+    /// The shortest interval of the callbacks following the tip
+    fn follow_interval(&self) -> Option<Duration> {
+        self.callbacks
+            .iter()
+            .filter_map(|child| child.callback.follow_interval())
+            .min()
+    }
+
+    fn on_tip(&mut self, block_height: u64) -> OpResult<()> {
+        for i in 0..self.callbacks.len() {
+            if let Err(e) = self.callbacks[i].callback.on_tip(block_height) {
+                return Err(self.abort(i, e, block_height));
+            }
+        }
+        Ok(())
+    }
+
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<()> {
+        for i in 0..self.callbacks.len() {
+            let child = &mut self.callbacks[i];
+            if child
+                .resume_height
+                .is_some_and(|height| block_height < height)
+            {
+                continue;
+            }
+            if let Err(e) = child.callback.on_block(block, block_height) {
+                return Err(self.abort(i, e, block_height));
+            }
+        }
+        Ok(())
+    }
+
+    fn on_transaction(
+        &mut self,
+        tx: &Hashed<EvaluatedTx>,
+        block_height: u64,
+        index: usize,
+    ) -> OpResult<()> {
+        for i in 0..self.callbacks.len() {
+            let child = &mut self.callbacks[i];
+            if child
+                .resume_height
+                .is_some_and(|height| block_height < height)
+            {
+                continue;
+            }
+            if let Err(e) = child.callback.on_transaction(tx, block_height, index) {
+                return Err(self.abort(i, e, block_height));
+            }
+        }
+        Ok(())
+    }
+
+    fn on_stale_block(&mut self, block: &Block, block_height: u64) -> OpResult<()> {
+        for i in 0..self.callbacks.len() {
+            if let Err(e) = self.callbacks[i]
+                .callback
+                .on_stale_block(block, block_height)
+            {
+                return Err(self.abort(i, e, block_height));
+            }
+        }
+        Ok(())
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        self.finish(block_height, false)
+    }
+
+    fn on_interrupt(&mut self, block_height: u64) -> OpResult<()> {
+        self.finish(block_height, true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::io;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::blockchain::parser::types::Bitcoin;
+    use crate::callbacks::fixtures::{new_block, new_tx};
+    use crate::errors::ErrorCategory;
+
+    type Log = Rc<RefCell<Vec<String>>>;
+
+    /// Records its calls, fails in `on_block` at `fail_at` or in `on_start` if it is `u64::MAX`
+    struct Recorder {
+        name: &'static str,
+        log: Log,
+        fail_at: Option<u64>,
+        resume_height: Option<u64>,
+        wants_transactions: bool,
+        wants_scripts: bool,
+    }
+
+    impl Recorder {
+        fn named(name: &'static str, log: &Log) -> Recorder {
+            Recorder {
+                name,
+                log: Rc::clone(log),
+                fail_at: None,
+                resume_height: None,
+                wants_transactions: true,
+                wants_scripts: true,
+            }
+        }
+
+        fn push(&self, event: String) {
+            self.log
+                .borrow_mut()
+                .push(format!("{} {}", self.name, event));
+        }
+    }
+
+    impl Callback for Recorder {
+        fn build_subcommand<'a, 'b>() -> App<'a, 'b> {
+            SubCommand::with_name("recorder")
+        }
+
+        fn new(_: &ArgMatches) -> OpResult<Self> {
+            unimplemented!()
+        }
+
+        fn on_start(&mut self, _: &CoinType, block_height: u64) -> OpResult<()> {
+            self.push(format!("start {}", block_height));
+            if self.fail_at == Some(u64::MAX) {
+                return Err(OpError::from(String::from("no start")));
+            }
+            Ok(())
+        }
+
+        fn resume_height(&self) -> Option<u64> {
+            self.resume_height
+        }
+
+        fn wants_transactions(&self) -> bool {
+            self.wants_transactions
+        }
+
+        fn wants_script_evaluation(&self) -> bool {
+            self.wants_scripts
+        }
+
+        fn on_block(&mut self, _: &Block, block_height: u64) -> OpResult<()> {
+            self.push(format!("block {}", block_height));
+            if self.fail_at == Some(block_height) {
+                return Err(OpError::from(io::Error::other("disk full")));
+            }
+            Ok(())
+        }
+
+        fn on_transaction(
+            &mut self,
+            _: &Hashed<EvaluatedTx>,
+            block_height: u64,
+            index: usize,
+        ) -> OpResult<()> {
+            self.push(format!("tx {}:{}", block_height, index));
+            Ok(())
+        }
+
+        fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+            self.push(format!("complete {}", block_height));
+            Ok(())
+        }
+    }
+
+    fn dispatch(recorders: Vec<Recorder>) -> Multi {
+        Multi::from_callbacks(
+            recorders
+                .into_iter()
+                .map(|r| (String::from(r.name), Box::new(r) as Box<dyn Callback>))
+                .collect(),
+        )
+    }
+
+    fn run(multi: &mut Multi, blocks: u64) -> OpResult<()> {
+        multi.on_start(&CoinType::from(Bitcoin), 0)?;
+        let block = new_block(Vec::new());
+        for height in 0..blocks {
+            multi.on_block(&block, height)?;
+        }
+        multi.on_complete(blocks)
+    }
+
+    #[test]
+    fn test_call_order() {
+        let log = Log::default();
+        let mut multi = dispatch(vec![Recorder::named("a", &log), Recorder::named("b", &log)]);
+        run(&mut multi, 2).unwrap();
+        assert_eq!(
+            vec![
+                "a start 0",
+                "b start 0",
+                "a block 0",
+                "b block 0",
+                "a block 1",
+                "b block 1",
+                "a complete 2",
+                "b complete 2"
+            ],
+            *log.borrow()
+        );
+    }
+
+    #[test]
+    fn test_error_propagation() {
+        let log = Log::default();
+        let mut failing = Recorder::named("b", &log);
+        failing.fail_at = Some(1);
+        let mut multi = dispatch(vec![
+            Recorder::named("a", &log),
+            failing,
+            Recorder::named("c", &log),
+        ]);
+        let err = run(&mut multi, 3).unwrap_err();
+        assert_eq!("Callback `b` failed: I/O Error: disk full", err.to_string());
+        assert!(matches!(&err.kind, OpErrorKind::Callback { name, .. } if name == "b"));
+        assert_eq!(ErrorCategory::Io, err.category());
+        // c doesn't get the block b failed on, but both others flush
+        assert_eq!(
+            vec!["a block 1", "b block 1", "a complete 1", "c complete 1"],
+            log.borrow()[6..]
+        );
+
+        // Callbacks which weren't started aren't completed
+        let log = Log::default();
+        let mut failing = Recorder::named("b", &log);
+        failing.fail_at = Some(u64::MAX);
+        let mut multi = dispatch(vec![
+            Recorder::named("a", &log),
+            failing,
+            Recorder::named("c", &log),
+        ]);
+        assert_eq!(
+            "no start",
+            run(&mut multi, 1).unwrap_err().cause().unwrap().message
+        );
+        assert_eq!(
+            vec!["a start 0", "b start 0", "a complete 0"],
+            *log.borrow()
+        );
+    }
+
+    #[test]
+    fn test_resume_and_wants() {
+        let log = Log::default();
+        let mut a = Recorder::named("a", &log);
+        a.resume_height = Some(2);
+        a.wants_transactions = false;
+        let mut b = Recorder::named("b", &log);
+        b.wants_transactions = false;
+        let mut multi = dispatch(vec![a, b]);
+        assert!(!multi.wants_transactions());
+        assert!(multi.wants_witnesses());
+        run(&mut multi, 3).unwrap();
+        // b resumes from the start, so everything is parsed, a skips what it has
+        assert_eq!(None, multi.resume_height());
+        assert_eq!(
+            vec!["b block 0", "b block 1", "a block 2", "b block 2"],
+            log.borrow()[2..6]
+        );
+
+        let mut c = Recorder::named("c", &log);
+        c.resume_height = Some(5);
+        let mut d = Recorder::named("d", &log);
+        d.resume_height = Some(3);
+        let mut multi = dispatch(vec![c, d]);
+        assert!(multi.wants_transactions());
+        multi.on_start(&CoinType::from(Bitcoin), 0).unwrap();
+        assert_eq!(Some(3), multi.resume_height());
+    }
+
+    #[test]
+    fn test_on_transaction() {
+        let log = Log::default();
+        let mut a = Recorder::named("a", &log);
+        a.resume_height = Some(1);
+        a.wants_scripts = false;
+        let mut b = Recorder::named("b", &log);
+        b.wants_scripts = false;
+        let mut multi = dispatch(vec![a, b]);
+        assert!(!multi.wants_script_evaluation());
+        let mut c = Recorder::named("c", &log);
+        c.wants_scripts = false;
+        assert!(dispatch(vec![c, Recorder::named("d", &log)]).wants_script_evaluation());
+
+        multi.on_start(&CoinType::from(Bitcoin), 0).unwrap();
+        let block = new_block(vec![
+            new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[5000000000]),
+            new_tx(&[([1u8; 32], 0)], &[1000]),
+        ]);
+        for height in 0..2 {
+            callbacks::deliver_block(&mut multi, &block, height).unwrap();
+        }
+        // a resumes at 1
+        assert_eq!(
+            vec![
+                "b block 0",
+                "b tx 0:0",
+                "b tx 0:1",
+                "a block 1",
+                "b block 1",
+                "a tx 1:0",
+                "b tx 1:0",
+                "a tx 1:1",
+                "b tx 1:1"
+            ],
+            log.borrow()[2..]
+        );
+    }
+
+    #[test]
+    fn test_parse_spec() {
+        let matches = Multi::build_subcommand().get_matches_from(vec![
+            "multi",
+            "--with",
+            "simplestats",
+            "--with",
+            "headers:--output-dir /tmp/headers --csv",
+        ]);
+        let multi = Multi::new(&matches).unwrap();
+        let names: Vec<&str> = multi.callbacks.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(vec!["simplestats", "headers"], names);
+
+        let err = |spec: &str| Multi::parse_spec(spec).err().unwrap().message;
+        assert_eq!("Unknown callback `nope` in `nope:/out`", err("nope:/out"));
+        assert_eq!("multi can't be nested", err("multi:--with simplestats"));
+        assert!(err("headers").starts_with("Invalid arguments for headers: "));
+        assert!(err("simplestats:--unknown").starts_with("Invalid arguments for simplestats: "));
+    }
+}
@@ -0,0 +1,308 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use crate::blockchain::parser::types::CoinType;
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::script::{self, MultiSigClass, ScriptPattern};
+use crate::blockchain::proto::tx::{EvaluatedTx, TxOutpoint};
+use crate::blockchain::proto::Hashed;
+use crate::callbacks::Callback;
+use crate::common::outputstore::{outpoint_key, OutputStore};
+use crate::common::utils;
+use crate::errors::OpResult;
+
+/// Writes bare multisig outputs and P2SH inputs revealing a multisig redeem script
+pub struct MultiSigDump {
+    output_dir: PathBuf,
+    writer: Option<BufWriter<File>>,
+    // Unspent P2SH outputs, without values
+    p2sh_outputs: Option<OutputStore>,
+
+    start_height: u64,
+    n_bare: u64,
+    n_p2sh: u64,
+    n_embedded: u64,
+    n_errors: u64,
+}
+
+impl MultiSigDump {
+    fn check_tx(&mut self, tx: &Hashed<EvaluatedTx>, block_height: u64) -> OpResult<()> {
+        let txid = utils::arr_to_hex_swapped(&tx.hash);
+        if !tx.value.is_coinbase() {
+            for (i, input) in tx.value.inputs.iter().enumerate() {
+                let p2sh_outputs = self.p2sh_outputs.as_mut().unwrap();
+                if p2sh_outputs.take(&outpoint_key(&input.outpoint))?.is_none() {
+                    continue;
+                }
+                let redeem_script = match script::last_push(&input.script_sig) {
+                    Ok(Some(redeem_script)) => redeem_script,
+                    _ => {
+                        self.write_row(&txid, "input", i, block_height, None)?;
+                        continue;
+                    }
+                };
+                match script::parse_multisig(redeem_script) {
+                    Ok(MultiSigClass::NoMultiSig) => {}
+                    class => self.write_row(&txid, "input", i, block_height, class.ok())?,
+                }
+            }
+        }
+        for (i, output) in tx.value.outputs.iter().enumerate() {
+            if let ScriptPattern::Pay2ScriptHash = output.script.pattern {
+                let key = outpoint_key(&TxOutpoint::new(tx.hash, i as u32));
+                self.p2sh_outputs
+                    .as_mut()
+                    .unwrap()
+                    .insert(key, Vec::new())?;
+                continue;
+            }
+            // Truncated output scripts are no multisig either
+            match script::parse_multisig(&output.out.script_pubkey) {
+                Ok(MultiSigClass::NoMultiSig) | Err(_) => {}
+                Ok(class) => self.write_row(&txid, "output", i, block_height, Some(class))?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes a row for a multisig script, `None` if the redeem script couldn't be parsed
+    fn write_row(
+        &mut self,
+        txid: &str,
+        kind: &str,
+        index: usize,
+        block_height: u64,
+        class: Option<MultiSigClass>,
+    ) -> OpResult<()> {
+        let (class, m, n, pubkeys) = match class {
+            Some(MultiSigClass::MultiSig(multisig)) => {
+                if kind == "input" {
+                    self.n_p2sh += 1;
+                } else {
+                    self.n_bare += 1;
+                }
+                let pubkeys: Vec<String> = multisig
+                    .pubkeys
+                    .iter()
+                    .map(|pubkey| utils::arr_to_hex(pubkey))
+                    .collect();
+                (
+                    "multisig",
+                    multisig.required.to_string(),
+                    multisig.pubkeys.len().to_string(),
+                    pubkeys.join(","),
+                )
+            }
+            Some(_) => {
+                self.n_embedded += 1;
+                ("embedded", String::new(), String::new(), String::new())
+            }
+            None => {
+                self.n_errors += 1;
+                ("parse_error", String::new(), String::new(), String::new())
+            }
+        };
+        let row = format!(
+            "{};{};{};{};{};{};{};{}\n",
+            txid, kind, index, block_height, class, m, n, pubkeys
+        );
+        self.writer.as_mut().unwrap().write_all(row.as_bytes())?;
+        Ok(())
+    }
+}
+
+impl Callback for MultiSigDump {
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b>
+    where
+        Self: Sized,
+    {
+        SubCommand::with_name("multisig")
+            .about("Writes bare and P2SH multisig scripts with their m-of-n parameters and public keys to CSV file")
+            .version("0.1")
+            .arg(
+                Arg::with_name("output-dir")
+                    .long("output-dir")
+                    .value_name("PATH")
+                    .help("Folder to store multisig.csv, created if necessary")
+                    .takes_value(true)
+                    .required(true),
+            )
+            .args(&OutputStore::args())
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let p2sh_outputs = OutputStore::from_matches(matches)?;
+        let cb = MultiSigDump {
+            output_dir: PathBuf::from(matches.value_of("output-dir").unwrap()),
+            writer: None,
+            p2sh_outputs: Some(p2sh_outputs),
+            start_height: 0,
+            n_bare: 0,
+            n_p2sh: 0,
+            n_embedded: 0,
+            n_errors: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, _: &CoinType, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        if block_height != 0 {
+            warn!(target: "callback", "Starting at height {}, spends of earlier P2SH outputs are left out.", block_height);
+        }
+        fs::create_dir_all(&self.output_dir)?;
+        let mut writer = BufWriter::with_capacity(
+            4000000,
+            File::create(self.output_dir.join("multisig.csv.tmp"))?,
+        );
+        writer.write_all(b"txid;type;index;height;class;m;n;pubkeys\n")?;
+        self.writer = Some(writer);
+        info!(target: "callback", "Using `multisig` with output dir: {} ...", &self.output_dir.display());
+        Ok(())
+    }
+
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<()> {
+        for tx in &block.txs {
+            self.check_tx(tx, block_height)?;
+        }
+        Ok(())
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        let mut writer = self.writer.take().unwrap();
+        writer.flush()?;
+        drop(writer);
+        let path = self.output_dir.join("multisig.csv");
+        fs::rename(path.with_extension("csv.tmp"), &path)?;
+        self.p2sh_outputs.take().unwrap().destroy()?;
+
+        info!(target: "callback", "Done.\nDumped blocks {} to {} into {}:\n\
+                                   \t-> bare multisig: {:9}\n\
+                                   \t-> P2SH multisig: {:9}\n\
+                                   \t-> embedded:      {:9}\n\
+                                   \t-> parse errors:  {:9}",
+              self.start_height, block_height, path.display(),
+              self.n_bare, self.n_p2sh, self.n_embedded, self.n_errors);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+    use crate::blockchain::parser::types::Bitcoin;
+    use crate::callbacks::fixtures::{new_chain, new_tx, new_tx_to};
+
+    const PUBKEY_A: &str = "02a0434d9e47f3c86235477c7b1ae6ae5d3442d49b1943c2b752a68e2a47e247c7";
+    const PUBKEY_B: &str = "03774ae7f858a9411e5ef4246b70c65aac5649980be5c17891bbec17895da008cb";
+
+    fn multisig_2of2() -> String {
+        format!("5221{}21{}52ae", PUBKEY_A, PUBKEY_B)
+    }
+
+    /// Returns a P2SH script and a scriptSig revealing the redeem script
+    fn p2sh(redeem_script: &str) -> (String, Vec<u8>) {
+        let redeem_script = utils::hex_to_vec(redeem_script);
+        let hash = utils::ridemp160(&utils::sha256(&redeem_script));
+        let mut script_sig = vec![0x00, 0x4c, redeem_script.len() as u8];
+        script_sig.extend_from_slice(&redeem_script);
+        (format!("a914{}87", utils::arr_to_hex(&hash)), script_sig)
+    }
+
+    #[test]
+    fn test_multisig() {
+        let dir = env::temp_dir().join(format!("blockparser-multisig-{}", process::id()));
+        let matches = MultiSigDump::build_subcommand().get_matches_from(vec![
+            "multisig",
+            "--output-dir",
+            dir.to_str().unwrap(),
+        ]);
+        let mut multisig = MultiSigDump::new(&matches).unwrap();
+
+        let bare = format!("5121{}21{}52ae", PUBKEY_A, PUBKEY_B);
+        // OP_IF <2-of-2> OP_ELSE OP_1 OP_ENDIF
+        let embedded = format!("63{}675168", multisig_2of2());
+        let (p2sh_multisig, sig_multisig) = p2sh(&multisig_2of2());
+        let (p2sh_embedded, sig_embedded) = p2sh(&embedded);
+        // The redeem script ends within a 2 byte push
+        let (p2sh_truncated, sig_truncated) = p2sh("5102aa");
+        let coinbase = new_tx_to(
+            &[([0u8; 32], 0xFFFFFFFF)],
+            &[
+                (100, &bare),
+                (100, &embedded),
+                (100, &p2sh_multisig),
+                (100, &p2sh_embedded),
+                (100, &p2sh_truncated),
+            ],
+        );
+        let mut spend = new_tx(
+            &[
+                (coinbase.hash, 2),
+                (coinbase.hash, 3),
+                (coinbase.hash, 4),
+                (coinbase.hash, 0),
+            ],
+            &[350],
+        )
+        .value;
+        spend.inputs[0].script_sig = sig_multisig;
+        spend.inputs[1].script_sig = sig_embedded;
+        spend.inputs[2].script_sig = sig_truncated;
+        let spend = Hashed::double_sha256(spend);
+        let coinbase_txid = utils::arr_to_hex_swapped(&coinbase.hash);
+        let spend_txid = utils::arr_to_hex_swapped(&spend.hash);
+        let chain = new_chain(vec![vec![coinbase], vec![spend]]);
+
+        multisig.on_start(&CoinType::from(Bitcoin), 0).unwrap();
+        for (height, block) in chain.iter().enumerate() {
+            multisig.on_block(block, height as u64).unwrap();
+        }
+        multisig.on_complete(1).unwrap();
+        let csv = fs::read_to_string(dir.join("multisig.csv")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let keys = format!("{},{}", PUBKEY_A, PUBKEY_B);
+        assert_eq!(
+            vec![
+                String::from("txid;type;index;height;class;m;n;pubkeys"),
+                format!("{};output;0;0;multisig;1;2;{}", coinbase_txid, keys),
+                format!("{};output;1;0;embedded;;;", coinbase_txid),
+                format!("{};input;0;1;multisig;2;2;{}", spend_txid, keys),
+                format!("{};input;1;1;embedded;;;", spend_txid),
+                format!("{};input;2;1;parse_error;;;", spend_txid),
+            ],
+            csv.lines().map(String::from).collect::<Vec<String>>()
+        );
+        assert_eq!(
+            (1, 1, 2, 1),
+            (
+                multisig.n_bare,
+                multisig.n_p2sh,
+                multisig.n_embedded,
+                multisig.n_errors
+            )
+        );
+    }
+
+    #[test]
+    fn test_invalid_args() {
+        let matches = MultiSigDump::build_subcommand().get_matches_from(vec![
+            "multisig",
+            "--output-dir",
+            "out",
+            "--buffer-size",
+            "0",
+        ]);
+        assert!(MultiSigDump::new(&matches).is_err());
+    }
+}
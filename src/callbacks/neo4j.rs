@@ -0,0 +1,701 @@
+use std::collections::HashMap;
+use std::env;
+use std::mem;
+use std::ops::Range;
+use std::time::Duration;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use serde_json::{json, Value};
+
+use crate::blockchain::parser::types::CoinType;
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::tx::TxOutpoint;
+use crate::callbacks::Callback;
+use crate::common::bolt::{self, Connection};
+use crate::common::outputstore::{outpoint_key, Key};
+use crate::common::utils;
+use crate::errors::{OpError, OpResult};
+
+const DEFAULT_URI: &str = "bolt://localhost:7687";
+const DEFAULT_USER: &str = "neo4j";
+const PASSWORD_ENV: &str = "NEO4J_PASSWORD";
+const DEFAULT_BATCH_SIZE: usize = 100;
+const DEFAULT_CACHE_SIZE: usize = 20000000;
+// Large transactions can take a while until the server answers
+const TIMEOUT: Duration = Duration::from_secs(600);
+// Rows per UNWIND statement, keeps the parameter maps of a statement small
+const ROWS_PER_STATEMENT: usize = 10_000;
+
+const CONSTRAINTS: [(&str, &str); 4] = [
+    (
+        "block_hash",
+        "CREATE CONSTRAINT block_hash IF NOT EXISTS FOR (b:Block) REQUIRE b.hash IS UNIQUE",
+    ),
+    (
+        "tx_txid",
+        "CREATE CONSTRAINT tx_txid IF NOT EXISTS FOR (t:Tx) REQUIRE t.txid IS UNIQUE",
+    ),
+    (
+        "output_id",
+        "CREATE CONSTRAINT output_id IF NOT EXISTS FOR (o:Output) REQUIRE o.id IS UNIQUE",
+    ),
+    (
+        "address",
+        "CREATE CONSTRAINT address IF NOT EXISTS FOR (a:Address) REQUIRE a.address IS UNIQUE",
+    ),
+];
+const MERGE_BLOCKS: &str = "UNWIND $rows AS row \
+     MERGE (b:Block {hash: row.hash}) SET b += row.props";
+const MERGE_TXS: &str = "UNWIND $rows AS row \
+     MATCH (b:Block {hash: row.blockHash}) \
+     MERGE (t:Tx {txid: row.txid}) SET t += row.props \
+     MERGE (b)-[:CONTAINS]->(t)";
+const MERGE_OUTPUTS: &str = "UNWIND $rows AS row \
+     MATCH (t:Tx {txid: row.txid}) \
+     MERGE (o:Output {id: row.id}) SET o += row.props \
+     MERGE (t)-[:CREATES]->(o)";
+const MERGE_ADDRESSES: &str = "UNWIND $rows AS row \
+     MATCH (o:Output {id: row.id}) \
+     MERGE (a:Address {address: row.address}) \
+     MERGE (o)-[:LOCKED_TO]->(a)";
+const MERGE_SPENDS: &str = "UNWIND $rows AS row \
+     MATCH (t:Tx {txid: row.txid}) \
+     MATCH (o:Output {id: row.outputId}) \
+     MERGE (t)-[s:SPENDS {indexIn: row.indexIn}]->(o) SET s.value = row.value";
+const LOOKUP_OUTPUTS: &str = "UNWIND $rows AS id \
+     MATCH (o:Output {id: id}) RETURN o.id, o.value";
+
+/// Runs Cypher statements, implemented by the Bolt connection and mocked in tests
+pub trait GraphClient {
+    /// Runs a statement with `params` and returns the records
+    fn run(&mut self, query: &str, params: Value) -> OpResult<Vec<Vec<Value>>>;
+    fn begin(&mut self) -> OpResult<()>;
+    fn commit(&mut self) -> OpResult<()>;
+}
+
+impl GraphClient for Connection {
+    fn run(&mut self, query: &str, params: Value) -> OpResult<Vec<Vec<Value>>> {
+        Ok(Connection::run(self, query, params)?)
+    }
+
+    fn begin(&mut self) -> OpResult<()> {
+        Ok(Connection::begin(self)?)
+    }
+
+    fn commit(&mut self) -> OpResult<()> {
+        Ok(Connection::commit(self)?)
+    }
+}
+
+/// Values of the outputs which aren't spent yet, bounded by dropping the older of two generations.
+/// Outputs missing here are looked up in the graph.
+struct PrevOutMap {
+    current: HashMap<Key, u64>,
+    previous: HashMap<Key, u64>,
+    capacity: usize,
+}
+
+impl PrevOutMap {
+    fn new(capacity: usize) -> Self {
+        PrevOutMap {
+            current: HashMap::new(),
+            previous: HashMap::new(),
+            capacity,
+        }
+    }
+
+    #[inline]
+    fn insert(&mut self, key: Key, value: u64) {
+        self.current.insert(key, value);
+    }
+
+    #[inline]
+    fn take(&mut self, key: &Key) -> Option<u64> {
+        self.current
+            .remove(key)
+            .or_else(|| self.previous.remove(key))
+    }
+
+    /// Drops the previous generation once the current one holds half of the capacity.
+    /// Only called after a flush, so outputs of unwritten blocks are never dropped.
+    fn rotate(&mut self) {
+        if self.current.len() >= self.capacity / 2 {
+            self.previous = mem::take(&mut self.current);
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.current.len() + self.previous.len()
+    }
+}
+
+/// Input of a buffered transaction
+struct Spend {
+    txid: String,
+    index_in: u32,
+    output_id: String,
+    // None if the output wasn't in the map
+    value: Option<u64>,
+}
+
+/// Rows of the buffered blocks, written by `flush()`
+#[derive(Default)]
+struct Batch {
+    blocks: Vec<Value>,
+    // (row, its inputs in `spends`, output value), the fee is added on flush
+    txs: Vec<(Value, Range<usize>, u64)>,
+    outputs: Vec<Value>,
+    addresses: Vec<Value>,
+    spends: Vec<Spend>,
+    n_blocks: usize,
+}
+
+/// Loads the spend graph into Neo4j: `(:Block)-[:CONTAINS]->(:Tx)-[:CREATES]->(:Output)`,
+/// `(:Tx)-[:SPENDS]->(:Output)` and `(:Output)-[:LOCKED_TO]->(:Address)`
+pub struct Neo4j {
+    config: bolt::Config,
+    // Connected in on_start
+    client: Option<Box<dyn GraphClient>>,
+    skip_addresses: bool,
+    batch_size: usize,
+    batch: Batch,
+    prev_outs: PrevOutMap,
+
+    start_height: u64,
+    end_height: u64,
+    tx_count: u64,
+    n_flushes: u64,
+    n_lookups: u64,
+    n_unresolved: u64,
+}
+
+impl Callback for Neo4j {
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b>
+    where
+        Self: Sized,
+    {
+        SubCommand::with_name("neo4j")
+            .about("Loads blocks, transactions, outputs and addresses as graph into Neo4j")
+            .version("0.1")
+            .arg(
+                Arg::with_name("uri")
+                    .long("uri")
+                    .value_name("URI")
+                    .help("Bolt URI of the server (default: bolt://localhost:7687)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("user")
+                    .long("user")
+                    .value_name("USER")
+                    .help("User name (default: neo4j)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("password")
+                    .long("password")
+                    .value_name("PASSWORD")
+                    .help("Password (default: $NEO4J_PASSWORD)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("batch-size")
+                    .long("batch-size")
+                    .value_name("N")
+                    .help("Number of blocks written per transaction (default: 100)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("cache-size")
+                    .long("cache-size")
+                    .value_name("N_OUTPUTS")
+                    .help("Unspent outputs kept in memory, older ones are looked up in the graph (default: 20000000)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("skip-addresses")
+                    .long("skip-addresses")
+                    .help("Don't create address nodes, only the transaction graph"),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let password = match matches.value_of("password") {
+            Some(password) => String::from(password),
+            None => env::var(PASSWORD_ENV).unwrap_or_default(),
+        };
+        let config = bolt::Config::new(
+            matches.value_of("uri").unwrap_or(DEFAULT_URI),
+            matches.value_of("user").unwrap_or(DEFAULT_USER),
+            &password,
+            TIMEOUT,
+        )
+        .map_err(|e| OpError::from(e).join_msg("Invalid --uri."))?;
+        let batch_size = match matches.value_of("batch-size") {
+            Some(_) => match value_t!(matches, "batch-size", usize) {
+                Ok(n) if n > 0 => n,
+                _ => {
+                    return Err(OpError::from(String::from(
+                        "--batch-size must be a positive number of blocks.",
+                    )))
+                }
+            },
+            None => DEFAULT_BATCH_SIZE,
+        };
+        let cache_size = match matches.value_of("cache-size") {
+            Some(_) => match value_t!(matches, "cache-size", usize) {
+                Ok(n) if n > 0 => n,
+                _ => {
+                    return Err(OpError::from(String::from(
+                        "--cache-size must be a positive number of outputs.",
+                    )))
+                }
+            },
+            None => DEFAULT_CACHE_SIZE,
+        };
+        let cb = Neo4j {
+            config,
+            client: None,
+            skip_addresses: matches.is_present("skip-addresses"),
+            batch_size,
+            batch: Batch::default(),
+            prev_outs: PrevOutMap::new(cache_size),
+            start_height: 0,
+            end_height: 0,
+            tx_count: 0,
+            n_flushes: 0,
+            n_lookups: 0,
+            n_unresolved: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, _: &CoinType, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        if self.client.is_none() {
+            let conn = Connection::connect(&self.config).map_err(|e| {
+                OpError::from(e).join_msg(&format!(
+                    "Unable to connect to Neo4j on {}:{}.",
+                    self.config.host, self.config.port
+                ))
+            })?;
+            info!(target: "callback", "Connected to {} (Bolt {}.{})",
+                  conn.server(), conn.version().0, conn.version().1);
+            self.client = Some(Box::new(conn));
+        }
+        let client = self.client.as_mut().unwrap();
+        for (name, statement) in CONSTRAINTS {
+            if self.skip_addresses && name == "address" {
+                continue;
+            }
+            client
+                .run(statement, Value::Null)
+                .map_err(|e| e.join_msg(&format!("Unable to create the constraint `{}`.", name)))?;
+        }
+        info!(target: "callback", "Using `neo4j` on {}:{} ...", self.config.host, self.config.port);
+        Ok(())
+    }
+
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<()> {
+        let block_hash = utils::arr_to_hex_swapped(&block.header.hash);
+        let header = &block.header.value;
+        self.batch.blocks.push(json!({
+            "hash": block_hash,
+            "props": {
+                "height": block_height,
+                "timestamp": header.timestamp,
+                "previousHash": utils::arr_to_hex_swapped(&header.prev_hash),
+                "txCount": block.tx_count.value
+            }
+        }));
+        for tx in &block.txs {
+            let txid = utils::arr_to_hex_swapped(&tx.hash);
+            let first_spend = self.batch.spends.len();
+            if !tx.value.is_coinbase() {
+                for (i, input) in tx.value.inputs.iter().enumerate() {
+                    let outpoint = &input.outpoint;
+                    self.batch.spends.push(Spend {
+                        txid: txid.clone(),
+                        index_in: i as u32,
+                        output_id: output_id(&outpoint.txid, outpoint.index),
+                        value: self.prev_outs.take(&outpoint_key(outpoint)),
+                    });
+                }
+            }
+            let mut output_value = 0;
+            for (i, output) in tx.value.outputs.iter().enumerate() {
+                let id = output_id(&tx.hash, i as u32);
+                let value = output.out.value;
+                self.prev_outs
+                    .insert(outpoint_key(&TxOutpoint::new(tx.hash, i as u32)), value);
+                if !self.skip_addresses {
+                    if let Some(address) = &output.script.address {
+                        self.batch
+                            .addresses
+                            .push(json!({"id": id, "address": address}));
+                    }
+                }
+                self.batch.outputs.push(json!({
+                    "txid": txid,
+                    "id": id,
+                    "props": {
+                        "index": i,
+                        "value": value,
+                        "scriptType": output.script.pattern.type_name()
+                    }
+                }));
+                output_value += value;
+            }
+            let row = json!({
+                "blockHash": block_hash,
+                "txid": txid,
+                "props": {
+                    "blockHeight": block_height,
+                    "version": tx.value.version,
+                    "lockTime": tx.value.locktime,
+                    "isCoinbase": tx.value.is_coinbase(),
+                    "hasWitness": tx.value.has_witness(),
+                    "inputCount": tx.value.in_count.value,
+                    "outputCount": tx.value.out_count.value,
+                    "outputValue": output_value
+                }
+            });
+            let spends = first_spend..self.batch.spends.len();
+            self.batch.txs.push((row, spends, output_value));
+        }
+        self.tx_count += block.tx_count.value;
+
+        self.batch.n_blocks += 1;
+        if self.batch.n_blocks >= self.batch_size {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        self.end_height = block_height;
+        self.flush()?;
+
+        info!(target: "callback", "Done.\nLoaded all {} blocks into Neo4j:\n\
+                                   \t-> transactions: {:9}\n\
+                                   \t-> lookups:      {:9}\n\
+                                   \t-> unresolved:   {:9}\n\
+                                   \t-> flushes:      {:9}",
+             self.end_height, self.tx_count, self.n_lookups, self.n_unresolved, self.n_flushes);
+        Ok(())
+    }
+}
+
+impl Neo4j {
+    /// Writes the buffered blocks in one transaction. Spent outputs which weren't in the map
+    /// are looked up in the graph first, unknown ones don't get a `SPENDS` relationship.
+    fn flush(&mut self) -> OpResult<()> {
+        if self.batch.n_blocks == 0 {
+            return Ok(());
+        }
+        let mut batch = mem::take(&mut self.batch);
+        let client = self
+            .client
+            .as_mut()
+            .ok_or_else(|| OpError::from(String::from("Not connected to Neo4j.")))?;
+
+        let missing: Vec<Value> = batch
+            .spends
+            .iter()
+            .filter(|spend| spend.value.is_none())
+            .map(|spend| Value::from(spend.output_id.as_str()))
+            .collect();
+        if !missing.is_empty() {
+            let mut values = HashMap::with_capacity(missing.len());
+            for chunk in missing.chunks(ROWS_PER_STATEMENT) {
+                let records = client
+                    .run(LOOKUP_OUTPUTS, json!({ "rows": chunk }))
+                    .map_err(|e| e.join_msg("Unable to look up previous outputs."))?;
+                for record in records {
+                    if let (Some(id), Some(value)) = (record[0].as_str(), record[1].as_u64()) {
+                        values.insert(String::from(id), value);
+                    }
+                }
+            }
+            self.n_lookups += missing.len() as u64;
+            for spend in batch.spends.iter_mut().filter(|s| s.value.is_none()) {
+                spend.value = values.get(&spend.output_id).copied();
+                if spend.value.is_none() {
+                    debug!(target: "neo4j", "Unable to resolve previous output {}", spend.output_id);
+                    self.n_unresolved += 1;
+                }
+            }
+        }
+
+        let spends = &batch.spends;
+        let txs: Vec<Value> = batch
+            .txs
+            .into_iter()
+            .map(|(mut row, inputs, output_value)| {
+                // Unknown for coinbases and if a spent output is unresolved
+                let fee = if inputs.is_empty() {
+                    None
+                } else {
+                    spends[inputs]
+                        .iter()
+                        .map(|spend| spend.value)
+                        .sum::<Option<u64>>()
+                        .map(|input_value| input_value.saturating_sub(output_value))
+                };
+                row["props"]["fee"] = json!(fee);
+                row
+            })
+            .collect();
+        let spends: Vec<Value> = spends
+            .iter()
+            .filter_map(|spend| {
+                spend.value.map(|value| {
+                    json!({
+                        "txid": spend.txid,
+                        "indexIn": spend.index_in,
+                        "outputId": spend.output_id,
+                        "value": value
+                    })
+                })
+            })
+            .collect();
+
+        client.begin()?;
+        for (query, rows) in [
+            (MERGE_BLOCKS, &batch.blocks),
+            (MERGE_TXS, &txs),
+            (MERGE_OUTPUTS, &batch.outputs),
+            (MERGE_ADDRESSES, &batch.addresses),
+            (MERGE_SPENDS, &spends),
+        ] {
+            for chunk in rows.chunks(ROWS_PER_STATEMENT) {
+                client
+                    .run(query, json!({ "rows": chunk }))
+                    .map_err(|e| e.join_msg("Unable to write the graph."))?;
+            }
+        }
+        client
+            .commit()
+            .map_err(|e| e.join_msg("Unable to commit the graph."))?;
+        self.prev_outs.rotate();
+        debug!(target: "neo4j", "Flushed {} blocks, {} outputs in memory", batch.n_blocks, self.prev_outs.len());
+        self.n_flushes += 1;
+        Ok(())
+    }
+}
+
+/// Id of an output node, e.g. `<txid>:0`
+fn output_id(txid: &[u8; 32], index: u32) -> String {
+    format!("{}:{}", utils::arr_to_hex_swapped(txid), index)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::blockchain::parser::types::Bitcoin;
+    use crate::callbacks::fixtures::{new_chain, new_tx, new_tx_to, SCRIPT_A};
+
+    /// (query, params) of the run statements, `BEGIN` and `COMMIT` without params
+    type Calls = Rc<RefCell<Vec<(String, Value)>>>;
+
+    /// Records the statements and answers output lookups with `outputs`
+    struct MockClient {
+        calls: Calls,
+        outputs: HashMap<String, u64>,
+    }
+
+    impl GraphClient for MockClient {
+        fn run(&mut self, query: &str, params: Value) -> OpResult<Vec<Vec<Value>>> {
+            self.calls
+                .borrow_mut()
+                .push((String::from(query), params.clone()));
+            if query != LOOKUP_OUTPUTS {
+                return Ok(Vec::new());
+            }
+            Ok(params["rows"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .filter_map(|id| {
+                    let value = self.outputs.get(id.as_str().unwrap())?;
+                    Some(vec![id.clone(), Value::from(*value)])
+                })
+                .collect())
+        }
+
+        fn begin(&mut self) -> OpResult<()> {
+            self.calls
+                .borrow_mut()
+                .push((String::from("BEGIN"), Value::Null));
+            Ok(())
+        }
+
+        fn commit(&mut self) -> OpResult<()> {
+            self.calls
+                .borrow_mut()
+                .push((String::from("COMMIT"), Value::Null));
+            Ok(())
+        }
+    }
+
+    fn new_neo4j(args: &[&str], outputs: &[(&str, u64)]) -> (Neo4j, Calls) {
+        let mut all_args = vec!["neo4j"];
+        all_args.extend_from_slice(args);
+        let matches = Neo4j::build_subcommand().get_matches_from(all_args);
+        let mut neo4j = Neo4j::new(&matches).unwrap();
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        neo4j.client = Some(Box::new(MockClient {
+            calls: calls.clone(),
+            outputs: outputs
+                .iter()
+                .map(|(id, value)| (String::from(*id), *value))
+                .collect(),
+        }));
+        (neo4j, calls)
+    }
+
+    /// Rows written with the given statement
+    fn rows(calls: &Calls, query: &str) -> Vec<Value> {
+        calls
+            .borrow()
+            .iter()
+            .filter(|(q, _)| q == query)
+            .flat_map(|(_, params)| params["rows"].as_array().unwrap().clone())
+            .collect()
+    }
+
+    fn queries(calls: &Calls) -> Vec<String> {
+        calls.borrow().iter().map(|(q, _)| q.clone()).collect()
+    }
+
+    #[test]
+    fn test_prev_out_map() {
+        let key = |n: u8| outpoint_key(&TxOutpoint::new([n; 32], 0));
+        let mut map = PrevOutMap::new(4);
+        map.insert(key(1), 10);
+        map.rotate();
+        assert_eq!(1, map.len());
+        map.insert(key(2), 20);
+        map.insert(key(3), 30);
+        map.rotate();
+        assert_eq!(3, map.len());
+        assert_eq!(Some(20), map.take(&key(2)));
+        assert_eq!(None, map.take(&key(2)));
+        // The older generation is dropped
+        map.insert(key(4), 40);
+        map.insert(key(5), 50);
+        map.rotate();
+        assert_eq!(None, map.take(&key(1)));
+        assert_eq!(None, map.take(&key(3)));
+        assert_eq!(Some(50), map.take(&key(5)));
+    }
+
+    #[test]
+    fn test_neo4j() {
+        let (mut neo4j, calls) = new_neo4j(&[], &[]);
+        let coinbase_0 = new_tx_to(&[([0u8; 32], 0xFFFFFFFF)], &[(5000000000, SCRIPT_A)]);
+        let coinbase_1 = new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[5000010000]);
+        let spend = new_tx(&[(coinbase_0.hash, 0)], &[4000000000, 999990000]);
+        let chain = new_chain(vec![vec![coinbase_0], vec![coinbase_1, spend]]);
+        neo4j.on_start(&CoinType::from(Bitcoin), 0).unwrap();
+        for (height, block) in chain.iter().enumerate() {
+            neo4j.on_block(block, height as u64).unwrap();
+        }
+        neo4j.on_complete(1).unwrap();
+        assert_eq!(0, neo4j.n_lookups);
+        assert_eq!(1, neo4j.n_flushes);
+
+        let mut expected: Vec<String> = CONSTRAINTS.iter().map(|(_, q)| q.to_string()).collect();
+        expected.extend(
+            [
+                "BEGIN",
+                MERGE_BLOCKS,
+                MERGE_TXS,
+                MERGE_OUTPUTS,
+                MERGE_ADDRESSES,
+                MERGE_SPENDS,
+                "COMMIT",
+            ]
+            .map(String::from),
+        );
+        assert_eq!(expected, queries(&calls));
+
+        let blocks = rows(&calls, MERGE_BLOCKS);
+        assert_eq!(2, blocks.len());
+        assert_eq!(1, blocks[1]["props"]["height"]);
+        let txs = rows(&calls, MERGE_TXS);
+        assert_eq!(3, txs.len());
+        assert_eq!(Value::Null, txs[0]["props"]["fee"]);
+        assert_eq!(10000, txs[2]["props"]["fee"]);
+        assert_eq!(blocks[1]["hash"], txs[2]["blockHash"]);
+        assert_eq!(4, rows(&calls, MERGE_OUTPUTS).len());
+        assert_eq!(4, rows(&calls, MERGE_ADDRESSES).len());
+
+        let txid_0 = utils::arr_to_hex_swapped(&chain[0].txs[0].hash);
+        assert_eq!(
+            vec![json!({
+                "txid": utils::arr_to_hex_swapped(&chain[1].txs[1].hash),
+                "indexIn": 0,
+                "outputId": format!("{}:0", txid_0),
+                "value": 5000000000u64
+            })],
+            rows(&calls, MERGE_SPENDS)
+        );
+    }
+
+    #[test]
+    fn test_lookup() {
+        let known = format!("{}:0", utils::arr_to_hex_swapped(&[7u8; 32]));
+        let (mut neo4j, calls) = new_neo4j(&["--skip-addresses"], &[(&known, 3000)]);
+        let coinbase = new_tx_to(&[([0u8; 32], 0xFFFFFFFF)], &[(5000000000, SCRIPT_A)]);
+        let unresolved = new_tx(&[([7u8; 32], 0), ([8u8; 32], 0)], &[1000]);
+        let resolved = new_tx(&[([7u8; 32], 0)], &[2000]);
+        let chain = new_chain(vec![vec![coinbase, unresolved, resolved]]);
+        neo4j.on_start(&CoinType::from(Bitcoin), 100).unwrap();
+        neo4j.on_block(&chain[0], 100).unwrap();
+        neo4j.on_complete(100).unwrap();
+        assert_eq!(3, neo4j.n_lookups);
+        // The known output is spent twice, the unknown one once
+        assert_eq!(1, neo4j.n_unresolved);
+
+        let queries = queries(&calls);
+        assert!(!queries.contains(&String::from(CONSTRAINTS[3].1)));
+        assert!(!queries.contains(&String::from(MERGE_ADDRESSES)));
+        assert_eq!(3, rows(&calls, LOOKUP_OUTPUTS).len());
+        let txs = rows(&calls, MERGE_TXS);
+        assert_eq!(Value::Null, txs[1]["props"]["fee"]);
+        assert_eq!(1000, txs[2]["props"]["fee"]);
+        assert_eq!(2, rows(&calls, MERGE_SPENDS).len());
+    }
+
+    #[test]
+    fn test_batch_size() {
+        let (mut neo4j, calls) = new_neo4j(&["--batch-size", "1"], &[]);
+        let coinbase_0 = new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[5000000000]);
+        let coinbase_1 = new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[5000000001]);
+        let chain = new_chain(vec![vec![coinbase_0], vec![coinbase_1]]);
+        neo4j.on_start(&CoinType::from(Bitcoin), 0).unwrap();
+        for (height, block) in chain.iter().enumerate() {
+            neo4j.on_block(block, height as u64).unwrap();
+        }
+        neo4j.on_complete(1).unwrap();
+        assert_eq!(2, neo4j.n_flushes);
+        let commits = queries(&calls).iter().filter(|q| *q == "COMMIT").count();
+        assert_eq!(2, commits);
+
+        for args in [
+            ["--batch-size", "0"],
+            ["--cache-size", "x"],
+            ["--uri", "http://a"],
+        ] {
+            let matches = Neo4j::build_subcommand().get_matches_from(["neo4j", args[0], args[1]]);
+            assert!(Neo4j::new(&matches).is_err(), "{:?}", args);
+        }
+    }
+}
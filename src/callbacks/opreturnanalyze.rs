@@ -0,0 +1,270 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as FmtWrite;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use crate::blockchain::parser::types::CoinType;
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::opreturn::{NullData, Protocol};
+use crate::blockchain::proto::tx::EvaluatedTx;
+use crate::blockchain::proto::Hashed;
+use crate::callbacks::{common, Callback};
+use crate::common::utils;
+use crate::errors::OpResult;
+
+/// Upper bounds of the payload size buckets in bytes, 80 is the standard relay limit
+const SIZE_BUCKETS: [usize; 5] = [0, 20, 40, 80, usize::MAX];
+const SIZE_LABELS: [&str; 5] = ["0", "1-20", "21-40", "41-80", ">80"];
+
+#[derive(Default)]
+struct YearStats {
+    count: u64,
+    bytes: u64,
+    protocols: BTreeMap<Protocol, u64>,
+    sizes: [u64; SIZE_BUCKETS.len()],
+}
+
+/// Classifies the payloads of OP_RETURN outputs by protocol and writes them to a csv file
+pub struct OpReturnAnalyze {
+    output_dir: PathBuf,
+    writer: Option<BufWriter<File>>,
+    // Reused for all rows
+    row: String,
+
+    // (count, bytes) per protocol
+    protocols: BTreeMap<Protocol, (u64, u64)>,
+    years: BTreeMap<i32, YearStats>,
+    // Year of the block whose transactions are passed to on_transaction()
+    year: i32,
+
+    start_height: u64,
+}
+
+impl OpReturnAnalyze {
+    fn record(&mut self, protocol: Protocol, size: usize, year: i32) {
+        let entry = self.protocols.entry(protocol).or_insert((0, 0));
+        entry.0 += 1;
+        entry.1 += size as u64;
+
+        let stats = self.years.entry(year).or_default();
+        stats.count += 1;
+        stats.bytes += size as u64;
+        *stats.protocols.entry(protocol).or_insert(0) += 1;
+        let bucket = SIZE_BUCKETS.iter().position(|max| size <= *max).unwrap();
+        stats.sizes[bucket] += 1;
+    }
+
+    fn write_histograms(&self, out: &mut Vec<u8>) -> OpResult<()> {
+        writeln!(out, "Protocols:")?;
+        for (protocol, (count, bytes)) in &self.protocols {
+            writeln!(
+                out,
+                "\t-> {:20} {:10} outputs {:12} bytes",
+                protocol.name(),
+                count,
+                bytes
+            )?;
+        }
+        writeln!(out, "Per year:")?;
+        for (year, stats) in &self.years {
+            writeln!(
+                out,
+                "\t{}: {} outputs, {} bytes",
+                year, stats.count, stats.bytes
+            )?;
+            let protocols: Vec<String> = stats
+                .protocols
+                .iter()
+                .map(|(protocol, count)| format!("{}={}", protocol, count))
+                .collect();
+            writeln!(out, "\t\tprotocols: {}", protocols.join(", "))?;
+            let sizes: Vec<String> = SIZE_LABELS
+                .iter()
+                .zip(stats.sizes.iter())
+                .map(|(label, count)| format!("{}={}", label, count))
+                .collect();
+            writeln!(out, "\t\tsizes:     {}", sizes.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+impl Callback for OpReturnAnalyze {
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b>
+    where
+        Self: Sized,
+    {
+        SubCommand::with_name("opreturn-analyze")
+            .about("Classifies OP_RETURN payloads by protocol and writes them to CSV file")
+            .version("0.1")
+            .arg(
+                Arg::with_name("output-dir")
+                    .long("output-dir")
+                    .value_name("PATH")
+                    .help("Folder to store opreturn.csv, created if necessary")
+                    .takes_value(true)
+                    .required(true),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let cb = OpReturnAnalyze {
+            output_dir: PathBuf::from(matches.value_of("output-dir").unwrap()),
+            writer: None,
+            row: String::new(),
+            protocols: BTreeMap::new(),
+            years: BTreeMap::new(),
+            year: 0,
+            start_height: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, _: &CoinType, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        fs::create_dir_all(&self.output_dir)?;
+        let mut writer = BufWriter::with_capacity(
+            4000000,
+            File::create(self.output_dir.join("opreturn.csv.tmp"))?,
+        );
+        writer.write_all(b"txid;indexOut;height;size;pushes;protocol;text\n")?;
+        self.writer = Some(writer);
+        info!(target: "callback", "Using `opreturn-analyze` with output dir: {} ...", &self.output_dir.display());
+        Ok(())
+    }
+
+    /// Payloads are parsed from the raw scripts
+    fn wants_script_evaluation(&self) -> bool {
+        false
+    }
+
+    fn wants_witnesses(&self) -> bool {
+        false
+    }
+
+    fn on_block(&mut self, block: &Block, _: u64) -> OpResult<()> {
+        self.year = utils::timestamp_to_year(block.header.value.timestamp);
+        Ok(())
+    }
+
+    fn on_transaction(
+        &mut self,
+        tx: &Hashed<EvaluatedTx>,
+        block_height: u64,
+        _: usize,
+    ) -> OpResult<()> {
+        let is_coinbase = tx.value.is_coinbase();
+        for (i, output) in tx.value.outputs.iter().enumerate() {
+            let script = &output.out.script_pubkey;
+            let data = match NullData::parse(script) {
+                Some(data) => data,
+                None => continue,
+            };
+            let protocol = data.classify(script, is_coinbase);
+            self.row.clear();
+            utils::hex_into_swapped(&mut self.row, &tx.hash);
+            let _ = writeln!(
+                self.row,
+                ";{};{};{};{};{};{}",
+                i,
+                block_height,
+                data.payload.len(),
+                data.push_count,
+                protocol,
+                data.text().map(common::escape_csv).unwrap_or_default()
+            );
+            self.writer
+                .as_mut()
+                .unwrap()
+                .write_all(self.row.as_bytes())?;
+            self.record(protocol, data.payload.len(), self.year);
+        }
+        Ok(())
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        let mut writer = self.writer.take().unwrap();
+        writer.flush()?;
+        drop(writer);
+        let path = self.output_dir.join("opreturn.csv");
+        fs::rename(path.with_extension("csv.tmp"), &path)?;
+
+        let mut buffer = Vec::with_capacity(4096);
+        self.write_histograms(&mut buffer)?;
+        info!(target: "callback", "Done.\nDumped OP_RETURN outputs of blocks {} to {} into {}.\n{}",
+              self.start_height, block_height, path.display(), String::from_utf8_lossy(&buffer));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+    use crate::blockchain::parser::types::Bitcoin;
+    use crate::callbacks;
+    use crate::callbacks::fixtures::{new_chain, new_tx_to, SCRIPT_A};
+
+    #[test]
+    fn test_analyze() {
+        let dir = env::temp_dir().join(format!("blockparser-opreturn-{}", process::id()));
+        let matches = OpReturnAnalyze::build_subcommand().get_matches_from(vec![
+            "opreturn-analyze",
+            "--output-dir",
+            dir.to_str().unwrap(),
+        ]);
+        let commitment =
+            "6a24aa21a9ede2f61c3f71d1defd3fa999dfa36953755c690689799962b48bebd836974e8cf9";
+        let coinbase_0 = new_tx_to(&[([0u8; 32], 0xFFFFFFFF)], &[(5000000000, SCRIPT_A)]);
+        let coinbase_1 = new_tx_to(
+            &[([0u8; 32], 0xFFFFFFFF)],
+            &[(5000000000, SCRIPT_A), (0, commitment)],
+        );
+        let tx = new_tx_to(
+            &[(coinbase_0.hash, 0)],
+            &[
+                (0, "6a0568656c6c6f07203b776f726c64"),
+                (0, "6a146f6d6e69000000000000001f000000002faf0800"),
+                (4999990000, SCRIPT_A),
+            ],
+        );
+        let mut chain = new_chain(vec![vec![coinbase_0], vec![coinbase_1, tx]]);
+        // 2017-01-01
+        chain[1].header.value.timestamp = 1483228800;
+        chain[1].header = Hashed::double_sha256(chain[1].header.value.clone());
+
+        let mut analyze = OpReturnAnalyze::new(&matches).unwrap();
+        assert!(!analyze.wants_script_evaluation());
+        analyze.on_start(&CoinType::from(Bitcoin), 0).unwrap();
+        for (height, block) in chain.iter().enumerate() {
+            callbacks::deliver_block(&mut analyze, block, height as u64).unwrap();
+        }
+        analyze.on_complete(1).unwrap();
+
+        let csv = fs::read_to_string(dir.join("opreturn.csv")).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(4, lines.len());
+        assert_eq!("txid;indexOut;height;size;pushes;protocol;text", lines[0]);
+        let txid = utils::arr_to_hex_swapped(&chain[1].txs[1].hash);
+        assert_eq!(format!("{};0;1;12;2;text;\"hello ;world\"", txid), lines[2]);
+        assert_eq!(format!("{};1;1;20;1;omni;", txid), lines[3]);
+        assert!(lines[1].ends_with(";1;1;36;1;witness_commitment;"));
+
+        let mut buffer = Vec::new();
+        analyze.write_histograms(&mut buffer).unwrap();
+        let histograms = String::from_utf8(buffer).unwrap();
+        assert!(histograms.contains("\t2017: 3 outputs, 68 bytes\n"));
+        assert!(histograms.contains("\t\tprotocols: witness_commitment=1, omni=1, text=1\n"));
+        assert!(histograms.contains("\t\tsizes:     0=0, 1-20=2, 21-40=1, 41-80=0, >80=0\n"));
+        assert!(!histograms.contains("2009"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,510 @@
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use crate::blockchain::parser::types::CoinType;
+use crate::blockchain::proto::block::Block;
+use crate::callbacks::common::base_units;
+use crate::callbacks::Callback;
+use crate::common::parquet::{Column, ColumnType, Compression, FileWriter, Value};
+use crate::errors::{OpError, OpResult};
+
+const DEFAULT_ROWS_PER_FILE: u64 = 10_000_000;
+// Compressed size of a row group, each table buffers one row group in memory
+const ROW_GROUP_SIZE: usize = 128 * 1024 * 1024;
+
+// Schemas of the tables, one directory of `part-NNNNN.parquet` files each.
+// Hashes are 32 byte binaries in the usual display (big-endian) order, i.e. `hex(txid)`
+// equals the txid shown by block explorers. Values are in base units (satoshis).
+// Columns must only be appended to keep existing queries working.
+
+/// blocks/
+const BLOCKS_SCHEMA: [Column; 10] = [
+    Column::required("height", ColumnType::UInt64),
+    Column::required("hash", ColumnType::Hash),
+    Column::required("version", ColumnType::UInt32),
+    Column::required("size", ColumnType::UInt32),
+    Column::required("prev_hash", ColumnType::Hash),
+    Column::required("merkle_root", ColumnType::Hash),
+    Column::required("timestamp", ColumnType::UInt32),
+    Column::required("bits", ColumnType::UInt32),
+    Column::required("nonce", ColumnType::UInt32),
+    Column::required("tx_count", ColumnType::UInt64),
+];
+
+/// transactions/
+const TRANSACTIONS_SCHEMA: [Column; 9] = [
+    Column::required("txid", ColumnType::Hash),
+    Column::required("block_height", ColumnType::UInt64),
+    // Position within the block
+    Column::required("tx_index", ColumnType::UInt32),
+    Column::required("version", ColumnType::UInt32),
+    Column::required("lock_time", ColumnType::UInt32),
+    Column::required("is_coinbase", ColumnType::Bool),
+    Column::required("has_witness", ColumnType::Bool),
+    Column::required("input_count", ColumnType::UInt64),
+    Column::required("output_count", ColumnType::UInt64),
+];
+
+/// tx_in/
+const TX_IN_SCHEMA: [Column; 7] = [
+    Column::required("txid", ColumnType::Hash),
+    Column::required("block_height", ColumnType::UInt64),
+    Column::required("index_in", ColumnType::UInt32),
+    Column::required("prev_txid", ColumnType::Hash),
+    Column::required("prev_index", ColumnType::UInt32),
+    Column::required("script_sig", ColumnType::Binary),
+    Column::required("sequence", ColumnType::UInt32),
+];
+
+/// tx_out/
+const TX_OUT_SCHEMA: [Column; 7] = [
+    Column::required("txid", ColumnType::Hash),
+    Column::required("block_height", ColumnType::UInt64),
+    Column::required("index_out", ColumnType::UInt32),
+    Column::required("value", ColumnType::Int64),
+    Column::required("script_pub_key", ColumnType::Binary),
+    // Null if the script has no address
+    Column::optional("address", ColumnType::Utf8),
+    Column::required("script_type", ColumnType::Utf8),
+];
+
+/// Directory of Parquet files with the same schema
+struct Table {
+    dir: PathBuf,
+    schema: &'static [Column],
+    compression: Compression,
+    rows_per_file: u64,
+    writer: Option<FileWriter>,
+    n_files: u32,
+    n_rows: u64,
+}
+
+impl Table {
+    fn new(
+        dir: PathBuf,
+        schema: &'static [Column],
+        compression: Compression,
+        rows_per_file: u64,
+    ) -> Table {
+        Table {
+            dir,
+            schema,
+            compression,
+            rows_per_file,
+            writer: None,
+            n_files: 0,
+            n_rows: 0,
+        }
+    }
+
+    fn file_path(&self, part: u32) -> PathBuf {
+        self.dir.join(format!("part-{:05}.parquet", part))
+    }
+
+    /// Creates the directory, refuses to mix files with a previous dump
+    fn create_dir(&self) -> OpResult<()> {
+        fs::create_dir_all(&self.dir)?;
+        if self.file_path(0).exists() {
+            return Err(OpError::from(format!(
+                "{} already contains a dump, please remove it first.",
+                self.dir.display()
+            )));
+        }
+        Ok(())
+    }
+
+    fn write_row(&mut self, row: &[Value]) -> OpResult<()> {
+        let writer = match self.writer.as_mut() {
+            Some(writer) => writer,
+            None => {
+                let path = self.file_path(self.n_files);
+                debug!(target: "callback", "Writing {} ...", path.display());
+                self.n_files += 1;
+                self.writer.insert(FileWriter::create(
+                    &path,
+                    self.schema,
+                    self.compression,
+                    ROW_GROUP_SIZE,
+                )?)
+            }
+        };
+        writer.write_row(row)?;
+        self.n_rows += 1;
+        if writer.num_rows() >= self.rows_per_file {
+            self.close()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the footer of the current file, which is unreadable without it
+    fn close(&mut self) -> OpResult<()> {
+        if let Some(writer) = self.writer.take() {
+            writer.close()?;
+        }
+        Ok(())
+    }
+}
+
+/// Dumps the whole blockchain into Parquet files
+pub struct ParquetDump {
+    output_dir: PathBuf,
+    blocks: Table,
+    transactions: Table,
+    tx_in: Table,
+    tx_out: Table,
+
+    start_height: u64,
+    end_height: u64,
+}
+
+impl Callback for ParquetDump {
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b>
+    where
+        Self: Sized,
+    {
+        SubCommand::with_name("parquetdump")
+            .about("Dumps the whole blockchain into Apache Parquet files")
+            .version("0.1")
+            .arg(
+                Arg::with_name("output-dir")
+                    .long("output-dir")
+                    .value_name("PATH")
+                    .help("Folder to store the blocks, transactions, tx_in and tx_out directories")
+                    .takes_value(true)
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name("compression")
+                    .long("compression")
+                    .value_name("CODEC")
+                    .help("Compression of the data pages (default: snappy)")
+                    .possible_values(&[
+                        "snappy",
+                        #[cfg(feature = "gzip")]
+                        "gzip",
+                        #[cfg(feature = "zstd")]
+                        "zstd",
+                        "none",
+                    ])
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("rows-per-file")
+                    .long("rows-per-file")
+                    .value_name("N")
+                    .help("Maximum number of rows per file (default: 10000000)")
+                    .takes_value(true),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let output_dir = PathBuf::from(matches.value_of("output-dir").unwrap());
+        let compression =
+            Compression::from_str(matches.value_of("compression").unwrap_or("snappy"))?;
+        let rows_per_file = match matches.value_of("rows-per-file") {
+            Some(_) => match value_t!(matches, "rows-per-file", u64) {
+                Ok(n) if n > 0 => n,
+                _ => {
+                    return Err(OpError::from(String::from(
+                        "--rows-per-file must be a positive number of rows.",
+                    )))
+                }
+            },
+            None => DEFAULT_ROWS_PER_FILE,
+        };
+        let table = |name: &str, schema: &'static [Column]| {
+            Table::new(output_dir.join(name), schema, compression, rows_per_file)
+        };
+        let cb = ParquetDump {
+            blocks: table("blocks", &BLOCKS_SCHEMA),
+            transactions: table("transactions", &TRANSACTIONS_SCHEMA),
+            tx_in: table("tx_in", &TX_IN_SCHEMA),
+            tx_out: table("tx_out", &TX_OUT_SCHEMA),
+            output_dir,
+            start_height: 0,
+            end_height: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, _: &CoinType, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        for table in self.tables() {
+            table.create_dir()?;
+        }
+        info!(target: "callback", "Using `parquetdump` with output dir: {} ...", &self.output_dir.display());
+        Ok(())
+    }
+
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<()> {
+        let header = &block.header.value;
+        let block_hash = display_order(&block.header.hash);
+        self.blocks.write_row(&[
+            Value::UInt64(block_height),
+            Value::Bytes(&block_hash),
+            Value::UInt32(header.version),
+            Value::UInt32(block.size),
+            Value::Bytes(&display_order(&header.prev_hash)),
+            Value::Bytes(&display_order(&header.merkle_root)),
+            Value::UInt32(header.timestamp),
+            Value::UInt32(header.bits),
+            Value::UInt32(header.nonce),
+            Value::UInt64(block.tx_count.value),
+        ])?;
+
+        for (tx_index, tx) in block.txs.iter().enumerate() {
+            let txid = display_order(&tx.hash);
+            self.transactions.write_row(&[
+                Value::Bytes(&txid),
+                Value::UInt64(block_height),
+                Value::UInt32(tx_index as u32),
+                Value::UInt32(tx.value.version),
+                Value::UInt32(tx.value.locktime),
+                Value::Bool(tx.value.is_coinbase()),
+                Value::Bool(tx.value.has_witness()),
+                Value::UInt64(tx.value.in_count.value),
+                Value::UInt64(tx.value.out_count.value),
+            ])?;
+            for (i, input) in tx.value.inputs.iter().enumerate() {
+                self.tx_in.write_row(&[
+                    Value::Bytes(&txid),
+                    Value::UInt64(block_height),
+                    Value::UInt32(i as u32),
+                    Value::Bytes(&display_order(&input.outpoint.txid)),
+                    Value::UInt32(input.outpoint.index),
+                    Value::Bytes(&input.script_sig),
+                    Value::UInt32(input.seq_no),
+                ])?;
+            }
+            for (i, output) in tx.value.outputs.iter().enumerate() {
+                let address = match &output.script.address {
+                    Some(address) => Value::Bytes(address.as_bytes()),
+                    None => Value::Null,
+                };
+                self.tx_out.write_row(&[
+                    Value::Bytes(&txid),
+                    Value::UInt64(block_height),
+                    Value::UInt32(i as u32),
+                    Value::Int64(base_units(output.out.value)),
+                    Value::Bytes(&output.out.script_pubkey),
+                    address,
+                    Value::Bytes(output.script.pattern.type_name().as_bytes()),
+                ])?;
+            }
+        }
+        Ok(())
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        self.end_height = block_height;
+        for table in self.tables() {
+            table.close()?;
+        }
+
+        info!(target: "callback", "Done.\nDumped all {} blocks:\n\
+                                   \t-> transactions: {:9} ({} files)\n\
+                                   \t-> inputs:       {:9} ({} files)\n\
+                                   \t-> outputs:      {:9} ({} files)",
+             self.end_height, self.transactions.n_rows, self.transactions.n_files,
+             self.tx_in.n_rows, self.tx_in.n_files, self.tx_out.n_rows, self.tx_out.n_files);
+        Ok(())
+    }
+}
+
+impl ParquetDump {
+    fn tables(&mut self) -> [&mut Table; 4] {
+        [
+            &mut self.blocks,
+            &mut self.transactions,
+            &mut self.tx_in,
+            &mut self.tx_out,
+        ]
+    }
+}
+
+/// Reverses the internal byte order of a hash
+#[inline]
+fn display_order(hash: &[u8; 32]) -> [u8; 32] {
+    let mut reversed = *hash;
+    reversed.reverse();
+    reversed
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::path::Path;
+    use std::process;
+
+    use super::*;
+    use crate::blockchain::parser::types::Bitcoin;
+    use crate::callbacks::fixtures::{new_chain, new_tx, new_tx_to, SCRIPT_A};
+    use crate::common::parquet::{read_file, Field};
+    use crate::common::utils;
+
+    // OP_RETURN "hello world"
+    const DATA_SCRIPT: &str = "6a0b68656c6c6f20776f726c64";
+
+    fn temp_dir(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("blockparser-{}-{}", name, process::id()))
+    }
+
+    fn dump_chain(dir: &Path, compression: &str, chain: &[Block]) -> ParquetDump {
+        let matches = ParquetDump::build_subcommand().get_matches_from(vec![
+            "parquetdump",
+            "--output-dir",
+            dir.to_str().unwrap(),
+            "--compression",
+            compression,
+            "--rows-per-file",
+            "2",
+        ]);
+        let mut dump = ParquetDump::new(&matches).unwrap();
+        dump.on_start(&CoinType::from(Bitcoin), 0).unwrap();
+        for (height, block) in chain.iter().enumerate() {
+            dump.on_block(block, height as u64).unwrap();
+        }
+        dump.on_complete(chain.len() as u64 - 1).unwrap();
+        dump
+    }
+
+    fn bytes(field: &Field) -> &[u8] {
+        match field {
+            Field::Bytes(bytes) => bytes,
+            _ => panic!("expected bytes, got {:?}", field),
+        }
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let coinbase_0 = new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[5000000000]);
+        let spending_tx = new_tx_to(
+            &[(coinbase_0.hash, 0)],
+            &[(0, DATA_SCRIPT), (4999990000, SCRIPT_A)],
+        );
+        let chain = new_chain(vec![
+            vec![coinbase_0],
+            vec![
+                new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[5000010000]),
+                spending_tx,
+            ],
+        ]);
+
+        for compression in [
+            "snappy",
+            "none",
+            #[cfg(feature = "gzip")]
+            "gzip",
+            #[cfg(feature = "zstd")]
+            "zstd",
+        ] {
+            let dir = temp_dir(&format!("parquetdump-{}", compression));
+            let dump = dump_chain(&dir, compression, &chain);
+            assert_eq!(3, dump.transactions.n_rows);
+            assert_eq!(3, dump.tx_in.n_rows);
+            assert_eq!(4, dump.tx_out.n_rows);
+            // 2 rows per file
+            assert_eq!(1, dump.blocks.n_files);
+            assert_eq!(2, dump.transactions.n_files);
+            assert_eq!(2, dump.tx_out.n_files);
+
+            let blocks = read_file(&dump.blocks.file_path(0));
+            assert_eq!(
+                BLOCKS_SCHEMA.iter().map(|c| c.name).collect::<Vec<_>>(),
+                blocks.columns
+            );
+            assert_eq!(vec![Field::Int(0), Field::Int(1)], blocks.column("height"));
+            let hashes = blocks.column("hash");
+            assert_eq!(display_order(&chain[1].header.hash), bytes(&hashes[1]));
+            assert_eq!(bytes(&hashes[0]), bytes(&blocks.column("prev_hash")[1]));
+            assert_eq!(
+                Field::Int(chain[1].header.value.timestamp as i64),
+                blocks.column("timestamp")[1]
+            );
+            assert_eq!(
+                vec![Field::Int(1), Field::Int(2)],
+                blocks.column("tx_count")
+            );
+
+            let txs = read_file(&dump.transactions.file_path(1));
+            assert_eq!(1, txs.rows.len());
+            assert_eq!(
+                display_order(&chain[1].txs[1].hash),
+                bytes(&txs.column("txid")[0])
+            );
+            assert_eq!(Field::Int(1), txs.column("block_height")[0]);
+            assert_eq!(Field::Int(1), txs.column("tx_index")[0]);
+            assert_eq!(Field::Bool(false), txs.column("is_coinbase")[0]);
+            assert_eq!(Field::Int(2), txs.column("output_count")[0]);
+
+            let inputs = read_file(&dump.tx_in.file_path(1));
+            assert_eq!(
+                display_order(&chain[0].txs[0].hash),
+                bytes(&inputs.column("prev_txid")[0])
+            );
+            assert_eq!(Field::Int(0), inputs.column("prev_index")[0]);
+
+            let outputs = read_file(&dump.tx_out.file_path(1));
+            assert_eq!(
+                vec![Field::Int(0), Field::Int(4999990000)],
+                outputs.column("value")
+            );
+            assert_eq!(
+                vec![
+                    Field::Null,
+                    Field::Bytes(b"12higDjoCCNXSA95xZMWUdPvXNmkAduhWv".to_vec())
+                ],
+                outputs.column("address")
+            );
+            assert_eq!(
+                vec![
+                    Field::Bytes(b"nulldata".to_vec()),
+                    Field::Bytes(b"pubkeyhash".to_vec())
+                ],
+                outputs.column("script_type")
+            );
+            assert_eq!(
+                utils::hex_to_vec(DATA_SCRIPT),
+                bytes(&outputs.column("script_pub_key")[0])
+            );
+            fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_invalid_args() {
+        let matches = ParquetDump::build_subcommand().get_matches_from(vec![
+            "parquetdump",
+            "--output-dir",
+            "/tmp",
+            "--rows-per-file",
+            "0",
+        ]);
+        assert!(ParquetDump::new(&matches).is_err());
+    }
+
+    #[test]
+    fn test_existing_dump() {
+        let dir = temp_dir("parquetdump-existing");
+        let chain = new_chain(vec![vec![new_tx(
+            &[([0u8; 32], 0xFFFFFFFF)],
+            &[5000000000],
+        )]]);
+        dump_chain(&dir, "none", &chain);
+
+        let matches = ParquetDump::build_subcommand().get_matches_from(vec![
+            "parquetdump",
+            "--output-dir",
+            dir.to_str().unwrap(),
+        ]);
+        let mut dump = ParquetDump::new(&matches).unwrap();
+        assert!(dump.on_start(&CoinType::from(Bitcoin), 0).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,504 @@
+use std::str::FromStr;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use crate::blockchain::parser::types::CoinType;
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::tx::{EvaluatedTx, EvaluatedTxOut, TxInput};
+use crate::blockchain::proto::Hashed;
+use crate::callbacks::Callback;
+use crate::common::pgwire::{Config, Connection};
+use crate::common::utils;
+use crate::errors::{OpError, OpResult};
+
+const DEFAULT_CONN: &str = "host=localhost";
+const DEFAULT_SCHEMA: &str = "public";
+const DEFAULT_BATCH_SIZE: usize = 500;
+
+/// Tables and their columns in COPY order, same layout as the csvdump files
+const TABLES: [(&str, &str); 4] = [
+    (
+        "blocks",
+        "hash, height, version, blocksize, hash_prev, hash_merkle_root, n_time, n_bits, n_nonce",
+    ),
+    ("transactions", "txid, hash_block, version, lock_time"),
+    (
+        "tx_in",
+        "txid, hash_prev_out, index_prev_out, script_sig, sequence",
+    ),
+    ("tx_out", "txid, index_out, value, script_pub_key, address"),
+];
+
+/// Dumps the whole blockchain into PostgreSQL, rows are streamed with COPY
+pub struct Postgres {
+    config: Config,
+    // Quoted identifier of the target schema
+    schema: String,
+    create_tables: bool,
+    conn: Option<Connection>,
+
+    // Buffered rows in COPY text format, one buffer per table (see TABLES)
+    rows: [Vec<u8>; 4],
+    batch_size: usize,
+    n_buffered: usize,
+    n_flushes: u64,
+
+    start_height: u64,
+    end_height: u64,
+    tx_count: u64,
+    in_count: u64,
+    out_count: u64,
+}
+
+impl Callback for Postgres {
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b>
+    where
+        Self: Sized,
+    {
+        SubCommand::with_name("postgres")
+            .about("Dumps the whole blockchain into a PostgreSQL database")
+            .version("0.1")
+            .arg(
+                Arg::with_name("conn")
+                    .long("conn")
+                    .value_name("CONN")
+                    .help("libpq-style connection string, e.g. `host=localhost user=btc dbname=chain` (default: host=localhost)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("schema")
+                    .long("schema")
+                    .value_name("NAME")
+                    .help("Schema containing the tables (default: public)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("create-tables")
+                    .long("create-tables")
+                    .help("Create schema and tables if necessary, indexes and foreign keys are added after the import"),
+            )
+            .arg(
+                Arg::with_name("batch-size")
+                    .long("batch-size")
+                    .value_name("N")
+                    .help("Number of blocks to buffer before writing (default: 500)")
+                    .takes_value(true),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let conn = matches.value_of("conn").unwrap_or(DEFAULT_CONN);
+        let config = Config::from_str(conn)
+            .map_err(|e| OpError::from(e).join_msg("Invalid connection string."))?;
+        let schema = matches.value_of("schema").unwrap_or(DEFAULT_SCHEMA);
+        let cb = Postgres {
+            config,
+            schema: quote_ident(schema),
+            create_tables: matches.is_present("create-tables"),
+            conn: None,
+            rows: Default::default(),
+            batch_size: value_t!(matches, "batch-size", usize)
+                .unwrap_or(DEFAULT_BATCH_SIZE)
+                .max(1),
+            n_buffered: 0,
+            n_flushes: 0,
+            start_height: 0,
+            end_height: 0,
+            tx_count: 0,
+            in_count: 0,
+            out_count: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, _: &CoinType, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        info!(target: "callback", "Using `postgres` with database `{}` on {}:{} (schema: {}) ...",
+              self.config.dbname, self.config.host, self.config.port, self.schema);
+        let mut conn = Connection::connect(&self.config).map_err(|e| {
+            OpError::from(e).join_msg(&format!(
+                "Unable to connect to PostgreSQL on {}:{}.",
+                self.config.host, self.config.port
+            ))
+        })?;
+        if self.create_tables {
+            conn.execute(&create_tables(&self.schema))?;
+        }
+        self.conn = Some(conn);
+        Ok(())
+    }
+
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<()> {
+        let [blocks, txs, inputs, outputs] = &mut self.rows;
+        blocks.extend_from_slice(block.as_copy_row(block_height).as_bytes());
+
+        let block_hash = utils::arr_to_hex_swapped(&block.header.hash);
+        for tx in &block.txs {
+            txs.extend_from_slice(tx.as_copy_row(&block_hash).as_bytes());
+            let txid_str = utils::arr_to_hex_swapped(&tx.hash);
+            for input in &tx.value.inputs {
+                inputs.extend_from_slice(input.as_copy_row(&txid_str).as_bytes());
+            }
+            for (i, output) in tx.value.outputs.iter().enumerate() {
+                outputs.extend_from_slice(output.as_copy_row(&txid_str, i as u32).as_bytes());
+            }
+            self.in_count += tx.value.in_count.value;
+            self.out_count += tx.value.out_count.value;
+        }
+        self.tx_count += block.tx_count.value;
+
+        self.n_buffered += 1;
+        if self.n_buffered >= self.batch_size {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        self.end_height = block_height;
+        self.flush()?;
+        if self.create_tables {
+            info!(target: "callback", "Creating indexes and foreign keys ...");
+            let sql = create_indexes(&self.schema);
+            self.connection()?.execute(&sql)?;
+        }
+
+        info!(target: "callback", "Done.\nDumped all {} blocks into schema {}:\n\
+                                   \t-> transactions: {:9}\n\
+                                   \t-> inputs:       {:9}\n\
+                                   \t-> outputs:      {:9}\n\
+                                   \t-> flushes:      {:9}",
+             self.end_height, self.schema, self.tx_count, self.in_count, self.out_count, self.n_flushes);
+        Ok(())
+    }
+}
+
+impl Postgres {
+    fn connection(&mut self) -> OpResult<&mut Connection> {
+        self.conn
+            .as_mut()
+            .ok_or_else(|| OpError::from(String::from("Not connected to PostgreSQL.")))
+    }
+
+    /// Copies all buffered rows in a single transaction,
+    /// so either all or none of the batch's blocks are stored.
+    fn flush(&mut self) -> OpResult<()> {
+        if self.n_buffered == 0 {
+            return Ok(());
+        }
+        let rows = std::mem::take(&mut self.rows);
+        let schema = self.schema.clone();
+        let conn = self.connection()?;
+        conn.execute("BEGIN")?;
+        for ((table, columns), data) in TABLES.iter().zip(rows.iter()) {
+            let sql = format!("COPY {}.{} ({}) FROM STDIN", schema, table, columns);
+            let n_rows = conn.copy_in(&sql, data).map_err(|e| {
+                OpError::from(e).join_msg(&format!("Unable to copy rows into `{}`.", table))
+            })?;
+            trace!(target: "postgres", "Copied {} rows into {}", n_rows, table);
+        }
+        conn.execute("COMMIT")?;
+        debug!(target: "postgres", "Flushed {} blocks", self.n_buffered);
+        self.n_buffered = 0;
+        self.n_flushes += 1;
+        Ok(())
+    }
+}
+
+/// Quotes an SQL identifier, e.g. `my"schema` -> `"my""schema"`
+fn quote_ident(ident: &str) -> String {
+    format!("\"{}\"", ident.replace('"', "\"\""))
+}
+
+/// Tables without any constraints, which are added after the import
+fn create_tables(schema: &str) -> String {
+    format!(
+        "CREATE SCHEMA IF NOT EXISTS {schema};
+        CREATE TABLE IF NOT EXISTS {schema}.blocks (
+            hash bytea NOT NULL,
+            height integer NOT NULL,
+            version bigint NOT NULL,
+            blocksize integer NOT NULL,
+            hash_prev bytea NOT NULL,
+            hash_merkle_root bytea NOT NULL,
+            n_time bigint NOT NULL,
+            n_bits bigint NOT NULL,
+            n_nonce bigint NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS {schema}.transactions (
+            txid bytea NOT NULL,
+            hash_block bytea NOT NULL,
+            version bigint NOT NULL,
+            lock_time bigint NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS {schema}.tx_in (
+            txid bytea NOT NULL,
+            hash_prev_out bytea NOT NULL,
+            index_prev_out bigint NOT NULL,
+            script_sig bytea NOT NULL,
+            sequence bigint NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS {schema}.tx_out (
+            txid bytea NOT NULL,
+            index_out integer NOT NULL,
+            value bigint NOT NULL,
+            script_pub_key bytea NOT NULL,
+            address text
+        );",
+        schema = schema
+    )
+}
+
+/// Indexes and foreign keys, all statements can be repeated.
+/// Transaction ids are not unique (see BIP 30), so only blocks can be referenced.
+fn create_indexes(schema: &str) -> String {
+    format!(
+        "CREATE UNIQUE INDEX IF NOT EXISTS blocks_hash_idx ON {schema}.blocks (hash);
+        CREATE UNIQUE INDEX IF NOT EXISTS blocks_height_idx ON {schema}.blocks (height);
+        CREATE INDEX IF NOT EXISTS transactions_txid_idx ON {schema}.transactions (txid);
+        CREATE INDEX IF NOT EXISTS transactions_hash_block_idx ON {schema}.transactions (hash_block);
+        CREATE INDEX IF NOT EXISTS tx_in_txid_idx ON {schema}.tx_in (txid);
+        CREATE INDEX IF NOT EXISTS tx_in_prev_out_idx ON {schema}.tx_in (hash_prev_out, index_prev_out);
+        CREATE INDEX IF NOT EXISTS tx_out_txid_idx ON {schema}.tx_out (txid, index_out);
+        DO $$ BEGIN
+            ALTER TABLE {schema}.transactions ADD CONSTRAINT transactions_hash_block_fkey
+                FOREIGN KEY (hash_block) REFERENCES {schema}.blocks (hash);
+        EXCEPTION WHEN duplicate_object THEN NULL;
+        END $$;",
+        schema = schema
+    )
+}
+
+/// Hex string as bytea in COPY text format, the backslash has to be escaped
+#[inline]
+fn bytea(hex: &str) -> String {
+    format!("\\\\x{}", hex)
+}
+
+/// Escapes a text value for COPY text format
+fn copy_text(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+impl Block {
+    #[inline]
+    fn as_copy_row(&self, block_height: u64) -> String {
+        // (@hash, height, version, blocksize, @hashPrev, @hashMerkleRoot, nTime, nBits, nNonce)
+        format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+            bytea(&utils::arr_to_hex_swapped(&self.header.hash)),
+            &block_height,
+            &self.header.value.version,
+            &self.size,
+            bytea(&utils::arr_to_hex_swapped(&self.header.value.prev_hash)),
+            bytea(&utils::arr_to_hex_swapped(&self.header.value.merkle_root)),
+            &self.header.value.timestamp,
+            &self.header.value.bits,
+            &self.header.value.nonce
+        )
+    }
+}
+
+impl Hashed<EvaluatedTx> {
+    #[inline]
+    fn as_copy_row(&self, block_hash: &str) -> String {
+        // (@txid, @hashBlock, version, lockTime)
+        format!(
+            "{}\t{}\t{}\t{}\n",
+            bytea(&utils::arr_to_hex_swapped(&self.hash)),
+            bytea(block_hash),
+            &self.value.version,
+            &self.value.locktime
+        )
+    }
+}
+
+impl TxInput {
+    #[inline]
+    fn as_copy_row(&self, txid: &str) -> String {
+        // (@txid, @hashPrevOut, indexPrevOut, scriptSig, sequence)
+        format!(
+            "{}\t{}\t{}\t{}\t{}\n",
+            bytea(txid),
+            bytea(&utils::arr_to_hex_swapped(&self.outpoint.txid)),
+            &self.outpoint.index,
+            bytea(&utils::arr_to_hex(&self.script_sig)),
+            &self.seq_no
+        )
+    }
+}
+
+impl EvaluatedTxOut {
+    #[inline]
+    fn as_copy_row(&self, txid: &str, index: u32) -> String {
+        let address = match &self.script.address {
+            Some(address) => copy_text(address),
+            None => {
+                debug!(target: "postgres", "Unable to evaluate address for utxo in txid: {} ({})", txid, self.script.pattern);
+                String::from("\\N")
+            }
+        };
+
+        // (@txid, indexOut, value, @scriptPubKey, address)
+        format!(
+            "{}\t{}\t{}\t{}\t{}\n",
+            bytea(txid),
+            &index,
+            &self.out.value,
+            bytea(&utils::arr_to_hex(&self.out.script_pubkey)),
+            &address
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+    use crate::blockchain::parser::types::Bitcoin;
+    use crate::callbacks::fixtures::{new_block, new_chain, new_tx, new_tx_to, SCRIPT_A, SCRIPT_B};
+
+    /// Connection string of a database for `test_postgres_dump`, which is skipped if unset
+    const TEST_CONN_ENV: &str = "BLOCKPARSER_TEST_POSTGRES";
+
+    #[test]
+    fn test_quote_ident() {
+        assert_eq!("\"public\"", quote_ident("public"));
+        assert_eq!("\"my\"\"schema\"", quote_ident("my\"schema"));
+    }
+
+    #[test]
+    fn test_copy_text() {
+        assert_eq!("abc", copy_text("abc"));
+        assert_eq!("a\\tb\\nc\\\\d\\r", copy_text("a\tb\nc\\d\r"));
+    }
+
+    #[test]
+    fn test_copy_rows() {
+        let coinbase = new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[5000000000]);
+        let txid = utils::arr_to_hex_swapped(&coinbase.hash);
+        let block = new_block(vec![coinbase]);
+        let block_hash = utils::arr_to_hex_swapped(&block.header.hash);
+
+        let row = block.as_copy_row(7);
+        let columns: Vec<&str> = row.trim_end_matches('\n').split('\t').collect();
+        assert_eq!(9, columns.len());
+        assert_eq!(format!("\\\\x{}", block_hash), columns[0]);
+        assert_eq!("7", columns[1]);
+        assert_eq!(format!("\\\\x{}", "0".repeat(64)), columns[4]);
+        assert_eq!("486604799", columns[7]);
+
+        let tx = &block.txs[0];
+        assert_eq!(
+            format!("\\\\x{}\t\\\\x{}\t1\t0\n", txid, block_hash),
+            tx.as_copy_row(&block_hash)
+        );
+        assert_eq!(
+            format!(
+                "\\\\x{}\t\\\\x{}\t4294967295\t\\\\x\t4294967295\n",
+                txid,
+                "0".repeat(64)
+            ),
+            tx.value.inputs[0].as_copy_row(&txid)
+        );
+        assert_eq!(
+            format!(
+                "\\\\x{}\t0\t5000000000\t\\\\x76a91412ab8dc588ca9d5787dde7eb29569da63c3a238c88ac\t12higDjoCCNXSA95xZMWUdPvXNmkAduhWv\n",
+                txid
+            ),
+            tx.value.outputs[0].as_copy_row(&txid, 0)
+        );
+    }
+
+    #[test]
+    fn test_invalid_conn() {
+        let matches =
+            Postgres::build_subcommand().get_matches_from(vec!["postgres", "--conn", "port=none"]);
+        assert!(Postgres::new(&matches).is_err());
+    }
+
+    /// Runs a full dump against the database in $BLOCKPARSER_TEST_POSTGRES
+    #[test]
+    fn test_postgres_dump() {
+        let conn_str = match env::var(TEST_CONN_ENV) {
+            Ok(conn_str) => conn_str,
+            Err(_) => return,
+        };
+        let schema = format!("blockparser_test_{}", process::id());
+        let matches = Postgres::build_subcommand().get_matches_from(vec![
+            "postgres",
+            "--conn",
+            &conn_str,
+            "--schema",
+            &schema,
+            "--create-tables",
+            "--batch-size",
+            "1",
+        ]);
+        let coinbase = new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[5000000000]);
+        let spending = new_tx_to(&[(coinbase.hash, 0)], &[(1000, SCRIPT_B), (2000, SCRIPT_A)]);
+        let coinbase_2 = new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[5000000000]);
+        let chain = new_chain(vec![vec![coinbase], vec![coinbase_2, spending]]);
+        let (block_0, block_1) = (&chain[0], &chain[1]);
+
+        let mut postgres = Postgres::new(&matches).unwrap();
+        postgres.on_start(&CoinType::from(Bitcoin), 0).unwrap();
+        postgres.on_block(block_0, 0).unwrap();
+        postgres.on_block(block_1, 1).unwrap();
+        let result = postgres.on_complete(1);
+
+        let mut conn = Connection::connect(&Config::from_str(&conn_str).unwrap()).unwrap();
+        let counts = conn
+            .query(&format!(
+                "SELECT (SELECT count(*) FROM {s}.blocks), (SELECT count(*) FROM {s}.transactions),
+                        (SELECT count(*) FROM {s}.tx_in), (SELECT count(*) FROM {s}.tx_out),
+                        (SELECT sum(value) FROM {s}.tx_out)",
+                s = quote_ident(&schema)
+            ))
+            .map(|rows| rows[0].clone());
+        conn.execute(&format!("DROP SCHEMA {} CASCADE", quote_ident(&schema)))
+            .unwrap();
+
+        result.unwrap();
+        let expected: Vec<Option<String>> = ["2", "3", "3", "4", "10000003000"]
+            .iter()
+            .map(|v| Some(String::from(*v)))
+            .collect();
+        assert_eq!(expected, counts.unwrap());
+
+        // Missing tables (the schema is gone already) abort the dump
+        let matches = Postgres::build_subcommand().get_matches_from(vec![
+            "postgres",
+            "--conn",
+            &conn_str,
+            "--schema",
+            &schema,
+            "--batch-size",
+            "1",
+        ]);
+        let mut postgres = Postgres::new(&matches).unwrap();
+        postgres.on_start(&CoinType::from(Bitcoin), 0).unwrap();
+        let err = postgres.on_block(block_0, 0).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("Unable to copy rows into `blocks`"),
+            "{}",
+            err
+        );
+    }
+}
@@ -0,0 +1,233 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use crate::blockchain::parser::types::CoinType;
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::ToRaw;
+use crate::callbacks::Callback;
+use crate::errors::{OpError, OpResult};
+
+/// 128 MiB like the blk files of Bitcoin Core
+const DEFAULT_MAX_FILE_SIZE: u64 = 128 * 1024 * 1024;
+
+/// Writes the blocks in network format to blk-style files, each block prefixed with magic and size
+pub struct RawDump {
+    output_dir: PathBuf,
+    max_file_size: u64,
+    magic: u32,
+    writer: Option<BufWriter<File>>,
+
+    file_count: usize,
+    // Bytes written to the current file
+    file_size: u64,
+    start_height: u64,
+    block_count: u64,
+    total_size: u64,
+}
+
+impl RawDump {
+    fn file_path(&self, index: usize) -> PathBuf {
+        self.output_dir.join(format!("blk_export_{:05}.dat", index))
+    }
+
+    /// Flushes the current file and starts the next one
+    fn next_file(&mut self) -> OpResult<()> {
+        if let Some(mut writer) = self.writer.take() {
+            writer.flush()?;
+        }
+        let path = self.file_path(self.file_count);
+        self.writer = Some(BufWriter::with_capacity(4000000, File::create(&path)?));
+        self.file_count += 1;
+        self.file_size = 0;
+        debug!(target: "callback", "Writing blocks to {} ...", path.display());
+        Ok(())
+    }
+}
+
+impl Callback for RawDump {
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b>
+    where
+        Self: Sized,
+    {
+        SubCommand::with_name("rawdump")
+            .about("Writes the blocks in network format to blk-style files")
+            .version("0.1")
+            .arg(
+                Arg::with_name("output-dir")
+                    .long("output-dir")
+                    .value_name("PATH")
+                    .help("Folder to store blk_export_<n>.dat, created if necessary")
+                    .takes_value(true)
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name("max-file-size")
+                    .long("max-file-size")
+                    .value_name("BYTES")
+                    .help("Start a new file before exceeding this size (default: 134217728)")
+                    .takes_value(true),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let max_file_size = match matches.value_of("max-file-size") {
+            Some(_) => match value_t!(matches, "max-file-size", u64) {
+                Ok(n) if n > 0 => n,
+                _ => {
+                    return Err(OpError::from(String::from(
+                        "--max-file-size must be a positive number of bytes.",
+                    )))
+                }
+            },
+            None => DEFAULT_MAX_FILE_SIZE,
+        };
+        let cb = RawDump {
+            output_dir: PathBuf::from(matches.value_of("output-dir").unwrap()),
+            max_file_size,
+            magic: 0,
+            writer: None,
+            file_count: 0,
+            file_size: 0,
+            start_height: 0,
+            block_count: 0,
+            total_size: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, coin_type: &CoinType, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        self.magic = coin_type.magic;
+        fs::create_dir_all(&self.output_dir)?;
+        // Files of an earlier export would be mixed with the new ones
+        let first_file = self.file_path(0);
+        if first_file.exists() {
+            return Err(OpError::from(format!(
+                "{} already exists.",
+                first_file.display()
+            )));
+        }
+        self.next_file()?;
+        info!(target: "callback", "Using `rawdump` with output dir: {} and max file size: {} bytes ...",
+              &self.output_dir.display(), self.max_file_size);
+        Ok(())
+    }
+
+    fn on_block(&mut self, block: &Block, _: u64) -> OpResult<()> {
+        let bytes = block.to_bytes();
+        let record_size = 8 + bytes.len() as u64;
+        // Blocks larger than the limit get a file of their own
+        if self.file_size > 0 && self.file_size + record_size > self.max_file_size {
+            self.next_file()?;
+        }
+        let writer = self.writer.as_mut().unwrap();
+        writer.write_all(&self.magic.to_le_bytes())?;
+        writer.write_all(&(bytes.len() as u32).to_le_bytes())?;
+        writer.write_all(&bytes)?;
+        self.file_size += record_size;
+        self.total_size += record_size;
+        self.block_count += 1;
+        Ok(())
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        if let Some(mut writer) = self.writer.take() {
+            writer.flush()?;
+        }
+        info!(target: "callback", "Done.\nDumped {} blocks from height {} to {} into {} files ({} bytes) in {}.",
+              self.block_count, self.start_height, block_height, self.file_count, self.total_size,
+              self.output_dir.display());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::io::{BufReader, Read};
+    use std::process;
+
+    use byteorder::{LittleEndian, ReadBytesExt};
+
+    use super::*;
+    use crate::blockchain::parser::reader::BlockchainRead;
+    use crate::blockchain::parser::types::Bitcoin;
+    use crate::blockchain::proto::block::BlockFormat;
+    use crate::blockchain::proto::script::AddressFormat;
+    use crate::callbacks::fixtures::{new_chain, new_tx};
+
+    /// Reads all blocks of the file and returns their hashes
+    fn read_file(path: &PathBuf) -> Vec<[u8; 32]> {
+        let mut reader = BufReader::new(File::open(path).unwrap());
+        let mut hashes = Vec::new();
+        while let Ok(magic) = reader.read_u32::<LittleEndian>() {
+            assert_eq!(0xd9b4bef9, magic);
+            let size = reader.read_u32::<LittleEndian>().unwrap();
+            let block = reader
+                .read_block(size, AddressFormat::default(), BlockFormat::Bitcoin)
+                .unwrap();
+            hashes.push(block.header.hash);
+        }
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+        hashes
+    }
+
+    #[test]
+    fn test_rawdump() {
+        let dir = env::temp_dir().join(format!("blockparser-rawdump-{}", process::id()));
+        let coinbase_0 = new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[5000000000]);
+        let spend = new_tx(&[(coinbase_0.hash, 0)], &[4000000000, 999990000]);
+        let coinbase_1 = new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[5000010000]);
+        let coinbase_2 = new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[5000000001]);
+        let chain = new_chain(vec![
+            vec![coinbase_0],
+            vec![coinbase_1, spend],
+            vec![coinbase_2],
+        ]);
+        let sizes: Vec<u64> = chain
+            .iter()
+            .map(|block| 8 + block.to_bytes().len() as u64)
+            .collect();
+        // The first two blocks fit into one file
+        let max_size = (sizes[0] + sizes[1]).to_string();
+        let matches = RawDump::build_subcommand().get_matches_from(vec![
+            "rawdump",
+            "--output-dir",
+            dir.to_str().unwrap(),
+            "--max-file-size",
+            &max_size,
+        ]);
+
+        let mut rawdump = RawDump::new(&matches).unwrap();
+        rawdump.on_start(&CoinType::from(Bitcoin), 0).unwrap();
+        for (height, block) in chain.iter().enumerate() {
+            rawdump.on_block(block, height as u64).unwrap();
+        }
+        rawdump.on_complete(2).unwrap();
+        assert_eq!(2, rawdump.file_count);
+        assert_eq!(sizes.iter().sum::<u64>(), rawdump.total_size);
+
+        assert_eq!(
+            vec![chain[0].header.hash, chain[1].header.hash],
+            read_file(&dir.join("blk_export_00000.dat"))
+        );
+        assert_eq!(
+            vec![chain[2].header.hash],
+            read_file(&dir.join("blk_export_00001.dat"))
+        );
+        assert!(!dir.join("blk_export_00002.dat").exists());
+
+        // Existing exports are not overwritten
+        let mut rawdump = RawDump::new(&matches).unwrap();
+        assert!(rawdump.on_start(&CoinType::from(Bitcoin), 0).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
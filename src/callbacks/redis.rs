@@ -0,0 +1,415 @@
+use std::time::Duration;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use crate::blockchain::parser::types::CoinType;
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::tx::{EvaluatedTx, TxOutpoint};
+use crate::blockchain::proto::Hashed;
+use crate::callbacks::Callback;
+use crate::common::outputstore::{outpoint_key, read_varint, write_varint, OutputStore};
+use crate::common::resp::{self, Connection};
+use crate::common::utils;
+use crate::errors::{OpError, OpResult};
+
+const DEFAULT_URL: &str = "redis://127.0.0.1:6379";
+const DEFAULT_PIPELINE_SIZE: usize = 1000;
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Destination of the commands, implemented by the Redis connection and mocked in tests
+pub trait RedisClient {
+    /// Queues a command without waiting for its reply
+    fn queue(&mut self, args: &[&[u8]]) -> OpResult<()>;
+    /// Sends the queued commands and checks their replies
+    fn sync(&mut self) -> OpResult<()>;
+}
+
+impl RedisClient for Connection {
+    fn queue(&mut self, args: &[&[u8]]) -> OpResult<()> {
+        Ok(Connection::queue(self, args)?)
+    }
+
+    fn sync(&mut self) -> OpResult<()> {
+        Connection::sync(self)?;
+        Ok(())
+    }
+}
+
+/// Stores the unspent outputs in Redis: `utxo:<txid>:<vout>` holds the value (8 bytes little endian)
+/// followed by the scriptPubKey, the set `addr:<address>` holds the `<txid>:<vout>` of its outputs.
+/// Spent outputs are deleted, their address is kept in an output store until then.
+pub struct Redis {
+    config: resp::Config,
+    // Connected in on_start
+    client: Option<Box<dyn RedisClient>>,
+    flushdb: bool,
+    pipeline_size: usize,
+    n_queued: usize,
+    // Value and address of the outputs which are not spent yet
+    outputs: Option<OutputStore>,
+
+    start_height: u64,
+    keys_written: u64,
+    keys_deleted: u64,
+    // Spends of outputs before the start height, their address set isn't updated
+    n_unresolved: u64,
+    unspent_value: u64,
+}
+
+impl Redis {
+    fn queue(&mut self, args: &[&[u8]]) -> OpResult<()> {
+        let client = self
+            .client
+            .as_mut()
+            .ok_or_else(|| OpError::from(String::from("Not connected to Redis.")))?;
+        client.queue(args)?;
+        self.n_queued += 1;
+        if self.n_queued >= self.pipeline_size {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> OpResult<()> {
+        if self.n_queued == 0 {
+            return Ok(());
+        }
+        if let Some(client) = self.client.as_mut() {
+            client
+                .sync()
+                .map_err(|e| e.join_msg("Redis rejected a command."))?;
+        }
+        self.n_queued = 0;
+        Ok(())
+    }
+
+    /// Deletes the spent outputs and stores the new ones. Outputs spent later in the same block
+    /// are stored first, the pipeline keeps the commands in order.
+    fn apply_tx(&mut self, tx: &Hashed<EvaluatedTx>) -> OpResult<()> {
+        let txid = utils::arr_to_hex_swapped(&tx.hash);
+        if !tx.value.is_coinbase() {
+            for input in &tx.value.inputs {
+                let outpoint = &input.outpoint;
+                let member = format!(
+                    "{}:{}",
+                    utils::arr_to_hex_swapped(&outpoint.txid),
+                    outpoint.index
+                );
+                let stored = self
+                    .outputs
+                    .as_mut()
+                    .unwrap()
+                    .take(&outpoint_key(outpoint))?;
+                self.queue(&[b"DEL", format!("utxo:{}", member).as_bytes()])?;
+                self.keys_deleted += 1;
+                match stored {
+                    Some(stored) => {
+                        let mut reader = stored.as_slice();
+                        let value = read_varint(&mut reader)?;
+                        self.unspent_value -= value;
+                        if !reader.is_empty() {
+                            let key = [b"addr:", reader].concat();
+                            self.queue(&[b"SREM", &key, member.as_bytes()])?;
+                        }
+                    }
+                    None => self.n_unresolved += 1,
+                }
+            }
+        }
+        for (i, output) in tx.value.outputs.iter().enumerate() {
+            let member = format!("{}:{}", txid, i);
+            let value = output.out.value;
+            let packed = [&value.to_le_bytes()[..], &output.out.script_pubkey].concat();
+            self.queue(&[b"SET", format!("utxo:{}", member).as_bytes(), &packed])?;
+            self.keys_written += 1;
+
+            let mut stored = Vec::with_capacity(9);
+            write_varint(&mut stored, value);
+            if let Some(address) = &output.script.address {
+                let key = format!("addr:{}", address);
+                self.queue(&[b"SADD", key.as_bytes(), member.as_bytes()])?;
+                stored.extend_from_slice(address.as_bytes());
+            }
+            self.outputs
+                .as_mut()
+                .unwrap()
+                .insert(outpoint_key(&TxOutpoint::new(tx.hash, i as u32)), stored)?;
+            self.unspent_value += value;
+        }
+        Ok(())
+    }
+}
+
+impl Callback for Redis {
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b>
+    where
+        Self: Sized,
+    {
+        SubCommand::with_name("redis")
+            .about("Stores the unspent outputs and the outputs of each address in Redis")
+            .version("0.1")
+            .arg(
+                Arg::with_name("url")
+                    .long("url")
+                    .value_name("URL")
+                    .help("redis://[[user]:password@]host[:port][/db] (default: redis://127.0.0.1:6379)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("db")
+                    .long("db")
+                    .value_name("N")
+                    .help("Database number, overrides the one of --url")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("pipeline-size")
+                    .long("pipeline-size")
+                    .value_name("N")
+                    .help("Number of commands sent before waiting for their replies (default: 1000)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("flushdb")
+                    .long("flushdb")
+                    .help("Remove all keys of the database before the first block"),
+            )
+            .args(&OutputStore::args())
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let mut config = resp::Config::new(matches.value_of("url").unwrap_or(DEFAULT_URL), TIMEOUT)
+            .map_err(|e| OpError::from(e).join_msg("Invalid --url."))?;
+        if matches.value_of("db").is_some() {
+            config.db = value_t!(matches, "db", u32)
+                .map_err(|_| OpError::from(String::from("--db must be a database number.")))?;
+        }
+        let pipeline_size = match matches.value_of("pipeline-size") {
+            Some(_) => match value_t!(matches, "pipeline-size", usize) {
+                Ok(n) if n > 0 => n,
+                _ => {
+                    return Err(OpError::from(String::from(
+                        "--pipeline-size must be a positive number of commands.",
+                    )))
+                }
+            },
+            None => DEFAULT_PIPELINE_SIZE,
+        };
+        let outputs = OutputStore::from_matches(matches)?;
+        let cb = Redis {
+            config,
+            client: None,
+            flushdb: matches.is_present("flushdb"),
+            pipeline_size,
+            n_queued: 0,
+            outputs: Some(outputs),
+            start_height: 0,
+            keys_written: 0,
+            keys_deleted: 0,
+            n_unresolved: 0,
+            unspent_value: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, _: &CoinType, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        if block_height != 0 {
+            warn!(target: "callback", "Starting at height {}, address sets of earlier outputs are not updated.", block_height);
+        }
+        if self.client.is_none() {
+            let conn = Connection::connect(&self.config).map_err(|e| {
+                OpError::from(e).join_msg(&format!(
+                    "Unable to connect to Redis on {}:{}.",
+                    self.config.host, self.config.port
+                ))
+            })?;
+            self.client = Some(Box::new(conn));
+        }
+        if self.flushdb {
+            self.queue(&[b"FLUSHDB"])?;
+            self.sync()?;
+        }
+        info!(target: "callback", "Using `redis` with database {} on {}:{} ...",
+              self.config.db, self.config.host, self.config.port);
+        Ok(())
+    }
+
+    fn on_block(&mut self, block: &Block, _: u64) -> OpResult<()> {
+        for tx in &block.txs {
+            self.apply_tx(tx)?;
+        }
+        Ok(())
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        self.sync()?;
+        self.outputs.take().unwrap().destroy()?;
+        info!(target: "callback", "Done.\nStored the unspent outputs of blocks {} to {} in database {}:\n\
+                                   \t-> keys written:  {:9}\n\
+                                   \t-> keys deleted:  {:9}\n\
+                                   \t-> unresolved:    {:9}\n\
+                                   \t-> unspent value: {:9}",
+              self.start_height, block_height, self.config.db, self.keys_written, self.keys_deleted,
+              self.n_unresolved, self.unspent_value);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::env;
+    use std::process;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::blockchain::parser::types::Bitcoin;
+    use crate::callbacks::fixtures::{new_chain, new_tx, SCRIPT_A};
+
+    /// Sent commands (arguments joined by spaces, binary ones as hex) and `SYNC` for each sync
+    type Commands = Rc<RefCell<Vec<String>>>;
+
+    struct MockClient {
+        commands: Commands,
+    }
+
+    impl RedisClient for MockClient {
+        fn queue(&mut self, args: &[&[u8]]) -> OpResult<()> {
+            let args: Vec<String> = args
+                .iter()
+                .map(|arg| match std::str::from_utf8(arg) {
+                    Ok(arg) if arg.is_ascii() && !arg.contains(char::is_control) => {
+                        String::from(arg)
+                    }
+                    _ => utils::arr_to_hex(arg),
+                })
+                .collect();
+            self.commands.borrow_mut().push(args.join(" "));
+            Ok(())
+        }
+
+        fn sync(&mut self) -> OpResult<()> {
+            self.commands.borrow_mut().push(String::from("SYNC"));
+            Ok(())
+        }
+    }
+
+    fn run(args: &[&str], start_height: u64) -> (Redis, Vec<String>, Vec<Block>) {
+        let mut all_args = vec!["redis"];
+        all_args.extend_from_slice(args);
+        let matches = Redis::build_subcommand().get_matches_from(all_args);
+        let mut redis = Redis::new(&matches).unwrap();
+        let commands = Rc::new(RefCell::new(Vec::new()));
+        redis.client = Some(Box::new(MockClient {
+            commands: commands.clone(),
+        }));
+
+        let coinbase_0 = new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[5000000000]);
+        let spend = new_tx(&[(coinbase_0.hash, 0)], &[4000000000, 999990000]);
+        // Spends an output of the same block
+        let spend_again = new_tx(&[(spend.hash, 1)], &[999980000]);
+        let coinbase_1 = new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[5000020000]);
+        let chain = new_chain(vec![vec![coinbase_0], vec![coinbase_1, spend, spend_again]]);
+        redis
+            .on_start(&CoinType::from(Bitcoin), start_height)
+            .unwrap();
+        for (height, block) in chain.iter().enumerate().skip(start_height as usize) {
+            redis.on_block(block, height as u64).unwrap();
+        }
+        redis.on_complete(1).unwrap();
+        let commands = commands.borrow().clone();
+        (redis, commands, chain)
+    }
+
+    #[test]
+    fn test_redis() {
+        let (redis, commands, chain) = run(&["--flushdb", "--db", "5"], 0);
+        assert_eq!(5, redis.config.db);
+        assert_eq!(5, redis.keys_written);
+        assert_eq!(2, redis.keys_deleted);
+        assert_eq!(0, redis.n_unresolved);
+        // Both coinbases minus the fees
+        assert_eq!(4000000000 + 999980000 + 5000020000, redis.unspent_value);
+
+        let txid = |tx: usize| utils::arr_to_hex_swapped(&chain[1].txs[tx].hash);
+        let txid_0 = utils::arr_to_hex_swapped(&chain[0].txs[0].hash);
+        let address = chain[0].txs[0].value.outputs[0]
+            .script
+            .address
+            .clone()
+            .unwrap();
+        let packed = |value: u64| {
+            format!(
+                "{}{}",
+                utils::arr_to_hex(&value.to_le_bytes()),
+                utils::arr_to_hex(&utils::hex_to_vec(SCRIPT_A))
+            )
+        };
+        assert_eq!("FLUSHDB", commands[0]);
+        assert_eq!("SYNC", commands[1]);
+        assert_eq!(
+            format!("SET utxo:{}:0 {}", txid_0, packed(5000000000)),
+            commands[2]
+        );
+        assert_eq!(format!("SADD addr:{} {}:0", address, txid_0), commands[3]);
+        // The spend of the first output of the same block comes after its SET
+        let set = commands
+            .iter()
+            .position(|c| c.starts_with(&format!("SET utxo:{}:1 ", txid(1))))
+            .unwrap();
+        let del = commands
+            .iter()
+            .position(|c| *c == format!("DEL utxo:{}:1", txid(1)))
+            .unwrap();
+        assert!(set < del);
+        assert!(commands.contains(&format!("SREM addr:{} {}:0", address, txid_0)));
+        assert!(commands.contains(&format!("SREM addr:{} {}:1", address, txid(1))));
+        assert_eq!("SYNC", commands.last().unwrap());
+        assert_eq!(2, commands.iter().filter(|c| *c == "SYNC").count());
+    }
+
+    #[test]
+    fn test_pipeline_size() {
+        let (redis, commands, _) = run(&["--pipeline-size", "2"], 0);
+        // 5 SET, 5 SADD, 2 DEL and 2 SREM commands
+        assert_eq!(14, commands.iter().filter(|c| *c != "SYNC").count());
+        assert_eq!(7, commands.iter().filter(|c| *c == "SYNC").count());
+        assert_eq!(0, redis.n_queued);
+
+        let db_path = env::temp_dir().join(format!("blockparser-redis-db-{}", process::id()));
+        let args = ["--db-path", db_path.to_str().unwrap(), "--buffer-size", "1"];
+        let (spilled, spilled_commands, _) = run(&args, 0);
+        assert_eq!(redis.unspent_value, spilled.unspent_value);
+        assert_eq!(commands.len() - 6, spilled_commands.len());
+        assert!(!db_path.exists());
+    }
+
+    #[test]
+    fn test_start_height() {
+        let (redis, commands, chain) = run(&[], 1);
+        // The spend of the first coinbase can't update the address set
+        assert_eq!(1, redis.n_unresolved);
+        let txid_0 = utils::arr_to_hex_swapped(&chain[0].txs[0].hash);
+        assert!(commands.contains(&format!("DEL utxo:{}:0", txid_0)));
+        assert!(!commands
+            .iter()
+            .any(|c| c.ends_with(&format!("{}:0", txid_0)) && c.starts_with("SREM")));
+    }
+
+    #[test]
+    fn test_invalid_args() {
+        for args in [
+            ["--pipeline-size", "0"],
+            ["--db", "x"],
+            ["--url", "http://localhost"],
+            ["--buffer-size", "0"],
+        ] {
+            let matches = Redis::build_subcommand().get_matches_from(["redis", args[0], args[1]]);
+            assert!(Redis::new(&matches).is_err(), "{:?}", args);
+        }
+    }
+}
@@ -0,0 +1,359 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use crate::blockchain::parser::types::CoinType;
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::tx::TxOutpoint;
+use crate::blockchain::proto::ToRaw;
+use crate::callbacks::{common, Callback};
+use crate::errors::{OpError, OpResult};
+
+const DEFAULT_TOP: usize = 10000;
+/// Distribution thresholds as powers of ten of the coin, from 0.001 to 100000
+const BUCKET_EXPONENTS: [i32; 9] = [-3, -2, -1, 0, 1, 2, 3, 4, 5];
+
+#[derive(Clone, Debug, Default, PartialEq)]
+struct AddressStats {
+    balance: u64,
+    utxo_count: u64,
+    first_seen_height: u64,
+    last_active_height: u64,
+}
+
+/// Writes the addresses with the highest balances in a csv file.
+/// Outputs without address are aggregated per script type, e.g. `nonstandard` or `nulldata`.
+pub struct RichList {
+    output_dir: PathBuf,
+    top: usize,
+    decimals: u8,
+
+    // key: txid + index
+    unspents: HashMap<Vec<u8>, common::UnspentValue>,
+    addresses: HashMap<String, AddressStats>,
+    // Pseudo entries in `addresses` which aren't part of the distribution
+    script_types: HashSet<&'static str>,
+
+    start_height: u64,
+}
+
+impl RichList {
+    /// Returns the addresses with a balance, highest first
+    fn top_addresses(&self) -> Vec<(&String, &AddressStats)> {
+        let mut addresses: Vec<(&String, &AddressStats)> = self
+            .addresses
+            .iter()
+            .filter(|(_, stats)| stats.balance > 0)
+            .collect();
+        let order = |a: &(&String, &AddressStats), b: &(&String, &AddressStats)| {
+            b.1.balance.cmp(&a.1.balance).then_with(|| a.0.cmp(b.0))
+        };
+        if addresses.len() > self.top {
+            addresses.select_nth_unstable_by(self.top, order);
+            addresses.truncate(self.top);
+        }
+        addresses.sort_unstable_by(order);
+        addresses
+    }
+
+    /// Counts the addresses holding more than each threshold
+    fn write_distribution(&self, out: &mut Vec<u8>) -> OpResult<()> {
+        let (mut total, mut n_addresses) = (0u64, 0usize);
+        let mut buckets: Vec<(u64, u64, u64)> = BUCKET_EXPONENTS
+            .iter()
+            .filter(|exp| self.decimals as i32 + **exp >= 0)
+            .map(|exp| (10u64.pow((self.decimals as i32 + exp) as u32), 0, 0))
+            .collect();
+        for (address, stats) in self.addresses.iter() {
+            total += stats.balance;
+            if self.script_types.contains(address.as_str()) {
+                continue;
+            }
+            n_addresses += 1;
+            for (threshold, count, sum) in buckets.iter_mut() {
+                if stats.balance > *threshold {
+                    *count += 1;
+                    *sum += stats.balance;
+                }
+            }
+        }
+        writeln!(
+            out,
+            "Addresses:    {} (and {} script types without address)",
+            n_addresses,
+            self.script_types.len()
+        )?;
+        writeln!(out, "Total amount: {}", self.format_amount(total))?;
+        writeln!(out, "Distribution:")?;
+        for (threshold, count, sum) in buckets {
+            writeln!(
+                out,
+                "\t> {:>12}: {:>10} addresses holding {}",
+                self.format_amount(threshold),
+                count,
+                self.format_amount(sum)
+            )?;
+        }
+        Ok(())
+    }
+
+    fn format_amount(&self, value: u64) -> String {
+        if self.decimals == 0 {
+            return value.to_string();
+        }
+        let unit = 10u64.pow(self.decimals as u32);
+        let fraction = format!("{:0width$}", value % unit, width = self.decimals as usize);
+        let fraction = fraction.trim_end_matches('0');
+        if fraction.is_empty() {
+            (value / unit).to_string()
+        } else {
+            format!("{}.{}", value / unit, fraction)
+        }
+    }
+}
+
+impl Callback for RichList {
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b>
+    where
+        Self: Sized,
+    {
+        SubCommand::with_name("richlist")
+            .about("Writes the addresses with the highest balances to CSV file")
+            .version("0.1")
+            .arg(
+                Arg::with_name("output-dir")
+                    .long("output-dir")
+                    .value_name("PATH")
+                    .help("Folder to store richlist.csv, created if necessary")
+                    .takes_value(true)
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name("top")
+                    .long("top")
+                    .value_name("N")
+                    .help("Number of addresses to write (default: 10000)")
+                    .takes_value(true),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let top = match matches.value_of("top") {
+            Some(_) => match value_t!(matches, "top", usize) {
+                Ok(n) if n > 0 => n,
+                _ => {
+                    return Err(OpError::from(String::from(
+                        "--top must be a positive number of addresses.",
+                    )))
+                }
+            },
+            None => DEFAULT_TOP,
+        };
+        let cb = RichList {
+            output_dir: PathBuf::from(matches.value_of("output-dir").unwrap()),
+            top,
+            decimals: 8,
+            unspents: HashMap::with_capacity(10000000),
+            addresses: HashMap::with_capacity(10000000),
+            script_types: HashSet::new(),
+            start_height: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, coin_type: &CoinType, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        self.decimals = coin_type.decimals;
+        if block_height != 0 {
+            warn!(target: "callback", "Starting at height {}, spends of earlier outputs are ignored.", block_height);
+        }
+        fs::create_dir_all(&self.output_dir)?;
+        info!(target: "callback", "Using `richlist` with output dir: {} ...", &self.output_dir.display());
+        Ok(())
+    }
+
+    fn wants_witnesses(&self) -> bool {
+        false
+    }
+
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<()> {
+        for tx in &block.txs {
+            for input in &tx.value.inputs {
+                if let Some(spent) = self.unspents.remove(&input.outpoint.to_bytes()) {
+                    let stats = self.addresses.get_mut(&spent.address).unwrap();
+                    stats.balance -= spent.value;
+                    stats.utxo_count -= 1;
+                    stats.last_active_height = block_height;
+                }
+            }
+            for (i, output) in tx.value.outputs.iter().enumerate() {
+                let address = match &output.script.address {
+                    Some(address) => address.clone(),
+                    None => {
+                        let script_type = output.script.pattern.type_name();
+                        self.script_types.insert(script_type);
+                        String::from(script_type)
+                    }
+                };
+                let stats = self
+                    .addresses
+                    .entry(address.clone())
+                    .or_insert_with(|| AddressStats {
+                        first_seen_height: block_height,
+                        ..Default::default()
+                    });
+                stats.balance += output.out.value;
+                stats.utxo_count += 1;
+                stats.last_active_height = block_height;
+
+                let unspent = common::UnspentValue {
+                    block_height,
+                    value: output.out.value,
+                    address,
+                };
+                let key = TxOutpoint::new(tx.hash, i as u32).to_bytes();
+                // Duplicate txids (BIP30) overwrite the earlier outputs
+                if let Some(replaced) = self.unspents.insert(key, unspent) {
+                    let stats = self.addresses.get_mut(&replaced.address).unwrap();
+                    stats.balance -= replaced.value;
+                    stats.utxo_count -= 1;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        let tmp_path = self.output_dir.join("richlist.csv.tmp");
+        let mut writer = BufWriter::with_capacity(4000000, File::create(&tmp_path)?);
+        writer.write_all(b"address;balance;utxo_count;first_seen_height;last_active_height\n")?;
+        let top = self.top_addresses();
+        for (address, stats) in &top {
+            writer.write_all(
+                format!(
+                    "{};{};{};{};{}\n",
+                    address,
+                    stats.balance,
+                    stats.utxo_count,
+                    stats.first_seen_height,
+                    stats.last_active_height
+                )
+                .as_bytes(),
+            )?;
+        }
+        writer.flush()?;
+        fs::rename(&tmp_path, self.output_dir.join("richlist.csv"))?;
+
+        let mut buffer = Vec::with_capacity(4096);
+        self.write_distribution(&mut buffer)?;
+        info!(target: "callback", "Done.\nWrote top {} addresses of blocks {} to {}.\n{}",
+              top.len(), self.start_height, block_height, String::from_utf8_lossy(&buffer));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+    use crate::blockchain::parser::types::Bitcoin;
+    use crate::callbacks::fixtures::{new_chain, new_tx, new_tx_to, SCRIPT_A, SCRIPT_B};
+
+    const ADDRESS_A: &str = "12higDjoCCNXSA95xZMWUdPvXNmkAduhWv";
+    const ADDRESS_B: &str = "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa";
+    // OP_RETURN "hello world"
+    const DATA_SCRIPT: &str = "6a0b68656c6c6f20776f726c64";
+    // OP_TRUE
+    const NONSTANDARD_SCRIPT: &str = "51";
+
+    fn run(top: &str) -> (RichList, Vec<String>) {
+        let dir = env::temp_dir().join(format!("blockparser-richlist-{}-{}", top, process::id()));
+        let matches = RichList::build_subcommand().get_matches_from(vec![
+            "richlist",
+            "--output-dir",
+            dir.to_str().unwrap(),
+            "--top",
+            top,
+        ]);
+        let coinbase_0 = new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[5000000000]);
+        let spend = new_tx_to(
+            &[(coinbase_0.hash, 0)],
+            &[
+                (1000000000, SCRIPT_B),
+                (5000, NONSTANDARD_SCRIPT),
+                (100, DATA_SCRIPT),
+                (3999985000, SCRIPT_A),
+            ],
+        );
+        let coinbase_1 = new_tx_to(
+            &[([0u8; 32], 0xFFFFFFFF)],
+            &[(5000010000, NONSTANDARD_SCRIPT)],
+        );
+        let spend_b = new_tx_to(&[(spend.hash, 0)], &[(999990000, SCRIPT_A)]);
+        let coinbase_2 = new_tx_to(&[([0u8; 32], 0xFFFFFFFF)], &[(5000010000, SCRIPT_B)]);
+        let chain = new_chain(vec![
+            vec![coinbase_0],
+            vec![coinbase_1, spend],
+            vec![coinbase_2, spend_b],
+        ]);
+
+        let mut richlist = RichList::new(&matches).unwrap();
+        richlist.on_start(&CoinType::from(Bitcoin), 0).unwrap();
+        for (height, block) in chain.iter().enumerate() {
+            richlist.on_block(block, height as u64).unwrap();
+        }
+        richlist.on_complete(2).unwrap();
+        let csv = fs::read_to_string(dir.join("richlist.csv")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        (richlist, csv.lines().map(String::from).collect())
+    }
+
+    #[test]
+    fn test_richlist() {
+        let (richlist, lines) = run("10");
+        assert_eq!(
+            vec![
+                String::from("address;balance;utxo_count;first_seen_height;last_active_height"),
+                String::from("nonstandard;5000015000;2;1;1"),
+                format!("{};5000010000;1;1;2", ADDRESS_B),
+                format!("{};4999975000;2;0;2", ADDRESS_A),
+                String::from("nulldata;100;1;1;1"),
+            ],
+            lines
+        );
+
+        let mut buffer = Vec::new();
+        richlist.write_distribution(&mut buffer).unwrap();
+        let distribution = String::from_utf8(buffer).unwrap();
+        assert!(distribution.contains("Addresses:    2 (and 2 script types without address)\n"));
+        // Block rewards and fees of the three coinbases
+        assert!(distribution.contains("Total amount: 150.000001\n"));
+        assert!(distribution.contains(">        0.001:          2 addresses holding 99.99985\n"));
+        assert!(distribution.contains(">          100:          0 addresses holding 0\n"));
+    }
+
+    #[test]
+    fn test_top() {
+        let (_, lines) = run("2");
+        assert_eq!(3, lines.len());
+        assert_eq!("nonstandard;5000015000;2;1;1", lines[1]);
+        assert!(lines[2].starts_with(ADDRESS_B));
+
+        let matches = RichList::build_subcommand().get_matches_from(vec![
+            "richlist",
+            "--output-dir",
+            "/tmp",
+            "--top",
+            "0",
+        ]);
+        assert!(RichList::new(&matches).is_err());
+    }
+}
@@ -0,0 +1,607 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use crate::blockchain::parser::types::CoinType;
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::script::ScriptPattern;
+use crate::blockchain::proto::target::{self, U256};
+use crate::blockchain::proto::tx::{EvaluatedTx, EvaluatedTxOut, TxInput};
+use crate::blockchain::proto::Hashed;
+use crate::callbacks::common::{base_units, wtx_hash};
+use crate::callbacks::Callback;
+use crate::common::sqlite::{Connection, Value};
+use crate::common::utils;
+use crate::errors::{OpError, OpResult};
+
+const DEFAULT_BATCH_SIZE: usize = 500;
+
+/// Tables use the same field names as the mongo documents.
+/// The outpoint index is created right away, it is needed to resolve previous outputs.
+const CREATE_TABLES: &str = "
+PRAGMA journal_mode = WAL;
+PRAGMA synchronous = NORMAL;
+CREATE TABLE IF NOT EXISTS blocks (
+    hash TEXT NOT NULL,
+    blockHeight INTEGER NOT NULL,
+    version INTEGER NOT NULL,
+    size INTEGER NOT NULL,
+    previousHash TEXT NOT NULL,
+    merkleRootHash TEXT NOT NULL,
+    timestamp INTEGER NOT NULL,
+    nBits INTEGER NOT NULL,
+    nNonce INTEGER NOT NULL,
+    txCount INTEGER NOT NULL,
+    difficulty REAL,
+    target TEXT,
+    chainwork TEXT,
+    reward INTEGER NOT NULL,
+    totalFees INTEGER
+);
+CREATE TABLE IF NOT EXISTS transactions (
+    txHash TEXT NOT NULL,
+    wtxHash TEXT NOT NULL,
+    hasWitness INTEGER NOT NULL,
+    blockHash TEXT NOT NULL,
+    blockHeight INTEGER NOT NULL,
+    version INTEGER NOT NULL,
+    lockTime INTEGER NOT NULL,
+    isCoinbase INTEGER NOT NULL,
+    inputCount INTEGER NOT NULL,
+    outputCount INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS tx_inputs (
+    txHash TEXT NOT NULL,
+    indexIn INTEGER NOT NULL,
+    hashPrevOut TEXT NOT NULL,
+    indexPrevOut INTEGER NOT NULL,
+    scriptSig TEXT NOT NULL,
+    sequenceNumber INTEGER NOT NULL,
+    value INTEGER,
+    address TEXT,
+    scriptType TEXT,
+    resolution TEXT
+);
+CREATE TABLE IF NOT EXISTS tx_outputs (
+    txHash TEXT NOT NULL,
+    indexOut INTEGER NOT NULL,
+    value INTEGER NOT NULL,
+    scriptPubKey TEXT NOT NULL,
+    address TEXT,
+    scriptType TEXT NOT NULL,
+    dataHex TEXT
+);
+CREATE INDEX IF NOT EXISTS tx_outputs_txHash_indexOut ON tx_outputs (txHash, indexOut);
+";
+
+const CREATE_INDEXES: &str = "
+CREATE INDEX IF NOT EXISTS blocks_hash ON blocks (hash);
+CREATE INDEX IF NOT EXISTS blocks_blockHeight ON blocks (blockHeight);
+CREATE INDEX IF NOT EXISTS transactions_txHash ON transactions (txHash);
+CREATE INDEX IF NOT EXISTS transactions_blockHeight ON transactions (blockHeight);
+CREATE INDEX IF NOT EXISTS tx_inputs_txHash ON tx_inputs (txHash);
+CREATE INDEX IF NOT EXISTS tx_inputs_address ON tx_inputs (address);
+CREATE INDEX IF NOT EXISTS tx_outputs_address ON tx_outputs (address);
+ANALYZE;
+";
+
+const INSERT_BLOCK: &str = "INSERT INTO blocks (hash, blockHeight, version, size, previousHash,
+    merkleRootHash, timestamp, nBits, nNonce, txCount, difficulty, target, chainwork, reward,
+    totalFees) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)";
+const INSERT_TX: &str = "INSERT INTO transactions (txHash, wtxHash, hasWitness, blockHash,
+    blockHeight, version, lockTime, isCoinbase, inputCount, outputCount)
+    VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)";
+const INSERT_INPUT: &str = "INSERT INTO tx_inputs (txHash, indexIn, hashPrevOut, indexPrevOut,
+    scriptSig, sequenceNumber, value, address, scriptType, resolution)
+    VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)";
+const INSERT_OUTPUT: &str = "INSERT INTO tx_outputs (txHash, indexOut, value, scriptPubKey,
+    address, scriptType, dataHex) VALUES (?, ?, ?, ?, ?, ?, ?)";
+// Duplicate txids are possible (BIP30), the most recent output wins
+const SELECT_OUTPUT: &str = "SELECT value, address, scriptType FROM tx_outputs
+    WHERE txHash = ? AND indexOut = ? ORDER BY rowid DESC LIMIT 1";
+const SELECT_CHAINWORK: &str = "SELECT chainwork FROM blocks WHERE blockHeight = ?
+    ORDER BY rowid DESC LIMIT 1";
+
+/// Details of a previous output which are copied into the spending input
+struct PrevOut {
+    value: i64,
+    address: Option<String>,
+    script_type: Option<String>,
+}
+
+/// Dumps the whole blockchain into a single SQLite database file
+pub struct Sqlite {
+    path: PathBuf,
+    conn: Option<Connection>,
+    genesis_bits: u32,
+    chainwork: Option<U256>,
+
+    // Outputs created in the open transaction: (txid, index) -> output
+    outputs: HashMap<([u8; 32], u32), PrevOut>,
+    batch_size: usize,
+    n_buffered: usize,
+
+    start_height: u64,
+    end_height: u64,
+    tx_count: u64,
+    in_count: u64,
+    out_count: u64,
+    n_lookups: u64,
+    n_missing: u64,
+}
+
+impl Callback for Sqlite {
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b>
+    where
+        Self: Sized,
+    {
+        SubCommand::with_name("sqlite")
+            .about("Dumps the whole blockchain into a SQLite database file")
+            .version("0.1")
+            .arg(
+                Arg::with_name("output")
+                    .long("output")
+                    .value_name("PATH")
+                    .help("Database file, created if it doesn't exist")
+                    .takes_value(true)
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name("batch-size")
+                    .long("batch-size")
+                    .value_name("N")
+                    .help("Number of blocks per transaction (default: 500)")
+                    .takes_value(true),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let cb = Sqlite {
+            path: PathBuf::from(matches.value_of("output").unwrap()),
+            conn: None,
+            genesis_bits: 0,
+            chainwork: None,
+            outputs: HashMap::new(),
+            batch_size: value_t!(matches, "batch-size", usize)
+                .unwrap_or(DEFAULT_BATCH_SIZE)
+                .max(1),
+            n_buffered: 0,
+            start_height: 0,
+            end_height: 0,
+            tx_count: 0,
+            in_count: 0,
+            out_count: 0,
+            n_lookups: 0,
+            n_missing: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, coin_type: &CoinType, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        self.genesis_bits = coin_type.genesis_bits;
+        info!(target: "callback", "Using `sqlite` with database {} ...", self.path.display());
+        let mut conn = Connection::open(&self.path).map_err(|e| {
+            OpError::from(e).join_msg(&format!("Unable to open {}.", self.path.display()))
+        })?;
+        conn.execute_batch(CREATE_TABLES)?;
+
+        // Continue the chain work of a previous run if the database contains the parent block
+        self.chainwork = match block_height {
+            0 => Some(U256::ZERO),
+            _ => conn
+                .query_row(
+                    SELECT_CHAINWORK,
+                    &[Value::Integer(block_height as i64 - 1)],
+                    |row| row.get_text(0),
+                )?
+                .flatten()
+                .and_then(|hex| U256::from_hex(&hex)),
+        };
+        self.conn = Some(conn);
+        Ok(())
+    }
+
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<()> {
+        if self.n_buffered == 0 {
+            self.connection()?.execute_batch("BEGIN")?;
+        }
+        let block_hash = utils::arr_to_hex_swapped(&block.header.hash);
+        let mut reward: u64 = 0;
+        // None as soon as a single input value is unknown
+        let mut total_fees: Option<i64> = Some(0);
+
+        // Inputs may spend outputs of previous transactions, so they are inserted in order
+        for tx in &block.txs {
+            self.insert_tx(tx, &block_hash, block_height)?;
+            let input_value = self.insert_inputs(tx)?;
+            let output_value: u64 = tx.value.outputs.iter().map(|o| o.out.value).sum();
+            if tx.value.is_coinbase() {
+                reward += output_value;
+            } else {
+                total_fees = total_fees.zip(input_value).map(|(fees, input_value)| {
+                    // Zcash transactions may take value out of or put it into the shielded pools
+                    fees + input_value + tx.value.shielded_value_delta() - base_units(output_value)
+                });
+            }
+            self.insert_outputs(tx)?;
+            self.in_count += tx.value.in_count.value;
+            self.out_count += tx.value.out_count.value;
+        }
+        self.tx_count += block.tx_count.value;
+
+        if let Some(chainwork) = self.chainwork.as_mut() {
+            let target = U256::from_compact(block.header.value.bits).unwrap_or_default();
+            *chainwork = *chainwork + target.work();
+        }
+        self.insert_block(block, &block_hash, block_height, reward, total_fees)?;
+
+        self.n_buffered += 1;
+        if self.n_buffered >= self.batch_size {
+            self.commit()?;
+        }
+        Ok(())
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        self.end_height = block_height;
+        self.commit()?;
+        info!(target: "callback", "Creating indexes ...");
+        self.connection()?.execute_batch(CREATE_INDEXES)?;
+
+        info!(target: "callback", "Done.\nDumped all {} blocks into {}:\n\
+                                   \t-> transactions:    {:9}\n\
+                                   \t-> inputs:          {:9}\n\
+                                   \t-> outputs:         {:9}\n\
+                                   \t-> output lookups:  {:9}\n\
+                                   \t-> missing outputs: {:9}",
+             self.end_height, self.path.display(), self.tx_count, self.in_count,
+             self.out_count, self.n_lookups, self.n_missing);
+        Ok(())
+    }
+}
+
+impl Sqlite {
+    fn connection(&mut self) -> OpResult<&mut Connection> {
+        self.conn
+            .as_mut()
+            .ok_or_else(|| OpError::from(String::from("SQLite database is not open.")))
+    }
+
+    /// Commits the open transaction, the buffered outputs are looked up in the database from now on
+    fn commit(&mut self) -> OpResult<()> {
+        if self.n_buffered == 0 {
+            return Ok(());
+        }
+        self.connection()?.execute_batch("COMMIT")?;
+        debug!(target: "sqlite", "Committed {} blocks", self.n_buffered);
+        self.n_buffered = 0;
+        self.outputs.clear();
+        Ok(())
+    }
+
+    fn insert_block(
+        &mut self,
+        block: &Block,
+        block_hash: &str,
+        block_height: u64,
+        reward: u64,
+        total_fees: Option<i64>,
+    ) -> OpResult<()> {
+        let header = &block.header.value;
+        let target = U256::from_compact(header.bits).map(|target| target.to_hex());
+        let chainwork = self.chainwork.map(|work| work.to_hex());
+        let prev_hash = utils::arr_to_hex_swapped(&header.prev_hash);
+        let merkle_root = utils::arr_to_hex_swapped(&header.merkle_root);
+        let params = [
+            Value::from(block_hash),
+            Value::Integer(block_height as i64),
+            Value::from(header.version),
+            Value::from(block.size),
+            Value::from(prev_hash.as_str()),
+            Value::from(merkle_root.as_str()),
+            Value::from(header.timestamp),
+            Value::from(header.bits),
+            Value::from(header.nonce),
+            Value::Integer(block.tx_count.value as i64),
+            Value::from(target::difficulty(header.bits, self.genesis_bits)),
+            Value::from(target.as_deref()),
+            Value::from(chainwork.as_deref()),
+            Value::Integer(base_units(reward)),
+            Value::from(total_fees),
+        ];
+        self.connection()?.execute(INSERT_BLOCK, &params)?;
+        Ok(())
+    }
+
+    fn insert_tx(
+        &mut self,
+        tx: &Hashed<EvaluatedTx>,
+        block_hash: &str,
+        block_height: u64,
+    ) -> OpResult<()> {
+        let txid = utils::arr_to_hex_swapped(&tx.hash);
+        let wtxid = wtx_hash(tx);
+        let params = [
+            Value::from(txid.as_str()),
+            Value::from(wtxid.as_str()),
+            Value::from(tx.value.has_witness()),
+            Value::from(block_hash),
+            Value::Integer(block_height as i64),
+            Value::from(tx.value.version),
+            Value::from(tx.value.locktime),
+            Value::from(tx.value.is_coinbase()),
+            Value::Integer(tx.value.in_count.value as i64),
+            Value::Integer(tx.value.out_count.value as i64),
+        ];
+        self.connection()?.execute(INSERT_TX, &params)?;
+        Ok(())
+    }
+
+    /// Inserts the inputs together with the details of the spent outputs.
+    /// Returns the sum of all input values, which is None if a previous output is missing.
+    fn insert_inputs(&mut self, tx: &Hashed<EvaluatedTx>) -> OpResult<Option<i64>> {
+        let txid = utils::arr_to_hex_swapped(&tx.hash);
+        let mut input_value = Some(0);
+        for (i, input) in tx.value.inputs.iter().enumerate() {
+            // Coinbase inputs don't spend anything
+            let prev_out = if input.outpoint.txid == [0u8; 32] {
+                Some(PrevOut {
+                    value: 0,
+                    address: None,
+                    script_type: None,
+                })
+            } else {
+                self.resolve(input)?
+            };
+            if prev_out.is_none() {
+                debug!(target: "sqlite", "Missing previous output {}:{} of {}",
+                       utils::arr_to_hex_swapped(&input.outpoint.txid), input.outpoint.index, txid);
+                self.n_missing += 1;
+            }
+            input_value = input_value
+                .zip(prev_out.as_ref())
+                .map(|(sum, prev_out)| sum + prev_out.value);
+            let hash_prev_out = utils::arr_to_hex_swapped(&input.outpoint.txid);
+            let script_sig = utils::arr_to_hex(&input.script_sig);
+            let params = [
+                Value::from(txid.as_str()),
+                Value::from(i as u32),
+                Value::from(hash_prev_out.as_str()),
+                Value::from(input.outpoint.index),
+                Value::from(script_sig.as_str()),
+                Value::from(input.seq_no),
+                Value::from(prev_out.as_ref().map(|prev_out| prev_out.value)),
+                Value::from(
+                    prev_out
+                        .as_ref()
+                        .and_then(|prev_out| prev_out.address.as_deref()),
+                ),
+                Value::from(
+                    prev_out
+                        .as_ref()
+                        .and_then(|prev_out| prev_out.script_type.as_deref()),
+                ),
+                Value::from(prev_out.as_ref().map_or(Some("missing"), |_| None)),
+            ];
+            self.connection()?.execute(INSERT_INPUT, &params)?;
+        }
+        Ok(input_value)
+    }
+
+    fn insert_outputs(&mut self, tx: &Hashed<EvaluatedTx>) -> OpResult<()> {
+        let txid = utils::arr_to_hex_swapped(&tx.hash);
+        for (i, output) in tx.value.outputs.iter().enumerate() {
+            let data_hex = match output.script.pattern {
+                ScriptPattern::DataOutput(_) => {
+                    Some(utils::arr_to_hex(&output.data_output_payload()))
+                }
+                _ => None,
+            };
+            let script_pubkey = utils::arr_to_hex(&output.out.script_pubkey);
+            let params = [
+                Value::from(txid.as_str()),
+                Value::from(i as u32),
+                Value::Integer(base_units(output.out.value)),
+                Value::from(script_pubkey.as_str()),
+                Value::from(output.script.address.as_deref()),
+                Value::from(output.script.pattern.type_name()),
+                Value::from(data_hex.as_deref()),
+            ];
+            self.connection()?.execute(INSERT_OUTPUT, &params)?;
+            let replaced = self
+                .outputs
+                .insert((tx.hash, i as u32), output.as_prev_out());
+            // A coinbase repeating the txid of an earlier one before BIP30, its rows are
+            // written twice but only the later outputs can be spent
+            if replaced.is_some() && i == 0 {
+                warn!(target: "sqlite", "Duplicate txid {}, its outputs replace the unspent outputs of the earlier transaction.", txid);
+            }
+        }
+        Ok(())
+    }
+
+    /// Looks up the spent output in the open transaction first, then in the database
+    fn resolve(&mut self, input: &TxInput) -> OpResult<Option<PrevOut>> {
+        let outpoint = &input.outpoint;
+        if let Some(prev_out) = self.outputs.remove(&(outpoint.txid, outpoint.index)) {
+            return Ok(Some(prev_out));
+        }
+        self.n_lookups += 1;
+        let txid = utils::arr_to_hex_swapped(&outpoint.txid);
+        let params = [Value::from(txid.as_str()), Value::from(outpoint.index)];
+        let prev_out = self
+            .connection()?
+            .query_row(SELECT_OUTPUT, &params, |row| {
+                row.get_i64(0).map(|value| PrevOut {
+                    value,
+                    address: row.get_text(1),
+                    script_type: row.get_text(2),
+                })
+            })?
+            .flatten();
+        Ok(prev_out)
+    }
+}
+
+impl EvaluatedTxOut {
+    #[inline]
+    fn as_prev_out(&self) -> PrevOut {
+        PrevOut {
+            value: base_units(self.out.value),
+            address: self.script.address.clone(),
+            script_type: Some(String::from(self.script.pattern.type_name())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use super::*;
+    use crate::blockchain::parser::types::Bitcoin;
+    use crate::callbacks::fixtures::{new_chain, new_tx, new_tx_to, SCRIPT_A, SCRIPT_B};
+
+    const ADDRESS_A: &str = "12higDjoCCNXSA95xZMWUdPvXNmkAduhWv";
+    const ADDRESS_B: &str = "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa";
+
+    fn count(conn: &mut Connection, sql: &'static str) -> i64 {
+        conn.query_row(sql, &[], |row| row.get_i64(0))
+            .unwrap()
+            .flatten()
+            .unwrap()
+    }
+
+    fn balance(conn: &mut Connection, address: &str) -> i64 {
+        conn.query_row(
+            "SELECT (SELECT total(value) FROM tx_outputs WHERE address = ?1)
+                  - (SELECT total(value) FROM tx_inputs WHERE address = ?1)",
+            &[Value::from(address)],
+            |row| row.get_f64(0),
+        )
+        .unwrap()
+        .flatten()
+        .unwrap() as i64
+    }
+
+    #[test]
+    fn test_sqlite_dump() {
+        let path = env::temp_dir().join(format!("blockparser-{}.sqlite", process::id()));
+        let path_str = path.to_str().unwrap();
+        let matches = Sqlite::build_subcommand().get_matches_from(vec![
+            "sqlite",
+            "--output",
+            path_str,
+            "--batch-size",
+            "1",
+        ]);
+
+        let coinbase_0 = new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[5000000000]);
+        let coinbase_1 = new_tx_to(&[([0u8; 32], 0xFFFFFFFF)], &[(5000010000, SCRIPT_B)]);
+        let tx_1 = new_tx_to(
+            &[(coinbase_0.hash, 0)],
+            &[(1000000000, SCRIPT_B), (3999990000, SCRIPT_A)],
+        );
+        let coinbase_2 = new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[5000020000]);
+        // Spends an output of the previous batch, which has to be looked up in the database
+        let tx_2 = new_tx(&[(tx_1.hash, 0)], &[999990000]);
+        // Spends an output of the same block
+        let tx_3 = new_tx_to(&[(tx_2.hash, 0)], &[(999980000, SCRIPT_B)]);
+        // Spends an unknown output
+        let tx_4 = new_tx(&[([1u8; 32], 0)], &[1000]);
+        let chain = new_chain(vec![
+            vec![coinbase_0],
+            vec![coinbase_1, tx_1],
+            vec![coinbase_2, tx_2, tx_3, tx_4],
+        ]);
+
+        let mut sqlite = Sqlite::new(&matches).unwrap();
+        sqlite.on_start(&CoinType::from(Bitcoin), 0).unwrap();
+        for (height, block) in chain.iter().enumerate() {
+            sqlite.on_block(block, height as u64).unwrap();
+        }
+        sqlite.on_complete(2).unwrap();
+        // Every block is committed on its own, only tx_3 finds its previous output in memory
+        assert_eq!(3, sqlite.n_lookups);
+        assert_eq!(1, sqlite.n_missing);
+        drop(sqlite);
+
+        let mut conn = Connection::open(&path).unwrap();
+        assert_eq!(3, count(&mut conn, "SELECT count(*) FROM blocks"));
+        assert_eq!(7, count(&mut conn, "SELECT count(*) FROM transactions"));
+        assert_eq!(7, count(&mut conn, "SELECT count(*) FROM tx_inputs"));
+        assert_eq!(8, count(&mut conn, "SELECT count(*) FROM tx_outputs"));
+        assert_eq!(
+            1,
+            count(
+                &mut conn,
+                "SELECT count(*) FROM tx_inputs WHERE resolution = 'missing'"
+            )
+        );
+        assert_eq!(9000011000, balance(&mut conn, ADDRESS_A));
+        assert_eq!(5999990000, balance(&mut conn, ADDRESS_B));
+
+        let fees = conn
+            .query_row(
+                "SELECT reward, totalFees, chainwork FROM blocks ORDER BY blockHeight",
+                &[],
+                |row| (row.get_i64(0), row.get_i64(1), row.get_text(2)),
+            )
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            (
+                Some(5000000000),
+                Some(0),
+                Some(U256::from_u64(0x100010001).to_hex())
+            ),
+            fees
+        );
+        // Fees are unknown as soon as an input can't be resolved
+        let fees = conn
+            .query_row(
+                "SELECT totalFees FROM blocks WHERE blockHeight = 2",
+                &[],
+                |row| row.get_i64(0),
+            )
+            .unwrap()
+            .unwrap();
+        assert_eq!(None, fees);
+        let fees = conn
+            .query_row(
+                "SELECT totalFees FROM blocks WHERE blockHeight = 1",
+                &[],
+                |row| row.get_i64(0),
+            )
+            .unwrap()
+            .unwrap();
+        assert_eq!(Some(10000), fees);
+        drop(conn);
+
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = fs::remove_file(format!("{}{}", path_str, suffix));
+        }
+    }
+
+    #[test]
+    fn test_invalid_path() {
+        let matches = Sqlite::build_subcommand().get_matches_from(vec![
+            "sqlite",
+            "--output",
+            "/nonexistent/dir/chain.sqlite",
+        ]);
+        let mut sqlite = Sqlite::new(&matches).unwrap();
+        let err = sqlite
+            .on_start(&CoinType::from(Bitcoin), 0)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("Unable to open"), "{}", err);
+    }
+}
@@ -0,0 +1,563 @@
+//! Column definitions of the csv dumps, from which `--emit-sql` generates the tables and the
+//! import script of a database. The header rows of the dumps are written from the same
+//! definitions, so the scripts always match the files.
+//!
+//! Hashes and scripts stay hex unless the dialect can decode them while loading (MySQL), empty
+//! fields of nullable columns become NULL. Indexes are created after the load, which is faster,
+//! ClickHouse gets them as sorting key and skip indexes of the tables instead.
+
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+
+use crate::callbacks::common::Compression;
+use crate::errors::{OpError, OpResult};
+
+pub const SCHEMA_FILE: &str = "schema.sql";
+pub const IMPORT_FILE: &str = "import.sql";
+
+/// Database of the generated scripts
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Dialect {
+    Postgres,
+    Mysql,
+    Sqlite,
+    Clickhouse,
+}
+
+impl Dialect {
+    /// Parses `postgres`, `mysql`, `sqlite` or `clickhouse`
+    pub fn from_name(name: &str) -> OpResult<Self> {
+        match name {
+            "postgres" => Ok(Dialect::Postgres),
+            "mysql" => Ok(Dialect::Mysql),
+            "sqlite" => Ok(Dialect::Sqlite),
+            "clickhouse" => Ok(Dialect::Clickhouse),
+            _ => Err(OpError::from(format!(
+                "Unknown SQL dialect `{}`, expected postgres, mysql, sqlite or clickhouse.",
+                name
+            ))),
+        }
+    }
+
+    /// Fails if the database can't load files of the compression, `LOAD DATA` only reads
+    /// plain files
+    pub fn check_compression(&self, compression: Compression) -> OpResult<()> {
+        if *self == Dialect::Mysql && compression != Compression::None {
+            return Err(OpError::from(String::from(
+                "--emit-sql mysql can't load compressed files, MySQL only reads plain csv.",
+            )));
+        }
+        Ok(())
+    }
+
+    fn quote(&self, name: &str) -> String {
+        match self {
+            Dialect::Mysql | Dialect::Clickhouse => format!("`{}`", name),
+            Dialect::Postgres | Dialect::Sqlite => format!("\"{}\"", name),
+        }
+    }
+
+    fn column_type(&self, column: &Column) -> String {
+        let name = match (self, column.kind) {
+            (Dialect::Postgres, ColumnType::Hash) => String::from("CHAR(64)"),
+            (Dialect::Postgres, ColumnType::Bytes) => String::from("TEXT"),
+            // Without unsigned types
+            (Dialect::Postgres, ColumnType::U32 | ColumnType::U64) => String::from("BIGINT"),
+            (Dialect::Postgres, ColumnType::Bool) => String::from("BOOLEAN"),
+            (Dialect::Postgres | Dialect::Mysql, ColumnType::Text(len)) => {
+                format!("VARCHAR({})", len)
+            }
+            (Dialect::Mysql, ColumnType::Hash) => String::from("BINARY(32)"),
+            // Up to 16 MiB, revealed scripts may be larger than 64 KiB
+            (Dialect::Mysql, ColumnType::Bytes) => String::from("MEDIUMBLOB"),
+            (Dialect::Mysql, ColumnType::U32) => String::from("INT UNSIGNED"),
+            (Dialect::Mysql, ColumnType::U64) => String::from("BIGINT UNSIGNED"),
+            (Dialect::Mysql, ColumnType::Bool) => String::from("BOOLEAN"),
+            (Dialect::Sqlite, ColumnType::U32 | ColumnType::U64 | ColumnType::Bool) => {
+                String::from("INTEGER")
+            }
+            (Dialect::Sqlite, _) => String::from("TEXT"),
+            (Dialect::Clickhouse, ColumnType::Hash) => String::from("FixedString(64)"),
+            (Dialect::Clickhouse, ColumnType::Bytes | ColumnType::Text(_)) => {
+                String::from("String")
+            }
+            (Dialect::Clickhouse, ColumnType::U32) => String::from("UInt32"),
+            (Dialect::Clickhouse, ColumnType::U64) => String::from("UInt64"),
+            (Dialect::Clickhouse, ColumnType::Bool) => String::from("Bool"),
+        };
+        match (self, column.nullable) {
+            (Dialect::Clickhouse, true) => format!("Nullable({})", name),
+            (Dialect::Clickhouse, false) => name,
+            (_, true) => name,
+            (_, false) => format!("{} NOT NULL", name),
+        }
+    }
+
+    /// String literal of the delimiter
+    fn delimiter(&self, delimiter: char) -> String {
+        match (self, delimiter) {
+            (Dialect::Postgres, '\t') => String::from("E'\\t'"),
+            (Dialect::Sqlite, '\t') => String::from("\"\\t\""),
+            (_, '\t') => String::from("'\\t'"),
+            (Dialect::Sqlite, c) => format!("\"{}\"", c),
+            (_, '\'') => String::from("''''"),
+            (Dialect::Mysql | Dialect::Clickhouse, '\\') => String::from("'\\\\'"),
+            (_, c) => format!("'{}'", c),
+        }
+    }
+}
+
+/// Content of a csv column
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColumnType {
+    /// Hex of a 32 byte hash
+    Hash,
+    /// Hex of a script or other data of any length
+    Bytes,
+    U32,
+    U64,
+    /// `true` or `false`
+    Bool,
+    /// Text of at most the given length, e.g. addresses
+    Text(u32),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Column {
+    pub name: &'static str,
+    pub kind: ColumnType,
+    /// Empty fields are NULL
+    pub nullable: bool,
+}
+
+impl Column {
+    pub const fn new(name: &'static str, kind: ColumnType) -> Self {
+        Column {
+            name,
+            kind,
+            nullable: false,
+        }
+    }
+
+    pub const fn nullable(name: &'static str, kind: ColumnType) -> Self {
+        Column {
+            name,
+            kind,
+            nullable: true,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Index {
+    pub columns: &'static [&'static str],
+    pub unique: bool,
+}
+
+impl Index {
+    pub const fn new(columns: &'static [&'static str]) -> Self {
+        Index {
+            columns,
+            unique: false,
+        }
+    }
+
+    pub const fn unique(columns: &'static [&'static str]) -> Self {
+        Index {
+            columns,
+            unique: true,
+        }
+    }
+}
+
+/// Table of a dump. The first index is the sorting key on ClickHouse, so its columns must not
+/// be nullable.
+#[derive(Debug, PartialEq)]
+pub struct Table {
+    pub name: &'static str,
+    pub columns: Vec<Column>,
+    pub indexes: &'static [Index],
+}
+
+impl Table {
+    pub fn column_names(&self) -> Vec<&'static str> {
+        self.columns.iter().map(|column| column.name).collect()
+    }
+
+    fn index_name(&self, index: &Index) -> String {
+        format!("{}_{}", self.name, index.columns.join("_"))
+    }
+}
+
+/// How the files of a dump are written
+#[derive(Clone, Copy, Debug)]
+pub struct CsvFormat {
+    pub delimiter: char,
+    pub header: bool,
+    pub compression: Compression,
+}
+
+/// `CREATE TABLE` statements of the tables
+pub fn schema_sql(dialect: Dialect, tables: &[Table]) -> String {
+    let mut sql = format!(
+        "-- Tables of the csv dump, generated by rusty-blockparser --emit-sql {}\n",
+        dialect_name(dialect)
+    );
+    for table in tables {
+        let _ = writeln!(
+            sql,
+            "\nCREATE TABLE IF NOT EXISTS {} (",
+            dialect.quote(table.name)
+        );
+        let mut lines: Vec<String> = table
+            .columns
+            .iter()
+            .map(|column| {
+                format!(
+                    "    {} {}",
+                    dialect.quote(column.name),
+                    dialect.column_type(column)
+                )
+            })
+            .collect();
+        if dialect == Dialect::Clickhouse {
+            for index in table.indexes.iter().skip(1) {
+                lines.push(format!(
+                    "    INDEX {} ({}) TYPE bloom_filter GRANULARITY 4",
+                    dialect.quote(&table.index_name(index)),
+                    quote_all(dialect, index.columns)
+                ));
+            }
+        }
+        sql.push_str(&lines.join(",\n"));
+        sql.push_str("\n)");
+        if dialect == Dialect::Clickhouse {
+            let key = match table.indexes.first() {
+                Some(index) => format!("({})", quote_all(dialect, index.columns)),
+                None => String::from("tuple()"),
+            };
+            let _ = write!(sql, " ENGINE = MergeTree ORDER BY {}", key);
+        }
+        sql.push_str(";\n");
+    }
+    sql
+}
+
+/// Statements which load the files, given as (table, file name) in load order, and create the
+/// indexes. File names are relative to the dump folder, the script has to run there.
+pub fn import_sql(
+    dialect: Dialect,
+    tables: &[Table],
+    files: &[(&str, String)],
+    format: &CsvFormat,
+) -> String {
+    let mut sql = String::from(match dialect {
+        Dialect::Postgres => "-- Run in the dump folder: psql -f import.sql\n",
+        Dialect::Mysql => {
+            "-- Run in the dump folder with local_infile enabled: mysql --local-infile=1 <database> < import.sql\n"
+        }
+        Dialect::Sqlite => "-- Run in the dump folder: sqlite3 <database> < import.sql\n",
+        Dialect::Clickhouse => {
+            "-- Run in the dump folder: clickhouse-client --queries-file import.sql\n"
+        }
+    });
+    if dialect == Dialect::Sqlite {
+        let _ = write!(
+            sql,
+            "\n.mode csv\n.separator {} \"\\n\"\n",
+            dialect.delimiter(format.delimiter)
+        );
+    }
+    for (table_name, file) in files {
+        let table = match tables.iter().find(|table| table.name == *table_name) {
+            Some(table) => table,
+            None => continue,
+        };
+        if dialect != Dialect::Sqlite {
+            sql.push('\n');
+        }
+        match dialect {
+            Dialect::Postgres => postgres_copy(&mut sql, table, file, format),
+            Dialect::Mysql => mysql_load(&mut sql, table, file, format),
+            Dialect::Sqlite => sqlite_import(&mut sql, table, file, format),
+            Dialect::Clickhouse => clickhouse_insert(&mut sql, table, file, format),
+        }
+    }
+
+    if dialect == Dialect::Sqlite {
+        // The shell imports empty fields as empty strings and booleans as text
+        for table in tables {
+            let updates: Vec<String> = table
+                .columns
+                .iter()
+                .filter_map(|column| {
+                    let name = dialect.quote(column.name);
+                    match (column.kind, column.nullable) {
+                        (ColumnType::Bool, _) => Some(format!("{} = ({} = 'true')", name, name)),
+                        (_, true) => Some(format!("{} = NULLIF({}, '')", name, name)),
+                        _ => None,
+                    }
+                })
+                .collect();
+            if !updates.is_empty() {
+                let _ = write!(
+                    sql,
+                    "\nUPDATE {} SET {};\n",
+                    dialect.quote(table.name),
+                    updates.join(", ")
+                );
+            }
+        }
+    }
+    if dialect != Dialect::Clickhouse {
+        sql.push('\n');
+        for table in tables {
+            for index in table.indexes {
+                let _ = writeln!(
+                    sql,
+                    "CREATE {}INDEX {} ON {} ({});",
+                    if index.unique { "UNIQUE " } else { "" },
+                    dialect.quote(&table.index_name(index)),
+                    dialect.quote(table.name),
+                    quote_all(dialect, index.columns)
+                );
+            }
+        }
+    }
+    sql
+}
+
+/// Writes `schema.sql` and `import.sql` into the dump folder
+pub fn write_sql(
+    dir: &Path,
+    dialect: Dialect,
+    tables: &[Table],
+    files: &[(&str, String)],
+    format: &CsvFormat,
+) -> OpResult<()> {
+    fs::write(dir.join(SCHEMA_FILE), schema_sql(dialect, tables))?;
+    fs::write(
+        dir.join(IMPORT_FILE),
+        import_sql(dialect, tables, files, format),
+    )?;
+    Ok(())
+}
+
+fn dialect_name(dialect: Dialect) -> &'static str {
+    match dialect {
+        Dialect::Postgres => "postgres",
+        Dialect::Mysql => "mysql",
+        Dialect::Sqlite => "sqlite",
+        Dialect::Clickhouse => "clickhouse",
+    }
+}
+
+fn quote_all(dialect: Dialect, names: &[&str]) -> String {
+    let quoted: Vec<String> = names.iter().map(|name| dialect.quote(name)).collect();
+    quoted.join(", ")
+}
+
+/// Command which writes the decompressed file to stdout
+fn decompress_command(compression: Compression, file: &str) -> Option<String> {
+    match compression {
+        Compression::None => None,
+        Compression::Gzip => Some(format!("gzip -dc {}", file)),
+        Compression::Zstd => Some(format!("zstd -dc {}", file)),
+    }
+}
+
+/// psql `\copy`, which reads the file on the client. Without FORCE_NOT_NULL empty fields of
+/// text columns would be NULL.
+fn postgres_copy(sql: &mut String, table: &Table, file: &str, format: &CsvFormat) {
+    let dialect = Dialect::Postgres;
+    let source = match decompress_command(format.compression, file) {
+        Some(command) => format!("PROGRAM '{}'", command),
+        None => format!("'{}'", file),
+    };
+    let mut options = vec![
+        String::from("FORMAT csv"),
+        format!("DELIMITER {}", dialect.delimiter(format.delimiter)),
+        format!("HEADER {}", format.header),
+    ];
+    let not_null: Vec<&str> = table
+        .columns
+        .iter()
+        .filter(|column| {
+            !column.nullable && matches!(column.kind, ColumnType::Bytes | ColumnType::Text(_))
+        })
+        .map(|column| column.name)
+        .collect();
+    if !not_null.is_empty() {
+        options.push(format!(
+            "FORCE_NOT_NULL ({})",
+            quote_all(dialect, &not_null)
+        ));
+    }
+    let _ = writeln!(
+        sql,
+        "\\copy {} ({}) FROM {} WITH ({})",
+        dialect.quote(table.name),
+        quote_all(dialect, &table.column_names()),
+        source,
+        options.join(", ")
+    );
+}
+
+/// `LOAD DATA`, hex is decoded and empty fields become NULL through user variables
+fn mysql_load(sql: &mut String, table: &Table, file: &str, format: &CsvFormat) {
+    let dialect = Dialect::Mysql;
+    let mut fields = Vec::new();
+    let mut assignments = Vec::new();
+    for column in &table.columns {
+        let name = dialect.quote(column.name);
+        let var = format!("@{}", column.name);
+        let value = match column.nullable {
+            true => format!("NULLIF({}, '')", var),
+            false => var.clone(),
+        };
+        let value = match column.kind {
+            ColumnType::Hash | ColumnType::Bytes => format!("UNHEX({})", value),
+            ColumnType::Bool => format!("({} = 'true')", value),
+            _ if column.nullable => value,
+            _ => {
+                fields.push(name);
+                continue;
+            }
+        };
+        fields.push(var);
+        assignments.push(format!("{} = {}", name, value));
+    }
+    let _ = writeln!(sql, "LOAD DATA LOCAL INFILE '{}'", file);
+    let _ = writeln!(sql, "INTO TABLE {}", dialect.quote(table.name));
+    let _ = writeln!(
+        sql,
+        "FIELDS TERMINATED BY {} OPTIONALLY ENCLOSED BY '\"' ESCAPED BY ''",
+        dialect.delimiter(format.delimiter)
+    );
+    sql.push_str("LINES TERMINATED BY '\\n'\n");
+    if format.header {
+        sql.push_str("IGNORE 1 LINES\n");
+    }
+    let _ = write!(sql, "({})", fields.join(", "));
+    if !assignments.is_empty() {
+        let _ = write!(sql, "\nSET {}", assignments.join(",\n    "));
+    }
+    sql.push_str(";\n");
+}
+
+/// `.import` of the sqlite3 shell, which reads compressed files from a pipe
+fn sqlite_import(sql: &mut String, table: &Table, file: &str, format: &CsvFormat) {
+    let source = match decompress_command(format.compression, file) {
+        Some(command) => format!("'|{}'", command),
+        None => format!("'{}'", file),
+    };
+    let skip = if format.header { "--skip 1 " } else { "" };
+    let _ = writeln!(sql, ".import {}{} {}", skip, source, table.name);
+}
+
+/// `INSERT ... FROM INFILE` of clickhouse-client, empty fields of Nullable columns are NULL
+fn clickhouse_insert(sql: &mut String, table: &Table, file: &str, format: &CsvFormat) {
+    let dialect = Dialect::Clickhouse;
+    let compression = match format.compression {
+        Compression::None => "",
+        Compression::Gzip => " COMPRESSION 'gzip'",
+        Compression::Zstd => " COMPRESSION 'zstd'",
+    };
+    let _ = writeln!(
+        sql,
+        "INSERT INTO {} ({}) FROM INFILE '{}'{} SETTINGS format_csv_delimiter = {} FORMAT {};",
+        dialect.quote(table.name),
+        quote_all(dialect, &table.column_names()),
+        file,
+        compression,
+        dialect.delimiter(format.delimiter),
+        if format.header { "CSVWithNames" } else { "CSV" }
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INDEXES: [Index; 2] = [
+        Index::new(&["txid", "indexOut"]),
+        Index::unique(&["address"]),
+    ];
+
+    fn tables() -> Vec<Table> {
+        vec![Table {
+            name: "outputs",
+            columns: vec![
+                Column::new("txid", ColumnType::Hash),
+                Column::new("indexOut", ColumnType::U32),
+                Column::new("value", ColumnType::U64),
+                Column::new("script", ColumnType::Bytes),
+                Column::nullable("spent", ColumnType::Bool),
+                Column::nullable("address", ColumnType::Text(90)),
+            ],
+            indexes: &INDEXES,
+        }]
+    }
+
+    fn format(delimiter: char, header: bool, compression: Compression) -> CsvFormat {
+        CsvFormat {
+            delimiter,
+            header,
+            compression,
+        }
+    }
+
+    #[test]
+    fn test_schema() {
+        let tables = tables();
+        let postgres = schema_sql(Dialect::Postgres, &tables);
+        assert!(postgres.contains("CREATE TABLE IF NOT EXISTS \"outputs\" (\n    \"txid\" CHAR(64) NOT NULL,\n    \"indexOut\" BIGINT NOT NULL,"));
+        assert!(postgres.contains("    \"address\" VARCHAR(90)\n);\n"));
+        let mysql = schema_sql(Dialect::Mysql, &tables);
+        assert!(mysql.contains("`txid` BINARY(32) NOT NULL,\n    `indexOut` INT UNSIGNED NOT NULL,\n    `value` BIGINT UNSIGNED NOT NULL,\n    `script` MEDIUMBLOB NOT NULL,"));
+        let clickhouse = schema_sql(Dialect::Clickhouse, &tables);
+        assert!(clickhouse.contains("    `spent` Nullable(Bool),\n    `address` Nullable(String),\n    INDEX `outputs_address` (`address`) TYPE bloom_filter GRANULARITY 4\n) ENGINE = MergeTree ORDER BY (`txid`, `indexOut`);"));
+    }
+
+    #[test]
+    fn test_import() {
+        let tables = tables();
+        let files = vec![
+            ("outputs", String::from("outputs-0-10.csv.gz")),
+            ("unknown", String::from("unknown.csv")),
+        ];
+        let gzip = format(';', true, Compression::Gzip);
+        let postgres = import_sql(Dialect::Postgres, &tables, &files, &gzip);
+        assert!(postgres.contains("\\copy \"outputs\" (\"txid\", \"indexOut\", \"value\", \"script\", \"spent\", \"address\") FROM PROGRAM 'gzip -dc outputs-0-10.csv.gz' WITH (FORMAT csv, DELIMITER ';', HEADER true, FORCE_NOT_NULL (\"script\"))\n"));
+        assert!(postgres.contains("CREATE INDEX \"outputs_txid_indexOut\" ON \"outputs\" (\"txid\", \"indexOut\");\nCREATE UNIQUE INDEX \"outputs_address\" ON \"outputs\" (\"address\");\n"));
+        assert!(!postgres.contains("unknown"));
+
+        let sqlite = import_sql(Dialect::Sqlite, &tables, &files, &gzip);
+        assert!(sqlite.contains(".import --skip 1 '|gzip -dc outputs-0-10.csv.gz' outputs\n"));
+        assert!(sqlite.contains("UPDATE \"outputs\" SET \"spent\" = (\"spent\" = 'true'), \"address\" = NULLIF(\"address\", '');"));
+
+        let files = vec![("outputs", String::from("outputs-0-10.csv"))];
+        let plain = format('\t', false, Compression::None);
+        let mysql = import_sql(Dialect::Mysql, &tables, &files, &plain);
+        assert!(mysql.contains("LOAD DATA LOCAL INFILE 'outputs-0-10.csv'\nINTO TABLE `outputs`\nFIELDS TERMINATED BY '\\t' OPTIONALLY ENCLOSED BY '\"' ESCAPED BY ''\nLINES TERMINATED BY '\\n'\n(@txid, `indexOut`, `value`, @script, @spent, @address)\nSET `txid` = UNHEX(@txid),\n    `script` = UNHEX(@script),\n    `spent` = (NULLIF(@spent, '') = 'true'),\n    `address` = NULLIF(@address, '');\n"));
+        let clickhouse = import_sql(Dialect::Clickhouse, &tables, &files, &plain);
+        assert!(clickhouse.contains(
+            "FROM INFILE 'outputs-0-10.csv' SETTINGS format_csv_delimiter = '\\t' FORMAT CSV;"
+        ));
+        assert!(!clickhouse.contains("CREATE INDEX"));
+    }
+
+    #[test]
+    fn test_dialects() {
+        assert_eq!(Dialect::Sqlite, Dialect::from_name("sqlite").unwrap());
+        assert!(Dialect::from_name("oracle").is_err());
+        assert!(Dialect::Mysql.check_compression(Compression::Gzip).is_err());
+        assert!(Dialect::Mysql.check_compression(Compression::None).is_ok());
+        assert!(Dialect::Postgres
+            .check_compression(Compression::Zstd)
+            .is_ok());
+        assert_eq!("''''", Dialect::Postgres.delimiter('\''));
+        assert_eq!("\"|\"", Dialect::Sqlite.delimiter('|'));
+    }
+}
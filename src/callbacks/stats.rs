@@ -1,18 +1,86 @@
-use std::collections::HashMap;
-use std::io::{self, Write};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
 
-use clap::{App, ArgMatches, SubCommand};
+use clap::{App, Arg, ArgMatches, SubCommand};
+use serde_json::{json, Map, Value};
 
 use crate::blockchain::parser::types::CoinType;
 use crate::blockchain::proto::block::{self, Block};
-use crate::blockchain::proto::script::ScriptPattern;
-use crate::blockchain::proto::ToRaw;
+use crate::blockchain::proto::script::{self, ScriptPattern};
+use crate::blockchain::proto::tx::EvaluatedTx;
+use crate::blockchain::proto::Hashed;
 use crate::callbacks::Callback;
+use crate::common::tdigest::TDigest;
 use crate::common::utils;
-use crate::errors::OpResult;
+use crate::errors::{OpError, OpResult};
+
+/// Percentiles reported for each distribution
+const PERCENTILES: [u8; 7] = [1, 10, 25, 50, 75, 90, 99];
+/// Most frequent templates of unrecognised scripts which are reported
+const TOP_UNKNOWN_TEMPLATES: usize = 20;
+
+/// Streaming distributions of block and transaction properties
+#[derive(Default)]
+struct Distributions {
+    n_blocks: u64,
+    n_tx: u64,
+    tx_sizes: TDigest,
+    tx_vsizes: TDigest,
+    tx_inputs: TDigest,
+    tx_outputs: TDigest,
+    block_sizes: TDigest,
+    block_weights: TDigest,
+    block_tx_counts: TDigest,
+    /// Output count by script type, e.g. `pubkeyhash`
+    script_types: BTreeMap<&'static str, u64>,
+}
+
+impl Distributions {
+    fn as_json(&self) -> Value {
+        let script_types: Map<String, Value> = self
+            .script_types
+            .iter()
+            .map(|(name, count)| (String::from(*name), json!(count)))
+            .collect();
+        json!({
+            "blocks": self.n_blocks,
+            "transactions": self.n_tx,
+            "block_size": percentiles_as_json(&self.block_sizes),
+            "block_weight": percentiles_as_json(&self.block_weights),
+            "block_tx_count": percentiles_as_json(&self.block_tx_counts),
+            "tx_size": percentiles_as_json(&self.tx_sizes),
+            "tx_vsize": percentiles_as_json(&self.tx_vsizes),
+            "tx_inputs": percentiles_as_json(&self.tx_inputs),
+            "tx_outputs": percentiles_as_json(&self.tx_outputs),
+            "script_types": script_types,
+        })
+    }
+}
+
+/// Returns min, max and the percentiles, e.g. `{"min": 1, "p50": 3, ...}`.
+/// Values are null if the distribution is empty.
+fn percentiles_as_json(digest: &TDigest) -> Value {
+    let mut map = Map::new();
+    map.insert(String::from("min"), json!(digest.min()));
+    for p in PERCENTILES {
+        map.insert(
+            format!("p{}", p),
+            json!(digest.quantile(f64::from(p) / 100.0)),
+        );
+    }
+    map.insert(String::from("max"), json!(digest.max()));
+    Value::Object(map)
+}
 
 #[derive(Default)]
 pub struct SimpleStats {
+    /// Writes json instead of the text report
+    json: bool,
+    /// Writes the report to this file instead of the log (text) or stdout (json)
+    output: Option<PathBuf>,
+
     //X coin_type: CoinType,
     n_valid_blocks: u64,
     block_sizes: Vec<u32>,
@@ -32,10 +100,18 @@ pub struct SimpleStats {
     /// First occurence of transaction type
     /// (block_height, txid)
     tx_first_occs: HashMap<ScriptPattern, (u64, [u8; 32], u32)>,
+    /// Output count of the unrecognised scripts by template, see `script::to_template()`
+    unknown_templates: HashMap<String, u64>,
 
     /// Time stats
     t_between_blocks: Vec<u32>,
     last_timestamp: u32,
+
+    /// Distributions over all blocks and per calendar year
+    distributions: Distributions,
+    years: BTreeMap<i32, Distributions>,
+    /// Year of the block whose transactions are passed to on_transaction()
+    year: i32,
 }
 
 impl SimpleStats {
@@ -164,6 +240,116 @@ impl SimpleStats {
         }
         Ok(())
     }
+
+    /// Returns the most frequent templates of unrecognised scripts, ties ordered by template
+    fn top_unknown_templates(&self) -> Vec<(&str, u64)> {
+        let mut templates: Vec<(&str, u64)> = self
+            .unknown_templates
+            .iter()
+            .map(|(template, count)| (template.as_str(), *count))
+            .collect();
+        templates.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        templates.truncate(TOP_UNKNOWN_TEMPLATES);
+        templates
+    }
+
+    fn print_unknown_templates(&self, buffer: &mut Vec<u8>) -> io::Result<()> {
+        writeln!(buffer, "Unknown Script Templates:")?;
+        for (template, count) in self.top_unknown_templates() {
+            writeln!(buffer, "   -> {}: {}", count, template)?;
+        }
+        Ok(())
+    }
+
+    fn as_json(&self) -> Value {
+        let unknown_templates: Vec<Value> = self
+            .top_unknown_templates()
+            .into_iter()
+            .map(|(template, count)| json!({"template": template, "count": count}))
+            .collect();
+        let years: Map<String, Value> = self
+            .years
+            .iter()
+            .map(|(year, distributions)| (year.to_string(), distributions.as_json()))
+            .collect();
+        json!({
+            "blocks": self.n_valid_blocks,
+            "transactions": self.n_tx,
+            "tx_inputs": self.n_tx_inputs,
+            "tx_outputs": self.n_tx_outputs,
+            "total_fee": self.n_tx_total_fee,
+            "total_volume": self.n_tx_total_volume,
+            "distributions": self.distributions.as_json(),
+            "unknown_script_templates": unknown_templates,
+            "years": years,
+        })
+    }
+
+    /// Adds a block to the overall and yearly distributions, its transactions are added
+    /// by `add_tx_to_distributions()`
+    fn add_block_to_distributions(&mut self, block: &Block) {
+        self.year = utils::timestamp_to_year(block.header.value.timestamp);
+        let yearly = self.years.entry(self.year).or_default();
+        for distributions in [&mut self.distributions, yearly] {
+            distributions.n_blocks += 1;
+            distributions.n_tx += block.tx_count.value;
+            distributions.block_sizes.add(f64::from(block.size));
+            distributions.block_weights.add(block.weight as f64);
+            distributions
+                .block_tx_counts
+                .add(block.tx_count.value as f64);
+        }
+    }
+
+    /// Adds a transaction of the current block to the overall and yearly distributions
+    fn add_tx_to_distributions(&mut self, tx: &EvaluatedTx) {
+        let yearly = self.years.entry(self.year).or_default();
+        for distributions in [&mut self.distributions, yearly] {
+            distributions.tx_sizes.add(f64::from(tx.size));
+            distributions.tx_vsizes.add(tx.vsize() as f64);
+            distributions.tx_inputs.add(tx.in_count.value as f64);
+            distributions.tx_outputs.add(tx.out_count.value as f64);
+            for o in &tx.outputs {
+                *distributions
+                    .script_types
+                    .entry(o.script.pattern.type_name())
+                    .or_insert(0) += 1;
+            }
+        }
+    }
+
+    fn write_report(&self, report: &[u8]) -> OpResult<()> {
+        match &self.output {
+            Some(path) => {
+                let mut writer = BufWriter::new(File::create(path)?);
+                writer.write_all(report)?;
+                writer.flush()?;
+                info!(target: "callback", "Wrote stats to {}.", path.display());
+            }
+            None if self.json => {
+                let stdout = io::stdout();
+                let mut handle = stdout.lock();
+                handle.write_all(report)?;
+                handle.flush()?;
+            }
+            None => {
+                info!(target: "simplestats", "\n\n{}", String::from_utf8_lossy(report));
+            }
+        }
+        Ok(())
+    }
+
+    fn text_report(&self) -> io::Result<Vec<u8>> {
+        let mut buffer = Vec::with_capacity(4096);
+        self.print_simple_stats(&mut buffer)?;
+        writeln!(&mut buffer)?;
+        self.print_unusual_transactions(&mut buffer)?;
+        self.print_averages(&mut buffer)?;
+        writeln!(&mut buffer)?;
+        self.print_transaction_types(&mut buffer)?;
+        self.print_unknown_templates(&mut buffer)?;
+        Ok(buffer)
+    }
 }
 
 impl Callback for SimpleStats {
@@ -175,13 +361,32 @@ impl Callback for SimpleStats {
             .about("Shows various Blockchain stats")
             .version("0.1")
             .author("gcarq <egger.m@protonmail.com>")
+            .arg(
+                Arg::with_name("format")
+                    .long("format")
+                    .value_name("FORMAT")
+                    .help("Output format, json adds percentiles and per-year breakdowns (default: text)")
+                    .possible_values(&["text", "json"])
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("output")
+                    .long("output")
+                    .value_name("FILE")
+                    .help("Write the stats to this file instead of the log (text) or stdout (json)")
+                    .takes_value(true),
+            )
     }
 
-    fn new(_: &ArgMatches) -> OpResult<Self>
+    fn new(matches: &ArgMatches) -> OpResult<Self>
     where
         Self: Sized,
     {
-        Ok(SimpleStats::default())
+        Ok(SimpleStats {
+            json: matches.value_of("format") == Some("json"),
+            output: matches.value_of("output").map(PathBuf::from),
+            ..Default::default()
+        })
     }
 
     fn on_start(&mut self, _: &CoinType, _: u64) -> OpResult<()> {
@@ -189,42 +394,15 @@ impl Callback for SimpleStats {
         Ok(())
     }
 
-    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<()> {
+    fn wants_witnesses(&self) -> bool {
+        false
+    }
+
+    fn on_block(&mut self, block: &Block, _: u64) -> OpResult<()> {
         self.n_valid_blocks += 1;
         self.n_tx += block.tx_count.value;
         self.block_sizes.push(block.size);
-
-        for tx in &block.txs {
-            // Collect fee rewards
-            if tx.value.is_coinbase() {
-                self.n_tx_total_fee += tx.value.outputs[0]
-                    .out
-                    .value
-                    .checked_sub(block::get_base_reward(block_height as u64))
-                    .unwrap_or_default();
-            }
-
-            self.n_tx_inputs += tx.value.in_count.value;
-            self.n_tx_outputs += tx.value.out_count.value;
-
-            let mut tx_value = 0;
-            for (i, o) in tx.value.outputs.iter().enumerate() {
-                self.process_tx_pattern(o.script.pattern.clone(), block_height, tx.hash, i as u32);
-                tx_value += o.out.value;
-            }
-            // Calculate and save biggest value transaction
-            if tx_value > self.tx_biggest_value.0 {
-                self.tx_biggest_value = (tx_value, block_height, tx.hash);
-            }
-
-            self.n_tx_total_volume += tx_value;
-
-            // Calculate and save biggest size transaction
-            let tx_size = tx.value.to_bytes().len();
-            if tx_size > self.tx_biggest_size.0 {
-                self.tx_biggest_size = (tx_size, block_height, tx.hash);
-            }
-        }
+        self.add_block_to_distributions(block);
 
         // Save time between blocks
         if self.last_timestamp > 0 {
@@ -232,23 +410,214 @@ impl Callback for SimpleStats {
                 .header
                 .value
                 .timestamp
-                .checked_sub(self.last_timestamp)
-                .unwrap_or_default();
+                .saturating_sub(self.last_timestamp);
             self.t_between_blocks.push(diff);
         }
         self.last_timestamp = block.header.value.timestamp;
         Ok(())
     }
 
-    fn on_complete(&mut self, _: u64) -> OpResult<()> {
-        let mut buffer = Vec::with_capacity(4096);
-        self.print_simple_stats(&mut buffer)?;
-        writeln!(&mut buffer)?;
-        self.print_unusual_transactions(&mut buffer)?;
-        self.print_averages(&mut buffer)?;
-        writeln!(&mut buffer)?;
-        self.print_transaction_types(&mut buffer)?;
-        info!(target: "simplestats", "\n\n{}", String::from_utf8_lossy(&buffer));
+    fn on_transaction(
+        &mut self,
+        tx: &Hashed<EvaluatedTx>,
+        block_height: u64,
+        _: usize,
+    ) -> OpResult<()> {
+        self.add_tx_to_distributions(&tx.value);
+        // Collect fee rewards
+        if tx.value.is_coinbase() {
+            self.n_tx_total_fee += tx.value.outputs[0]
+                .out
+                .value
+                .saturating_sub(block::get_base_reward(block_height));
+        }
+
+        self.n_tx_inputs += tx.value.in_count.value;
+        self.n_tx_outputs += tx.value.out_count.value;
+
+        let mut tx_value = 0;
+        for (i, o) in tx.value.outputs.iter().enumerate() {
+            if o.script.pattern == ScriptPattern::NotRecognised {
+                *self
+                    .unknown_templates
+                    .entry(script::to_template(&o.out.script_pubkey))
+                    .or_insert(0) += 1;
+            }
+            self.process_tx_pattern(o.script.pattern.clone(), block_height, tx.hash, i as u32);
+            tx_value += o.out.value;
+        }
+        // Calculate and save biggest value transaction
+        if tx_value > self.tx_biggest_value.0 {
+            self.tx_biggest_value = (tx_value, block_height, tx.hash);
+        }
+
+        self.n_tx_total_volume += tx_value;
+
+        // Calculate and save biggest size transaction
+        let tx_size = tx.value.size as usize;
+        if tx_size > self.tx_biggest_size.0 {
+            self.tx_biggest_size = (tx_size, block_height, tx.hash);
+        }
         Ok(())
     }
+
+    fn on_complete(&mut self, _: u64) -> OpResult<()> {
+        let report = if self.json {
+            let mut buffer = serde_json::to_vec_pretty(&self.as_json())
+                .map_err(|e| OpError::from(format!("Unable to serialize JSON: {}", e)))?;
+            buffer.push(b'\n');
+            buffer
+        } else {
+            self.text_report()?
+        };
+        self.write_report(&report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use super::*;
+    use crate::blockchain::parser::types::Bitcoin;
+    use crate::callbacks;
+    use crate::callbacks::fixtures::{new_chain, new_tx, new_tx_to, SCRIPT_A};
+
+    // 2009-01-03 and 2010-01-01
+    const TIMESTAMP_2009: u32 = 1231006505;
+    const TIMESTAMP_2010: u32 = 1262304000;
+
+    fn coinbase(value: u64) -> Hashed<EvaluatedTx> {
+        new_tx_to(&[([0u8; 32], 0xFFFFFFFF)], &[(value, SCRIPT_A)])
+    }
+
+    fn run_stats(args: &[&str]) -> SimpleStats {
+        let spend = new_tx_to(&[([1u8; 32], 0), ([1u8; 32], 1)], &[(10, SCRIPT_A)]);
+        let mut chain = new_chain(vec![
+            vec![coinbase(5000000000)],
+            vec![coinbase(5000000000), spend],
+            vec![coinbase(5000000000), new_tx(&[([2u8; 32], 0)], &[1, 2, 3])],
+        ]);
+        for (block, (size, timestamp)) in chain.iter_mut().zip([
+            (200, TIMESTAMP_2009),
+            (400, TIMESTAMP_2009 + 600),
+            (300, TIMESTAMP_2010),
+        ]) {
+            block.size = size;
+            block.measure();
+            block.header.value.timestamp = timestamp;
+        }
+
+        let mut all_args = vec!["simplestats"];
+        all_args.extend_from_slice(args);
+        let matches = SimpleStats::build_subcommand().get_matches_from(all_args);
+        let mut stats = SimpleStats::new(&matches).unwrap();
+        stats.on_start(&CoinType::from(Bitcoin), 0).unwrap();
+        for (height, block) in chain.iter().enumerate() {
+            callbacks::deliver_block(&mut stats, block, height as u64).unwrap();
+        }
+        stats
+    }
+
+    #[test]
+    fn test_text_report() {
+        let mut stats = run_stats(&[]);
+        assert!(!stats.json);
+        let report = String::from_utf8(stats.text_report().unwrap()).unwrap();
+        assert!(report
+            .starts_with("SimpleStats:\n   -> valid blocks:\t\t3\n   -> total transactions:\t5\n"));
+        assert!(report.contains("   -> avg txs per block:\t1.67\n"));
+        assert!(!report.contains("p50"));
+
+        let path = env::temp_dir().join(format!("blockparser-simplestats-{}.txt", process::id()));
+        stats.output = Some(path.clone());
+        stats.on_complete(3).unwrap();
+        assert_eq!(report, fs::read_to_string(&path).unwrap());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_json_report() {
+        let path = env::temp_dir().join(format!("blockparser-simplestats-{}.json", process::id()));
+        let mut stats = run_stats(&["--format", "json", "--output", path.to_str().unwrap()]);
+        stats.on_complete(3).unwrap();
+        let report: Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(json!(3), report["blocks"]);
+        assert_eq!(json!(5), report["transactions"]);
+        assert_eq!(json!(7), report["tx_outputs"]);
+
+        let overall = &report["distributions"];
+        assert_eq!(
+            json!({"min": 1.0, "p1": 1.0, "p10": 1.0, "p25": 1.0, "p50": 1.0, "p75": 1.0, "p90": 2.0,
+                   "p99": 2.0, "max": 2.0}),
+            overall["tx_inputs"]
+        );
+        assert_eq!(json!(1.0), overall["tx_outputs"]["p50"]);
+        assert_eq!(json!(3.0), overall["tx_outputs"]["max"]);
+        assert_eq!(json!(300.0), overall["block_size"]["p50"]);
+        assert_eq!(json!(1200.0), overall["block_weight"]["p50"]);
+        // Without witnesses the vsize equals the size
+        assert_eq!(json!(85.0), overall["tx_size"]["min"]);
+        assert_eq!(json!(153.0), overall["tx_vsize"]["max"]);
+        assert_eq!(json!(2.0), overall["block_tx_count"]["p75"]);
+        assert_eq!(json!({"pubkeyhash": 7}), overall["script_types"]);
+
+        let years = report["years"].as_object().unwrap();
+        assert_eq!(vec!["2009", "2010"], years.keys().collect::<Vec<_>>());
+        assert_eq!(json!(2), years["2009"]["blocks"]);
+        assert_eq!(json!(3), years["2009"]["transactions"]);
+        assert_eq!(json!(200.0), years["2009"]["block_size"]["min"]);
+        assert_eq!(json!(400.0), years["2009"]["block_size"]["max"]);
+        assert_eq!(json!(1), years["2010"]["blocks"]);
+        assert_eq!(json!({"pubkeyhash": 4}), years["2010"]["script_types"]);
+    }
+
+    #[test]
+    fn test_unknown_templates() {
+        // Pushes of the same length share a template, pushed numbers don't
+        let tx = new_tx_to(
+            &[([1u8; 32], 0)],
+            &[
+                (1, "01aa75"),
+                (1, "01bb75"),
+                (1, "5175"),
+                (1, SCRIPT_A),
+                (1, "5275"),
+            ],
+        );
+        let chain = new_chain(vec![vec![coinbase(5000000000), tx]]);
+        let mut stats = SimpleStats::default();
+        callbacks::deliver_block(&mut stats, &chain[0], 0).unwrap();
+        assert_eq!(
+            vec![("<push1> OP_DROP", 2), ("1 OP_DROP", 1), ("2 OP_DROP", 1)],
+            stats.top_unknown_templates()
+        );
+        let report = String::from_utf8(stats.text_report().unwrap()).unwrap();
+        assert!(report.ends_with(
+            "Unknown Script Templates:\n   -> 2: <push1> OP_DROP\n   -> 1: 1 OP_DROP\n   -> 1: 2 OP_DROP\n"
+        ));
+        assert_eq!(
+            json!({"template": "<push1> OP_DROP", "count": 2}),
+            stats.as_json()["unknown_script_templates"][0]
+        );
+
+        for i in 0..30 {
+            let script = format!("{:02x}{}75", i + 1, "00".repeat(i + 1));
+            stats.unknown_templates.insert(script, 1);
+        }
+        assert_eq!(TOP_UNKNOWN_TEMPLATES, stats.top_unknown_templates().len());
+    }
+
+    #[test]
+    fn test_empty_json() {
+        let stats = SimpleStats::default();
+        let report = stats.as_json();
+        assert_eq!(json!(0), report["blocks"]);
+        assert_eq!(Value::Null, report["distributions"]["tx_size"]["p50"]);
+        assert_eq!(json!({}), report["years"]);
+    }
 }
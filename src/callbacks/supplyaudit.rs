@@ -0,0 +1,318 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use crate::blockchain::parser::types::CoinType;
+use crate::blockchain::proto::block::{Block, Subsidy};
+use crate::blockchain::proto::tx::TxOutpoint;
+use crate::callbacks::Callback;
+use crate::common::outputstore::{outpoint_key, read_varint, write_varint, OutputStore};
+use crate::common::utils;
+use crate::errors::{OpError, OpResult};
+
+/// Compares the value claimed by each coinbase with the subsidy of the coin's schedule plus the
+/// fees of the block. Blocks claiming less destroyed the difference, blocks claiming more point
+/// to a parsing bug or an unknown schedule. Both are written to a csv file.
+///
+/// Blocks with inputs which couldn't be resolved have unknown fees and aren't audited.
+pub struct SupplyAudit {
+    output_dir: PathBuf,
+    subsidy: Subsidy,
+    // Values of the outputs which are not spent yet
+    outputs: Option<OutputStore>,
+    writer: Option<BufWriter<File>>,
+
+    start_height: u64,
+    // Sum of the claimed values minus the fees, the new coins
+    supply: u64,
+    destroyed: u64,
+    excess: u64,
+    n_discrepancies: u64,
+    n_unaudited: u64,
+}
+
+impl SupplyAudit {
+    /// Resolves the inputs of the block and returns the fees, None if an input is unknown.
+    /// The `known` fees are the ones of transactions whose inputs are all resolved.
+    fn block_fees(&mut self, block: &Block) -> OpResult<(Option<u64>, u64)> {
+        let outputs = self.outputs.as_mut().unwrap();
+        let (mut all_known, mut known) = (true, 0u64);
+        for tx in &block.txs {
+            let is_coinbase = tx.value.is_coinbase();
+            let mut input_value = Some(0u64);
+            for input in tx.value.inputs.iter().filter(|_| !is_coinbase) {
+                let value = match outputs.take(&outpoint_key(&input.outpoint))? {
+                    Some(value) => Some(read_varint(&mut value.as_slice())?),
+                    None => None,
+                };
+                input_value = input_value.zip(value).map(|(sum, value)| sum + value);
+            }
+            let mut output_value = 0;
+            for (i, output) in tx.value.outputs.iter().enumerate() {
+                let mut value = Vec::with_capacity(9);
+                write_varint(&mut value, output.out.value);
+                outputs.insert(outpoint_key(&TxOutpoint::new(tx.hash, i as u32)), value)?;
+                output_value += output.out.value;
+            }
+            if is_coinbase {
+                continue;
+            }
+            match input_value {
+                Some(input_value) => known += input_value.saturating_sub(output_value),
+                None => all_known = false,
+            }
+        }
+        Ok((Some(known).filter(|_| all_known), known))
+    }
+
+    fn write_discrepancy(
+        &mut self,
+        block: &Block,
+        block_height: u64,
+        values: (u64, u64, u64),
+        difference: u64,
+        kind: &str,
+    ) -> OpResult<()> {
+        let (claimed, subsidy, fees) = values;
+        let row = format!(
+            "{};{};{};{};{};{};{}\n",
+            block_height,
+            utils::arr_to_hex_swapped(&block.header.hash),
+            claimed,
+            subsidy,
+            fees,
+            difference,
+            kind
+        );
+        self.writer.as_mut().unwrap().write_all(row.as_bytes())?;
+        self.n_discrepancies += 1;
+        Ok(())
+    }
+}
+
+impl Callback for SupplyAudit {
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b>
+    where
+        Self: Sized,
+    {
+        SubCommand::with_name("supplyaudit")
+            .about("Checks the coinbase of each block against the subsidy schedule and the fees")
+            .version("0.1")
+            .arg(
+                Arg::with_name("output-dir")
+                    .long("output-dir")
+                    .value_name("PATH")
+                    .help("Folder to store supplyaudit.csv and supply.csv, created if necessary")
+                    .takes_value(true)
+                    .required(true),
+            )
+            .args(&OutputStore::args())
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let outputs = OutputStore::from_matches(matches)?;
+        let cb = SupplyAudit {
+            output_dir: PathBuf::from(matches.value_of("output-dir").unwrap()),
+            subsidy: Subsidy::Unknown,
+            outputs: Some(outputs),
+            writer: None,
+            start_height: 0,
+            supply: 0,
+            destroyed: 0,
+            excess: 0,
+            n_discrepancies: 0,
+            n_unaudited: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, coin_type: &CoinType, block_height: u64) -> OpResult<()> {
+        if coin_type.subsidy == Subsidy::Unknown {
+            return Err(OpError::from(format!(
+                "The subsidy schedule of {} is unknown.",
+                coin_type.name
+            )));
+        }
+        self.subsidy = coin_type.subsidy;
+        self.start_height = block_height;
+        if block_height != 0 {
+            warn!(target: "callback", "Starting at height {}, the supply only counts later blocks and blocks spending earlier outputs aren't audited.", block_height);
+        }
+        fs::create_dir_all(&self.output_dir)?;
+        let mut writer = BufWriter::with_capacity(
+            4000000,
+            File::create(self.output_dir.join("supplyaudit.csv.tmp"))?,
+        );
+        writer.write_all(b"height;hash;claimed;subsidy;fees;difference;kind\n")?;
+        self.writer = Some(writer);
+        info!(target: "callback", "Using `supplyaudit` with output dir: {} ...", &self.output_dir.display());
+        Ok(())
+    }
+
+    fn wants_witnesses(&self) -> bool {
+        false
+    }
+
+    fn wants_script_evaluation(&self) -> bool {
+        false
+    }
+
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<()> {
+        let claimed: u64 = match block.txs.first() {
+            Some(tx) if tx.value.is_coinbase() => {
+                tx.value.outputs.iter().map(|output| output.out.value).sum()
+            }
+            _ => 0,
+        };
+        let (fees, known_fees) = self.block_fees(block)?;
+        self.supply += claimed.saturating_sub(known_fees);
+        let fees = match fees {
+            Some(fees) => fees,
+            None => {
+                self.n_unaudited += 1;
+                return Ok(());
+            }
+        };
+        let (subsidy, is_exact) = self.subsidy.max_subsidy(block_height).unwrap();
+        let allowed = subsidy + fees;
+        let values = (claimed, subsidy, fees);
+        if claimed > allowed {
+            self.excess += claimed - allowed;
+            warn!(target: "callback", "Block {} claims {} more than the subsidy and fees.", block_height, claimed - allowed);
+            self.write_discrepancy(block, block_height, values, claimed - allowed, "excess")?;
+        } else if claimed < allowed && is_exact {
+            self.destroyed += allowed - claimed;
+            self.write_discrepancy(block, block_height, values, allowed - claimed, "destroyed")?;
+        }
+        Ok(())
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        let mut writer = self.writer.take().unwrap();
+        writer.flush()?;
+        drop(writer);
+        let path = self.output_dir.join("supplyaudit.csv");
+        fs::rename(path.with_extension("csv.tmp"), &path)?;
+        let supply_path = self.output_dir.join("supply.csv");
+        fs::write(
+            supply_path.with_extension("csv.tmp"),
+            format!(
+                "height;supply;destroyed;excess;unaudited_blocks\n{};{};{};{};{}\n",
+                block_height, self.supply, self.destroyed, self.excess, self.n_unaudited
+            ),
+        )?;
+        fs::rename(supply_path.with_extension("csv.tmp"), &supply_path)?;
+        self.outputs.take().unwrap().destroy()?;
+
+        info!(target: "callback", "Done.\nAudited blocks {} to {} into {}:\n\
+                                   \t-> supply:           {:18}\n\
+                                   \t-> destroyed:        {:18}\n\
+                                   \t-> excess:           {:18}\n\
+                                   \t-> discrepancies:    {:18}\n\
+                                   \t-> unaudited blocks: {:18}",
+              self.start_height, block_height, path.display(), self.supply, self.destroyed,
+              self.excess, self.n_discrepancies, self.n_unaudited);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+    use crate::blockchain::parser::types::{Bitcoin, Dogecoin, Zcash};
+    use crate::callbacks::fixtures::{new_chain, new_tx};
+
+    const COINBASE: ([u8; 32], u32) = ([0u8; 32], 0xFFFFFFFF);
+
+    fn run(coin_type: CoinType, subsidy: u64, name: &str) -> (SupplyAudit, Vec<String>, String) {
+        let dir = env::temp_dir().join(format!(
+            "blockparser-supplyaudit-{}-{}",
+            name,
+            process::id()
+        ));
+        let matches = SupplyAudit::build_subcommand().get_matches_from(vec![
+            "supplyaudit",
+            "--output-dir",
+            dir.to_str().unwrap(),
+        ]);
+
+        let genesis = new_tx(&[COINBASE], &[5000000000]);
+        // Claims 1 satoshi less than the subsidy
+        let coinbase_1 = new_tx(&[COINBASE], &[4999999998, 1]);
+        // Spends the coinbase of height 1 with a fee of 1000, the coinbase claims the fee
+        let spend = new_tx(&[(coinbase_1.hash, 0)], &[4999998998]);
+        let coinbase_2 = new_tx(&[COINBASE], &[5000001000]);
+        // Claims the fee of 100 of an unknown input and more, unaudited
+        let coinbase_3 = new_tx(&[COINBASE], &[9000000000]);
+        let unknown = new_tx(&[([1u8; 32], 0)], &[1]);
+        // Claims 1000 more than the subsidy and fees
+        let coinbase_4 = new_tx(&[COINBASE], &[subsidy, 1000]);
+        let chain = new_chain(vec![
+            vec![genesis],
+            vec![coinbase_1],
+            vec![coinbase_2, spend],
+            vec![coinbase_3, unknown],
+            vec![coinbase_4],
+        ]);
+
+        let mut audit = SupplyAudit::new(&matches).unwrap();
+        audit.on_start(&coin_type, 0).unwrap();
+        for (height, block) in chain.iter().enumerate() {
+            audit.on_block(block, height as u64).unwrap();
+        }
+        audit.on_complete(4).unwrap();
+        let rows = fs::read_to_string(dir.join("supplyaudit.csv")).unwrap();
+        let supply = fs::read_to_string(dir.join("supply.csv")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        (audit, rows.lines().map(String::from).collect(), supply)
+    }
+
+    #[test]
+    fn test_bitcoin() {
+        let (audit, rows, supply) = run(CoinType::from(Bitcoin), 5000000000, "bitcoin");
+        assert_eq!("height;hash;claimed;subsidy;fees;difference;kind", rows[0]);
+        assert_eq!(3, rows.len());
+        assert!(rows[1].starts_with("1;"));
+        assert!(rows[1].ends_with(";4999999999;5000000000;0;1;destroyed"));
+        assert!(rows[2].ends_with(";5000001000;5000000000;0;1000;excess"));
+        assert_eq!(1, audit.n_unaudited);
+        // The unknown input's fee isn't known, the whole claim of height 3 counts
+        let total = 5000000000u64 + 4999999999 + 5000000000 + 9000000000 + 5000001000;
+        assert_eq!(
+            format!(
+                "height;supply;destroyed;excess;unaudited_blocks\n4;{};1;1000;1\n",
+                total
+            ),
+            supply
+        );
+    }
+
+    #[test]
+    fn test_dogecoin() {
+        // Random rewards, only claims above 1000000 DOGE are flagged
+        let (audit, rows, _) = run(CoinType::from(Dogecoin), 100000000000000, "dogecoin");
+        assert_eq!(2, rows.len());
+        assert!(rows[1].ends_with(";100000000001000;100000000000000;0;1000;excess"));
+        assert_eq!(0, audit.destroyed);
+    }
+
+    #[test]
+    fn test_unknown_schedule() {
+        let matches = SupplyAudit::build_subcommand().get_matches_from(vec![
+            "supplyaudit",
+            "--output-dir",
+            "/nonexistent",
+        ]);
+        let mut audit = SupplyAudit::new(&matches).unwrap();
+        let err = audit.on_start(&CoinType::from(Zcash), 0).unwrap_err();
+        assert_eq!("The subsidy schedule of Zcash is unknown.", err.to_string());
+    }
+}
@@ -0,0 +1,17 @@
+-- Run in the dump folder: clickhouse-client --queries-file import.sql
+
+INSERT INTO `blocks` (`hash`, `height`, `version`, `blocksize`, `hashPrev`, `hashMerkleRoot`, `nTime`, `nBits`, `nNonce`, `mwebSize`) FROM INFILE 'blocks-0-2.csv.gz' COMPRESSION 'gzip' SETTINGS format_csv_delimiter = ';' FORMAT CSV;
+
+INSERT INTO `transactions` (`txid`, `hashBlock`, `version`, `lockTime`, `size`, `strippedSize`, `vsize`, `weight`, `lockTimeType`, `usesLockTime`) FROM INFILE 'transactions-0-2.csv.gz' COMPRESSION 'gzip' SETTINGS format_csv_delimiter = ';' FORMAT CSV;
+
+INSERT INTO `tx_in` (`txid`, `hashPrevOut`, `indexPrevOut`, `scriptSig`, `sequence`, `revealedScriptType`, `revealedScriptHex`, `relativeLockType`, `relativeLockValue`) FROM INFILE 'tx_in-0-2.csv.gz' COMPRESSION 'gzip' SETTINGS format_csv_delimiter = ';' FORMAT CSV;
+
+INSERT INTO `tx_out` (`txid`, `indexOut`, `value`, `scriptPubKey`, `address`) FROM INFILE 'tx_out-0-2.csv.gz' COMPRESSION 'gzip' SETTINGS format_csv_delimiter = ';' FORMAT CSV;
+
+INSERT INTO `blocks` (`hash`, `height`, `version`, `blocksize`, `hashPrev`, `hashMerkleRoot`, `nTime`, `nBits`, `nNonce`, `mwebSize`) FROM INFILE 'blocks-2-4.csv.gz' COMPRESSION 'gzip' SETTINGS format_csv_delimiter = ';' FORMAT CSV;
+
+INSERT INTO `transactions` (`txid`, `hashBlock`, `version`, `lockTime`, `size`, `strippedSize`, `vsize`, `weight`, `lockTimeType`, `usesLockTime`) FROM INFILE 'transactions-2-4.csv.gz' COMPRESSION 'gzip' SETTINGS format_csv_delimiter = ';' FORMAT CSV;
+
+INSERT INTO `tx_in` (`txid`, `hashPrevOut`, `indexPrevOut`, `scriptSig`, `sequence`, `revealedScriptType`, `revealedScriptHex`, `relativeLockType`, `relativeLockValue`) FROM INFILE 'tx_in-2-4.csv.gz' COMPRESSION 'gzip' SETTINGS format_csv_delimiter = ';' FORMAT CSV;
+
+INSERT INTO `tx_out` (`txid`, `indexOut`, `value`, `scriptPubKey`, `address`) FROM INFILE 'tx_out-2-4.csv.gz' COMPRESSION 'gzip' SETTINGS format_csv_delimiter = ';' FORMAT CSV;
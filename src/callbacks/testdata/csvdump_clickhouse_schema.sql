@@ -0,0 +1,51 @@
+-- Tables of the csv dump, generated by rusty-blockparser --emit-sql clickhouse
+
+CREATE TABLE IF NOT EXISTS `blocks` (
+    `hash` FixedString(64),
+    `height` UInt64,
+    `version` UInt32,
+    `blocksize` UInt32,
+    `hashPrev` FixedString(64),
+    `hashMerkleRoot` FixedString(64),
+    `nTime` UInt32,
+    `nBits` UInt32,
+    `nNonce` UInt32,
+    `mwebSize` UInt32,
+    INDEX `blocks_hash` (`hash`) TYPE bloom_filter GRANULARITY 4
+) ENGINE = MergeTree ORDER BY (`height`);
+
+CREATE TABLE IF NOT EXISTS `transactions` (
+    `txid` FixedString(64),
+    `hashBlock` FixedString(64),
+    `version` UInt32,
+    `lockTime` UInt32,
+    `size` UInt32,
+    `strippedSize` UInt32,
+    `vsize` UInt32,
+    `weight` UInt32,
+    `lockTimeType` String,
+    `usesLockTime` Bool,
+    INDEX `transactions_hashBlock` (`hashBlock`) TYPE bloom_filter GRANULARITY 4
+) ENGINE = MergeTree ORDER BY (`txid`);
+
+CREATE TABLE IF NOT EXISTS `tx_in` (
+    `txid` FixedString(64),
+    `hashPrevOut` FixedString(64),
+    `indexPrevOut` UInt32,
+    `scriptSig` String,
+    `sequence` UInt32,
+    `revealedScriptType` Nullable(String),
+    `revealedScriptHex` Nullable(String),
+    `relativeLockType` Nullable(String),
+    `relativeLockValue` Nullable(UInt32),
+    INDEX `tx_in_txid` (`txid`) TYPE bloom_filter GRANULARITY 4
+) ENGINE = MergeTree ORDER BY (`hashPrevOut`, `indexPrevOut`);
+
+CREATE TABLE IF NOT EXISTS `tx_out` (
+    `txid` FixedString(64),
+    `indexOut` UInt32,
+    `value` UInt64,
+    `scriptPubKey` String,
+    `address` Nullable(String),
+    INDEX `tx_out_address` (`address`) TYPE bloom_filter GRANULARITY 4
+) ENGINE = MergeTree ORDER BY (`txid`, `indexOut`);
@@ -0,0 +1,98 @@
+-- Run in the dump folder with local_infile enabled: mysql --local-infile=1 <database> < import.sql
+
+LOAD DATA LOCAL INFILE 'blocks-0-2.csv'
+INTO TABLE `blocks`
+FIELDS TERMINATED BY ';' OPTIONALLY ENCLOSED BY '"' ESCAPED BY ''
+LINES TERMINATED BY '\n'
+IGNORE 1 LINES
+(@hash, `height`, `version`, `blocksize`, @hashPrev, @hashMerkleRoot, `nTime`, `nBits`, `nNonce`, `mwebSize`)
+SET `hash` = UNHEX(@hash),
+    `hashPrev` = UNHEX(@hashPrev),
+    `hashMerkleRoot` = UNHEX(@hashMerkleRoot);
+
+LOAD DATA LOCAL INFILE 'transactions-0-2.csv'
+INTO TABLE `transactions`
+FIELDS TERMINATED BY ';' OPTIONALLY ENCLOSED BY '"' ESCAPED BY ''
+LINES TERMINATED BY '\n'
+IGNORE 1 LINES
+(@txid, @hashBlock, `version`, `lockTime`, `size`, `strippedSize`, `vsize`, `weight`, `lockTimeType`, @usesLockTime)
+SET `txid` = UNHEX(@txid),
+    `hashBlock` = UNHEX(@hashBlock),
+    `usesLockTime` = (@usesLockTime = 'true');
+
+LOAD DATA LOCAL INFILE 'tx_in-0-2.csv'
+INTO TABLE `tx_in`
+FIELDS TERMINATED BY ';' OPTIONALLY ENCLOSED BY '"' ESCAPED BY ''
+LINES TERMINATED BY '\n'
+IGNORE 1 LINES
+(@txid, @hashPrevOut, `indexPrevOut`, @scriptSig, `sequence`, @revealedScriptType, @revealedScriptHex, @relativeLockType, @relativeLockValue)
+SET `txid` = UNHEX(@txid),
+    `hashPrevOut` = UNHEX(@hashPrevOut),
+    `scriptSig` = UNHEX(@scriptSig),
+    `revealedScriptType` = NULLIF(@revealedScriptType, ''),
+    `revealedScriptHex` = UNHEX(NULLIF(@revealedScriptHex, '')),
+    `relativeLockType` = NULLIF(@relativeLockType, ''),
+    `relativeLockValue` = NULLIF(@relativeLockValue, '');
+
+LOAD DATA LOCAL INFILE 'tx_out-0-2.csv'
+INTO TABLE `tx_out`
+FIELDS TERMINATED BY ';' OPTIONALLY ENCLOSED BY '"' ESCAPED BY ''
+LINES TERMINATED BY '\n'
+IGNORE 1 LINES
+(@txid, `indexOut`, `value`, @scriptPubKey, @address)
+SET `txid` = UNHEX(@txid),
+    `scriptPubKey` = UNHEX(@scriptPubKey),
+    `address` = NULLIF(@address, '');
+
+LOAD DATA LOCAL INFILE 'blocks-2-4.csv'
+INTO TABLE `blocks`
+FIELDS TERMINATED BY ';' OPTIONALLY ENCLOSED BY '"' ESCAPED BY ''
+LINES TERMINATED BY '\n'
+IGNORE 1 LINES
+(@hash, `height`, `version`, `blocksize`, @hashPrev, @hashMerkleRoot, `nTime`, `nBits`, `nNonce`, `mwebSize`)
+SET `hash` = UNHEX(@hash),
+    `hashPrev` = UNHEX(@hashPrev),
+    `hashMerkleRoot` = UNHEX(@hashMerkleRoot);
+
+LOAD DATA LOCAL INFILE 'transactions-2-4.csv'
+INTO TABLE `transactions`
+FIELDS TERMINATED BY ';' OPTIONALLY ENCLOSED BY '"' ESCAPED BY ''
+LINES TERMINATED BY '\n'
+IGNORE 1 LINES
+(@txid, @hashBlock, `version`, `lockTime`, `size`, `strippedSize`, `vsize`, `weight`, `lockTimeType`, @usesLockTime)
+SET `txid` = UNHEX(@txid),
+    `hashBlock` = UNHEX(@hashBlock),
+    `usesLockTime` = (@usesLockTime = 'true');
+
+LOAD DATA LOCAL INFILE 'tx_in-2-4.csv'
+INTO TABLE `tx_in`
+FIELDS TERMINATED BY ';' OPTIONALLY ENCLOSED BY '"' ESCAPED BY ''
+LINES TERMINATED BY '\n'
+IGNORE 1 LINES
+(@txid, @hashPrevOut, `indexPrevOut`, @scriptSig, `sequence`, @revealedScriptType, @revealedScriptHex, @relativeLockType, @relativeLockValue)
+SET `txid` = UNHEX(@txid),
+    `hashPrevOut` = UNHEX(@hashPrevOut),
+    `scriptSig` = UNHEX(@scriptSig),
+    `revealedScriptType` = NULLIF(@revealedScriptType, ''),
+    `revealedScriptHex` = UNHEX(NULLIF(@revealedScriptHex, '')),
+    `relativeLockType` = NULLIF(@relativeLockType, ''),
+    `relativeLockValue` = NULLIF(@relativeLockValue, '');
+
+LOAD DATA LOCAL INFILE 'tx_out-2-4.csv'
+INTO TABLE `tx_out`
+FIELDS TERMINATED BY ';' OPTIONALLY ENCLOSED BY '"' ESCAPED BY ''
+LINES TERMINATED BY '\n'
+IGNORE 1 LINES
+(@txid, `indexOut`, `value`, @scriptPubKey, @address)
+SET `txid` = UNHEX(@txid),
+    `scriptPubKey` = UNHEX(@scriptPubKey),
+    `address` = NULLIF(@address, '');
+
+CREATE INDEX `blocks_height` ON `blocks` (`height`);
+CREATE UNIQUE INDEX `blocks_hash` ON `blocks` (`hash`);
+CREATE INDEX `transactions_txid` ON `transactions` (`txid`);
+CREATE INDEX `transactions_hashBlock` ON `transactions` (`hashBlock`);
+CREATE INDEX `tx_in_hashPrevOut_indexPrevOut` ON `tx_in` (`hashPrevOut`, `indexPrevOut`);
+CREATE INDEX `tx_in_txid` ON `tx_in` (`txid`);
+CREATE INDEX `tx_out_txid_indexOut` ON `tx_out` (`txid`, `indexOut`);
+CREATE INDEX `tx_out_address` ON `tx_out` (`address`);
@@ -0,0 +1,47 @@
+-- Tables of the csv dump, generated by rusty-blockparser --emit-sql mysql
+
+CREATE TABLE IF NOT EXISTS `blocks` (
+    `hash` BINARY(32) NOT NULL,
+    `height` BIGINT UNSIGNED NOT NULL,
+    `version` INT UNSIGNED NOT NULL,
+    `blocksize` INT UNSIGNED NOT NULL,
+    `hashPrev` BINARY(32) NOT NULL,
+    `hashMerkleRoot` BINARY(32) NOT NULL,
+    `nTime` INT UNSIGNED NOT NULL,
+    `nBits` INT UNSIGNED NOT NULL,
+    `nNonce` INT UNSIGNED NOT NULL,
+    `mwebSize` INT UNSIGNED NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS `transactions` (
+    `txid` BINARY(32) NOT NULL,
+    `hashBlock` BINARY(32) NOT NULL,
+    `version` INT UNSIGNED NOT NULL,
+    `lockTime` INT UNSIGNED NOT NULL,
+    `size` INT UNSIGNED NOT NULL,
+    `strippedSize` INT UNSIGNED NOT NULL,
+    `vsize` INT UNSIGNED NOT NULL,
+    `weight` INT UNSIGNED NOT NULL,
+    `lockTimeType` VARCHAR(8) NOT NULL,
+    `usesLockTime` BOOLEAN NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS `tx_in` (
+    `txid` BINARY(32) NOT NULL,
+    `hashPrevOut` BINARY(32) NOT NULL,
+    `indexPrevOut` INT UNSIGNED NOT NULL,
+    `scriptSig` MEDIUMBLOB NOT NULL,
+    `sequence` INT UNSIGNED NOT NULL,
+    `revealedScriptType` VARCHAR(32),
+    `revealedScriptHex` MEDIUMBLOB,
+    `relativeLockType` VARCHAR(8),
+    `relativeLockValue` INT UNSIGNED
+);
+
+CREATE TABLE IF NOT EXISTS `tx_out` (
+    `txid` BINARY(32) NOT NULL,
+    `indexOut` INT UNSIGNED NOT NULL,
+    `value` BIGINT UNSIGNED NOT NULL,
+    `scriptPubKey` MEDIUMBLOB NOT NULL,
+    `address` VARCHAR(90)
+);
@@ -0,0 +1,26 @@
+-- Run in the dump folder: psql -f import.sql
+
+\copy "blocks" ("hash", "height", "version", "blocksize", "hashPrev", "hashMerkleRoot", "nTime", "nBits", "nNonce", "mwebSize") FROM PROGRAM 'gzip -dc blocks-0-2.csv.gz' WITH (FORMAT csv, DELIMITER ';', HEADER false)
+
+\copy "transactions" ("txid", "hashBlock", "version", "lockTime", "size", "strippedSize", "vsize", "weight", "lockTimeType", "usesLockTime") FROM PROGRAM 'gzip -dc transactions-0-2.csv.gz' WITH (FORMAT csv, DELIMITER ';', HEADER false, FORCE_NOT_NULL ("lockTimeType"))
+
+\copy "tx_in" ("txid", "hashPrevOut", "indexPrevOut", "scriptSig", "sequence", "revealedScriptType", "revealedScriptHex", "relativeLockType", "relativeLockValue") FROM PROGRAM 'gzip -dc tx_in-0-2.csv.gz' WITH (FORMAT csv, DELIMITER ';', HEADER false, FORCE_NOT_NULL ("scriptSig"))
+
+\copy "tx_out" ("txid", "indexOut", "value", "scriptPubKey", "address") FROM PROGRAM 'gzip -dc tx_out-0-2.csv.gz' WITH (FORMAT csv, DELIMITER ';', HEADER false, FORCE_NOT_NULL ("scriptPubKey"))
+
+\copy "blocks" ("hash", "height", "version", "blocksize", "hashPrev", "hashMerkleRoot", "nTime", "nBits", "nNonce", "mwebSize") FROM PROGRAM 'gzip -dc blocks-2-4.csv.gz' WITH (FORMAT csv, DELIMITER ';', HEADER false)
+
+\copy "transactions" ("txid", "hashBlock", "version", "lockTime", "size", "strippedSize", "vsize", "weight", "lockTimeType", "usesLockTime") FROM PROGRAM 'gzip -dc transactions-2-4.csv.gz' WITH (FORMAT csv, DELIMITER ';', HEADER false, FORCE_NOT_NULL ("lockTimeType"))
+
+\copy "tx_in" ("txid", "hashPrevOut", "indexPrevOut", "scriptSig", "sequence", "revealedScriptType", "revealedScriptHex", "relativeLockType", "relativeLockValue") FROM PROGRAM 'gzip -dc tx_in-2-4.csv.gz' WITH (FORMAT csv, DELIMITER ';', HEADER false, FORCE_NOT_NULL ("scriptSig"))
+
+\copy "tx_out" ("txid", "indexOut", "value", "scriptPubKey", "address") FROM PROGRAM 'gzip -dc tx_out-2-4.csv.gz' WITH (FORMAT csv, DELIMITER ';', HEADER false, FORCE_NOT_NULL ("scriptPubKey"))
+
+CREATE INDEX "blocks_height" ON "blocks" ("height");
+CREATE UNIQUE INDEX "blocks_hash" ON "blocks" ("hash");
+CREATE INDEX "transactions_txid" ON "transactions" ("txid");
+CREATE INDEX "transactions_hashBlock" ON "transactions" ("hashBlock");
+CREATE INDEX "tx_in_hashPrevOut_indexPrevOut" ON "tx_in" ("hashPrevOut", "indexPrevOut");
+CREATE INDEX "tx_in_txid" ON "tx_in" ("txid");
+CREATE INDEX "tx_out_txid_indexOut" ON "tx_out" ("txid", "indexOut");
+CREATE INDEX "tx_out_address" ON "tx_out" ("address");
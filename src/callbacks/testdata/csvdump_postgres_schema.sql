@@ -0,0 +1,47 @@
+-- Tables of the csv dump, generated by rusty-blockparser --emit-sql postgres
+
+CREATE TABLE IF NOT EXISTS "blocks" (
+    "hash" CHAR(64) NOT NULL,
+    "height" BIGINT NOT NULL,
+    "version" BIGINT NOT NULL,
+    "blocksize" BIGINT NOT NULL,
+    "hashPrev" CHAR(64) NOT NULL,
+    "hashMerkleRoot" CHAR(64) NOT NULL,
+    "nTime" BIGINT NOT NULL,
+    "nBits" BIGINT NOT NULL,
+    "nNonce" BIGINT NOT NULL,
+    "mwebSize" BIGINT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS "transactions" (
+    "txid" CHAR(64) NOT NULL,
+    "hashBlock" CHAR(64) NOT NULL,
+    "version" BIGINT NOT NULL,
+    "lockTime" BIGINT NOT NULL,
+    "size" BIGINT NOT NULL,
+    "strippedSize" BIGINT NOT NULL,
+    "vsize" BIGINT NOT NULL,
+    "weight" BIGINT NOT NULL,
+    "lockTimeType" VARCHAR(8) NOT NULL,
+    "usesLockTime" BOOLEAN NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS "tx_in" (
+    "txid" CHAR(64) NOT NULL,
+    "hashPrevOut" CHAR(64) NOT NULL,
+    "indexPrevOut" BIGINT NOT NULL,
+    "scriptSig" TEXT NOT NULL,
+    "sequence" BIGINT NOT NULL,
+    "revealedScriptType" VARCHAR(32),
+    "revealedScriptHex" TEXT,
+    "relativeLockType" VARCHAR(8),
+    "relativeLockValue" BIGINT
+);
+
+CREATE TABLE IF NOT EXISTS "tx_out" (
+    "txid" CHAR(64) NOT NULL,
+    "indexOut" BIGINT NOT NULL,
+    "value" BIGINT NOT NULL,
+    "scriptPubKey" TEXT NOT NULL,
+    "address" VARCHAR(90)
+);
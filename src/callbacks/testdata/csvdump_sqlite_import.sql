@@ -0,0 +1,27 @@
+-- Run in the dump folder: sqlite3 <database> < import.sql
+
+.mode csv
+.separator ";" "\n"
+.import '|gzip -dc blocks-0-2.csv.gz' blocks
+.import '|gzip -dc transactions-0-2.csv.gz' transactions
+.import '|gzip -dc tx_in-0-2.csv.gz' tx_in
+.import '|gzip -dc tx_out-0-2.csv.gz' tx_out
+.import '|gzip -dc blocks-2-4.csv.gz' blocks
+.import '|gzip -dc transactions-2-4.csv.gz' transactions
+.import '|gzip -dc tx_in-2-4.csv.gz' tx_in
+.import '|gzip -dc tx_out-2-4.csv.gz' tx_out
+
+UPDATE "transactions" SET "usesLockTime" = ("usesLockTime" = 'true');
+
+UPDATE "tx_in" SET "revealedScriptType" = NULLIF("revealedScriptType", ''), "revealedScriptHex" = NULLIF("revealedScriptHex", ''), "relativeLockType" = NULLIF("relativeLockType", ''), "relativeLockValue" = NULLIF("relativeLockValue", '');
+
+UPDATE "tx_out" SET "address" = NULLIF("address", '');
+
+CREATE INDEX "blocks_height" ON "blocks" ("height");
+CREATE UNIQUE INDEX "blocks_hash" ON "blocks" ("hash");
+CREATE INDEX "transactions_txid" ON "transactions" ("txid");
+CREATE INDEX "transactions_hashBlock" ON "transactions" ("hashBlock");
+CREATE INDEX "tx_in_hashPrevOut_indexPrevOut" ON "tx_in" ("hashPrevOut", "indexPrevOut");
+CREATE INDEX "tx_in_txid" ON "tx_in" ("txid");
+CREATE INDEX "tx_out_txid_indexOut" ON "tx_out" ("txid", "indexOut");
+CREATE INDEX "tx_out_address" ON "tx_out" ("address");
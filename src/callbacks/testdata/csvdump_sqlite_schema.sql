@@ -0,0 +1,47 @@
+-- Tables of the csv dump, generated by rusty-blockparser --emit-sql sqlite
+
+CREATE TABLE IF NOT EXISTS "blocks" (
+    "hash" TEXT NOT NULL,
+    "height" INTEGER NOT NULL,
+    "version" INTEGER NOT NULL,
+    "blocksize" INTEGER NOT NULL,
+    "hashPrev" TEXT NOT NULL,
+    "hashMerkleRoot" TEXT NOT NULL,
+    "nTime" INTEGER NOT NULL,
+    "nBits" INTEGER NOT NULL,
+    "nNonce" INTEGER NOT NULL,
+    "mwebSize" INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS "transactions" (
+    "txid" TEXT NOT NULL,
+    "hashBlock" TEXT NOT NULL,
+    "version" INTEGER NOT NULL,
+    "lockTime" INTEGER NOT NULL,
+    "size" INTEGER NOT NULL,
+    "strippedSize" INTEGER NOT NULL,
+    "vsize" INTEGER NOT NULL,
+    "weight" INTEGER NOT NULL,
+    "lockTimeType" TEXT NOT NULL,
+    "usesLockTime" INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS "tx_in" (
+    "txid" TEXT NOT NULL,
+    "hashPrevOut" TEXT NOT NULL,
+    "indexPrevOut" INTEGER NOT NULL,
+    "scriptSig" TEXT NOT NULL,
+    "sequence" INTEGER NOT NULL,
+    "revealedScriptType" TEXT,
+    "revealedScriptHex" TEXT,
+    "relativeLockType" TEXT,
+    "relativeLockValue" INTEGER
+);
+
+CREATE TABLE IF NOT EXISTS "tx_out" (
+    "txid" TEXT NOT NULL,
+    "indexOut" INTEGER NOT NULL,
+    "value" INTEGER NOT NULL,
+    "scriptPubKey" TEXT NOT NULL,
+    "address" TEXT
+);
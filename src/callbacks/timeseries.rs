@@ -0,0 +1,443 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use crate::blockchain::parser::types::CoinType;
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::script::ScriptPattern;
+use crate::blockchain::proto::tx::{EvaluatedTx, TxOutpoint};
+use crate::blockchain::proto::Hashed;
+use crate::callbacks::Callback;
+use crate::common::outputstore::{outpoint_key, read_varint, write_varint, OutputStore};
+use crate::common::utils;
+use crate::errors::{OpError, OpResult};
+
+/// Blocks of the median time past
+const MEDIAN_TIME_SPAN: usize = 11;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BucketSize {
+    Hour,
+    Day,
+    Week,
+}
+
+impl BucketSize {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "hour" => Some(BucketSize::Hour),
+            "day" => Some(BucketSize::Day),
+            "week" => Some(BucketSize::Week),
+            _ => None,
+        }
+    }
+
+    #[inline]
+    fn seconds(&self) -> i64 {
+        match *self {
+            BucketSize::Hour => 3600,
+            BucketSize::Day => 86400,
+            BucketSize::Week => 7 * 86400,
+        }
+    }
+
+    /// Start of the bucket containing the timestamp, weeks start on Monday (UTC)
+    #[inline]
+    fn start(&self, timestamp: u32) -> i64 {
+        // 1970-01-05 was a Monday
+        let offset = match *self {
+            BucketSize::Week => 4 * 86400,
+            _ => 0,
+        };
+        (timestamp as i64 - offset).div_euclid(self.seconds()) * self.seconds() + offset
+    }
+}
+
+/// Statistics of the blocks with a timestamp in `start..start + bucket size`
+#[derive(Debug, Default)]
+struct Bucket {
+    start: i64,
+    first_height: u64,
+    last_height: u64,
+    blocks: u64,
+    txs: u64,
+    output_value: u64,
+    fees: u64,
+    unresolved_inputs: u64,
+    total_size: u64,
+    // Sum and number of the time differences to the previous block
+    total_interval: i64,
+    intervals: u64,
+    // Hashes of the output addresses
+    addresses: HashSet<u64>,
+}
+
+impl Bucket {
+    fn new(start: i64, height: u64) -> Self {
+        Bucket {
+            start,
+            first_height: height,
+            last_height: height,
+            ..Default::default()
+        }
+    }
+
+    fn header() -> &'static str {
+        "bucket_start;first_height;last_height;blocks;txs;output_value;fees;unresolved_inputs;\
+         avg_block_size;avg_block_interval;active_addresses\n"
+    }
+
+    fn to_row(&self) -> String {
+        format!(
+            "{};{};{};{};{};{};{};{};{};{};{}\n",
+            utils::timestamp_to_datetime(self.start.clamp(0, u32::MAX as i64) as u32),
+            self.first_height,
+            self.last_height,
+            self.blocks,
+            self.txs,
+            self.output_value,
+            self.fees,
+            self.unresolved_inputs,
+            average(self.total_size as i64, self.blocks),
+            average(self.total_interval, self.intervals),
+            self.addresses.len()
+        )
+    }
+}
+
+/// Mean with two decimals, empty if there is nothing to average
+fn average(total: i64, n: u64) -> String {
+    if n == 0 {
+        return String::new();
+    }
+    format!("{:.2}", total as f64 / n as f64)
+}
+
+#[inline]
+fn address_hash(address: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    address.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Aggregates blocks into hourly, daily or weekly buckets by their timestamp
+pub struct TimeSeries {
+    output_dir: PathBuf,
+    bucket_size: BucketSize,
+    // Values of the outputs which are not spent yet
+    outputs: Option<OutputStore>,
+    writer: Option<BufWriter<File>>,
+    // Buckets which can still get blocks, by start
+    buckets: BTreeMap<i64, Bucket>,
+    recent_times: VecDeque<u32>,
+    prev_timestamp: Option<u32>,
+
+    start_height: u64,
+    n_buckets: u64,
+}
+
+impl TimeSeries {
+    fn apply_tx(&mut self, tx: &Hashed<EvaluatedTx>, start: i64) -> OpResult<()> {
+        let outputs = self.outputs.as_mut().unwrap();
+        let bucket = self.buckets.get_mut(&start).unwrap();
+        bucket.txs += 1;
+        let mut input_value = Some(0);
+        if !tx.value.is_coinbase() {
+            for input in &tx.value.inputs {
+                match outputs.take(&outpoint_key(&input.outpoint))? {
+                    Some(value) => {
+                        let value = read_varint(&mut value.as_slice())?;
+                        input_value = input_value.map(|total| total + value);
+                    }
+                    None => {
+                        bucket.unresolved_inputs += 1;
+                        input_value = None;
+                    }
+                }
+            }
+        }
+        let mut output_value = 0;
+        for (i, output) in tx.value.outputs.iter().enumerate() {
+            output_value += output.out.value;
+            if let Some(address) = &output.script.address {
+                bucket.addresses.insert(address_hash(address));
+            }
+            // Data outputs can't be spent
+            if let ScriptPattern::DataOutput(_) = output.script.pattern {
+                continue;
+            }
+            let mut value = Vec::with_capacity(9);
+            write_varint(&mut value, output.out.value);
+            outputs.insert(outpoint_key(&TxOutpoint::new(tx.hash, i as u32)), value)?;
+        }
+        bucket.output_value += output_value;
+        if !tx.value.is_coinbase() {
+            if let Some(input_value) = input_value {
+                bucket.fees += input_value.saturating_sub(output_value);
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the buckets ending before `time` in order
+    fn write_buckets_before(&mut self, time: i64) -> OpResult<()> {
+        let writer = self.writer.as_mut().unwrap();
+        while let Some(entry) = self.buckets.first_entry() {
+            if entry.key() + self.bucket_size.seconds() > time {
+                break;
+            }
+            writer.write_all(entry.remove().to_row().as_bytes())?;
+            self.n_buckets += 1;
+        }
+        Ok(())
+    }
+}
+
+impl Callback for TimeSeries {
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b>
+    where
+        Self: Sized,
+    {
+        SubCommand::with_name("timeseries")
+            .about("Writes block, transaction, value, fee and address statistics per hour, day or week to CSV file")
+            .version("0.1")
+            .arg(
+                Arg::with_name("output-dir")
+                    .long("output-dir")
+                    .value_name("PATH")
+                    .help("Folder to store timeseries.csv, created if necessary")
+                    .takes_value(true)
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name("bucket")
+                    .long("bucket")
+                    .value_name("SIZE")
+                    .help("Time span per row, by block timestamp in UTC (default: day)")
+                    .possible_values(&["hour", "day", "week"])
+                    .takes_value(true),
+            )
+            .args(&OutputStore::args())
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let bucket_size = BucketSize::from_name(matches.value_of("bucket").unwrap_or("day"))
+            .ok_or_else(|| OpError::from(String::from("--bucket must be hour, day or week.")))?;
+        let outputs = OutputStore::from_matches(matches)?;
+        let cb = TimeSeries {
+            output_dir: PathBuf::from(matches.value_of("output-dir").unwrap()),
+            bucket_size,
+            outputs: Some(outputs),
+            writer: None,
+            buckets: BTreeMap::new(),
+            recent_times: VecDeque::with_capacity(MEDIAN_TIME_SPAN),
+            prev_timestamp: None,
+            start_height: 0,
+            n_buckets: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, _: &CoinType, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        if block_height != 0 {
+            warn!(target: "callback", "Starting at height {}, fees of transactions spending earlier outputs are left out.", block_height);
+        }
+        fs::create_dir_all(&self.output_dir)?;
+        let mut writer = BufWriter::with_capacity(
+            4000000,
+            File::create(self.output_dir.join("timeseries.csv.tmp"))?,
+        );
+        writer.write_all(Bucket::header().as_bytes())?;
+        self.writer = Some(writer);
+        info!(target: "callback", "Using `timeseries` with output dir: {} and buckets of one {:?} ...",
+              &self.output_dir.display(), self.bucket_size);
+        Ok(())
+    }
+
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<()> {
+        let timestamp = block.header.value.timestamp;
+        // Timestamps are not ordered, the block goes to the bucket of its own timestamp
+        let start = self.bucket_size.start(timestamp);
+        let bucket = self
+            .buckets
+            .entry(start)
+            .or_insert_with(|| Bucket::new(start, block_height));
+        bucket.first_height = bucket.first_height.min(block_height);
+        bucket.last_height = bucket.last_height.max(block_height);
+        bucket.blocks += 1;
+        bucket.total_size += block.size as u64;
+        if let Some(prev_timestamp) = self.prev_timestamp {
+            bucket.total_interval += timestamp as i64 - prev_timestamp as i64;
+            bucket.intervals += 1;
+        }
+        self.prev_timestamp = Some(timestamp);
+        for tx in &block.txs {
+            self.apply_tx(tx, start)?;
+        }
+
+        // Each block must be newer than the median of the previous 11 (BIP113) and that median
+        // never decreases, so buckets ending before it are complete
+        if self.recent_times.len() == MEDIAN_TIME_SPAN {
+            self.recent_times.pop_front();
+        }
+        self.recent_times.push_back(timestamp);
+        if self.recent_times.len() == MEDIAN_TIME_SPAN {
+            let mut times: Vec<u32> = self.recent_times.iter().copied().collect();
+            times.sort_unstable();
+            self.write_buckets_before(times[MEDIAN_TIME_SPAN / 2] as i64)?;
+        }
+        Ok(())
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        self.write_buckets_before(i64::MAX)?;
+        let mut writer = self.writer.take().unwrap();
+        writer.flush()?;
+        drop(writer);
+        let path = self.output_dir.join("timeseries.csv");
+        fs::rename(path.with_extension("csv.tmp"), &path)?;
+        self.outputs.take().unwrap().destroy()?;
+
+        info!(target: "callback", "Done.\nDumped {} buckets of blocks {} to {} into {}.",
+              self.n_buckets, self.start_height, block_height, path.display());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+    use crate::blockchain::parser::types::Bitcoin;
+    use crate::callbacks::fixtures::{new_chain, new_tx, new_tx_to, SCRIPT_A, SCRIPT_B};
+
+    // 2009-01-03 23:50:00
+    const BEFORE_MIDNIGHT: u32 = 1231026600;
+
+    /// Returns the callback, the number of buckets still open before `on_complete` and the rows
+    fn run(bucket: &str, timestamps: &[u32]) -> (TimeSeries, usize, Vec<String>) {
+        let dir = env::temp_dir().join(format!(
+            "blockparser-timeseries-{}-{}-{}",
+            bucket,
+            timestamps.len(),
+            process::id()
+        ));
+        let matches = TimeSeries::build_subcommand().get_matches_from(vec![
+            "timeseries",
+            "--output-dir",
+            dir.to_str().unwrap(),
+            "--bucket",
+            bucket,
+        ]);
+        let mut timeseries = TimeSeries::new(&matches).unwrap();
+
+        let coinbase_0 = new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[5000000000]);
+        let spend = new_tx_to(
+            &[(coinbase_0.hash, 0)],
+            &[(3000000000, SCRIPT_B), (1999990000, SCRIPT_A)],
+        );
+        let mut blocks = vec![vec![coinbase_0], vec![spend]];
+        for i in 2..timestamps.len() {
+            blocks.push(vec![new_tx(
+                &[([0u8; 32], 0xFFFFFFFF)],
+                &[5000000000 + i as u64],
+            )]);
+        }
+        let mut chain = new_chain(blocks);
+        for (block, timestamp) in chain.iter_mut().zip(timestamps) {
+            block.header.value.timestamp = *timestamp;
+            block.size = 1000;
+        }
+
+        timeseries.on_start(&CoinType::from(Bitcoin), 0).unwrap();
+        for (height, block) in chain.iter().enumerate() {
+            timeseries.on_block(block, height as u64).unwrap();
+            if height == 0 {
+                // Not enough blocks for a median time
+                assert_eq!(1, timeseries.buckets.len());
+            }
+        }
+        let open_buckets = timeseries.buckets.len();
+        timeseries.on_complete(timestamps.len() as u64 - 1).unwrap();
+        let csv = fs::read_to_string(dir.join("timeseries.csv")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        (
+            timeseries,
+            open_buckets,
+            csv.lines().map(String::from).collect(),
+        )
+    }
+
+    #[test]
+    fn test_bucket_start() {
+        assert_eq!(1230940800, BucketSize::Day.start(1231006505));
+        assert_eq!(1231005600, BucketSize::Hour.start(1231006505));
+        // Monday, 2008-12-29
+        assert_eq!(1230508800, BucketSize::Week.start(1231006505));
+        assert_eq!(4 * 86400, BucketSize::Week.start(4 * 86400));
+        assert_eq!(-3 * 86400, BucketSize::Week.start(0));
+    }
+
+    #[test]
+    fn test_midnight() {
+        // The third block is dated back before midnight
+        let (timeseries, _, rows) = run(
+            "day",
+            &[
+                BEFORE_MIDNIGHT,
+                BEFORE_MIDNIGHT + 1200,
+                BEFORE_MIDNIGHT - 300,
+            ],
+        );
+        assert_eq!(2, timeseries.n_buckets);
+        assert_eq!(
+            vec![
+                Bucket::header().trim_end(),
+                "2009-01-03 00:00:00;0;2;2;2;10000000002;0;0;1000.00;-1500.00;1",
+                "2009-01-04 00:00:00;1;1;1;1;4999990000;10000;0;1000.00;1200.00;2",
+            ],
+            rows
+        );
+    }
+
+    #[test]
+    fn test_write_buckets() {
+        // One block per hour, the buckets are written once 11 blocks passed them
+        let timestamps: Vec<u32> = (0..14).map(|i| BEFORE_MIDNIGHT + i * 3600).collect();
+        let (timeseries, open_buckets, rows) = run("hour", &timestamps);
+        // The median time is the timestamp of block 8, the buckets from 23:00 to 06:00 are done
+        assert_eq!(6, open_buckets);
+        assert_eq!(14, timeseries.n_buckets);
+        assert_eq!(15, rows.len());
+        assert!(rows[1].starts_with("2009-01-03 23:00:00;0;0;1;1;"));
+        assert!(rows[14].starts_with("2009-01-04 12:00:00;13;13;1;1;"));
+
+        let (timeseries, _, rows) = run("week", &timestamps);
+        assert_eq!(1, timeseries.n_buckets);
+        assert!(rows[1].starts_with("2008-12-29 00:00:00;0;13;14;14;"));
+        assert!(rows[1].ends_with(";1000.00;3600.00;2"));
+    }
+
+    #[test]
+    fn test_invalid_args() {
+        let matches = TimeSeries::build_subcommand().get_matches_from(vec![
+            "timeseries",
+            "--output-dir",
+            "out",
+            "--buffer-size",
+            "0",
+        ]);
+        assert!(TimeSeries::new(&matches).is_err());
+    }
+}
@@ -8,17 +8,37 @@ use clap::{App, Arg, ArgMatches, SubCommand};
 
 use crate::blockchain::parser::types::CoinType;
 use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::tx::{EvaluatedTx, TxOutpoint};
+use crate::blockchain::proto::Hashed;
+use crate::callbacks::common::Compression;
+use crate::callbacks::sqlschema::ColumnType::{Hash, Text, U32, U64};
+use crate::callbacks::sqlschema::{self, Column, CsvFormat, Dialect, Index, Table};
 use crate::callbacks::{common, Callback};
+use crate::common::outputstore::{outpoint_key, OutputStore};
 use crate::common::utils;
-use crate::errors::OpResult;
+use crate::errors::{OpError, OpResult};
+
+const TABLE: &str = "unspent";
+const COLUMNS: [Column; 5] = [
+    Column::new("txid", Hash),
+    Column::new("indexOut", U32),
+    Column::new("height", U64),
+    Column::new("value", U64),
+    Column::new("address", Text(90)),
+];
+const INDEXES: [Index; 2] = [
+    Index::unique(&["txid", "indexOut"]),
+    Index::new(&["address"]),
+];
 
 /// Dumps the UTXOs along with address in a csv file
 pub struct UnspentCsvDump {
     dump_folder: PathBuf,
     writer: BufWriter<File>,
+    // Writes schema.sql and import.sql for the database
+    emit_sql: Option<Dialect>,
 
-    // key: txid + index
-    unspents: HashMap<Vec<u8>, common::UnspentValue>,
+    unspents: Option<Unspents>,
 
     start_height: u64,
     tx_count: u64,
@@ -26,12 +46,66 @@ pub struct UnspentCsvDump {
     out_count: u64,
 }
 
+/// Backend of the unspent outputs, keyed by txid + index
+enum Unspents {
+    Memory(HashMap<Vec<u8>, common::UnspentValue>),
+    // Values are encoded by `common::encode_unspent()`
+    Disk(OutputStore),
+}
+
 impl UnspentCsvDump {
     fn create_writer(cap: usize, path: PathBuf) -> OpResult<BufWriter<File>> {
         Ok(BufWriter::with_capacity(cap, File::create(&path)?))
     }
 }
 
+/// Same as `common::remove_unspents()` for the disk backend
+fn remove_stored(tx: &Hashed<EvaluatedTx>, store: &mut OutputStore) -> OpResult<u64> {
+    if !tx.value.is_coinbase() {
+        for input in &tx.value.inputs {
+            store.remove(&outpoint_key(&input.outpoint))?;
+        }
+    }
+    Ok(tx.value.in_count.value)
+}
+
+/// Same as `common::insert_unspents()` for the disk backend. Only coinbases are checked for
+/// duplicates, other transactions can't repeat a txid as they spend unique outputs.
+fn insert_stored(
+    tx: &Hashed<EvaluatedTx>,
+    block_height: u64,
+    store: &mut OutputStore,
+) -> OpResult<u64> {
+    let mut count = 0;
+    let mut duplicate = false;
+    for (i, output) in tx.value.outputs.iter().enumerate() {
+        match &output.script.address {
+            Some(address) => {
+                let key = outpoint_key(&TxOutpoint::new(tx.hash, i as u32));
+                if tx.value.is_coinbase() && !duplicate {
+                    duplicate = store.get(&key)?.is_some();
+                }
+                store.insert(
+                    key,
+                    common::encode_unspent(block_height, output.out.value, address),
+                )?;
+                count += 1;
+            }
+            None => {
+                debug!(
+                    target: "callback", "Ignoring invalid utxo in: {} ({})",
+                    utils::arr_to_hex_swapped(&tx.hash),
+                    output.script.pattern
+                );
+            }
+        }
+    }
+    if duplicate {
+        common::warn_duplicate_txid(tx, block_height);
+    }
+    Ok(count)
+}
+
 impl Callback for UnspentCsvDump {
     fn build_subcommand<'a, 'b>() -> App<'a, 'b>
     where
@@ -47,17 +121,34 @@ impl Callback for UnspentCsvDump {
                     .index(1)
                     .required(true),
             )
+            .args(&OutputStore::backend_args())
+            .arg(
+                Arg::with_name("emit-sql")
+                    .long("emit-sql")
+                    .value_name("DIALECT")
+                    .help("Writes the table to schema.sql and the statement loading the file to import.sql")
+                    .possible_values(&["postgres", "mysql", "sqlite", "clickhouse"])
+                    .takes_value(true),
+            )
     }
 
     fn new(matches: &ArgMatches) -> OpResult<Self>
     where
         Self: Sized,
     {
+        let unspents = match OutputStore::backend_from_matches(matches)? {
+            Some(store) => Unspents::Disk(store),
+            None => Unspents::Memory(HashMap::with_capacity(10000000)),
+        };
         let dump_folder = &PathBuf::from(matches.value_of("dump-folder").unwrap());
         let cb = UnspentCsvDump {
             dump_folder: PathBuf::from(dump_folder),
             writer: UnspentCsvDump::create_writer(4000000, dump_folder.join("unspent.csv.tmp"))?,
-            unspents: HashMap::with_capacity(10000000),
+            emit_sql: match matches.value_of("emit-sql") {
+                Some(name) => Some(Dialect::from_name(name)?),
+                None => None,
+            },
+            unspents: Some(unspents),
             start_height: 0,
             tx_count: 0,
             in_count: 0,
@@ -75,56 +166,205 @@ impl Callback for UnspentCsvDump {
     /// For each transaction in the block
     ///   1. apply input transactions (remove (TxID == prevTxIDOut and prevOutID == spentOutID))
     ///   2. apply output transactions (add (TxID + curOutID -> HashMapVal))
+    ///
     /// For each address, retain:
     ///   * block height as "last modified"
     ///   * output_val
     ///   * address
+    fn wants_witnesses(&self) -> bool {
+        false
+    }
+
     fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<()> {
         for tx in &block.txs {
-            self.in_count += common::remove_unspents(&tx, &mut self.unspents);
-            self.out_count += common::insert_unspents(&tx, block_height, &mut self.unspents);
+            match self.unspents.as_mut().unwrap() {
+                Unspents::Memory(unspents) => {
+                    self.in_count += common::remove_unspents(tx, unspents);
+                    self.out_count += common::insert_unspents(tx, block_height, unspents);
+                }
+                Unspents::Disk(store) => {
+                    self.in_count += remove_stored(tx, store)?;
+                    self.out_count += insert_stored(tx, block_height, store)?;
+                }
+            }
         }
         self.tx_count += block.tx_count.value;
         Ok(())
     }
 
     fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
-        self.writer.write_all(
-            format!(
-                "{};{};{};{};{}\n",
-                "txid", "indexOut", "height", "value", "address"
-            )
-            .as_bytes(),
-        )?;
-        for (key, value) in self.unspents.iter() {
+        let table = Table {
+            name: TABLE,
+            columns: COLUMNS.to_vec(),
+            indexes: &INDEXES,
+        };
+        self.writer
+            .write_all(common::csv_row(&table.column_names(), ';').as_bytes())?;
+        let writer = &mut self.writer;
+        let mut write_row = |key: &[u8], height: u64, value: u64, address: &str| {
             let txid = &key[0..32];
             let mut index = &key[32..];
-            self.writer.write_all(
+            writer.write_all(
                 format!(
                     "{};{};{};{};{}\n",
                     utils::arr_to_hex_swapped(txid),
                     index.read_u32::<LittleEndian>()?,
-                    value.block_height,
-                    value.value,
-                    value.address
+                    height,
+                    value,
+                    address
                 )
                 .as_bytes(),
             )?;
+            Ok::<_, OpError>(())
+        };
+        match self.unspents.take().unwrap() {
+            Unspents::Memory(unspents) => {
+                for (key, value) in unspents.iter() {
+                    write_row(key, value.block_height, value.value, &value.address)?;
+                }
+            }
+            Unspents::Disk(store) => {
+                // Streamed in key order
+                store.for_each(|key, value| {
+                    let (height, value, address) = common::decode_unspent(value)?;
+                    write_row(key, height, value, address)
+                })?;
+                store.destroy()?;
+            }
         }
+        self.writer.flush()?;
 
+        let file_name = format!("{}-{}-{}.csv", TABLE, self.start_height, block_height);
         fs::rename(
             self.dump_folder.as_path().join("unspent.csv.tmp"),
-            self.dump_folder.as_path().join(format!(
-                "unspent-{}-{}.csv",
-                self.start_height, block_height
-            )),
+            self.dump_folder.as_path().join(&file_name),
         )?;
+        if let Some(dialect) = self.emit_sql {
+            let format = CsvFormat {
+                delimiter: ';',
+                header: true,
+                compression: Compression::None,
+            };
+            sqlschema::write_sql(
+                &self.dump_folder,
+                dialect,
+                &[table],
+                &[(TABLE, file_name)],
+                &format,
+            )?;
+        }
 
+        let peak_memory = match utils::peak_memory() {
+            Some(bytes) => format!("{} MiB", bytes / (1024 * 1024)),
+            None => String::from("unknown"),
+        };
         info!(target: "callback", "Done.\nDumped all {} blocks:\n\
                                    \t-> transactions: {:9}\n\
                                    \t-> inputs:       {:9}\n\
-                                   \t-> outputs:      {:9}",
-             block_height, self.tx_count, self.in_count, self.out_count);
+                                   \t-> outputs:      {:9}\n\
+                                   \t-> peak memory:  {:>9}",
+             block_height, self.tx_count, self.in_count, self.out_count, peak_memory);
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+    use crate::blockchain::parser::types::Bitcoin;
+    use crate::callbacks::fixtures::{new_chain, new_tx, new_tx_to, SCRIPT_A, SCRIPT_B};
+
+    fn temp_dir(name: &str) -> PathBuf {
+        env::temp_dir().join(format!(
+            "blockparser-unspentcsvdump-{}-{}",
+            name,
+            process::id()
+        ))
+    }
+
+    /// Dumps the chain and returns the lines of the csv file
+    fn run_dump(name: &str, args: &[&str], chain: &[Block]) -> Vec<String> {
+        let dir = temp_dir(name);
+        fs::create_dir_all(&dir).unwrap();
+        let db_path = dir.join("db");
+        let mut all_args = vec!["unspentcsvdump", dir.to_str().unwrap()];
+        all_args.extend_from_slice(args);
+        if args.contains(&"disk") {
+            all_args.extend_from_slice(&["--db-path", db_path.to_str().unwrap()]);
+        }
+        let matches = UnspentCsvDump::build_subcommand().get_matches_from(all_args);
+        let mut dump = UnspentCsvDump::new(&matches).unwrap();
+        dump.on_start(&CoinType::from(Bitcoin), 0).unwrap();
+        for (height, block) in chain.iter().enumerate() {
+            dump.on_block(block, height as u64).unwrap();
+        }
+        dump.on_complete(chain.len() as u64).unwrap();
+        assert!(!db_path.exists());
+
+        let content =
+            fs::read_to_string(dir.join(format!("unspent-0-{}.csv", chain.len()))).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        content.lines().map(String::from).collect()
+    }
+
+    #[test]
+    fn test_disk_backend() {
+        // Every block spends the outputs of the previous one, except the first output each
+        let mut blocks = Vec::new();
+        let mut prev: Option<[u8; 32]> = None;
+        for height in 0..20u64 {
+            let mut txs = vec![new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[5000000000 + height])];
+            if let Some(prev) = prev {
+                txs.push(new_tx_to(
+                    &[(prev, 1), (prev, 2)],
+                    &[(height, SCRIPT_A), (1000, SCRIPT_B), (2000, SCRIPT_A)],
+                ));
+            } else {
+                txs.push(new_tx_to(
+                    &[(txs[0].hash, 0)],
+                    &[(0, SCRIPT_A), (1, SCRIPT_B), (2, SCRIPT_A), (3, "51")],
+                ));
+            }
+            prev = Some(txs[1].hash);
+            blocks.push(txs);
+        }
+        let chain = new_chain(blocks);
+
+        let mut memory = run_dump("memory", &[], &chain);
+        assert_eq!("txid;indexOut;height;value;address", memory[0]);
+        // 19 coinbases (the first is spent) and the first output of each spending tx, plus
+        // the two outputs of the last one
+        assert_eq!(1 + 19 + 20 + 2, memory.len());
+        memory[1..].sort();
+        for buffer_size in ["1", "3", "1000"] {
+            let mut disk = run_dump(
+                "disk",
+                &["--db-backend", "disk", "--buffer-size", buffer_size],
+                &chain,
+            );
+            disk[1..].sort();
+            assert_eq!(memory, disk, "buffer size {}", buffer_size);
+        }
+    }
+
+    #[test]
+    fn test_invalid_args() {
+        let dir = temp_dir("args");
+        fs::create_dir_all(&dir).unwrap();
+        for args in [
+            vec!["--db-backend", "disk"],
+            vec!["--db-path", "/tmp/db"],
+            vec!["--db-backend", "memory", "--db-path", "/tmp/db"],
+            vec!["--buffer-size", "0"],
+        ] {
+            let mut all_args = vec!["unspentcsvdump", dir.to_str().unwrap()];
+            all_args.extend_from_slice(&args);
+            let matches = UnspentCsvDump::build_subcommand().get_matches_from(all_args);
+            assert!(UnspentCsvDump::new(&matches).is_err(), "{:?}", args);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
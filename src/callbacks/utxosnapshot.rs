@@ -0,0 +1,444 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use crate::blockchain::parser::types::CoinType;
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::tx::{EvaluatedTx, TxOutpoint};
+use crate::blockchain::proto::Hashed;
+use crate::callbacks::Callback;
+use crate::common::outputstore::{outpoint_key, read_varint, write_varint, OutputStore};
+use crate::common::utils;
+use crate::errors::{OpError, OpResult};
+
+/// Magic bytes and format version at the start of the binary snapshot
+const MAGIC: &[u8; 8] = b"UTXOSNAP";
+const VERSION: u8 = 1;
+
+// Outputs starting with OP_RETURN or with larger scripts can never be spent
+// and are not part of the UTXO set (see CScript::IsUnspendable in Bitcoin Core)
+const OP_RETURN: u8 = 0x6a;
+const MAX_SCRIPT_SIZE: usize = 10000;
+
+/// Unspent output decoded from the store
+struct Utxo<'a> {
+    height: u64,
+    coinbase: bool,
+    amount: u64,
+    script: &'a [u8],
+}
+
+impl<'a> Utxo<'a> {
+    fn encode(height: u64, coinbase: bool, amount: u64, script: &[u8]) -> Vec<u8> {
+        let mut value = Vec::with_capacity(script.len() + 16);
+        write_varint(&mut value, height);
+        value.push(coinbase as u8);
+        write_varint(&mut value, amount);
+        value.extend_from_slice(script);
+        value
+    }
+
+    fn decode(mut value: &'a [u8]) -> OpResult<Self> {
+        let height = read_varint(&mut value)?;
+        let (coinbase, mut value) = match value.split_first() {
+            Some((flag, rest)) => (*flag != 0, rest),
+            None => return Err(OpError::from(String::from("Truncated UTXO record."))),
+        };
+        let amount = read_varint(&mut value)?;
+        Ok(Utxo {
+            height,
+            coinbase,
+            amount,
+            script: value,
+        })
+    }
+}
+
+/// Maintains the UTXO set and writes it to a CSV and a binary file, either at a given
+/// height or when the parser is done.
+///
+/// The binary file starts with `UTXOSNAP`, a version byte and the varint height, followed by
+/// one record per output: varint record length, txid (32 bytes, display order), varint vout,
+/// varint height, coinbase flag (1 byte), varint amount, varint script length and the script.
+/// All varints are unsigned LEB128.
+pub struct UtxoSnapshot {
+    output_dir: PathBuf,
+    at_height: Option<u64>,
+    // None after the snapshot has been written
+    store: Option<OutputStore>,
+
+    start_height: u64,
+    tx_count: u64,
+    in_count: u64,
+    out_count: u64,
+}
+
+impl UtxoSnapshot {
+    fn apply_tx(
+        store: &mut OutputStore,
+        tx: &Hashed<EvaluatedTx>,
+        block_height: u64,
+    ) -> OpResult<()> {
+        let coinbase = tx.value.is_coinbase();
+        if !coinbase {
+            for input in &tx.value.inputs {
+                store.remove(&outpoint_key(&input.outpoint))?;
+            }
+        }
+        for (i, output) in tx.value.outputs.iter().enumerate() {
+            let script = &output.out.script_pubkey;
+            if script.first() == Some(&OP_RETURN) || script.len() > MAX_SCRIPT_SIZE {
+                continue;
+            }
+            let key = outpoint_key(&TxOutpoint::new(tx.hash, i as u32));
+            store.insert(
+                key,
+                Utxo::encode(block_height, coinbase, output.out.value, script),
+            )?;
+        }
+        Ok(())
+    }
+
+    fn file_path(&self, block_height: u64, extension: &str) -> PathBuf {
+        self.output_dir
+            .join(format!("utxo-{}.{}", block_height, extension))
+    }
+
+    /// Writes both files and releases the store
+    fn write_snapshot(&mut self, block_height: u64) -> OpResult<()> {
+        let store = self.store.take().unwrap();
+        let csv_path = self.file_path(block_height, "csv");
+        let bin_path = self.file_path(block_height, "bin");
+        let csv_tmp = csv_path.with_extension("csv.tmp");
+        let bin_tmp = bin_path.with_extension("bin.tmp");
+        let mut csv = BufWriter::with_capacity(4000000, File::create(&csv_tmp)?);
+        let mut bin = BufWriter::with_capacity(4000000, File::create(&bin_tmp)?);
+
+        csv.write_all(b"txid;vout;height;coinbase;amount;scriptPubKey\n")?;
+        bin.write_all(MAGIC)?;
+        bin.write_all(&[VERSION])?;
+        let mut header = Vec::new();
+        write_varint(&mut header, block_height);
+        bin.write_all(&header)?;
+
+        let (mut count, mut total_amount) = (0u64, 0u64);
+        let mut record = Vec::new();
+        let mut payload = Vec::new();
+        store.for_each(|key, value| {
+            let utxo = Utxo::decode(value)?;
+            let mut txid = [0u8; 32];
+            txid.copy_from_slice(&key[0..32]);
+            txid.reverse();
+            let vout = u32::from_le_bytes([key[32], key[33], key[34], key[35]]);
+
+            csv.write_all(
+                format!(
+                    "{};{};{};{};{};{}\n",
+                    utils::arr_to_hex(&txid),
+                    vout,
+                    utxo.height,
+                    utxo.coinbase as u8,
+                    utxo.amount,
+                    utils::arr_to_hex(utxo.script)
+                )
+                .as_bytes(),
+            )?;
+
+            payload.clear();
+            payload.extend_from_slice(&txid);
+            write_varint(&mut payload, vout as u64);
+            write_varint(&mut payload, utxo.height);
+            payload.push(utxo.coinbase as u8);
+            write_varint(&mut payload, utxo.amount);
+            write_varint(&mut payload, utxo.script.len() as u64);
+            payload.extend_from_slice(utxo.script);
+            record.clear();
+            write_varint(&mut record, payload.len() as u64);
+            record.extend_from_slice(&payload);
+            bin.write_all(&record)?;
+
+            count += 1;
+            total_amount += utxo.amount;
+            Ok(())
+        })?;
+        store.destroy()?;
+
+        csv.flush()?;
+        bin.flush()?;
+        fs::rename(&csv_tmp, &csv_path)?;
+        fs::rename(&bin_tmp, &bin_path)?;
+        info!(target: "callback", "UTXO snapshot at height {}:\n\
+                                   \t-> txouts:       {:9}\n\
+                                   \t-> total_amount: {}.{:08}\n\
+                                   \t-> written to {} and {}",
+             block_height, count, total_amount / 100000000, total_amount % 100000000,
+             csv_path.display(), bin_path.display());
+        Ok(())
+    }
+}
+
+impl Callback for UtxoSnapshot {
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b>
+    where
+        Self: Sized,
+    {
+        SubCommand::with_name("utxosnapshot")
+            .about("Writes a snapshot of the UTXO set to CSV and binary files")
+            .version("0.1")
+            .arg(
+                Arg::with_name("output-dir")
+                    .long("output-dir")
+                    .value_name("PATH")
+                    .help("Folder to store utxo-<height>.csv and utxo-<height>.bin, created if necessary")
+                    .takes_value(true)
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name("at-height")
+                    .long("at-height")
+                    .value_name("HEIGHT")
+                    .help("Take the snapshot after this block, later blocks are ignored")
+                    .takes_value(true),
+            )
+            .args(&OutputStore::args())
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let at_height = match matches.value_of("at-height") {
+            Some(_) => match value_t!(matches, "at-height", u64) {
+                Ok(n) => Some(n),
+                Err(_) => {
+                    return Err(OpError::from(String::from(
+                        "--at-height must be a block height.",
+                    )))
+                }
+            },
+            None => None,
+        };
+        let store = OutputStore::from_matches(matches)?;
+        let cb = UtxoSnapshot {
+            output_dir: PathBuf::from(matches.value_of("output-dir").unwrap()),
+            at_height,
+            store: Some(store),
+            start_height: 0,
+            tx_count: 0,
+            in_count: 0,
+            out_count: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, _: &CoinType, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        if block_height != 0 {
+            warn!(target: "callback", "Starting at height {}, the UTXO snapshot will be incomplete.", block_height);
+        }
+        fs::create_dir_all(&self.output_dir)?;
+        info!(target: "callback", "Using `utxosnapshot` with output dir: {} ...", &self.output_dir.display());
+        Ok(())
+    }
+
+    fn wants_witnesses(&self) -> bool {
+        false
+    }
+
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<()> {
+        let store = match self.store.as_mut() {
+            Some(store) => store,
+            None => return Ok(()),
+        };
+        // Outputs of the genesis block can't be spent and are not in the UTXO set
+        if block_height > 0 {
+            for tx in &block.txs {
+                UtxoSnapshot::apply_tx(store, tx, block_height)?;
+                self.in_count += tx.value.in_count.value;
+                self.out_count += tx.value.out_count.value;
+            }
+        }
+        self.tx_count += block.tx_count.value;
+        if self.at_height == Some(block_height) {
+            self.write_snapshot(block_height)?;
+        }
+        Ok(())
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        if self.store.is_some() {
+            if let Some(at_height) = self.at_height {
+                warn!(target: "callback", "Height {} was not reached, taking the snapshot at height {}.", at_height, block_height);
+            }
+            self.write_snapshot(block_height)?;
+        }
+        info!(target: "callback", "Done.\nProcessed blocks {} to {}:\n\
+                                   \t-> transactions: {:9}\n\
+                                   \t-> inputs:       {:9}\n\
+                                   \t-> outputs:      {:9}",
+             self.start_height, block_height, self.tx_count, self.in_count, self.out_count);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::path::Path;
+    use std::process;
+
+    use super::*;
+    use crate::blockchain::parser::types::Bitcoin;
+    use crate::callbacks::fixtures::{new_chain, new_tx, new_tx_to, SCRIPT_A, SCRIPT_B};
+
+    // OP_RETURN "hello world"
+    const DATA_SCRIPT: &str = "6a0b68656c6c6f20776f726c64";
+
+    fn temp_dir(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("blockparser-{}-{}", name, process::id()))
+    }
+
+    /// txid, vout, height, coinbase, amount, script
+    type Record = (String, u64, u64, bool, u64, String);
+
+    fn read_bin(path: &Path) -> (u64, Vec<Record>) {
+        let data = fs::read(path).unwrap();
+        assert_eq!(MAGIC, &data[..8]);
+        assert_eq!(VERSION, data[8]);
+        let mut data = &data[9..];
+        let height = read_varint(&mut data).unwrap();
+        let mut records = Vec::new();
+        while !data.is_empty() {
+            let len = read_varint(&mut data).unwrap() as usize;
+            let (mut record, rest) = data.split_at(len);
+            data = rest;
+            let txid = utils::arr_to_hex(&record[..32]);
+            record = &record[32..];
+            let vout = read_varint(&mut record).unwrap();
+            let height = read_varint(&mut record).unwrap();
+            let coinbase = record[0] == 1;
+            record = &record[1..];
+            let amount = read_varint(&mut record).unwrap();
+            let script_len = read_varint(&mut record).unwrap() as usize;
+            assert_eq!(script_len, record.len());
+            records.push((
+                txid,
+                vout,
+                height,
+                coinbase,
+                amount,
+                utils::arr_to_hex(record),
+            ));
+        }
+        records.sort();
+        (height, records)
+    }
+
+    fn run(args: &[&str], dir: &Path) -> UtxoSnapshot {
+        let mut all_args = vec!["utxosnapshot", "--output-dir", dir.to_str().unwrap()];
+        all_args.extend_from_slice(args);
+        let matches = UtxoSnapshot::build_subcommand().get_matches_from(all_args);
+        let genesis = new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[5000000000]);
+        let coinbase_1 = new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[5000000000]);
+        let spend = new_tx_to(
+            &[(coinbase_1.hash, 0)],
+            &[(0, DATA_SCRIPT), (1000, SCRIPT_B), (4999998000, SCRIPT_A)],
+        );
+        let spend_again = new_tx(&[(spend.hash, 2)], &[4999990000]);
+        let chain = new_chain(vec![
+            vec![genesis],
+            vec![coinbase_1],
+            vec![new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[5000001000]), spend],
+            vec![
+                new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[5000008000]),
+                spend_again,
+            ],
+        ]);
+
+        let mut snapshot = UtxoSnapshot::new(&matches).unwrap();
+        snapshot.on_start(&CoinType::from(Bitcoin), 0).unwrap();
+        for (height, block) in chain.iter().enumerate() {
+            snapshot.on_block(block, height as u64).unwrap();
+        }
+        snapshot.on_complete(3).unwrap();
+        snapshot
+    }
+
+    #[test]
+    fn test_snapshot() {
+        // No spill folder, spill folder without and with merges
+        for buffer_size in [None, Some("100"), Some("3"), Some("1")] {
+            let dir = temp_dir(&format!("utxosnapshot-{:?}", buffer_size));
+            let db_path = dir.join("db");
+            let mut args = vec![];
+            if let Some(buffer_size) = buffer_size {
+                args.extend_from_slice(&[
+                    "--db-path",
+                    db_path.to_str().unwrap(),
+                    "--buffer-size",
+                    buffer_size,
+                ]);
+            }
+            let snapshot = run(&args, &dir);
+            assert_eq!(6, snapshot.tx_count);
+            assert!(!db_path.exists());
+
+            let (height, records) = read_bin(&snapshot.file_path(3, "bin"));
+            assert_eq!(3, height);
+            // Block 2 and 3 coinbases, output 1 of `spend` and `spend_again`
+            assert_eq!(4, records.len());
+            let total: u64 = records.iter().map(|r| r.4).sum();
+            // Fees are claimed by the coinbases
+            assert_eq!(3 * 5000000000, total);
+            assert!(records.iter().all(|r| r.5 != DATA_SCRIPT));
+            let b_output = records.iter().find(|r| r.5 == SCRIPT_B).unwrap();
+            assert_eq!(
+                (1, 2, false, 1000),
+                (b_output.1, b_output.2, b_output.3, b_output.4)
+            );
+            assert_eq!(2, records.iter().filter(|r| r.3).count());
+
+            let csv = fs::read_to_string(snapshot.file_path(3, "csv")).unwrap();
+            let mut lines: Vec<&str> = csv.lines().collect();
+            assert_eq!("txid;vout;height;coinbase;amount;scriptPubKey", lines[0]);
+            lines.sort();
+            let line = format!("{};1;2;0;1000;{}", b_output.0, SCRIPT_B);
+            assert!(lines.contains(&line.as_str()));
+            assert_eq!(5, lines.len());
+            fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_at_height() {
+        let dir = temp_dir("utxosnapshot-at-height");
+        let snapshot = run(&["--at-height", "2"], &dir);
+        assert!(!snapshot.file_path(3, "bin").exists());
+        let (height, records) = read_bin(&snapshot.file_path(2, "bin"));
+        assert_eq!(2, height);
+        // `spend_again` of block 3 is not applied
+        assert_eq!(3, records.len());
+        assert!(records.iter().any(|r| r.4 == 4999998000));
+        // Later blocks are ignored
+        assert_eq!(4, snapshot.tx_count);
+        assert_eq!(3, snapshot.in_count);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_existing_db_path() {
+        let dir = temp_dir("utxosnapshot-existing");
+        fs::create_dir_all(&dir).unwrap();
+        let matches = UtxoSnapshot::build_subcommand().get_matches_from(vec![
+            "utxosnapshot",
+            "--output-dir",
+            dir.to_str().unwrap(),
+            "--db-path",
+            dir.to_str().unwrap(),
+        ]);
+        assert!(UtxoSnapshot::new(&matches).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,666 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use crate::blockchain::parser::types::CoinType;
+use crate::blockchain::proto::block::Block;
+use crate::blockchain::proto::script::AddressFormat;
+use crate::blockchain::proto::tx::EvaluatedTx;
+use crate::blockchain::proto::Hashed;
+use crate::callbacks::filter::{TxFilter, WatchKey};
+use crate::callbacks::Callback;
+use crate::common::bech32;
+use crate::common::bip32::{Bip32Error, ExtendedPubKey, HARDENED};
+use crate::common::utils;
+use crate::errors::{OpError, OpResult};
+
+const DEFAULT_GAP_LIMIT: u32 = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ScriptType {
+    P2pkh,
+    P2shP2wpkh,
+    P2wpkh,
+    P2tr,
+}
+
+/// Returns the script type implied by the SLIP-132 version and the bech32 HRP of its network
+fn key_version_info(version: u32) -> Option<(ScriptType, &'static str)> {
+    match version {
+        0x0488b21e => Some((ScriptType::P2pkh, "bc")), // xpub
+        0x049d7cb2 => Some((ScriptType::P2shP2wpkh, "bc")), // ypub
+        0x04b24746 => Some((ScriptType::P2wpkh, "bc")), // zpub
+        0x043587cf => Some((ScriptType::P2pkh, "tb")), // tpub
+        0x044a5262 => Some((ScriptType::P2shP2wpkh, "tb")), // upub
+        0x045f1cf6 => Some((ScriptType::P2wpkh, "tb")), // vpub
+        _ => None,
+    }
+}
+
+/// Single key wallet, either an extended public key or a descriptor like
+/// `wpkh([d34db33f/84h/0h/0h]xpub.../<0;1>/*)`
+#[derive(Debug)]
+struct Descriptor {
+    script_type: ScriptType,
+    key: ExtendedPubKey,
+    hrp: &'static str,
+    /// Derivation path of `key`, `m` if unknown
+    origin: String,
+    /// Paths below `key` whose children are scanned, e.g. receive and change
+    chains: Vec<Vec<u32>>,
+}
+
+impl Descriptor {
+    fn parse_key(key: &str) -> OpResult<(ExtendedPubKey, ScriptType, &'static str)> {
+        let key = ExtendedPubKey::from_base58(key)
+            .map_err(|e| OpError::from(format!("Invalid extended public key {}: {}.", key, e)))?;
+        match key_version_info(key.version) {
+            Some((script_type, hrp)) => Ok((key, script_type, hrp)),
+            None => Err(OpError::from(format!(
+                "Unknown extended public key version {:08x}.",
+                key.version
+            ))),
+        }
+    }
+
+    /// An extended public key scans its receive (/0/*) and change (/1/*) addresses,
+    /// the script type follows from the prefix (xpub: P2PKH, ypub: P2SH-P2WPKH, zpub: P2WPKH).
+    fn from_xpub(xpub: &str) -> OpResult<Descriptor> {
+        let (key, script_type, hrp) = Descriptor::parse_key(xpub)?;
+        Ok(Descriptor {
+            script_type,
+            key,
+            hrp,
+            origin: String::from("m"),
+            chains: vec![vec![0], vec![1]],
+        })
+    }
+
+    /// Parses `pkh()`, `sh(wpkh())`, `wpkh()` and `tr()` descriptors with a single extended key
+    /// followed by unhardened steps and a final `*`, one step may be a `<0;1>` multipath.
+    /// The checksum isn't verified.
+    fn parse(descriptor: &str) -> OpResult<Descriptor> {
+        let invalid =
+            |reason: &str| OpError::from(format!("Invalid descriptor {}: {}.", descriptor, reason));
+        let desc = descriptor.split('#').next().unwrap_or_default().trim();
+        let functions = [
+            ("sh(wpkh(", "))", ScriptType::P2shP2wpkh),
+            ("pkh(", ")", ScriptType::P2pkh),
+            ("wpkh(", ")", ScriptType::P2wpkh),
+            ("tr(", ")", ScriptType::P2tr),
+        ];
+        let (script_type, inner) = functions
+            .iter()
+            .find_map(|(prefix, suffix, script_type)| {
+                let inner = desc.strip_prefix(prefix)?.strip_suffix(suffix)?;
+                Some((*script_type, inner))
+            })
+            .ok_or_else(|| invalid("expected pkh(), sh(wpkh()), wpkh() or tr()"))?;
+
+        let (origin, key_path) = match inner.strip_prefix('[') {
+            Some(rest) => {
+                let (origin, key_path) = rest
+                    .split_once(']')
+                    .ok_or_else(|| invalid("unterminated key origin"))?;
+                let path = origin.split_once('/').map(|(_, path)| path);
+                (format!("m/{}", path.unwrap_or_default()), key_path)
+            }
+            None => (String::from("m"), inner),
+        };
+        let origin = String::from(origin.trim_end_matches('/'));
+
+        let mut steps = key_path.split('/');
+        let (key, _, hrp) = Descriptor::parse_key(steps.next().unwrap_or_default())?;
+        let steps: Vec<&str> = steps.collect();
+        match steps.split_last() {
+            Some((&"*", _)) => (),
+            _ => return Err(invalid("the key must end with /*")),
+        }
+
+        let mut chains = vec![Vec::new()];
+        for step in &steps[..steps.len() - 1] {
+            let indexes: Vec<&str> = match step.strip_prefix('<').and_then(|s| s.strip_suffix('>'))
+            {
+                Some(_) if chains.len() > 1 => {
+                    return Err(invalid("only one multipath step is allowed"))
+                }
+                Some(multipath) => multipath.split(';').collect(),
+                None => vec![step],
+            };
+            let indexes = indexes
+                .iter()
+                .map(|index| match index.parse::<u32>() {
+                    Ok(index) if index < HARDENED => Ok(index),
+                    _ => Err(invalid(
+                        "only unhardened steps can be derived from a public key",
+                    )),
+                })
+                .collect::<OpResult<Vec<u32>>>()?;
+            let prefix = chains.pop().unwrap();
+            if indexes.len() > 1 {
+                chains = indexes
+                    .iter()
+                    .map(|index| [&prefix[..], &[*index]].concat())
+                    .collect();
+            } else {
+                chains.push([&prefix[..], &indexes[..]].concat());
+            }
+        }
+        // A multipath step must be the last one before `*`
+        if chains.len() > 1 && chains[0].len() != steps.len() - 1 {
+            return Err(invalid("the multipath step must be followed by /*"));
+        }
+        Ok(Descriptor {
+            script_type,
+            key,
+            hrp,
+            origin,
+            chains,
+        })
+    }
+
+    /// Returns the watched destination and the address of a derived key
+    fn destination(
+        &self,
+        key: &ExtendedPubKey,
+        format: &AddressFormat,
+    ) -> Option<(WatchKey, String)> {
+        let pub_key = key.public_key.serialize();
+        let h160 = utils::ridemp160(&utils::sha256(&pub_key));
+        let destination = match self.script_type {
+            ScriptType::P2pkh => (WatchKey::KeyHash(h160), format.p2pkh(&h160)),
+            ScriptType::P2shP2wpkh => {
+                let redeem_script = [&[0x00, 0x14], &h160[..]].concat();
+                let script_hash = utils::ridemp160(&utils::sha256(&redeem_script));
+                (WatchKey::ScriptHash(script_hash), format.p2sh(&script_hash))
+            }
+            ScriptType::P2wpkh => (
+                WatchKey::KeyHash(h160),
+                bech32::encode_segwit(self.hrp, 0, &h160),
+            ),
+            ScriptType::P2tr => {
+                let output_key = key.public_key.taproot_output_key()?;
+                (
+                    WatchKey::WitnessProgram(1, output_key.to_vec()),
+                    bech32::encode_segwit(self.hrp, 1, &output_key),
+                )
+            }
+        };
+        Some(destination)
+    }
+}
+
+struct Chain {
+    key: ExtendedPubKey,
+    path: String,
+    n_derived: u32,
+}
+
+struct Derived {
+    address: String,
+    path: String,
+    chain: usize,
+    index: u32,
+}
+
+/// Scans the history of an HD wallet: derives the addresses of an extended public key
+/// or descriptor and dumps their transactions like `filter`, plus the unspent outputs.
+pub struct WalletScan {
+    output_dir: PathBuf,
+    descriptor: Descriptor,
+    gap_limit: u32,
+    address_format: AddressFormat,
+    chains: Vec<Chain>,
+    // Derived addresses in the order of the destinations in `tx_filter`
+    derived: Vec<Derived>,
+    tx_filter: Option<TxFilter>,
+
+    start_height: u64,
+}
+
+impl WalletScan {
+    /// Derives the addresses of a chain up to (excluding) index `count`
+    fn derive(&mut self, chain: usize, count: u32) -> OpResult<()> {
+        let tx_filter = self.tx_filter.as_mut().unwrap();
+        while self.chains[chain].n_derived < count {
+            let index = self.chains[chain].n_derived;
+            self.chains[chain].n_derived += 1;
+            let key = match self.chains[chain].key.derive_child(index) {
+                Ok(key) => key,
+                Err(Bip32Error::InvalidChild) => continue,
+                Err(e) => return Err(OpError::from(e.to_string())),
+            };
+            let (destination, address) =
+                match self.descriptor.destination(&key, &self.address_format) {
+                    Some(destination) => destination,
+                    None => continue,
+                };
+            if tx_filter.watch(destination) == self.derived.len() {
+                self.derived.push(Derived {
+                    address,
+                    path: format!("{}/{}", self.chains[chain].path, index),
+                    chain,
+                    index,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Keeps `gap_limit` unused addresses after the last used one of each chain. Outputs of
+    /// earlier transactions to the new addresses are missed, like in any gap limit based wallet.
+    fn extend_gap(&mut self, tx: &Hashed<EvaluatedTx>) -> OpResult<()> {
+        // A new address may be paid by another output of the same transaction
+        loop {
+            let n_derived = self.derived.len();
+            for output in &tx.value.outputs {
+                let index = match WatchKey::from_script(&output.out.script_pubkey)
+                    .and_then(|key| self.tx_filter.as_ref().unwrap().index_of(&key))
+                {
+                    Some(index) => index,
+                    None => continue,
+                };
+                let (chain, index) = (self.derived[index].chain, self.derived[index].index);
+                self.derive(chain, index.saturating_add(self.gap_limit + 1))?;
+            }
+            if self.derived.len() == n_derived {
+                return Ok(());
+            }
+        }
+    }
+
+    fn write_addresses(&self, tx_filter: &TxFilter) -> OpResult<()> {
+        let mut writer = BufWriter::new(File::create(self.output_dir.join("addresses.csv.tmp"))?);
+        writer.write_all(b"path;address;received;sent;txs\n")?;
+        for (derived, activity) in self.derived.iter().zip(&tx_filter.activity) {
+            writer.write_all(
+                format!(
+                    "{};{};{};{};{}\n",
+                    derived.path, derived.address, activity.received, activity.sent, activity.txs
+                )
+                .as_bytes(),
+            )?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Writes the unspent outputs ordered by height, returns the balance
+    fn write_utxos(&self, tx_filter: &TxFilter) -> OpResult<u64> {
+        let mut utxos: Vec<_> = tx_filter.outputs.iter().collect();
+        utxos.sort_unstable_by_key(|(key, output)| (output.height, **key));
+        let mut writer = BufWriter::new(File::create(self.output_dir.join("utxos.csv.tmp"))?);
+        writer.write_all(b"txid;index;height;value;address;path\n")?;
+        let mut balance = 0;
+        for (key, output) in utxos {
+            let derived = &self.derived[output.index];
+            writer.write_all(
+                format!(
+                    "{};{};{};{};{};{}\n",
+                    utils::arr_to_hex_swapped(&key[..32]),
+                    u32::from_le_bytes([key[32], key[33], key[34], key[35]]),
+                    output.height,
+                    output.value,
+                    derived.address,
+                    derived.path
+                )
+                .as_bytes(),
+            )?;
+            balance += output.value;
+        }
+        writer.flush()?;
+        Ok(balance)
+    }
+}
+
+impl Callback for WalletScan {
+    fn build_subcommand<'a, 'b>() -> App<'a, 'b>
+    where
+        Self: Sized,
+    {
+        SubCommand::with_name("wallet-scan")
+            .about("Dumps the transactions and unspent outputs of an HD wallet into CSV files")
+            .version("0.1")
+            .arg(
+                Arg::with_name("output-dir")
+                    .long("output-dir")
+                    .value_name("PATH")
+                    .help("Folder to store csv files, created if necessary")
+                    .takes_value(true)
+                    .required(true),
+            )
+            .arg(
+                Arg::with_name("xpub")
+                    .long("xpub")
+                    .value_name("KEY")
+                    .help("Extended public key, scans /0/* and /1/* (xpub: P2PKH, ypub: P2SH-P2WPKH, zpub: P2WPKH)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("descriptor")
+                    .long("descriptor")
+                    .value_name("DESCRIPTOR")
+                    .help("Single key descriptor, e.g. wpkh([d34db33f/84h/0h/0h]xpub.../<0;1>/*) or tr(xpub.../0/*)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("gap-limit")
+                    .long("gap-limit")
+                    .value_name("N")
+                    .help("Number of unused addresses to derive after the last used one (default: 20)")
+                    .takes_value(true),
+            )
+    }
+
+    fn new(matches: &ArgMatches) -> OpResult<Self>
+    where
+        Self: Sized,
+    {
+        let descriptor = match (matches.value_of("xpub"), matches.value_of("descriptor")) {
+            (Some(xpub), None) => Descriptor::from_xpub(xpub)?,
+            (None, Some(descriptor)) => Descriptor::parse(descriptor)?,
+            _ => {
+                return Err(OpError::from(String::from(
+                    "Either --xpub or --descriptor is required.",
+                )))
+            }
+        };
+        let gap_limit = match matches.value_of("gap-limit") {
+            Some(_) => match value_t!(matches, "gap-limit", u32) {
+                Ok(n) if n > 0 => n,
+                _ => {
+                    return Err(OpError::from(String::from(
+                        "--gap-limit must be a positive number of addresses.",
+                    )))
+                }
+            },
+            None => DEFAULT_GAP_LIMIT,
+        };
+        let mut chains = Vec::with_capacity(descriptor.chains.len());
+        for path in &descriptor.chains {
+            chains.push(Chain {
+                key: descriptor
+                    .key
+                    .derive_path(path)
+                    .map_err(|e| OpError::from(e.to_string()))?,
+                path: path.iter().fold(descriptor.origin.clone(), |path, index| {
+                    format!("{}/{}", path, index)
+                }),
+                n_derived: 0,
+            });
+        }
+
+        let cb = WalletScan {
+            output_dir: PathBuf::from(matches.value_of("output-dir").unwrap()),
+            descriptor,
+            gap_limit,
+            address_format: AddressFormat::default(),
+            chains,
+            derived: Vec::new(),
+            tx_filter: None,
+            start_height: 0,
+        };
+        Ok(cb)
+    }
+
+    fn on_start(&mut self, coin_type: &CoinType, block_height: u64) -> OpResult<()> {
+        self.start_height = block_height;
+        self.address_format = coin_type.address_format();
+        self.tx_filter = Some(TxFilter::create(&self.output_dir)?);
+        for chain in 0..self.chains.len() {
+            self.derive(chain, self.gap_limit)?;
+        }
+        info!(target: "callback", "Using `wallet-scan` with {} chains and output dir: {} ...",
+              self.chains.len(), &self.output_dir.display());
+        Ok(())
+    }
+
+    fn on_block(&mut self, block: &Block, block_height: u64) -> OpResult<()> {
+        let block_hash = utils::arr_to_hex_swapped(&block.header.hash);
+        for tx in &block.txs {
+            self.extend_gap(tx)?;
+            self.tx_filter
+                .as_mut()
+                .unwrap()
+                .filter_tx(tx, &block_hash, block_height)?;
+        }
+        Ok(())
+    }
+
+    fn on_complete(&mut self, block_height: u64) -> OpResult<()> {
+        let mut tx_filter = self.tx_filter.take().unwrap();
+        tx_filter.finish()?;
+        self.write_addresses(&tx_filter)?;
+        let balance = self.write_utxos(&tx_filter)?;
+        for name in &["addresses", "utxos"] {
+            fs::rename(
+                self.output_dir.join(format!("{}.csv.tmp", name)),
+                self.output_dir.join(format!("{}.csv", name)),
+            )?;
+        }
+
+        let n_used = tx_filter.activity.iter().filter(|a| a.txs > 0).count();
+        info!(target: "callback", "Done.\nDumped blocks {} to {} into {}:\n\
+                                   \t-> addresses:    {:9} ({} used)\n\
+                                   \t-> transactions: {:9}\n\
+                                   \t-> utxos:        {:9}\n\
+                                   \t-> balance:      {:9}",
+              self.start_height, block_height, self.output_dir.display(),
+              self.derived.len(), n_used, tx_filter.tx_count, tx_filter.outputs.len(), balance);
+        self.tx_filter = Some(tx_filter);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+    use crate::blockchain::parser::types::Bitcoin;
+    use crate::callbacks::fixtures::{new_chain, new_tx, new_tx_to};
+
+    // Account keys of the BIP44, BIP49, BIP84 and BIP86 test vectors,
+    // derived from the mnemonic `abandon abandon ... about`
+    const XPUB_44: &str = "xpub6BosfCnifzxcFwrSzQiqu2DBVTshkCXacvNsWGYJVVhhawA7d4R5WSWGFNbi8Aw6ZRc1brxMyWMzG3DSSSSoekkudhUd9yLb6qx39T9nMdj";
+    const YPUB_49: &str = "ypub6Ww3ibxVfGzLrAH1PNcjyAWenMTbbAosGNB6VvmSEgytSER9azLDWCxoJwW7Ke7icmizBMXrzBx9979FfaHxHcrArf3zbeJJJUZPf663zsP";
+    const ZPUB_84: &str = "zpub6rFR7y4Q2AijBEqTUquhVz398htDFrtymD9xYYfG1m4wAcvPhXNfE3EfH1r1ADqtfSdVCToUG868RvUUkgDKf31mGDtKsAYz2oz2AGutZYs";
+    const XPUB_86: &str = "xpub6BgBgsespWvERF3LHQu6CnqdvfEvtMcQjYrcRzx53QJjSxarj2afYWcLteoGVky7D3UKDP9QyrLprQ3VCECoY49yfdDEHGCtMMj92pReUsQ";
+
+    fn new_wallet(name: &str, args: &[&str]) -> OpResult<(WalletScan, PathBuf)> {
+        let dir =
+            env::temp_dir().join(format!("blockparser-walletscan-{}-{}", name, process::id()));
+        let mut full_args = vec!["wallet-scan", "--output-dir", dir.to_str().unwrap()];
+        full_args.extend_from_slice(args);
+        let matches = WalletScan::build_subcommand().get_matches_from(full_args);
+        Ok((WalletScan::new(&matches)?, dir))
+    }
+
+    #[test]
+    fn test_derivation() {
+        let pkh = format!("pkh([73c5da0a/44h/0h/0h]{}/<0;1>/*)", XPUB_44);
+        let tr = format!("tr([73c5da0a/86'/0'/0']{}/<0;1>/*)#xxxxxxxx", XPUB_86);
+        let vectors: [(&str, &str, [&str; 3]); 4] = [
+            (
+                "--descriptor",
+                &pkh,
+                [
+                    "1LqBGSKuX5yYUonjxT5qGfpUsXKYYWeabA",
+                    "1Ak8PffB2meyfYnbXZR9EGfLfFZVpzJvQP",
+                    "1J3J6EvPrv8q6AC3VCjWV45Uf3nssNMRtH",
+                ],
+            ),
+            (
+                "--xpub",
+                YPUB_49,
+                [
+                    "37VucYSaXLCAsxYyAPfbSi9eh4iEcbShgf",
+                    "3LtMnn87fqUeHBUG414p9CWwnoV6E2pNKS",
+                    "34K56kSjgUCUSD8GTtuF7c9Zzwokbs6uZ7",
+                ],
+            ),
+            (
+                "--xpub",
+                ZPUB_84,
+                [
+                    "bc1qcr8te4kr609gcawutmrza0j4xv80jy8z306fyu",
+                    "bc1qnjg0jd8228aq7egyzacy8cys3knf9xvrerkf9g",
+                    "bc1q8c6fshw2dlwun7ekn9qwf37cu2rn755upcp6el",
+                ],
+            ),
+            (
+                "--descriptor",
+                &tr,
+                [
+                    "bc1p5cyxnuxmeuwuvkwfem96lqzszd02n6xdcjrs20cac6yqjjwudpxqkedrcr",
+                    "bc1p4qhjn9zdvkux4e44uhx8tc55attvtyu358kutcqkudyccelu0was9fqzwh",
+                    "bc1p3qkhfews2uk44qtvauqyr2ttdsw7svhkl9nkm9s9c3x4ax5h60wqwruhk7",
+                ],
+            ),
+        ];
+        for (i, (arg, key, addresses)) in vectors.iter().enumerate() {
+            let (mut wallet, dir) =
+                new_wallet(&format!("derive{}", i), &[arg, key, "--gap-limit", "2"]).unwrap();
+            wallet.on_start(&CoinType::from(Bitcoin), 0).unwrap();
+            fs::remove_dir_all(&dir).unwrap();
+            let derived: Vec<(&str, &str)> = wallet
+                .derived
+                .iter()
+                .map(|d| (d.address.as_str(), d.path.as_str()))
+                .collect();
+            let origin = ["m/44h/0h/0h", "m", "m", "m/86'/0'/0'"][i];
+            assert_eq!(
+                vec![
+                    (addresses[0], format!("{}/0/0", origin).as_str()),
+                    (addresses[1], format!("{}/0/1", origin).as_str()),
+                    (addresses[2], format!("{}/1/0", origin).as_str()),
+                ],
+                vec![derived[0], derived[1], derived[2]]
+            );
+            assert_eq!(4, derived.len());
+        }
+    }
+
+    #[test]
+    fn test_parse_descriptor() {
+        let desc = Descriptor::parse(&format!("sh(wpkh({}/7/<3;4>/*))", XPUB_44)).unwrap();
+        assert_eq!(ScriptType::P2shP2wpkh, desc.script_type);
+        assert_eq!(vec![vec![7, 3], vec![7, 4]], desc.chains);
+        assert_eq!("m", desc.origin);
+        assert_eq!(
+            vec![vec![0]],
+            Descriptor::parse(&format!("wpkh({}/0/*)", ZPUB_84))
+                .unwrap()
+                .chains
+        );
+
+        let invalid = [
+            format!("wpkh({})", ZPUB_84),
+            format!("wpkh({}/0)", ZPUB_84),
+            format!("wpkh({}/0h/*)", ZPUB_84),
+            format!("wpkh({}/<0;1>/<0;1>/*)", ZPUB_84),
+            format!("wpkh({}/<0;1>/0/*)", ZPUB_84),
+            format!("wpkh([73c5da0a/84h/0h/0h{}/0/*)", ZPUB_84),
+            format!("sh(multi(1,{}/0/*))", ZPUB_84),
+            String::from("wpkh(xpub/0/*)"),
+        ];
+        for desc in invalid.iter() {
+            assert!(Descriptor::parse(desc).is_err(), "{}", desc);
+        }
+        assert!(new_wallet("args", &[]).is_err());
+        assert!(new_wallet(
+            "args",
+            &[
+                "--xpub",
+                ZPUB_84,
+                "--descriptor",
+                &format!("wpkh({}/0/*)", ZPUB_84)
+            ]
+        )
+        .is_err());
+        assert!(new_wallet("args", &["--xpub", ZPUB_84, "--gap-limit", "0"]).is_err());
+    }
+
+    #[test]
+    fn test_gap_limit() {
+        // P2WPKH scripts of ZPUB_84 /0/0, /0/1, /0/3, /0/5 and /0/9
+        let scripts = [
+            "0014c0cebcd6c3d3ca8c75dc5ec62ebe55330ef910e2",
+            "00149c90f934ea51fa0f6504177043e0908da6929983",
+            "001447e8cfa1e26ffc460468348a9cb218d0346a3778",
+            "0014984420485959d0da23e7fe902bf315814cc7b64b",
+            "0014441c190a0040f6a05936496c2005dad554158d02",
+        ];
+        let coinbase = new_tx(&[([0u8; 32], 0xFFFFFFFF)], &[5000000000]);
+        // /0/1 is within the initial gap, /0/3 only after /0/1 was used
+        let fund = new_tx_to(
+            &[(coinbase.hash, 0)],
+            &[(1000, scripts[1]), (2000, scripts[2])],
+        );
+        // /0/9 is beyond the gap and missed
+        let late = new_tx_to(
+            &[([0x01; 32], 0)],
+            &[(3000, scripts[3]), (4000, scripts[4])],
+        );
+        let spend = new_tx_to(&[(fund.hash, 0)], &[(900, scripts[0])]);
+        let txids: Vec<String> = [&fund, &late, &spend]
+            .iter()
+            .map(|tx| utils::arr_to_hex_swapped(&tx.hash))
+            .collect();
+        let chain = new_chain(vec![vec![coinbase], vec![fund], vec![late], vec![spend]]);
+
+        let desc = format!("wpkh({}/0/*)", ZPUB_84);
+        let (mut wallet, dir) =
+            new_wallet("gap", &["--descriptor", &desc, "--gap-limit", "2"]).unwrap();
+        wallet.on_start(&CoinType::from(Bitcoin), 0).unwrap();
+        for (height, block) in chain.iter().enumerate() {
+            wallet.on_block(block, height as u64).unwrap();
+        }
+        wallet.on_complete(3).unwrap();
+
+        let read = |name: &str| {
+            fs::read_to_string(dir.join(name))
+                .unwrap()
+                .lines()
+                .map(String::from)
+                .collect::<Vec<String>>()
+        };
+        let addresses = read("addresses.csv");
+        let utxos = read("utxos.csv");
+        let txs = read("transactions.csv");
+        fs::remove_dir_all(&dir).unwrap();
+
+        // /0/5 was derived once /0/3 got used, /0/6 and /0/7 after /0/5
+        assert_eq!(3, txs.len());
+        assert_eq!(1 + 8, addresses.len());
+        assert_eq!(
+            "m/0/1;bc1qnjg0jd8228aq7egyzacy8cys3knf9xvrerkf9g;1000;1000;2",
+            addresses[2]
+        );
+        assert_eq!(
+            "m/0/5;bc1qnpzzqjzet8gd5gl8l6gzhuc4s9xv0djt0rlu7a;3000;0;1",
+            addresses[6]
+        );
+        assert_eq!(
+            vec![
+                String::from("txid;index;height;value;address;path"),
+                format!(
+                    "{};1;1;2000;bc1qgl5vlg0zdl7yvprgxj9fevsc6q6x5dmcyk3cn3;m/0/3",
+                    txids[0]
+                ),
+                format!(
+                    "{};0;2;3000;bc1qnpzzqjzet8gd5gl8l6gzhuc4s9xv0djt0rlu7a;m/0/5",
+                    txids[1]
+                ),
+                format!(
+                    "{};0;3;900;bc1qcr8te4kr609gcawutmrza0j4xv80jy8z306fyu;m/0/0",
+                    txids[2]
+                ),
+            ],
+            utxos
+        );
+    }
+}
@@ -0,0 +1,259 @@
+//! Encoding and decoding of segwit addresses, bech32 (BIP173) for witness version 0 and
+//! bech32m (BIP350) for all later versions.
+
+use std::error;
+use std::fmt;
+
+const CHARSET: &[u8; 32] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+const BECH32_CONST: u32 = 1;
+const BECH32M_CONST: u32 = 0x2bc830a3;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Bech32Error {
+    /// Mixed case, missing separator, invalid characters or a bad length
+    InvalidFormat,
+    InvalidChecksum,
+    /// The witness version or program length violates BIP141/BIP350
+    InvalidProgram,
+}
+
+impl fmt::Display for Bech32Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Bech32Error::InvalidFormat => write!(f, "Invalid bech32 format"),
+            Bech32Error::InvalidChecksum => write!(f, "Invalid bech32 checksum"),
+            Bech32Error::InvalidProgram => write!(f, "Invalid witness program"),
+        }
+    }
+}
+
+impl error::Error for Bech32Error {}
+
+/// A decoded segwit address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SegwitAddress {
+    pub hrp: String,
+    pub version: u8,
+    pub program: Vec<u8>,
+}
+
+/// Decodes a segwit address like `bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4`.
+/// The human readable part is not checked, it's up to the caller to compare it.
+pub fn decode_segwit(address: &str) -> Result<SegwitAddress, Bech32Error> {
+    if address.len() > 90
+        || address.bytes().any(|c| !(33..=126).contains(&c))
+        || (address.bytes().any(|c| c.is_ascii_lowercase())
+            && address.bytes().any(|c| c.is_ascii_uppercase()))
+    {
+        return Err(Bech32Error::InvalidFormat);
+    }
+    let address = address.to_ascii_lowercase();
+    let sep = address.rfind('1').ok_or(Bech32Error::InvalidFormat)?;
+    let (hrp, data) = (&address[..sep], &address.as_bytes()[sep + 1..]);
+    // At least the witness version and the 6 character checksum
+    if hrp.is_empty() || data.len() < 7 {
+        return Err(Bech32Error::InvalidFormat);
+    }
+    let values = data
+        .iter()
+        .map(|c| CHARSET.iter().position(|x| x == c).map(|v| v as u8))
+        .collect::<Option<Vec<u8>>>()
+        .ok_or(Bech32Error::InvalidFormat)?;
+
+    let version = values[0];
+    let expected = if version == 0 {
+        BECH32_CONST
+    } else {
+        BECH32M_CONST
+    };
+    let mut checked = expand_hrp(hrp);
+    checked.extend_from_slice(&values);
+    if polymod(&checked) != expected {
+        return Err(Bech32Error::InvalidChecksum);
+    }
+
+    let program = convert_bits(&values[1..values.len() - 6])?;
+    if version > 16
+        || program.len() < 2
+        || program.len() > 40
+        || (version == 0 && program.len() != 20 && program.len() != 32)
+    {
+        return Err(Bech32Error::InvalidProgram);
+    }
+    Ok(SegwitAddress {
+        hrp: String::from(hrp),
+        version,
+        program,
+    })
+}
+
+/// Encodes a witness program as segwit address with bech32 for version 0 and bech32m otherwise
+pub fn encode_segwit(hrp: &str, version: u8, program: &[u8]) -> String {
+    let mut values = vec![version];
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    for b in program {
+        acc = (acc << 8) | *b as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            values.push(((acc >> bits) & 31) as u8);
+        }
+    }
+    if bits > 0 {
+        values.push(((acc << (5 - bits)) & 31) as u8);
+    }
+
+    let constant = if version == 0 {
+        BECH32_CONST
+    } else {
+        BECH32M_CONST
+    };
+    let mut checked = expand_hrp(hrp);
+    checked.extend_from_slice(&values);
+    checked.extend_from_slice(&[0; 6]);
+    let checksum = polymod(&checked) ^ constant;
+    values.extend((0..6).map(|i| ((checksum >> (5 * (5 - i))) & 31) as u8));
+
+    let mut address = String::with_capacity(hrp.len() + 1 + values.len());
+    address.push_str(hrp);
+    address.push('1');
+    address.extend(values.iter().map(|v| CHARSET[*v as usize] as char));
+    address
+}
+
+fn polymod(values: &[u8]) -> u32 {
+    const GENERATOR: [u32; 5] = [0x3b6a57b2, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3];
+    let mut chk: u32 = 1;
+    for v in values {
+        let top = chk >> 25;
+        chk = (chk & 0x1ffffff) << 5 ^ *v as u32;
+        for (i, g) in GENERATOR.iter().enumerate() {
+            if (top >> i) & 1 == 1 {
+                chk ^= g;
+            }
+        }
+    }
+    chk
+}
+
+fn expand_hrp(hrp: &str) -> Vec<u8> {
+    let mut expanded: Vec<u8> = hrp.bytes().map(|c| c >> 5).collect();
+    expanded.push(0);
+    expanded.extend(hrp.bytes().map(|c| c & 31));
+    expanded
+}
+
+/// Regroups 5 bit values to bytes, the padding must be zero and shorter than 5 bits
+fn convert_bits(values: &[u8]) -> Result<Vec<u8>, Bech32Error> {
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    let mut bytes = Vec::with_capacity(values.len() * 5 / 8);
+    for v in values {
+        acc = (acc << 5) | *v as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((acc >> bits) as u8);
+        }
+    }
+    if bits >= 5 || (acc << (8 - bits)) as u8 != 0 {
+        return Err(Bech32Error::InvalidProgram);
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::utils;
+
+    #[test]
+    fn test_decode_segwit() {
+        // Vectors from BIP173 and BIP350
+        let valid = [
+            (
+                "BC1QW508D6QEJXTDG4Y5R3ZARVARY0C5XW7KV8F3T4",
+                "bc",
+                0,
+                "751e76e8199196d454941c45d1b3a323f1433bd6",
+            ),
+            (
+                "tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7",
+                "tb",
+                0,
+                "1863143c14c5166804bd19203356da136c985678cd4d27a1b8c6329604903262",
+            ),
+            (
+                "bc1p0xlxvlhemja6c4dqv22uapctqupfhlxm9h8z3k2e72q4k9hcz7vqzk5jj0",
+                "bc",
+                1,
+                "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+            ),
+            (
+                "tb1pqqqqp399et2xygdj5xreqhjjvcmzhxw4aywxecjdzew6hylgvsesf3hn0c",
+                "tb",
+                1,
+                "000000c4a5cad46221b2a187905e5266362b99d5e91c6ce24d165dab93e86433",
+            ),
+            (
+                "bc1zw508d6qejxtdg4y5r3zarvaryvaxxpcs",
+                "bc",
+                2,
+                "751e76e8199196d454941c45d1b3a323",
+            ),
+            ("BC1SW50QGDZ25J", "bc", 16, "751e"),
+        ];
+        for (address, hrp, version, program) in valid.iter() {
+            let decoded = decode_segwit(address).unwrap();
+            assert_eq!(*hrp, decoded.hrp);
+            assert_eq!(*version, decoded.version);
+            assert_eq!(*program, utils::arr_to_hex(&decoded.program));
+            assert_eq!(
+                address.to_lowercase(),
+                encode_segwit(hrp, *version, &decoded.program)
+            );
+        }
+
+        let invalid = [
+            // Mixed case
+            (
+                "tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sL5k7",
+                Bech32Error::InvalidFormat,
+            ),
+            // No separator
+            ("bc1", Bech32Error::InvalidFormat),
+            (
+                "bcqw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4",
+                Bech32Error::InvalidFormat,
+            ),
+            // Invalid character `b`
+            (
+                "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3tb",
+                Bech32Error::InvalidFormat,
+            ),
+            (
+                "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t5",
+                Bech32Error::InvalidChecksum,
+            ),
+            // Version 1 with a bech32 instead of a bech32m checksum
+            (
+                "bc1pw508d6qejxtdg4y5r3zarvary0c5xw7kw508d6qejxtdg4y5r3zarvary0c5xw7k7grplx",
+                Bech32Error::InvalidChecksum,
+            ),
+            // Version 0 with a bech32m checksum
+            (
+                "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kemeawh",
+                Bech32Error::InvalidChecksum,
+            ),
+            // Version 0 programs must be 20 or 32 bytes
+            (
+                "BC1QR508D6QEJXTDG4Y5R3ZARVARYV98GJ9P",
+                Bech32Error::InvalidProgram,
+            ),
+        ];
+        for (address, err) in invalid.iter() {
+            assert_eq!(Err(err.clone()), decode_segwit(address), "{}", address);
+        }
+    }
+}
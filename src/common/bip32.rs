@@ -0,0 +1,150 @@
+//! Unhardened BIP32 derivation of extended public keys
+
+use std::error;
+use std::fmt;
+
+use crate::crypto::hmac::Hmac;
+use crate::crypto::mac::Mac;
+use crate::crypto::sha2::Sha512;
+use rust_base58::FromBase58;
+
+use crate::common::secp256k1::PublicKey;
+use crate::common::utils;
+
+/// Child numbers from 2^31 on are hardened and need the private key
+pub const HARDENED: u32 = 0x80000000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Bip32Error {
+    /// Not base58, a bad checksum or not 78 bytes long
+    InvalidFormat,
+    InvalidPublicKey,
+    HardenedDerivation,
+    /// Happens with a probability below 2^-127, BIP32 says to skip the index
+    InvalidChild,
+}
+
+impl fmt::Display for Bip32Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Bip32Error::InvalidFormat => write!(f, "Invalid extended key format"),
+            Bip32Error::InvalidPublicKey => write!(f, "Invalid public key"),
+            Bip32Error::HardenedDerivation => {
+                write!(f, "Hardened derivation requires a private key")
+            }
+            Bip32Error::InvalidChild => write!(f, "Invalid child key"),
+        }
+    }
+}
+
+impl error::Error for Bip32Error {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtendedPubKey {
+    /// SLIP-132 version bytes, e.g. 0x0488b21e for xpub
+    pub version: u32,
+    pub depth: u8,
+    pub parent_fingerprint: [u8; 4],
+    pub child_number: u32,
+    pub chain_code: [u8; 32],
+    pub public_key: PublicKey,
+}
+
+impl ExtendedPubKey {
+    /// Parses a base58check encoded extended public key (xpub, ypub, zpub, tpub, ...)
+    pub fn from_base58(key: &str) -> Result<ExtendedPubKey, Bip32Error> {
+        let decoded = key.from_base58().map_err(|_| Bip32Error::InvalidFormat)?;
+        if decoded.len() != 82
+            || utils::sha256(&utils::sha256(&decoded[..78]))[..4] != decoded[78..]
+        {
+            return Err(Bip32Error::InvalidFormat);
+        }
+        let mut parent_fingerprint = [0u8; 4];
+        parent_fingerprint.copy_from_slice(&decoded[5..9]);
+        let mut chain_code = [0u8; 32];
+        chain_code.copy_from_slice(&decoded[13..45]);
+        Ok(ExtendedPubKey {
+            version: u32::from_be_bytes([decoded[0], decoded[1], decoded[2], decoded[3]]),
+            depth: decoded[4],
+            parent_fingerprint,
+            child_number: u32::from_be_bytes([decoded[9], decoded[10], decoded[11], decoded[12]]),
+            chain_code,
+            public_key: PublicKey::from_slice(&decoded[45..78])
+                .ok_or(Bip32Error::InvalidPublicKey)?,
+        })
+    }
+
+    /// Derives the unhardened child key `index`
+    pub fn derive_child(&self, index: u32) -> Result<ExtendedPubKey, Bip32Error> {
+        if index >= HARDENED {
+            return Err(Bip32Error::HardenedDerivation);
+        }
+        let parent = self.public_key.serialize();
+        let mut hmac = Hmac::new(Sha512::new(), &self.chain_code);
+        hmac.input(&parent);
+        hmac.input(&index.to_be_bytes());
+        let result = hmac.result();
+        let (left, right) = result.code().split_at(32);
+
+        let mut tweak = [0u8; 32];
+        tweak.copy_from_slice(left);
+        let mut chain_code = [0u8; 32];
+        chain_code.copy_from_slice(right);
+        let mut parent_fingerprint = [0u8; 4];
+        parent_fingerprint.copy_from_slice(&utils::ridemp160(&utils::sha256(&parent))[..4]);
+        Ok(ExtendedPubKey {
+            version: self.version,
+            depth: self.depth.wrapping_add(1),
+            parent_fingerprint,
+            child_number: index,
+            chain_code,
+            public_key: self
+                .public_key
+                .add_tweak(&tweak)
+                .ok_or(Bip32Error::InvalidChild)?,
+        })
+    }
+
+    pub fn derive_path(&self, path: &[u32]) -> Result<ExtendedPubKey, Bip32Error> {
+        let mut key = self.clone();
+        for index in path {
+            key = key.derive_child(*index)?;
+        }
+        Ok(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_derive_child() {
+        // Public derivation steps of BIP32 test vector 1, m/0H -> m/0H/1 and m/0H/1/2H -> m/0H/1/2H/2
+        let vectors = [
+            ("xpub68Gmy5EdvgibQVfPdqkBBCHxA5htiqg55crXYuXoQRKfDBFA1WEjWgP6LHhwBZeNK1VTsfTFUHCdrfp1bgwQ9xv5ski8PX9rL2dZXvgGDnw",
+             1,
+             "xpub6ASuArnXKPbfEwhqN6e3mwBcDTgzisQN1wXN9BJcM47sSikHjJf3UFHKkNAWbWMiGj7Wf5uMash7SyYq527Hqck2AxYysAA7xmALppuCkwQ"),
+            ("xpub6D4BDPcP2GT577Vvch3R8wDkScZWzQzMMUm3PWbmWvVJrZwQY4VUNgqFJPMM3No2dFDFGTsxxpG5uJh7n7epu4trkrX7x7DogT5Uv6fcLW5",
+             2,
+             "xpub6FHa3pjLCk84BayeJxFW2SP4XRrFd1JYnxeLeU8EqN3vDfZmbqBqaGJAyiLjTAwm6ZLRQUMv1ZACTj37sR62cfN7fe5JnJ7dh8zL4fiyLHV"),
+        ];
+        for (parent, index, child) in vectors.iter() {
+            let parent = ExtendedPubKey::from_base58(parent).unwrap();
+            let child = ExtendedPubKey::from_base58(child).unwrap();
+            assert_eq!(child, parent.derive_child(*index).unwrap());
+            assert_eq!(child, parent.derive_path(&[*index]).unwrap());
+        }
+
+        let parent = ExtendedPubKey::from_base58(vectors[0].0).unwrap();
+        assert_eq!(
+            Err(Bip32Error::HardenedDerivation),
+            parent.derive_child(HARDENED)
+        );
+        // Broken checksum
+        assert_eq!(
+            Err(Bip32Error::InvalidFormat),
+            ExtendedPubKey::from_base58("xpub68Gmy5EdvgibQVfPdqkBBCHxA5htiqg55crXYuXoQRKfDBFA1WEjWgP6LHhwBZeNK1VTsfTFUHCdrfp1bgwQ9xv5ski8PX9rL2dZXvgGDnx")
+        );
+    }
+}
@@ -0,0 +1,177 @@
+//! BLAKE2b (RFC 7693) with the personalization used by Zcash, e.g. for ZIP 244 transaction ids.
+//! rust-crypto's implementation doesn't expose the parameter block.
+
+const IV: [u64; 8] = [
+    0x6a09e667f3bcc908,
+    0xbb67ae8584caa73b,
+    0x3c6ef372fe94f82b,
+    0xa54ff53a5f1d36f1,
+    0x510e527fade682d1,
+    0x9b05688c2b3e6c1f,
+    0x1f83d9abfb41bd6b,
+    0x5be0cd19137e2179,
+];
+
+const SIGMA: [[usize; 16]; 10] = [
+    [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15],
+    [14, 10, 4, 8, 9, 15, 13, 6, 1, 12, 0, 2, 11, 7, 5, 3],
+    [11, 8, 12, 0, 5, 2, 15, 13, 10, 14, 3, 6, 7, 1, 9, 4],
+    [7, 9, 3, 1, 13, 12, 11, 14, 2, 6, 5, 10, 4, 0, 15, 8],
+    [9, 0, 5, 7, 2, 4, 10, 15, 14, 1, 11, 12, 6, 8, 3, 13],
+    [2, 12, 6, 10, 0, 11, 8, 3, 4, 13, 7, 5, 15, 14, 1, 9],
+    [12, 5, 1, 15, 14, 13, 4, 10, 0, 7, 6, 3, 9, 2, 8, 11],
+    [13, 11, 7, 14, 12, 1, 3, 9, 5, 0, 15, 4, 8, 6, 2, 10],
+    [6, 15, 14, 9, 11, 3, 0, 8, 12, 2, 13, 7, 1, 4, 10, 5],
+    [10, 2, 8, 4, 7, 6, 1, 5, 15, 11, 9, 14, 3, 12, 13, 0],
+];
+
+const BLOCK_SIZE: usize = 128;
+
+/// Unkeyed BLAKE2b with up to 64 bytes of output
+pub struct Blake2b {
+    h: [u64; 8],
+    // Bytes compressed so far
+    t: u128,
+    buf: [u8; BLOCK_SIZE],
+    buf_len: usize,
+    out_len: usize,
+}
+
+impl Blake2b {
+    pub fn new(out_len: usize, personal: &[u8; 16]) -> Self {
+        assert!(
+            out_len > 0 && out_len <= 64,
+            "Invalid BLAKE2b output length"
+        );
+        let mut h = IV;
+        // Parameter block: digest length, no key, fanout and depth 1
+        h[0] ^= 0x01010000 ^ out_len as u64;
+        h[6] ^= u64::from_le_bytes(personal[..8].try_into().unwrap());
+        h[7] ^= u64::from_le_bytes(personal[8..].try_into().unwrap());
+        Blake2b {
+            h,
+            t: 0,
+            buf: [0u8; BLOCK_SIZE],
+            buf_len: 0,
+            out_len,
+        }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            // The last block is compressed in finalize(), so a full buffer waits for more data
+            if self.buf_len == BLOCK_SIZE {
+                self.t += BLOCK_SIZE as u128;
+                let block = self.buf;
+                self.compress(&block, false);
+                self.buf_len = 0;
+            }
+            let n = data.len().min(BLOCK_SIZE - self.buf_len);
+            self.buf[self.buf_len..self.buf_len + n].copy_from_slice(&data[..n]);
+            self.buf_len += n;
+            data = &data[n..];
+        }
+    }
+
+    pub fn finalize(mut self) -> Vec<u8> {
+        self.t += self.buf_len as u128;
+        let mut block = self.buf;
+        block[self.buf_len..].fill(0);
+        self.compress(&block, true);
+        self.h
+            .iter()
+            .flat_map(|word| word.to_le_bytes())
+            .take(self.out_len)
+            .collect()
+    }
+
+    fn compress(&mut self, block: &[u8; BLOCK_SIZE], last: bool) {
+        let mut m = [0u64; 16];
+        for (i, word) in m.iter_mut().enumerate() {
+            *word = u64::from_le_bytes(block[i * 8..i * 8 + 8].try_into().unwrap());
+        }
+        let mut v = [0u64; 16];
+        v[..8].copy_from_slice(&self.h);
+        v[8..].copy_from_slice(&IV);
+        v[12] ^= self.t as u64;
+        v[13] ^= (self.t >> 64) as u64;
+        if last {
+            v[14] = !v[14];
+        }
+        for round in 0..12 {
+            let s = &SIGMA[round % 10];
+            g(&mut v, 0, 4, 8, 12, m[s[0]], m[s[1]]);
+            g(&mut v, 1, 5, 9, 13, m[s[2]], m[s[3]]);
+            g(&mut v, 2, 6, 10, 14, m[s[4]], m[s[5]]);
+            g(&mut v, 3, 7, 11, 15, m[s[6]], m[s[7]]);
+            g(&mut v, 0, 5, 10, 15, m[s[8]], m[s[9]]);
+            g(&mut v, 1, 6, 11, 12, m[s[10]], m[s[11]]);
+            g(&mut v, 2, 7, 8, 13, m[s[12]], m[s[13]]);
+            g(&mut v, 3, 4, 9, 14, m[s[14]], m[s[15]]);
+        }
+        for i in 0..8 {
+            self.h[i] ^= v[i] ^ v[i + 8];
+        }
+    }
+}
+
+#[inline]
+fn g(v: &mut [u64; 16], a: usize, b: usize, c: usize, d: usize, x: u64, y: u64) {
+    v[a] = v[a].wrapping_add(v[b]).wrapping_add(x);
+    v[d] = (v[d] ^ v[a]).rotate_right(32);
+    v[c] = v[c].wrapping_add(v[d]);
+    v[b] = (v[b] ^ v[c]).rotate_right(24);
+    v[a] = v[a].wrapping_add(v[b]).wrapping_add(y);
+    v[d] = (v[d] ^ v[a]).rotate_right(16);
+    v[c] = v[c].wrapping_add(v[d]);
+    v[b] = (v[b] ^ v[c]).rotate_right(63);
+}
+
+/// 32 byte digest of the concatenated parts
+pub fn blake2b_256(personal: &[u8; 16], parts: &[&[u8]]) -> [u8; 32] {
+    let mut hasher = Blake2b::new(32, personal);
+    for part in parts {
+        hasher.update(part);
+    }
+    let mut hash = [0u8; 32];
+    hash.copy_from_slice(&hasher.finalize());
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::utils;
+
+    #[test]
+    fn test_blake2b() {
+        // RFC 7693, appendix A
+        let mut hasher = Blake2b::new(64, &[0u8; 16]);
+        hasher.update(b"abc");
+        assert_eq!(
+            concat!(
+                "ba80a53f981c4d0d6a2797b69f12f6e94c212f14685ac4b74b12bb6fdbffa2d1",
+                "7d87c5392aab792dc252d5de4533cc9518d38aa8dbf1925ab92386edd4009923"
+            ),
+            utils::arr_to_hex(&hasher.finalize())
+        );
+        assert_eq!(
+            "c33f2e95705faab35f8d533fa61e95c3b7aaba0776b874a9f74fc12784376a59",
+            utils::arr_to_hex(&blake2b_256(b"ZTxIdTranspaHash", &[]))
+        );
+
+        // Exactly one block, and more than two split at arbitrary positions
+        let data: Vec<u8> = (0..300).map(|i| i as u8).collect();
+        assert_eq!(
+            "68f5bac43bc706250ecbf60870c6e7c556f62ab2e572d17d6da79e07667a91fa",
+            utils::arr_to_hex(&blake2b_256(b"0123456789abcdef", &[&data[..128]]))
+        );
+        assert_eq!(
+            "e7e6be6755247d74801ab13ea3bd4bc2905c982d1b250782379a7a6706fde08a",
+            utils::arr_to_hex(&blake2b_256(
+                b"ZcashTxHash_\xbb\x09\xb8\x76",
+                &[&data[..1], &data[1..128], &data[128..129], &data[129..]]
+            ))
+        );
+    }
+}
@@ -0,0 +1,187 @@
+//! Bloom filter over byte strings, the bit positions are derived from two SipHash values
+//! (Kirsch-Mitzenmacher double hashing).
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    n_hashes: u32,
+}
+
+impl BloomFilter {
+    /// Creates a filter of at least `n_bits` bits which sets `n_hashes` bits per item
+    pub fn new(n_bits: usize, n_hashes: u32) -> Self {
+        BloomFilter {
+            bits: vec![0; n_bits.max(1).div_ceil(64)],
+            n_hashes: n_hashes.max(1),
+        }
+    }
+
+    /// Creates a filter sized for `n_items` items with a false positive rate of about `fp_rate`
+    pub fn with_rate(n_items: usize, fp_rate: f64) -> Self {
+        let n_bits = optimal_bits(n_items, fp_rate);
+        BloomFilter::new(n_bits, optimal_hashes(n_bits, n_items))
+    }
+
+    #[inline]
+    pub fn n_bits(&self) -> usize {
+        self.bits.len() * 64
+    }
+
+    #[inline]
+    pub fn n_hashes(&self) -> u32 {
+        self.n_hashes
+    }
+
+    /// Expected false positive rate after inserting `n_items` distinct items
+    pub fn false_positive_rate(&self, n_items: usize) -> f64 {
+        let k = self.n_hashes as f64;
+        (1.0 - (-k * n_items as f64 / self.n_bits() as f64).exp()).powf(k)
+    }
+
+    /// Resets all bits
+    pub fn clear(&mut self) {
+        self.bits.iter_mut().for_each(|word| *word = 0);
+    }
+
+    /// Adds the item and returns whether it may have been contained before
+    pub fn insert(&mut self, item: &[u8]) -> bool {
+        let mut contained = true;
+        for bit in self.positions(item) {
+            let word = &mut self.bits[bit / 64];
+            contained &= *word & (1 << (bit % 64)) != 0;
+            *word |= 1 << (bit % 64);
+        }
+        contained
+    }
+
+    /// Returns false if the item was never inserted, true if it may have been
+    pub fn contains(&self, item: &[u8]) -> bool {
+        self.positions(item)
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    fn positions(&self, item: &[u8]) -> impl Iterator<Item = usize> {
+        let mut hasher = DefaultHasher::new();
+        hasher.write(item);
+        let h1 = hasher.finish();
+        hasher.write_u8(0xff);
+        let h2 = hasher.finish() | 1;
+        let n_bits = self.bits.len() as u64 * 64;
+        (0..self.n_hashes as u64)
+            .map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % n_bits) as usize)
+    }
+}
+
+/// Number of bits for `n_items` items at a false positive rate of `fp_rate`: -n * ln(p) / ln(2)^2
+pub fn optimal_bits(n_items: usize, fp_rate: f64) -> usize {
+    let fp_rate = fp_rate.clamp(f64::MIN_POSITIVE, 0.5);
+    let n_items = n_items.max(1) as f64;
+    (-n_items * fp_rate.ln() / (2f64.ln() * 2f64.ln())).ceil() as usize
+}
+
+/// Number of hashes minimizing the false positive rate: m / n * ln(2)
+pub fn optimal_hashes(n_bits: usize, n_items: usize) -> u32 {
+    let k = n_bits as f64 / n_items.max(1) as f64 * 2f64.ln();
+    (k.round() as u32).clamp(1, 32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bloom_filter() {
+        let mut filter = BloomFilter::new(100000, 4);
+        assert_eq!(1563, filter.bits.len());
+        let collisions = (0u32..5000)
+            .filter(|n| filter.insert(&n.to_le_bytes()))
+            .count();
+        assert!(collisions < 25, "{} collisions", collisions);
+        for n in 0u32..5000 {
+            assert!(filter.contains(&n.to_le_bytes()));
+            assert!(filter.insert(&n.to_le_bytes()));
+        }
+        let false_positives = (5000u32..15000)
+            .filter(|n| filter.contains(&n.to_le_bytes()))
+            .count();
+        // About 0.1% expected
+        assert!(false_positives < 50, "{} false positives", false_positives);
+
+        // A single word filter still works, it only reports more false positives
+        let mut filter = BloomFilter::new(0, 0);
+        assert!(!filter.insert(b"a"));
+        assert!(filter.contains(b"a"));
+    }
+
+    #[test]
+    fn test_sizing() {
+        // 1 million items at 1% need about 9.59 bits per item and 7 hashes
+        assert_eq!(9_585_059, optimal_bits(1_000_000, 0.01));
+        assert_eq!(7, optimal_hashes(9_585_059, 1_000_000));
+        // 0.1% about 14.38 bits per item and 10 hashes
+        assert_eq!(14_377_588, optimal_bits(1_000_000, 0.001));
+        assert_eq!(10, optimal_hashes(14_377_588, 1_000_000));
+        // Degenerate inputs still give usable filters
+        assert_eq!(1, optimal_hashes(1, 1000));
+        assert_eq!(32, optimal_hashes(usize::MAX, 1));
+        assert!(optimal_bits(0, 0.0) > 0);
+        assert_eq!(3, optimal_bits(2, 0.5));
+
+        let filter = BloomFilter::with_rate(1_000_000, 0.01);
+        assert_eq!(9_585_088, filter.n_bits());
+        assert_eq!(7, filter.n_hashes());
+        let rate = filter.false_positive_rate(1_000_000);
+        assert!((rate - 0.01).abs() < 0.0005, "{}", rate);
+        assert_eq!(0.0, filter.false_positive_rate(0));
+    }
+
+    #[test]
+    fn test_double_hashing() {
+        let filter = BloomFilter::new(1 << 20, 8);
+        let positions = filter.positions(b"item").collect::<Vec<_>>();
+        assert_eq!(8, positions.len());
+        assert!(positions.iter().all(|&bit| bit < filter.n_bits()));
+        // h2 is odd, the power of two sized filter sets distinct bits
+        let mut distinct = positions.clone();
+        distinct.sort_unstable();
+        distinct.dedup();
+        assert_eq!(8, distinct.len());
+        // Equally spaced modulo the filter size
+        let step = (positions[1] + filter.n_bits() - positions[0]) % filter.n_bits();
+        for i in 1..positions.len() {
+            let diff = (positions[i] + filter.n_bits() - positions[i - 1]) % filter.n_bits();
+            assert_eq!(step, diff);
+        }
+        // Deterministic, different items hash differently
+        assert_eq!(positions, filter.positions(b"item").collect::<Vec<_>>());
+        assert_ne!(positions, filter.positions(b"items").collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_false_positive_rate() {
+        for &target in &[0.05, 0.01, 0.001] {
+            let mut filter = BloomFilter::with_rate(20_000, target);
+            for n in 0u32..20_000 {
+                filter.insert(&n.to_le_bytes());
+            }
+            let n_tests = 200_000u32;
+            let false_positives = (1_000_000..1_000_000 + n_tests)
+                .filter(|n| filter.contains(&n.to_le_bytes()))
+                .count();
+            let rate = false_positives as f64 / n_tests as f64;
+            assert!(
+                rate > target / 2.0 && rate < target * 1.5,
+                "target {}, measured {}",
+                target,
+                rate
+            );
+        }
+
+        let mut filter = BloomFilter::with_rate(10, 0.01);
+        filter.insert(b"a");
+        filter.clear();
+        assert!(!filter.contains(b"a"));
+    }
+}
@@ -0,0 +1,805 @@
+//! Minimal Neo4j client (Bolt protocol 4.4 and 5.0 - 5.4). Supports basic authentication,
+//! queries with parameters and explicit transactions. Values are encoded with PackStream,
+//! parameters and records are JSON values.
+//! TLS and routing (cluster discovery for `neo4j://` URIs) are not supported.
+
+use std::error;
+use std::fmt;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use byteorder::{BigEndian, ReadBytesExt};
+use serde_json::{json, Map, Number, Value};
+use url::Url;
+
+const MAGIC: [u8; 4] = [0x60, 0x60, 0xb0, 0x17];
+// Proposed versions as [0, range, minor, major]: 5.4 down to 5.0, then 4.4
+const VERSIONS: [[u8; 4]; 4] = [[0, 4, 4, 5], [0, 0, 4, 4], [0; 4], [0; 4]];
+const DEFAULT_PORT: u16 = 7687;
+const USER_AGENT: &str = concat!("rusty-blockparser/", env!("CARGO_PKG_VERSION"));
+const MAX_CHUNK_SIZE: usize = 0xffff;
+// Nesting limit of received values
+const MAX_DEPTH: usize = 64;
+
+// Request messages
+const HELLO: u8 = 0x01;
+const GOODBYE: u8 = 0x02;
+const RESET: u8 = 0x0f;
+const RUN: u8 = 0x10;
+const BEGIN: u8 = 0x11;
+const COMMIT: u8 = 0x12;
+const ROLLBACK: u8 = 0x13;
+const PULL: u8 = 0x3f;
+const LOGON: u8 = 0x6a;
+// Summary and detail messages
+const SUCCESS: u8 = 0x70;
+const RECORD: u8 = 0x71;
+const IGNORED: u8 = 0x7e;
+const FAILURE: u8 = 0x7f;
+
+#[derive(Debug)]
+pub enum BoltError {
+    Io(io::Error),
+    /// FAILURE sent by the server, e.g. `Neo.ClientError.Statement.SyntaxError`
+    Server {
+        code: String,
+        message: String,
+    },
+    /// Invalid URI
+    Config(String),
+    /// Unexpected message or value
+    Protocol(String),
+}
+
+impl BoltError {
+    /// Returns the status code of errors reported by the server
+    pub fn code(&self) -> Option<&str> {
+        match self {
+            BoltError::Server { code, .. } => Some(code),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for BoltError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BoltError::Io(err) => write!(f, "I/O Error: {}", err),
+            BoltError::Server { code, message } => write!(f, "{}: {}", code, message),
+            BoltError::Config(msg) => write!(f, "Invalid configuration: {}", msg),
+            BoltError::Protocol(msg) => write!(f, "Protocol error: {}", msg),
+        }
+    }
+}
+
+impl error::Error for BoltError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            BoltError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for BoltError {
+    fn from(err: io::Error) -> Self {
+        BoltError::Io(err)
+    }
+}
+
+/// Connection parameters
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub host: String,
+    pub port: u16,
+    pub user: String,
+    pub password: String,
+    pub timeout: Duration,
+}
+
+impl Config {
+    /// Parses `bolt://host[:port]`, `neo4j://` URIs connect to the given server directly
+    pub fn new(
+        uri: &str,
+        user: &str,
+        password: &str,
+        timeout: Duration,
+    ) -> Result<Self, BoltError> {
+        let url = Url::parse(uri).map_err(|e| BoltError::Config(format!("{}", e)))?;
+        if url.scheme() != "bolt" && url.scheme() != "neo4j" {
+            return Err(BoltError::Config(format!(
+                "scheme `{}` is not supported, only bolt and neo4j",
+                url.scheme()
+            )));
+        }
+        let host = url
+            .host_str()
+            .ok_or_else(|| BoltError::Config(String::from("missing host")))?;
+        Ok(Config {
+            host: String::from(host),
+            port: url.port().unwrap_or(DEFAULT_PORT),
+            user: String::from(user),
+            password: String::from(password),
+            timeout,
+        })
+    }
+}
+
+pub struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+    // (major, minor)
+    version: (u8, u8),
+    server: String,
+}
+
+impl Connection {
+    /// Negotiates the protocol version and authenticates
+    pub fn connect(config: &Config) -> Result<Self, BoltError> {
+        let addr = (config.host.as_str(), config.port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| BoltError::Config(format!("unable to resolve `{}`", config.host)))?;
+        let stream = TcpStream::connect_timeout(&addr, config.timeout)?;
+        stream.set_read_timeout(Some(config.timeout))?;
+        stream.set_write_timeout(Some(config.timeout))?;
+        stream.set_nodelay(true)?;
+        let mut conn = Connection {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+            version: (0, 0),
+            server: String::new(),
+        };
+
+        conn.writer.write_all(&MAGIC)?;
+        for version in VERSIONS {
+            conn.writer.write_all(&version)?;
+        }
+        conn.writer.flush()?;
+        let mut version = [0u8; 4];
+        conn.reader.read_exact(&mut version)?;
+        if version == [0; 4] {
+            return Err(BoltError::Protocol(String::from(
+                "server doesn't support Bolt 4.4 or 5.x",
+            )));
+        }
+        conn.version = (version[3], version[2]);
+
+        let auth = json!({
+            "scheme": "basic",
+            "principal": config.user,
+            "credentials": config.password
+        });
+        let mut extra = json!({ "user_agent": USER_AGENT });
+        if conn.version >= (5, 3) {
+            extra["bolt_agent"] = json!({ "product": USER_AGENT });
+        }
+        // Since 5.1 the credentials are sent separately
+        let logon = conn.version >= (5, 1);
+        if !logon {
+            extra
+                .as_object_mut()
+                .unwrap()
+                .extend(auth.as_object().unwrap().clone());
+        }
+        conn.send(HELLO, &[extra])?;
+        if logon {
+            conn.send(LOGON, &[auth])?;
+        }
+        conn.writer.flush()?;
+        let metadata = conn.summary()?;
+        if logon {
+            conn.summary()?;
+        }
+        conn.server = String::from(metadata["server"].as_str().unwrap_or_default());
+        Ok(conn)
+    }
+
+    /// Negotiated protocol version as (major, minor)
+    pub fn version(&self) -> (u8, u8) {
+        self.version
+    }
+
+    /// Agent string of the server, e.g. `Neo4j/5.20.0`
+    pub fn server(&self) -> &str {
+        &self.server
+    }
+
+    /// Runs a query and returns all records. Failed queries roll back an open transaction.
+    pub fn run(&mut self, query: &str, params: Value) -> Result<Vec<Vec<Value>>, BoltError> {
+        let params = match params {
+            Value::Null => Value::Object(Map::new()),
+            params => params,
+        };
+        self.send(RUN, &[Value::from(query), params, json!({})])?;
+        self.send(PULL, &[json!({ "n": -1 })])?;
+        self.writer.flush()?;
+        let mut result = self.summary();
+        let mut records = Vec::new();
+        loop {
+            let (tag, mut fields) = self.receive()?;
+            match tag {
+                RECORD => match fields.pop() {
+                    Some(Value::Array(values)) if fields.is_empty() => records.push(values),
+                    _ => return Err(BoltError::Protocol(String::from("invalid record"))),
+                },
+                SUCCESS => break,
+                FAILURE => {
+                    if result.is_ok() {
+                        result = Err(server_error(fields));
+                    }
+                    break;
+                }
+                IGNORED => break,
+                _ => return Err(unexpected(tag)),
+            }
+        }
+        match result {
+            Ok(_) => Ok(records),
+            Err(err) => {
+                self.reset()?;
+                Err(err)
+            }
+        }
+    }
+
+    pub fn begin(&mut self) -> Result<(), BoltError> {
+        self.request(BEGIN, &[json!({})])
+    }
+
+    pub fn commit(&mut self) -> Result<(), BoltError> {
+        self.request(COMMIT, &[])
+    }
+
+    pub fn rollback(&mut self) -> Result<(), BoltError> {
+        self.request(ROLLBACK, &[])
+    }
+
+    /// Sends a message and waits for its summary
+    fn request(&mut self, tag: u8, fields: &[Value]) -> Result<(), BoltError> {
+        self.send(tag, fields)?;
+        self.writer.flush()?;
+        match self.summary() {
+            Ok(_) => Ok(()),
+            Err(err @ BoltError::Server { .. }) => {
+                self.reset()?;
+                Err(err)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Clears the failed state (and an open transaction) after a FAILURE
+    fn reset(&mut self) -> Result<(), BoltError> {
+        self.send(RESET, &[])?;
+        self.writer.flush()?;
+        loop {
+            match self.receive()? {
+                (SUCCESS, _) => return Ok(()),
+                // Replies of messages sent before the RESET
+                (IGNORED, _) | (FAILURE, _) => continue,
+                (tag, _) => return Err(unexpected(tag)),
+            }
+        }
+    }
+
+    /// Reads a summary message, returns the metadata of a SUCCESS
+    fn summary(&mut self) -> Result<Value, BoltError> {
+        let (tag, mut fields) = self.receive()?;
+        match tag {
+            SUCCESS => Ok(fields.pop().unwrap_or_default()),
+            FAILURE => Err(server_error(fields)),
+            IGNORED => Err(BoltError::Protocol(String::from("request was ignored"))),
+            _ => Err(unexpected(tag)),
+        }
+    }
+
+    /// Writes a message in chunks, terminated by an empty chunk
+    fn send(&mut self, tag: u8, fields: &[Value]) -> Result<(), BoltError> {
+        let message = message(tag, fields)?;
+        for chunk in message.chunks(MAX_CHUNK_SIZE) {
+            self.writer.write_all(&(chunk.len() as u16).to_be_bytes())?;
+            self.writer.write_all(chunk)?;
+        }
+        self.writer.write_all(&[0, 0])?;
+        Ok(())
+    }
+
+    /// Reads the next message, skips NOOP chunks
+    fn receive(&mut self) -> Result<(u8, Vec<Value>), BoltError> {
+        let mut message = Vec::new();
+        loop {
+            let size = self.reader.read_u16::<BigEndian>()? as usize;
+            if size == 0 {
+                if message.is_empty() {
+                    continue;
+                }
+                break;
+            }
+            let start = message.len();
+            message.resize(start + size, 0);
+            self.reader.read_exact(&mut message[start..])?;
+        }
+        let mut reader = message.as_slice();
+        let (tag, fields) = match unpack(&mut reader, 0)? {
+            Unpacked::Structure(tag, fields) => (tag, fields),
+            Unpacked::Value(_) => {
+                return Err(BoltError::Protocol(String::from(
+                    "message is not a structure",
+                )))
+            }
+        };
+        if !reader.is_empty() {
+            return Err(BoltError::Protocol(String::from(
+                "trailing bytes after message",
+            )));
+        }
+        Ok((tag, fields))
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if self.version != (0, 0) && self.send(GOODBYE, &[]).is_ok() {
+            let _ = self.writer.flush();
+        }
+    }
+}
+
+fn server_error(mut fields: Vec<Value>) -> BoltError {
+    let metadata = fields.pop().unwrap_or_default();
+    BoltError::Server {
+        code: String::from(metadata["code"].as_str().unwrap_or("unknown")),
+        message: String::from(metadata["message"].as_str().unwrap_or_default()),
+    }
+}
+
+fn unexpected(tag: u8) -> BoltError {
+    BoltError::Protocol(format!("unexpected message 0x{:02x}", tag))
+}
+
+/// Encodes a message as PackStream structure
+fn message(tag: u8, fields: &[Value]) -> Result<Vec<u8>, BoltError> {
+    let mut out = vec![0xb0 | fields.len() as u8, tag];
+    for field in fields {
+        pack(&mut out, field)?;
+    }
+    Ok(out)
+}
+
+/// Encodes a JSON value with the smallest PackStream representation of each item.
+/// Integers must fit into an i64.
+pub fn pack(out: &mut Vec<u8>, value: &Value) -> Result<(), BoltError> {
+    match value {
+        Value::Null => out.push(0xc0),
+        Value::Bool(false) => out.push(0xc2),
+        Value::Bool(true) => out.push(0xc3),
+        Value::Number(n) => {
+            if let Some(n) = n.as_i64() {
+                pack_int(out, n);
+            } else if n.is_f64() {
+                out.push(0xc1);
+                out.extend_from_slice(&n.as_f64().unwrap().to_be_bytes());
+            } else {
+                return Err(BoltError::Protocol(format!("integer {} out of range", n)));
+            }
+        }
+        Value::String(s) => {
+            pack_len(out, s.len(), 0x80, [0xd0, 0xd1, 0xd2]);
+            out.extend_from_slice(s.as_bytes());
+        }
+        Value::Array(items) => {
+            pack_len(out, items.len(), 0x90, [0xd4, 0xd5, 0xd6]);
+            for item in items {
+                pack(out, item)?;
+            }
+        }
+        Value::Object(map) => {
+            pack_len(out, map.len(), 0xa0, [0xd8, 0xd9, 0xda]);
+            for (key, item) in map {
+                pack_len(out, key.len(), 0x80, [0xd0, 0xd1, 0xd2]);
+                out.extend_from_slice(key.as_bytes());
+                pack(out, item)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn pack_int(out: &mut Vec<u8>, n: i64) {
+    if (-16..=127).contains(&n) {
+        out.push(n as u8);
+    } else if n as i8 as i64 == n {
+        out.extend_from_slice(&[0xc8, n as u8]);
+    } else if n as i16 as i64 == n {
+        out.push(0xc9);
+        out.extend_from_slice(&(n as i16).to_be_bytes());
+    } else if n as i32 as i64 == n {
+        out.push(0xca);
+        out.extend_from_slice(&(n as i32).to_be_bytes());
+    } else {
+        out.push(0xcb);
+        out.extend_from_slice(&n.to_be_bytes());
+    }
+}
+
+/// Tiny marker (high nibble) for less than 16 items, otherwise a marker for 8, 16 or 32 bit sizes
+fn pack_len(out: &mut Vec<u8>, len: usize, tiny: u8, markers: [u8; 3]) {
+    if len < 16 {
+        out.push(tiny | len as u8);
+    } else if len <= u8::MAX as usize {
+        out.extend_from_slice(&[markers[0], len as u8]);
+    } else if len <= u16::MAX as usize {
+        out.push(markers[1]);
+        out.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        out.push(markers[2]);
+        out.extend_from_slice(&(len as u32).to_be_bytes());
+    }
+}
+
+enum Unpacked {
+    Value(Value),
+    Structure(u8, Vec<Value>),
+}
+
+/// Decodes the next PackStream value. Nested structures (e.g. nodes) become arrays of their fields,
+/// byte arrays become arrays of numbers.
+pub fn unpack_value(reader: &mut &[u8]) -> Result<Value, BoltError> {
+    Ok(match unpack(reader, 0)? {
+        Unpacked::Value(value) => value,
+        Unpacked::Structure(_, fields) => Value::Array(fields),
+    })
+}
+
+fn unpack(reader: &mut &[u8], depth: usize) -> Result<Unpacked, BoltError> {
+    if depth > MAX_DEPTH {
+        return Err(BoltError::Protocol(String::from("value nested too deep")));
+    }
+    let marker = reader.read_u8()?;
+    let value = match marker {
+        0x00..=0x7f => Value::from(marker as i64),
+        0xf0..=0xff => Value::from(marker as i8 as i64),
+        0xc0 => Value::Null,
+        0xc2 => Value::Bool(false),
+        0xc3 => Value::Bool(true),
+        0xc1 => Number::from_f64(reader.read_f64::<BigEndian>()?)
+            .map(Value::Number)
+            .unwrap_or_default(),
+        0xc8 => Value::from(reader.read_i8()? as i64),
+        0xc9 => Value::from(reader.read_i16::<BigEndian>()? as i64),
+        0xca => Value::from(reader.read_i32::<BigEndian>()? as i64),
+        0xcb => Value::from(reader.read_i64::<BigEndian>()?),
+        0xcc..=0xce => {
+            let len = read_len(reader, marker - 0xcc)?;
+            Value::from(take(reader, len)?.to_vec())
+        }
+        0x80..=0x8f | 0xd0..=0xd2 => {
+            let len = unpack_len(reader, marker, 0x80, 0xd0)?;
+            Value::from(read_string(reader, len)?)
+        }
+        0x90..=0x9f | 0xd4..=0xd6 => {
+            let len = unpack_len(reader, marker, 0x90, 0xd4)?;
+            let mut items = Vec::with_capacity(len.min(1024));
+            for _ in 0..len {
+                items.push(unpack_nested(reader, depth)?);
+            }
+            Value::Array(items)
+        }
+        0xa0..=0xaf | 0xd8..=0xda => {
+            let len = unpack_len(reader, marker, 0xa0, 0xd8)?;
+            let mut map = Map::new();
+            for _ in 0..len {
+                let key = match unpack_nested(reader, depth)? {
+                    Value::String(key) => key,
+                    _ => return Err(BoltError::Protocol(String::from("map key is no string"))),
+                };
+                map.insert(key, unpack_nested(reader, depth)?);
+            }
+            Value::Object(map)
+        }
+        0xb0..=0xbf => {
+            let tag = reader.read_u8()?;
+            let mut fields = Vec::new();
+            for _ in 0..(marker & 0x0f) {
+                fields.push(unpack_nested(reader, depth)?);
+            }
+            return Ok(Unpacked::Structure(tag, fields));
+        }
+        _ => {
+            return Err(BoltError::Protocol(format!(
+                "invalid marker 0x{:02x}",
+                marker
+            )))
+        }
+    };
+    Ok(Unpacked::Value(value))
+}
+
+fn unpack_nested(reader: &mut &[u8], depth: usize) -> Result<Value, BoltError> {
+    Ok(match unpack(reader, depth + 1)? {
+        Unpacked::Value(value) => value,
+        Unpacked::Structure(_, fields) => Value::Array(fields),
+    })
+}
+
+fn unpack_len(reader: &mut &[u8], marker: u8, tiny: u8, sized: u8) -> Result<usize, BoltError> {
+    if marker & 0xf0 == tiny {
+        return Ok((marker & 0x0f) as usize);
+    }
+    read_len(reader, marker - sized)
+}
+
+/// Reads an 8, 16 or 32 bit size
+fn read_len(reader: &mut &[u8], width: u8) -> Result<usize, BoltError> {
+    Ok(match width {
+        0 => reader.read_u8()? as usize,
+        1 => reader.read_u16::<BigEndian>()? as usize,
+        _ => reader.read_u32::<BigEndian>()? as usize,
+    })
+}
+
+fn take<'a>(reader: &mut &'a [u8], len: usize) -> Result<&'a [u8], BoltError> {
+    if reader.len() < len {
+        return Err(BoltError::Protocol(String::from("truncated value")));
+    }
+    let (bytes, rest) = reader.split_at(len);
+    *reader = rest;
+    Ok(bytes)
+}
+
+fn read_string(reader: &mut &[u8], len: usize) -> Result<String, BoltError> {
+    String::from_utf8(take(reader, len)?.to_vec())
+        .map_err(|_| BoltError::Protocol(String::from("invalid UTF-8 string")))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::thread::{self, JoinHandle};
+
+    use super::*;
+
+    fn packed(value: &Value) -> Vec<u8> {
+        let mut out = Vec::new();
+        pack(&mut out, value).unwrap();
+        out
+    }
+
+    #[test]
+    fn test_config() {
+        let timeout = Duration::from_secs(1);
+        let config = Config::new("bolt://db.example:7688", "neo4j", "pw", timeout).unwrap();
+        assert_eq!("db.example", config.host);
+        assert_eq!(7688, config.port);
+        let config = Config::new("neo4j://localhost", "neo4j", "", timeout).unwrap();
+        assert_eq!(DEFAULT_PORT, config.port);
+        assert!(Config::new("http://localhost:7474", "neo4j", "", timeout).is_err());
+        assert!(Config::new("localhost", "neo4j", "", timeout).is_err());
+    }
+
+    #[test]
+    fn test_pack_scalars() {
+        let cases = [
+            (json!(null), vec![0xc0]),
+            (json!(true), vec![0xc3]),
+            (json!(false), vec![0xc2]),
+            (json!(1), vec![0x01]),
+            (json!(-16), vec![0xf0]),
+            (json!(-17), vec![0xc8, 0xef]),
+            (json!(127), vec![0x7f]),
+            (json!(128), vec![0xc9, 0x00, 0x80]),
+            (json!(-129), vec![0xc9, 0xff, 0x7f]),
+            (json!(32768), vec![0xca, 0x00, 0x00, 0x80, 0x00]),
+            (
+                json!(5000000000u64),
+                vec![0xcb, 0x00, 0x00, 0x00, 0x01, 0x2a, 0x05, 0xf2, 0x00],
+            ),
+            (
+                json!(1.5),
+                vec![0xc1, 0x3f, 0xf8, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+            ),
+            (json!("a"), vec![0x81, 0x61]),
+        ];
+        for (value, bytes) in cases {
+            assert_eq!(bytes, packed(&value), "{}", value);
+            assert_eq!(value, unpack_value(&mut bytes.as_slice()).unwrap());
+        }
+        let mut out = Vec::new();
+        assert!(pack(&mut out, &json!(u64::MAX)).is_err());
+    }
+
+    #[test]
+    fn test_pack_containers() {
+        let long_string = "x".repeat(300);
+        let value = json!({
+            "list": [1, "two", [3]],
+            "map": {},
+            "long": long_string,
+            "many": (0..20).collect::<Vec<u32>>()
+        });
+        let bytes = packed(&value);
+        assert_eq!(0xa4, bytes[0]);
+        assert_eq!(value, unpack_value(&mut bytes.as_slice()).unwrap());
+        assert_eq!(vec![0x93, 0x01, 0x02, 0x03], packed(&json!([1, 2, 3])));
+        assert_eq!(
+            vec![0xd0, 0x10],
+            packed(&json!("abcdefghijklmnop"))[..2].to_vec()
+        );
+        assert_eq!(
+            vec![0xd1, 0x01, 0x2c],
+            packed(&json!(long_string))[..3].to_vec()
+        );
+
+        // Node structure, bytes
+        let node = [0xb3, 0x4e, 0x01, 0x91, 0x82, 0x54, 0x78, 0xa0];
+        assert_eq!(
+            json!([1, ["Tx"], {}]),
+            unpack_value(&mut &node[..]).unwrap()
+        );
+        assert_eq!(
+            json!([1, 2]),
+            unpack_value(&mut &[0xcc, 0x02, 0x01, 0x02][..]).unwrap()
+        );
+
+        // Truncated and invalid values
+        assert!(unpack_value(&mut &[0x82, 0x61][..]).is_err());
+        assert!(unpack_value(&mut &[0xa1, 0x01, 0x01][..]).is_err());
+        assert!(unpack_value(&mut &[0xc7][..]).is_err());
+        let nested = vec![0x91; MAX_DEPTH + 2];
+        assert!(unpack_value(&mut nested.as_slice()).is_err());
+    }
+
+    /// Tag and fields
+    type Message = (u8, Vec<Value>);
+
+    /// Reads a chunked message from the client
+    fn read_message(reader: &mut impl Read) -> Option<Message> {
+        let mut message = Vec::new();
+        loop {
+            let size = reader.read_u16::<BigEndian>().ok()? as usize;
+            if size == 0 {
+                break;
+            }
+            let start = message.len();
+            message.resize(start + size, 0);
+            reader.read_exact(&mut message[start..]).ok()?;
+        }
+        match unpack(&mut message.as_slice(), 0).unwrap() {
+            Unpacked::Structure(tag, fields) => Some((tag, fields)),
+            Unpacked::Value(_) => panic!("message is not a structure"),
+        }
+    }
+
+    fn write_message(writer: &mut impl Write, tag: u8, fields: &[Value]) {
+        let message = message(tag, fields).unwrap();
+        writer
+            .write_all(&(message.len() as u16).to_be_bytes())
+            .unwrap();
+        writer.write_all(&message).unwrap();
+        writer.write_all(&[0, 0]).unwrap();
+    }
+
+    /// Accepts one connection with the given version. Queries containing `FAIL` fail,
+    /// the others return two records. Returns the address and the server thread,
+    /// which returns the received messages once the client disconnected.
+    fn fake_server(version: [u8; 4]) -> (String, JoinHandle<Vec<Message>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let mut messages = Vec::new();
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut handshake = [0u8; 20];
+            stream.read_exact(&mut handshake).unwrap();
+            assert_eq!(MAGIC, handshake[..4]);
+            stream.write_all(&version).unwrap();
+            // A NOOP chunk is skipped by the client
+            stream.write_all(&[0, 0]).unwrap();
+            let mut failed = false;
+            while let Some((tag, fields)) = read_message(&mut stream) {
+                messages.push((tag, fields.clone()));
+                match tag {
+                    HELLO => {
+                        write_message(&mut stream, SUCCESS, &[json!({"server": "Neo4j/5.20.0"})])
+                    }
+                    RUN if fields[0].as_str().unwrap().contains("FAIL") => {
+                        failed = true;
+                        let failure = json!({"code": "Neo.ClientError.Statement.SyntaxError", "message": "Invalid input"});
+                        write_message(&mut stream, FAILURE, &[failure]);
+                    }
+                    PULL if failed => write_message(&mut stream, IGNORED, &[]),
+                    PULL => {
+                        write_message(&mut stream, RECORD, &[json!([1, "a"])]);
+                        write_message(&mut stream, RECORD, &[json!([2, "b"])]);
+                        write_message(&mut stream, SUCCESS, &[json!({})]);
+                    }
+                    RESET => {
+                        failed = false;
+                        write_message(&mut stream, SUCCESS, &[json!({})]);
+                    }
+                    GOODBYE => break,
+                    _ => write_message(&mut stream, SUCCESS, &[json!({})]),
+                }
+            }
+            messages
+        });
+        (addr, server)
+    }
+
+    fn connect(addr: &str) -> Result<Connection, BoltError> {
+        let uri = format!("bolt://{}", addr);
+        let config = Config::new(&uri, "neo4j", "secret", Duration::from_secs(5)).unwrap();
+        Connection::connect(&config)
+    }
+
+    #[test]
+    fn test_connection() {
+        let (addr, server) = fake_server([0, 0, 4, 5]);
+        let mut conn = connect(&addr).unwrap();
+        assert_eq!((5, 4), conn.version());
+        assert_eq!("Neo4j/5.20.0", conn.server());
+
+        let records = conn
+            .run("UNWIND $rows AS row RETURN row", json!({"rows": [1, 2]}))
+            .unwrap();
+        assert_eq!(
+            vec![vec![json!(1), json!("a")], vec![json!(2), json!("b")]],
+            records
+        );
+        conn.begin().unwrap();
+        let err = conn.run("FAIL", Value::Null).unwrap_err();
+        assert_eq!(Some("Neo.ClientError.Statement.SyntaxError"), err.code());
+        // Usable again after the RESET
+        assert_eq!(2, conn.run("RETURN 1", Value::Null).unwrap().len());
+        drop(conn);
+
+        let received = server.join().unwrap();
+        let tags: Vec<u8> = received.iter().map(|(tag, _)| *tag).collect();
+        assert_eq!(
+            vec![HELLO, LOGON, RUN, PULL, BEGIN, RUN, PULL, RESET, RUN, PULL, GOODBYE],
+            tags
+        );
+        let hello = &received[0].1[0];
+        assert_eq!(USER_AGENT, hello["user_agent"]);
+        assert_eq!(Value::Null, hello["credentials"]);
+        assert_eq!(
+            json!({"scheme": "basic", "principal": "neo4j", "credentials": "secret"}),
+            received[1].1[0]
+        );
+        assert_eq!(json!({"rows": [1, 2]}), received[2].1[1]);
+        assert_eq!(json!({}), received[5].1[1]);
+        assert_eq!(json!({"n": -1}), received[3].1[0]);
+    }
+
+    #[test]
+    fn test_connection_4_4() {
+        let (addr, server) = fake_server([0, 0, 4, 4]);
+        let conn = connect(&addr).unwrap();
+        assert_eq!((4, 4), conn.version());
+        drop(conn);
+        let received = server.join().unwrap();
+        // Credentials are part of the HELLO
+        assert_eq!(HELLO, received[0].0);
+        assert_eq!("secret", received[0].1[0]["credentials"]);
+        assert_eq!(GOODBYE, received[1].0);
+    }
+
+    #[test]
+    fn test_unsupported_version() {
+        let (addr, _) = fake_server([0; 4]);
+        assert!(matches!(connect(&addr), Err(BoltError::Protocol(_))));
+    }
+
+    #[test]
+    fn test_chunks() {
+        let (addr, server) = fake_server([0, 0, 4, 5]);
+        let mut conn = connect(&addr).unwrap();
+        // Spans several chunks
+        let large = "x".repeat(3 * MAX_CHUNK_SIZE);
+        conn.run("RETURN $large", json!({ "large": large }))
+            .unwrap();
+        drop(conn);
+        assert_eq!(large, server.join().unwrap()[2].1[1]["large"]);
+    }
+}
@@ -0,0 +1,248 @@
+//! CashAddr encoding of Bitcoin Cash addresses, e.g. `bitcoincash:qpm2qsznhks23z7629mms6s4cwef74vcwvy22gdx6a`
+//! See https://github.com/bitcoincashorg/bitcoincash.org/blob/master/spec/cashaddr.md
+
+use std::error;
+use std::fmt;
+
+const CHARSET: &[u8; 32] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+/// Address types of the version byte
+pub const P2PKH: u8 = 0;
+pub const P2SH: u8 = 1;
+
+/// Hash lengths in bytes, indexed by the size bits of the version byte
+const HASH_SIZES: [usize; 8] = [20, 24, 28, 32, 40, 48, 56, 64];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CashAddrError {
+    /// Mixed case, a missing prefix, invalid characters or a bad length
+    InvalidFormat,
+    InvalidChecksum,
+    /// The hash length doesn't match the version byte
+    InvalidPayload,
+}
+
+impl fmt::Display for CashAddrError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CashAddrError::InvalidFormat => write!(f, "Invalid CashAddr format"),
+            CashAddrError::InvalidChecksum => write!(f, "Invalid CashAddr checksum"),
+            CashAddrError::InvalidPayload => write!(f, "Invalid CashAddr payload"),
+        }
+    }
+}
+
+impl error::Error for CashAddrError {}
+
+/// A decoded CashAddr address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CashAddress {
+    pub prefix: String,
+    /// `P2PKH`, `P2SH` or one of the reserved types
+    pub kind: u8,
+    pub hash: Vec<u8>,
+}
+
+/// Encodes a hash of one of the lengths in `HASH_SIZES`, panics for other lengths
+pub fn encode(prefix: &str, kind: u8, hash: &[u8]) -> String {
+    let size = HASH_SIZES
+        .iter()
+        .position(|s| *s == hash.len())
+        .expect("Invalid CashAddr hash length");
+    let mut payload = vec![(kind << 3) | size as u8];
+    payload.extend_from_slice(hash);
+
+    let mut values = Vec::with_capacity((payload.len() * 8).div_ceil(5) + 8);
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    for b in payload {
+        acc = (acc << 8) | b as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            values.push(((acc >> bits) & 31) as u8);
+        }
+    }
+    if bits > 0 {
+        values.push(((acc << (5 - bits)) & 31) as u8);
+    }
+
+    let mut checked = expand_prefix(prefix);
+    checked.extend_from_slice(&values);
+    checked.extend_from_slice(&[0; 8]);
+    let checksum = polymod(&checked);
+    values.extend((0..8).map(|i| ((checksum >> (5 * (7 - i))) & 31) as u8));
+
+    let mut address = String::with_capacity(prefix.len() + 1 + values.len());
+    address.push_str(prefix);
+    address.push(':');
+    address.extend(values.iter().map(|v| CHARSET[*v as usize] as char));
+    address
+}
+
+/// Decodes a CashAddr address, `default_prefix` is used if the address has none.
+/// The prefix is not checked, it's up to the caller to compare it.
+pub fn decode(address: &str, default_prefix: &str) -> Result<CashAddress, CashAddrError> {
+    if address.bytes().any(|c| c.is_ascii_lowercase())
+        && address.bytes().any(|c| c.is_ascii_uppercase())
+    {
+        return Err(CashAddrError::InvalidFormat);
+    }
+    let address = address.to_ascii_lowercase();
+    let (prefix, data) = match address.rfind(':') {
+        Some(sep) => (&address[..sep], &address.as_bytes()[sep + 1..]),
+        None => (default_prefix, address.as_bytes()),
+    };
+    // At least the version byte and the 8 character checksum
+    if prefix.is_empty() || data.len() < 10 {
+        return Err(CashAddrError::InvalidFormat);
+    }
+    let values = data
+        .iter()
+        .map(|c| CHARSET.iter().position(|x| x == c).map(|v| v as u8))
+        .collect::<Option<Vec<u8>>>()
+        .ok_or(CashAddrError::InvalidFormat)?;
+
+    let mut checked = expand_prefix(prefix);
+    checked.extend_from_slice(&values);
+    if polymod(&checked) != 0 {
+        return Err(CashAddrError::InvalidChecksum);
+    }
+
+    let mut payload = Vec::with_capacity(values.len() * 5 / 8);
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    for v in &values[..values.len() - 8] {
+        acc = (acc << 5) | *v as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            payload.push((acc >> bits) as u8);
+        }
+    }
+    if bits >= 5 || (acc << (8 - bits)) as u8 != 0 {
+        return Err(CashAddrError::InvalidPayload);
+    }
+    let version = payload[0];
+    // The most significant bit is reserved
+    if version & 0x80 != 0 || HASH_SIZES[(version & 7) as usize] != payload.len() - 1 {
+        return Err(CashAddrError::InvalidPayload);
+    }
+    Ok(CashAddress {
+        prefix: String::from(prefix),
+        kind: version >> 3,
+        hash: payload[1..].to_vec(),
+    })
+}
+
+/// 40 bit BCH code checksum, zero for valid addresses
+fn polymod(values: &[u8]) -> u64 {
+    const GENERATOR: [u64; 5] = [
+        0x98f2bc8e61,
+        0x79b76d99e2,
+        0xf33e5fb3c4,
+        0xae2eabe2a8,
+        0x1e4f43e470,
+    ];
+    let mut chk: u64 = 1;
+    for v in values {
+        let top = chk >> 35;
+        chk = ((chk & 0x07ffffffff) << 5) ^ *v as u64;
+        for (i, g) in GENERATOR.iter().enumerate() {
+            if (top >> i) & 1 == 1 {
+                chk ^= g;
+            }
+        }
+    }
+    chk ^ 1
+}
+
+/// The lower 5 bits of each prefix character followed by a zero for the separator
+fn expand_prefix(prefix: &str) -> Vec<u8> {
+    let mut expanded: Vec<u8> = prefix.bytes().map(|c| c & 31).collect();
+    expanded.push(0);
+    expanded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::utils;
+
+    #[test]
+    fn test_cashaddr() {
+        // Vectors from the CashAddr specification
+        let hash = "f5bf48b397dae70be82b3cca4793f8eb2b6cdac9";
+        let valid = [
+            (
+                "bitcoincash:qr6m7j9njldwwzlg9v7v53unlr4jkmx6eylep8ekg2",
+                P2PKH,
+                hash,
+            ),
+            (
+                "bchtest:pr6m7j9njldwwzlg9v7v53unlr4jkmx6eyvwc0uz5t",
+                P2SH,
+                hash,
+            ),
+            (
+                "pref:pr6m7j9njldwwzlg9v7v53unlr4jkmx6ey65nvtks5",
+                P2SH,
+                hash,
+            ),
+            (
+                "prefix:0r6m7j9njldwwzlg9v7v53unlr4jkmx6ey3qnjwsrf",
+                15,
+                hash,
+            ),
+            (
+                "bitcoincash:q9lekxnlk6xkp3fkct7c4649828nesp94z7p7j5mlrznzu0a",
+                P2PKH,
+                "7f9b1a7fb68d60c536c2fd8aeaa53a8f3cc025a8bc1f4a9b",
+            ),
+        ];
+        for (address, kind, hash) in valid.iter() {
+            let decoded = decode(address, "bitcoincash").unwrap();
+            assert_eq!(*kind, decoded.kind);
+            assert_eq!(*hash, utils::arr_to_hex(&decoded.hash));
+            assert_eq!(*address, encode(&decoded.prefix, *kind, &decoded.hash));
+        }
+
+        // The prefix is optional and case doesn't matter
+        let decoded = decode("QR6M7J9NJLDWWZLG9V7V53UNLR4JKMX6EYLEP8EKG2", "bitcoincash").unwrap();
+        assert_eq!("bitcoincash", decoded.prefix);
+        assert_eq!(hash, utils::arr_to_hex(&decoded.hash));
+
+        let invalid = [
+            (
+                "bitcoincash:qr6m7j9njldwwzlg9v7v53unlr4jkmx6eylep8ekG2",
+                CashAddrError::InvalidFormat,
+            ),
+            (
+                "bitcoincash:qr6m7j9njldwwzlg9v7v53unlr4jkmx6eylep8ekgb",
+                CashAddrError::InvalidFormat,
+            ),
+            (
+                "bitcoincash:qr6m7j9njldwwzlg9v7v53unlr4jkmx6eylep8ekg3",
+                CashAddrError::InvalidChecksum,
+            ),
+            // Valid for another prefix
+            (
+                "bchtest:qr6m7j9njldwwzlg9v7v53unlr4jkmx6eylep8ekg2",
+                CashAddrError::InvalidChecksum,
+            ),
+            // A 24 byte hash with the size bits of 20 bytes
+            (
+                "bitcoincash:qqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqljyuvg06",
+                CashAddrError::InvalidPayload,
+            ),
+        ];
+        for (address, err) in invalid.iter() {
+            assert_eq!(
+                Err(err.clone()),
+                decode(address, "bitcoincash"),
+                "{}",
+                address
+            );
+        }
+    }
+}
@@ -0,0 +1,236 @@
+//! Minimal gzip encoder on top of the system zlib
+
+use std::io::{self, Write};
+use std::mem;
+use std::os::raw::{c_char, c_int, c_uint, c_ulong, c_void};
+
+#[allow(non_camel_case_types)]
+mod ffi {
+    use super::*;
+
+    pub const Z_OK: c_int = 0;
+    pub const Z_STREAM_END: c_int = 1;
+    pub const Z_BUF_ERROR: c_int = -5;
+    pub const Z_NO_FLUSH: c_int = 0;
+    pub const Z_FINISH: c_int = 4;
+    pub const Z_DEFLATED: c_int = 8;
+    pub const Z_DEFAULT_STRATEGY: c_int = 0;
+    // 15 bits window size + 16 to write a gzip header and trailer instead of a zlib wrapper
+    pub const GZIP_WINDOW_BITS: c_int = 15 + 16;
+    pub const DEFAULT_MEM_LEVEL: c_int = 8;
+
+    #[repr(C)]
+    pub struct z_stream {
+        pub next_in: *const u8,
+        pub avail_in: c_uint,
+        pub total_in: c_ulong,
+        pub next_out: *mut u8,
+        pub avail_out: c_uint,
+        pub total_out: c_ulong,
+        pub msg: *const c_char,
+        pub state: *mut c_void,
+        pub zalloc: *const c_void,
+        pub zfree: *const c_void,
+        pub opaque: *mut c_void,
+        pub data_type: c_int,
+        pub adler: c_ulong,
+        pub reserved: c_ulong,
+    }
+
+    #[link(name = "z")]
+    extern "C" {
+        pub fn zlibVersion() -> *const c_char;
+        pub fn deflateInit2_(
+            strm: *mut z_stream,
+            level: c_int,
+            method: c_int,
+            window_bits: c_int,
+            mem_level: c_int,
+            strategy: c_int,
+            version: *const c_char,
+            stream_size: c_int,
+        ) -> c_int;
+        pub fn deflate(strm: *mut z_stream, flush: c_int) -> c_int;
+        pub fn deflateEnd(strm: *mut z_stream) -> c_int;
+        #[cfg(test)]
+        pub fn inflateInit2_(
+            strm: *mut z_stream,
+            window_bits: c_int,
+            version: *const c_char,
+            stream_size: c_int,
+        ) -> c_int;
+        #[cfg(test)]
+        pub fn inflate(strm: *mut z_stream, flush: c_int) -> c_int;
+        #[cfg(test)]
+        pub fn inflateEnd(strm: *mut z_stream) -> c_int;
+    }
+}
+
+// Size of the buffer for compressed data, which is written to the inner writer when full
+const OUT_BUFFER_SIZE: usize = 256 * 1024;
+
+/// Compresses everything written to it into the gzip format.
+/// `finish()` must be called to write the trailer, otherwise the output is truncated.
+pub struct GzWriter<W: Write> {
+    inner: Option<W>,
+    // zlib keeps a pointer to the stream, so it must not move
+    stream: Box<ffi::z_stream>,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> GzWriter<W> {
+    /// Creates an encoder with compression level 0 (none) to 9 (best)
+    pub fn new(inner: W, level: u32) -> io::Result<GzWriter<W>> {
+        // Safe to zero: the struct only contains integers and pointers, null pointers select
+        // the default allocator
+        let mut stream: Box<ffi::z_stream> = Box::new(unsafe { mem::zeroed() });
+        let code = unsafe {
+            ffi::deflateInit2_(
+                stream.as_mut(),
+                level.min(9) as c_int,
+                ffi::Z_DEFLATED,
+                ffi::GZIP_WINDOW_BITS,
+                ffi::DEFAULT_MEM_LEVEL,
+                ffi::Z_DEFAULT_STRATEGY,
+                ffi::zlibVersion(),
+                mem::size_of::<ffi::z_stream>() as c_int,
+            )
+        };
+        if code != ffi::Z_OK {
+            return Err(zlib_error(code, &stream));
+        }
+        Ok(GzWriter {
+            inner: Some(inner),
+            stream,
+            buffer: vec![0u8; OUT_BUFFER_SIZE],
+        })
+    }
+
+    /// Writes the remaining compressed data and the gzip trailer, returns the inner writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.deflate(&[], ffi::Z_FINISH)?;
+        let mut inner = self.inner.take().unwrap();
+        inner.flush()?;
+        Ok(inner)
+    }
+
+    /// Feeds `data` to zlib and writes all output it produces
+    fn deflate(&mut self, data: &[u8], flush: c_int) -> io::Result<()> {
+        self.stream.next_in = data.as_ptr();
+        self.stream.avail_in = data.len() as c_uint;
+        loop {
+            self.stream.next_out = self.buffer.as_mut_ptr();
+            self.stream.avail_out = self.buffer.len() as c_uint;
+            let code = unsafe { ffi::deflate(self.stream.as_mut(), flush) };
+            if code != ffi::Z_OK && code != ffi::Z_STREAM_END && code != ffi::Z_BUF_ERROR {
+                return Err(zlib_error(code, &self.stream));
+            }
+            let produced = self.buffer.len() - self.stream.avail_out as usize;
+            if let Some(inner) = self.inner.as_mut() {
+                inner.write_all(&self.buffer[..produced])?;
+            }
+            let done = match flush {
+                ffi::Z_FINISH => code == ffi::Z_STREAM_END,
+                // A partially filled output buffer means zlib consumed all input
+                _ => self.stream.avail_in == 0 && self.stream.avail_out != 0,
+            };
+            if done {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl<W: Write> Write for GzWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Larger chunks would overflow avail_in
+        let len = buf.len().min(c_uint::MAX as usize);
+        self.deflate(&buf[..len], ffi::Z_NO_FLUSH)?;
+        Ok(len)
+    }
+
+    /// Only flushes the inner writer, data buffered by zlib stays there until `finish()`
+    fn flush(&mut self) -> io::Result<()> {
+        match self.inner.as_mut() {
+            Some(inner) => inner.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<W: Write> Drop for GzWriter<W> {
+    fn drop(&mut self) {
+        unsafe { ffi::deflateEnd(self.stream.as_mut()) };
+    }
+}
+
+fn zlib_error(code: c_int, stream: &ffi::z_stream) -> io::Error {
+    let msg = if stream.msg.is_null() {
+        String::from("unknown error")
+    } else {
+        unsafe { std::ffi::CStr::from_ptr(stream.msg) }
+            .to_string_lossy()
+            .into_owned()
+    };
+    io::Error::other(format!("zlib error {}: {}", code, msg))
+}
+
+/// Decompresses gzip data, panics on invalid or truncated input
+#[cfg(test)]
+pub fn gunzip(data: &[u8]) -> Vec<u8> {
+    let mut stream: Box<ffi::z_stream> = Box::new(unsafe { mem::zeroed() });
+    let code = unsafe {
+        ffi::inflateInit2_(
+            stream.as_mut(),
+            ffi::GZIP_WINDOW_BITS,
+            ffi::zlibVersion(),
+            mem::size_of::<ffi::z_stream>() as c_int,
+        )
+    };
+    assert_eq!(ffi::Z_OK, code);
+    stream.next_in = data.as_ptr();
+    stream.avail_in = data.len() as c_uint;
+    let mut output = Vec::new();
+    let mut buffer = vec![0u8; 4096];
+    loop {
+        stream.next_out = buffer.as_mut_ptr();
+        stream.avail_out = buffer.len() as c_uint;
+        let code = unsafe { ffi::inflate(stream.as_mut(), ffi::Z_NO_FLUSH) };
+        let produced = buffer.len() - stream.avail_out as usize;
+        output.extend_from_slice(&buffer[..produced]);
+        match code {
+            ffi::Z_STREAM_END => break,
+            ffi::Z_OK => continue,
+            _ => panic!("inflate failed with {}", code),
+        }
+    }
+    unsafe { ffi::inflateEnd(stream.as_mut()) };
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let data: Vec<u8> = (0..1_000_000u32)
+            .flat_map(|i| (i % 251).to_le_bytes())
+            .collect();
+        let mut writer = GzWriter::new(Vec::new(), 6).unwrap();
+        for chunk in data.chunks(10_000) {
+            writer.write_all(chunk).unwrap();
+        }
+        let compressed = writer.finish().unwrap();
+        // gzip magic
+        assert_eq!(&[0x1f, 0x8b], &compressed[..2]);
+        assert!(compressed.len() < data.len() / 10);
+        assert_eq!(data, gunzip(&compressed));
+    }
+
+    #[test]
+    fn test_empty() {
+        let compressed = GzWriter::new(Vec::new(), 9).unwrap().finish().unwrap();
+        assert!(gunzip(&compressed).is_empty());
+    }
+}
@@ -0,0 +1,332 @@
+//! Minimal HTTP/1.1 client: one request per connection, responses with `Content-Length`,
+//! chunked transfer encoding or a body until the connection is closed.
+//! HTTPS is not supported.
+
+use std::error;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use url::Url;
+
+// Upper bound for the status line and each header line
+const MAX_LINE_LENGTH: usize = 64 * 1024;
+
+#[derive(Debug)]
+pub enum HttpError {
+    Io(io::Error),
+    /// Unsupported or incomplete URL
+    Url(String),
+    /// Malformed response
+    Protocol(String),
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HttpError::Io(err) => write!(f, "I/O Error: {}", err),
+            HttpError::Url(msg) => write!(f, "Invalid URL: {}", msg),
+            HttpError::Protocol(msg) => write!(f, "Protocol error: {}", msg),
+        }
+    }
+}
+
+impl error::Error for HttpError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            HttpError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for HttpError {
+    fn from(err: io::Error) -> Self {
+        HttpError::Io(err)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    /// Header names are lowercase
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+}
+
+/// Checks that the URL can be used with `post()`
+pub fn validate_url(url: &Url) -> Result<(), HttpError> {
+    if url.scheme() != "http" {
+        return Err(HttpError::Url(format!(
+            "scheme `{}` is not supported, only http",
+            url.scheme()
+        )));
+    }
+    if url.host_str().is_none() {
+        return Err(HttpError::Url(String::from("missing host")));
+    }
+    Ok(())
+}
+
+/// Sends a POST request and reads the whole response
+pub fn post(
+    url: &Url,
+    headers: &[(&str, &str)],
+    body: &[u8],
+    timeout: Duration,
+) -> Result<Response, HttpError> {
+    validate_url(url)?;
+    let host = url.host_str().unwrap();
+    let port = url.port_or_known_default().unwrap_or(80);
+    let addr = (host, port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| HttpError::Url(format!("unable to resolve `{}`", host)))?;
+    let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    let mut target = String::from(url.path());
+    if let Some(query) = url.query() {
+        target.push('?');
+        target.push_str(query);
+    }
+    let mut head = format!(
+        "POST {} HTTP/1.1\r\nHost: {}:{}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        target,
+        host,
+        port,
+        body.len()
+    );
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(body)?;
+    stream.flush()?;
+    read_response(&mut BufReader::new(stream))
+}
+
+fn read_response<R: BufRead>(reader: &mut R) -> Result<Response, HttpError> {
+    let status_line = read_line(reader)?;
+    // e.g. `HTTP/1.1 200 OK`
+    let status = status_line
+        .split(' ')
+        .nth(1)
+        .and_then(|status| status.parse::<u16>().ok())
+        .filter(|_| status_line.starts_with("HTTP/1."))
+        .ok_or_else(|| HttpError::Protocol(format!("invalid status line `{}`", status_line)))?;
+
+    let mut headers = Vec::new();
+    loop {
+        let line = read_line(reader)?;
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| HttpError::Protocol(format!("invalid header `{}`", line)))?;
+        headers.push((name.trim().to_ascii_lowercase(), String::from(value.trim())));
+    }
+    let mut response = Response {
+        status,
+        headers,
+        body: Vec::new(),
+    };
+
+    if response
+        .header("transfer-encoding")
+        .is_some_and(|encoding| encoding.eq_ignore_ascii_case("chunked"))
+    {
+        response.body = read_chunked(reader)?;
+    } else if let Some(length) = response.header("content-length") {
+        let length = length
+            .parse::<usize>()
+            .map_err(|_| HttpError::Protocol(format!("invalid content length `{}`", length)))?;
+        response.body = vec![0u8; length];
+        reader.read_exact(&mut response.body)?;
+    } else {
+        reader.read_to_end(&mut response.body)?;
+    }
+    Ok(response)
+}
+
+fn read_chunked<R: BufRead>(reader: &mut R) -> Result<Vec<u8>, HttpError> {
+    let mut body = Vec::new();
+    loop {
+        let line = read_line(reader)?;
+        // Chunk extensions after `;` are ignored
+        let size = line.split(';').next().unwrap_or("").trim();
+        let size = usize::from_str_radix(size, 16)
+            .map_err(|_| HttpError::Protocol(format!("invalid chunk size `{}`", line)))?;
+        if size == 0 {
+            // Trailers
+            while !read_line(reader)?.is_empty() {}
+            return Ok(body);
+        }
+        let start = body.len();
+        body.resize(start + size, 0);
+        reader.read_exact(&mut body[start..])?;
+        if !read_line(reader)?.is_empty() {
+            return Err(HttpError::Protocol(String::from(
+                "missing CRLF after chunk",
+            )));
+        }
+    }
+}
+
+/// Reads a line without the trailing CRLF
+fn read_line<R: BufRead>(reader: &mut R) -> Result<String, HttpError> {
+    let mut line = Vec::new();
+    reader
+        .take(MAX_LINE_LENGTH as u64)
+        .read_until(b'\n', &mut line)?;
+    if line.last() != Some(&b'\n') {
+        return Err(HttpError::Protocol(String::from(
+            "connection closed or line too long",
+        )));
+    }
+    while let Some(b'\n') | Some(b'\r') = line.last() {
+        line.pop();
+    }
+    Ok(String::from_utf8_lossy(&line).into_owned())
+}
+
+/// Request received by `test_server()`
+#[cfg(test)]
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub target: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+/// Answers each request with the next of `responses` (status and body, sent chunked),
+/// 200 once they are used up. Returns the base URL and the received requests.
+#[cfg(test)]
+pub fn test_server(
+    responses: Vec<(u16, &'static str)>,
+) -> (Url, std::sync::Arc<std::sync::Mutex<Vec<Request>>>) {
+    use std::collections::VecDeque;
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let received = requests.clone();
+    thread::spawn(move || {
+        let mut responses = VecDeque::from(responses);
+        for stream in listener.incoming() {
+            let mut reader = BufReader::new(stream.unwrap());
+            let request_line = read_line(&mut reader).unwrap();
+            let target = String::from(request_line.split(' ').nth(1).unwrap());
+            let mut headers = Vec::new();
+            loop {
+                let line = read_line(&mut reader).unwrap();
+                if line.is_empty() {
+                    break;
+                }
+                let (name, value) = line.split_once(':').unwrap();
+                headers.push((name.to_ascii_lowercase(), String::from(value.trim())));
+            }
+            let length: usize = headers
+                .iter()
+                .find(|(name, _)| name == "content-length")
+                .map(|(_, value)| value.parse().unwrap())
+                .unwrap_or(0);
+            let mut body = vec![0u8; length];
+            reader.read_exact(&mut body).unwrap();
+            received.lock().unwrap().push(Request {
+                target,
+                headers,
+                body,
+            });
+
+            let (status, body) = responses.pop_front().unwrap_or((200, ""));
+            let mut stream = reader.into_inner();
+            let mut response = format!(
+                "HTTP/1.1 {} Status\r\nTransfer-Encoding: chunked\r\n\r\n",
+                status
+            );
+            if !body.is_empty() {
+                response.push_str(&format!("{:x}\r\n{}\r\n", body.len(), body));
+            }
+            response.push_str("0\r\n\r\n");
+            stream.write_all(response.as_bytes()).unwrap();
+        }
+    });
+    (url, requests)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_response() {
+        let data = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nX-Test: a: b\r\n\r\nhello";
+        let response = read_response(&mut &data[..]).unwrap();
+        assert_eq!(200, response.status);
+        assert_eq!(Some("a: b"), response.header("X-Test"));
+        assert_eq!(b"hello".to_vec(), response.body);
+
+        let data = b"HTTP/1.1 500 Internal Server Error\r\ntransfer-encoding: chunked\r\n\r\n\
+                     3;ext=1\r\nabc\r\n2\r\nde\r\n0\r\nTrailer: x\r\n\r\n";
+        let response = read_response(&mut &data[..]).unwrap();
+        assert_eq!(500, response.status);
+        assert!(!response.is_success());
+        assert_eq!(b"abcde".to_vec(), response.body);
+
+        let data = b"HTTP/1.0 204 No Content\r\n\r\n";
+        assert!(read_response(&mut &data[..]).unwrap().body.is_empty());
+        assert!(read_response(&mut &b"SSH-2.0\r\n\r\n"[..]).is_err());
+        assert!(
+            read_response(&mut &b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nab"[..]).is_err()
+        );
+    }
+
+    #[test]
+    fn test_post() {
+        let (url, requests) = test_server(vec![(404, "not found")]);
+        let url = url.join("path?query=a%20b").unwrap();
+        let response = post(
+            &url,
+            &[("X-Key", "secret")],
+            b"body",
+            Duration::from_secs(5),
+        )
+        .unwrap();
+        assert_eq!(404, response.status);
+        assert_eq!(b"not found".to_vec(), response.body);
+
+        let requests = requests.lock().unwrap();
+        assert_eq!("/path?query=a%20b", requests[0].target);
+        assert!(requests[0]
+            .headers
+            .contains(&(String::from("x-key"), String::from("secret"))));
+        assert_eq!(b"body".to_vec(), requests[0].body);
+    }
+
+    #[test]
+    fn test_validate_url() {
+        assert!(validate_url(&Url::parse("http://localhost:8123").unwrap()).is_ok());
+        assert!(validate_url(&Url::parse("https://localhost:8443").unwrap()).is_err());
+    }
+}
@@ -0,0 +1,1229 @@
+//! Minimal Kafka producer (wire protocol with Metadata v4 and Produce v3 requests).
+//! Messages are partitioned like the Java client (murmur2 hash of the key), collected
+//! into uncompressed v2 record batches and sent with `acks=all`.
+//! Delivery is at-least-once: batches are retried after leader changes and connection errors.
+//! TLS and SASL are not supported.
+//!
+//! The `rdkafka` crate isn't used because it builds librdkafka from source (or links a system
+//! librdkafka) and brings its own zlib, while the producer only needs these two requests.
+
+use std::collections::{HashMap, VecDeque};
+use std::error;
+use std::fmt;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+const CLIENT_ID: &str = "rusty-blockparser";
+const API_PRODUCE: i16 = 0;
+const API_METADATA: i16 = 3;
+const PRODUCE_VERSION: i16 = 3;
+const METADATA_VERSION: i16 = 4;
+// Wait for all in-sync replicas
+const ACKS_ALL: i16 = -1;
+// Upper bound of a record batch, the default `message.max.bytes` of the brokers is ~1 MB.
+// Larger messages are sent in a batch of their own.
+const BATCH_SIZE: usize = 1000 * 1000;
+// Rounds of requests without progress before giving up
+const MAX_RETRIES: u32 = 5;
+const RETRY_BACKOFF: Duration = Duration::from_millis(500);
+
+// Error codes of the brokers, which can be fixed by refreshing the metadata
+const UNKNOWN_TOPIC_OR_PARTITION: i16 = 3;
+const LEADER_NOT_AVAILABLE: i16 = 5;
+const NOT_LEADER_OR_FOLLOWER: i16 = 6;
+const REQUEST_TIMED_OUT: i16 = 7;
+const NOT_ENOUGH_REPLICAS: i16 = 19;
+const NOT_ENOUGH_REPLICAS_AFTER_APPEND: i16 = 20;
+
+#[derive(Debug)]
+pub enum KafkaError {
+    Io(io::Error),
+    /// Error code returned by a broker
+    Broker {
+        code: i16,
+        topic: String,
+        partition: Option<i32>,
+    },
+    /// Messages couldn't be delivered before the deadline
+    Timeout(usize),
+    /// Invalid broker list
+    Config(String),
+    /// Unexpected response
+    Protocol(String),
+}
+
+impl KafkaError {
+    fn is_retriable(&self) -> bool {
+        match self {
+            KafkaError::Io(_) => true,
+            KafkaError::Broker { code, .. } => matches!(
+                *code,
+                UNKNOWN_TOPIC_OR_PARTITION
+                    | LEADER_NOT_AVAILABLE
+                    | NOT_LEADER_OR_FOLLOWER
+                    | REQUEST_TIMED_OUT
+                    | NOT_ENOUGH_REPLICAS
+                    | NOT_ENOUGH_REPLICAS_AFTER_APPEND
+            ),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for KafkaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KafkaError::Io(err) => write!(f, "I/O Error: {}", err),
+            KafkaError::Broker {
+                code,
+                topic,
+                partition,
+            } => {
+                write!(
+                    f,
+                    "broker returned error {} ({}) for `{}`",
+                    code,
+                    error_name(*code),
+                    topic
+                )?;
+                match partition {
+                    Some(partition) => write!(f, " partition {}", partition),
+                    None => Ok(()),
+                }
+            }
+            KafkaError::Timeout(n) => write!(f, "timed out with {} undelivered messages", n),
+            KafkaError::Config(msg) => write!(f, "Invalid configuration: {}", msg),
+            KafkaError::Protocol(msg) => write!(f, "Protocol error: {}", msg),
+        }
+    }
+}
+
+impl error::Error for KafkaError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            KafkaError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for KafkaError {
+    fn from(err: io::Error) -> Self {
+        KafkaError::Io(err)
+    }
+}
+
+/// Names of the error codes a producer usually runs into
+fn error_name(code: i16) -> &'static str {
+    match code {
+        UNKNOWN_TOPIC_OR_PARTITION => "UNKNOWN_TOPIC_OR_PARTITION",
+        LEADER_NOT_AVAILABLE => "LEADER_NOT_AVAILABLE",
+        NOT_LEADER_OR_FOLLOWER => "NOT_LEADER_OR_FOLLOWER",
+        REQUEST_TIMED_OUT => "REQUEST_TIMED_OUT",
+        10 => "MESSAGE_TOO_LARGE",
+        17 => "INVALID_TOPIC_EXCEPTION",
+        18 => "RECORD_LIST_TOO_LARGE",
+        NOT_ENOUGH_REPLICAS => "NOT_ENOUGH_REPLICAS",
+        NOT_ENOUGH_REPLICAS_AFTER_APPEND => "NOT_ENOUGH_REPLICAS_AFTER_APPEND",
+        29 => "TOPIC_AUTHORIZATION_FAILED",
+        87 => "INVALID_RECORD",
+        _ => "see the Kafka protocol documentation",
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ProducerConfig {
+    /// Bootstrap brokers as `host:port`
+    pub brokers: Vec<String>,
+    /// Timeout of connects, requests and acknowledgements
+    pub timeout: Duration,
+    /// `send()` blocks until the brokers acknowledged the queued messages once their
+    /// size exceeds this limit
+    pub max_buffered_bytes: usize,
+}
+
+impl ProducerConfig {
+    /// Parses a comma separated broker list, the port defaults to 9092
+    pub fn new(
+        brokers: &str,
+        timeout: Duration,
+        max_buffered_bytes: usize,
+    ) -> Result<Self, KafkaError> {
+        let brokers: Vec<String> = brokers
+            .split(',')
+            .map(str::trim)
+            .filter(|broker| !broker.is_empty())
+            .map(|broker| match broker.rsplit_once(':') {
+                Some(_) => String::from(broker),
+                None => format!("{}:9092", broker),
+            })
+            .collect();
+        if brokers.is_empty() {
+            return Err(KafkaError::Config(String::from("no brokers given")));
+        }
+        Ok(ProducerConfig {
+            brokers,
+            timeout,
+            max_buffered_bytes,
+        })
+    }
+}
+
+/// Records of one partition, encoded as they would appear in a v2 record batch
+struct Batch {
+    records: Vec<u8>,
+    count: i32,
+    first_timestamp: i64,
+    max_timestamp: i64,
+}
+
+impl Batch {
+    fn new(timestamp: i64) -> Batch {
+        Batch {
+            records: Vec::new(),
+            count: 0,
+            first_timestamp: timestamp,
+            max_timestamp: timestamp,
+        }
+    }
+
+    fn push(&mut self, timestamp: i64, key: &[u8], value: &[u8]) {
+        let mut record = Vec::with_capacity(key.len() + value.len() + 16);
+        record.push(0); // attributes
+        write_varint(&mut record, timestamp - self.first_timestamp);
+        write_varint(&mut record, self.count as i64);
+        write_varint(&mut record, key.len() as i64);
+        record.extend_from_slice(key);
+        write_varint(&mut record, value.len() as i64);
+        record.extend_from_slice(value);
+        write_varint(&mut record, 0); // headers
+        write_varint(&mut self.records, record.len() as i64);
+        self.records.extend_from_slice(&record);
+        self.count += 1;
+        self.max_timestamp = self.max_timestamp.max(timestamp);
+    }
+
+    /// Encodes the batch header and records
+    fn encode(&self) -> Vec<u8> {
+        // Everything after the crc field, which is covered by the checksum
+        let mut tail = Vec::with_capacity(self.records.len() + 40);
+        tail.write_i16::<BigEndian>(0).unwrap(); // attributes: no compression, create time
+        tail.write_i32::<BigEndian>(self.count - 1).unwrap(); // last offset delta
+        tail.write_i64::<BigEndian>(self.first_timestamp).unwrap();
+        tail.write_i64::<BigEndian>(self.max_timestamp).unwrap();
+        tail.write_i64::<BigEndian>(-1).unwrap(); // producer id
+        tail.write_i16::<BigEndian>(-1).unwrap(); // producer epoch
+        tail.write_i32::<BigEndian>(-1).unwrap(); // base sequence
+        tail.write_i32::<BigEndian>(self.count).unwrap();
+        tail.extend_from_slice(&self.records);
+
+        let mut batch = Vec::with_capacity(tail.len() + 21);
+        batch.write_i64::<BigEndian>(0).unwrap(); // base offset, assigned by the broker
+        batch.write_i32::<BigEndian>(tail.len() as i32 + 9).unwrap();
+        batch.write_i32::<BigEndian>(-1).unwrap(); // partition leader epoch
+        batch.push(2); // magic
+        batch.write_u32::<BigEndian>(crc32c(&tail)).unwrap();
+        batch.extend_from_slice(&tail);
+        batch
+    }
+}
+
+/// Topic, partition and encoded record batch
+type EncodedBatch<'a> = (&'a str, i32, Vec<u8>);
+
+/// Partition of a topic with its queued batches
+struct Partition {
+    leader: i32,
+    // The first batch is the one in flight, later batches are only sent after it was acknowledged
+    batches: VecDeque<Batch>,
+}
+
+struct Connection {
+    stream: BufReader<TcpStream>,
+    correlation_id: i32,
+}
+
+impl Connection {
+    fn connect(addr: &str, timeout: Duration) -> Result<Connection, KafkaError> {
+        let mut last_err = None;
+        for addr in addr.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, timeout) {
+                Ok(stream) => {
+                    stream.set_nodelay(true)?;
+                    stream.set_write_timeout(Some(timeout))?;
+                    return Ok(Connection {
+                        stream: BufReader::new(stream),
+                        correlation_id: 0,
+                    });
+                }
+                Err(err) => last_err = Some(err),
+            }
+        }
+        Err(match last_err {
+            Some(err) => KafkaError::Io(err),
+            None => KafkaError::Config(format!("unable to resolve `{}`", addr)),
+        })
+    }
+
+    /// Sends a request, returns its correlation id
+    fn send(&mut self, api_key: i16, api_version: i16, body: &[u8]) -> Result<i32, KafkaError> {
+        self.correlation_id = self.correlation_id.wrapping_add(1);
+        let mut request = Vec::with_capacity(body.len() + 32);
+        request.write_i32::<BigEndian>(0).unwrap(); // size, filled in below
+        request.write_i16::<BigEndian>(api_key).unwrap();
+        request.write_i16::<BigEndian>(api_version).unwrap();
+        request.write_i32::<BigEndian>(self.correlation_id).unwrap();
+        write_string(&mut request, CLIENT_ID);
+        request.extend_from_slice(body);
+        let size = (request.len() - 4) as i32;
+        request[..4].copy_from_slice(&size.to_be_bytes());
+        self.stream.get_mut().write_all(&request)?;
+        Ok(self.correlation_id)
+    }
+
+    /// Reads the response of the request with the given correlation id
+    fn receive(&mut self, correlation_id: i32, timeout: Duration) -> Result<Vec<u8>, KafkaError> {
+        self.stream
+            .get_mut()
+            .set_read_timeout(Some(timeout.max(Duration::from_millis(1))))?;
+        let size = self.stream.read_i32::<BigEndian>()?;
+        if size < 4 {
+            return Err(KafkaError::Protocol(format!(
+                "invalid response size {}",
+                size
+            )));
+        }
+        let mut response = vec![0u8; size as usize];
+        self.stream.read_exact(&mut response)?;
+        let id = i32::from_be_bytes(response[..4].try_into().unwrap());
+        if id != correlation_id {
+            return Err(KafkaError::Protocol(format!(
+                "expected response {}, got {}",
+                correlation_id, id
+            )));
+        }
+        response.drain(..4);
+        Ok(response)
+    }
+
+    fn request(
+        &mut self,
+        api_key: i16,
+        api_version: i16,
+        body: &[u8],
+        timeout: Duration,
+    ) -> Result<Vec<u8>, KafkaError> {
+        let id = self.send(api_key, api_version, body)?;
+        self.receive(id, timeout)
+    }
+}
+
+/// Synchronous producer, `send()` queues messages and `flush()` waits until they are acknowledged
+pub struct Producer {
+    config: ProducerConfig,
+    // Address of each broker by node id
+    brokers: HashMap<i32, String>,
+    connections: HashMap<i32, Connection>,
+    topics: HashMap<String, Vec<Partition>>,
+    buffered_bytes: usize,
+    buffered_messages: usize,
+}
+
+impl Producer {
+    /// Connects to the cluster and fetches the partitions of the given topics,
+    /// which are created if the brokers allow it
+    pub fn connect(config: ProducerConfig, topics: &[&str]) -> Result<Producer, KafkaError> {
+        let mut producer = Producer {
+            config,
+            brokers: HashMap::new(),
+            connections: HashMap::new(),
+            topics: topics
+                .iter()
+                .map(|topic| (String::from(*topic), Vec::new()))
+                .collect(),
+            buffered_bytes: 0,
+            buffered_messages: 0,
+        };
+        let deadline = Instant::now() + producer.config.timeout;
+        let mut retries = 0;
+        // Freshly created topics have no leader yet, unreachable brokers fail right away
+        while let Err(err) = producer.refresh_metadata() {
+            let retriable = matches!(err, KafkaError::Broker { .. }) && err.is_retriable();
+            if !retriable || retries == MAX_RETRIES || Instant::now() >= deadline {
+                return Err(err);
+            }
+            retries += 1;
+            thread::sleep(RETRY_BACKOFF);
+        }
+        Ok(producer)
+    }
+
+    /// Queues a message, blocks until the queued messages are acknowledged if the buffer is full
+    pub fn send(&mut self, topic: &str, key: &[u8], value: &[u8]) -> Result<(), KafkaError> {
+        let partitions = self
+            .topics
+            .get_mut(topic)
+            .ok_or_else(|| KafkaError::Config(format!("unknown topic `{}`", topic)))?;
+        let index = (murmur2(key) & 0x7fffffff) as usize % partitions.len();
+        let batches = &mut partitions[index].batches;
+        let timestamp = now_millis();
+        let batch = match batches.back_mut() {
+            Some(batch) if batch.records.len() + key.len() + value.len() < BATCH_SIZE => batch,
+            _ => {
+                batches.push_back(Batch::new(timestamp));
+                batches.back_mut().unwrap()
+            }
+        };
+        let size = batch.records.len();
+        batch.push(timestamp, key, value);
+        self.buffered_bytes += batch.records.len() - size;
+        self.buffered_messages += 1;
+        if self.buffered_bytes >= self.config.max_buffered_bytes {
+            let timeout = self.config.timeout;
+            self.flush(timeout)?;
+        }
+        Ok(())
+    }
+
+    /// Number of messages which were not acknowledged yet
+    pub fn buffered_messages(&self) -> usize {
+        self.buffered_messages
+    }
+
+    /// Sends all queued messages and waits for their acknowledgements
+    pub fn flush(&mut self, timeout: Duration) -> Result<(), KafkaError> {
+        let deadline = Instant::now() + timeout;
+        let mut retries = 0;
+        while self.buffered_messages > 0 {
+            if Instant::now() >= deadline {
+                return Err(KafkaError::Timeout(self.buffered_messages));
+            }
+            match self.send_batches(deadline) {
+                Ok(()) => retries = 0,
+                Err(err) if err.is_retriable() && retries < MAX_RETRIES => {
+                    warn!(target: "kafka", "Retrying after error: {}", err);
+                    retries += 1;
+                    thread::sleep(
+                        RETRY_BACKOFF.min(deadline.saturating_duration_since(Instant::now())),
+                    );
+                    self.connections.clear();
+                    // Errors are retried in the next round
+                    if let Err(err) = self.refresh_metadata() {
+                        debug!(target: "kafka", "Unable to refresh metadata: {}", err);
+                    }
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends the first batch of every partition, one request per leader
+    fn send_batches(&mut self, deadline: Instant) -> Result<(), KafkaError> {
+        let mut requests: HashMap<i32, Vec<EncodedBatch>> = HashMap::new();
+        for (topic, partitions) in &self.topics {
+            for (index, partition) in partitions.iter().enumerate() {
+                if let Some(batch) = partition.batches.front() {
+                    requests.entry(partition.leader).or_default().push((
+                        topic,
+                        index as i32,
+                        batch.encode(),
+                    ));
+                }
+            }
+        }
+
+        // Send all requests before waiting for the first response
+        let mut pending = Vec::with_capacity(requests.len());
+        for (leader, batches) in requests {
+            let body = produce_request(&batches, self.config.timeout);
+            let addr = self
+                .brokers
+                .get(&leader)
+                .ok_or_else(|| KafkaError::Protocol(format!("no address of broker {}", leader)))?;
+            if !self.connections.contains_key(&leader) {
+                let conn = Connection::connect(addr, self.config.timeout)?;
+                self.connections.insert(leader, conn);
+            }
+            let id = self.connections.get_mut(&leader).unwrap().send(
+                API_PRODUCE,
+                PRODUCE_VERSION,
+                &body,
+            )?;
+            pending.push((leader, id));
+        }
+
+        let mut first_error = None;
+        for (leader, id) in pending {
+            // The broker waits up to `timeout` for the replicas before answering
+            let timeout = deadline
+                .saturating_duration_since(Instant::now())
+                .max(self.config.timeout + Duration::from_secs(1));
+            let conn = self.connections.get_mut(&leader).unwrap();
+            let response = conn.receive(id, timeout)?;
+            for (topic, partition, code) in parse_produce_response(&response)? {
+                if code != 0 {
+                    first_error.get_or_insert(KafkaError::Broker {
+                        code,
+                        topic,
+                        partition: Some(partition),
+                    });
+                    continue;
+                }
+                let batch = self
+                    .topics
+                    .get_mut(&topic)
+                    .and_then(|partitions| partitions.get_mut(partition as usize))
+                    .and_then(|partition| partition.batches.pop_front())
+                    .ok_or_else(|| {
+                        KafkaError::Protocol(format!(
+                            "unexpected partition {} {}",
+                            topic, partition
+                        ))
+                    })?;
+                self.buffered_messages -= batch.count as usize;
+                self.buffered_bytes -= batch.records.len();
+            }
+        }
+        match first_error {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// Fetches brokers and partition leaders of all topics
+    fn refresh_metadata(&mut self) -> Result<(), KafkaError> {
+        let mut body = Vec::new();
+        body.write_i32::<BigEndian>(self.topics.len() as i32)
+            .unwrap();
+        for topic in self.topics.keys() {
+            write_string(&mut body, topic);
+        }
+        body.push(1); // allow auto topic creation
+
+        // Known brokers first, they might have replaced the bootstrap list
+        let mut addrs: Vec<String> = self.brokers.values().cloned().collect();
+        addrs.extend(self.config.brokers.iter().cloned());
+        let mut last_err = None;
+        for addr in addrs {
+            let response = Connection::connect(&addr, self.config.timeout).and_then(|mut conn| {
+                conn.request(API_METADATA, METADATA_VERSION, &body, self.config.timeout)
+            });
+            match response {
+                Ok(response) => return self.update_metadata(&response),
+                Err(err) => {
+                    debug!(target: "kafka", "Unable to fetch metadata from {}: {}", addr, err);
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(last_err.unwrap())
+    }
+
+    fn update_metadata(&mut self, response: &[u8]) -> Result<(), KafkaError> {
+        let metadata = parse_metadata_response(response)?;
+        self.brokers = metadata.brokers.into_iter().collect();
+        for (topic, code, leaders) in metadata.topics {
+            if code != 0 {
+                return Err(KafkaError::Broker {
+                    code,
+                    topic,
+                    partition: None,
+                });
+            }
+            let partitions = match self.topics.get_mut(&topic) {
+                Some(partitions) => partitions,
+                None => continue,
+            };
+            if leaders.is_empty() {
+                return Err(KafkaError::Broker {
+                    code: UNKNOWN_TOPIC_OR_PARTITION,
+                    topic,
+                    partition: None,
+                });
+            }
+            if !partitions.is_empty() && partitions.len() != leaders.len() {
+                // Keys would be assigned to different partitions in the middle of the stream
+                return Err(KafkaError::Protocol(format!(
+                    "number of partitions of `{}` changed from {} to {}",
+                    topic,
+                    partitions.len(),
+                    leaders.len()
+                )));
+            }
+            for (index, leader) in leaders.iter().enumerate() {
+                if *leader < 0 {
+                    return Err(KafkaError::Broker {
+                        code: LEADER_NOT_AVAILABLE,
+                        topic,
+                        partition: Some(index as i32),
+                    });
+                }
+            }
+            if partitions.is_empty() {
+                partitions.extend(leaders.iter().map(|leader| Partition {
+                    leader: *leader,
+                    batches: VecDeque::new(),
+                }));
+            } else {
+                for (partition, leader) in partitions.iter_mut().zip(leaders) {
+                    partition.leader = leader;
+                }
+            }
+        }
+        if let Some((topic, _)) = self
+            .topics
+            .iter()
+            .find(|(_, partitions)| partitions.is_empty())
+        {
+            return Err(KafkaError::Broker {
+                code: UNKNOWN_TOPIC_OR_PARTITION,
+                topic: topic.clone(),
+                partition: None,
+            });
+        }
+        Ok(())
+    }
+}
+
+fn produce_request(batches: &[EncodedBatch], timeout: Duration) -> Vec<u8> {
+    let mut body = Vec::with_capacity(batches.iter().map(|b| b.2.len() + 64).sum());
+    body.write_i16::<BigEndian>(-1).unwrap(); // transactional id
+    body.write_i16::<BigEndian>(ACKS_ALL).unwrap();
+    body.write_i32::<BigEndian>(timeout.as_millis().min(i32::MAX as u128) as i32)
+        .unwrap();
+    let mut topics: Vec<&str> = batches.iter().map(|b| b.0).collect();
+    topics.dedup();
+    body.write_i32::<BigEndian>(topics.len() as i32).unwrap();
+    for topic in topics {
+        write_string(&mut body, topic);
+        let partitions: Vec<_> = batches.iter().filter(|b| b.0 == topic).collect();
+        body.write_i32::<BigEndian>(partitions.len() as i32)
+            .unwrap();
+        for (_, partition, records) in partitions {
+            body.write_i32::<BigEndian>(*partition).unwrap();
+            body.write_i32::<BigEndian>(records.len() as i32).unwrap();
+            body.extend_from_slice(records);
+        }
+    }
+    body
+}
+
+/// Returns topic, partition and error code of each partition
+fn parse_produce_response(data: &[u8]) -> Result<Vec<(String, i32, i16)>, KafkaError> {
+    let mut reader = Reader(data);
+    let mut partitions = Vec::new();
+    for _ in 0..reader.array_len()? {
+        let topic = reader.string()?;
+        for _ in 0..reader.array_len()? {
+            let partition = reader.i32()?;
+            let code = reader.i16()?;
+            reader.i64()?; // base offset
+            reader.i64()?; // log append time
+            partitions.push((topic.clone(), partition, code));
+        }
+    }
+    Ok(partitions)
+}
+
+struct Metadata {
+    brokers: Vec<(i32, String)>,
+    /// Name, error code and the leader of each partition
+    topics: Vec<(String, i16, Vec<i32>)>,
+}
+
+fn parse_metadata_response(data: &[u8]) -> Result<Metadata, KafkaError> {
+    let mut reader = Reader(data);
+    reader.i32()?; // throttle time
+    let mut brokers = Vec::new();
+    for _ in 0..reader.array_len()? {
+        let node_id = reader.i32()?;
+        let host = reader.string()?;
+        let port = reader.i32()?;
+        reader.nullable_string()?; // rack
+        brokers.push((node_id, format!("{}:{}", host, port)));
+    }
+    reader.nullable_string()?; // cluster id
+    reader.i32()?; // controller id
+    let mut topics = Vec::new();
+    for _ in 0..reader.array_len()? {
+        let code = reader.i16()?;
+        let name = reader.string()?;
+        reader.i8()?; // is internal
+        let mut leaders = Vec::new();
+        for _ in 0..reader.array_len()? {
+            reader.i16()?; // partition error, reflected in the leader
+            let index = reader.i32()?;
+            let leader = reader.i32()?;
+            for _ in 0..2 {
+                // replicas and in-sync replicas
+                for _ in 0..reader.array_len()? {
+                    reader.i32()?;
+                }
+            }
+            leaders.push((index, leader));
+        }
+        leaders.sort_unstable();
+        if leaders
+            .iter()
+            .enumerate()
+            .any(|(i, (index, _))| i as i32 != *index)
+        {
+            return Err(KafkaError::Protocol(format!(
+                "incomplete partition list of `{}`",
+                name
+            )));
+        }
+        topics.push((
+            name,
+            code,
+            leaders.into_iter().map(|(_, leader)| leader).collect(),
+        ));
+    }
+    Ok(Metadata { brokers, topics })
+}
+
+/// Reads big-endian protocol primitives
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn take(&mut self, n: usize) -> Result<&[u8], KafkaError> {
+        if self.0.len() < n {
+            return Err(KafkaError::Protocol(String::from("truncated response")));
+        }
+        let (value, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(value)
+    }
+
+    fn i8(&mut self) -> Result<i8, KafkaError> {
+        Ok(self.take(1)?[0] as i8)
+    }
+
+    fn i16(&mut self) -> Result<i16, KafkaError> {
+        Ok(i16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn i32(&mut self) -> Result<i32, KafkaError> {
+        Ok(i32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn i64(&mut self) -> Result<i64, KafkaError> {
+        Ok(i64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn array_len(&mut self) -> Result<usize, KafkaError> {
+        Ok(self.i32()?.max(0) as usize)
+    }
+
+    fn nullable_string(&mut self) -> Result<Option<String>, KafkaError> {
+        let len = self.i16()?;
+        if len < 0 {
+            return Ok(None);
+        }
+        let bytes = self.take(len as usize)?;
+        Ok(Some(String::from_utf8_lossy(bytes).into_owned()))
+    }
+
+    fn string(&mut self) -> Result<String, KafkaError> {
+        self.nullable_string()?
+            .ok_or_else(|| KafkaError::Protocol(String::from("unexpected null string")))
+    }
+}
+
+fn write_string(out: &mut Vec<u8>, value: &str) {
+    out.write_i16::<BigEndian>(value.len() as i16).unwrap();
+    out.extend_from_slice(value.as_bytes());
+}
+
+/// Zigzag encoded variable length integer as used in records
+fn write_varint(out: &mut Vec<u8>, value: i64) {
+    let mut value = ((value << 1) ^ (value >> 63)) as u64;
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+/// Hash of the default partitioner of the Java client
+pub fn murmur2(data: &[u8]) -> u32 {
+    const SEED: u32 = 0x9747b28c;
+    const M: u32 = 0x5bd1e995;
+    const R: u32 = 24;
+
+    let mut h = SEED ^ data.len() as u32;
+    let chunks = data.chunks_exact(4);
+    let tail = chunks.remainder();
+    for chunk in chunks {
+        let mut k = u32::from_le_bytes(chunk.try_into().unwrap());
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+        h = h.wrapping_mul(M);
+        h ^= k;
+    }
+    if tail.len() >= 3 {
+        h ^= (tail[2] as u32) << 16;
+    }
+    if tail.len() >= 2 {
+        h ^= (tail[1] as u32) << 8;
+    }
+    if !tail.is_empty() {
+        h ^= tail[0] as u32;
+        h = h.wrapping_mul(M);
+    }
+    h ^= h >> 13;
+    h = h.wrapping_mul(M);
+    h ^= h >> 15;
+    h
+}
+
+const CRC32C_TABLE: [u32; 256] = crc32c_table();
+
+const fn crc32c_table() -> [u32; 256] {
+    // Reversed Castagnoli polynomial
+    const POLY: u32 = 0x82f63b78;
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ POLY
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// CRC-32C checksum of record batches
+fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc = CRC32C_TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::net::TcpListener;
+    use std::process;
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::common::utils;
+
+    const TEST_BROKERS_ENV: &str = "BLOCKPARSER_TEST_KAFKA";
+
+    /// Metadata v4 response of a cluster of two brokers for the topic `txs` with two partitions,
+    /// field by field as in MetadataResponse.json of Kafka, without the correlation id
+    const METADATA_RESPONSE: &str = concat!(
+        "00000000",                                         // throttle_time_ms
+        "00000002",                                         // brokers
+        "0000000100076b61666b612d310000238400027231",       // 1, "kafka-1", 9092, rack "r1"
+        "0000000200076b61666b612d3200002384ffff",           // 2, "kafka-2", 9092, no rack
+        "00164d6b55334f4556424e5463774e544a454e444d32516b", // cluster_id
+        "00000001",                                         // controller_id
+        "00000001",                                         // topics
+        "0000000374787300",                                 // no error, "txs", not internal
+        "00000002",                                         // partitions, in no particular order
+        "00000000000100000002",                             // no error, partition 1, leader 2
+        "0000000200000002000000010000000100000002",         // replicas [2, 1], isr [2]
+        "00000000000000000001",                             // no error, partition 0, leader 1
+        "000000020000000100000002000000020000000100000002", // replicas [1, 2], isr [1, 2]
+    );
+
+    /// Produce v3 response with an acknowledged partition and one which moved to another
+    /// leader, as in ProduceResponse.json of Kafka, without the correlation id
+    const PRODUCE_RESPONSE: &str = concat!(
+        "00000001",                                     // responses
+        "000374787300000002",                           // "txs", partitions
+        "000000000000000000000000002affffffffffffffff", // partition 0, no error, offset 42, no append time
+        "000000010006ffffffffffffffffffffffffffffffff", // partition 1, NOT_LEADER_OR_FOLLOWER
+        "00000000",                                     // throttle_time_ms
+    );
+
+    /// Message received by the fake broker
+    #[derive(Debug, Clone, PartialEq)]
+    struct Received {
+        topic: String,
+        partition: i32,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    }
+
+    fn read_varint(reader: &mut Reader) -> i64 {
+        let mut value = 0u64;
+        let mut shift = 0;
+        loop {
+            let byte = reader.take(1).unwrap()[0];
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return (value >> 1) as i64 ^ -((value & 1) as i64);
+            }
+            shift += 7;
+        }
+    }
+
+    /// Decodes the records of a v2 batch, verifies header and checksum
+    fn decode_batch(data: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut reader = Reader(data);
+        assert_eq!(0, reader.i64().unwrap());
+        assert_eq!(data.len() - 12, reader.i32().unwrap() as usize);
+        reader.i32().unwrap();
+        assert_eq!(2, reader.i8().unwrap());
+        let crc = reader.i32().unwrap() as u32;
+        assert_eq!(crc32c(reader.0), crc);
+        assert_eq!(0, reader.i16().unwrap());
+        let last_offset_delta = reader.i32().unwrap();
+        let first_timestamp = reader.i64().unwrap();
+        assert!(reader.i64().unwrap() >= first_timestamp);
+        reader.take(14).unwrap(); // producer id, epoch and base sequence
+        let count = reader.i32().unwrap();
+        assert_eq!(count - 1, last_offset_delta);
+        let mut records = Vec::new();
+        for offset in 0..count {
+            let len = read_varint(&mut reader) as usize;
+            let mut record = Reader(reader.take(len).unwrap());
+            assert_eq!(0, record.i8().unwrap());
+            assert!(read_varint(&mut record) >= 0);
+            assert_eq!(offset as i64, read_varint(&mut record));
+            let key_len = read_varint(&mut record) as usize;
+            let key = record.take(key_len).unwrap().to_vec();
+            let value_len = read_varint(&mut record) as usize;
+            let value = record.take(value_len).unwrap().to_vec();
+            assert_eq!(0, read_varint(&mut record));
+            assert!(record.0.is_empty());
+            records.push((key, value));
+        }
+        assert!(reader.0.is_empty());
+        records
+    }
+
+    /// Single node cluster with `partitions` per topic.
+    /// The first Produce responses return `errors` (one per partition) instead of storing the batch.
+    fn fake_broker(partitions: i32, errors: Vec<i16>) -> (String, Arc<Mutex<Vec<Received>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let errors = Arc::new(Mutex::new(VecDeque::from(errors)));
+        let messages = received.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let messages = messages.clone();
+                let errors = errors.clone();
+                thread::spawn(move || {
+                    while let Ok(size) = stream.read_i32::<BigEndian>() {
+                        let mut request = vec![0u8; size as usize];
+                        stream.read_exact(&mut request).unwrap();
+                        let mut reader = Reader(&request);
+                        let api_key = reader.i16().unwrap();
+                        reader.i16().unwrap();
+                        let id = reader.i32().unwrap();
+                        assert_eq!(CLIENT_ID, reader.string().unwrap());
+                        let mut response = id.to_be_bytes().to_vec();
+                        match api_key {
+                            API_METADATA => {
+                                response.write_i32::<BigEndian>(0).unwrap();
+                                response.write_i32::<BigEndian>(1).unwrap();
+                                response.write_i32::<BigEndian>(0).unwrap();
+                                write_string(&mut response, "127.0.0.1");
+                                response.write_i32::<BigEndian>(port as i32).unwrap();
+                                response.write_i16::<BigEndian>(-1).unwrap();
+                                response.write_i16::<BigEndian>(-1).unwrap();
+                                response.write_i32::<BigEndian>(0).unwrap();
+                                let n_topics = reader.i32().unwrap();
+                                response.write_i32::<BigEndian>(n_topics).unwrap();
+                                for _ in 0..n_topics {
+                                    response.write_i16::<BigEndian>(0).unwrap();
+                                    write_string(&mut response, &reader.string().unwrap());
+                                    response.push(0);
+                                    response.write_i32::<BigEndian>(partitions).unwrap();
+                                    // Reversed, clients must not rely on the order
+                                    for index in (0..partitions).rev() {
+                                        response.write_i16::<BigEndian>(0).unwrap();
+                                        response.write_i32::<BigEndian>(index).unwrap();
+                                        response.write_i32::<BigEndian>(0).unwrap();
+                                        for _ in 0..2 {
+                                            response.write_i32::<BigEndian>(1).unwrap();
+                                            response.write_i32::<BigEndian>(0).unwrap();
+                                        }
+                                    }
+                                }
+                            }
+                            API_PRODUCE => {
+                                assert_eq!(-1, reader.i16().unwrap());
+                                assert_eq!(ACKS_ALL, reader.i16().unwrap());
+                                reader.i32().unwrap();
+                                let n_topics = reader.i32().unwrap();
+                                response.write_i32::<BigEndian>(n_topics).unwrap();
+                                for _ in 0..n_topics {
+                                    let topic = reader.string().unwrap();
+                                    write_string(&mut response, &topic);
+                                    let n_partitions = reader.i32().unwrap();
+                                    response.write_i32::<BigEndian>(n_partitions).unwrap();
+                                    for _ in 0..n_partitions {
+                                        let partition = reader.i32().unwrap();
+                                        let len = reader.i32().unwrap() as usize;
+                                        let batch = decode_batch(reader.take(len).unwrap());
+                                        let code = errors.lock().unwrap().pop_front().unwrap_or(0);
+                                        if code == 0 {
+                                            messages.lock().unwrap().extend(batch.into_iter().map(
+                                                |(key, value)| Received {
+                                                    topic: topic.clone(),
+                                                    partition,
+                                                    key,
+                                                    value,
+                                                },
+                                            ));
+                                        }
+                                        response.write_i32::<BigEndian>(partition).unwrap();
+                                        response.write_i16::<BigEndian>(code).unwrap();
+                                        response.write_i64::<BigEndian>(0).unwrap();
+                                        response.write_i64::<BigEndian>(-1).unwrap();
+                                    }
+                                }
+                                response.write_i32::<BigEndian>(0).unwrap();
+                            }
+                            _ => panic!("unexpected request {}", api_key),
+                        }
+                        assert!(reader.0.is_empty() || api_key == API_METADATA);
+                        stream
+                            .write_i32::<BigEndian>(response.len() as i32)
+                            .unwrap();
+                        stream.write_all(&response).unwrap();
+                    }
+                });
+            }
+        });
+        (format!("127.0.0.1:{}", port), received)
+    }
+
+    fn producer(brokers: &str, max_buffered_bytes: usize) -> Result<Producer, KafkaError> {
+        let config = ProducerConfig::new(brokers, Duration::from_secs(5), max_buffered_bytes)?;
+        Producer::connect(config, &["blocks", "txs"])
+    }
+
+    #[test]
+    fn test_murmur2() {
+        // Test vectors of the Java client
+        let cases: [(&[u8], i32); 6] = [
+            (b"21", -973932308),
+            (b"foobar", -790332482),
+            (b"a-little-bit-long-string", -985981536),
+            (b"a-little-bit-longer-string", -1486304829),
+            (
+                b"lkjh234lh9fiuh90y23oiuhsafujhadof229phr9h19h89h8",
+                -58897971,
+            ),
+            (b"abc", 479470107),
+        ];
+        for (data, hash) in cases {
+            assert_eq!(hash, murmur2(data) as i32);
+        }
+    }
+
+    #[test]
+    fn test_crc32c() {
+        assert_eq!(0xe3069283, crc32c(b"123456789"));
+        assert_eq!(0, crc32c(b""));
+    }
+
+    #[test]
+    fn test_varint() {
+        for (value, encoded) in [
+            (0i64, vec![0u8]),
+            (-1, vec![1]),
+            (1, vec![2]),
+            (300, vec![0xd8, 0x04]),
+        ] {
+            let mut out = Vec::new();
+            write_varint(&mut out, value);
+            assert_eq!(encoded, out);
+            assert_eq!(value, read_varint(&mut Reader(&out)));
+        }
+    }
+
+    #[test]
+    fn test_config() {
+        let config = ProducerConfig::new("a:1, b ,", Duration::from_secs(1), 0).unwrap();
+        assert_eq!(vec!["a:1", "b:9092"], config.brokers);
+        assert!(ProducerConfig::new(" , ", Duration::from_secs(1), 0).is_err());
+    }
+
+    #[test]
+    fn test_produce() {
+        let (addr, received) = fake_broker(3, Vec::new());
+        let mut producer = producer(&addr, 1 << 20).unwrap();
+        for i in 0..100u32 {
+            let key = format!("key-{}", i % 10);
+            producer
+                .send("txs", key.as_bytes(), &i.to_le_bytes())
+                .unwrap();
+        }
+        producer.send("blocks", b"", b"block").unwrap();
+        assert_eq!(101, producer.buffered_messages());
+        producer.flush(Duration::from_secs(5)).unwrap();
+        assert_eq!(0, producer.buffered_messages());
+
+        let received = received.lock().unwrap();
+        assert_eq!(101, received.len());
+        let blocks: Vec<_> = received.iter().filter(|m| m.topic == "blocks").collect();
+        assert_eq!(b"block".to_vec(), blocks[0].value);
+        for key in 0..10u32 {
+            let key = format!("key-{}", key);
+            let messages: Vec<_> = received
+                .iter()
+                .filter(|m| m.topic == "txs" && m.key == key.as_bytes())
+                .collect();
+            assert_eq!(10, messages.len());
+            // Same partition as the Java client, in the order they were sent
+            let partition = ((murmur2(key.as_bytes()) & 0x7fffffff) % 3) as i32;
+            assert!(messages.iter().all(|m| m.partition == partition));
+            let values: Vec<u32> = messages
+                .iter()
+                .map(|m| u32::from_le_bytes(m.value[..].try_into().unwrap()))
+                .collect();
+            let mut sorted = values.clone();
+            sorted.sort_unstable();
+            assert_eq!(sorted, values);
+        }
+    }
+
+    #[test]
+    fn test_backpressure() {
+        let (addr, received) = fake_broker(1, Vec::new());
+        let mut producer = producer(&addr, 100).unwrap();
+        // 50 bytes per record
+        for _ in 0..3 {
+            producer.send("txs", b"key", &[0u8; 40]).unwrap();
+        }
+        // The second message filled the buffer, both were delivered synchronously
+        assert_eq!(2, received.lock().unwrap().len());
+        assert_eq!(1, producer.buffered_messages());
+    }
+
+    #[test]
+    fn test_retry() {
+        let (addr, received) = fake_broker(1, vec![NOT_LEADER_OR_FOLLOWER]);
+        let mut producer = producer(&addr, 1 << 20).unwrap();
+        producer.send("txs", b"key", b"value").unwrap();
+        producer.flush(Duration::from_secs(5)).unwrap();
+        assert_eq!(1, received.lock().unwrap().len());
+    }
+
+    #[test]
+    fn test_broker_error() {
+        let (addr, received) = fake_broker(1, vec![10]);
+        let mut producer = producer(&addr, 1 << 20).unwrap();
+        producer.send("txs", b"key", b"value").unwrap();
+        match producer.flush(Duration::from_secs(5)) {
+            Err(KafkaError::Broker {
+                code: 10,
+                topic,
+                partition: Some(0),
+            }) => assert_eq!("txs", topic),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(received.lock().unwrap().is_empty());
+        assert_eq!(1, producer.buffered_messages());
+    }
+
+    #[test]
+    fn test_broker_responses() {
+        let response = utils::hex_to_vec(METADATA_RESPONSE);
+        let metadata = parse_metadata_response(&response).unwrap();
+        assert_eq!(
+            vec![
+                (1, String::from("kafka-1:9092")),
+                (2, String::from("kafka-2:9092"))
+            ],
+            metadata.brokers
+        );
+        assert_eq!(1, metadata.topics.len());
+        assert_eq!((String::from("txs"), 0, vec![1, 2]), metadata.topics[0]);
+        for len in 0..response.len() {
+            assert!(parse_metadata_response(&response[..len]).is_err());
+        }
+
+        let config = ProducerConfig::new("kafka-1", Duration::from_secs(1), 1 << 20).unwrap();
+        let mut producer = Producer {
+            config,
+            brokers: HashMap::new(),
+            connections: HashMap::new(),
+            topics: HashMap::from([(String::from("txs"), Vec::new())]),
+            buffered_bytes: 0,
+            buffered_messages: 0,
+        };
+        producer.update_metadata(&response).unwrap();
+        let leaders: Vec<i32> = producer.topics["txs"].iter().map(|p| p.leader).collect();
+        assert_eq!(vec![1, 2], leaders);
+        assert_eq!("kafka-2:9092", producer.brokers[&2]);
+
+        let response = utils::hex_to_vec(PRODUCE_RESPONSE);
+        assert_eq!(
+            vec![
+                (String::from("txs"), 0, 0),
+                (String::from("txs"), 1, NOT_LEADER_OR_FOLLOWER)
+            ],
+            parse_produce_response(&response).unwrap()
+        );
+        assert!(parse_produce_response(&response[..30]).is_err());
+    }
+
+    /// Produces to the cluster in $BLOCKPARSER_TEST_KAFKA (`host:port,...`), which has to allow
+    /// the creation of topics
+    #[test]
+    fn test_kafka_broker() {
+        let brokers = match env::var(TEST_BROKERS_ENV) {
+            Ok(brokers) => brokers,
+            Err(_) => return,
+        };
+        let topic = format!("blockparser-test-{}", process::id());
+        let config = ProducerConfig::new(&brokers, Duration::from_secs(30), 1 << 16).unwrap();
+        let mut producer = Producer::connect(config, &[&topic]).unwrap();
+        for i in 0..10000u32 {
+            let value = format!("message {}", i);
+            producer
+                .send(&topic, &i.to_be_bytes(), value.as_bytes())
+                .unwrap();
+        }
+        producer.flush(Duration::from_secs(30)).unwrap();
+        assert_eq!(0, producer.buffered_messages());
+    }
+
+    #[test]
+    fn test_unreachable() {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        match producer(&format!("127.0.0.1:{}", port), 1 << 20) {
+            Err(KafkaError::Io(_)) => {}
+            Err(err) => panic!("unexpected error: {}", err),
+            Ok(_) => panic!("connected to a closed port"),
+        }
+    }
+}
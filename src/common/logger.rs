@@ -1,49 +1,408 @@
-use std::io::{stderr, stdout, Write};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, stderr, stdout, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use log::{self, Level, LevelFilter, Metadata, Record, SetLoggerError};
+use log::{self, Level, LevelFilter, Metadata, Record};
+use serde_json::{json, Value};
 use time::OffsetDateTime;
 
+use crate::common::utils;
+use crate::errors::{OpError, OpErrorKind, OpResult};
+
+/// Rotated log files which are kept, `<path>.1` is the newest
+const ROTATED_FILES: usize = 5;
+/// Rotates the log file before it exceeds 100 MiB by default
+pub const DEFAULT_MAX_SIZE: u64 = 100 * 1024 * 1024;
+
+// Height of the block the parser is working on, u64::MAX before the first one
+static HEIGHT: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// Adds the height to the following json log records
+pub fn set_height(height: u64) {
+    HEIGHT.store(height, Ordering::Relaxed);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    /// `[12:00:00] INFO - parser: message`, errors go to stderr and the rest to stdout
+    Text,
+    /// One json object per line on stderr
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = OpError;
+
+    fn from_str(s: &str) -> OpResult<Self> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(
+                OpError::new(OpErrorKind::InvalidArgsError).join_msg(&format!(
+                    "Unknown log format `{}`, expected text or json",
+                    s
+                )),
+            ),
+        }
+    }
+}
+
+/// Level of each record depending on its target
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogLevels {
+    default: LevelFilter,
+    targets: Vec<(String, LevelFilter)>,
+}
+
+impl LogLevels {
+    pub fn new(default: LevelFilter) -> Self {
+        LogLevels {
+            default,
+            targets: Vec::new(),
+        }
+    }
+
+    /// Applies comma separated overrides like `callback=debug,parser=info`.
+    /// An entry without target replaces the default level.
+    pub fn parse_overrides(mut self, spec: &str) -> OpResult<Self> {
+        for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (target, level) = match entry.split_once('=') {
+                Some((target, level)) => (Some(target.trim()), level.trim()),
+                None => (None, entry),
+            };
+            let level = LevelFilter::from_str(level).map_err(|_| {
+                OpError::new(OpErrorKind::InvalidArgsError).join_msg(&format!(
+                    "Invalid log level `{}` in `{}`, expected off, error, warn, info, debug or trace",
+                    level, entry
+                ))
+            })?;
+            match target {
+                Some("") => {
+                    return Err(OpError::new(OpErrorKind::InvalidArgsError)
+                        .join_msg(&format!("Missing log target in `{}`", entry)))
+                }
+                Some(target) => {
+                    self.targets.retain(|(other, _)| other != target);
+                    self.targets.push((String::from(target), level));
+                }
+                None => self.default = level,
+            }
+        }
+        Ok(self)
+    }
+
+    /// The most specific override wins, `mongo` also applies to `mongo::inserter`
+    pub fn level(&self, target: &str) -> LevelFilter {
+        self.targets
+            .iter()
+            .filter(|(prefix, _)| {
+                target == prefix
+                    || (target.starts_with(prefix.as_str())
+                        && target[prefix.len()..].starts_with("::"))
+            })
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.targets
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, Ord::max)
+    }
+}
+
+/// Log file which is renamed to `<path>.1` before it exceeds `max_size`
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: &Path, max_size: u64) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path: PathBuf::from(path),
+            file,
+            size,
+            max_size,
+        })
+    }
+
+    fn rotated_path(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        PathBuf::from(path)
+    }
+
+    fn write_line(&mut self, line: &[u8]) -> io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        for n in (1..ROTATED_FILES).rev() {
+            let from = self.rotated_path(n);
+            if from.exists() {
+                fs::rename(&from, self.rotated_path(n + 1))?;
+            }
+        }
+        fs::rename(&self.path, self.rotated_path(1))?;
+        self.file = File::create(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+/// Settings of the logger from the command line
+pub struct LogConfig {
+    pub levels: LogLevels,
+    pub format: LogFormat,
+    /// Records are also appended to this file
+    pub file: Option<PathBuf>,
+    /// Size in bytes at which the log file is rotated
+    pub max_size: u64,
+}
+
 pub struct SimpleLogger {
-    level_filter: LevelFilter,
+    levels: LogLevels,
+    format: LogFormat,
+    file: Option<Mutex<RotatingFile>>,
 }
 
 impl SimpleLogger {
-    pub fn init(level_filter: LevelFilter) -> Result<(), SetLoggerError> {
-        let logger = SimpleLogger { level_filter };
-        log::set_boxed_logger(Box::new(logger))?;
-        log::set_max_level(level_filter);
+    pub fn init(level_filter: LevelFilter) -> OpResult<()> {
+        SimpleLogger::init_with(LogConfig {
+            levels: LogLevels::new(level_filter),
+            format: LogFormat::Text,
+            file: None,
+            max_size: DEFAULT_MAX_SIZE,
+        })
+    }
+
+    /// Fails if the log file can't be opened
+    pub fn init_with(config: LogConfig) -> OpResult<()> {
+        let LogConfig {
+            levels,
+            format,
+            file,
+            max_size,
+        } = config;
+        let file = match file {
+            Some(path) => Some(Mutex::new(RotatingFile::open(&path, max_size).map_err(
+                |e| OpError::from(format!("Unable to open log file {}: {}", path.display(), e)),
+            )?)),
+            None => None,
+        };
+        let max_level = levels.max_level();
+        let logger = SimpleLogger {
+            levels,
+            format,
+            file,
+        };
+        log::set_boxed_logger(Box::new(logger))
+            .map_err(|e| OpError::from(format!("Unable to initialize logger: {}", e)))?;
+        log::set_max_level(max_level);
         Ok(())
     }
 
     fn create_log_line(&self, record: &Record) -> String {
-        format!(
-            "[{}] {} - {}: {}\n",
-            OffsetDateTime::now_local().format("%T"),
-            record.level().to_string(),
-            record.target(),
-            record.args()
-        )
+        match self.format {
+            LogFormat::Text => format!(
+                "[{}] {} - {}: {}\n",
+                OffsetDateTime::now_local().format("%T"),
+                record.level(),
+                record.target(),
+                record.args()
+            ),
+            LogFormat::Json => {
+                let mut line = json_record(record, HEIGHT.load(Ordering::Relaxed)).to_string();
+                line.push('\n');
+                line
+            }
+        }
     }
 }
 
+/// Builds the json object of a record: timestamp, level, target, message, the height of the
+/// current block and the txid if the message mentions one (`txid: <hex>`)
+fn json_record(record: &Record, height: u64) -> Value {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let timestamp = utils::timestamp_to_iso8601(now.as_secs() as u32);
+    let message = record.args().to_string();
+    let mut object = json!({
+        // Adds milliseconds, e.g. 2024-05-01T12:00:00.123Z
+        "timestamp": format!("{}.{:03}Z", &timestamp[..timestamp.len() - 1], now.subsec_millis()),
+        "level": record.level().to_string(),
+        "target": record.target(),
+        "message": message,
+    });
+    if height != u64::MAX {
+        object["height"] = json!(height);
+    }
+    if let Some(txid) = find_txid(&message) {
+        object["txid"] = json!(txid);
+    }
+    object
+}
+
+fn find_txid(message: &str) -> Option<&str> {
+    let start = message.find("txid: ")? + "txid: ".len();
+    let txid = message.get(start..start + 64)?;
+    txid.bytes().all(|b| b.is_ascii_hexdigit()).then_some(txid)
+}
+
 impl log::Log for SimpleLogger {
     fn enabled(&self, metadata: &Metadata) -> bool {
-        metadata.level() <= self.level_filter
+        metadata.level() <= self.levels.level(metadata.target())
     }
 
     fn log(&self, record: &Record) {
         if self.enabled(record.metadata()) {
             let line = self.create_log_line(record);
-            match record.level() {
-                Level::Error => {
+            match (self.format, record.level()) {
+                (LogFormat::Json, _) | (_, Level::Error) => {
                     stderr().write_all(line.as_bytes()).unwrap();
                 }
                 _ => {
                     stdout().write_all(line.as_bytes()).unwrap();
                 }
             }
+            if let Some(file) = &self.file {
+                if let Ok(mut file) = file.lock() {
+                    // Logging must not fail because of the log file
+                    let _ = file.write_line(line.as_bytes());
+                }
+            }
         }
     }
 
     fn flush(&self) {}
 }
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+
+    #[test]
+    fn test_levels() {
+        let levels = LogLevels::new(LevelFilter::Info)
+            .parse_overrides("callback=debug, parser=warn,mongo::inserter=trace")
+            .unwrap();
+        assert_eq!(LevelFilter::Debug, levels.level("callback"));
+        assert_eq!(LevelFilter::Warn, levels.level("parser"));
+        assert_eq!(LevelFilter::Info, levels.level("mongo"));
+        assert_eq!(LevelFilter::Trace, levels.level("mongo::inserter"));
+        assert_eq!(LevelFilter::Trace, levels.level("mongo::inserter::batch"));
+        // Only whole path segments match
+        assert_eq!(LevelFilter::Info, levels.level("callbacks"));
+        assert_eq!(LevelFilter::Trace, levels.max_level());
+
+        let levels = LogLevels::new(LevelFilter::Info)
+            .parse_overrides("error,parser=info,parser=debug")
+            .unwrap();
+        assert_eq!(LevelFilter::Error, levels.level("callback"));
+        assert_eq!(LevelFilter::Debug, levels.level("parser"));
+        assert_eq!(1, levels.targets.len());
+
+        for spec in ["parser=loud", "=debug", "verbose"] {
+            assert!(
+                LogLevels::new(LevelFilter::Info)
+                    .parse_overrides(spec)
+                    .is_err(),
+                "{}",
+                spec
+            );
+        }
+    }
+
+    #[test]
+    fn test_json_record() {
+        let txid = "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b";
+        let message = format!("seen in block #0, txid: {}", txid);
+        let object = json_record(
+            &Record::builder()
+                .args(format_args!("{}", message))
+                .level(Level::Warn)
+                .target("simplestats")
+                .build(),
+            42,
+        );
+        assert_eq!(json!("WARN"), object["level"]);
+        assert_eq!(json!("simplestats"), object["target"]);
+        assert_eq!(json!(message), object["message"]);
+        assert_eq!(json!(42), object["height"]);
+        assert_eq!(json!(txid), object["txid"]);
+        let timestamp = object["timestamp"].as_str().unwrap();
+        assert_eq!(24, timestamp.len(), "{}", timestamp);
+        assert!(timestamp.ends_with('Z') && &timestamp[10..11] == "T");
+
+        let object = json_record(
+            &Record::builder()
+                .args(format_args!("txid: 1234"))
+                .level(Level::Info)
+                .target("parser")
+                .build(),
+            u64::MAX,
+        );
+        assert!(object.get("height").is_none());
+        assert!(object.get("txid").is_none());
+    }
+
+    #[test]
+    fn test_rotating_file() {
+        let dir = env::temp_dir().join(format!("blockparser-logger-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("parser.log");
+        let mut file = RotatingFile::open(&path, 20).unwrap();
+        for i in 0..8 {
+            file.write_line(format!("line {}\n", i).as_bytes()).unwrap();
+        }
+        // Three lines of 7 bytes don't fit into 20
+        assert_eq!("line 6\nline 7\n", fs::read_to_string(&path).unwrap());
+        assert_eq!(
+            "line 4\nline 5\n",
+            fs::read_to_string(dir.join("parser.log.1")).unwrap()
+        );
+        assert_eq!(
+            "line 0\nline 1\n",
+            fs::read_to_string(dir.join("parser.log.3")).unwrap()
+        );
+
+        // Appends to an existing file
+        drop(file);
+        let mut file = RotatingFile::open(&path, 100).unwrap();
+        file.write_line(b"line 8\n").unwrap();
+        assert_eq!(
+            "line 6\nline 7\nline 8\n",
+            fs::read_to_string(&path).unwrap()
+        );
+
+        // Keeps the newest ones
+        let mut file = RotatingFile::open(&path, 1).unwrap();
+        for i in 0..ROTATED_FILES + 2 {
+            file.write_line(format!("{}\n", i).as_bytes()).unwrap();
+        }
+        assert!(dir.join(format!("parser.log.{}", ROTATED_FILES)).exists());
+        assert!(!dir
+            .join(format!("parser.log.{}", ROTATED_FILES + 1))
+            .exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
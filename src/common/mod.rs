@@ -1,2 +1,26 @@
+pub mod bech32;
+pub mod bip32;
+pub mod blake2b;
+pub mod bloom;
+pub mod bolt;
+pub mod cashaddr;
+#[cfg(feature = "gzip")]
+pub mod gzip;
+pub mod http;
+pub mod kafka;
 pub mod logger;
+pub mod mmap;
+pub mod msgpack;
+pub mod outputstore;
+pub mod parquet;
+pub mod pgwire;
+pub mod prometheus;
+pub mod resp;
+pub mod secp256k1;
+pub mod signal;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod tdigest;
 pub mod utils;
+#[cfg(feature = "zstd")]
+pub mod zstd;
@@ -0,0 +1,138 @@
+//! MessagePack encoding of JSON values
+
+use serde_json::Value;
+
+/// Encodes a JSON value with the smallest MessagePack representation of each item
+pub fn to_vec(value: &Value) -> Vec<u8> {
+    let mut out = Vec::new();
+    write_value(&mut out, value);
+    out
+}
+
+fn write_value(out: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Null => out.push(0xc0),
+        Value::Bool(false) => out.push(0xc2),
+        Value::Bool(true) => out.push(0xc3),
+        Value::Number(n) => {
+            if let Some(n) = n.as_u64() {
+                write_uint(out, n);
+            } else if let Some(n) = n.as_i64() {
+                write_int(out, n);
+            } else {
+                out.push(0xcb);
+                out.extend_from_slice(&n.as_f64().unwrap_or(f64::NAN).to_be_bytes());
+            }
+        }
+        Value::String(s) => {
+            write_len(out, s.len(), 0xa0, 31, [0xd9, 0xda, 0xdb]);
+            out.extend_from_slice(s.as_bytes());
+        }
+        Value::Array(items) => {
+            write_len(out, items.len(), 0x90, 15, [0, 0xdc, 0xdd]);
+            for item in items {
+                write_value(out, item);
+            }
+        }
+        Value::Object(map) => {
+            write_len(out, map.len(), 0x80, 15, [0, 0xde, 0xdf]);
+            for (key, item) in map {
+                write_len(out, key.len(), 0xa0, 31, [0xd9, 0xda, 0xdb]);
+                out.extend_from_slice(key.as_bytes());
+                write_value(out, item);
+            }
+        }
+    }
+}
+
+fn write_uint(out: &mut Vec<u8>, n: u64) {
+    if n < 0x80 {
+        out.push(n as u8);
+    } else if n <= u8::MAX as u64 {
+        out.extend_from_slice(&[0xcc, n as u8]);
+    } else if n <= u16::MAX as u64 {
+        out.push(0xcd);
+        out.extend_from_slice(&(n as u16).to_be_bytes());
+    } else if n <= u32::MAX as u64 {
+        out.push(0xce);
+        out.extend_from_slice(&(n as u32).to_be_bytes());
+    } else {
+        out.push(0xcf);
+        out.extend_from_slice(&n.to_be_bytes());
+    }
+}
+
+/// Only called for negative numbers
+fn write_int(out: &mut Vec<u8>, n: i64) {
+    if n >= -32 {
+        out.push(n as u8);
+    } else if n >= i8::MIN as i64 {
+        out.extend_from_slice(&[0xd0, n as u8]);
+    } else if n >= i16::MIN as i64 {
+        out.push(0xd1);
+        out.extend_from_slice(&(n as i16).to_be_bytes());
+    } else if n >= i32::MIN as i64 {
+        out.push(0xd2);
+        out.extend_from_slice(&(n as i32).to_be_bytes());
+    } else {
+        out.push(0xd3);
+        out.extend_from_slice(&n.to_be_bytes());
+    }
+}
+
+/// Writes the fix type if `len` fits, otherwise the 8, 16 or 32 bit variant (0: not available)
+fn write_len(out: &mut Vec<u8>, len: usize, fix: u8, fix_max: usize, markers: [u8; 3]) {
+    if len <= fix_max {
+        out.push(fix | len as u8);
+    } else if len <= u8::MAX as usize && markers[0] != 0 {
+        out.extend_from_slice(&[markers[0], len as u8]);
+    } else if len <= u16::MAX as usize {
+        out.push(markers[1]);
+        out.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        out.push(markers[2]);
+        out.extend_from_slice(&(len as u32).to_be_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_scalars() {
+        assert_eq!(vec![0xc0], to_vec(&Value::Null));
+        assert_eq!(vec![0xc3], to_vec(&json!(true)));
+        assert_eq!(vec![0x7f], to_vec(&json!(127)));
+        assert_eq!(vec![0xcc, 0x80], to_vec(&json!(128)));
+        assert_eq!(vec![0xcd, 0x01, 0x00], to_vec(&json!(256)));
+        assert_eq!(
+            vec![0xcf, 0, 0, 0, 0x01, 0x2a, 0x05, 0xf2, 0x00],
+            to_vec(&json!(5000000000u64))
+        );
+        assert_eq!(vec![0xff], to_vec(&json!(-1)));
+        assert_eq!(vec![0xd0, 0xdf], to_vec(&json!(-33)));
+        assert_eq!(vec![0xd2, 0xff, 0xfe, 0x79, 0x60], to_vec(&json!(-100000)));
+        assert_eq!(
+            vec![0xcb, 0x3f, 0xf8, 0, 0, 0, 0, 0, 0],
+            to_vec(&json!(1.5))
+        );
+    }
+
+    #[test]
+    fn test_containers() {
+        assert_eq!(
+            vec![0x82, 0xa1, b'a', 0x01, 0xa1, b'b', 0x92, 0xc2, 0xa0],
+            to_vec(&json!({"a": 1, "b": [false, ""]}))
+        );
+        let long = "x".repeat(40);
+        let encoded = to_vec(&json!(long));
+        assert_eq!(&[0xd9, 40], &encoded[..2]);
+        assert_eq!(42, encoded.len());
+
+        let array = Value::Array(vec![Value::Null; 16]);
+        assert_eq!(&[0xdc, 0x00, 0x10], &to_vec(&array)[..3]);
+    }
+}
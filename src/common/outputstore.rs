@@ -0,0 +1,429 @@
+//! Map of transaction outputs which is kept in memory or, when a folder is given,
+//! spilled into a file sorted by key once the in-memory buffer is full.
+//!
+//! The file is rewritten by merging it with the buffer in key order, spent outputs are kept as
+//! tombstones in the buffer until the next merge. Every `INDEX_INTERVAL`th key of the file is
+//! kept in memory, so a lookup reads at most `INDEX_INTERVAL` records.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use byteorder::ReadBytesExt;
+use clap::{Arg, ArgMatches};
+
+use crate::blockchain::proto::tx::TxOutpoint;
+use crate::blockchain::proto::ToRaw;
+use crate::errors::{OpError, OpResult};
+
+/// Outputs and spends kept in memory before they are merged into the file
+pub const DEFAULT_BUFFER_SIZE: usize = 20000000;
+const INDEX_INTERVAL: usize = 256;
+const FILE_NAME: &str = "outputs.dat";
+
+/// Outpoint as stored by `TxOutpoint::to_bytes()`: txid + little endian index
+pub type Key = [u8; 36];
+
+pub fn outpoint_key(outpoint: &TxOutpoint) -> Key {
+    let mut key = [0u8; 36];
+    key.copy_from_slice(&outpoint.to_bytes());
+    key
+}
+
+pub struct OutputStore {
+    buffer: HashMap<Key, Option<Box<[u8]>>>,
+    spill_dir: Option<PathBuf>,
+    buffer_size: usize,
+    // Open while the file exists
+    reader: Option<BufReader<File>>,
+    // Every INDEX_INTERVAL'th key of the file and its offset
+    index: Vec<(Key, u64)>,
+}
+
+impl OutputStore {
+    /// Creates a store which spills into `spill_dir` after `buffer_size` entries.
+    /// The folder must not exist, it's created and removed again by `destroy()`.
+    pub fn open(spill_dir: Option<&Path>, buffer_size: usize) -> OpResult<Self> {
+        if let Some(path) = spill_dir {
+            if path.exists() {
+                return Err(OpError::from(format!("{} already exists.", path.display())));
+            }
+            fs::create_dir_all(path)?;
+        }
+        Ok(OutputStore {
+            buffer: HashMap::with_capacity(buffer_size.min(DEFAULT_BUFFER_SIZE)),
+            spill_dir: spill_dir.map(PathBuf::from),
+            buffer_size,
+            reader: None,
+            index: Vec::new(),
+        })
+    }
+
+    /// `--db-path` and `--buffer-size` of the callbacks keeping their outputs in a store,
+    /// see `from_matches()`
+    pub fn args<'a, 'b>() -> [Arg<'a, 'b>; 2] {
+        [
+            Arg::with_name("db-path")
+                .long("db-path")
+                .value_name("PATH")
+                .help("Spill the outputs to a new folder when the buffer is full, removed at the end")
+                .takes_value(true),
+            Arg::with_name("buffer-size")
+                .long("buffer-size")
+                .value_name("N_OUTPUTS")
+                .help("Outputs and spends kept in memory before spilling to --db-path (default: 20000000)")
+                .takes_value(true),
+        ]
+    }
+
+    /// `--db-backend` in front of `args()`, for callbacks which keep their outputs in a plain map
+    /// unless told to spill them, see `backend_from_matches()`
+    pub fn backend_args<'a, 'b>() -> [Arg<'a, 'b>; 3] {
+        let [db_path, buffer_size] = OutputStore::args();
+        [
+            Arg::with_name("db-backend")
+                .long("db-backend")
+                .value_name("BACKEND")
+                .help("Keeps the unspent outputs in memory or spills them to --db-path (default: memory)")
+                .possible_values(&["memory", "disk"])
+                .takes_value(true),
+            db_path.help("New folder for the unspent outputs of --db-backend disk, removed at the end"),
+            buffer_size,
+        ]
+    }
+
+    /// Opens the store given by the arguments of `args()`, it spills only with `--db-path`
+    pub fn from_matches(matches: &ArgMatches) -> OpResult<Self> {
+        OutputStore::open(
+            matches.value_of("db-path").map(Path::new),
+            OutputStore::buffer_size(matches)?,
+        )
+        .map_err(|e| e.join_msg("Invalid --db-path."))
+    }
+
+    /// Opens the store given by the arguments of `backend_args()`, None for the memory backend
+    pub fn backend_from_matches(matches: &ArgMatches) -> OpResult<Option<Self>> {
+        // Checked for both backends, so a typo doesn't go unnoticed
+        OutputStore::buffer_size(matches)?;
+        match (matches.value_of("db-backend"), matches.value_of("db-path")) {
+            (Some("disk"), Some(_)) => Ok(Some(OutputStore::from_matches(matches)?)),
+            (Some("disk"), None) => Err(OpError::from(String::from(
+                "--db-backend disk requires --db-path.",
+            ))),
+            (_, Some(_)) => Err(OpError::from(String::from(
+                "--db-path requires --db-backend disk.",
+            ))),
+            _ => Ok(None),
+        }
+    }
+
+    /// Value of `--buffer-size`, `DEFAULT_BUFFER_SIZE` if it isn't given
+    pub fn buffer_size(matches: &ArgMatches) -> OpResult<usize> {
+        match matches.value_of("buffer-size") {
+            Some(_) => match value_t!(matches, "buffer-size", usize) {
+                Ok(n) if n > 0 => Ok(n),
+                _ => Err(OpError::from(String::from(
+                    "--buffer-size must be a positive number of outputs.",
+                ))),
+            },
+            None => Ok(DEFAULT_BUFFER_SIZE),
+        }
+    }
+
+    pub fn insert(&mut self, key: Key, value: Vec<u8>) -> OpResult<()> {
+        self.buffer.insert(key, Some(value.into_boxed_slice()));
+        self.spill_if_full()
+    }
+
+    pub fn remove(&mut self, key: &Key) -> OpResult<()> {
+        if self.reader.is_some() {
+            self.buffer.insert(*key, None);
+            self.spill_if_full()
+        } else {
+            self.buffer.remove(key);
+            Ok(())
+        }
+    }
+
+    /// Returns the value of the given output if it is stored
+    pub fn get(&mut self, key: &Key) -> OpResult<Option<Vec<u8>>> {
+        match self.buffer.get(key) {
+            Some(value) => Ok(value.as_ref().map(|value| value.to_vec())),
+            None => self.lookup(key),
+        }
+    }
+
+    /// Removes the given output and returns its value if it was stored
+    pub fn take(&mut self, key: &Key) -> OpResult<Option<Vec<u8>>> {
+        let value = self.get(key)?;
+        if value.is_some() {
+            self.remove(key)?;
+        }
+        Ok(value)
+    }
+
+    /// Calls `f` with key and value of each output, in key order if there is a spill folder
+    pub fn for_each<F>(&self, mut f: F) -> OpResult<()>
+    where
+        F: FnMut(&[u8], &[u8]) -> OpResult<()>,
+    {
+        if self.spill_dir.is_none() {
+            for (key, value) in self.buffer.iter() {
+                if let Some(value) = value {
+                    f(key, value)?;
+                }
+            }
+            return Ok(());
+        }
+
+        let mut keys: Vec<&Key> = self.buffer.keys().collect();
+        keys.sort_unstable();
+        let mut reader: Box<dyn BufRead> = if self.reader.is_some() {
+            Box::new(BufReader::with_capacity(
+                4000000,
+                File::open(self.file_path())?,
+            ))
+        } else {
+            Box::new(io::empty())
+        };
+        let mut stored = read_record(&mut reader)?;
+        for key in keys {
+            while let Some((stored_key, value)) = &stored {
+                if stored_key > key {
+                    break;
+                }
+                // Entries of the buffer replace the stored ones
+                if stored_key < key {
+                    f(stored_key, value)?;
+                }
+                stored = read_record(&mut reader)?;
+            }
+            if let Some(value) = &self.buffer[key] {
+                f(key, value)?;
+            }
+        }
+        while let Some((stored_key, value)) = &stored {
+            f(stored_key, value)?;
+            stored = read_record(&mut reader)?;
+        }
+        Ok(())
+    }
+
+    /// Frees the memory and deletes the spill folder
+    pub fn destroy(self) -> OpResult<()> {
+        if let Some(path) = &self.spill_dir {
+            drop(self.reader);
+            fs::remove_dir_all(path)?;
+        }
+        Ok(())
+    }
+
+    fn file_path(&self) -> PathBuf {
+        self.spill_dir.as_ref().unwrap().join(FILE_NAME)
+    }
+
+    /// Looks up an output which is not buffered in the file
+    fn lookup(&mut self, key: &Key) -> OpResult<Option<Vec<u8>>> {
+        let reader = match self.reader.as_mut() {
+            Some(reader) => reader,
+            None => return Ok(None),
+        };
+        let offset = match self
+            .index
+            .partition_point(|(index_key, _)| index_key <= key)
+        {
+            0 => return Ok(None),
+            n => self.index[n - 1].1,
+        };
+        reader.seek(SeekFrom::Start(offset))?;
+        for _ in 0..INDEX_INTERVAL {
+            match read_record(reader)? {
+                Some((stored_key, value)) if &stored_key == key => return Ok(Some(value)),
+                Some((stored_key, _)) if &stored_key < key => continue,
+                _ => break,
+            }
+        }
+        Ok(None)
+    }
+
+    fn spill_if_full(&mut self) -> OpResult<()> {
+        if self.spill_dir.is_none() || self.buffer.len() < self.buffer_size {
+            return Ok(());
+        }
+        let path = self.file_path();
+        let tmp_path = path.with_extension("dat.tmp");
+        let mut writer = BufWriter::with_capacity(4000000, File::create(&tmp_path)?);
+        let mut index = Vec::new();
+        let (mut n_records, mut offset) = (0usize, 0u64);
+        self.for_each(|key, value| {
+            if n_records % INDEX_INTERVAL == 0 {
+                let mut index_key = [0u8; 36];
+                index_key.copy_from_slice(key);
+                index.push((index_key, offset));
+            }
+            let mut record = Vec::with_capacity(key.len() + value.len() + 2);
+            record.extend_from_slice(key);
+            write_varint(&mut record, value.len() as u64);
+            record.extend_from_slice(value);
+            writer.write_all(&record)?;
+            n_records += 1;
+            offset += record.len() as u64;
+            Ok(())
+        })?;
+        writer.flush()?;
+        drop(writer);
+        self.reader = None;
+        fs::rename(&tmp_path, &path)?;
+        self.reader = Some(BufReader::with_capacity(64 * 1024, File::open(&path)?));
+        self.index = index;
+        self.buffer.clear();
+        debug!(target: "callback", "Merged {} buffered outputs into {} ({} stored)", self.buffer_size, path.display(), n_records);
+        Ok(())
+    }
+}
+
+/// Reads key and value of the next record written by `OutputStore::spill_if_full()`
+fn read_record<R: BufRead + ?Sized>(reader: &mut R) -> OpResult<Option<(Key, Vec<u8>)>> {
+    if reader.fill_buf()?.is_empty() {
+        return Ok(None);
+    }
+    let mut key = [0u8; 36];
+    reader.read_exact(&mut key)?;
+    let mut value = vec![0u8; read_varint(reader)? as usize];
+    reader.read_exact(&mut value)?;
+    Ok(Some((key, value)))
+}
+
+/// Unsigned LEB128
+pub fn write_varint(out: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        out.push((n as u8 & 0x7f) | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+pub fn read_varint<R: Read + ?Sized>(reader: &mut R) -> OpResult<u64> {
+    let mut n = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = reader.read_u8()?;
+        n |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(n);
+        }
+    }
+    Err(OpError::from(String::from("Invalid varint.")))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+
+    fn key(n: u32) -> Key {
+        let mut key = [0u8; 36];
+        key[..4].copy_from_slice(&n.to_be_bytes());
+        key
+    }
+
+    #[test]
+    fn test_varint() {
+        for n in [0, 1, 127, 128, 300, u32::MAX as u64, u64::MAX] {
+            let mut data = Vec::new();
+            write_varint(&mut data, n);
+            let mut slice = &data[..];
+            assert_eq!(n, read_varint(&mut slice).unwrap());
+            assert!(slice.is_empty());
+        }
+        let mut data = Vec::new();
+        write_varint(&mut data, 300);
+        assert_eq!(vec![0xac, 0x02], data);
+        assert!(read_varint(&mut &[0x80u8][..]).is_err());
+    }
+
+    #[test]
+    fn test_spill() {
+        let dir = env::temp_dir().join(format!("blockparser-outputstore-{}", process::id()));
+        let mut store = OutputStore::open(Some(&dir), 100).unwrap();
+        assert!(OutputStore::open(Some(&dir), 100).is_err());
+        for n in 0..1000 {
+            store.insert(key(n), n.to_le_bytes().to_vec()).unwrap();
+        }
+        assert!(store.reader.is_some());
+        assert_eq!(4, store.index.len());
+        for n in (0..1000).step_by(2) {
+            store.remove(&key(n)).unwrap();
+        }
+        assert_eq!(None, store.take(&key(10)).unwrap());
+        assert_eq!(
+            Some(11u32.to_le_bytes().to_vec()),
+            store.get(&key(11)).unwrap()
+        );
+        assert_eq!(
+            Some(11u32.to_le_bytes().to_vec()),
+            store.take(&key(11)).unwrap()
+        );
+        assert_eq!(None, store.take(&key(11)).unwrap());
+        assert_eq!(
+            Some(999u32.to_le_bytes().to_vec()),
+            store.take(&key(999)).unwrap()
+        );
+        assert_eq!(None, store.take(&key(1000)).unwrap());
+
+        let mut keys = Vec::new();
+        store
+            .for_each(|key, value| {
+                assert_eq!(
+                    &key[..4],
+                    &(u32::from_le_bytes(value.try_into().unwrap())).to_be_bytes()
+                );
+                keys.push(u32::from_be_bytes(key[..4].try_into().unwrap()));
+                Ok(())
+            })
+            .unwrap();
+        let expected: Vec<u32> = (1..999).step_by(2).filter(|n| *n != 11).collect();
+        assert_eq!(expected, keys);
+        store.destroy().unwrap();
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn test_memory() {
+        let mut store = OutputStore::open(None, 1).unwrap();
+        store.insert(key(1), vec![1]).unwrap();
+        store.insert(key(2), vec![2]).unwrap();
+        store.remove(&key(1)).unwrap();
+        assert_eq!(1, store.buffer.len());
+        assert_eq!(Some(vec![2]), store.take(&key(2)).unwrap());
+        assert!(store.buffer.is_empty());
+    }
+
+    #[test]
+    fn test_from_matches() {
+        let app = || clap::App::new("test").args(&OutputStore::args());
+        let store = OutputStore::from_matches(&app().get_matches_from(vec!["test"])).unwrap();
+        assert_eq!(DEFAULT_BUFFER_SIZE, store.buffer_size);
+        assert!(store.spill_dir.is_none());
+
+        let dir = env::temp_dir().join(format!("blockparser-outputstore-args-{}", process::id()));
+        let matches = app().get_matches_from(vec![
+            "test",
+            "--db-path",
+            dir.to_str().unwrap(),
+            "--buffer-size",
+            "1",
+        ]);
+        let store = OutputStore::from_matches(&matches).unwrap();
+        assert_eq!(1, store.buffer_size);
+        assert_eq!(Some(&dir), store.spill_dir.as_ref());
+        store.destroy().unwrap();
+
+        for size in ["0", "ten"] {
+            let matches = app().get_matches_from(vec!["test", "--buffer-size", size]);
+            assert!(OutputStore::from_matches(&matches).is_err());
+        }
+    }
+}
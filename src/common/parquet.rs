@@ -0,0 +1,1192 @@
+//! Minimal Apache Parquet writer for flat schemas.
+//!
+//! Every column chunk consists of version 1 data pages with PLAIN encoded values,
+//! definition levels of optional columns are RLE encoded. No dictionaries, no statistics.
+//! The footer is written with the Thrift compact protocol as described in `parquet.thrift`.
+//!
+//! The `parquet` crate isn't used because it pulls in the arrow crates and its own zlib and
+//! zstd bindings, while the other callbacks link gzip and zstd from the system libraries.
+//! The tests read the files back with pyarrow when it's installed.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::mem;
+use std::path::Path;
+use std::str::FromStr;
+
+#[cfg(feature = "gzip")]
+use crate::common::gzip::GzWriter;
+#[cfg(feature = "zstd")]
+use crate::common::zstd::ZstdWriter;
+
+const MAGIC: &[u8; 4] = b"PAR1";
+const CREATED_BY: &str = concat!("rusty-blockparser version ", env!("CARGO_PKG_VERSION"));
+// Uncompressed size after which a data page is completed
+const PAGE_SIZE: usize = 1024 * 1024;
+
+// parquet.thrift enums
+const TYPE_BOOLEAN: i32 = 0;
+const TYPE_INT32: i32 = 1;
+const TYPE_INT64: i32 = 2;
+const TYPE_BYTE_ARRAY: i32 = 6;
+const TYPE_FIXED_LEN_BYTE_ARRAY: i32 = 7;
+const REPETITION_REQUIRED: i32 = 0;
+const REPETITION_OPTIONAL: i32 = 1;
+const CONVERTED_UTF8: i32 = 0;
+const CONVERTED_UINT_32: i32 = 13;
+const CONVERTED_UINT_64: i32 = 14;
+const ENCODING_PLAIN: i32 = 0;
+const ENCODING_RLE: i32 = 3;
+const PAGE_TYPE_DATA: i32 = 0;
+
+/// Logical column types and how they are stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColumnType {
+    Bool,
+    /// INT32 annotated as UINT_32
+    UInt32,
+    /// INT64 annotated as UINT_64
+    UInt64,
+    Int64,
+    /// FIXED_LEN_BYTE_ARRAY of 32 bytes
+    Hash,
+    /// BYTE_ARRAY
+    Binary,
+    /// BYTE_ARRAY annotated as UTF8
+    Utf8,
+}
+
+impl ColumnType {
+    fn physical_type(&self) -> i32 {
+        match self {
+            ColumnType::Bool => TYPE_BOOLEAN,
+            ColumnType::UInt32 => TYPE_INT32,
+            ColumnType::UInt64 | ColumnType::Int64 => TYPE_INT64,
+            ColumnType::Hash => TYPE_FIXED_LEN_BYTE_ARRAY,
+            ColumnType::Binary | ColumnType::Utf8 => TYPE_BYTE_ARRAY,
+        }
+    }
+
+    fn converted_type(&self) -> Option<i32> {
+        match self {
+            ColumnType::UInt32 => Some(CONVERTED_UINT_32),
+            ColumnType::UInt64 => Some(CONVERTED_UINT_64),
+            ColumnType::Utf8 => Some(CONVERTED_UTF8),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Column {
+    pub name: &'static str,
+    pub column_type: ColumnType,
+    pub optional: bool,
+}
+
+impl Column {
+    pub const fn required(name: &'static str, column_type: ColumnType) -> Column {
+        Column {
+            name,
+            column_type,
+            optional: false,
+        }
+    }
+
+    pub const fn optional(name: &'static str, column_type: ColumnType) -> Column {
+        Column {
+            name,
+            column_type,
+            optional: true,
+        }
+    }
+}
+
+/// Field of a row, has to match the type of its column
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value<'a> {
+    Null,
+    Bool(bool),
+    UInt32(u32),
+    UInt64(u64),
+    Int64(i64),
+    Bytes(&'a [u8]),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Snappy,
+    #[cfg(feature = "gzip")]
+    Gzip,
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl Compression {
+    /// CompressionCodec in parquet.thrift
+    fn codec(&self) -> i32 {
+        match self {
+            Compression::None => 0,
+            Compression::Snappy => 1,
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => 2,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => 6,
+        }
+    }
+
+    fn compress(&self, data: Vec<u8>) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data),
+            Compression::Snappy => snap::raw::Encoder::new()
+                .compress_vec(&data)
+                .map_err(io::Error::other),
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => {
+                let mut encoder = GzWriter::new(Vec::new(), 6)?;
+                encoder.write_all(&data)?;
+                encoder.finish()
+            }
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => {
+                let mut encoder = ZstdWriter::new(Vec::new(), 3)?;
+                encoder.write_all(&data)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "snappy" => Ok(Compression::Snappy),
+            #[cfg(feature = "gzip")]
+            "gzip" => Ok(Compression::Gzip),
+            #[cfg(feature = "zstd")]
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(format!("unsupported compression: {}", s)),
+        }
+    }
+}
+
+/// Values of one column in the current row group
+struct ColumnChunk {
+    column: Column,
+    // Completed pages including their headers
+    pages: Vec<u8>,
+    uncompressed_size: usize,
+    num_values: u64,
+    // Current page
+    def_levels: Vec<u8>,
+    values: Vec<u8>,
+    page_values: u32,
+    // Number of booleans in the current page, they are packed into bytes
+    bool_bits: usize,
+}
+
+impl ColumnChunk {
+    fn new(column: Column) -> ColumnChunk {
+        ColumnChunk {
+            column,
+            pages: Vec::new(),
+            uncompressed_size: 0,
+            num_values: 0,
+            def_levels: Vec::new(),
+            values: Vec::new(),
+            page_values: 0,
+            bool_bits: 0,
+        }
+    }
+
+    /// Appends a value which passed `check()`
+    fn push(&mut self, value: &Value) {
+        if self.column.optional {
+            self.def_levels.push((*value != Value::Null) as u8);
+        }
+        match value {
+            Value::Null => {}
+            Value::Bool(bit) => {
+                if self.bool_bits.is_multiple_of(8) {
+                    self.values.push(0);
+                }
+                *self.values.last_mut().unwrap() |= (*bit as u8) << (self.bool_bits % 8);
+                self.bool_bits += 1;
+            }
+            Value::UInt32(value) => self.values.extend_from_slice(&value.to_le_bytes()),
+            Value::UInt64(value) => self.values.extend_from_slice(&value.to_le_bytes()),
+            Value::Int64(value) => self.values.extend_from_slice(&value.to_le_bytes()),
+            Value::Bytes(bytes) => {
+                if self.column.column_type != ColumnType::Hash {
+                    self.values
+                        .extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+                }
+                self.values.extend_from_slice(bytes);
+            }
+        }
+        self.page_values += 1;
+        self.num_values += 1;
+    }
+
+    /// Returns an error if the value doesn't match the column
+    fn check(&self, value: &Value) -> io::Result<()> {
+        let valid = match (self.column.column_type, value) {
+            (_, Value::Null) => self.column.optional,
+            (ColumnType::Bool, Value::Bool(_))
+            | (ColumnType::UInt32, Value::UInt32(_))
+            | (ColumnType::UInt64, Value::UInt64(_))
+            | (ColumnType::Int64, Value::Int64(_))
+            | (ColumnType::Binary, Value::Bytes(_))
+            | (ColumnType::Utf8, Value::Bytes(_)) => true,
+            (ColumnType::Hash, Value::Bytes(bytes)) => bytes.len() == 32,
+            _ => false,
+        };
+        if !valid {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "invalid value for column `{}` ({:?}): {:?}",
+                    self.column.name, self.column.column_type, value
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Current size of the chunk, uncompressed for the open page
+    fn buffered_size(&self) -> usize {
+        self.pages.len() + self.values.len() + self.def_levels.len()
+    }
+
+    /// Compresses the current page and appends it to the completed pages
+    fn finish_page(&mut self, compression: Compression) -> io::Result<()> {
+        if self.page_values == 0 {
+            return Ok(());
+        }
+        let mut data = Vec::with_capacity(self.values.len() + self.def_levels.len() / 4 + 4);
+        if self.column.optional {
+            let levels = rle_encode(&self.def_levels);
+            data.extend_from_slice(&(levels.len() as u32).to_le_bytes());
+            data.extend_from_slice(&levels);
+        }
+        data.extend_from_slice(&self.values);
+        let uncompressed_size = data.len();
+        let compressed = compression.compress(data)?;
+
+        let mut header = Thrift::new();
+        header.i32_field(1, PAGE_TYPE_DATA);
+        header.i32_field(2, uncompressed_size as i32);
+        header.i32_field(3, compressed.len() as i32);
+        let num_values = self.page_values as i32;
+        header.struct_field(5, |t| {
+            t.i32_field(1, num_values);
+            t.i32_field(2, ENCODING_PLAIN);
+            t.i32_field(3, ENCODING_RLE);
+            t.i32_field(4, ENCODING_RLE);
+        });
+        let header = header.finish();
+
+        self.uncompressed_size += header.len() + uncompressed_size;
+        self.pages.extend_from_slice(&header);
+        self.pages.extend_from_slice(&compressed);
+        self.def_levels.clear();
+        self.values.clear();
+        self.page_values = 0;
+        self.bool_bits = 0;
+        Ok(())
+    }
+}
+
+/// Location and size of a written column chunk
+struct ChunkMeta {
+    offset: u64,
+    num_values: u64,
+    uncompressed_size: usize,
+    compressed_size: usize,
+}
+
+struct RowGroupMeta {
+    num_rows: u64,
+    chunks: Vec<ChunkMeta>,
+}
+
+/// Writes rows into a single Parquet file.
+/// Rows are buffered until the row group reaches `row_group_size` bytes (compressed),
+/// `close()` needs to be called to write the footer.
+pub struct FileWriter {
+    writer: BufWriter<File>,
+    schema: &'static [Column],
+    compression: Compression,
+    row_group_size: usize,
+    offset: u64,
+    chunks: Vec<ColumnChunk>,
+    buffered_rows: u64,
+    row_groups: Vec<RowGroupMeta>,
+}
+
+impl FileWriter {
+    pub fn create(
+        path: &Path,
+        schema: &'static [Column],
+        compression: Compression,
+        row_group_size: usize,
+    ) -> io::Result<FileWriter> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(MAGIC)?;
+        Ok(FileWriter {
+            writer,
+            schema,
+            compression,
+            row_group_size,
+            offset: MAGIC.len() as u64,
+            chunks: schema.iter().map(|c| ColumnChunk::new(*c)).collect(),
+            buffered_rows: 0,
+            row_groups: Vec::new(),
+        })
+    }
+
+    /// Number of rows written so far
+    pub fn num_rows(&self) -> u64 {
+        self.row_groups.iter().map(|g| g.num_rows).sum::<u64>() + self.buffered_rows
+    }
+
+    pub fn write_row(&mut self, row: &[Value]) -> io::Result<()> {
+        if row.len() != self.schema.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("expected {} values, got {}", self.schema.len(), row.len()),
+            ));
+        }
+        // Validate first, so that a rejected row doesn't leave the columns misaligned
+        for (chunk, value) in self.chunks.iter_mut().zip(row) {
+            chunk.check(value)?;
+        }
+        let mut size = 0;
+        for (chunk, value) in self.chunks.iter_mut().zip(row) {
+            chunk.push(value);
+            if chunk.values.len() + chunk.def_levels.len() >= PAGE_SIZE {
+                chunk.finish_page(self.compression)?;
+            }
+            size += chunk.buffered_size();
+        }
+        self.buffered_rows += 1;
+        if size >= self.row_group_size {
+            self.flush_row_group()?;
+        }
+        Ok(())
+    }
+
+    /// Writes all buffered rows as a row group
+    pub fn flush_row_group(&mut self) -> io::Result<()> {
+        if self.buffered_rows == 0 {
+            return Ok(());
+        }
+        let mut metas = Vec::with_capacity(self.chunks.len());
+        for chunk in &mut self.chunks {
+            chunk.finish_page(self.compression)?;
+            let pages = mem::take(&mut chunk.pages);
+            self.writer.write_all(&pages)?;
+            metas.push(ChunkMeta {
+                offset: self.offset,
+                num_values: chunk.num_values,
+                uncompressed_size: chunk.uncompressed_size,
+                compressed_size: pages.len(),
+            });
+            self.offset += pages.len() as u64;
+            chunk.num_values = 0;
+            chunk.uncompressed_size = 0;
+        }
+        self.row_groups.push(RowGroupMeta {
+            num_rows: self.buffered_rows,
+            chunks: metas,
+        });
+        self.buffered_rows = 0;
+        Ok(())
+    }
+
+    /// Writes the remaining rows and the footer and syncs the file to disk
+    pub fn close(mut self) -> io::Result<()> {
+        self.flush_row_group()?;
+        let footer = self.file_metadata();
+        self.writer.write_all(&footer)?;
+        self.writer
+            .write_all(&(footer.len() as u32).to_le_bytes())?;
+        self.writer.write_all(MAGIC)?;
+        let file = self.writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()
+    }
+
+    /// Encodes FileMetaData
+    fn file_metadata(&self) -> Vec<u8> {
+        let mut t = Thrift::new();
+        t.i32_field(1, 1);
+        t.list_header(2, THRIFT_STRUCT, self.schema.len() + 1);
+        t.nested(|t| {
+            t.binary_field(4, b"schema");
+            t.i32_field(5, self.schema.len() as i32);
+        });
+        for column in self.schema {
+            let column_type = column.column_type;
+            t.nested(|t| {
+                t.i32_field(1, column_type.physical_type());
+                if column_type == ColumnType::Hash {
+                    t.i32_field(2, 32);
+                }
+                let repetition = match column.optional {
+                    true => REPETITION_OPTIONAL,
+                    false => REPETITION_REQUIRED,
+                };
+                t.i32_field(3, repetition);
+                t.binary_field(4, column.name.as_bytes());
+                if let Some(converted_type) = column_type.converted_type() {
+                    t.i32_field(6, converted_type);
+                }
+            });
+        }
+        t.i64_field(3, self.num_rows() as i64);
+        t.list_header(4, THRIFT_STRUCT, self.row_groups.len());
+        for row_group in &self.row_groups {
+            t.nested(|t| {
+                t.list_header(1, THRIFT_STRUCT, row_group.chunks.len());
+                for (column, chunk) in self.schema.iter().zip(&row_group.chunks) {
+                    t.nested(|t| {
+                        t.i64_field(2, chunk.offset as i64);
+                        t.struct_field(3, |t| {
+                            t.i32_field(1, column.column_type.physical_type());
+                            t.list_header(2, THRIFT_I32, 2);
+                            t.list_i32(ENCODING_PLAIN);
+                            t.list_i32(ENCODING_RLE);
+                            t.list_header(3, THRIFT_BINARY, 1);
+                            t.list_binary(column.name.as_bytes());
+                            t.i32_field(4, self.compression.codec());
+                            t.i64_field(5, chunk.num_values as i64);
+                            t.i64_field(6, chunk.uncompressed_size as i64);
+                            t.i64_field(7, chunk.compressed_size as i64);
+                            t.i64_field(9, chunk.offset as i64);
+                        });
+                    });
+                }
+                let total_size: usize = row_group.chunks.iter().map(|c| c.uncompressed_size).sum();
+                t.i64_field(2, total_size as i64);
+                t.i64_field(3, row_group.num_rows as i64);
+            });
+        }
+        t.binary_field(6, CREATED_BY.as_bytes());
+        t.finish()
+    }
+}
+
+/// Encodes levels of bit width 1 as RLE runs of the RLE/bit-packing hybrid
+fn rle_encode(levels: &[u8]) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    let mut i = 0;
+    while i < levels.len() {
+        let value = levels[i];
+        let run = levels[i..].iter().take_while(|l| **l == value).count();
+        write_varint(&mut encoded, (run as u64) << 1);
+        encoded.push(value);
+        i += run;
+    }
+    encoded
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+// Thrift compact protocol types
+const THRIFT_STOP: u8 = 0;
+const THRIFT_I32: u8 = 5;
+const THRIFT_I64: u8 = 6;
+const THRIFT_BINARY: u8 = 8;
+const THRIFT_LIST: u8 = 9;
+const THRIFT_STRUCT: u8 = 12;
+
+/// Encoder for the Thrift compact protocol, starts with an open struct
+struct Thrift {
+    buf: Vec<u8>,
+    // Id of the previous field in the current struct, field ids are delta encoded
+    last_id: i16,
+}
+
+impl Thrift {
+    fn new() -> Thrift {
+        Thrift {
+            buf: Vec::new(),
+            last_id: 0,
+        }
+    }
+
+    /// Closes the outermost struct
+    fn finish(mut self) -> Vec<u8> {
+        self.buf.push(THRIFT_STOP);
+        self.buf
+    }
+
+    fn field_header(&mut self, id: i16, field_type: u8) {
+        let delta = id - self.last_id;
+        if delta > 0 && delta <= 15 {
+            self.buf.push((delta as u8) << 4 | field_type);
+        } else {
+            self.buf.push(field_type);
+            write_varint(&mut self.buf, zigzag(id as i64));
+        }
+        self.last_id = id;
+    }
+
+    fn i32_field(&mut self, id: i16, value: i32) {
+        self.field_header(id, THRIFT_I32);
+        write_varint(&mut self.buf, zigzag(value as i64));
+    }
+
+    fn i64_field(&mut self, id: i16, value: i64) {
+        self.field_header(id, THRIFT_I64);
+        write_varint(&mut self.buf, zigzag(value));
+    }
+
+    fn binary_field(&mut self, id: i16, value: &[u8]) {
+        self.field_header(id, THRIFT_BINARY);
+        self.list_binary(value);
+    }
+
+    fn struct_field<F: FnOnce(&mut Self)>(&mut self, id: i16, f: F) {
+        self.field_header(id, THRIFT_STRUCT);
+        self.nested(f);
+    }
+
+    /// Writes a struct with the fields added by `f`, either as field or list element
+    fn nested<F: FnOnce(&mut Self)>(&mut self, f: F) {
+        let last_id = mem::replace(&mut self.last_id, 0);
+        f(self);
+        self.buf.push(THRIFT_STOP);
+        self.last_id = last_id;
+    }
+
+    /// Starts a list field, followed by `len` elements
+    fn list_header(&mut self, id: i16, element_type: u8, len: usize) {
+        self.field_header(id, THRIFT_LIST);
+        if len < 15 {
+            self.buf.push((len as u8) << 4 | element_type);
+        } else {
+            self.buf.push(0xf0 | element_type);
+            write_varint(&mut self.buf, len as u64);
+        }
+    }
+
+    fn list_i32(&mut self, value: i32) {
+        write_varint(&mut self.buf, zigzag(value as i64));
+    }
+
+    fn list_binary(&mut self, value: &[u8]) {
+        write_varint(&mut self.buf, value.len() as u64);
+        self.buf.extend_from_slice(value);
+    }
+}
+
+#[inline]
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+/// Field of a row read back by `read_file()`
+#[cfg(test)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum Field {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Bytes(Vec<u8>),
+}
+
+/// Columns and rows of a Parquet file written by `FileWriter`
+#[cfg(test)]
+pub struct Table {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<Field>>,
+    pub num_row_groups: usize,
+}
+
+#[cfg(test)]
+impl Table {
+    /// Returns all values of the given column
+    pub fn column(&self, name: &str) -> Vec<Field> {
+        let index = self.columns.iter().position(|c| c == name).unwrap();
+        self.rows.iter().map(|row| row[index].clone()).collect()
+    }
+}
+
+/// Reads a file with PLAIN encoded data pages, panics on anything unexpected
+#[cfg(test)]
+pub fn read_file(path: &Path) -> Table {
+    let data = std::fs::read(path).unwrap();
+    assert_eq!(MAGIC, &data[..4]);
+    assert_eq!(MAGIC, &data[data.len() - 4..]);
+    let footer_len = u32::from_le_bytes(data[data.len() - 8..data.len() - 4].try_into().unwrap());
+    let footer_start = data.len() - 8 - footer_len as usize;
+    let (metadata, _) = thrift_decode::read(&data[footer_start..]);
+
+    let schema = metadata.list(2);
+    assert_eq!(schema.len() - 1, schema[0].int(5) as usize);
+    // (name, physical type, type length, optional)
+    let columns: Vec<(String, i64, i64, bool)> = schema[1..]
+        .iter()
+        .map(|e| {
+            let type_length = e.try_field(2).map_or(0, |v| v.as_int());
+            let name = String::from_utf8(e.binary(4)).unwrap();
+            (
+                name,
+                e.int(1),
+                type_length,
+                e.int(3) == REPETITION_OPTIONAL as i64,
+            )
+        })
+        .collect();
+
+    let mut rows: Vec<Vec<Field>> = Vec::new();
+    let row_groups = metadata.list(4);
+    for row_group in &row_groups {
+        let num_rows = row_group.int(3) as usize;
+        let mut group_columns: Vec<Vec<Field>> = Vec::new();
+        for (chunk, column) in row_group.list(1).iter().zip(&columns) {
+            let meta = chunk.field(3);
+            assert_eq!(column.1, meta.int(1));
+            let mut pos = meta.int(9) as usize;
+            let end = pos + meta.int(7) as usize;
+            let mut values = Vec::new();
+            while pos < end {
+                let (header, header_len) = thrift_decode::read(&data[pos..]);
+                pos += header_len;
+                let page_len = header.int(3) as usize;
+                let page = decompress(meta.int(4), &data[pos..pos + page_len]);
+                assert_eq!(header.int(2) as usize, page.len());
+                pos += page_len;
+                let num_values = header.field(5).int(1) as usize;
+                values.extend(decode_page(&page, num_values, column.1, column.2, column.3));
+            }
+            assert_eq!(meta.int(5) as usize, values.len());
+            assert_eq!(num_rows, values.len());
+            group_columns.push(values);
+        }
+        for i in 0..num_rows {
+            rows.push(group_columns.iter().map(|c| c[i].clone()).collect());
+        }
+    }
+    assert_eq!(metadata.int(3) as usize, rows.len());
+    Table {
+        columns: columns.into_iter().map(|c| c.0).collect(),
+        rows,
+        num_row_groups: row_groups.len(),
+    }
+}
+
+#[cfg(test)]
+fn decompress(codec: i64, data: &[u8]) -> Vec<u8> {
+    match codec {
+        0 => data.to_vec(),
+        1 => snap::raw::Decoder::new().decompress_vec(data).unwrap(),
+        #[cfg(feature = "gzip")]
+        2 => crate::common::gzip::gunzip(data),
+        #[cfg(feature = "zstd")]
+        6 => crate::common::zstd::unzstd(data),
+        _ => panic!("unsupported codec {}", codec),
+    }
+}
+
+#[cfg(test)]
+fn decode_page(
+    page: &[u8],
+    num_values: usize,
+    physical_type: i64,
+    type_length: i64,
+    optional: bool,
+) -> Vec<Field> {
+    let (levels, mut data) = if optional {
+        let len = u32::from_le_bytes(page[..4].try_into().unwrap()) as usize;
+        (rle_decode(&page[4..4 + len], num_values), &page[4 + len..])
+    } else {
+        (vec![1; num_values], page)
+    };
+    let mut fields = Vec::with_capacity(num_values);
+    let mut n_bools = 0;
+    for level in levels {
+        if level == 0 {
+            fields.push(Field::Null);
+            continue;
+        }
+        let field = match physical_type as i32 {
+            TYPE_BOOLEAN => {
+                let bit = data[n_bools / 8] >> (n_bools % 8) & 1;
+                n_bools += 1;
+                Field::Bool(bit == 1)
+            }
+            TYPE_INT32 => {
+                let (value, rest) = data.split_at(4);
+                data = rest;
+                Field::Int(u32::from_le_bytes(value.try_into().unwrap()) as i64)
+            }
+            TYPE_INT64 => {
+                let (value, rest) = data.split_at(8);
+                data = rest;
+                Field::Int(i64::from_le_bytes(value.try_into().unwrap()))
+            }
+            TYPE_FIXED_LEN_BYTE_ARRAY => {
+                let (value, rest) = data.split_at(type_length as usize);
+                data = rest;
+                Field::Bytes(value.to_vec())
+            }
+            TYPE_BYTE_ARRAY => {
+                let len = u32::from_le_bytes(data[..4].try_into().unwrap()) as usize;
+                let (value, rest) = data[4..].split_at(len);
+                data = rest;
+                Field::Bytes(value.to_vec())
+            }
+            t => panic!("unsupported type {}", t),
+        };
+        fields.push(field);
+    }
+    if physical_type as i32 != TYPE_BOOLEAN {
+        assert!(data.is_empty(), "{} bytes left in page", data.len());
+    }
+    fields
+}
+
+/// Decodes the RLE/bit-packing hybrid with bit width 1
+#[cfg(test)]
+fn rle_decode(mut data: &[u8], num_values: usize) -> Vec<u8> {
+    let mut levels = Vec::with_capacity(num_values);
+    while levels.len() < num_values {
+        let (header, len) = thrift_decode::varint(data);
+        data = &data[len..];
+        if header & 1 == 0 {
+            levels.extend(std::iter::repeat_n(data[0], (header >> 1) as usize));
+            data = &data[1..];
+        } else {
+            let n_bytes = (header >> 1) as usize;
+            for byte in &data[..n_bytes] {
+                levels.extend((0..8).map(|i| byte >> i & 1));
+            }
+            data = &data[n_bytes..];
+        }
+    }
+    levels.truncate(num_values);
+    levels
+}
+
+/// Generic decoder for the Thrift compact protocol
+#[cfg(test)]
+mod thrift_decode {
+    #[derive(Debug, Clone)]
+    pub enum TValue {
+        // Booleans are returned as 0 or 1
+        Int(i64),
+        Binary(Vec<u8>),
+        List(Vec<TValue>),
+        Struct(Vec<(i16, TValue)>),
+    }
+
+    impl TValue {
+        pub fn try_field(&self, id: i16) -> Option<&TValue> {
+            match self {
+                TValue::Struct(fields) => fields.iter().find(|f| f.0 == id).map(|f| &f.1),
+                _ => panic!("not a struct: {:?}", self),
+            }
+        }
+
+        pub fn field(&self, id: i16) -> &TValue {
+            self.try_field(id)
+                .unwrap_or_else(|| panic!("missing field {}", id))
+        }
+
+        pub fn as_int(&self) -> i64 {
+            match self {
+                TValue::Int(value) => *value,
+                _ => panic!("not an int: {:?}", self),
+            }
+        }
+
+        pub fn int(&self, id: i16) -> i64 {
+            self.field(id).as_int()
+        }
+
+        pub fn binary(&self, id: i16) -> Vec<u8> {
+            match self.field(id) {
+                TValue::Binary(value) => value.clone(),
+                value => panic!("not binary: {:?}", value),
+            }
+        }
+
+        pub fn list(&self, id: i16) -> Vec<TValue> {
+            match self.field(id) {
+                TValue::List(value) => value.clone(),
+                value => panic!("not a list: {:?}", value),
+            }
+        }
+    }
+
+    /// Returns the varint and its length
+    pub fn varint(data: &[u8]) -> (u64, usize) {
+        let mut value = 0u64;
+        for (i, byte) in data.iter().enumerate() {
+            value |= ((byte & 0x7f) as u64) << (7 * i);
+            if byte & 0x80 == 0 {
+                return (value, i + 1);
+            }
+        }
+        panic!("truncated varint");
+    }
+
+    /// Reads a struct, returns it with the number of consumed bytes
+    pub fn read(data: &[u8]) -> (TValue, usize) {
+        let mut decoder = Decoder { data, pos: 0 };
+        let value = decoder.read_struct();
+        (value, decoder.pos)
+    }
+
+    struct Decoder<'a> {
+        data: &'a [u8],
+        pos: usize,
+    }
+
+    impl Decoder<'_> {
+        fn byte(&mut self) -> u8 {
+            self.pos += 1;
+            self.data[self.pos - 1]
+        }
+
+        fn varint(&mut self) -> u64 {
+            let (value, len) = varint(&self.data[self.pos..]);
+            self.pos += len;
+            value
+        }
+
+        fn zigzag(&mut self) -> i64 {
+            let value = self.varint();
+            (value >> 1) as i64 ^ -((value & 1) as i64)
+        }
+
+        fn read_struct(&mut self) -> TValue {
+            let mut fields = Vec::new();
+            let mut last_id = 0i16;
+            loop {
+                let header = self.byte();
+                if header == 0 {
+                    return TValue::Struct(fields);
+                }
+                let id = match header >> 4 {
+                    0 => self.zigzag() as i16,
+                    delta => last_id + delta as i16,
+                };
+                last_id = id;
+                let value = self.read_value(header & 0x0f);
+                fields.push((id, value));
+            }
+        }
+
+        fn read_value(&mut self, value_type: u8) -> TValue {
+            match value_type {
+                1 => TValue::Int(1),
+                2 => TValue::Int(0),
+                3 => TValue::Int(self.byte() as i8 as i64),
+                4..=6 => TValue::Int(self.zigzag()),
+                8 => {
+                    let len = self.varint() as usize;
+                    self.pos += len;
+                    TValue::Binary(self.data[self.pos - len..self.pos].to_vec())
+                }
+                9 | 10 => {
+                    let header = self.byte();
+                    let len = match header >> 4 {
+                        15 => self.varint() as usize,
+                        len => len as usize,
+                    };
+                    let element_type = header & 0x0f;
+                    TValue::List((0..len).map(|_| self.read_value(element_type)).collect())
+                }
+                12 => self.read_struct(),
+                t => panic!("unsupported thrift type {}", t),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process::{self, Command};
+
+    use super::*;
+    use crate::common::utils;
+
+    /// Prints the number of row groups, the Arrow schema and the rows with hex encoded
+    /// bytes and strings of the file in argv[1]
+    const PYARROW_READER: &str = r#"
+import sys
+import pyarrow.parquet as pq
+
+def render(value):
+    if value is None:
+        return "null"
+    if isinstance(value, bool):
+        return str(value).lower()
+    if isinstance(value, str):
+        return value.encode().hex()
+    if isinstance(value, bytes):
+        return value.hex()
+    return str(value)
+
+f = pq.ParquetFile(sys.argv[1])
+print(f.metadata.num_row_groups)
+print(";".join("%s:%s:%s" % (c.name, c.type, c.nullable) for c in f.schema_arrow))
+for row in f.read().to_pylist():
+    print(";".join(render(value) for value in row.values()))
+"#;
+
+    const SCHEMA: [Column; 7] = [
+        Column::required("flag", ColumnType::Bool),
+        Column::required("small", ColumnType::UInt32),
+        Column::required("large", ColumnType::UInt64),
+        Column::required("signed", ColumnType::Int64),
+        Column::required("hash", ColumnType::Hash),
+        Column::optional("blob", ColumnType::Binary),
+        Column::optional("text", ColumnType::Utf8),
+    ];
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        env::temp_dir().join(format!("blockparser-{}-{}.parquet", name, process::id()))
+    }
+
+    fn write_rows(path: &Path, compression: Compression, n_rows: usize, row_group_size: usize) {
+        let mut writer = FileWriter::create(path, &SCHEMA, compression, row_group_size).unwrap();
+        let blob = vec![0xab; 1000];
+        for i in 0..n_rows {
+            let hash = [i as u8; 32];
+            let text = format!("row {}", i);
+            writer
+                .write_row(&[
+                    Value::Bool(i % 3 == 0),
+                    Value::UInt32(u32::MAX - i as u32),
+                    Value::UInt64(u64::MAX - i as u64),
+                    Value::Int64(-(i as i64)),
+                    Value::Bytes(&hash),
+                    match i % 2 {
+                        0 => Value::Bytes(&blob[..i % 1000]),
+                        _ => Value::Null,
+                    },
+                    match i % 5 {
+                        0 => Value::Null,
+                        _ => Value::Bytes(text.as_bytes()),
+                    },
+                ])
+                .unwrap();
+        }
+        assert_eq!(n_rows as u64, writer.num_rows());
+        writer.close().unwrap();
+    }
+
+    fn assert_rows(table: &Table, n_rows: usize) {
+        assert_eq!(
+            SCHEMA.iter().map(|c| c.name).collect::<Vec<_>>(),
+            table.columns
+        );
+        assert_eq!(n_rows, table.rows.len());
+        for (i, row) in table.rows.iter().enumerate() {
+            let expected = vec![
+                Field::Bool(i % 3 == 0),
+                Field::Int((u32::MAX - i as u32) as i64),
+                // Read as signed INT64, same bits
+                Field::Int((u64::MAX - i as u64) as i64),
+                Field::Int(-(i as i64)),
+                Field::Bytes(vec![i as u8; 32]),
+                match i % 2 {
+                    0 => Field::Bytes(vec![0xab; i % 1000]),
+                    _ => Field::Null,
+                },
+                match i % 5 {
+                    0 => Field::Null,
+                    _ => Field::Bytes(format!("row {}", i).into_bytes()),
+                },
+            ];
+            assert_eq!(&expected, row, "row {}", i);
+        }
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let compressions = [
+            Compression::None,
+            Compression::Snappy,
+            #[cfg(feature = "gzip")]
+            Compression::Gzip,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd,
+        ];
+        for compression in compressions {
+            let path = temp_path("roundtrip");
+            write_rows(&path, compression, 100, 128 * 1024 * 1024);
+            let table = read_file(&path);
+            assert_eq!(1, table.num_row_groups);
+            assert_rows(&table, 100);
+            fs::remove_file(&path).unwrap();
+        }
+    }
+
+    /// Reads the files with pyarrow, the reference implementation of Arrow, skipped if it
+    /// isn't installed (`pip install pyarrow`)
+    #[test]
+    fn test_pyarrow() {
+        let pyarrow = Command::new("python3")
+            .args(["-c", "import pyarrow"])
+            .output();
+        if !pyarrow.is_ok_and(|output| output.status.success()) {
+            return;
+        }
+        let compressions = [
+            Compression::None,
+            Compression::Snappy,
+            #[cfg(feature = "gzip")]
+            Compression::Gzip,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd,
+        ];
+        for compression in compressions {
+            // Several row groups and pages of the blob column
+            let path = temp_path("pyarrow");
+            write_rows(&path, compression, 5000, 512 * 1024);
+            let groups = read_file(&path).num_row_groups;
+            assert!(groups > 1, "{}", groups);
+
+            let mut expected = vec![
+                groups.to_string(),
+                String::from(
+                    "flag:bool:False;small:uint32:False;large:uint64:False;signed:int64:False;\
+                     hash:fixed_size_binary[32]:False;blob:binary:True;text:string:True",
+                ),
+            ];
+            for i in 0..5000 {
+                let blob = match i % 2 {
+                    0 => utils::arr_to_hex(&vec![0xab; i % 1000]),
+                    _ => String::from("null"),
+                };
+                let text = match i % 5 {
+                    0 => String::from("null"),
+                    _ => utils::arr_to_hex(format!("row {}", i).as_bytes()),
+                };
+                expected.push(format!(
+                    "{};{};{};{};{};{};{}",
+                    i % 3 == 0,
+                    u32::MAX - i as u32,
+                    u64::MAX - i as u64,
+                    -(i as i64),
+                    utils::arr_to_hex(&[i as u8; 32]),
+                    blob,
+                    text
+                ));
+            }
+            let output = Command::new("python3")
+                .args(["-c", PYARROW_READER])
+                .arg(&path)
+                .output()
+                .unwrap();
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            assert!(output.status.success(), "{:?}: {}", compression, stderr);
+            let lines: Vec<&str> = std::str::from_utf8(&output.stdout)
+                .unwrap()
+                .lines()
+                .collect();
+            assert_eq!(expected, lines, "{:?}", compression);
+            fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_pages_and_row_groups() {
+        // ~2.5 MB of blobs, so the blob column has several pages
+        let path = temp_path("pages");
+        write_rows(&path, Compression::None, 5000, 64 * 1024 * 1024);
+        let table = read_file(&path);
+        assert_eq!(1, table.num_row_groups);
+        assert_rows(&table, 5000);
+
+        // More than 15 row groups need the long list header
+        write_rows(&path, Compression::Snappy, 5000, 16 * 1024);
+        let table = read_file(&path);
+        assert!(table.num_row_groups > 15, "{}", table.num_row_groups);
+        assert_rows(&table, 5000);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_empty_file() {
+        let path = temp_path("empty");
+        write_rows(&path, Compression::Snappy, 0, 1024);
+        let table = read_file(&path);
+        assert_eq!(0, table.num_row_groups);
+        assert!(table.rows.is_empty());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_rows() {
+        let path = temp_path("invalid");
+        let mut writer = FileWriter::create(&path, &SCHEMA[..2], Compression::None, 1024).unwrap();
+        // Wrong type, missing required value, wrong number of values
+        assert!(writer
+            .write_row(&[Value::UInt32(1), Value::UInt32(1)])
+            .is_err());
+        assert!(writer.write_row(&[Value::Bool(true), Value::Null]).is_err());
+        assert!(writer.write_row(&[Value::Bool(true)]).is_err());
+        writer
+            .write_row(&[Value::Bool(true), Value::UInt32(1)])
+            .unwrap();
+        assert_eq!(1, writer.num_rows());
+        writer.close().unwrap();
+        assert_eq!(
+            vec![vec![Field::Bool(true), Field::Int(1)]],
+            read_file(&path).rows
+        );
+        fs::remove_file(&path).unwrap();
+
+        let mut writer = FileWriter::create(&path, &SCHEMA[4..5], Compression::None, 1024).unwrap();
+        assert!(writer.write_row(&[Value::Bytes(&[0u8; 31])]).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_rle() {
+        let levels = [1, 1, 1, 0, 0, 1, 0, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1];
+        let encoded = rle_encode(&levels);
+        assert_eq!(vec![6, 1, 4, 0, 2, 1, 2, 0, 26, 1], encoded);
+        assert_eq!(levels.to_vec(), rle_decode(&encoded, levels.len()));
+        // Bit-packed run of 8 values
+        assert_eq!(vec![1, 0, 1, 1, 0, 0, 0, 0], rle_decode(&[3, 0b1101], 8));
+    }
+
+    #[test]
+    fn test_thrift() {
+        let mut t = Thrift::new();
+        t.i32_field(1, -1);
+        t.i64_field(20, 300);
+        t.struct_field(21, |t| t.binary_field(1, b"ab"));
+        t.list_header(22, THRIFT_I32, 16);
+        (0..16).for_each(|i| t.list_i32(i));
+        let encoded = t.finish();
+        assert_eq!(&[0x15, 0x01, 0x06, 0x28, 0xd8, 0x04, 0x1c], &encoded[..7]);
+
+        let (value, len) = thrift_decode::read(&encoded);
+        assert_eq!(encoded.len(), len);
+        assert_eq!(-1, value.int(1));
+        assert_eq!(300, value.int(20));
+        assert_eq!(b"ab".to_vec(), value.field(21).binary(1));
+        assert_eq!(16, value.list(22).len());
+        assert_eq!(15, value.list(22)[15].as_int());
+    }
+}
@@ -0,0 +1,835 @@
+//! Minimal PostgreSQL client (frontend/backend protocol 3.0).
+//! Supports what the `postgres` callback needs: password authentication
+//! (cleartext, md5 and SCRAM-SHA-256), simple queries and `COPY ... FROM STDIN`.
+//! TLS is not supported.
+
+use std::collections::hash_map::RandomState;
+use std::env;
+use std::error;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use byteorder::{BigEndian, ReadBytesExt};
+
+use crate::crypto::digest::Digest;
+use crate::crypto::hmac::Hmac;
+use crate::crypto::mac::Mac;
+use crate::crypto::md5::Md5;
+use crate::crypto::pbkdf2::pbkdf2;
+use crate::crypto::sha2::Sha256;
+
+const PROTOCOL_VERSION: i32 = 196608; // 3.0
+const DEFAULT_PORT: u16 = 5432;
+const PASSWORD_ENV: &str = "PGPASSWORD";
+// Size of the CopyData messages sent to the server
+const COPY_CHUNK_SIZE: usize = 1 << 16;
+
+#[derive(Debug)]
+pub enum PgError {
+    Io(io::Error),
+    /// ErrorResponse sent by the server
+    Server {
+        severity: String,
+        code: String,
+        message: String,
+        detail: Option<String>,
+    },
+    /// Invalid connection string or unsupported server request
+    Config(String),
+    /// Unexpected message from the server
+    Protocol(String),
+}
+
+impl PgError {
+    /// Returns the SQLSTATE of errors reported by the server
+    pub fn code(&self) -> Option<&str> {
+        match self {
+            PgError::Server { code, .. } => Some(code),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for PgError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PgError::Io(err) => write!(f, "I/O Error: {}", err),
+            PgError::Server {
+                severity,
+                code,
+                message,
+                detail,
+            } => {
+                write!(f, "{} {}: {}", severity, code, message)?;
+                match detail {
+                    Some(detail) => write!(f, " ({})", detail),
+                    None => Ok(()),
+                }
+            }
+            PgError::Config(msg) => write!(f, "Invalid configuration: {}", msg),
+            PgError::Protocol(msg) => write!(f, "Protocol error: {}", msg),
+        }
+    }
+}
+
+impl error::Error for PgError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            PgError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for PgError {
+    fn from(err: io::Error) -> Self {
+        PgError::Io(err)
+    }
+}
+
+/// Connection parameters, parsed from a libpq-style `key=value` string
+/// (e.g. `host=localhost port=5432 user=postgres dbname=btc`).
+/// The password defaults to `$PGPASSWORD`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub host: String,
+    pub port: u16,
+    pub user: String,
+    pub password: Option<String>,
+    pub dbname: String,
+    pub application_name: String,
+    pub connect_timeout: Option<Duration>,
+}
+
+impl FromStr for Config {
+    type Err = PgError;
+
+    fn from_str(conn: &str) -> Result<Self, Self::Err> {
+        let mut host = String::from("localhost");
+        let mut port = DEFAULT_PORT;
+        let mut user = None;
+        let mut password = None;
+        let mut dbname = None;
+        let mut application_name = String::from("rusty-blockparser");
+        let mut connect_timeout = None;
+
+        for (key, value) in parse_pairs(conn)? {
+            match key.as_str() {
+                "host" | "hostaddr" => host = value,
+                "port" => {
+                    port = value
+                        .parse()
+                        .map_err(|_| PgError::Config(format!("invalid port `{}`", value)))?
+                }
+                "user" => user = Some(value),
+                "password" => password = Some(value),
+                "dbname" => dbname = Some(value),
+                "application_name" => application_name = value,
+                "connect_timeout" => {
+                    let secs: u64 = value.parse().map_err(|_| {
+                        PgError::Config(format!("invalid connect_timeout `{}`", value))
+                    })?;
+                    connect_timeout = Some(Duration::from_secs(secs)).filter(|t| !t.is_zero());
+                }
+                "sslmode" => match value.as_str() {
+                    "disable" | "allow" | "prefer" => (),
+                    _ => {
+                        return Err(PgError::Config(format!(
+                            "sslmode `{}` is not supported, TLS is not available",
+                            value
+                        )))
+                    }
+                },
+                _ => return Err(PgError::Config(format!("unknown parameter `{}`", key))),
+            }
+        }
+
+        let user = user
+            .or_else(|| env::var("USER").ok())
+            .unwrap_or_else(|| String::from("postgres"));
+        Ok(Config {
+            host,
+            port,
+            dbname: dbname.unwrap_or_else(|| user.clone()),
+            user,
+            password: password.or_else(|| env::var(PASSWORD_ENV).ok()),
+            application_name,
+            connect_timeout,
+        })
+    }
+}
+
+/// Splits `key = value` pairs separated by whitespace.
+/// Values can be single-quoted, backslash escapes quotes and backslashes.
+fn parse_pairs(conn: &str) -> Result<Vec<(String, String)>, PgError> {
+    let mut pairs = Vec::new();
+    let mut chars = conn.chars().peekable();
+    loop {
+        while chars.peek().is_some_and(|c| c.is_whitespace()) {
+            chars.next();
+        }
+        if chars.peek().is_none() {
+            return Ok(pairs);
+        }
+        let mut key = String::new();
+        while let Some(c) = chars.next_if(|c| *c != '=' && !c.is_whitespace()) {
+            key.push(c);
+        }
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        if chars.next() != Some('=') {
+            return Err(PgError::Config(format!("missing `=` after `{}`", key)));
+        }
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+
+        let mut value = String::new();
+        if chars.next_if_eq(&'\'').is_some() {
+            loop {
+                match chars.next() {
+                    Some('\'') => break,
+                    Some('\\') => value.extend(chars.next()),
+                    Some(c) => value.push(c),
+                    None => {
+                        return Err(PgError::Config(format!(
+                            "unterminated quoted value for `{}`",
+                            key
+                        )))
+                    }
+                }
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                if c == '\\' {
+                    value.extend(chars.next());
+                } else {
+                    value.push(c);
+                }
+            }
+        }
+        pairs.push((key, value));
+    }
+}
+
+enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Rows of a simple query, values are in text format
+pub type Rows = Vec<Vec<Option<String>>>;
+
+/// Result of a simple query
+struct Response {
+    rows: Rows,
+    // Tag of the last CommandComplete, e.g. `INSERT 0 5` or `COPY 42`
+    tag: String,
+}
+
+pub struct Connection {
+    stream: BufReader<Stream>,
+    out: Vec<u8>,
+}
+
+impl Connection {
+    /// Connects and authenticates. Hosts starting with `/` are Unix socket directories.
+    pub fn connect(config: &Config) -> Result<Connection, PgError> {
+        let stream = if config.host.starts_with('/') {
+            Connection::connect_unix(config)?
+        } else {
+            let addr = (config.host.as_str(), config.port)
+                .to_socket_addrs()?
+                .next()
+                .ok_or_else(|| PgError::Config(format!("unable to resolve `{}`", config.host)))?;
+            let stream = match config.connect_timeout {
+                Some(timeout) => TcpStream::connect_timeout(&addr, timeout)?,
+                None => TcpStream::connect(addr)?,
+            };
+            stream.set_nodelay(true)?;
+            Stream::Tcp(stream)
+        };
+
+        let mut conn = Connection {
+            stream: BufReader::with_capacity(COPY_CHUNK_SIZE, stream),
+            out: Vec::with_capacity(COPY_CHUNK_SIZE + 5),
+        };
+        conn.startup(config)?;
+        Ok(conn)
+    }
+
+    #[cfg(unix)]
+    fn connect_unix(config: &Config) -> Result<Stream, PgError> {
+        let path = Path::new(&config.host).join(format!(".s.PGSQL.{}", config.port));
+        Ok(Stream::Unix(UnixStream::connect(path)?))
+    }
+
+    #[cfg(not(unix))]
+    fn connect_unix(_: &Config) -> Result<Stream, PgError> {
+        Err(PgError::Config(String::from(
+            "Unix sockets are not supported on this platform",
+        )))
+    }
+
+    /// Executes one or more statements and returns the number of affected rows
+    /// of the last one (if reported by the server)
+    pub fn execute(&mut self, sql: &str) -> Result<u64, PgError> {
+        Ok(affected_rows(&self.simple_query(sql)?.tag))
+    }
+
+    /// Executes a query and returns all rows in text format
+    pub fn query(&mut self, sql: &str) -> Result<Rows, PgError> {
+        Ok(self.simple_query(sql)?.rows)
+    }
+
+    /// Runs a `COPY ... FROM STDIN` statement and streams `data` (in the format
+    /// given by the statement). Returns the number of copied rows.
+    pub fn copy_in(&mut self, sql: &str, data: &[u8]) -> Result<u64, PgError> {
+        self.send_query(sql)?;
+        let mut error = None;
+        loop {
+            let (tag, body) = self.read_message()?;
+            match tag {
+                b'G' => break,
+                b'E' => error = Some(server_error(&body)),
+                b'Z' => {
+                    return Err(error.unwrap_or_else(|| {
+                        PgError::Protocol(String::from("statement didn't start a COPY"))
+                    }))
+                }
+                _ => self.handle_async(tag, &body)?,
+            }
+        }
+
+        for chunk in data.chunks(COPY_CHUNK_SIZE) {
+            message(&mut self.out, b'd', |buf| buf.extend_from_slice(chunk));
+            self.send()?;
+        }
+        message(&mut self.out, b'c', |_| ());
+        self.send()?;
+        Ok(affected_rows(&self.read_response()?.tag))
+    }
+
+    fn startup(&mut self, config: &Config) -> Result<(), PgError> {
+        // The startup message has no type byte
+        let mut body = PROTOCOL_VERSION.to_be_bytes().to_vec();
+        for (key, value) in [
+            ("user", config.user.as_str()),
+            ("database", config.dbname.as_str()),
+            ("application_name", config.application_name.as_str()),
+            ("client_encoding", "UTF8"),
+        ] {
+            put_cstr(&mut body, key);
+            put_cstr(&mut body, value);
+        }
+        body.push(0);
+        self.out
+            .extend_from_slice(&(body.len() as i32 + 4).to_be_bytes());
+        self.out.extend_from_slice(&body);
+        self.send()?;
+
+        self.authenticate(config)?;
+        self.read_response().map(|_| ())
+    }
+
+    fn authenticate(&mut self, config: &Config) -> Result<(), PgError> {
+        let password = || {
+            config.password.as_deref().ok_or_else(|| {
+                PgError::Config(format!(
+                    "the server requested a password, but none was given (see ${})",
+                    PASSWORD_ENV
+                ))
+            })
+        };
+        let mut scram = None;
+        loop {
+            let (tag, body) = self.read_message()?;
+            match tag {
+                b'R' => (),
+                b'E' => return Err(server_error(&body)),
+                _ => {
+                    return Err(PgError::Protocol(format!(
+                        "unexpected message `{}` during authentication",
+                        tag as char
+                    )))
+                }
+            }
+            let mut reader = body.as_slice();
+            match reader.read_i32::<BigEndian>()? {
+                // AuthenticationOk
+                0 => return Ok(()),
+                // AuthenticationCleartextPassword
+                3 => {
+                    let password = password()?;
+                    message(&mut self.out, b'p', |buf| put_cstr(buf, password));
+                }
+                // AuthenticationMD5Password
+                5 => {
+                    let hash = md5_password(&config.user, password()?, reader);
+                    message(&mut self.out, b'p', |buf| put_cstr(buf, &hash));
+                }
+                // AuthenticationSASL
+                10 => {
+                    let mechanisms: Vec<&[u8]> = reader.split(|b| *b == 0).collect();
+                    if !mechanisms.contains(&&b"SCRAM-SHA-256"[..]) {
+                        return Err(PgError::Config(String::from(
+                            "the server doesn't offer SCRAM-SHA-256",
+                        )));
+                    }
+                    // The server uses the user name of the startup message
+                    let client = Scram::new("", &nonce());
+                    let first = client.client_first();
+                    message(&mut self.out, b'p', |buf| {
+                        put_cstr(buf, "SCRAM-SHA-256");
+                        buf.extend_from_slice(&(first.len() as i32).to_be_bytes());
+                        buf.extend_from_slice(first.as_bytes());
+                    });
+                    scram = Some(client);
+                }
+                // AuthenticationSASLContinue
+                11 => {
+                    let client = scram.as_mut().ok_or_else(|| {
+                        PgError::Protocol(String::from("unexpected SASLContinue"))
+                    })?;
+                    let server_first = String::from_utf8_lossy(reader);
+                    let last = client.client_final(password()?, &server_first)?;
+                    message(&mut self.out, b'p', |buf| {
+                        buf.extend_from_slice(last.as_bytes())
+                    });
+                }
+                // AuthenticationSASLFinal
+                12 => {
+                    let client = scram
+                        .as_ref()
+                        .ok_or_else(|| PgError::Protocol(String::from("unexpected SASLFinal")))?;
+                    client.verify(&String::from_utf8_lossy(reader))?;
+                    continue;
+                }
+                code => {
+                    return Err(PgError::Config(format!(
+                        "unsupported authentication method (code {})",
+                        code
+                    )))
+                }
+            }
+            self.send()?;
+        }
+    }
+
+    fn simple_query(&mut self, sql: &str) -> Result<Response, PgError> {
+        self.send_query(sql)?;
+        self.read_response()
+    }
+
+    fn send_query(&mut self, sql: &str) -> Result<(), PgError> {
+        trace!(target: "pgwire", "Query: {}", sql);
+        message(&mut self.out, b'Q', |buf| put_cstr(buf, sql));
+        self.send()
+    }
+
+    /// Reads messages until the server is ready for the next query.
+    /// Returns the first reported error.
+    fn read_response(&mut self) -> Result<Response, PgError> {
+        let mut response = Response {
+            rows: Vec::new(),
+            tag: String::new(),
+        };
+        let mut error = None;
+        loop {
+            let (tag, body) = self.read_message()?;
+            match tag {
+                b'Z' => {
+                    return match error {
+                        Some(err) => Err(err),
+                        None => Ok(response),
+                    }
+                }
+                b'E' => {
+                    error.get_or_insert(server_error(&body));
+                }
+                b'C' => response.tag = read_cstr(&body),
+                b'D' => response.rows.push(data_row(&body)?),
+                // RowDescription, EmptyQueryResponse, BackendKeyData
+                b'T' | b'I' | b'K' => (),
+                // COPY FROM STDIN issued via execute()
+                b'G' => {
+                    message(&mut self.out, b'f', |buf| {
+                        put_cstr(buf, "COPY requires copy_in()")
+                    });
+                    self.send()?;
+                }
+                _ => self.handle_async(tag, &body)?,
+            }
+        }
+    }
+
+    /// Handles messages that may arrive at any time
+    fn handle_async(&mut self, tag: u8, body: &[u8]) -> Result<(), PgError> {
+        match tag {
+            // NoticeResponse
+            b'N' => {
+                debug!(target: "pgwire", "{}", server_error(body));
+                Ok(())
+            }
+            // ParameterStatus, NotificationResponse
+            b'S' | b'A' => Ok(()),
+            _ => Err(PgError::Protocol(format!(
+                "unexpected message `{}`",
+                tag as char
+            ))),
+        }
+    }
+
+    fn send(&mut self) -> Result<(), PgError> {
+        let stream = self.stream.get_mut();
+        stream.write_all(&self.out)?;
+        stream.flush()?;
+        self.out.clear();
+        Ok(())
+    }
+
+    fn read_message(&mut self) -> Result<(u8, Vec<u8>), PgError> {
+        let tag = self.stream.read_u8()?;
+        let len = self.stream.read_i32::<BigEndian>()?;
+        if len < 4 {
+            return Err(PgError::Protocol(format!("invalid message length {}", len)));
+        }
+        let mut body = vec![0u8; len as usize - 4];
+        self.stream.read_exact(&mut body)?;
+        Ok((tag, body))
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        // Terminate, the server closes the connection anyway
+        message(&mut self.out, b'X', |_| ());
+        let _ = self.send();
+    }
+}
+
+/// Appends a message with the given type and body to `out`
+fn message<F: FnOnce(&mut Vec<u8>)>(out: &mut Vec<u8>, tag: u8, body: F) {
+    out.push(tag);
+    let start = out.len();
+    out.extend_from_slice(&[0; 4]);
+    body(out);
+    let len = (out.len() - start) as i32;
+    out[start..start + 4].copy_from_slice(&len.to_be_bytes());
+}
+
+#[inline]
+fn put_cstr(buf: &mut Vec<u8>, value: &str) {
+    buf.extend_from_slice(value.as_bytes());
+    buf.push(0);
+}
+
+fn read_cstr(buf: &[u8]) -> String {
+    let end = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..end]).into_owned()
+}
+
+/// Parses the fields of an ErrorResponse or NoticeResponse
+fn server_error(body: &[u8]) -> PgError {
+    let (mut severity, mut code, mut message, mut detail) =
+        (String::new(), String::new(), String::new(), None);
+    for field in body.split(|b| *b == 0).filter(|f| !f.is_empty()) {
+        let value = String::from_utf8_lossy(&field[1..]).into_owned();
+        match field[0] {
+            b'S' => severity = value,
+            b'C' => code = value,
+            b'M' => message = value,
+            b'D' => detail = Some(value),
+            _ => (),
+        }
+    }
+    PgError::Server {
+        severity,
+        code,
+        message,
+        detail,
+    }
+}
+
+fn data_row(body: &[u8]) -> Result<Vec<Option<String>>, PgError> {
+    let mut reader = body;
+    let n_columns = reader.read_i16::<BigEndian>()?;
+    let mut row = Vec::with_capacity(n_columns.max(0) as usize);
+    for _ in 0..n_columns {
+        let len = reader.read_i32::<BigEndian>()?;
+        if len < 0 {
+            row.push(None);
+            continue;
+        }
+        let len = len as usize;
+        if len > reader.len() {
+            return Err(PgError::Protocol(String::from("truncated DataRow")));
+        }
+        row.push(Some(String::from_utf8_lossy(&reader[..len]).into_owned()));
+        reader = &reader[len..];
+    }
+    Ok(row)
+}
+
+/// Extracts the row count of a command tag like `INSERT 0 5` or `COPY 42`
+fn affected_rows(tag: &str) -> u64 {
+    tag.rsplit(' ')
+        .next()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(0)
+}
+
+/// Hashes the password as `md5(md5(password + user) + salt)`
+fn md5_password(user: &str, password: &str, salt: &[u8]) -> String {
+    let mut md5 = Md5::new();
+    md5.input_str(password);
+    md5.input_str(user);
+    let inner = md5.result_str();
+
+    let mut md5 = Md5::new();
+    md5.input_str(&inner);
+    md5.input(salt);
+    format!("md5{}", md5.result_str())
+}
+
+/// Random client nonce, base64 encoded
+fn nonce() -> String {
+    let bytes: Vec<u8> = (0..3)
+        .flat_map(|_| RandomState::new().build_hasher().finish().to_le_bytes())
+        .collect();
+    base64::encode(bytes)
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut mac = Hmac::new(Sha256::new(), key);
+    mac.input(data);
+    let mut out = [0u8; 32];
+    out.copy_from_slice(mac.result().code());
+    out
+}
+
+/// Client side of SCRAM-SHA-256 without channel binding (RFC 5802, RFC 7677)
+struct Scram {
+    client_first_bare: String,
+    nonce: String,
+    // Expected server signature, known after client_final()
+    server_signature: Option<[u8; 32]>,
+}
+
+impl Scram {
+    fn new(user: &str, nonce: &str) -> Self {
+        Scram {
+            client_first_bare: format!("n={},r={}", user, nonce),
+            nonce: String::from(nonce),
+            server_signature: None,
+        }
+    }
+
+    fn client_first(&self) -> String {
+        format!("n,,{}", self.client_first_bare)
+    }
+
+    fn client_final(&mut self, password: &str, server_first: &str) -> Result<String, PgError> {
+        let attribute = |name: &str| {
+            server_first
+                .split(',')
+                .find_map(|attr| attr.strip_prefix(name))
+                .ok_or_else(|| PgError::Protocol(format!("SCRAM: missing `{}`", name)))
+        };
+        let nonce = attribute("r=")?;
+        if !nonce.starts_with(&self.nonce) {
+            return Err(PgError::Protocol(String::from(
+                "SCRAM: invalid server nonce",
+            )));
+        }
+        let salt = base64::decode(attribute("s=")?)
+            .map_err(|_| PgError::Protocol(String::from("SCRAM: invalid salt")))?;
+        let iterations: u32 = attribute("i=")?
+            .parse()
+            .map_err(|_| PgError::Protocol(String::from("SCRAM: invalid iteration count")))?;
+
+        let mut salted_password = [0u8; 32];
+        let mut mac = Hmac::new(Sha256::new(), password.as_bytes());
+        pbkdf2(&mut mac, &salt, iterations, &mut salted_password);
+
+        let client_key = hmac_sha256(&salted_password, b"Client Key");
+        let mut stored_key = [0u8; 32];
+        let mut sha = Sha256::new();
+        sha.input(&client_key);
+        sha.result(&mut stored_key);
+
+        // "biws" is the base64 encoded GS2 header "n,,"
+        let client_final = format!("c=biws,r={}", nonce);
+        let auth_message = format!(
+            "{},{},{}",
+            self.client_first_bare, server_first, client_final
+        );
+        let signature = hmac_sha256(&stored_key, auth_message.as_bytes());
+        let proof: Vec<u8> = client_key
+            .iter()
+            .zip(signature.iter())
+            .map(|(k, s)| k ^ s)
+            .collect();
+
+        let server_key = hmac_sha256(&salted_password, b"Server Key");
+        self.server_signature = Some(hmac_sha256(&server_key, auth_message.as_bytes()));
+        Ok(format!("{},p={}", client_final, base64::encode(proof)))
+    }
+
+    fn verify(&self, server_final: &str) -> Result<(), PgError> {
+        let expected = self.server_signature.map(base64::encode);
+        match server_final.strip_prefix("v=") {
+            Some(signature) if Some(signature) == expected.as_deref() => Ok(()),
+            _ => Err(PgError::Protocol(String::from(
+                "SCRAM: invalid server signature",
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config() {
+        let config = Config::from_str(
+            "host=db.local port=5433 user=btc password='s3cr3t \\'pw' dbname = chain connect_timeout=5",
+        )
+        .unwrap();
+        assert_eq!("db.local", config.host);
+        assert_eq!(5433, config.port);
+        assert_eq!("btc", config.user);
+        assert_eq!(Some("s3cr3t 'pw"), config.password.as_deref());
+        assert_eq!("chain", config.dbname);
+        assert_eq!(Some(Duration::from_secs(5)), config.connect_timeout);
+
+        // dbname defaults to the user
+        let config = Config::from_str("user=btc sslmode=disable").unwrap();
+        assert_eq!("localhost", config.host);
+        assert_eq!(DEFAULT_PORT, config.port);
+        assert_eq!("btc", config.dbname);
+        assert_eq!(None, config.connect_timeout);
+    }
+
+    #[test]
+    fn test_config_invalid() {
+        for conn in [
+            "port=abc",
+            "user",
+            "user=btc password='open",
+            "foo=bar",
+            "sslmode=require",
+        ] {
+            assert!(
+                matches!(Config::from_str(conn), Err(PgError::Config(_))),
+                "{}",
+                conn
+            );
+        }
+    }
+
+    #[test]
+    fn test_message() {
+        let mut out = Vec::new();
+        message(&mut out, b'Q', |buf| put_cstr(buf, "SELECT 1"));
+        assert_eq!(b"Q\x00\x00\x00\x0dSELECT 1\x00".to_vec(), out);
+
+        out.clear();
+        message(&mut out, b'c', |_| ());
+        assert_eq!(b"c\x00\x00\x00\x04".to_vec(), out);
+    }
+
+    #[test]
+    fn test_server_error() {
+        let body =
+            b"SERROR\0VERROR\0C23505\0Mduplicate key value\0DKey (hash)=(x) already exists.\0\0";
+        let err = server_error(body);
+        assert_eq!(Some("23505"), err.code());
+        assert_eq!(
+            "ERROR 23505: duplicate key value (Key (hash)=(x) already exists.)",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn test_data_row() {
+        let body = b"\x00\x02\x00\x00\x00\x0242\xff\xff\xff\xff";
+        assert_eq!(
+            vec![Some(String::from("42")), None],
+            data_row(body).unwrap()
+        );
+        assert!(data_row(b"\x00\x01\x00\x00\x00\x09abc").is_err());
+    }
+
+    #[test]
+    fn test_affected_rows() {
+        assert_eq!(42, affected_rows("COPY 42"));
+        assert_eq!(5, affected_rows("INSERT 0 5"));
+        assert_eq!(0, affected_rows("CREATE TABLE"));
+        assert_eq!(0, affected_rows(""));
+    }
+
+    #[test]
+    fn test_md5_password() {
+        assert_eq!(
+            "md5bb41a296aab6baccb36ff243a562abff",
+            md5_password("postgres", "secret", &[1, 2, 3, 4])
+        );
+    }
+
+    #[test]
+    fn test_scram() {
+        // Test vector of RFC 7677
+        let mut scram = Scram::new("user", "rOprNGfwEbeRWgbNEkqO");
+        assert_eq!("n,,n=user,r=rOprNGfwEbeRWgbNEkqO", scram.client_first());
+
+        let server_first =
+            "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096";
+        assert_eq!(
+            "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=",
+            scram.client_final("pencil", server_first).unwrap()
+        );
+        scram
+            .verify("v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=")
+            .unwrap();
+        assert!(scram.verify("v=AAAA").is_err());
+
+        // The server nonce has to extend ours
+        let mut scram = Scram::new("user", "abc");
+        assert!(scram.client_final("pencil", server_first).is_err());
+    }
+}
@@ -0,0 +1,330 @@
+//! Metrics in the Prometheus text format and a minimal HTTP server to scrape them.
+//! The server answers `GET /metrics` (and `/`) with one response per connection.
+
+use std::fmt::Write as FmtWrite;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+// How often the server checks for new connections and the stop flag
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+// Clients which don't send their request in time are dropped
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+// Upper bound of the request head
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+
+/// Value which can go up and down, e.g. the latency of the last insert
+#[derive(Clone, Default)]
+pub struct Gauge(Arc<AtomicU64>);
+
+impl Gauge {
+    pub fn set(&self, value: f64) {
+        self.0.store(value.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+}
+
+/// Monotonically increasing count
+#[derive(Clone, Default)]
+pub struct Counter(Arc<AtomicU64>);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.inc_by(1);
+    }
+
+    pub fn inc_by(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+enum Metric {
+    Gauge(Gauge),
+    Counter(Counter),
+}
+
+/// Metrics registered by the callbacks, rendered on each scrape.
+/// The returned handles can be updated from any thread.
+#[derive(Default)]
+pub struct Registry {
+    metrics: Vec<(String, String, Metric)>,
+}
+
+impl Registry {
+    /// Registering a name again returns the same gauge
+    pub fn gauge(&mut self, name: &str, help: &str) -> Gauge {
+        match self.find_or_register(name, help, || Metric::Gauge(Gauge::default())) {
+            Metric::Gauge(gauge) => gauge.clone(),
+            Metric::Counter(_) => panic!("Metric `{}` is already registered as counter", name),
+        }
+    }
+
+    /// Registering a name again returns the same counter, `name` should end with `_total`
+    pub fn counter(&mut self, name: &str, help: &str) -> Counter {
+        match self.find_or_register(name, help, || Metric::Counter(Counter::default())) {
+            Metric::Counter(counter) => counter.clone(),
+            Metric::Gauge(_) => panic!("Metric `{}` is already registered as gauge", name),
+        }
+    }
+
+    fn find_or_register<F: FnOnce() -> Metric>(
+        &mut self,
+        name: &str,
+        help: &str,
+        new_metric: F,
+    ) -> &Metric {
+        assert!(
+            is_valid_name(name),
+            "Invalid metric name `{}`, only [a-zA-Z_:][a-zA-Z0-9_:]* is allowed",
+            name
+        );
+        let i = match self.metrics.iter().position(|(other, _, _)| other == name) {
+            Some(i) => i,
+            None => {
+                self.metrics
+                    .push((String::from(name), String::from(help), new_metric()));
+                self.metrics.len() - 1
+            }
+        };
+        &self.metrics[i].2
+    }
+
+    /// Appends all metrics in the text exposition format
+    pub fn render(&self, out: &mut String) {
+        for (name, help, metric) in &self.metrics {
+            match metric {
+                Metric::Gauge(gauge) => write_metric(out, name, help, "gauge", gauge.get()),
+                Metric::Counter(counter) => {
+                    write_metric(out, name, help, "counter", counter.get() as f64)
+                }
+            }
+        }
+    }
+}
+
+/// Appends a single metric with its HELP and TYPE lines
+pub fn write_metric(out: &mut String, name: &str, help: &str, kind: &str, value: f64) {
+    let help = help.replace('\\', "\\\\").replace('\n', "\\n");
+    // Writing into a String can't fail
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    let _ = writeln!(out, "{} {}", name, format_value(value));
+}
+
+fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        String::from("NaN")
+    } else if value.is_infinite() {
+        String::from(if value > 0.0 { "+Inf" } else { "-Inf" })
+    } else {
+        value.to_string()
+    }
+}
+
+fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_' || c == ':')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+}
+
+/// Serves the output of `render` in its own thread until it is shut down or dropped
+pub struct MetricsServer {
+    local_addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl MetricsServer {
+    pub fn start<A, F>(addr: A, render: F) -> io::Result<Self>
+    where
+        A: ToSocketAddrs,
+        F: Fn() -> String + Send + 'static,
+    {
+        let listener = TcpListener::bind(addr)?;
+        // Polled, so the stop flag is seen without another connection
+        listener.set_nonblocking(true)?;
+        let local_addr = listener.local_addr()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = Arc::clone(&stop);
+        let handle = thread::Builder::new()
+            .name(String::from("metrics-server"))
+            .spawn(move || serve(listener, &thread_stop, render))?;
+        Ok(MetricsServer {
+            local_addr,
+            stop,
+            handle: Some(handle),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Stops accepting connections and waits for the server thread
+    pub fn shutdown(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                warn!(target: "metrics", "Metrics server thread panicked.");
+            }
+        }
+    }
+}
+
+impl Drop for MetricsServer {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+fn serve<F: Fn() -> String>(listener: TcpListener, stop: &AtomicBool, render: F) {
+    while !stop.load(Ordering::Relaxed) {
+        match listener.accept() {
+            Ok((stream, peer)) => {
+                if let Err(err) = respond(stream, &render) {
+                    debug!(target: "metrics", "Unable to answer {}: {}", peer, err);
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => thread::sleep(POLL_INTERVAL),
+            Err(err) => {
+                warn!(target: "metrics", "Unable to accept connection: {}", err);
+                thread::sleep(POLL_INTERVAL);
+            }
+        }
+    }
+}
+
+fn respond<F: Fn() -> String>(mut stream: TcpStream, render: &F) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+
+    // Only the request line matters, the rest of the head is read to not reset the connection
+    let mut head = Vec::with_capacity(512);
+    let mut buf = [0u8; 512];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf)?;
+        if n == 0 || head.len() + n > MAX_REQUEST_SIZE {
+            break;
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    let head = String::from_utf8_lossy(&head);
+    let mut request_line = head.lines().next().unwrap_or("").split(' ');
+    let (method, path) = (request_line.next(), request_line.next());
+
+    let (status, content_type, body) = match (method, path) {
+        (Some("GET"), Some("/metrics")) | (Some("GET"), Some("/")) => (
+            "200 OK",
+            "text/plain; version=0.0.4; charset=utf-8",
+            render(),
+        ),
+        (Some("GET"), Some(_)) => ("404 Not Found", "text/plain", String::from("Not Found\n")),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain",
+            String::from("Method Not Allowed\n"),
+        ),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+/// Sends `GET <path>` and returns the status line and body
+#[cfg(test)]
+pub fn scrape(addr: SocketAddr, path: &str) -> (String, String) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    (
+        String::from(head.lines().next().unwrap()),
+        String::from(body),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let mut registry = Registry::default();
+        let latency = registry.gauge("insert_latency_seconds", "Latency of the last insert");
+        let batches = registry.counter("batches_total", "Written batches\nwith \\ escapes");
+        latency.set(0.25);
+        batches.inc();
+        registry.counter("batches_total", "").inc_by(2);
+        let mut out = String::new();
+        registry.render(&mut out);
+        assert_eq!(
+            "# HELP insert_latency_seconds Latency of the last insert\n\
+             # TYPE insert_latency_seconds gauge\n\
+             insert_latency_seconds 0.25\n\
+             # HELP batches_total Written batches\\nwith \\\\ escapes\n\
+             # TYPE batches_total counter\n\
+             batches_total 3\n",
+            out
+        );
+        assert_eq!("NaN", format_value(f64::NAN));
+        assert_eq!("-Inf", format_value(f64::NEG_INFINITY));
+        assert_eq!("1000000", format_value(1E6));
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid metric name")]
+    fn test_invalid_name() {
+        Registry::default().gauge("batch-size", "");
+    }
+
+    #[test]
+    fn test_names() {
+        for name in ["a", "_a", "mongo:batch_size", "A9"] {
+            assert!(is_valid_name(name), "{}", name);
+        }
+        for name in ["", "9a", "a-b", "a b", "ä"] {
+            assert!(!is_valid_name(name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_server() {
+        let mut registry = Registry::default();
+        let height = registry.gauge("height", "Height");
+        let mut server = MetricsServer::start("127.0.0.1:0", move || {
+            let mut out = String::new();
+            registry.render(&mut out);
+            out
+        })
+        .unwrap();
+        let addr = server.local_addr();
+
+        let (status, body) = scrape(addr, "/metrics");
+        assert_eq!("HTTP/1.1 200 OK", status);
+        assert!(body.ends_with("height 0\n"), "{}", body);
+        height.set(42.0);
+        assert!(scrape(addr, "/").1.ends_with("height 42\n"));
+        assert_eq!("HTTP/1.1 404 Not Found", scrape(addr, "/other").0);
+
+        server.shutdown();
+        assert!(TcpStream::connect(addr).is_err());
+    }
+}
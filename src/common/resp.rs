@@ -0,0 +1,392 @@
+//! Minimal Redis client (RESP2). Supports `AUTH`, database selection and pipelining:
+//! commands are queued into the write buffer and their replies read with `sync()`.
+//! TLS (`rediss://`) and cluster mode are not supported.
+
+use std::error;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use url::Url;
+
+const DEFAULT_PORT: u16 = 6379;
+// Upper bound for bulk strings and arrays in replies
+const MAX_REPLY_SIZE: usize = 512 * 1024 * 1024;
+
+#[derive(Debug)]
+pub enum RespError {
+    Io(io::Error),
+    /// Error reply, e.g. `WRONGTYPE Operation against a key holding the wrong kind of value`
+    Server(String),
+    /// Invalid URL
+    Config(String),
+    /// Malformed reply
+    Protocol(String),
+}
+
+impl fmt::Display for RespError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RespError::Io(err) => write!(f, "I/O Error: {}", err),
+            RespError::Server(msg) => write!(f, "{}", msg),
+            RespError::Config(msg) => write!(f, "Invalid configuration: {}", msg),
+            RespError::Protocol(msg) => write!(f, "Protocol error: {}", msg),
+        }
+    }
+}
+
+impl error::Error for RespError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            RespError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for RespError {
+    fn from(err: io::Error) -> Self {
+        RespError::Io(err)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reply {
+    Status(String),
+    Error(String),
+    Integer(i64),
+    /// None for the null bulk string
+    Bulk(Option<Vec<u8>>),
+    /// None for the null array
+    Array(Option<Vec<Reply>>),
+}
+
+/// Connection parameters
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub host: String,
+    pub port: u16,
+    pub user: Option<String>,
+    pub password: Option<String>,
+    pub db: u32,
+    pub timeout: Duration,
+}
+
+impl Config {
+    /// Parses `redis://[[user]:password@]host[:port][/db]`
+    pub fn new(url: &str, timeout: Duration) -> Result<Self, RespError> {
+        let url = Url::parse(url).map_err(|e| RespError::Config(format!("{}", e)))?;
+        if url.scheme() != "redis" {
+            return Err(RespError::Config(format!(
+                "scheme `{}` is not supported, only redis",
+                url.scheme()
+            )));
+        }
+        let host = url
+            .host_str()
+            .ok_or_else(|| RespError::Config(String::from("missing host")))?;
+        let db = match url.path().trim_start_matches('/') {
+            "" => 0,
+            db => db
+                .parse()
+                .map_err(|_| RespError::Config(format!("invalid database `{}`", db)))?,
+        };
+        let user = match url.username() {
+            "" => None,
+            user => Some(String::from(user)),
+        };
+        Ok(Config {
+            host: String::from(host),
+            port: url.port().unwrap_or(DEFAULT_PORT),
+            user,
+            password: url.password().map(String::from),
+            db,
+            timeout,
+        })
+    }
+}
+
+pub struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+    // Commands whose replies weren't read yet
+    pending: usize,
+}
+
+impl Connection {
+    /// Connects, authenticates and selects the database
+    pub fn connect(config: &Config) -> Result<Self, RespError> {
+        let addr = (config.host.as_str(), config.port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| RespError::Config(format!("unable to resolve `{}`", config.host)))?;
+        let stream = TcpStream::connect_timeout(&addr, config.timeout)?;
+        stream.set_read_timeout(Some(config.timeout))?;
+        stream.set_write_timeout(Some(config.timeout))?;
+        stream.set_nodelay(true)?;
+        let mut conn = Connection {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::with_capacity(1 << 20, stream),
+            pending: 0,
+        };
+        if let Some(password) = &config.password {
+            match &config.user {
+                Some(user) => conn.command(&[b"AUTH", user.as_bytes(), password.as_bytes()])?,
+                None => conn.command(&[b"AUTH", password.as_bytes()])?,
+            };
+        }
+        if config.db != 0 {
+            conn.command(&[b"SELECT", config.db.to_string().as_bytes()])?;
+        }
+        Ok(conn)
+    }
+
+    /// Sends a command and waits for its reply, error replies are returned as error
+    pub fn command(&mut self, args: &[&[u8]]) -> Result<Reply, RespError> {
+        self.sync()?;
+        self.queue(args)?;
+        Ok(self.sync()?.pop().unwrap())
+    }
+
+    /// Writes a command into the buffer without waiting for the reply
+    pub fn queue(&mut self, args: &[&[u8]]) -> Result<(), RespError> {
+        self.writer.write_all(&encode(args))?;
+        self.pending += 1;
+        Ok(())
+    }
+
+    /// Sends the queued commands and reads all their replies.
+    /// Returns the first error reply as error after reading the remaining replies.
+    pub fn sync(&mut self) -> Result<Vec<Reply>, RespError> {
+        self.writer.flush()?;
+        let mut replies = Vec::with_capacity(self.pending);
+        let mut error = None;
+        while self.pending > 0 {
+            let reply = read_reply(&mut self.reader)?;
+            self.pending -= 1;
+            if let Reply::Error(msg) = &reply {
+                error.get_or_insert_with(|| RespError::Server(msg.clone()));
+            }
+            replies.push(reply);
+        }
+        match error {
+            Some(error) => Err(error),
+            None => Ok(replies),
+        }
+    }
+
+    /// Number of queued commands
+    pub fn pending(&self) -> usize {
+        self.pending
+    }
+}
+
+/// Encodes a command as array of bulk strings
+pub fn encode(args: &[&[u8]]) -> Vec<u8> {
+    let size = args.iter().map(|arg| arg.len() + 16).sum::<usize>() + 16;
+    let mut out = Vec::with_capacity(size);
+    out.extend_from_slice(format!("*{}\r\n", args.len()).as_bytes());
+    for arg in args {
+        out.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        out.extend_from_slice(arg);
+        out.extend_from_slice(b"\r\n");
+    }
+    out
+}
+
+pub fn read_reply<R: BufRead>(reader: &mut R) -> Result<Reply, RespError> {
+    let line = read_line(reader)?;
+    let (kind, rest) = match line.chars().next() {
+        Some(kind) => (kind, &line[1..]),
+        None => return Err(RespError::Protocol(String::from("empty reply"))),
+    };
+    Ok(match kind {
+        '+' => Reply::Status(String::from(rest)),
+        '-' => Reply::Error(String::from(rest)),
+        ':' => Reply::Integer(parse_int(rest)?),
+        '$' => match read_len(rest)? {
+            None => Reply::Bulk(None),
+            Some(len) => {
+                let mut data = vec![0u8; len + 2];
+                reader.read_exact(&mut data)?;
+                if !data.ends_with(b"\r\n") {
+                    return Err(RespError::Protocol(String::from(
+                        "bulk string without CRLF",
+                    )));
+                }
+                data.truncate(len);
+                Reply::Bulk(Some(data))
+            }
+        },
+        '*' => match read_len(rest)? {
+            None => Reply::Array(None),
+            Some(len) => {
+                let mut items = Vec::with_capacity(len.min(1024));
+                for _ in 0..len {
+                    items.push(read_reply(reader)?);
+                }
+                Reply::Array(Some(items))
+            }
+        },
+        _ => {
+            return Err(RespError::Protocol(format!(
+                "invalid reply type `{}`",
+                kind
+            )))
+        }
+    })
+}
+
+fn read_line<R: BufRead>(reader: &mut R) -> Result<String, RespError> {
+    let mut line = Vec::new();
+    reader.read_until(b'\n', &mut line)?;
+    if !line.ends_with(b"\r\n") {
+        return Err(RespError::Io(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "connection closed",
+        )));
+    }
+    line.truncate(line.len() - 2);
+    String::from_utf8(line).map_err(|_| RespError::Protocol(String::from("invalid UTF-8 line")))
+}
+
+fn parse_int(s: &str) -> Result<i64, RespError> {
+    s.parse()
+        .map_err(|_| RespError::Protocol(format!("invalid integer `{}`", s)))
+}
+
+/// Length of a bulk string or array, None for -1
+fn read_len(s: &str) -> Result<Option<usize>, RespError> {
+    match parse_int(s)? {
+        -1 => Ok(None),
+        len if len >= 0 && len as usize <= MAX_REPLY_SIZE => Ok(Some(len as usize)),
+        len => Err(RespError::Protocol(format!("invalid length {}", len))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn test_config() {
+        let timeout = Duration::from_secs(1);
+        let config = Config::new("redis://:secret@cache:6380/3", timeout).unwrap();
+        assert_eq!("cache", config.host);
+        assert_eq!(6380, config.port);
+        assert_eq!(None, config.user);
+        assert_eq!(Some(String::from("secret")), config.password);
+        assert_eq!(3, config.db);
+        let config = Config::new("redis://app:pw@localhost", timeout).unwrap();
+        assert_eq!(Some(String::from("app")), config.user);
+        assert_eq!(DEFAULT_PORT, config.port);
+        assert_eq!(0, config.db);
+        assert!(Config::new("rediss://localhost", timeout).is_err());
+        assert!(Config::new("redis://localhost/x", timeout).is_err());
+    }
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(
+            b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$0\r\n\r\n".to_vec(),
+            encode(&[b"SET", b"k", b""])
+        );
+    }
+
+    #[test]
+    fn test_read_reply() {
+        let data = b"+OK\r\n-ERR oops\r\n:42\r\n$5\r\nhe\r\no\r\n$-1\r\n*2\r\n:1\r\n*0\r\n*-1\r\n";
+        let mut reader = &data[..];
+        let expected = [
+            Reply::Status(String::from("OK")),
+            Reply::Error(String::from("ERR oops")),
+            Reply::Integer(42),
+            Reply::Bulk(Some(b"he\r\no".to_vec())),
+            Reply::Bulk(None),
+            Reply::Array(Some(vec![Reply::Integer(1), Reply::Array(Some(vec![]))])),
+            Reply::Array(None),
+        ];
+        for reply in expected {
+            assert_eq!(reply, read_reply(&mut reader).unwrap());
+        }
+        assert!(reader.is_empty());
+
+        for invalid in [
+            &b"?x\r\n"[..],
+            b":x\r\n",
+            b"$3\r\nabcd\r\n",
+            b"$-2\r\n",
+            b"+OK",
+        ] {
+            assert!(read_reply(&mut &invalid[..]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_pipeline() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            let mut commands = Vec::new();
+            while let Ok(Reply::Array(Some(args))) = read_reply(&mut reader) {
+                let args: Vec<String> = args
+                    .into_iter()
+                    .map(|arg| match arg {
+                        Reply::Bulk(Some(arg)) => String::from_utf8(arg).unwrap(),
+                        _ => panic!("invalid argument"),
+                    })
+                    .collect();
+                let reply: &[u8] = match args[0].as_str() {
+                    "DEL" => b":1\r\n",
+                    "SADD" => b"-WRONGTYPE wrong kind of value\r\n",
+                    _ => b"+OK\r\n",
+                };
+                writer.write_all(reply).unwrap();
+                commands.push(args.join(" "));
+            }
+            commands
+        });
+
+        let url = format!("redis://app:pw@127.0.0.1:{}/2", port);
+        let mut conn =
+            Connection::connect(&Config::new(&url, Duration::from_secs(5)).unwrap()).unwrap();
+        conn.queue(&[b"SET", b"a", b"1"]).unwrap();
+        conn.queue(&[b"DEL", b"a"]).unwrap();
+        assert_eq!(2, conn.pending());
+        assert_eq!(
+            vec![Reply::Status(String::from("OK")), Reply::Integer(1)],
+            conn.sync().unwrap()
+        );
+        conn.queue(&[b"SADD", b"s", b"x"]).unwrap();
+        conn.queue(&[b"SET", b"b", b"2"]).unwrap();
+        let err = conn.sync().unwrap_err();
+        assert_eq!("WRONGTYPE wrong kind of value", format!("{}", err));
+        // All replies were read
+        assert_eq!(0, conn.pending());
+        assert_eq!(
+            Reply::Status(String::from("OK")),
+            conn.command(&[b"PING"]).unwrap()
+        );
+        drop(conn);
+
+        assert_eq!(
+            vec![
+                "AUTH app pw",
+                "SELECT 2",
+                "SET a 1",
+                "DEL a",
+                "SADD s x",
+                "SET b 2",
+                "PING"
+            ],
+            server.join().unwrap()
+        );
+    }
+}
@@ -0,0 +1,441 @@
+//! Public key arithmetic on secp256k1, just enough for unhardened BIP32 derivation, taproot
+//! output keys and the compressed keys of undo data. Not constant time, which is fine as no
+//! secret keys are involved.
+
+use std::cmp::Ordering;
+
+use crate::common::utils;
+
+/// Field prime p = 2^256 - 2^32 - 977, limbs in little endian order
+const P: [u64; 4] = [
+    0xfffffffefffffc2f,
+    0xffffffffffffffff,
+    0xffffffffffffffff,
+    0xffffffffffffffff,
+];
+/// 2^256 - p
+const P_COMPLEMENT: u64 = 0x1000003d1;
+/// Group order n
+const N: [u64; 4] = [
+    0xbfd25e8cd0364141,
+    0xbaaedce6af48a03b,
+    0xfffffffffffffffe,
+    0xffffffffffffffff,
+];
+const G_X: [u64; 4] = [
+    0x59f2815b16f81798,
+    0x029bfcdb2dce28d9,
+    0x55a06295ce870b07,
+    0x79be667ef9dcbbac,
+];
+const G_Y: [u64; 4] = [
+    0x9c47d08ffb10d4b8,
+    0xfd17b448a6855419,
+    0x5da4fbfc0e1108a8,
+    0x483ada7726a3c465,
+];
+
+fn cmp_limbs(a: &[u64; 4], b: &[u64; 4]) -> Ordering {
+    a.iter().rev().cmp(b.iter().rev())
+}
+
+fn limbs_from_bytes(bytes: &[u8; 32]) -> [u64; 4] {
+    let mut limbs = [0u64; 4];
+    for (i, limb) in limbs.iter_mut().enumerate() {
+        let mut word = [0u8; 8];
+        word.copy_from_slice(&bytes[24 - i * 8..32 - i * 8]);
+        *limb = u64::from_be_bytes(word);
+    }
+    limbs
+}
+
+/// Element of the field modulo p, always fully reduced
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Fe([u64; 4]);
+
+impl Fe {
+    const ZERO: Fe = Fe([0; 4]);
+    const ONE: Fe = Fe([1, 0, 0, 0]);
+
+    fn from_bytes(bytes: &[u8; 32]) -> Option<Fe> {
+        let limbs = limbs_from_bytes(bytes);
+        match cmp_limbs(&limbs, &P) {
+            Ordering::Less => Some(Fe(limbs)),
+            _ => None,
+        }
+    }
+
+    fn to_bytes(self) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        for (i, limb) in self.0.iter().enumerate() {
+            bytes[24 - i * 8..32 - i * 8].copy_from_slice(&limb.to_be_bytes());
+        }
+        bytes
+    }
+
+    fn is_zero(&self) -> bool {
+        *self == Fe::ZERO
+    }
+
+    fn is_odd(&self) -> bool {
+        self.0[0] & 1 == 1
+    }
+
+    /// Subtracts p if the value isn't reduced, `carry` is bit 256 of the value
+    fn reduce(limbs: [u64; 4], carry: bool) -> Fe {
+        if !carry && cmp_limbs(&limbs, &P) == Ordering::Less {
+            return Fe(limbs);
+        }
+        let mut r = [0u64; 4];
+        let mut borrow = false;
+        for i in 0..4 {
+            let (d, b1) = limbs[i].overflowing_sub(P[i]);
+            let (d, b2) = d.overflowing_sub(borrow as u64);
+            r[i] = d;
+            borrow = b1 || b2;
+        }
+        Fe(r)
+    }
+
+    fn add(&self, other: &Fe) -> Fe {
+        let mut r = [0u64; 4];
+        let mut carry = false;
+        for (i, limb) in r.iter_mut().enumerate() {
+            let (s, c1) = self.0[i].overflowing_add(other.0[i]);
+            let (s, c2) = s.overflowing_add(carry as u64);
+            *limb = s;
+            carry = c1 || c2;
+        }
+        Fe::reduce(r, carry)
+    }
+
+    fn neg(&self) -> Fe {
+        Fe::ZERO.sub(self)
+    }
+
+    fn sub(&self, other: &Fe) -> Fe {
+        let mut r = [0u64; 4];
+        let mut borrow = false;
+        for (i, limb) in r.iter_mut().enumerate() {
+            let (d, b1) = self.0[i].overflowing_sub(other.0[i]);
+            let (d, b2) = d.overflowing_sub(borrow as u64);
+            *limb = d;
+            borrow = b1 || b2;
+        }
+        if borrow {
+            // Wraps back into the field
+            let mut carry = false;
+            for (i, limb) in r.iter_mut().enumerate() {
+                let (s, c1) = limb.overflowing_add(P[i]);
+                let (s, c2) = s.overflowing_add(carry as u64);
+                *limb = s;
+                carry = c1 || c2;
+            }
+        }
+        Fe(r)
+    }
+
+    fn mul(&self, other: &Fe) -> Fe {
+        let mut t = [0u64; 8];
+        for i in 0..4 {
+            let mut carry: u128 = 0;
+            for j in 0..4 {
+                let cur = t[i + j] as u128 + self.0[i] as u128 * other.0[j] as u128 + carry;
+                t[i + j] = cur as u64;
+                carry = cur >> 64;
+            }
+            t[i + 4] = carry as u64;
+        }
+
+        // 2^256 is congruent to P_COMPLEMENT, fold the upper half into the lower one
+        let mut r = [0u64; 4];
+        let mut carry: u128 = 0;
+        for i in 0..4 {
+            let cur = t[i] as u128 + t[i + 4] as u128 * P_COMPLEMENT as u128 + carry;
+            r[i] = cur as u64;
+            carry = cur >> 64;
+        }
+        let mut carry = carry * P_COMPLEMENT as u128;
+        for limb in r.iter_mut() {
+            let cur = *limb as u128 + carry;
+            *limb = cur as u64;
+            carry = cur >> 64;
+        }
+        if carry != 0 {
+            // The value wrapped around 2^256 again, so it's tiny and adding can't overflow
+            let cur = r[0] as u128 + P_COMPLEMENT as u128;
+            r[0] = cur as u64;
+            r[1] += (cur >> 64) as u64;
+        }
+        Fe::reduce(r, false)
+    }
+
+    fn square(&self) -> Fe {
+        self.mul(self)
+    }
+
+    fn pow(&self, exp: &[u64; 4]) -> Fe {
+        let mut r = Fe::ONE;
+        for limb in exp.iter().rev() {
+            for bit in (0..64).rev() {
+                r = r.square();
+                if (limb >> bit) & 1 == 1 {
+                    r = r.mul(self);
+                }
+            }
+        }
+        r
+    }
+
+    fn inv(&self) -> Fe {
+        let mut exp = P;
+        exp[0] -= 2;
+        self.pow(&exp)
+    }
+
+    fn sqrt(&self) -> Option<Fe> {
+        // p = 3 mod 4, so the root is a^((p+1)/4)
+        let exp = [
+            0xffffffffbfffff0c,
+            0xffffffffffffffff,
+            0xffffffffffffffff,
+            0x3fffffffffffffff,
+        ];
+        let root = self.pow(&exp);
+        if root.square() == *self {
+            Some(root)
+        } else {
+            None
+        }
+    }
+}
+
+/// Point in jacobian coordinates (x / z^2, y / z^3), z = 0 is the point at infinity
+#[derive(Clone, Copy)]
+struct Jacobian {
+    x: Fe,
+    y: Fe,
+    z: Fe,
+}
+
+impl Jacobian {
+    const INFINITY: Jacobian = Jacobian {
+        x: Fe::ZERO,
+        y: Fe::ONE,
+        z: Fe::ZERO,
+    };
+
+    fn double(&self) -> Jacobian {
+        if self.z.is_zero() || self.y.is_zero() {
+            return Jacobian::INFINITY;
+        }
+        let a = self.x.square();
+        let b = self.y.square();
+        let c = b.square();
+        let d = self.x.add(&b).square().sub(&a).sub(&c);
+        let d = d.add(&d);
+        let e = a.add(&a).add(&a);
+        let f = e.square();
+        let x = f.sub(&d).sub(&d);
+        let c2 = c.add(&c);
+        let c4 = c2.add(&c2);
+        let c8 = c4.add(&c4);
+        let y = e.mul(&d.sub(&x)).sub(&c8);
+        let z = self.y.mul(&self.z);
+        Jacobian { x, y, z: z.add(&z) }
+    }
+
+    fn add_affine(&self, other: &PublicKey) -> Jacobian {
+        if self.z.is_zero() {
+            return Jacobian {
+                x: other.x,
+                y: other.y,
+                z: Fe::ONE,
+            };
+        }
+        let z1z1 = self.z.square();
+        let u2 = other.x.mul(&z1z1);
+        let s2 = other.y.mul(&self.z).mul(&z1z1);
+        let h = u2.sub(&self.x);
+        let r = s2.sub(&self.y);
+        if h.is_zero() {
+            return if r.is_zero() {
+                self.double()
+            } else {
+                Jacobian::INFINITY
+            };
+        }
+        let hh = h.square();
+        let i = hh.add(&hh);
+        let i = i.add(&i);
+        let j = h.mul(&i);
+        let r = r.add(&r);
+        let v = self.x.mul(&i);
+        let x = r.square().sub(&j).sub(&v).sub(&v);
+        let y1j = self.y.mul(&j);
+        let y = r.mul(&v.sub(&x)).sub(&y1j).sub(&y1j);
+        let z = self.z.add(&h).square().sub(&z1z1).sub(&hh);
+        Jacobian { x, y, z }
+    }
+
+    fn to_affine(self) -> Option<PublicKey> {
+        if self.z.is_zero() {
+            return None;
+        }
+        let zinv = self.z.inv();
+        let zinv2 = zinv.square();
+        Some(PublicKey {
+            x: self.x.mul(&zinv2),
+            y: self.y.mul(&zinv2).mul(&zinv),
+        })
+    }
+}
+
+/// A point on the curve other than infinity
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PublicKey {
+    x: Fe,
+    y: Fe,
+}
+
+impl PublicKey {
+    const GENERATOR: PublicKey = PublicKey {
+        x: Fe(G_X),
+        y: Fe(G_Y),
+    };
+
+    /// Parses a compressed (33 bytes) or uncompressed (65 bytes) SEC1 encoded key
+    pub fn from_slice(bytes: &[u8]) -> Option<PublicKey> {
+        let mut x = [0u8; 32];
+        match (bytes.len(), bytes.first()) {
+            (33, Some(0x02)) | (33, Some(0x03)) => {
+                x.copy_from_slice(&bytes[1..]);
+                let x = Fe::from_bytes(&x)?;
+                let y = x.square().mul(&x).add(&Fe([7, 0, 0, 0])).sqrt()?;
+                let y = if y.is_odd() == (bytes[0] == 0x03) {
+                    y
+                } else {
+                    y.neg()
+                };
+                Some(PublicKey { x, y })
+            }
+            (65, Some(0x04)) => {
+                let mut y = [0u8; 32];
+                x.copy_from_slice(&bytes[1..33]);
+                y.copy_from_slice(&bytes[33..]);
+                let key = PublicKey {
+                    x: Fe::from_bytes(&x)?,
+                    y: Fe::from_bytes(&y)?,
+                };
+                let on_curve = key.y.square() == key.x.square().mul(&key.x).add(&Fe([7, 0, 0, 0]));
+                if on_curve {
+                    Some(key)
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the compressed SEC1 encoding
+    pub fn serialize(&self) -> [u8; 33] {
+        let mut bytes = [0u8; 33];
+        bytes[0] = if self.y.is_odd() { 0x03 } else { 0x02 };
+        bytes[1..].copy_from_slice(&self.x.to_bytes());
+        bytes
+    }
+
+    /// Returns the uncompressed SEC1 encoding
+    pub fn serialize_uncompressed(&self) -> [u8; 65] {
+        let mut bytes = [0u8; 65];
+        bytes[0] = 0x04;
+        bytes[1..33].copy_from_slice(&self.x.to_bytes());
+        bytes[33..].copy_from_slice(&self.y.to_bytes());
+        bytes
+    }
+
+    /// Returns self + tweak * G, None if the tweak isn't below the group order
+    /// or the result is the point at infinity
+    pub fn add_tweak(&self, tweak: &[u8; 32]) -> Option<PublicKey> {
+        let scalar = limbs_from_bytes(tweak);
+        if cmp_limbs(&scalar, &N) != Ordering::Less {
+            return None;
+        }
+        let mut point = Jacobian::INFINITY;
+        for limb in scalar.iter().rev() {
+            for bit in (0..64).rev() {
+                point = point.double();
+                if (limb >> bit) & 1 == 1 {
+                    point = point.add_affine(&PublicKey::GENERATOR);
+                }
+            }
+        }
+        point.add_affine(self).to_affine()
+    }
+
+    /// Returns the x-only output key of a BIP86 key path only taproot output (BIP341)
+    pub fn taproot_output_key(&self) -> Option<[u8; 32]> {
+        let internal = self.x.to_bytes();
+        let even = PublicKey {
+            x: self.x,
+            y: if self.y.is_odd() {
+                self.y.neg()
+            } else {
+                self.y
+            },
+        };
+        let tag = utils::sha256(b"TapTweak");
+        let tweak = utils::sha256(&[&tag[..], &tag[..], &internal[..]].concat());
+        Some(even.add_tweak(&tweak)?.x.to_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_field() {
+        let minus_one = Fe::ONE.neg();
+        assert_eq!(Fe::ONE, minus_one.mul(&minus_one));
+        assert_eq!(Fe::ZERO, minus_one.add(&Fe::ONE));
+        let a = Fe::from_bytes(&PublicKey::GENERATOR.x.to_bytes()).unwrap();
+        assert_eq!(Fe::ONE, a.mul(&a.inv()));
+        let root = a.square().sqrt().unwrap();
+        assert!(root == a || root == a.neg());
+        assert!(Fe::from_bytes(&[0xff; 32]).is_none());
+    }
+
+    #[test]
+    fn test_add_tweak() {
+        let mut one = [0u8; 32];
+        one[31] = 1;
+        let g = PublicKey::GENERATOR;
+        let g2 =
+            utils::hex_to_vec("02c6047f9441ed7d6d3045406e95c07cd85c778e4b8cef3ca7abac09b95c709ee5");
+        assert_eq!(&g2[..], &g.add_tweak(&one).unwrap().serialize()[..]);
+        assert_eq!(
+            Some(g),
+            PublicKey::from_slice(&utils::hex_to_vec(
+                "0479be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798\
+                 483ada7726a3c4655da4fbfc0e1108a8fd17b448a68554199c47d08ffb10d4b8"
+            ))
+        );
+        assert_eq!(Some(g), PublicKey::from_slice(&g.serialize()));
+        assert_eq!(Some(g), PublicKey::from_slice(&g.serialize_uncompressed()));
+
+        // (n - 1) * G + G is the point at infinity
+        let mut n_minus_one = [0u8; 32];
+        n_minus_one.copy_from_slice(&utils::hex_to_vec(
+            "fffffffffffffffffffffffffffffffebaaedce6af48a03bbfd25e8cd0364140",
+        ));
+        assert!(g.add_tweak(&n_minus_one).is_none());
+        assert!(g.add_tweak(&[0xff; 32]).is_none());
+        // x = 5 is not on the curve
+        let mut not_on_curve = [0u8; 33];
+        not_on_curve[0] = 0x02;
+        not_on_curve[32] = 5;
+        assert!(PublicKey::from_slice(&not_on_curve).is_none());
+    }
+}
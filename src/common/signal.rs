@@ -0,0 +1,59 @@
+//! Turns SIGINT and SIGTERM into a flag which the parser checks between blocks.
+//! A second signal exits immediately.
+
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Exit code after a graceful shutdown, as usual 128 + SIGINT
+pub const EXIT_INTERRUPTED: i32 = 130;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Installs the signal handlers and returns the flag they set
+pub fn install() -> io::Result<&'static AtomicBool> {
+    #[cfg(unix)]
+    for signum in [libc::SIGINT, libc::SIGTERM] {
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = handle_signal as extern "C" fn(libc::c_int) as usize;
+            // Blocking reads and writes continue instead of failing with EINTR
+            action.sa_flags = libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            if libc::sigaction(signum, &action, std::ptr::null_mut()) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+    }
+    Ok(&INTERRUPTED)
+}
+
+/// Only uses async-signal-safe functions
+#[cfg(unix)]
+extern "C" fn handle_signal(signum: libc::c_int) {
+    if INTERRUPTED.swap(true, Ordering::SeqCst) {
+        unsafe { libc::_exit(128 + signum) };
+    }
+    let msg =
+        b"\nShutting down after the current block, send the signal again to exit immediately ...\n";
+    unsafe {
+        libc::write(
+            libc::STDERR_FILENO,
+            msg.as_ptr() as *const libc::c_void,
+            msg.len(),
+        )
+    };
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signal_sets_flag() {
+        let flag = install().unwrap();
+        assert!(!flag.load(Ordering::SeqCst));
+        // Only once, the second one would exit the test process
+        unsafe { libc::raise(libc::SIGTERM) };
+        assert!(flag.load(Ordering::SeqCst));
+    }
+}
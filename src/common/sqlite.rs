@@ -0,0 +1,481 @@
+//! Minimal safe wrapper around the system SQLite library.
+//! Statements are prepared once per SQL string and cached by the connection.
+
+use std::collections::HashMap;
+use std::error;
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::os::raw::{c_char, c_int, c_void};
+use std::path::Path;
+use std::ptr;
+
+#[allow(non_camel_case_types)]
+mod ffi {
+    use std::os::raw::{c_char, c_double, c_int, c_void};
+
+    pub enum sqlite3 {}
+    pub enum sqlite3_stmt {}
+
+    pub const SQLITE_OK: c_int = 0;
+    pub const SQLITE_ROW: c_int = 100;
+    pub const SQLITE_DONE: c_int = 101;
+    pub const SQLITE_NULL: c_int = 5;
+    pub const SQLITE_OPEN_READWRITE: c_int = 0x02;
+    pub const SQLITE_OPEN_CREATE: c_int = 0x04;
+    // Makes SQLite copy bound text and blobs
+    pub const SQLITE_TRANSIENT: isize = -1;
+
+    #[link(name = "sqlite3")]
+    extern "C" {
+        pub fn sqlite3_open_v2(
+            filename: *const c_char,
+            db: *mut *mut sqlite3,
+            flags: c_int,
+            vfs: *const c_char,
+        ) -> c_int;
+        pub fn sqlite3_close(db: *mut sqlite3) -> c_int;
+        pub fn sqlite3_errmsg(db: *mut sqlite3) -> *const c_char;
+        pub fn sqlite3_exec(
+            db: *mut sqlite3,
+            sql: *const c_char,
+            callback: *const c_void,
+            arg: *mut c_void,
+            errmsg: *mut *mut c_char,
+        ) -> c_int;
+        pub fn sqlite3_free(ptr: *mut c_void);
+        pub fn sqlite3_changes(db: *mut sqlite3) -> c_int;
+
+        pub fn sqlite3_prepare_v2(
+            db: *mut sqlite3,
+            sql: *const c_char,
+            n_byte: c_int,
+            stmt: *mut *mut sqlite3_stmt,
+            tail: *mut *const c_char,
+        ) -> c_int;
+        pub fn sqlite3_bind_parameter_count(stmt: *mut sqlite3_stmt) -> c_int;
+        pub fn sqlite3_bind_null(stmt: *mut sqlite3_stmt, index: c_int) -> c_int;
+        pub fn sqlite3_bind_int64(stmt: *mut sqlite3_stmt, index: c_int, value: i64) -> c_int;
+        pub fn sqlite3_bind_double(stmt: *mut sqlite3_stmt, index: c_int, value: c_double)
+            -> c_int;
+        pub fn sqlite3_bind_text(
+            stmt: *mut sqlite3_stmt,
+            index: c_int,
+            value: *const c_char,
+            n_byte: c_int,
+            destructor: isize,
+        ) -> c_int;
+        pub fn sqlite3_bind_blob(
+            stmt: *mut sqlite3_stmt,
+            index: c_int,
+            value: *const c_void,
+            n_byte: c_int,
+            destructor: isize,
+        ) -> c_int;
+        pub fn sqlite3_step(stmt: *mut sqlite3_stmt) -> c_int;
+        pub fn sqlite3_reset(stmt: *mut sqlite3_stmt) -> c_int;
+        pub fn sqlite3_clear_bindings(stmt: *mut sqlite3_stmt) -> c_int;
+        pub fn sqlite3_finalize(stmt: *mut sqlite3_stmt) -> c_int;
+
+        pub fn sqlite3_column_count(stmt: *mut sqlite3_stmt) -> c_int;
+        pub fn sqlite3_column_type(stmt: *mut sqlite3_stmt, index: c_int) -> c_int;
+        pub fn sqlite3_column_int64(stmt: *mut sqlite3_stmt, index: c_int) -> i64;
+        pub fn sqlite3_column_double(stmt: *mut sqlite3_stmt, index: c_int) -> c_double;
+        pub fn sqlite3_column_text(stmt: *mut sqlite3_stmt, index: c_int) -> *const u8;
+        pub fn sqlite3_column_bytes(stmt: *mut sqlite3_stmt, index: c_int) -> c_int;
+    }
+}
+
+#[derive(Debug)]
+pub struct SqliteError {
+    pub code: i32,
+    pub message: String,
+}
+
+impl SqliteError {
+    fn new(code: i32, message: &str) -> Self {
+        SqliteError {
+            code,
+            message: String::from(message),
+        }
+    }
+}
+
+impl fmt::Display for SqliteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (code {})", self.message, self.code)
+    }
+}
+
+impl error::Error for SqliteError {}
+
+/// Parameter of a statement
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value<'a> {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Text(&'a str),
+    Blob(&'a [u8]),
+}
+
+impl From<i64> for Value<'_> {
+    fn from(value: i64) -> Self {
+        Value::Integer(value)
+    }
+}
+
+impl From<u32> for Value<'_> {
+    fn from(value: u32) -> Self {
+        Value::Integer(value as i64)
+    }
+}
+
+impl From<bool> for Value<'_> {
+    fn from(value: bool) -> Self {
+        Value::Integer(value as i64)
+    }
+}
+
+impl From<f64> for Value<'_> {
+    fn from(value: f64) -> Self {
+        Value::Real(value)
+    }
+}
+
+impl<'a> From<&'a str> for Value<'a> {
+    fn from(value: &'a str) -> Self {
+        Value::Text(value)
+    }
+}
+
+impl<'a> From<&'a [u8]> for Value<'a> {
+    fn from(value: &'a [u8]) -> Self {
+        Value::Blob(value)
+    }
+}
+
+impl<'a, T: Into<Value<'a>>> From<Option<T>> for Value<'a> {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(Value::Null, Into::into)
+    }
+}
+
+/// Current row of a query
+pub struct Row {
+    stmt: *mut ffi::sqlite3_stmt,
+}
+
+impl Row {
+    pub fn column_count(&self) -> usize {
+        unsafe { ffi::sqlite3_column_count(self.stmt) as usize }
+    }
+
+    fn is_null(&self, index: usize) -> bool {
+        unsafe { ffi::sqlite3_column_type(self.stmt, index as c_int) == ffi::SQLITE_NULL }
+    }
+
+    /// Returns None for NULL values
+    pub fn get_i64(&self, index: usize) -> Option<i64> {
+        if index >= self.column_count() || self.is_null(index) {
+            return None;
+        }
+        Some(unsafe { ffi::sqlite3_column_int64(self.stmt, index as c_int) })
+    }
+
+    pub fn get_f64(&self, index: usize) -> Option<f64> {
+        if index >= self.column_count() || self.is_null(index) {
+            return None;
+        }
+        Some(unsafe { ffi::sqlite3_column_double(self.stmt, index as c_int) })
+    }
+
+    pub fn get_text(&self, index: usize) -> Option<String> {
+        if index >= self.column_count() || self.is_null(index) {
+            return None;
+        }
+        unsafe {
+            let text = ffi::sqlite3_column_text(self.stmt, index as c_int);
+            // Has to be called after sqlite3_column_text(), which may convert the value
+            let len = ffi::sqlite3_column_bytes(self.stmt, index as c_int) as usize;
+            if text.is_null() {
+                return Some(String::new());
+            }
+            let bytes = std::slice::from_raw_parts(text, len);
+            Some(String::from_utf8_lossy(bytes).into_owned())
+        }
+    }
+}
+
+pub struct Connection {
+    db: *mut ffi::sqlite3,
+    statements: HashMap<&'static str, *mut ffi::sqlite3_stmt>,
+}
+
+impl Connection {
+    /// Opens or creates the database at the given path
+    pub fn open(path: &Path) -> Result<Connection, SqliteError> {
+        let path = CString::new(path.to_string_lossy().as_bytes())
+            .map_err(|_| SqliteError::new(-1, "path contains a NUL byte"))?;
+        let mut db = ptr::null_mut();
+        let flags = ffi::SQLITE_OPEN_READWRITE | ffi::SQLITE_OPEN_CREATE;
+        let code = unsafe { ffi::sqlite3_open_v2(path.as_ptr(), &mut db, flags, ptr::null()) };
+        let conn = Connection {
+            db,
+            statements: HashMap::new(),
+        };
+        if code != ffi::SQLITE_OK {
+            if db.is_null() {
+                return Err(SqliteError::new(code, "out of memory"));
+            }
+            return Err(conn.error(code));
+        }
+        Ok(conn)
+    }
+
+    /// Executes one or more statements without parameters
+    pub fn execute_batch(&mut self, sql: &str) -> Result<(), SqliteError> {
+        let sql = CString::new(sql).map_err(|_| SqliteError::new(-1, "SQL contains a NUL byte"))?;
+        let mut errmsg: *mut c_char = ptr::null_mut();
+        let code = unsafe {
+            ffi::sqlite3_exec(
+                self.db,
+                sql.as_ptr(),
+                ptr::null(),
+                ptr::null_mut(),
+                &mut errmsg,
+            )
+        };
+        if code == ffi::SQLITE_OK {
+            return Ok(());
+        }
+        if errmsg.is_null() {
+            return Err(self.error(code));
+        }
+        let message = unsafe { CStr::from_ptr(errmsg) }
+            .to_string_lossy()
+            .into_owned();
+        unsafe { ffi::sqlite3_free(errmsg as *mut c_void) };
+        Err(SqliteError { code, message })
+    }
+
+    /// Executes a single statement and returns the number of changed rows
+    pub fn execute(&mut self, sql: &'static str, params: &[Value]) -> Result<usize, SqliteError> {
+        let stmt = self.bind(sql, params)?;
+        let result = loop {
+            match unsafe { ffi::sqlite3_step(stmt) } {
+                ffi::SQLITE_ROW => continue,
+                ffi::SQLITE_DONE => break Ok(unsafe { ffi::sqlite3_changes(self.db) } as usize),
+                code => break Err(self.error(code)),
+            }
+        };
+        self.reset(stmt);
+        result
+    }
+
+    /// Runs a query and maps its first row, returns None if there is no row
+    pub fn query_row<T, F>(
+        &mut self,
+        sql: &'static str,
+        params: &[Value],
+        f: F,
+    ) -> Result<Option<T>, SqliteError>
+    where
+        F: FnOnce(&Row) -> T,
+    {
+        let stmt = self.bind(sql, params)?;
+        let result = match unsafe { ffi::sqlite3_step(stmt) } {
+            ffi::SQLITE_ROW => Ok(Some(f(&Row { stmt }))),
+            ffi::SQLITE_DONE => Ok(None),
+            code => Err(self.error(code)),
+        };
+        self.reset(stmt);
+        result
+    }
+
+    /// Returns the cached statement with all parameters bound
+    fn bind(
+        &mut self,
+        sql: &'static str,
+        params: &[Value],
+    ) -> Result<*mut ffi::sqlite3_stmt, SqliteError> {
+        let stmt = self.prepare(sql)?;
+        let n_params = unsafe { ffi::sqlite3_bind_parameter_count(stmt) } as usize;
+        if n_params != params.len() {
+            return Err(SqliteError::new(
+                -1,
+                &format!(
+                    "statement expects {} parameters, got {}",
+                    n_params,
+                    params.len()
+                ),
+            ));
+        }
+        for (i, param) in params.iter().enumerate() {
+            let index = i as c_int + 1;
+            let code = unsafe {
+                match param {
+                    Value::Null => ffi::sqlite3_bind_null(stmt, index),
+                    Value::Integer(value) => ffi::sqlite3_bind_int64(stmt, index, *value),
+                    Value::Real(value) => ffi::sqlite3_bind_double(stmt, index, *value),
+                    Value::Text(value) => ffi::sqlite3_bind_text(
+                        stmt,
+                        index,
+                        value.as_ptr() as *const c_char,
+                        value.len() as c_int,
+                        ffi::SQLITE_TRANSIENT,
+                    ),
+                    Value::Blob(value) => ffi::sqlite3_bind_blob(
+                        stmt,
+                        index,
+                        value.as_ptr() as *const c_void,
+                        value.len() as c_int,
+                        ffi::SQLITE_TRANSIENT,
+                    ),
+                }
+            };
+            if code != ffi::SQLITE_OK {
+                let err = self.error(code);
+                self.reset(stmt);
+                return Err(err);
+            }
+        }
+        Ok(stmt)
+    }
+
+    fn prepare(&mut self, sql: &'static str) -> Result<*mut ffi::sqlite3_stmt, SqliteError> {
+        if let Some(stmt) = self.statements.get(sql) {
+            return Ok(*stmt);
+        }
+        let mut stmt = ptr::null_mut();
+        let code = unsafe {
+            ffi::sqlite3_prepare_v2(
+                self.db,
+                sql.as_ptr() as *const c_char,
+                sql.len() as c_int,
+                &mut stmt,
+                ptr::null_mut(),
+            )
+        };
+        if code != ffi::SQLITE_OK {
+            return Err(self.error(code));
+        }
+        if stmt.is_null() {
+            return Err(SqliteError::new(-1, "empty statement"));
+        }
+        self.statements.insert(sql, stmt);
+        Ok(stmt)
+    }
+
+    fn reset(&self, stmt: *mut ffi::sqlite3_stmt) {
+        unsafe {
+            ffi::sqlite3_reset(stmt);
+            ffi::sqlite3_clear_bindings(stmt);
+        }
+    }
+
+    fn error(&self, code: c_int) -> SqliteError {
+        let message = unsafe { CStr::from_ptr(ffi::sqlite3_errmsg(self.db)) };
+        SqliteError::new(code, &message.to_string_lossy())
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        unsafe {
+            for (_, stmt) in self.statements.drain() {
+                ffi::sqlite3_finalize(stmt);
+            }
+            ffi::sqlite3_close(self.db);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn memory() -> Connection {
+        Connection::open(Path::new(":memory:")).unwrap()
+    }
+
+    #[test]
+    fn test_execute_query() {
+        let mut conn = memory();
+        conn.execute_batch("CREATE TABLE t (i INTEGER, r REAL, s TEXT, b BLOB);")
+            .unwrap();
+        let insert = "INSERT INTO t VALUES (?, ?, ?, ?)";
+        assert_eq!(
+            1,
+            conn.execute(
+                insert,
+                &[
+                    Value::from(42i64),
+                    Value::from(1.5),
+                    Value::from("abc"),
+                    Value::from(&b"\x00\x01"[..])
+                ]
+            )
+            .unwrap()
+        );
+        conn.execute(
+            insert,
+            &[
+                Value::from(None::<i64>),
+                Value::Null,
+                Value::from(Some("def")),
+                Value::Null,
+            ],
+        )
+        .unwrap();
+
+        let select = "SELECT i, r, s, length(b) FROM t WHERE s = ?";
+        let row = conn
+            .query_row(select, &[Value::from("abc")], |row| {
+                (
+                    row.get_i64(0),
+                    row.get_f64(1),
+                    row.get_text(2),
+                    row.get_i64(3),
+                )
+            })
+            .unwrap();
+        assert_eq!(
+            Some((Some(42), Some(1.5), Some(String::from("abc")), Some(2))),
+            row
+        );
+        let row = conn
+            .query_row(select, &[Value::from("def")], |row| {
+                (row.get_i64(0), row.get_text(2), row.get_i64(7))
+            })
+            .unwrap();
+        assert_eq!(Some((None, Some(String::from("def")), None)), row);
+        assert_eq!(
+            None,
+            conn.query_row(select, &[Value::from("xyz")], |row| row.get_i64(0))
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut conn = memory();
+        let err = conn.execute_batch("CREATE TABL t (i);").unwrap_err();
+        assert!(err.message.contains("syntax error"), "{}", err);
+
+        let err = conn.execute("SELECT * FROM missing", &[]).unwrap_err();
+        assert!(err.message.contains("no such table"), "{}", err);
+
+        conn.execute_batch("CREATE TABLE t (i INTEGER NOT NULL);")
+            .unwrap();
+        let err = conn
+            .execute("INSERT INTO t VALUES (?)", &[Value::Null])
+            .unwrap_err();
+        assert!(err.message.contains("NOT NULL"), "{}", err);
+        // The statement is still usable
+        conn.execute("INSERT INTO t VALUES (?)", &[Value::from(1i64)])
+            .unwrap();
+
+        let err = conn.execute("INSERT INTO t VALUES (?)", &[]).unwrap_err();
+        assert!(err.message.contains("expects 1 parameters"), "{}", err);
+    }
+}
@@ -0,0 +1,272 @@
+//! Merging t-digest (Dunning & Ertl) to estimate quantiles of a stream in constant memory.
+//!
+//! Values are buffered and merged into centroids once the buffer is full. The `asin` scale
+//! function keeps the centroids at the tails small, so extreme quantiles are more accurate than
+//! the median. Until the first merge all values are kept and quantiles are exact.
+
+use std::f64::consts::PI;
+
+/// Default number of centroids, roughly. Rank errors are below 1% with it.
+pub const DEFAULT_COMPRESSION: f64 = 100.0;
+// Values buffered before merging, as a multiple of the compression
+const BUFFER_FACTOR: usize = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Centroid {
+    mean: f64,
+    weight: f64,
+}
+
+#[derive(Clone, Debug)]
+pub struct TDigest {
+    compression: f64,
+    centroids: Vec<Centroid>,
+    buffer: Vec<f64>,
+    count: u64,
+    min: f64,
+    max: f64,
+}
+
+impl Default for TDigest {
+    fn default() -> Self {
+        TDigest::new(DEFAULT_COMPRESSION)
+    }
+}
+
+impl TDigest {
+    pub fn new(compression: f64) -> Self {
+        TDigest {
+            compression,
+            centroids: Vec::new(),
+            buffer: Vec::with_capacity(compression as usize * BUFFER_FACTOR),
+            count: 0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+        }
+    }
+
+    pub fn add(&mut self, value: f64) {
+        self.buffer.push(value);
+        self.count += 1;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        if self.buffer.len() >= self.compression as usize * BUFFER_FACTOR {
+            self.merge();
+        }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn min(&self) -> Option<f64> {
+        (self.count > 0).then_some(self.min)
+    }
+
+    pub fn max(&self) -> Option<f64> {
+        (self.count > 0).then_some(self.max)
+    }
+
+    /// Estimates the value at quantile `q` (0.0 to 1.0). Exact (nearest-rank) as long as
+    /// nothing was merged yet.
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        if self.count == 0 {
+            return None;
+        }
+        let q = q.clamp(0.0, 1.0);
+        if self.centroids.is_empty() {
+            let mut sorted = self.buffer.clone();
+            sorted.sort_by(f64::total_cmp);
+            let rank = ((q * sorted.len() as f64).ceil() as usize).max(1);
+            return Some(sorted[rank - 1]);
+        }
+        if !self.buffer.is_empty() {
+            let mut merged = self.clone();
+            merged.merge();
+            return merged.quantile(q);
+        }
+        Some(self.interpolate(q))
+    }
+
+    /// Number of centroids after merging the buffer
+    #[cfg(test)]
+    fn size(&mut self) -> usize {
+        self.merge();
+        self.centroids.len()
+    }
+
+    /// Merges the buffered values into the centroids
+    fn merge(&mut self) {
+        if self.buffer.is_empty() {
+            return;
+        }
+        let mut all: Vec<Centroid> = self
+            .buffer
+            .drain(..)
+            .map(|value| Centroid {
+                mean: value,
+                weight: 1.0,
+            })
+            .collect();
+        all.append(&mut self.centroids);
+        all.sort_by(|a, b| a.mean.total_cmp(&b.mean));
+
+        let total: f64 = all.iter().map(|c| c.weight).sum();
+        let mut merged = Vec::with_capacity(self.compression as usize);
+        let mut current = all[0];
+        let mut weight_before = 0.0;
+        let mut q_limit = self.q_limit(0.0);
+        for next in all.into_iter().skip(1) {
+            if (weight_before + current.weight + next.weight) / total <= q_limit {
+                let weight = current.weight + next.weight;
+                current.mean += (next.mean - current.mean) * next.weight / weight;
+                current.weight = weight;
+            } else {
+                weight_before += current.weight;
+                merged.push(current);
+                q_limit = self.q_limit(weight_before / total);
+                current = next;
+            }
+        }
+        merged.push(current);
+        self.centroids = merged;
+    }
+
+    /// Highest quantile a centroid starting at `q` may reach, one unit of the scale function
+    /// k(q) = compression / 2pi * asin(2q - 1) further
+    fn q_limit(&self, q: f64) -> f64 {
+        let k = self.compression / (2.0 * PI) * (2.0 * q - 1.0).asin();
+        let next = (k + 1.0) * 2.0 * PI / self.compression;
+        if next >= PI / 2.0 {
+            1.0
+        } else {
+            (next.sin() + 1.0) / 2.0
+        }
+    }
+
+    /// Interpolates between the centers of the centroids, and min and max outside of them
+    fn interpolate(&self, q: f64) -> f64 {
+        let target = q * self.count as f64;
+        let first = self.centroids[0];
+        if target < first.weight / 2.0 {
+            return self.min + (first.mean - self.min) * target / (first.weight / 2.0);
+        }
+        let mut weight_before = 0.0;
+        for pair in self.centroids.windows(2) {
+            let (left, right) = (pair[0], pair[1]);
+            let left_center = weight_before + left.weight / 2.0;
+            let right_center = weight_before + left.weight + right.weight / 2.0;
+            if target < right_center {
+                let t = (target - left_center) / (right_center - left_center);
+                return left.mean + (right.mean - left.mean) * t;
+            }
+            weight_before += left.weight;
+        }
+        let last = self.centroids[self.centroids.len() - 1];
+        let last_center = self.count as f64 - last.weight / 2.0;
+        let t = ((target - last_center) / (last.weight / 2.0)).min(1.0);
+        last.mean + (self.max - last.mean) * t
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Permutation of 0..n, so the values arrive unsorted
+    fn shuffled(n: u64) -> Vec<f64> {
+        // 7919 is prime and doesn't divide n
+        (0..n).map(|i| ((i * 7919) % n) as f64).collect()
+    }
+
+    #[test]
+    fn test_exact_small_samples() {
+        let mut digest = TDigest::default();
+        assert_eq!(None, digest.quantile(0.5));
+        assert_eq!(None, digest.min());
+        for value in [7.0, 3.0, 9.0, 1.0, 10.0, 4.0, 2.0, 6.0, 8.0, 5.0] {
+            digest.add(value);
+        }
+        assert_eq!(10, digest.count());
+        assert_eq!(Some(3.0), digest.quantile(0.25));
+        assert_eq!(Some(5.0), digest.quantile(0.5));
+        assert_eq!(Some(9.0), digest.quantile(0.9));
+        assert_eq!(Some(1.0), digest.quantile(0.0));
+        assert_eq!(Some(10.0), digest.quantile(1.0));
+        assert_eq!((Some(1.0), Some(10.0)), (digest.min(), digest.max()));
+
+        let mut single = TDigest::default();
+        single.add(42.0);
+        assert_eq!(Some(42.0), single.quantile(0.99));
+
+        // Still exact right before the first merge
+        let mut digest = TDigest::default();
+        for value in shuffled(999) {
+            digest.add(value);
+        }
+        assert!(digest.centroids.is_empty());
+        assert_eq!(Some(499.0), digest.quantile(0.5));
+        assert_eq!(Some(989.0), digest.quantile(0.99));
+    }
+
+    #[test]
+    fn test_accuracy() {
+        let n = 100_000;
+        let mut digest = TDigest::default();
+        for value in shuffled(n) {
+            digest.add(value);
+        }
+        // Values are their own ranks, so the error is the rank error
+        for (q, max_error) in [
+            (0.001, 0.0005),
+            (0.01, 0.001),
+            (0.1, 0.005),
+            (0.25, 0.01),
+            (0.5, 0.01),
+            (0.75, 0.01),
+            (0.9, 0.005),
+            (0.99, 0.001),
+            (0.999, 0.0005),
+        ] {
+            let estimate = digest.quantile(q).unwrap();
+            let error = (estimate / n as f64 - q).abs();
+            assert!(error < max_error, "q={}: {} (error {})", q, estimate, error);
+        }
+        assert_eq!(Some(0.0), digest.quantile(0.0));
+        assert_eq!(Some((n - 1) as f64), digest.quantile(1.0));
+        assert_eq!(n, digest.count());
+        // Constant memory
+        assert!(digest.size() <= DEFAULT_COMPRESSION as usize);
+    }
+
+    #[test]
+    fn test_skewed() {
+        // Mostly small values with a long tail, like transaction sizes
+        let mut digest = TDigest::default();
+        let mut exact = Vec::new();
+        for i in 0..50_000u64 {
+            let value = if i % 100 == 0 {
+                10_000.0 + (i % 977) as f64 * 100.0
+            } else {
+                200.0 + (i % 61) as f64
+            };
+            digest.add(value);
+            exact.push(value);
+        }
+        exact.sort_by(f64::total_cmp);
+        for q in [0.5, 0.9, 0.995] {
+            let estimate = digest.quantile(q).unwrap();
+            let rank = exact.partition_point(|v| *v < estimate) as f64 / exact.len() as f64;
+            let rank_hi = exact.partition_point(|v| *v <= estimate) as f64 / exact.len() as f64;
+            // The estimate lies within 1% of the requested rank
+            assert!(
+                rank - 0.01 <= q && q <= rank_hi + 0.01,
+                "q={}: {} at ranks {}..{}",
+                q,
+                estimate,
+                rank,
+                rank_hi
+            );
+        }
+    }
+}
@@ -4,6 +4,7 @@ use crate::blockchain::parser::types::CoinType;
 use crate::crypto::digest::Digest;
 use crate::crypto::ripemd160::Ripemd160;
 use crate::crypto::sha2::Sha256;
+use crate::errors::{OpError, OpErrorKind, OpResult};
 
 #[inline]
 pub fn ridemp160(data: &[u8]) -> [u8; 20] {
@@ -23,52 +24,140 @@ pub fn sha256(data: &[u8]) -> [u8; 32] {
     out
 }
 
+/// Double SHA256, which hashes headers, transactions and merkle tree nodes
+#[inline]
+pub fn sha256d(data: &[u8]) -> [u8; 32] {
+    let mut out = [0u8; 32];
+    let mut hasher = Sha256::new();
+    hasher.input(data);
+    hasher.result(&mut out);
+    hasher.reset();
+    hasher.input(&out);
+    hasher.result(&mut out);
+    out
+}
+
 /// Calculates merkle root for the whole block
 /// See: https://en.bitcoin.it/wiki/Protocol_documentation#Merkle_Trees
+#[inline]
 pub fn merkle_root(hashes: &[[u8; 32]]) -> [u8; 32] {
-    let mut hashes = Vec::from(hashes);
-
-    while hashes.len() > 1 {
-        // Calculates double sha hash for each pair. If len is odd, last value is ignored.
-        let mut new_hashes = hashes
-            .chunks(2)
-            .filter(|c| c.len() == 2)
-            .map(|c| sha256(&sha256(&[c[0], c[1]].concat())))
-            .collect::<Vec<[u8; 32]>>();
-
-        // If the length is odd, take the last hash twice
-        if hashes.len() % 2 == 1 {
-            let last_hash = hashes.last().unwrap();
-            new_hashes.push(sha256(&sha256(&[&last_hash[..], &last_hash[..]].concat())));
+    merkle_root_mutated(hashes).0
+}
+
+/// Calculates the merkle root and whether the tree is mutated (CVE-2012-2459).
+/// Levels with an odd number of hashes pair the last one with itself, so repeating the last
+/// transactions of a block gives the same root. Like Bitcoin Core, a pair of equal hashes on any
+/// level marks the tree as mutated. The root of no hashes is zero.
+pub fn merkle_root_mutated(hashes: &[[u8; 32]]) -> ([u8; 32], bool) {
+    let mut level = Vec::from(hashes);
+    let mut mutated = false;
+    let mut pair = [0u8; 64];
+    while level.len() > 1 {
+        // Checked before padding, the duplicated last hash is no mutation
+        mutated |= level.chunks_exact(2).any(|pair| pair[0] == pair[1]);
+        if level.len() % 2 == 1 {
+            level.push(level[level.len() - 1]);
+        }
+        // Hashes the level in place, the parent of the pair at 2i ends up at i
+        for i in 0..level.len() / 2 {
+            pair[..32].copy_from_slice(&level[2 * i]);
+            pair[32..].copy_from_slice(&level[2 * i + 1]);
+            level[i] = sha256d(&pair);
         }
-        hashes = new_hashes;
+        level.truncate(level.len() / 2);
+    }
+    (level.first().copied().unwrap_or([0u8; 32]), mutated)
+}
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+/// Appends the lowercase hex digits of `data` to `buf`, which can be reused to avoid allocations
+#[inline]
+pub fn hex_into(buf: &mut String, data: &[u8]) {
+    buf.reserve(data.len() * 2);
+    for b in data {
+        buf.push(HEX_DIGITS[(b >> 4) as usize] as char);
+        buf.push(HEX_DIGITS[(b & 0x0f) as usize] as char);
+    }
+}
+
+/// Like `hex_into()` in reversed byte order, hashes are displayed this way
+#[inline]
+pub fn hex_into_swapped(buf: &mut String, data: &[u8]) {
+    buf.reserve(data.len() * 2);
+    for b in data.iter().rev() {
+        buf.push(HEX_DIGITS[(b >> 4) as usize] as char);
+        buf.push(HEX_DIGITS[(b & 0x0f) as usize] as char);
     }
-    *hashes.first().unwrap()
 }
 
+#[inline]
 pub fn arr_to_hex(data: &[u8]) -> String {
-    data.iter()
-        .map(|b| format!("{:02x?}", b))
-        .collect::<String>()
+    let mut hex = String::with_capacity(data.len() * 2);
+    hex_into(&mut hex, data);
+    hex
 }
 
 #[inline]
 pub fn arr_to_hex_swapped(data: &[u8]) -> String {
-    data.iter()
-        .rev()
-        .map(|b| format!("{:02x}", b))
-        .collect::<String>()
+    let mut hex = String::with_capacity(data.len() * 2);
+    hex_into_swapped(&mut hex, data);
+    hex
 }
 
-pub fn hex_to_vec(hex_str: &str) -> Vec<u8> {
-    if hex_str.len() % 2 != 0 {
-        panic!("string length is not even");
+#[inline]
+fn hex_digit(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// Decodes `hex_str` into `out`, which determines the expected length
+fn decode_hex(hex_str: &str, out: &mut [u8]) -> OpResult<()> {
+    let invalid = |reason: &str| {
+        OpError::new(OpErrorKind::InvalidArgsError)
+            .join_msg(&format!("Invalid hex string `{}`: {}", hex_str, reason))
+    };
+    if hex_str.len() != out.len() * 2 {
+        return Err(invalid(&format!(
+            "expected {} characters, got {}",
+            out.len() * 2,
+            hex_str.len()
+        )));
     }
+    for (byte, pair) in out.iter_mut().zip(hex_str.as_bytes().chunks_exact(2)) {
+        match (hex_digit(pair[0]), hex_digit(pair[1])) {
+            (Some(high), Some(low)) => *byte = high << 4 | low,
+            _ => return Err(invalid("not a hex digit")),
+        }
+    }
+    Ok(())
+}
+
+/// Decodes a hex string of exactly `N` bytes, e.g. a script or a key
+pub fn hex_to_arr<const N: usize>(hex_str: &str) -> OpResult<[u8; N]> {
+    let mut arr = [0u8; N];
+    decode_hex(hex_str, &mut arr)?;
+    Ok(arr)
+}
 
-    (0..hex_str.len())
-        .step_by(2)
-        .map(|i| u8::from_str_radix(&hex_str[i..i + 2], 16).unwrap())
-        .collect()
+/// Decodes a hex string of exactly `N` bytes in reversed byte order, e.g. a txid or block hash
+pub fn hex_to_arr_swapped<const N: usize>(hex_str: &str) -> OpResult<[u8; N]> {
+    let mut arr = hex_to_arr(hex_str)?;
+    arr.reverse();
+    Ok(arr)
+}
+
+/// Decodes hex of known good data like constants, panics on invalid input
+pub fn hex_to_vec(hex_str: &str) -> Vec<u8> {
+    let mut vec = vec![0u8; hex_str.len() / 2];
+    if let Err(err) = decode_hex(hex_str, &mut vec) {
+        panic!("{}", err);
+    }
+    vec
 }
 
 #[inline]
@@ -78,23 +167,37 @@ pub fn hex_to_vec_swapped(hex_str: &str) -> Vec<u8> {
     vec
 }
 
+/// Decodes hashes of known good data like constants, see `hex_to_arr_swapped()` for user input
 #[inline]
 pub fn hex_to_arr32_swapped(hex_str: &str) -> [u8; 32] {
-    debug_assert_eq!(hex_str.len(), 64);
-    let mut arr = [0u8; 32];
-    for (place, element) in arr.iter_mut().zip(hex_to_vec(hex_str).iter().rev()) {
-        *place = *element;
+    match hex_to_arr_swapped(hex_str) {
+        Ok(arr) => arr,
+        Err(err) => panic!("{}", err),
     }
-    arr
 }
 
-/// Returns default directory. TODO: test on windows
 pub fn get_absolute_blockchain_dir(coin_type: &CoinType) -> PathBuf {
     dirs::home_dir()
         .expect("Unable to get home path from env!")
         .join(coin_type.default_folder.clone())
 }
 
+/// Returns the peak resident memory of the process in bytes, if the platform reports it
+pub fn peak_memory() -> Option<u64> {
+    #[cfg(unix)]
+    {
+        let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+        if unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) } != 0 {
+            return None;
+        }
+        // Linux reports KiB, macOS bytes
+        let unit = if cfg!(target_os = "macos") { 1 } else { 1024 };
+        Some(usage.ru_maxrss as u64 * unit)
+    }
+    #[cfg(not(unix))]
+    None
+}
+
 /// Get mean value from u32 slice
 #[inline]
 pub fn get_mean(slice: &[u32]) -> f64 {
@@ -105,10 +208,91 @@ pub fn get_mean(slice: &[u32]) -> f64 {
     sum as f64 / slice.len() as f64
 }
 
+/// Returns the UTC date (year, month, day) of an unix timestamp
+fn timestamp_to_date(timestamp: u32) -> (i32, u32, u32) {
+    // Days since 0000-03-01, so leap days are at the end of a year
+    let days = (timestamp / 86400) as i64 + 719468;
+    let era = days / 146097;
+    let day_of_era = days % 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let march_based_month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * march_based_month + 2) / 5 + 1) as u32;
+    let year = (era * 400 + year_of_era) as i32;
+    // January and February belong to the following year
+    if march_based_month >= 10 {
+        (year + 1, march_based_month as u32 - 9, day)
+    } else {
+        (year, march_based_month as u32 + 3, day)
+    }
+}
+
+/// Returns the UTC year of an unix timestamp
+pub fn timestamp_to_year(timestamp: u32) -> i32 {
+    timestamp_to_date(timestamp).0
+}
+
+/// Formats an unix timestamp as UTC date and time, e.g. `2009-01-03 18:15:05`
+pub fn timestamp_to_datetime(timestamp: u32) -> String {
+    let (year, month, day) = timestamp_to_date(timestamp);
+    let seconds = timestamp % 86400;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+/// Formats an unix timestamp as UTC date and time, e.g. `2009-01-03T18:15:05Z`
+pub fn timestamp_to_iso8601(timestamp: u32) -> String {
+    let datetime = timestamp_to_datetime(timestamp);
+    format!("{}T{}Z", &datetime[..10], &datetime[11..])
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    #[cfg(unix)]
+    fn test_peak_memory() {
+        let before = peak_memory().unwrap();
+        assert!(before > 1024 * 1024);
+        let data = vec![1u8; 64 * 1024 * 1024];
+        assert!(peak_memory().unwrap() >= before.max(data.iter().map(|b| *b as u64).sum()));
+    }
+
+    #[test]
+    fn test_timestamp_to_year() {
+        assert_eq!(1970, timestamp_to_year(0));
+        // Genesis block
+        assert_eq!(2009, timestamp_to_year(1231006505));
+        assert_eq!(2016, timestamp_to_year(1456703999)); // 2016-02-28 23:59:59
+        assert_eq!(2016, timestamp_to_year(1483228799)); // 2016-12-31 23:59:59
+        assert_eq!(2017, timestamp_to_year(1483228800));
+        assert_eq!(2106, timestamp_to_year(u32::MAX));
+    }
+
+    #[test]
+    fn test_timestamp_to_iso8601() {
+        assert_eq!("1970-01-01T00:00:00Z", timestamp_to_iso8601(0));
+        assert_eq!("2009-01-03T18:15:05Z", timestamp_to_iso8601(1231006505));
+        assert_eq!("2016-02-29T00:00:00Z", timestamp_to_iso8601(1456704000));
+        assert_eq!("2016-12-31T23:59:59Z", timestamp_to_iso8601(1483228799));
+        assert_eq!("2106-02-07T06:28:15Z", timestamp_to_iso8601(u32::MAX));
+    }
+
+    #[test]
+    fn test_timestamp_to_datetime() {
+        assert_eq!("2009-01-03 18:15:05", timestamp_to_datetime(1231006505));
+        assert_eq!("2016-02-29 00:00:00", timestamp_to_datetime(1456704000));
+    }
+
     #[test]
     fn test_arr_to_hex() {
         let test = [
@@ -139,7 +323,101 @@ mod tests {
             0xf7, 0x4f, 0x93, 0x1e, 0x83, 0x65, 0xe1, 0x5a, 0x08, 0x9c, 0x68, 0xd6, 0x19, 0x00,
             0x00, 0x00, 0x00, 0x00,
         ];
-        assert_eq!(hex_to_arr32_swapped(&test), expected);
+        assert_eq!(hex_to_arr32_swapped(test), expected);
+    }
+
+    #[test]
+    fn test_hex_into() {
+        let mut buf = String::new();
+        hex_into(&mut buf, &[]);
+        assert_eq!("", buf);
+        hex_into(&mut buf, &[0x00, 0x0f, 0xf0, 0xff]);
+        hex_into_swapped(&mut buf, &[0x01, 0xab]);
+        assert_eq!("000ff0ffab01", buf);
+        assert_eq!("", arr_to_hex(&[]));
+        assert_eq!("", arr_to_hex_swapped(&[]));
+        // Every byte round trips
+        let all: Vec<u8> = (0..=255).collect();
+        assert_eq!(all, hex_to_vec(&arr_to_hex(&all)));
+        assert_eq!(all, hex_to_vec_swapped(&arr_to_hex_swapped(&all)));
+    }
+
+    #[test]
+    fn test_hex_to_arr() {
+        assert_eq!([0x01, 0xab, 0xCD], hex_to_arr::<3>("01abCD").unwrap());
+        assert_eq!(
+            [0xcd, 0xab, 0x01],
+            hex_to_arr_swapped::<3>("01abcd").unwrap()
+        );
+        assert_eq!([0u8; 0], hex_to_arr::<0>("").unwrap());
+        assert_eq!(
+            hex_to_arr32_swapped(
+                "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f"
+            ),
+            hex_to_arr_swapped::<32>(
+                "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f"
+            )
+            .unwrap()
+        );
+        // Odd and wrong lengths, empty input and invalid digits
+        for hex in [
+            "0", "01a", "", "0102", "0g0000", "+1ab", " 1ab", "01ab\n", "éa",
+        ] {
+            let err = hex_to_arr::<3>(hex).unwrap_err();
+            assert!(matches!(err.kind, OpErrorKind::InvalidArgsError), "{}", hex);
+        }
+        assert!(hex_to_arr::<3>("01a")
+            .unwrap_err()
+            .to_string()
+            .contains("expected 6 characters, got 3"));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_hex_to_vec_odd_length() {
+        hex_to_vec("abc");
+    }
+
+    /// Compares `arr_to_hex_swapped` with a reused buffer and the previous `format!` based
+    /// encoding, run with `cargo test --release -- --ignored bench`
+    #[test]
+    #[ignore]
+    fn bench_hex() {
+        use std::time::Instant;
+
+        let hash = sha256d(b"bench");
+        let n = 1_000_000;
+        let started = Instant::now();
+        let mut total = 0;
+        for _ in 0..n {
+            total += hash
+                .iter()
+                .rev()
+                .map(|b| format!("{:02x}", b))
+                .collect::<String>()
+                .len();
+        }
+        println!("format!:     {:.3}s", started.elapsed().as_secs_f32());
+        let started = Instant::now();
+        for _ in 0..n {
+            total += arr_to_hex_swapped(&hash).len();
+        }
+        println!("table:       {:.3}s", started.elapsed().as_secs_f32());
+        let started = Instant::now();
+        let mut buf = String::new();
+        for _ in 0..n {
+            buf.clear();
+            hex_into_swapped(&mut buf, &hash);
+            total += buf.len();
+        }
+        println!("reused buf:  {:.3}s", started.elapsed().as_secs_f32());
+        let hex = arr_to_hex_swapped(&hash);
+        let started = Instant::now();
+        for _ in 0..n {
+            total += hex_to_arr_swapped::<32>(&hex).unwrap()[0] as usize;
+        }
+        println!("decode:      {:.3}s", started.elapsed().as_secs_f32());
+        assert!(total > 0);
     }
 
     #[test]
@@ -152,6 +430,49 @@ mod tests {
         ];
         let hash = sha256(&sha256(test.as_bytes()));
         assert_eq!(expected, hash);
+        assert_eq!(expected, sha256d(test.as_bytes()));
+    }
+
+    #[test]
+    fn test_merkle_root_mutated() {
+        // Transactions of mainnet block 100000
+        let txids: Vec<[u8; 32]> = [
+            "8c14f0db3df150123e6f3dbbf30f8b955a8249b62ac1d1ff16284aefa3d06d87",
+            "fff2525b8931402dd09222c50775608f75787bd2b87e56995a7bdd30f79702c4",
+            "6359f0868171b1d194cbee1af2f16ea598ae8fad666d9b012c8ed2b79a236ec4",
+            "e9a66845e05d5abc0ad04ec80f774a7e585c6e8db975962d069a522137b80c1d",
+        ]
+        .iter()
+        .map(|txid| hex_to_arr32_swapped(txid))
+        .collect();
+        let expected = "f3e94742aca4b5ef85488dc37c06c3282295ffec960994b2c0d5ac2a25a95766";
+        assert_eq!(
+            (expected.to_string(), false),
+            (
+                arr_to_hex_swapped(&merkle_root(&txids)),
+                merkle_root_mutated(&txids).1
+            )
+        );
+
+        // A single transaction is the root, no transactions give zero
+        assert_eq!(txids[0], merkle_root(&txids[..1]));
+        assert_eq!(([0u8; 32], false), merkle_root_mutated(&[]));
+
+        // Odd levels pair the last hash with itself
+        let pair = |a: &[u8; 32], b: &[u8; 32]| sha256d(&[&a[..], &b[..]].concat());
+        let odd = pair(&pair(&txids[0], &txids[1]), &pair(&txids[2], &txids[2]));
+        assert_eq!((odd, false), merkle_root_mutated(&txids[..3]));
+
+        // CVE-2012-2459: repeating the last transaction keeps the root but is detected
+        let repeated = [txids[0], txids[1], txids[2], txids[2]];
+        assert_eq!((odd, true), merkle_root_mutated(&repeated));
+        let mut five = txids.clone();
+        five.push(txids[3]);
+        let mut six = five.clone();
+        six.push(txids[3]);
+        assert_eq!(merkle_root(&five), merkle_root(&six));
+        assert!(!merkle_root_mutated(&five).1);
+        assert!(merkle_root_mutated(&six).1);
     }
 
     #[test]
@@ -167,42 +488,38 @@ mod tests {
 
     #[test]
     fn test_merkle_root() {
-        let mut hashes: Vec<[u8; 32]> = Vec::new();
-        hashes.push([
-            0x8c, 0xb1, 0xdf, 0x74, 0xdb, 0xe9, 0x80, 0xc6, 0xb9, 0x20, 0x2e, 0x91, 0x95, 0x97,
-            0xa5, 0xea, 0xbe, 0xb2, 0xd3, 0x2e, 0x4d, 0xe0, 0x21, 0x4a, 0x39, 0xf8, 0x0c, 0x5f,
-            0xab, 0x9e, 0x45, 0x3a,
-        ]);
-
-        hashes.push([
-            0xb7, 0xa6, 0x06, 0x8e, 0x58, 0x14, 0x73, 0x84, 0x22, 0x76, 0x8b, 0x92, 0xb7, 0xff,
-            0x81, 0xb8, 0x07, 0xfd, 0x51, 0x58, 0x71, 0xed, 0x6a, 0x41, 0x72, 0xba, 0xcc, 0x0e,
-            0x6f, 0xf4, 0x38, 0xbe,
-        ]);
-
-        hashes.push([
-            0xbe, 0x32, 0x73, 0x29, 0xc9, 0x6d, 0x01, 0xbb, 0x0e, 0xf9, 0x39, 0x77, 0xd0, 0x26,
-            0xb8, 0x02, 0xdb, 0x0b, 0x59, 0xbb, 0x7b, 0xfe, 0xd9, 0x77, 0x3a, 0xf6, 0x6f, 0x2b,
-            0xa1, 0xf2, 0x73, 0xd1,
-        ]);
-
-        hashes.push([
-            0x2f, 0x05, 0xc7, 0x5f, 0x38, 0x82, 0x9e, 0xee, 0xaf, 0x84, 0x34, 0x55, 0xdf, 0x87,
-            0xaa, 0xc0, 0xa7, 0xf2, 0xbb, 0x3c, 0xf2, 0x4f, 0x23, 0x91, 0xb4, 0xbb, 0x68, 0x52,
-            0x3e, 0xe8, 0xd1, 0x59,
-        ]);
-
-        hashes.push([
-            0x0c, 0xc6, 0x7a, 0x79, 0xdd, 0x56, 0x4d, 0x24, 0x55, 0xdf, 0x58, 0xb3, 0x71, 0xaf,
-            0xde, 0xb1, 0xa3, 0x1f, 0x44, 0xff, 0xa0, 0x08, 0x3b, 0x9e, 0xb7, 0xef, 0x06, 0x9d,
-            0xa6, 0x77, 0xce, 0xf1,
-        ]);
-
-        hashes.push([
-            0xe0, 0x52, 0xdf, 0x8e, 0x7d, 0x50, 0xda, 0x4b, 0xe4, 0x74, 0xcd, 0x50, 0x5b, 0x21,
-            0x99, 0x6b, 0x74, 0xe3, 0xd0, 0x2f, 0xbf, 0xa1, 0xaf, 0xd3, 0x9f, 0x65, 0xfe, 0x91,
-            0xba, 0x3c, 0x05, 0x84,
-        ]);
+        let hashes: Vec<[u8; 32]> = vec![
+            [
+                0x8c, 0xb1, 0xdf, 0x74, 0xdb, 0xe9, 0x80, 0xc6, 0xb9, 0x20, 0x2e, 0x91, 0x95, 0x97,
+                0xa5, 0xea, 0xbe, 0xb2, 0xd3, 0x2e, 0x4d, 0xe0, 0x21, 0x4a, 0x39, 0xf8, 0x0c, 0x5f,
+                0xab, 0x9e, 0x45, 0x3a,
+            ],
+            [
+                0xb7, 0xa6, 0x06, 0x8e, 0x58, 0x14, 0x73, 0x84, 0x22, 0x76, 0x8b, 0x92, 0xb7, 0xff,
+                0x81, 0xb8, 0x07, 0xfd, 0x51, 0x58, 0x71, 0xed, 0x6a, 0x41, 0x72, 0xba, 0xcc, 0x0e,
+                0x6f, 0xf4, 0x38, 0xbe,
+            ],
+            [
+                0xbe, 0x32, 0x73, 0x29, 0xc9, 0x6d, 0x01, 0xbb, 0x0e, 0xf9, 0x39, 0x77, 0xd0, 0x26,
+                0xb8, 0x02, 0xdb, 0x0b, 0x59, 0xbb, 0x7b, 0xfe, 0xd9, 0x77, 0x3a, 0xf6, 0x6f, 0x2b,
+                0xa1, 0xf2, 0x73, 0xd1,
+            ],
+            [
+                0x2f, 0x05, 0xc7, 0x5f, 0x38, 0x82, 0x9e, 0xee, 0xaf, 0x84, 0x34, 0x55, 0xdf, 0x87,
+                0xaa, 0xc0, 0xa7, 0xf2, 0xbb, 0x3c, 0xf2, 0x4f, 0x23, 0x91, 0xb4, 0xbb, 0x68, 0x52,
+                0x3e, 0xe8, 0xd1, 0x59,
+            ],
+            [
+                0x0c, 0xc6, 0x7a, 0x79, 0xdd, 0x56, 0x4d, 0x24, 0x55, 0xdf, 0x58, 0xb3, 0x71, 0xaf,
+                0xde, 0xb1, 0xa3, 0x1f, 0x44, 0xff, 0xa0, 0x08, 0x3b, 0x9e, 0xb7, 0xef, 0x06, 0x9d,
+                0xa6, 0x77, 0xce, 0xf1,
+            ],
+            [
+                0xe0, 0x52, 0xdf, 0x8e, 0x7d, 0x50, 0xda, 0x4b, 0xe4, 0x74, 0xcd, 0x50, 0x5b, 0x21,
+                0x99, 0x6b, 0x74, 0xe3, 0xd0, 0x2f, 0xbf, 0xa1, 0xaf, 0xd3, 0x9f, 0x65, 0xfe, 0x91,
+                0xba, 0x3c, 0x05, 0x84,
+            ],
+        ];
 
         let expected = [
             0x52, 0xed, 0x57, 0x8c, 0xb6, 0xed, 0x9a, 0xe5, 0xf5, 0x31, 0x6d, 0x45, 0x42, 0x9b,
@@ -0,0 +1,219 @@
+//! Minimal zstd encoder on top of the system libzstd
+
+use std::io::{self, Write};
+use std::os::raw::{c_char, c_int, c_uint, c_void};
+
+#[allow(non_camel_case_types)]
+mod ffi {
+    use super::*;
+
+    pub const ZSTD_C_COMPRESSION_LEVEL: c_int = 100;
+    pub const ZSTD_E_CONTINUE: c_int = 0;
+    pub const ZSTD_E_END: c_int = 2;
+
+    #[repr(C)]
+    pub struct ZSTD_inBuffer {
+        pub src: *const c_void,
+        pub size: usize,
+        pub pos: usize,
+    }
+
+    #[repr(C)]
+    pub struct ZSTD_outBuffer {
+        pub dst: *mut c_void,
+        pub size: usize,
+        pub pos: usize,
+    }
+
+    #[link(name = "zstd")]
+    extern "C" {
+        pub fn ZSTD_createCCtx() -> *mut c_void;
+        pub fn ZSTD_freeCCtx(cctx: *mut c_void) -> usize;
+        pub fn ZSTD_CCtx_setParameter(cctx: *mut c_void, param: c_int, value: c_int) -> usize;
+        pub fn ZSTD_compressStream2(
+            cctx: *mut c_void,
+            output: *mut ZSTD_outBuffer,
+            input: *mut ZSTD_inBuffer,
+            end_op: c_int,
+        ) -> usize;
+        pub fn ZSTD_isError(code: usize) -> c_uint;
+        pub fn ZSTD_getErrorName(code: usize) -> *const c_char;
+        #[cfg(test)]
+        pub fn ZSTD_createDCtx() -> *mut c_void;
+        #[cfg(test)]
+        pub fn ZSTD_freeDCtx(dctx: *mut c_void) -> usize;
+        #[cfg(test)]
+        pub fn ZSTD_decompressStream(
+            dctx: *mut c_void,
+            output: *mut ZSTD_outBuffer,
+            input: *mut ZSTD_inBuffer,
+        ) -> usize;
+    }
+}
+
+// Size of the buffer for compressed data, which is written to the inner writer when full
+const OUT_BUFFER_SIZE: usize = 256 * 1024;
+
+/// Compresses everything written to it into a zstd frame.
+/// `finish()` must be called to end the frame, otherwise the output is truncated.
+pub struct ZstdWriter<W: Write> {
+    inner: Option<W>,
+    cctx: *mut c_void,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> ZstdWriter<W> {
+    /// Creates an encoder with compression level 1 (fast) to 22 (best)
+    pub fn new(inner: W, level: u32) -> io::Result<ZstdWriter<W>> {
+        let cctx = unsafe { ffi::ZSTD_createCCtx() };
+        if cctx.is_null() {
+            return Err(io::Error::other("Unable to create zstd context"));
+        }
+        // Owns the context from here on, so it gets freed on errors
+        let writer = ZstdWriter {
+            inner: Some(inner),
+            cctx,
+            buffer: vec![0u8; OUT_BUFFER_SIZE],
+        };
+        let code = unsafe {
+            ffi::ZSTD_CCtx_setParameter(
+                cctx,
+                ffi::ZSTD_C_COMPRESSION_LEVEL,
+                level.clamp(1, 22) as c_int,
+            )
+        };
+        check(code)?;
+        Ok(writer)
+    }
+
+    /// Writes the remaining compressed data and ends the frame, returns the inner writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.compress(&[], ffi::ZSTD_E_END)?;
+        let mut inner = self.inner.take().unwrap();
+        inner.flush()?;
+        Ok(inner)
+    }
+
+    /// Feeds `data` to zstd and writes all output it produces
+    fn compress(&mut self, data: &[u8], end_op: c_int) -> io::Result<()> {
+        let mut input = ffi::ZSTD_inBuffer {
+            src: data.as_ptr() as *const c_void,
+            size: data.len(),
+            pos: 0,
+        };
+        loop {
+            let mut output = ffi::ZSTD_outBuffer {
+                dst: self.buffer.as_mut_ptr() as *mut c_void,
+                size: self.buffer.len(),
+                pos: 0,
+            };
+            // Returns the number of bytes still buffered by zstd for ZSTD_e_end
+            let remaining = check(unsafe {
+                ffi::ZSTD_compressStream2(self.cctx, &mut output, &mut input, end_op)
+            })?;
+            if let Some(inner) = self.inner.as_mut() {
+                inner.write_all(&self.buffer[..output.pos])?;
+            }
+            let done = match end_op {
+                ffi::ZSTD_E_END => remaining == 0,
+                _ => input.pos == input.size,
+            };
+            if done {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl<W: Write> Write for ZstdWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.compress(buf, ffi::ZSTD_E_CONTINUE)?;
+        Ok(buf.len())
+    }
+
+    /// Only flushes the inner writer, data buffered by zstd stays there until `finish()`
+    fn flush(&mut self) -> io::Result<()> {
+        match self.inner.as_mut() {
+            Some(inner) => inner.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<W: Write> Drop for ZstdWriter<W> {
+    fn drop(&mut self) {
+        unsafe { ffi::ZSTD_freeCCtx(self.cctx) };
+    }
+}
+
+/// Converts zstd error codes into io errors
+fn check(code: usize) -> io::Result<usize> {
+    if unsafe { ffi::ZSTD_isError(code) } == 0 {
+        return Ok(code);
+    }
+    let msg = unsafe { std::ffi::CStr::from_ptr(ffi::ZSTD_getErrorName(code)) }
+        .to_string_lossy()
+        .into_owned();
+    Err(io::Error::other(format!("zstd error: {}", msg)))
+}
+
+/// Decompresses zstd data, panics on invalid or truncated input
+#[cfg(test)]
+pub fn unzstd(data: &[u8]) -> Vec<u8> {
+    let dctx = unsafe { ffi::ZSTD_createDCtx() };
+    assert!(!dctx.is_null());
+    let mut input = ffi::ZSTD_inBuffer {
+        src: data.as_ptr() as *const c_void,
+        size: data.len(),
+        pos: 0,
+    };
+    let mut output = Vec::new();
+    let mut buffer = vec![0u8; 4096];
+    loop {
+        let mut out = ffi::ZSTD_outBuffer {
+            dst: buffer.as_mut_ptr() as *mut c_void,
+            size: buffer.len(),
+            pos: 0,
+        };
+        let code = check(unsafe { ffi::ZSTD_decompressStream(dctx, &mut out, &mut input) })
+            .expect("decompression failed");
+        output.extend_from_slice(&buffer[..out.pos]);
+        // 0 means a frame was completely decoded and flushed
+        if code == 0 && input.pos == input.size {
+            break;
+        }
+        assert!(
+            input.pos < input.size || out.pos == out.size,
+            "truncated zstd frame"
+        );
+    }
+    unsafe { ffi::ZSTD_freeDCtx(dctx) };
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let data: Vec<u8> = (0..1_000_000u32)
+            .flat_map(|i| (i % 251).to_le_bytes())
+            .collect();
+        let mut writer = ZstdWriter::new(Vec::new(), 3).unwrap();
+        for chunk in data.chunks(10_000) {
+            writer.write_all(chunk).unwrap();
+        }
+        let compressed = writer.finish().unwrap();
+        // zstd frame magic
+        assert_eq!(&[0x28, 0xb5, 0x2f, 0xfd], &compressed[..4]);
+        assert!(compressed.len() < data.len() / 10);
+        assert_eq!(data, unzstd(&compressed));
+    }
+
+    #[test]
+    fn test_empty() {
+        let compressed = ZstdWriter::new(Vec::new(), 19).unwrap().finish().unwrap();
+        assert!(unzstd(&compressed).is_empty());
+    }
+}
@@ -2,6 +2,7 @@ use std::convert::{self, From};
 use std::error;
 use std::fmt;
 use std::io;
+use std::path::{Path, PathBuf};
 use std::string;
 use std::sync;
 
@@ -9,6 +10,14 @@ use mongodb;
 use rusty_leveldb::Status;
 
 use crate::blockchain::proto::script;
+use crate::common::bolt::BoltError;
+use crate::common::http::HttpError;
+use crate::common::kafka::KafkaError;
+use crate::common::pgwire::PgError;
+use crate::common::resp::RespError;
+use crate::common::signal;
+#[cfg(feature = "sqlite")]
+use crate::common::sqlite::SqliteError;
 
 /// Returns a string with filename, current code line and column
 macro_rules! line_mark {
@@ -50,12 +59,153 @@ impl OpError {
             message: self.message,
         }
     }
+
+    /// Wraps an error returned by the callback `name`
+    pub fn callback(name: &str, source: OpError) -> Self {
+        Self::new(OpErrorKind::Callback {
+            name: String::from(name),
+            source: Box::new(source),
+        })
+    }
+
+    /// Wraps an error while deserializing a block, `offset` is relative to the start of the
+    /// block until `at_block` adds the location of the block
+    pub fn deserialization(source: OpError, offset: u64) -> Self {
+        Self::new(OpErrorKind::Deserialization {
+            file: None,
+            offset,
+            height: None,
+            source: Box::new(source),
+        })
+    }
+
+    /// Adds the blk file, the offset of the block in it and its height from the index.
+    /// Other errors are wrapped into a deserialization error at the start of the block.
+    pub fn at_block(self, path: &Path, block_offset: u64, block_height: u64) -> Self {
+        match self.kind {
+            OpErrorKind::Deserialization {
+                file: None,
+                offset,
+                height: _,
+                source,
+            } => OpError {
+                kind: OpErrorKind::Deserialization {
+                    file: Some(PathBuf::from(path)),
+                    offset: block_offset + offset,
+                    height: Some(block_height),
+                    source,
+                },
+                message: self.message,
+            },
+            kind => Self::new(OpErrorKind::Deserialization {
+                file: Some(PathBuf::from(path)),
+                offset: block_offset,
+                height: Some(block_height),
+                source: Box::new(OpError {
+                    kind,
+                    message: self.message,
+                }),
+            }),
+        }
+    }
+
+    /// The wrapped error, if any
+    pub fn cause(&self) -> Option<&OpError> {
+        match &self.kind {
+            OpErrorKind::Deserialization { source, .. } | OpErrorKind::Callback { source, .. } => {
+                Some(source)
+            }
+            _ => None,
+        }
+    }
+
+    /// Category of the innermost error which has one, so a database failure inside a callback
+    /// is still reported as such
+    pub fn category(&self) -> ErrorCategory {
+        let own = match &self.kind {
+            OpErrorKind::IoError(_) => ErrorCategory::Io,
+            OpErrorKind::ByteOrderError(_)
+            | OpErrorKind::Utf8Error(_)
+            | OpErrorKind::ScriptError(_)
+            | OpErrorKind::Deserialization { .. } => ErrorCategory::Deserialization,
+            OpErrorKind::MongodbError(_)
+            | OpErrorKind::PostgresError(_)
+            | OpErrorKind::KafkaError(_)
+            | OpErrorKind::HttpError(_)
+            | OpErrorKind::Neo4jError(_)
+            | OpErrorKind::RedisError(_)
+            | OpErrorKind::LevelDBError(_) => ErrorCategory::Database,
+            #[cfg(feature = "sqlite")]
+            OpErrorKind::SqliteError(_) => ErrorCategory::Database,
+            OpErrorKind::InvalidArgsError => ErrorCategory::InvalidArgument,
+            OpErrorKind::ValidateError => ErrorCategory::Validation,
+            OpErrorKind::Callback { .. } => ErrorCategory::Callback,
+            OpErrorKind::Interrupted => ErrorCategory::Interrupted,
+            OpErrorKind::None
+            | OpErrorKind::JsonError(_)
+            | OpErrorKind::RuntimeError
+            | OpErrorKind::PoisonError
+            | OpErrorKind::SendError => ErrorCategory::Other,
+        };
+        match self.cause().map(OpError::category) {
+            Some(ErrorCategory::Other) | None => own,
+            // The location is more telling than the I/O error of a truncated block
+            Some(ErrorCategory::Io) if own == ErrorCategory::Deserialization => own,
+            Some(inner) => inner,
+        }
+    }
+
+    /// Lists the error and each wrapped error on its own line
+    pub fn report(&self) -> String {
+        let mut report = self.layer();
+        let mut cause = self.cause();
+        while let Some(err) = cause {
+            report.push_str("\n  caused by: ");
+            report.push_str(&err.layer());
+            cause = err.cause();
+        }
+        report
+    }
+
+    /// Message and kind without the wrapped error
+    fn layer(&self) -> String {
+        let kind = match &self.kind {
+            OpErrorKind::Deserialization {
+                file,
+                offset,
+                height,
+                ..
+            } => location(file.as_deref(), *offset, *height),
+            OpErrorKind::Callback { name, .. } => format!("Callback `{}` failed", name),
+            kind => kind.to_string(),
+        };
+        match (self.message.is_empty(), kind.is_empty()) {
+            (true, _) => kind,
+            (false, true) => self.message.clone(),
+            (false, false) => format!("{} {}", self.message, kind),
+        }
+    }
+}
+
+/// `blk00042.dat offset 0x5a3f2, block height ~812345`
+fn location(file: Option<&Path>, offset: u64, height: Option<u64>) -> String {
+    let mut location = match file {
+        Some(path) => format!("{} offset {:#x}", path.display(), offset),
+        None => format!("Block offset {:#x}", offset),
+    };
+    if let Some(height) = height {
+        // The height is taken from the index, the block itself doesn't know it
+        location.push_str(&format!(", block height ~{}", height));
+    }
+    location
 }
 
 impl fmt::Display for OpError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         if self.message.is_empty() {
             write!(f, "{}", &self.kind)
+        } else if matches!(self.kind, OpErrorKind::None) {
+            write!(f, "{}", &self.message)
         } else {
             write!(f, "{} {}", &self.message, &self.kind)
         }
@@ -63,14 +213,39 @@ impl fmt::Display for OpError {
 }
 
 impl error::Error for OpError {
-    fn description(&self) -> &str {
-        self.message.as_ref()
-    }
-    fn cause(&self) -> Option<&dyn error::Error> {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
         self.kind.source()
     }
 }
 
+/// Groups errors by their origin, each one exits with its own code
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCategory {
+    InvalidArgument,
+    Io,
+    Deserialization,
+    Validation,
+    Callback,
+    Database,
+    Interrupted,
+    Other,
+}
+
+impl ErrorCategory {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ErrorCategory::Other => 1,
+            ErrorCategory::InvalidArgument => 2,
+            ErrorCategory::Io => 3,
+            ErrorCategory::Deserialization => 4,
+            ErrorCategory::Validation => 5,
+            ErrorCategory::Callback => 6,
+            ErrorCategory::Database => 7,
+            ErrorCategory::Interrupted => signal::EXIT_INTERRUPTED,
+        }
+    }
+}
+
 // impl mongodb::error::Error for OpError {
 //     fn description(&self) -> &str {
 //         self.message.as_ref()
@@ -85,13 +260,35 @@ pub enum OpErrorKind {
     None,
     IoError(io::Error),
     MongodbError(mongodb::error::Error),
+    PostgresError(PgError),
+    KafkaError(KafkaError),
+    HttpError(HttpError),
+    Neo4jError(BoltError),
+    RedisError(RespError),
+    #[cfg(feature = "sqlite")]
+    SqliteError(SqliteError),
     ByteOrderError(io::Error),
     Utf8Error(string::FromUtf8Error),
+    JsonError(serde_json::Error),
     ScriptError(script::ScriptError),
     InvalidArgsError,
-    CallbackError,
+    // Error returned by a callback, `name` is its subcommand
+    Callback {
+        name: String,
+        source: Box<OpError>,
+    },
+    // Block which couldn't be deserialized, the file and height are unknown while only the
+    // serialized block is at hand
+    Deserialization {
+        file: Option<PathBuf>,
+        offset: u64,
+        height: Option<u64>,
+        source: Box<OpError>,
+    },
     ValidateError,
     RuntimeError,
+    // SIGINT or SIGTERM stopped the parser, the callback finished cleanly
+    Interrupted,
     PoisonError,
     SendError,
     LevelDBError(String),
@@ -100,18 +297,41 @@ pub enum OpErrorKind {
 impl fmt::Display for OpErrorKind {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
+            OpErrorKind::Callback {
+                ref name,
+                ref source,
+            } => write!(f, "Callback `{}` failed: {}", name, source),
+            OpErrorKind::Deserialization {
+                ref file,
+                offset,
+                height,
+                ref source,
+            } => write!(
+                f,
+                "{}: {}",
+                location(file.as_deref(), offset, height),
+                source
+            ),
             OpErrorKind::IoError(ref err) => write!(f, "I/O Error: {}", err),
             OpErrorKind::ByteOrderError(ref err) => write!(f, "ByteOrder: {}", err),
             OpErrorKind::MongodbError(ref err) => write!(f, "MongodbError: {}", err),
+            OpErrorKind::PostgresError(ref err) => write!(f, "PostgresError: {}", err),
+            OpErrorKind::KafkaError(ref err) => write!(f, "KafkaError: {}", err),
+            OpErrorKind::HttpError(ref err) => write!(f, "HttpError: {}", err),
+            OpErrorKind::Neo4jError(ref err) => write!(f, "Neo4jError: {}", err),
+            OpErrorKind::RedisError(ref err) => write!(f, "RedisError: {}", err),
+            #[cfg(feature = "sqlite")]
+            OpErrorKind::SqliteError(ref err) => write!(f, "SqliteError: {}", err),
             OpErrorKind::Utf8Error(ref err) => write!(f, "Utf8 Conversion: {}", err),
+            OpErrorKind::JsonError(ref err) => write!(f, "JSON: {}", err),
             OpErrorKind::ScriptError(ref err) => write!(f, "Script: {}", err),
             OpErrorKind::LevelDBError(ref err) => write!(f, "LevelDB: {}", err),
-            ref err @ OpErrorKind::PoisonError => write!(f, "Threading Error: {}", err),
-            ref err @ OpErrorKind::SendError => write!(f, "Sync: {}", err),
-            ref err @ OpErrorKind::InvalidArgsError => write!(f, "InvalidArgs: {}", err),
-            ref err @ OpErrorKind::CallbackError => write!(f, "Callback: {}", err),
-            ref err @ OpErrorKind::ValidateError => write!(f, "Validation: {}", err),
-            ref err @ OpErrorKind::RuntimeError => write!(f, "RuntimeError: {}", err),
+            OpErrorKind::PoisonError => write!(f, "Threading Error"),
+            OpErrorKind::SendError => write!(f, "Sync"),
+            OpErrorKind::InvalidArgsError => write!(f, "InvalidArgs"),
+            OpErrorKind::ValidateError => write!(f, "Validation"),
+            OpErrorKind::RuntimeError => write!(f, "RuntimeError"),
+            OpErrorKind::Interrupted => write!(f, "Interrupted"),
             OpErrorKind::None => write!(f, ""),
         }
     }
@@ -120,12 +340,21 @@ impl fmt::Display for OpErrorKind {
 impl error::Error for OpErrorKind {
     fn source(&self) -> Option<&(dyn error::Error + 'static)> {
         match *self {
+            OpErrorKind::Callback { ref source, .. } => Some(source.as_ref()),
+            OpErrorKind::Deserialization { ref source, .. } => Some(source.as_ref()),
             OpErrorKind::IoError(ref err) => Some(err),
+            OpErrorKind::MongodbError(ref err) => Some(err),
             OpErrorKind::ByteOrderError(ref err) => Some(err),
             OpErrorKind::Utf8Error(ref err) => Some(err),
+            OpErrorKind::JsonError(ref err) => Some(err),
             OpErrorKind::ScriptError(ref err) => Some(err),
-            ref err @ OpErrorKind::PoisonError => Some(err),
-            ref err @ OpErrorKind::SendError => Some(err),
+            OpErrorKind::PostgresError(ref err) => Some(err),
+            OpErrorKind::KafkaError(ref err) => Some(err),
+            OpErrorKind::HttpError(ref err) => Some(err),
+            OpErrorKind::Neo4jError(ref err) => Some(err),
+            OpErrorKind::RedisError(ref err) => Some(err),
+            #[cfg(feature = "sqlite")]
+            OpErrorKind::SqliteError(ref err) => Some(err),
             _ => None,
         }
     }
@@ -142,6 +371,43 @@ impl From<mongodb::error::Error> for OpError {
     }
 }
 
+impl From<PgError> for OpError {
+    fn from(err: PgError) -> Self {
+        Self::new(OpErrorKind::PostgresError(err))
+    }
+}
+
+impl From<KafkaError> for OpError {
+    fn from(err: KafkaError) -> Self {
+        Self::new(OpErrorKind::KafkaError(err))
+    }
+}
+
+impl From<HttpError> for OpError {
+    fn from(err: HttpError) -> Self {
+        Self::new(OpErrorKind::HttpError(err))
+    }
+}
+
+impl From<BoltError> for OpError {
+    fn from(err: BoltError) -> Self {
+        Self::new(OpErrorKind::Neo4jError(err))
+    }
+}
+
+impl From<RespError> for OpError {
+    fn from(err: RespError) -> Self {
+        Self::new(OpErrorKind::RedisError(err))
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl From<SqliteError> for OpError {
+    fn from(err: SqliteError) -> Self {
+        Self::new(OpErrorKind::SqliteError(err))
+    }
+}
+
 impl convert::From<i32> for OpError {
     fn from(err_code: i32) -> Self {
         Self::from(io::Error::from_raw_os_error(err_code))
@@ -172,6 +438,12 @@ impl convert::From<string::FromUtf8Error> for OpError {
     }
 }
 
+impl convert::From<serde_json::Error> for OpError {
+    fn from(err: serde_json::Error) -> Self {
+        Self::new(OpErrorKind::JsonError(err))
+    }
+}
+
 impl convert::From<rusty_leveldb::Status> for OpError {
     fn from(status: Status) -> Self {
         Self::new(OpErrorKind::LevelDBError(status.err))
@@ -191,5 +463,105 @@ mod tests {
 
         let err = err.join_msg("Cannot proceed.");
         assert_eq!(format!("{}", err), "Cannot proceed. I/O Error: oh no!");
+
+        let err = OpError::new(OpErrorKind::InvalidArgsError).join_msg("Bad value.");
+        assert_eq!(format!("{}", err), "Bad value. InvalidArgs");
+    }
+
+    #[test]
+    fn test_deserialization_context() {
+        let eof = OpError::from(io::Error::from(io::ErrorKind::UnexpectedEof));
+        let err = OpError::deserialization(eof, 0x20);
+        assert_eq!(
+            "Block offset 0x20: I/O Error: unexpected end of file",
+            err.to_string()
+        );
+
+        let err = err.at_block(Path::new("blk01234.dat"), 0x5a3d2, 812345);
+        assert_eq!(
+            "blk01234.dat offset 0x5a3f2, block height ~812345: I/O Error: unexpected end of file",
+            err.to_string()
+        );
+        assert_eq!(ErrorCategory::Deserialization, err.category());
+        match &err.kind {
+            OpErrorKind::Deserialization {
+                file,
+                offset,
+                height,
+                ..
+            } => {
+                assert_eq!(Some(Path::new("blk01234.dat")), file.as_deref());
+                assert_eq!((0x5a3f2, Some(812345)), (*offset, *height));
+            }
+            kind => panic!("{:?}", kind),
+        }
+
+        // Other errors start at the block
+        let err =
+            OpError::from(String::from("Invalid magic")).at_block(Path::new("blk00000.dat"), 8, 0);
+        assert_eq!(
+            "blk00000.dat offset 0x8, block height ~0: Invalid magic",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn test_error_chain() {
+        let io = io::Error::new(io::ErrorKind::ConnectionRefused, "refused");
+        let err = OpError::callback(
+            "multi",
+            OpError::callback("mongo", OpError::from(io).join_msg("Unable to connect.")),
+        );
+        assert_eq!(
+            "Callback `multi` failed\n  \
+             caused by: Callback `mongo` failed\n  \
+             caused by: Unable to connect. I/O Error: refused",
+            err.report()
+        );
+        assert_eq!(
+            "Callback `multi` failed: Callback `mongo` failed: Unable to connect. I/O Error: refused",
+            err.to_string()
+        );
+        // The innermost category wins
+        assert_eq!(ErrorCategory::Io, err.category());
+
+        // Chained through std::error::Error down to the io::Error
+        let mut sources = Vec::new();
+        let mut source = error::Error::source(&err);
+        while let Some(err) = source {
+            sources.push(err.to_string());
+            source = err.source();
+        }
+        assert_eq!(
+            vec![
+                "Callback `mongo` failed: Unable to connect. I/O Error: refused",
+                "Unable to connect. I/O Error: refused",
+                "refused"
+            ],
+            sources
+        );
+
+        let err = OpError::callback("csvdump", OpError::from(String::from("oops")));
+        assert_eq!(ErrorCategory::Callback, err.category());
+        assert!(error::Error::source(&OpError::new(OpErrorKind::PoisonError)).is_none());
+    }
+
+    #[test]
+    fn test_exit_codes() {
+        let categories = [
+            ErrorCategory::InvalidArgument,
+            ErrorCategory::Io,
+            ErrorCategory::Deserialization,
+            ErrorCategory::Validation,
+            ErrorCategory::Callback,
+            ErrorCategory::Database,
+            ErrorCategory::Interrupted,
+            ErrorCategory::Other,
+        ];
+        let mut codes: Vec<i32> = categories.iter().map(|c| c.exit_code()).collect();
+        codes.sort_unstable();
+        codes.dedup();
+        assert_eq!(categories.len(), codes.len());
+        assert!(!codes.contains(&0));
     }
 }
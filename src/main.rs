@@ -2,21 +2,23 @@ use clap::{App, Arg};
 use std::boxed::Box;
 use std::cell::RefCell;
 use std::fmt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process;
+use std::thread;
+use std::time::Duration;
 
+use crate::blockchain::parser::blkfile::BlkFile;
 use crate::blockchain::parser::chain::ChainStorage;
-use crate::blockchain::parser::types::{Bitcoin, CoinType};
+use crate::blockchain::parser::coinfile;
+use crate::blockchain::parser::stream;
+use crate::blockchain::parser::types::{Bitcoin, CoinType, COIN_NAMES};
+use crate::blockchain::parser::unknown;
 use crate::blockchain::parser::BlockchainParser;
-use crate::callbacks::balances::Balances;
-use crate::callbacks::csvdump::CsvDump;
-use crate::callbacks::mongo::Mongo;
-use crate::callbacks::stats::SimpleStats;
-use crate::callbacks::unspentcsvdump::UnspentCsvDump;
 use crate::callbacks::Callback;
-use crate::common::logger::SimpleLogger;
+use crate::common::logger::{self, LogConfig, LogLevels, SimpleLogger};
+use crate::common::signal;
 use crate::common::utils;
-use crate::errors::{OpError, OpResult};
+use crate::errors::{ErrorCategory, OpError, OpErrorKind, OpResult};
 
 #[macro_use]
 extern crate log;
@@ -36,6 +38,7 @@ pub mod common;
 #[macro_use]
 pub mod callbacks;
 
+/// Heights of the blocks to parse, both inclusive
 pub struct ParseRange {
     start: usize,
     end: Option<usize>,
@@ -43,9 +46,9 @@ pub struct ParseRange {
 
 impl ParseRange {
     pub fn new(start: usize, end: Option<usize>) -> OpResult<Self> {
-        if end.is_some() && start >= end.unwrap() {
+        if end.is_some() && start > end.unwrap() {
             return Err(OpError::from(String::from(
-                "--start value must be lower than --end value",
+                "--start value must not be greater than --end value",
             )));
         }
         Ok(Self { start, end })
@@ -66,12 +69,45 @@ impl fmt::Display for ParseRange {
 pub struct ParserOptions {
     // Name of the callback which gets executed for each block. (See callbacks/mod.rs)
     callback: Box<dyn Callback>,
+    // Subcommand of the callback, names it in errors
+    callback_name: String,
     // Holds the name of the coin we want to parse
     coin_type: CoinType,
     // Enable this if you want to check the chain index integrity and merkle root for each block.
     verify: bool,
+    // Extracts the redeem and witness scripts of inputs, costs some CPU
+    classify_inputs: bool,
+    // Attaches the outputs spent by the inputs from the rev files of the blockchain directory
+    use_undo_files: bool,
     // Path to directory where blk.dat files are stored
     blockchain_dir: PathBuf,
+    // Files of magic prefixed blocks read in this order instead of the blockchain directory
+    block_files: Vec<PathBuf>,
+    // Overrides the obfuscation key of xor.dat
+    xor_key: Option<[u8; 8]>,
+    // Number of threads deserializing blocks
+    threads: usize,
+    // Reads blk files through memory maps
+    mmap: bool,
+    // Aborts on corrupt or truncated blocks instead of skipping them
+    strict: bool,
+    // Passes blocks of a stream which aren't part of the best chain to the callback as well
+    include_orphans: bool,
+    // Checks the proof of work and that each block links to the previously delivered one
+    check_pow: bool,
+    // Logs failed PoW and continuity checks instead of aborting
+    keep_going: bool,
+    // Interval of the progress reports on stderr, None disables them
+    progress_interval: Option<Duration>,
+    // Address of the HTTP server exposing Prometheus metrics
+    metrics_listen: Option<String>,
+    // Node which serves blocks missing on disk and past the tip of the index
+    p2p_fallback: Option<String>,
+    // Time the node may take to answer
+    p2p_timeout: Duration,
+    // Folder of the corpus of unrecognised output scripts, at most `max_unknown` of them
+    dump_unknown_scripts: Option<PathBuf>,
+    max_unknown: usize,
     // Verbosity level, 0 = Error, 1 = Info, 2 = Debug, 3+ = Trace
     log_level_filter: log::LevelFilter,
     // Range which is considered for parsing
@@ -79,143 +115,375 @@ pub struct ParserOptions {
 }
 
 fn main() {
-    let options = match parse_args() {
+    let (options, log_config) = match parse_args() {
         Ok(o) => o,
         Err(desc) => {
             // Init logger to print outstanding error message
             SimpleLogger::init(log::LevelFilter::Debug).unwrap();
-            error!(target: "main", "{}", desc);
-            process::exit(1);
+            error!(target: "main", "{}", desc.report());
+            // Most of them are rejected arguments of the callbacks
+            let category = match desc.category() {
+                ErrorCategory::Other => ErrorCategory::InvalidArgument,
+                category => category,
+            };
+            process::exit(category.exit_code());
         }
     };
 
-    // Apply log filter based on verbosity
+    // Apply log filter based on verbosity and --log-level
     let log_level = options.borrow().log_level_filter;
-    SimpleLogger::init(log_level).expect("Unable to initialize logger!");
+    if let Err(e) = SimpleLogger::init_with(log_config) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
     info!(target: "main", "Starting rusty-blockparser v{} ...", env!("CARGO_PKG_VERSION"));
     debug!(target: "main", "Using LogLevel {}", log_level);
 
-    let chain_storage = match ChainStorage::new(&options) {
-        Ok(storage) => storage,
+    let passes = (*options.borrow().callback).passes();
+    if passes > 1
+        && options.borrow().block_files.is_empty()
+        && stream::is_stream(&options.borrow().blockchain_dir)
+    {
+        error!(target: "main", "A stream of blocks can only be read once, {} passes need a blocks directory.", passes);
+        process::exit(ErrorCategory::InvalidArgument.exit_code());
+    }
+    let interrupt = match signal::install() {
+        Ok(flag) => flag,
         Err(e) => {
-            error!(
-                "Cannot load blockchain from: '{}'. {}",
-                options.borrow().blockchain_dir.display(),
-                e
-            );
-            process::exit(1);
+            error!(target: "main", "Unable to install signal handlers: {}", e);
+            process::exit(ErrorCategory::Io.exit_code());
         }
     };
-
-    let mut parser = BlockchainParser::new(&options, chain_storage);
-    match parser.start() {
-        Ok(_) => info!(target: "main", "Fin."),
-        Err(why) => {
-            error!("{}", why);
-            process::exit(1);
+    for pass in 1..=passes {
+        if passes > 1 {
+            info!(target: "main", "Starting pass {} of {} ...", pass, passes);
+        }
+        let chain_storage = match ChainStorage::new(&options) {
+            Ok(storage) => storage,
+            Err(e) => {
+                error!(
+                    "Cannot load blockchain from: '{}'. {}",
+                    stream::source_name(&options.borrow()).display(),
+                    e.report()
+                );
+                process::exit(e.category().exit_code());
+            }
+        };
+        let mut parser = BlockchainParser::new(&options, chain_storage);
+        parser.set_interrupt(interrupt);
+        match parser.start() {
+            Ok(_) => (),
+            Err(why) if matches!(why.kind, OpErrorKind::Interrupted) => {
+                warn!(target: "main", "{}", why.message);
+                process::exit(signal::EXIT_INTERRUPTED);
+            }
+            Err(why) => {
+                error!("{}", why.report());
+                process::exit(why.category().exit_code());
+            }
         }
     }
+    info!(target: "main", "Fin.");
 }
 
-/// Parses args or panics if some requirements are not met.
-fn parse_args() -> OpResult<RefCell<ParserOptions>> {
-    let coins = &[
-        "bitcoin",
-        "testnet3",
-        "namecoin",
-        "litecoin",
-        "dogecoin",
-        "myriadcoin",
-        "unobtanium",
-    ];
-    let matches = App::new("Multithreaded Blockchain Parser written in Rust")
+fn parse_args() -> OpResult<(RefCell<ParserOptions>, LogConfig)> {
+    // `testnet3` is the former name of `bitcoin-testnet3`
+    let coins: Vec<&str> = COIN_NAMES.iter().copied().chain(["testnet3"]).collect();
+    let app = App::new("Multithreaded Blockchain Parser written in Rust")
         .version(crate_version!())
         .author("gcarq <egger.m@protonmail.com>")
         // Add flags
         .arg(Arg::with_name("verify")
             .long("verify")
-            .help("Verifies the leveldb index integrity and verifies merkle roots"))
+            .help("Verifies block hashes and the chain against the index, merkle roots and witness commitments"))
         .arg(Arg::with_name("verbosity")
             .short("v")
             .multiple(true)
             .help("Increases verbosity level. Info=0, Debug=1, Trace=2 (default: 0)"))
+        .arg(Arg::with_name("check-pow")
+            .long("check-pow")
+            .help("Checks the proof of work of the headers and that each block links to the previous one"))
+        .arg(Arg::with_name("keep-going")
+            .long("keep-going")
+            .requires("check-pow")
+            .help("Logs blocks failing --check-pow instead of aborting"))
+        .arg(Arg::with_name("classify-inputs")
+            .long("classify-inputs")
+            .help("Extracts and classifies the redeem and witness scripts of P2SH and P2WSH spends"))
+        .arg(Arg::with_name("use-undo-files")
+            .long("use-undo-files")
+            .conflicts_with_all(&["block-file", "p2p-fallback"])
+            .help("Attaches value and script of the spent outputs to the inputs, read from the rev files of the blockchain directory"))
+        .arg(Arg::with_name("legacy-addresses")
+            .long("legacy-addresses")
+            .help("Uses legacy base58 instead of CashAddr addresses for Bitcoin Cash"))
+        .arg(Arg::with_name("no-mmap")
+            .long("no-mmap")
+            .help("Reads blk files with buffered reads instead of memory maps"))
+        .arg(Arg::with_name("p2pk-as-pubkey")
+            .long("p2pk-as-pubkey")
+            .help("Uses the hex encoded public key instead of the derived address for P2PK outputs"))
+        .arg(Arg::with_name("strict")
+            .long("strict")
+            .help("Aborts on corrupt, truncated or invalid blocks instead of skipping them"))
+        .arg(Arg::with_name("include-orphans")
+            .long("include-orphans")
+            .help("Passes the stale blocks of a stream to the callback as well, flagged as stale"))
         // Add options
         .arg(Arg::with_name("coin")
             .short("c")
             .long("coin")
             .value_name("NAME")
             .help("Specify blockchain coin (default: bitcoin)")
-            .possible_values(coins)
+            .possible_values(&coins)
+            .takes_value(true))
+        .arg(Arg::with_name("coin-file")
+            .long("coin-file")
+            .value_name("PATH")
+            .help("Loads the coin from a TOML or JSON definition file, overrides --coin")
+            .takes_value(true))
+        .arg(Arg::with_name("signet-magic")
+            .long("signet-magic")
+            .value_name("HEX")
+            .help("Message start bytes of a custom signet with --coin bitcoin-signet (default: 0a03cf40)")
             .takes_value(true))
         .arg(Arg::with_name("blockchain-dir")
             .short("d")
             .long("blockchain-dir")
-            .help("Sets blockchain directory which contains blk.dat files (default: ~/.bitcoin/blocks)")
+            .help("Sets blockchain directory which contains blk.dat files, `-` or a named pipe reads a stream of blocks instead (default: ~/.bitcoin/blocks)")
+            .takes_value(true))
+        .arg(Arg::with_name("block-file")
+            .long("block-file")
+            .value_name("PATH")
+            .help("Reads the blocks of a bootstrap.dat or concatenated blk file instead of the blockchain directory, repeatable, `*` and `?` match file names")
+            .multiple(true)
+            .number_of_values(1)
+            .conflicts_with("blockchain-dir")
+            .takes_value(true))
+        .arg(Arg::with_name("threads")
+            .long("threads")
+            .value_name("NUMBER")
+            .help("Number of threads deserializing blocks (default: number of logical cores)")
+            .takes_value(true))
+        .arg(Arg::with_name("xor-key")
+            .long("xor-key")
+            .value_name("HEX")
+            .help("Obfuscation key of the blk files (default: read from xor.dat in the blockchain directory)")
+            .takes_value(true))
+        .arg(Arg::with_name("log-format")
+            .long("log-format")
+            .value_name("FORMAT")
+            .help("Writes log records as text or as one JSON object per line to stderr (default: text)")
+            .possible_values(&["text", "json"])
+            .takes_value(true))
+        .arg(Arg::with_name("log-level")
+            .long("log-level")
+            .value_name("SPEC")
+            .help("Sets the log level, optionally per target, e.g. `warn,callback=debug,parser=info`")
+            .takes_value(true))
+        .arg(Arg::with_name("log-file")
+            .long("log-file")
+            .value_name("PATH")
+            .help("Also appends log records to this file")
+            .takes_value(true))
+        .arg(Arg::with_name("log-max-size")
+            .long("log-max-size")
+            .value_name("BYTES")
+            .requires("log-file")
+            .help("Rotates the log file at this size, keeping 5 old files (default: 104857600)")
+            .takes_value(true))
+        .arg(Arg::with_name("metrics-listen")
+            .long("metrics-listen")
+            .value_name("ADDR:PORT")
+            .help("Serves Prometheus metrics on http://ADDR:PORT/metrics while parsing")
+            .takes_value(true))
+        .arg(Arg::with_name("p2p-fallback")
+            .long("p2p-fallback")
+            .value_name("HOST:PORT")
+            .help("Downloads blocks which are missing on disk or past the tip of the blockchain directory from this node")
+            .takes_value(true))
+        .arg(Arg::with_name("p2p-timeout")
+            .long("p2p-timeout")
+            .value_name("SECONDS")
+            .requires("p2p-fallback")
+            .help("Aborts the P2P fallback if the node doesn't answer within this time (default: 30)")
+            .takes_value(true))
+        .arg(Arg::with_name("dump-unknown-scripts")
+            .long("dump-unknown-scripts")
+            .value_name("DIR")
+            .help("Appends output scripts which aren't recognised to DIR/unknown_scripts.csv, once per script")
+            .takes_value(true))
+        .arg(Arg::with_name("max-unknown")
+            .long("max-unknown")
+            .value_name("NUMBER")
+            .requires("dump-unknown-scripts")
+            .help("Stops adding scripts once the file holds this many (default: 10000)")
+            .takes_value(true))
+        .arg(Arg::with_name("progress-interval")
+            .long("progress-interval")
+            .value_name("SECONDS")
+            .help("Reports the progress to stderr every N seconds, 0 disables it (default: 10)")
             .takes_value(true))
         .arg(Arg::with_name("start")
             .short("s")
             .long("start")
+            .visible_alias("start-height")
             .value_name("NUMBER")
             .help("Specify starting block for parsing (inclusive)")
             .takes_value(true))
         .arg(Arg::with_name("end")
             .short("e")
             .long("end")
+            .visible_alias("end-height")
             .value_name("NUMBER")
-            .help("Specify last block for parsing (inclusive) (default: all known blocks)")
+            .help("Specify last block for parsing (inclusive, up to 0.8.1 parsing stopped before it) (default: all known blocks)")
             .takes_value(true))
         // Add callbacks
-        .subcommand(UnspentCsvDump::build_subcommand())
-        .subcommand(CsvDump::build_subcommand())
-        .subcommand(SimpleStats::build_subcommand())
-        .subcommand(Mongo::build_subcommand())
-        .subcommand(Balances::build_subcommand())
-        .get_matches();
+        .subcommands(callbacks::subcommands());
+    let matches = app.get_matches();
 
     let verify = matches.is_present("verify");
+    let classify_inputs = matches.is_present("classify-inputs");
+    let use_undo_files = matches.is_present("use-undo-files");
+    let mmap = !matches.is_present("no-mmap");
+    let strict = matches.is_present("strict");
+    let include_orphans = matches.is_present("include-orphans");
+    let check_pow = matches.is_present("check-pow");
+    let keep_going = matches.is_present("keep-going");
     let log_level_filter = match matches.occurrences_of("verbosity") {
         0 => log::LevelFilter::Info,
         1 => log::LevelFilter::Debug,
         _ => log::LevelFilter::Trace,
     };
+    let log_config = LogConfig {
+        levels: match matches.value_of("log-level") {
+            Some(spec) => LogLevels::new(log_level_filter).parse_overrides(spec)?,
+            None => LogLevels::new(log_level_filter),
+        },
+        format: value_t!(matches, "log-format", logger::LogFormat)
+            .unwrap_or(logger::LogFormat::Text),
+        file: matches.value_of("log-file").map(PathBuf::from),
+        max_size: match matches.value_of("log-max-size") {
+            Some(_) => value_t!(matches, "log-max-size", u64)
+                .ok()
+                .filter(|n| *n > 0)
+                .ok_or_else(|| {
+                    OpError::from(String::from("--log-max-size must be a positive number"))
+                })?,
+            None => logger::DEFAULT_MAX_SIZE,
+        },
+    };
 
-    let coin_type = value_t!(matches, "coin", CoinType).unwrap_or_else(|_| CoinType::from(Bitcoin));
+    let mut coin_type = match matches.value_of("coin-file") {
+        Some(path) => coinfile::load(Path::new(path))?,
+        None => value_t!(matches, "coin", CoinType).unwrap_or_else(|_| CoinType::from(Bitcoin)),
+    };
+    if let Some(hex) = matches.value_of("signet-magic") {
+        if matches.is_present("coin-file") || matches.value_of("coin") != Some("bitcoin-signet") {
+            return Err(OpError::new(OpErrorKind::InvalidArgsError)
+                .join_msg("--signet-magic requires --coin bitcoin-signet"));
+        }
+        coin_type.magic = coinfile::parse_magic(hex)?;
+    }
+    if matches.is_present("legacy-addresses") {
+        coin_type.cashaddr_prefix = None;
+    }
+    coin_type.p2pk_as_pubkey = matches.is_present("p2pk-as-pubkey");
     let blockchain_dir = match matches.value_of("blockchain-dir") {
         Some(p) => PathBuf::from(p),
         None => utils::get_absolute_blockchain_dir(&coin_type),
     };
+    let block_files = match matches.values_of("block-file") {
+        Some(patterns) => stream::expand_block_files(patterns)?,
+        None => Vec::new(),
+    };
+    let xor_key = match matches.value_of("xor-key") {
+        Some(hex) => Some(BlkFile::parse_xor_key(hex)?),
+        None => None,
+    };
+    let threads = match matches.value_of("threads") {
+        Some(_) => value_t!(matches, "threads", usize)
+            .ok()
+            .filter(|n| *n > 0)
+            .ok_or_else(|| OpError::from(String::from("--threads must be a positive number")))?,
+        None => thread::available_parallelism().map_or(1, |n| n.get()),
+    };
+    let progress_interval = match matches.value_of("progress-interval") {
+        Some(_) => value_t!(matches, "progress-interval", u64).map_err(|_| {
+            OpError::from(String::from(
+                "--progress-interval must be a number of seconds",
+            ))
+        })?,
+        None => 10,
+    };
+    let progress_interval = Some(Duration::from_secs(progress_interval)).filter(|d| !d.is_zero());
+    let metrics_listen = matches.value_of("metrics-listen").map(String::from);
+    let p2p_fallback = matches.value_of("p2p-fallback").map(String::from);
+    let p2p_timeout = match matches.value_of("p2p-timeout") {
+        Some(_) => value_t!(matches, "p2p-timeout", u64)
+            .ok()
+            .filter(|secs| *secs > 0)
+            .ok_or_else(|| {
+                OpError::from(String::from(
+                    "--p2p-timeout must be a positive number of seconds",
+                ))
+            })?,
+        None => 30,
+    };
+    let p2p_timeout = Duration::from_secs(p2p_timeout);
+    let dump_unknown_scripts = matches.value_of("dump-unknown-scripts").map(PathBuf::from);
+    let max_unknown = match matches.value_of("max-unknown") {
+        Some(_) => value_t!(matches, "max-unknown", usize)
+            .ok()
+            .filter(|n| *n > 0)
+            .ok_or_else(|| {
+                OpError::from(String::from("--max-unknown must be a positive number"))
+            })?,
+        None => unknown::DEFAULT_MAX_UNKNOWN,
+    };
     let start = value_t!(matches, "start", usize).unwrap_or(0);
     let end = value_t!(matches, "end", usize).ok();
     let range = ParseRange::new(start, end)?;
 
     // Set callback
-    let callback: Box<dyn Callback>;
-    if let Some(ref matches) = matches.subcommand_matches("simplestats") {
-        callback = Box::new(SimpleStats::new(matches)?);
-    } else if let Some(ref matches) = matches.subcommand_matches("csvdump") {
-        callback = Box::new(CsvDump::new(matches)?);
-    } else if let Some(ref matches) = matches.subcommand_matches("unspentcsvdump") {
-        callback = Box::new(UnspentCsvDump::new(matches)?);
-    } else if let Some(ref matches) = matches.subcommand_matches("balances") {
-        callback = Box::new(Balances::new(matches)?);
-    } else if let Some(ref matches) = matches.subcommand_matches("mongo") {
-        callback = Box::new(Mongo::new(matches)?);
-    } else {
-        clap::Error {
-            message: String::from("error: No Callback specified.\nFor more information try --help"),
-            kind: clap::ErrorKind::MissingSubcommand,
-            info: None,
+    let (callback, callback_name) = match matches.subcommand() {
+        (name, Some(matches)) => (callbacks::new_callback(name, matches)?, String::from(name)),
+        _ => {
+            clap::Error {
+                message: String::from(
+                    "error: No Callback specified.\nFor more information try --help",
+                ),
+                kind: clap::ErrorKind::MissingSubcommand,
+                info: None,
+            }
+            .exit();
         }
-        .exit();
-    }
+    };
 
     let options = ParserOptions {
         coin_type,
         callback,
+        callback_name,
         verify,
+        classify_inputs,
+        use_undo_files,
         blockchain_dir,
+        block_files,
+        xor_key,
+        threads,
+        mmap,
+        strict,
+        include_orphans,
+        check_pow,
+        keep_going,
+        progress_interval,
+        metrics_listen,
+        p2p_fallback,
+        p2p_timeout,
+        dump_unknown_scripts,
+        max_unknown,
         log_level_filter,
         range,
     };
-    Ok(RefCell::new(options))
+    Ok((RefCell::new(options), log_config))
 }
@@ -0,0 +1,6 @@
+-- Run in the dump folder: psql -f import.sql
+
+\copy "unspent" ("txid", "indexOut", "height", "value", "address") FROM 'unspent-0-300.csv' WITH (FORMAT csv, DELIMITER ';', HEADER true, FORCE_NOT_NULL ("address"))
+
+CREATE UNIQUE INDEX "unspent_txid_indexOut" ON "unspent" ("txid", "indexOut");
+CREATE INDEX "unspent_address" ON "unspent" ("address");
@@ -0,0 +1,9 @@
+-- Tables of the csv dump, generated by rusty-blockparser --emit-sql postgres
+
+CREATE TABLE IF NOT EXISTS "unspent" (
+    "txid" CHAR(64) NOT NULL,
+    "indexOut" BIGINT NOT NULL,
+    "height" BIGINT NOT NULL,
+    "value" BIGINT NOT NULL,
+    "address" VARCHAR(90) NOT NULL
+);
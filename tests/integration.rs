@@ -0,0 +1,404 @@
+//! End to end tests of the callbacks. Each test parses the regtest chain of `testdata/regtest.dat`
+//! (built by `RegtestChain` in src/callbacks/fixtures.rs), or the duplicate coinbases of
+//! `testdata/bip30.dat` (built by `bip30_chain`), and compares the files a callback writes
+//! with the golden files in `testdata/golden/<test>`. After an intended change of the output they
+//! are rewritten with `BLESS=1 cargo test --test integration`, the diff shows what changed.
+//!
+//! There is no library target, so the tests run the binary with the chain on stdin. Callbacks
+//! which need a database server aren't covered except `mongo --dry-run`.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+
+const FIXTURE: &str = "testdata/regtest.dat";
+/// Chain with the duplicate coinbases before BIP30, built by `bip30_chain`
+const BIP30_FIXTURE: &str = "testdata/bip30.dat";
+const GOLDEN: &str = "testdata/golden";
+/// Replaced with the output folder of the test
+const OUT: &str = "{out}";
+/// Paid by the chain several times
+const ADDRESS: &str = "bcrt1qhvjpad02pqa6zrut3t4tmqg07ehvz8dqkyyhpg";
+const WALLET: &str = "xpub6BosfCnifzxcFwrSzQiqu2DBVTshkCXacvNsWGYJVVhhawA7d4R5WSWGFNbi8Aw6ZRc1brxMyWMzG3DSSSSoekkudhUd9yLb6qx39T9nMdj";
+
+/// Name of the file holding the standard output of the binary, if there is any
+const STDOUT: &str = "stdout.txt";
+
+/// Files and their content, by path relative to the output folder
+type Files = BTreeMap<String, Vec<u8>>;
+
+fn root() -> &'static Path {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+}
+
+/// Runs the binary on the fixture with the arguments after the parser options, returns what it
+/// wrote to the output folder and its standard output
+fn run(name: &str, fixture: &str, args: &[&str]) -> Files {
+    let out = env::temp_dir().join(format!("blockparser-it-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&out);
+    fs::create_dir_all(&out).unwrap();
+    let out_str = out.to_str().unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_rusty-blockparser"))
+        .args([
+            "-c",
+            "bitcoin-regtest",
+            "-d",
+            "-",
+            "--verify",
+            "--check-pow",
+        ])
+        // Log records go to stderr then, they have a timestamp
+        .args(["--log-format", "json"])
+        .args(args.iter().map(|arg| arg.replace(OUT, out_str)))
+        .stdin(File::open(root().join(fixture)).unwrap())
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{} failed with {}:\n{}",
+        name,
+        output.status,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let mut files = read_files(&out);
+    if !output.stdout.is_empty() {
+        files.insert(String::from(STDOUT), output.stdout);
+    }
+    fs::remove_dir_all(&out).unwrap();
+    files
+}
+
+fn read_files(dir: &Path) -> Files {
+    let mut files = Files::new();
+    let mut dirs = vec![PathBuf::from(dir)];
+    while let Some(next) = dirs.pop() {
+        for entry in fs::read_dir(&next).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                dirs.push(path);
+            } else {
+                let name = path.strip_prefix(dir).unwrap().to_str().unwrap();
+                files.insert(name.replace('\\', "/"), fs::read(&path).unwrap());
+            }
+        }
+    }
+    files
+}
+
+/// Sorts the rows after the header line, or the records of a binary UTXO snapshot, which are
+/// written in hash map order
+fn sort_rows(name: &str, content: &[u8]) -> Vec<u8> {
+    if name.ends_with(".bin") {
+        return sort_records(content);
+    }
+    let text = String::from_utf8(content.to_vec()).unwrap();
+    let mut lines: Vec<&str> = text.lines().collect();
+    if !lines.is_empty() {
+        lines[1..].sort_unstable();
+    }
+    lines
+        .iter()
+        .map(|line| format!("{}\n", line))
+        .collect::<String>()
+        .into_bytes()
+}
+
+/// Sorts the length prefixed records after magic, version and height of a utxo-<height>.bin
+fn sort_records(content: &[u8]) -> Vec<u8> {
+    let read_varint = |data: &mut &[u8]| {
+        let (mut n, mut shift) = (0u64, 0);
+        loop {
+            let byte = data[0];
+            *data = &data[1..];
+            n |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return n;
+            }
+            shift += 7;
+        }
+    };
+    let mut data = &content[9..];
+    read_varint(&mut data);
+    let header_len = content.len() - data.len();
+    let mut records = Vec::new();
+    while !data.is_empty() {
+        let start = data;
+        let len = read_varint(&mut data) as usize;
+        let prefix_len = start.len() - data.len();
+        records.push(&start[..prefix_len + len]);
+        data = &data[len..];
+    }
+    records.sort_unstable();
+    let mut sorted = content[..header_len].to_vec();
+    records
+        .iter()
+        .for_each(|record| sorted.extend_from_slice(record));
+    sorted
+}
+
+/// Describes the first difference of two files, the line if both are text
+fn difference(name: &str, expected: &[u8], actual: &[u8]) -> String {
+    if let (Ok(expected), Ok(actual)) = (std::str::from_utf8(expected), std::str::from_utf8(actual))
+    {
+        let mut lines = expected.lines().zip(actual.lines()).enumerate();
+        if let Some((i, (expected, actual))) = lines.find(|(_, (a, b))| a != b) {
+            return format!(
+                "{} differs in line {}:\nexpected: {}\n  actual: {}",
+                name,
+                i + 1,
+                expected,
+                actual
+            );
+        }
+    }
+    format!(
+        "{} differs, expected {} bytes, got {}",
+        name,
+        expected.len(),
+        actual.len()
+    )
+}
+
+/// Runs the callback and compares its output with the golden files of the test, or rewrites
+/// them with `BLESS=1`. The rows of the `unordered` files are sorted first.
+fn check(name: &str, args: &[&str], unordered: &[&str]) {
+    check_fixture(name, FIXTURE, args, unordered);
+}
+
+fn check_fixture(name: &str, fixture: &str, args: &[&str], unordered: &[&str]) {
+    let files: Files = run(name, fixture, args)
+        .into_iter()
+        .map(|(file, content)| {
+            let file_name = file.rsplit('/').next().unwrap();
+            if unordered.iter().any(|prefix| file_name.starts_with(prefix)) {
+                let content = sort_rows(&file, &content);
+                (file, content)
+            } else {
+                (file, content)
+            }
+        })
+        .collect();
+    assert!(!files.is_empty(), "{} wrote nothing", name);
+    let golden = root().join(GOLDEN).join(name);
+    if env::var_os("BLESS").is_some() {
+        let _ = fs::remove_dir_all(&golden);
+        for (file, content) in &files {
+            let path = golden.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+        return;
+    }
+    let expected = read_files(&golden);
+    let names = |files: &Files| files.keys().cloned().collect::<Vec<String>>();
+    assert_eq!(
+        names(&expected),
+        names(&files),
+        "Files of {} differ, rewrite {} with `BLESS=1 cargo test --test integration`",
+        name,
+        golden.display()
+    );
+    for (file, content) in &files {
+        if expected[file] != *content {
+            panic!(
+                "{}, rewrite {} with `BLESS=1 cargo test --test integration` if that's intended",
+                difference(file, &expected[file], content),
+                golden.display()
+            );
+        }
+    }
+}
+
+macro_rules! golden_test {
+    ($name:ident, [$($arg:expr),* $(,)?] $(, unordered: [$($file:expr),*])?) => {
+        #[test]
+        fn $name() {
+            check(stringify!($name), &[$($arg),*], &[$($($file),*)?]);
+        }
+    };
+}
+
+golden_test!(
+    adoption,
+    ["adoption", "--bucket-size", "50", "--output-dir", OUT]
+);
+golden_test!(
+    addressindex,
+    ["addressindex", "--sort", "balance", "--output-dir", OUT]
+);
+golden_test!(anomalies, ["anomalies", "--output-dir", OUT]);
+golden_test!(balances, ["balances", "--snapshot-every", "150", OUT], unordered: ["balances"]);
+golden_test!(blockchair, ["blockchair", "--output-dir", OUT]);
+golden_test!(
+    cluster,
+    [
+        "cluster",
+        "--with-change-heuristic",
+        "--skip-coinjoin-like",
+        "--output-dir",
+        OUT
+    ]
+);
+golden_test!(coinbase, ["coinbase", "--output-dir", OUT]);
+golden_test!(coindays, ["coindays", "--output-dir", OUT]);
+golden_test!(csvdump, ["csvdump", "--header", "--extended", OUT]);
+golden_test!(dust, ["dust", "--bucket-size", "50", "--output-dir", OUT]);
+golden_test!(etldump, ["etldump", "--output-dir", OUT]);
+golden_test!(feestats, ["feestats", "--output-dir", OUT]);
+golden_test!(
+    filter,
+    ["filter", "--address", ADDRESS, "--output-dir", OUT]
+);
+golden_test!(graph, ["graph", "--output-dir", OUT]);
+golden_test!(headers, ["headers", "--csv", "--output-dir", OUT]);
+golden_test!(
+    inscriptions,
+    ["inscriptions", "--output-dir", OUT, "--content-dir", OUT]
+);
+golden_test!(jsondump, ["jsondump", "--output-dir", OUT]);
+golden_test!(mongo, ["mongo", "--dry-run", "--sample", "3"]);
+golden_test!(multisig, ["multisig", "--output-dir", OUT]);
+golden_test!(opreturn_analyze, ["opreturn-analyze", "--output-dir", OUT]);
+golden_test!(parquetdump, ["parquetdump", "--output-dir", OUT]);
+golden_test!(rawdump, ["rawdump", "--output-dir", OUT]);
+golden_test!(richlist, ["richlist", "--top", "50", "--output-dir", OUT]);
+golden_test!(simplestats, ["simplestats", "--format", "json"]);
+golden_test!(
+    unknown_scripts,
+    [
+        "--dump-unknown-scripts",
+        OUT,
+        "simplestats",
+        "--format",
+        "json"
+    ]
+);
+golden_test!(
+    timeseries,
+    ["timeseries", "--bucket", "hour", "--output-dir", OUT]
+);
+golden_test!(unspentcsvdump, ["unspentcsvdump", OUT], unordered: ["unspent"]);
+golden_test!(
+    unspentcsvdump_sql,
+    ["unspentcsvdump", "--emit-sql", "postgres", OUT],
+    unordered: ["unspent"]
+);
+golden_test!(utxosnapshot, ["utxosnapshot", "--output-dir", OUT], unordered: ["utxo"]);
+golden_test!(
+    wallet_scan,
+    ["wallet-scan", "--xpub", WALLET, "--output-dir", OUT]
+);
+
+// The later of two coinbases with the same txid replaces the outputs of the earlier one, the
+// spend in block 5 leaves nothing behind. Both pairs pay 50 coins once.
+#[test]
+fn bip30_balances() {
+    check_fixture(
+        "bip30_balances",
+        BIP30_FIXTURE,
+        &["balances", OUT],
+        &["balances"],
+    );
+}
+
+// The replaced outputs were received but aren't part of the balance
+#[test]
+fn bip30_addressindex() {
+    check_fixture(
+        "bip30_addressindex",
+        BIP30_FIXTURE,
+        &["addressindex", "--output-dir", OUT],
+        &[],
+    );
+    check_fixture(
+        "bip30_addressindex",
+        BIP30_FIXTURE,
+        &[
+            "addressindex",
+            "--db-backend",
+            "disk",
+            "--db-path",
+            "{out}/db",
+            "--buffer-size",
+            "1",
+            "--output-dir",
+            OUT,
+        ],
+        &[],
+    );
+}
+
+#[test]
+fn bip30_unspentcsvdump() {
+    check_fixture(
+        "bip30_unspentcsvdump",
+        BIP30_FIXTURE,
+        &["unspentcsvdump", OUT],
+        &["unspent"],
+    );
+    // Spilled after every output, the duplicates are found on disk
+    check_fixture(
+        "bip30_unspentcsvdump",
+        BIP30_FIXTURE,
+        &[
+            "unspentcsvdump",
+            "--db-backend",
+            "disk",
+            "--db-path",
+            "{out}/db",
+            "--buffer-size",
+            "1",
+            OUT,
+        ],
+        &["unspent"],
+    );
+}
+
+// The fixture pays the regtest subsidy, which halves every 150 blocks, no block claims too much
+#[test]
+fn supplyaudit() {
+    let args = ["supplyaudit", "--output-dir", OUT];
+    check("supplyaudit", &args, &[]);
+    let files = run("supplyaudit", FIXTURE, &args);
+    let text = |name: &str| String::from_utf8(files[name].clone()).unwrap();
+    assert_eq!(
+        vec!["height;hash;claimed;subsidy;fees;difference;kind"],
+        text("supplyaudit.csv").lines().collect::<Vec<&str>>()
+    );
+    assert_eq!(
+        Some("300;1125000000000;0;0;0"),
+        text("supply.csv").lines().nth(1)
+    );
+}
+
+// The times vary, only the counts are compared
+#[test]
+fn bench() {
+    let files = run("bench", FIXTURE, &["bench"]);
+    let report = String::from_utf8(files[STDOUT].clone()).unwrap();
+    assert_eq!(
+        Some("Parsed 300 blocks, 710 transactions, 902 inputs, 1547 outputs, 216958 bytes in 1 passes"),
+        report.lines().next()
+    );
+    let phases: Vec<&str> = report
+        .lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .collect();
+    assert_eq!(
+        vec![
+            "Parsed",
+            "min",
+            "wall",
+            "read",
+            "deserialize",
+            "evaluate",
+            "dispatch",
+            "blocks/s",
+            "txs/s",
+            "MB/s"
+        ],
+        phases
+    );
+}